@@ -0,0 +1,253 @@
+//! HTTP access log: one line per request, apart from the application log.
+//!
+//! [`layer`] wraps the whole router. It notes method, path and the caller's
+//! token fingerprint, with the version of the forward token it matched,
+//! when a request arrives and writes the entry once the response body is
+//! finished, so a streamed response is timed to its last byte. Forwarding
+//! handlers tell it which model and upstream served the request by
+//! attaching [`Served`] to the response.
+//!
+//! Lines go to `logs/access.log`, rotated with the log file settings, and
+//! optionally to the `access_log` table.
+
+use std::sync::RwLock;
+use std::task::Poll;
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::StreamExt;
+
+use crate::config::LoggingConfig;
+use crate::db::AccessRecord;
+use crate::forward::{self, middleware};
+use crate::logger::{RotatingFile, Rotation};
+
+static ACCESS_FILE: RotatingFile = RotatingFile::new("access");
+
+#[derive(Clone, Copy)]
+struct Output {
+    file: bool,
+    db: bool,
+    rotation: Rotation,
+}
+
+static OUTPUT: RwLock<Option<Output>> = RwLock::new(None);
+
+/// Apply the access log settings from `logging`.
+pub fn configure(cfg: &LoggingConfig) {
+    *OUTPUT.write().unwrap_or_else(|e| e.into_inner()) = Some(Output {
+        file: cfg.access_log,
+        db: cfg.access_log_db,
+        rotation: Rotation::from_config(cfg),
+    });
+    if !cfg.access_log {
+        ACCESS_FILE.close();
+    }
+}
+
+/// Response extension naming the model and upstream that served a request,
+/// and why the plan routed it there.
+#[derive(Debug, Clone)]
+pub struct Served {
+    pub model: String,
+    pub upstream_id: String,
+    /// The upstream's first endpoint, redacted
+    pub endpoint: Option<String>,
+    pub route_reason: String,
+}
+
+impl Served {
+    pub fn of(ctx: &forward::ForwardContext) -> Self {
+        Self {
+            model: ctx.model.id.clone(),
+            upstream_id: ctx.upstream.id.clone(),
+            endpoint: ctx.primary_endpoint().map(crate::redact::text),
+            route_reason: ctx.route.label(),
+        }
+    }
+
+    pub fn attach(self, mut response: Response) -> Response {
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// An entry waiting for its response body to finish. Written exactly once,
+/// at the end of the body or when it is dropped early.
+struct Pending {
+    record: AccessRecord,
+    started: Instant,
+    written: bool,
+    sink: fn(&AccessRecord),
+}
+
+impl Pending {
+    fn finish(&mut self) {
+        if self.written {
+            return;
+        }
+        self.written = true;
+        self.record.duration_ms = self.started.elapsed().as_millis() as i64;
+        (self.sink)(&self.record);
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Layer recording every request in the access log.
+pub async fn layer(req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    // The query string may carry a key (`?key=` for Gemini), so only the path
+    let path = req.uri().path().to_string();
+    let token = middleware::extract_request_token(req.headers());
+    let client_token = token.as_deref().map(middleware::token_fingerprint);
+    // Tells the previous forward token from the new one during a rotation
+    let token_version = token.and_then(|t| {
+        crate::config::current()
+            .forward_token_version(&t, chrono::Utc::now().timestamp())
+            .map(|v| v.version)
+    });
+
+    let response = next.run(req).await;
+    let (parts, body) = response.into_parts();
+    let served = parts.extensions.get::<Served>();
+    let pending = Pending {
+        record: AccessRecord {
+            timestamp: chrono::Utc::now().timestamp(),
+            method,
+            path,
+            status: parts.status.as_u16(),
+            duration_ms: 0,
+            bytes: 0,
+            model: served.map(|s| s.model.clone()),
+            upstream_id: served.map(|s| s.upstream_id.clone()),
+            client_token,
+            token_version,
+            request_id: parts
+                .headers
+                .get(forward::REQUEST_ID_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+        },
+        started,
+        written: false,
+        sink: write,
+    };
+    Response::from_parts(parts, counted(body, pending))
+}
+
+/// `body`, counting bytes into `pending` and finishing it after the last one.
+fn counted(body: Body, mut pending: Pending) -> Body {
+    let mut inner = body.into_data_stream();
+    Body::from_stream(futures_util::stream::poll_fn(move |cx| {
+        let poll = inner.poll_next_unpin(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => pending.record.bytes += chunk.len() as i64,
+            Poll::Ready(_) => pending.finish(),
+            Poll::Pending => {}
+        }
+        poll
+    }))
+}
+
+fn write(record: &AccessRecord) {
+    let Some(output) = *OUTPUT.read().unwrap_or_else(|e| e.into_inner()) else {
+        return;
+    };
+    if output.file {
+        let mut line = format_line(record, chrono::Utc::now());
+        line.push('\n');
+        ACCESS_FILE.append(&line, output.rotation);
+    }
+    if output.db {
+        crate::db::log_access(record.clone());
+    }
+}
+
+/// `<time> <method> <path> <status> <bytes> <ms>ms model=.. upstream=..
+/// token=.. token_version=.. request_id=..`, with `-` for anything unknown.
+fn format_line(record: &AccessRecord, at: chrono::DateTime<chrono::Utc>) -> String {
+    let or_dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+    format!(
+        "{} {} {} {} {} {}ms model={} upstream={} token={} token_version={} request_id={}",
+        at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        record.method,
+        record.path,
+        record.status,
+        record.bytes,
+        record.duration_ms,
+        or_dash(&record.model),
+        or_dash(&record.upstream_id),
+        or_dash(&record.client_token),
+        or_dash(&record.token_version.map(|v| v.to_string())),
+        or_dash(&record.request_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let record = AccessRecord {
+            timestamp: 1_700_000_000,
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            status: 200,
+            duration_ms: 1234,
+            bytes: 5678,
+            model: Some("gpt-4o".to_string()),
+            upstream_id: Some("openai".to_string()),
+            client_token: Some("ccr_...abcd".to_string()),
+            token_version: Some(2),
+            request_id: None,
+        };
+        let at = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(
+            format_line(&record, at),
+            "2023-11-14T22:13:20.000Z POST /v1/chat/completions 200 5678 1234ms \
+             model=gpt-4o upstream=openai token=ccr_...abcd token_version=2 request_id=-"
+        );
+    }
+
+    static WRITTEN: std::sync::Mutex<Vec<AccessRecord>> = std::sync::Mutex::new(Vec::new());
+
+    fn collect(record: &AccessRecord) {
+        WRITTEN.lock().unwrap().push(record.clone());
+    }
+
+    #[tokio::test]
+    async fn test_entry_written_after_last_byte() {
+        let chunks: Vec<Result<&'static str, std::io::Error>> =
+            vec![Ok("data: a\n\n"), Ok("data: b\n\n")];
+        let pending = Pending {
+            record: AccessRecord::default(),
+            started: Instant::now(),
+            written: false,
+            sink: collect,
+        };
+        let mut body = counted(
+            Body::from_stream(futures_util::stream::iter(chunks)),
+            pending,
+        )
+        .into_data_stream();
+        assert!(body.next().await.is_some());
+        assert!(WRITTEN.lock().unwrap().is_empty());
+        assert!(body.next().await.is_some());
+        assert!(body.next().await.is_none());
+        drop(body);
+
+        let written = WRITTEN.lock().unwrap();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].bytes, 18);
+    }
+}
@@ -1,21 +1,1973 @@
-use super::ModelAdapter;
+//! Anthropic Messages, mapped to and from OpenAI chat completions.
+//!
+//! Thinking blocks travel as OpenAI `reasoning_content`, tool use as
+//! `tool_calls` and images as `image_url` parts. An OpenAI
+//! `response_format` becomes a forced `structured_output` tool whose input
+//! is handed back as the reply.
+
 use serde_json::Value;
 
-#[allow(dead_code)]
-pub struct AnthropicAdapter;
+use super::openai::{
+    content_from_parts, marker_part, parse_data_url, parse_openai_usage, stream_chunk, Document,
+    AUDIO_DROPPED, DOCUMENT_DROPPED,
+};
+use super::params::effort_for_budget;
+use super::{epoch_seconds, ModelAdapter, ParamPolicy, StreamMap};
+use crate::config::ReasoningBudgets;
+use crate::forward::context::{estimate_tokens, TokenUsage};
 
-impl ModelAdapter for AnthropicAdapter {
-    fn map_chat_request(&self, input: Value) -> Value {
-        input
-    }
-    fn map_chat_response(&self, output: Value) -> Value {
-        output
+/// Tool emulating OpenAI's `response_format`; a call to it is the reply
+const STRUCTURED_OUTPUT_TOOL: &str = "structured_output";
+
+pub struct AnthropicAdapter {
+    /// Whether OpenAI reasoning is passed on as thinking blocks
+    pub thinking: bool,
+    /// Whether thinking blocks of earlier turns become text in OpenAI
+    /// requests rather than being dropped
+    pub keep_thinking_history: bool,
+    /// Effort levels thinking budgets read as in OpenAI requests
+    pub reasoning_budgets: ReasoningBudgets,
+}
+
+impl Default for AnthropicAdapter {
+    fn default() -> Self {
+        Self {
+            thinking: true,
+            keep_thinking_history: false,
+            reasoning_budgets: ReasoningBudgets::default(),
+        }
     }
 }
 
-#[allow(dead_code)]
 impl AnthropicAdapter {
+    /// The adapter for a client's request, with thinking as it asks.
+    pub fn for_request(payload: &Value) -> Self {
+        let cfg = crate::config::current();
+        Self {
+            thinking: is_thinking_enabled(payload),
+            keep_thinking_history: cfg.keep_thinking_history,
+            reasoning_budgets: cfg.reasoning_budgets.clone(),
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn id() -> &'static str {
         "anthropic"
     }
 }
+
+impl ModelAdapter for AnthropicAdapter {
+    type StreamToOpenAI = AnthropicToOpenAIStreamState;
+    type StreamFromOpenAI = OpenAIToAnthropicStreamState;
+
+    fn request_from_openai(&self, request: &Value, model: &str) -> Value {
+        convert_openai_to_anthropic_request(request, model)
+    }
+
+    fn request_to_openai(&self, request: &Value, model: &str) -> Value {
+        convert_anthropic_to_openai(
+            request,
+            model,
+            self.keep_thinking_history,
+            &self.reasoning_budgets,
+        )
+    }
+
+    fn response_from_openai(&self, response: &Value, model: &str) -> Value {
+        convert_openai_response_to_anthropic(response, model, self.thinking)
+    }
+
+    fn response_to_openai(&self, response: &Value, model: &str) -> Value {
+        convert_anthropic_response_to_openai(response, model)
+    }
+
+    fn normalize_request(&self, request: &mut Value, policy: &ParamPolicy) {
+        let Some(obj) = request.as_object_mut() else {
+            return;
+        };
+        // Left for here by `request_from_openai`
+        if let Some(effort) = obj.remove("reasoning_effort") {
+            let budget = policy.budget(&effort);
+            if let Some(budget) = budget.filter(|_| !obj.contains_key("thinking")) {
+                obj.insert(
+                    "thinking".to_string(),
+                    serde_json::json!({ "type": "enabled", "budget_tokens": budget }),
+                );
+            }
+        }
+        policy.keep(obj, "thinking", policy.reasoning);
+        fit_thinking_budget(obj, policy);
+        // Thinking cannot go with a tool the model is forced to use
+        let forced = matches!(
+            obj.get("tool_choice")
+                .and_then(|c| c.get("type"))
+                .and_then(|v| v.as_str()),
+            Some("any" | "tool")
+        );
+        if forced && obj.remove("thinking").is_some() {
+            policy.dropped("thinking", "a tool is forced");
+        }
+
+        // Extended thinking fixes temperature and top_k, and wants top_p
+        // of at least 0.95
+        let thinking = obj
+            .get("thinking")
+            .and_then(|t| t.get("type"))
+            .and_then(|v| v.as_str())
+            == Some("enabled");
+        if thinking {
+            for key in ["temperature", "top_k"] {
+                if obj.remove(key).is_some() {
+                    policy.dropped(key, "fixed while thinking");
+                }
+            }
+        }
+        policy.keep(obj, "temperature", policy.temperature);
+        policy.keep(obj, "top_p", policy.top_p);
+        policy.keep(obj, "top_k", policy.top_k.unwrap_or(true));
+        policy.clamp(obj, "temperature", 0.0, 1.0);
+        policy.clamp(obj, "top_p", if thinking { 0.95 } else { 0.0 }, 1.0);
+        policy.integer(obj, "top_k", 1);
+    }
+
+    fn stream_from_openai(&self, _model: &str, prompt_tokens: i64) -> OpenAIToAnthropicStreamState {
+        OpenAIToAnthropicStreamState::new(self.thinking, prompt_tokens)
+    }
+
+    fn stream_to_openai(&self, model: &str, prompt_tokens: i64) -> AnthropicToOpenAIStreamState {
+        let mut state = AnthropicToOpenAIStreamState::new(model);
+        state.prompt_tokens = prompt_tokens;
+        state
+    }
+}
+
+/// Anthropic takes a thinking budget of at least 1024 tokens and below
+/// `max_tokens`.
+fn fit_thinking_budget(obj: &mut serde_json::Map<String, Value>, policy: &ParamPolicy) {
+    let Some(max_tokens) = obj.get("max_tokens").and_then(|v| v.as_i64()) else {
+        return;
+    };
+    let Some(thinking) = obj.get_mut("thinking").and_then(|v| v.as_object_mut()) else {
+        return;
+    };
+    let Some(budget) = thinking.get("budget_tokens").and_then(|v| v.as_i64()) else {
+        return;
+    };
+    if budget < max_tokens {
+        return;
+    }
+    if max_tokens > 1024 {
+        thinking.insert("budget_tokens".to_string(), Value::from(max_tokens - 1));
+    } else {
+        obj.remove("thinking");
+        policy.dropped("thinking", "max_tokens leaves no room for a budget");
+    }
+}
+
+/// Turns OpenAI chunks into Anthropic events: `message_start` first, a
+/// thinking block for `reasoning_content` followed by a text block, and
+/// `message_delta` and `message_stop` once a finish reason arrives.
+/// Reasoning that comes after the text has started is dropped.
+pub struct OpenAIToAnthropicStreamState {
+    thinking: bool,
+    usage: TokenUsage,
+    started: bool,
+    stopped: bool,
+    thinking_index: Option<usize>,
+    text_index: Option<usize>,
+    next_index: usize,
+}
+
+impl OpenAIToAnthropicStreamState {
+    fn new(thinking: bool, prompt_tokens: i64) -> Self {
+        Self {
+            thinking,
+            usage: TokenUsage::estimated(prompt_tokens),
+            started: false,
+            stopped: false,
+            thinking_index: None,
+            text_index: None,
+            next_index: 0,
+        }
+    }
+
+    fn message_start(&self, chunk: &Value) -> Value {
+        let id = chunk
+            .get("id")
+            .and_then(|v| v.as_str())
+            .unwrap_or("msg_unknown");
+        let id = if id.starts_with("msg_") {
+            id.to_string()
+        } else {
+            format!("msg_{}", id)
+        };
+        let usage = chunk.get("usage");
+        let input_tokens = usage
+            .and_then(|v| v.get("prompt_tokens"))
+            .and_then(|v| v.as_i64())
+            .unwrap_or(self.usage.prompt_tokens);
+        let output_tokens = usage
+            .and_then(|v| v.get("completion_tokens"))
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        serde_json::json!({
+            "type": "message_start",
+            "message": {
+                "id": id,
+                "type": "message",
+                "role": "assistant",
+                "content": [],
+                "model": chunk.get("model").cloned().unwrap_or(Value::String("unknown".to_string())),
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": {
+                    "input_tokens": input_tokens,
+                    "output_tokens": output_tokens
+                }
+            }
+        })
+    }
+
+    /// Index of the open thinking or text block, started if needed.
+    fn open_block(&mut self, thinking: bool, out: &mut Vec<Value>) -> usize {
+        let open = if thinking {
+            self.thinking_index
+        } else {
+            self.text_index
+        };
+        if let Some(index) = open {
+            return index;
+        }
+        let index = self.next_index;
+        self.next_index += 1;
+        let content_block = if thinking {
+            self.thinking_index = Some(index);
+            serde_json::json!({ "type": "thinking", "thinking": "" })
+        } else {
+            self.text_index = Some(index);
+            serde_json::json!({ "type": "text", "text": "" })
+        };
+        out.push(serde_json::json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": content_block
+        }));
+        index
+    }
+
+    /// Stop the open blocks, in index order.
+    fn close_blocks(&mut self, out: &mut Vec<Value>) {
+        let mut indices: Vec<usize> = [self.thinking_index.take(), self.text_index.take()]
+            .into_iter()
+            .flatten()
+            .collect();
+        indices.sort_unstable();
+        out.extend(indices.into_iter().map(block_stop));
+    }
+
+    fn stop(&mut self, out: &mut Vec<Value>) {
+        if !self.stopped {
+            self.stopped = true;
+            out.push(serde_json::json!({ "type": "message_stop" }));
+        }
+    }
+}
+
+impl StreamMap for OpenAIToAnthropicStreamState {
+    fn map_event(&mut self, chunk: &Value) -> Vec<Value> {
+        let mut out = Vec::new();
+        if !self.started {
+            self.started = true;
+            out.push(self.message_start(chunk));
+        }
+
+        let choice = chunk
+            .get("choices")
+            .and_then(|c| c.as_array())
+            .and_then(|c| c.first());
+        if let Some(choice) = choice {
+            let delta = choice.get("delta").unwrap_or(&Value::Null);
+            let reasoning = delta
+                .get("reasoning_content")
+                .and_then(|r| r.as_str())
+                .filter(|_| self.thinking)
+                .unwrap_or("");
+            let content = delta.get("content").and_then(|c| c.as_str()).unwrap_or("");
+
+            if !reasoning.is_empty() && (self.thinking_index.is_some() || self.text_index.is_none())
+            {
+                let index = self.open_block(true, &mut out);
+                out.push(block_delta(
+                    index,
+                    serde_json::json!({ "type": "thinking_delta", "thinking": reasoning }),
+                ));
+                self.usage
+                    .add_estimated_reasoning(estimate_tokens(reasoning));
+            }
+            if !content.is_empty() {
+                if let Some(index) = self.thinking_index.take() {
+                    out.push(block_stop(index));
+                }
+                let index = self.open_block(false, &mut out);
+                out.push(block_delta(
+                    index,
+                    serde_json::json!({ "type": "text_delta", "text": content }),
+                ));
+                self.usage.add_estimated_output(estimate_tokens(content));
+            }
+
+            if let Some(reason) = choice.get("finish_reason").filter(|r| !r.is_null()) {
+                self.close_blocks(&mut out);
+                out.push(serde_json::json!({
+                    "type": "message_delta",
+                    "delta": {
+                        "stop_reason": map_openai_finish_reason(reason.as_str()),
+                        "stop_sequence": null
+                    },
+                    "usage": {
+                        "output_tokens": self.usage.completion_tokens
+                    }
+                }));
+                self.stop(&mut out);
+            }
+        }
+
+        if let Some(usage) = chunk.get("usage").filter(|u| u.is_object()) {
+            if let Some(prompt_tokens) = usage.get("prompt_tokens").and_then(|v| v.as_i64()) {
+                self.usage.set_reported_prompt(prompt_tokens);
+            }
+            if let Some(completion_tokens) = usage.get("completion_tokens").and_then(|v| v.as_i64())
+            {
+                self.usage.set_reported_completion(completion_tokens);
+            }
+            self.usage.merge_details(&parse_openai_usage(usage));
+        }
+        out
+    }
+
+    fn finish(&mut self) -> Vec<Value> {
+        let mut out = Vec::new();
+        if !self.stopped {
+            self.close_blocks(&mut out);
+            self.stop(&mut out);
+        }
+        out
+    }
+
+    fn usage(&self) -> TokenUsage {
+        self.usage.clone()
+    }
+}
+
+fn block_delta(index: usize, delta: Value) -> Value {
+    serde_json::json!({
+        "type": "content_block_delta",
+        "index": index,
+        "delta": delta
+    })
+}
+
+fn block_stop(index: usize) -> Value {
+    serde_json::json!({
+        "type": "content_block_stop",
+        "index": index
+    })
+}
+
+fn parse_boolish(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(flag) => Some(*flag),
+        Value::Number(num) => num.as_i64().map(|v| v != 0),
+        Value::String(raw) => {
+            let normalized = raw.trim().to_ascii_lowercase();
+            if matches!(normalized.as_str(), "true" | "1" | "yes" | "on" | "enabled") {
+                Some(true)
+            } else if matches!(
+                normalized.as_str(),
+                "false" | "0" | "no" | "off" | "disabled" | "none"
+            ) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_thinking_enabled(payload: &Value) -> bool {
+    let Some(thinking) = payload.get("thinking") else {
+        return true;
+    };
+
+    if let Some(flag) = parse_boolish(thinking) {
+        return flag;
+    }
+
+    if let Some(obj) = thinking.as_object() {
+        if let Some(flag) = obj.get("enabled").and_then(parse_boolish) {
+            return flag;
+        }
+        if let Some(flag) = obj.get("enable").and_then(parse_boolish) {
+            return flag;
+        }
+        if let Some(kind) = obj.get("type").and_then(|v| v.as_str()) {
+            let normalized = kind.trim().to_ascii_lowercase();
+            if matches!(normalized.as_str(), "disabled" | "off" | "false" | "none") {
+                return false;
+            }
+            if matches!(normalized.as_str(), "enabled" | "on" | "true") {
+                return true;
+            }
+        }
+        if obj.get("budget_tokens").is_some() {
+            return true;
+        }
+    }
+
+    true
+}
+
+/// Parse an Anthropic `usage` object. Cache writes and reads are billed as
+/// input, so they are folded into `prompt_tokens` and also kept separately.
+pub fn parse_anthropic_usage(usage: &Value) -> TokenUsage {
+    let input = usage
+        .get("input_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let output = usage
+        .get("output_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let cache_creation = usage
+        .get("cache_creation_input_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let cache_read = usage
+        .get("cache_read_input_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+
+    TokenUsage {
+        cache_creation_tokens: cache_creation,
+        cache_read_tokens: cache_read,
+        ..TokenUsage::new(input + cache_creation + cache_read, output)
+    }
+}
+
+/// Convert OpenAI-compatible response payload into Anthropic message format.
+fn convert_openai_response_to_anthropic(
+    response: &Value,
+    default_model: &str,
+    thinking_enabled: bool,
+) -> Value {
+    let mut message = serde_json::Map::new();
+
+    // DEBUG: Add a marker to confirm conversion is called
+    let id = response
+        .get("id")
+        .and_then(|v| v.as_str())
+        .unwrap_or("msg_unknown");
+    message.insert(
+        "id".to_string(),
+        Value::String(format!("[CONVERTED]{}", id)),
+    );
+    message.insert("type".to_string(), Value::String("message".to_string()));
+    message.insert("role".to_string(), Value::String("assistant".to_string()));
+
+    let model_value = response
+        .get("model")
+        .cloned()
+        .unwrap_or_else(|| Value::String(default_model.to_string()));
+    message.insert("model".to_string(), model_value);
+
+    let mut content_blocks = Vec::new();
+    let mut finish_reason = None;
+
+    if let Some(choice) = response
+        .get("choices")
+        .and_then(|v| v.as_array())
+        .and_then(|v| v.first())
+    {
+        finish_reason = choice.get("finish_reason").and_then(|v| v.as_str());
+        content_blocks = extract_openai_choice_content(choice, thinking_enabled);
+    }
+
+    if content_blocks.is_empty() {
+        content_blocks.push(serde_json::json!({
+            "type": "text",
+            "text": ""
+        }));
+    }
+
+    message.insert("content".to_string(), Value::Array(content_blocks));
+    message.insert(
+        "stop_reason".to_string(),
+        Value::String(map_openai_finish_reason(finish_reason)),
+    );
+    message.insert("stop_sequence".to_string(), Value::Null);
+
+    if let Some(usage) = convert_openai_usage_to_anthropic(response) {
+        message.insert("usage".to_string(), usage);
+    }
+
+    Value::Object(message)
+}
+
+fn extract_openai_choice_content(choice: &Value, thinking_enabled: bool) -> Vec<Value> {
+    if let Some(message) = choice.get("message") {
+        let blocks = openai_message_to_anthropic_content(message, thinking_enabled);
+        if !blocks.is_empty() {
+            return blocks;
+        }
+    }
+
+    if let Some(delta) = choice.get("delta") {
+        let blocks = openai_message_to_anthropic_content(delta, thinking_enabled);
+        if !blocks.is_empty() {
+            return blocks;
+        }
+    }
+
+    if let Some(text) = choice.get("text").and_then(|v| v.as_str()) {
+        return vec![serde_json::json!({
+            "type": "text",
+            "text": text
+        })];
+    }
+
+    if let Some(content) = choice.get("content") {
+        let blocks = openai_message_to_anthropic_content(content, thinking_enabled);
+        if !blocks.is_empty() {
+            return blocks;
+        }
+    }
+
+    Vec::new()
+}
+
+fn openai_message_to_anthropic_content(message: &Value, thinking_enabled: bool) -> Vec<Value> {
+    let mut blocks = Vec::new();
+
+    if thinking_enabled {
+        if let Some(reasoning) = message.get("reasoning_content").and_then(|v| v.as_str()) {
+            if !reasoning.is_empty() {
+                push_thinking_block(&mut blocks, reasoning);
+            }
+        }
+    }
+
+    if let Some(text) = message.as_str() {
+        push_text_block(&mut blocks, text);
+        return blocks;
+    }
+
+    if let Some(parts) = message.as_array() {
+        append_openai_content_parts(&mut blocks, parts);
+        return blocks;
+    }
+
+    if let Some(content) = message.get("content") {
+        match content {
+            Value::String(text) => push_text_block(&mut blocks, text),
+            Value::Array(parts) => {
+                append_openai_content_parts(&mut blocks, parts);
+            }
+            Value::Object(obj) => {
+                if let Some(text) = obj.get("text").and_then(|t| t.as_str()) {
+                    push_text_block(&mut blocks, text);
+                } else if let Some(value) = obj.get("value").and_then(|t| t.as_str()) {
+                    push_text_block(&mut blocks, value);
+                }
+            }
+            _ => {}
+        }
+    } else if let Some(obj) = message.as_object() {
+        if let Some(text) = obj.get("text").and_then(|t| t.as_str()) {
+            push_text_block(&mut blocks, text);
+        } else if let Some(value) = obj.get("value").and_then(|t| t.as_str()) {
+            push_text_block(&mut blocks, value);
+        }
+    }
+
+    if let Some(tool_calls) = message.get("tool_calls").and_then(|v| v.as_array()) {
+        for tool_call in tool_calls {
+            if let Some(block) = convert_openai_tool_call(tool_call) {
+                blocks.push(block);
+            }
+        }
+    } else if let Some(function_call) = message.get("function_call") {
+        if let Some(block) = convert_openai_function_call(function_call) {
+            blocks.push(block);
+        }
+    }
+
+    blocks
+}
+
+fn push_text_block(blocks: &mut Vec<Value>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    blocks.push(serde_json::json!({
+        "type": "text",
+        "text": text
+    }));
+}
+
+fn push_thinking_block(blocks: &mut Vec<Value>, thinking: &str) {
+    if thinking.is_empty() {
+        return;
+    }
+    blocks.push(serde_json::json!({
+        "type": "thinking",
+        "thinking": thinking
+    }));
+}
+
+fn append_openai_content_parts(blocks: &mut Vec<Value>, parts: &[Value]) {
+    for part in parts {
+        let Some(part_type) = part.get("type").and_then(|t| t.as_str()) else {
+            continue;
+        };
+        match part_type {
+            "text" => {
+                if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                    push_text_block(blocks, text);
+                }
+            }
+            "image_url" => {
+                push_text_block(blocks, "[Image]");
+            }
+            _ => {}
+        }
+    }
+}
+
+fn convert_openai_tool_call(tool_call: &Value) -> Option<Value> {
+    let function = tool_call.get("function")?;
+    let name = function.get("name")?.as_str()?;
+    let args_raw = function
+        .get("arguments")
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let input =
+        serde_json::from_str(args_raw).unwrap_or_else(|_| Value::String(args_raw.to_string()));
+    let id = tool_call
+        .get("id")
+        .and_then(|v| v.as_str())
+        .unwrap_or("tool_call");
+
+    Some(serde_json::json!({
+        "type": "tool_use",
+        "id": id,
+        "name": name,
+        "input": input
+    }))
+}
+
+fn convert_openai_function_call(function_call: &Value) -> Option<Value> {
+    let name = function_call.get("name")?.as_str()?;
+    let args_raw = function_call
+        .get("arguments")
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let input =
+        serde_json::from_str(args_raw).unwrap_or_else(|_| Value::String(args_raw.to_string()));
+
+    Some(serde_json::json!({
+        "type": "tool_use",
+        "id": "function_call",
+        "name": name,
+        "input": input
+    }))
+}
+
+fn convert_openai_usage_to_anthropic(response: &Value) -> Option<Value> {
+    let usage = response.get("usage")?;
+    let input = usage
+        .get("prompt_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let output = usage
+        .get("completion_tokens")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+
+    Some(serde_json::json!({
+        "input_tokens": input,
+        "output_tokens": output
+    }))
+}
+
+fn map_openai_finish_reason(reason: Option<&str>) -> String {
+    match reason {
+        Some("stop") => "end_turn".to_string(),
+        Some("length") => "max_tokens".to_string(),
+        Some("tool_calls") | Some("function_call") => "tool_use".to_string(),
+        Some("content_filter") => "content_filter".to_string(),
+        Some(other) => other.to_string(),
+        None => "end_turn".to_string(),
+    }
+}
+
+fn map_openai_tools_to_anthropic(tools: &Value) -> Option<Value> {
+    let tools_array = tools.as_array()?;
+    let mut mapped = Vec::new();
+
+    for tool in tools_array {
+        let tool_type = tool.get("type").and_then(|v| v.as_str()).unwrap_or("");
+        if !tool_type.eq_ignore_ascii_case("function") {
+            continue;
+        }
+        let function = tool.get("function")?;
+        let name = function.get("name")?.as_str()?;
+
+        let mut entry = serde_json::Map::new();
+        entry.insert("name".to_string(), Value::String(name.to_string()));
+
+        if let Some(desc) = function.get("description") {
+            entry.insert("description".to_string(), desc.clone());
+        }
+        if let Some(params) = function.get("parameters") {
+            entry.insert("input_schema".to_string(), params.clone());
+        }
+
+        mapped.push(Value::Object(entry));
+    }
+
+    if mapped.is_empty() {
+        None
+    } else {
+        Some(Value::Array(mapped))
+    }
+}
+
+fn map_openai_tool_choice_to_anthropic(choice: &Value) -> Option<Value> {
+    match choice {
+        Value::String(raw) => match raw.to_ascii_lowercase().as_str() {
+            "auto" => Some(serde_json::json!({ "type": "auto" })),
+            "none" => Some(serde_json::json!({ "type": "none" })),
+            _ => None,
+        },
+        Value::Object(obj) => {
+            let kind = obj.get("type").and_then(|v| v.as_str())?;
+            if kind.eq_ignore_ascii_case("function") {
+                let name = obj
+                    .get("function")
+                    .and_then(|v| v.get("name"))
+                    .and_then(|v| v.as_str())?;
+                Some(serde_json::json!({ "type": "tool", "name": name }))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn map_anthropic_tools_to_openai(tools: &Value) -> Option<Value> {
+    let tools_array = tools.as_array()?;
+    let mut mapped = Vec::new();
+
+    for tool in tools_array {
+        let name = tool.get("name").and_then(|v| v.as_str())?;
+        let mut function = serde_json::Map::new();
+        function.insert("name".to_string(), Value::String(name.to_string()));
+
+        if let Some(desc) = tool.get("description") {
+            function.insert("description".to_string(), desc.clone());
+        }
+        if let Some(schema) = tool.get("input_schema") {
+            function.insert("parameters".to_string(), schema.clone());
+        }
+
+        let mut entry = serde_json::Map::new();
+        entry.insert("type".to_string(), Value::String("function".to_string()));
+        entry.insert("function".to_string(), Value::Object(function));
+        mapped.push(Value::Object(entry));
+    }
+
+    if mapped.is_empty() {
+        None
+    } else {
+        Some(Value::Array(mapped))
+    }
+}
+
+fn map_anthropic_tool_choice_to_openai(choice: &Value) -> Option<Value> {
+    match choice {
+        Value::String(raw) => match raw.to_ascii_lowercase().as_str() {
+            "auto" | "any" => Some(Value::String("auto".to_string())),
+            "none" => Some(Value::String("none".to_string())),
+            _ => None,
+        },
+        Value::Object(obj) => {
+            let kind = obj.get("type").and_then(|v| v.as_str())?;
+            match kind.to_ascii_lowercase().as_str() {
+                "auto" | "any" => Some(Value::String("auto".to_string())),
+                "none" => Some(Value::String("none".to_string())),
+                "tool" => {
+                    let name = obj.get("name").and_then(|v| v.as_str())?;
+                    Some(serde_json::json!({
+                        "type": "function",
+                        "function": { "name": name }
+                    }))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Text blocks of an OpenAI system message, one per text part, keeping
+/// `cache_control` markers. A marker on the message itself goes on its last
+/// block.
+fn openai_system_to_anthropic_blocks(message: &Value) -> Vec<Value> {
+    let mut blocks = Vec::new();
+    match message.get("content") {
+        Some(Value::String(text)) if !text.is_empty() => {
+            blocks.push(text_block(text, None));
+        }
+        Some(Value::Array(parts)) => {
+            for part in parts {
+                if part.get("type").and_then(|v| v.as_str()) != Some("text") {
+                    continue;
+                }
+                if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                    if !text.is_empty() {
+                        blocks.push(text_block(text, part.get("cache_control")));
+                    }
+                }
+            }
+        }
+        Some(Value::Object(obj)) => {
+            if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
+                if !text.is_empty() {
+                    blocks.push(text_block(text, obj.get("cache_control")));
+                }
+            }
+        }
+        _ => {}
+    }
+    if let (Some(cache_control), Some(Value::Object(last))) =
+        (message.get("cache_control"), blocks.last_mut())
+    {
+        last.insert("cache_control".to_string(), cache_control.clone());
+    }
+    blocks
+}
+
+/// A text block, or part: both formats spell them the same.
+fn text_block(text: &str, cache_control: Option<&Value>) -> Value {
+    let mut block = serde_json::json!({ "type": "text", "text": text });
+    if let (Some(cache_control), Some(obj)) = (cache_control, block.as_object_mut()) {
+        obj.insert("cache_control".to_string(), cache_control.clone());
+    }
+    block
+}
+
+fn append_openai_content_blocks(blocks: &mut Vec<Value>, content: &Value) {
+    match content {
+        Value::String(text) if !text.is_empty() => {
+            blocks.push(serde_json::json!({ "type": "text", "text": text }));
+        }
+        Value::Array(parts) => {
+            for part in parts {
+                let part_type = part.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                match part_type {
+                    "text" => {
+                        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                            blocks.push(text_block(text, part.get("cache_control")));
+                        }
+                    }
+                    "image_url" => {
+                        let url = part
+                            .get("image_url")
+                            .and_then(|v| v.get("url"))
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("[Image]");
+                        // Remote URLs are inlined beforehand when `image_fetch` is on
+                        if let Some((media_type, data)) = parse_data_url(url) {
+                            blocks.push(serde_json::json!({
+                                "type": "image",
+                                "source": { "type": "base64", "media_type": media_type, "data": data }
+                            }));
+                        } else {
+                            blocks.push(serde_json::json!({
+                                "type": "text",
+                                "text": format!("[Image] {}", url)
+                            }));
+                        }
+                    }
+                    "file" => blocks.push(openai_file_to_anthropic_block(part)),
+                    // Anthropic takes no audio input
+                    "input_audio" => blocks.push(marker_part(AUDIO_DROPPED)),
+                    _ => {}
+                }
+            }
+        }
+        Value::Object(obj) => {
+            if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
+                blocks.push(serde_json::json!({ "type": "text", "text": text }));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An OpenAI `file` part as a `document` block; Anthropic takes base64
+/// documents as PDF only.
+fn openai_file_to_anthropic_block(part: &Value) -> Value {
+    match Document::from_file_part(part) {
+        Some(doc) if doc.media_type == "application/pdf" => {
+            let mut block = serde_json::json!({
+                "type": "document",
+                "source": { "type": "base64", "media_type": doc.media_type, "data": doc.data }
+            });
+            if let (Some(title), Some(obj)) = (doc.filename, block.as_object_mut()) {
+                obj.insert("title".to_string(), Value::String(title));
+            }
+            block
+        }
+        _ => marker_part(DOCUMENT_DROPPED),
+    }
+}
+
+/// A `document` block as an OpenAI part: a `file` for base64 sources, the
+/// text of plain text ones.
+fn anthropic_document_to_openai_part(block: &Value) -> Value {
+    let source = block.get("source").unwrap_or(&Value::Null);
+    let field = |key: &str| source.get(key).and_then(|v| v.as_str()).unwrap_or("");
+    let document = match field("type") {
+        "base64" => Document::new(
+            field("media_type"),
+            field("data"),
+            block.get("title").and_then(|v| v.as_str()),
+        ),
+        "text" if !field("data").is_empty() => {
+            return serde_json::json!({ "type": "text", "text": field("data") });
+        }
+        _ => None,
+    };
+    match document {
+        Some(doc) => doc.to_file_part(),
+        None => marker_part(DOCUMENT_DROPPED),
+    }
+}
+
+/// Convert OpenAI request format to Anthropic format
+fn convert_openai_to_anthropic_request(payload: &Value, model: &str) -> Value {
+    let mut anthropic_request = serde_json::Map::new();
+    anthropic_request.insert("model".to_string(), Value::String(model.to_string()));
+
+    let mut system_blocks = Vec::new();
+    let mut messages_out = Vec::new();
+
+    if let Some(messages) = payload.get("messages").and_then(|v| v.as_array()) {
+        for msg in messages {
+            let role = msg.get("role").and_then(|v| v.as_str()).unwrap_or("user");
+
+            if role.eq_ignore_ascii_case("system") {
+                system_blocks.extend(openai_system_to_anthropic_blocks(msg));
+                continue;
+            }
+
+            let mut blocks = Vec::new();
+            if let Some(content) = msg.get("content") {
+                append_openai_content_blocks(&mut blocks, content);
+            }
+
+            if let Some(tool_calls) = msg.get("tool_calls").and_then(|v| v.as_array()) {
+                for tool_call in tool_calls {
+                    if let Some(block) = convert_openai_tool_call(tool_call) {
+                        blocks.push(block);
+                    }
+                }
+            }
+
+            if let Some(function_call) = msg.get("function_call") {
+                if let Some(block) = convert_openai_function_call(function_call) {
+                    blocks.push(block);
+                }
+            }
+
+            if blocks.is_empty() {
+                blocks.push(serde_json::json!({ "type": "text", "text": "" }));
+            }
+
+            let mut msg_obj = serde_json::Map::new();
+            msg_obj.insert("role".to_string(), Value::String(role.to_string()));
+            msg_obj.insert("content".to_string(), Value::Array(blocks));
+            messages_out.push(Value::Object(msg_obj));
+        }
+    }
+
+    if !messages_out.is_empty() {
+        anthropic_request.insert("messages".to_string(), Value::Array(messages_out));
+    }
+
+    if !system_blocks.is_empty() {
+        anthropic_request.insert("system".to_string(), Value::Array(system_blocks));
+    }
+
+    if let Some(max_tokens) = payload
+        .get("max_tokens")
+        .or_else(|| payload.get("max_completion_tokens"))
+    {
+        anthropic_request.insert("max_tokens".to_string(), max_tokens.clone());
+    }
+    if let Some(temperature) = payload.get("temperature") {
+        anthropic_request.insert("temperature".to_string(), temperature.clone());
+    }
+    if let Some(top_p) = payload.get("top_p") {
+        anthropic_request.insert("top_p".to_string(), top_p.clone());
+    }
+    if let Some(stream) = payload.get("stream") {
+        anthropic_request.insert("stream".to_string(), stream.clone());
+    }
+    if let Some(stop) = payload.get("stop") {
+        let mapped = match stop {
+            Value::String(text) => Value::Array(vec![Value::String(text.clone())]),
+            _ => stop.clone(),
+        };
+        anthropic_request.insert("stop_sequences".to_string(), mapped);
+    }
+    if let Some(metadata) = payload.get("metadata") {
+        anthropic_request.insert("metadata".to_string(), metadata.clone());
+    }
+    if let Some(tools) = payload.get("tools") {
+        if let Some(mapped) = map_openai_tools_to_anthropic(tools) {
+            anthropic_request.insert("tools".to_string(), mapped);
+        }
+    }
+    if let Some(tool_choice) = payload.get("tool_choice") {
+        if let Some(mapped) = map_openai_tool_choice_to_anthropic(tool_choice) {
+            anthropic_request.insert("tool_choice".to_string(), mapped);
+        }
+    }
+    if let Some(tool) = payload
+        .get("response_format")
+        .and_then(structured_output_tool)
+    {
+        let tools = anthropic_request
+            .entry("tools")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        // The client's own tools stay callable, but one must be called
+        let choice = if tools.as_array().is_some_and(|t| !t.is_empty()) {
+            serde_json::json!({ "type": "any" })
+        } else {
+            serde_json::json!({ "type": "tool", "name": STRUCTURED_OUTPUT_TOOL })
+        };
+        if let Some(tools) = tools.as_array_mut() {
+            tools.push(tool);
+        }
+        anthropic_request.insert("tool_choice".to_string(), choice);
+    }
+    // Turned into a thinking budget by `normalize_request`
+    if let Some(effort) = payload.get("reasoning_effort") {
+        anthropic_request.insert("reasoning_effort".to_string(), effort.clone());
+    }
+
+    Value::Object(anthropic_request)
+}
+
+/// The tool a `json_schema` or `json_object` response format is forced
+/// through; `None` for plain text.
+fn structured_output_tool(format: &Value) -> Option<Value> {
+    let (description, schema) = match format.get("type").and_then(|v| v.as_str())? {
+        "json_schema" => {
+            let spec = format.get("json_schema")?;
+            let description = spec
+                .get("description")
+                .and_then(|v| v.as_str())
+                .unwrap_or("Respond with JSON matching this schema.");
+            let schema = spec
+                .get("schema")
+                .cloned()
+                .unwrap_or_else(|| serde_json::json!({ "type": "object" }));
+            (description, schema)
+        }
+        "json_object" => (
+            "Respond with a JSON object.",
+            serde_json::json!({ "type": "object" }),
+        ),
+        _ => return None,
+    };
+    Some(serde_json::json!({
+        "name": STRUCTURED_OUTPUT_TOOL,
+        "description": description,
+        "input_schema": schema
+    }))
+}
+
+fn is_structured_output_call(tool_call: &Value) -> bool {
+    tool_call
+        .get("function")
+        .and_then(|f| f.get("name"))
+        .and_then(|v| v.as_str())
+        == Some(STRUCTURED_OUTPUT_TOOL)
+}
+
+fn text_from_anthropic_block(block: &Value) -> Option<String> {
+    match block.get("type").and_then(|v| v.as_str()) {
+        Some("text") => block
+            .get("text")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string()),
+        Some("thinking") => block
+            .get("thinking")
+            .and_then(|v| v.as_str())
+            .map(|s| format!("[Thinking] {}", s)),
+        _ => None,
+    }
+}
+
+fn push_anthropic_image_as_openai_part(parts: &mut Vec<Value>, block: &Value) {
+    let source = block.get("source");
+    let source_type = source
+        .and_then(|v| v.get("type"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    if source_type.eq_ignore_ascii_case("base64") {
+        let media_type = source
+            .and_then(|v| v.get("media_type"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("application/octet-stream");
+        let data = source
+            .and_then(|v| v.get("data"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        if !data.is_empty() {
+            let url = format!("data:{};base64,{}", media_type, data);
+            parts.push(serde_json::json!({
+                "type": "image_url",
+                "image_url": { "url": url }
+            }));
+            return;
+        }
+    }
+    parts.push(serde_json::json!({
+        "type": "text",
+        "text": "[Image]"
+    }));
+}
+
+/// Convert Anthropic request format to OpenAI format
+/// Thinking blocks, signed or redacted, only mean something to Anthropic;
+/// with `keep_thinking` the readable ones are kept as text. A thinking
+/// budget becomes the `reasoning_effort` it reads as in `budgets`.
+fn convert_anthropic_to_openai(
+    payload: &Value,
+    model: &str,
+    keep_thinking: bool,
+    budgets: &ReasoningBudgets,
+) -> Value {
+    let mut openai_request = serde_json::Map::new();
+    openai_request.insert("model".to_string(), Value::String(model.to_string()));
+
+    let mut openai_messages = Vec::new();
+    // Ids of the tool calls the last assistant message made, in order
+    let mut pending_calls: Vec<String> = Vec::new();
+
+    if let Some(messages) = payload.get("messages").and_then(|v| v.as_array()) {
+        for msg in messages {
+            let role = msg.get("role").and_then(|v| v.as_str()).unwrap_or("user");
+
+            let mut content_parts = Vec::new();
+            let mut tool_calls = Vec::new();
+            let mut tool_messages = Vec::new();
+            let mut dropped_thinking = false;
+
+            match msg.get("content") {
+                Some(Value::String(text)) if !text.is_empty() => {
+                    content_parts.push(serde_json::json!({ "type": "text", "text": text }));
+                }
+                Some(Value::Array(blocks)) => {
+                    for block in blocks {
+                        match block.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+                            "thinking" | "redacted_thinking" if !keep_thinking => {
+                                dropped_thinking = true;
+                            }
+                            "text" | "thinking" => {
+                                if let Some(text) = text_from_anthropic_block(block) {
+                                    content_parts
+                                        .push(text_block(&text, block.get("cache_control")));
+                                }
+                            }
+                            "image" => {
+                                push_anthropic_image_as_openai_part(&mut content_parts, block);
+                            }
+                            "document" => {
+                                content_parts.push(anthropic_document_to_openai_part(block));
+                            }
+                            "tool_use" => {
+                                let name =
+                                    block.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                                let id = block
+                                    .get("id")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or("tool_call");
+                                let input = block.get("input").cloned().unwrap_or(Value::Null);
+                                let args = serde_json::to_string(&input)
+                                    .unwrap_or_else(|_| "{}".to_string());
+                                tool_calls.push(serde_json::json!({
+                                    "id": id,
+                                    "type": "function",
+                                    "function": { "name": name, "arguments": args }
+                                }));
+                            }
+                            "tool_result" => {
+                                let tool_id = block
+                                    .get("tool_use_id")
+                                    .or_else(|| block.get("id"))
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or("tool_call");
+                                let content = match block.get("content") {
+                                    Some(Value::String(text)) => text.clone(),
+                                    Some(Value::Array(parts)) => parts
+                                        .iter()
+                                        .filter_map(|p| p.get("text").and_then(|v| v.as_str()))
+                                        .collect::<Vec<_>>()
+                                        .join("\n"),
+                                    Some(other) => other.to_string(),
+                                    None => String::new(),
+                                };
+                                tool_messages.push(serde_json::json!({
+                                    "role": "tool",
+                                    "tool_call_id": tool_id,
+                                    "content": content
+                                }));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            // OpenAI wants the `tool` messages right after the assistant
+            // message that made the calls, so they go before whatever else
+            // the user turn says, in the order the calls were made
+            let has_tool_results = !tool_messages.is_empty();
+            tool_messages.sort_by_key(|m| {
+                let id = m["tool_call_id"].as_str().unwrap_or_default();
+                pending_calls
+                    .iter()
+                    .position(|call| call == id)
+                    .unwrap_or(usize::MAX)
+            });
+            openai_messages.extend(tool_messages);
+
+            pending_calls = tool_calls
+                .iter()
+                .filter_map(|call| call["id"].as_str().map(str::to_string))
+                .collect();
+            if (has_tool_results || dropped_thinking)
+                && content_parts.is_empty()
+                && tool_calls.is_empty()
+            {
+                continue;
+            }
+
+            let mut openai_msg = serde_json::Map::new();
+            openai_msg.insert("role".to_string(), Value::String(role.to_string()));
+            if content_parts.is_empty() {
+                openai_msg.insert("content".to_string(), Value::String(String::new()));
+            } else {
+                openai_msg.insert("content".to_string(), content_from_parts(content_parts));
+            }
+            if !tool_calls.is_empty() {
+                openai_msg.insert("tool_calls".to_string(), Value::Array(tool_calls));
+            }
+
+            openai_messages.push(Value::Object(openai_msg));
+        }
+    }
+
+    if let Some(system) = payload.get("system") {
+        let system_messages = anthropic_system_to_openai_messages(system);
+        openai_messages.splice(0..0, system_messages);
+    }
+
+    if !openai_messages.is_empty() {
+        openai_request.insert("messages".to_string(), Value::Array(openai_messages));
+    }
+
+    if let Some(max_tokens) = payload.get("max_tokens") {
+        openai_request.insert("max_tokens".to_string(), max_tokens.clone());
+    }
+    if let Some(temperature) = payload.get("temperature") {
+        openai_request.insert("temperature".to_string(), temperature.clone());
+    }
+    if let Some(top_p) = payload.get("top_p") {
+        openai_request.insert("top_p".to_string(), top_p.clone());
+    }
+    let budget = payload
+        .get("thinking")
+        .filter(|thinking| thinking["type"] == "enabled")
+        .and_then(|thinking| thinking.get("budget_tokens"));
+    if let Some(effort) = effort_for_budget(budget, budgets) {
+        openai_request.insert("reasoning_effort".to_string(), effort);
+    }
+    if let Some(stream) = payload.get("stream") {
+        openai_request.insert("stream".to_string(), stream.clone());
+    }
+    if let Some(stop_sequences) = payload.get("stop_sequences") {
+        openai_request.insert("stop".to_string(), stop_sequences.clone());
+    }
+    if let Some(metadata) = payload.get("metadata") {
+        openai_request.insert("metadata".to_string(), metadata.clone());
+    }
+    if let Some(tools) = payload.get("tools") {
+        if let Some(mapped) = map_anthropic_tools_to_openai(tools) {
+            openai_request.insert("tools".to_string(), mapped);
+        }
+    }
+    if let Some(tool_choice) = payload.get("tool_choice") {
+        if let Some(mapped) = map_anthropic_tool_choice_to_openai(tool_choice) {
+            openai_request.insert("tool_choice".to_string(), mapped);
+        }
+    }
+
+    Value::Object(openai_request)
+}
+
+fn map_anthropic_stop_reason(reason: &str) -> Value {
+    let mapped = match reason {
+        "end_turn" => "stop",
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "content_filter" => "content_filter",
+        other => other,
+    };
+    Value::String(mapped.to_string())
+}
+
+fn convert_anthropic_usage_to_openai(usage: &Value) -> Value {
+    let parsed = parse_anthropic_usage(usage);
+    serde_json::json!({
+        "prompt_tokens": parsed.prompt_tokens,
+        "completion_tokens": parsed.completion_tokens,
+        "total_tokens": parsed.total(),
+        "prompt_tokens_details": { "cached_tokens": parsed.cache_read_tokens }
+    })
+}
+
+/// One OpenAI system message per text block of an Anthropic `system`, in
+/// order. Blocks marked with `cache_control` keep it as a content part.
+fn anthropic_system_to_openai_messages(system: &Value) -> Vec<Value> {
+    match system {
+        Value::String(text) => vec![serde_json::json!({ "role": "system", "content": text })],
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter(|block| block.get("type").and_then(|v| v.as_str()) == Some("text"))
+            .filter_map(|block| {
+                let text = block.get("text").and_then(|v| v.as_str())?;
+                let content = match block.get("cache_control") {
+                    Some(cache_control) => {
+                        Value::Array(vec![text_block(text, Some(cache_control))])
+                    }
+                    None => Value::String(text.to_string()),
+                };
+                Some(serde_json::json!({ "role": "system", "content": content }))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn anthropic_content_to_openai_message(content: &Value) -> (Value, Vec<Value>) {
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    match content {
+        Value::String(text) if !text.is_empty() => {
+            parts.push(serde_json::json!({ "type": "text", "text": text }));
+        }
+        Value::Array(blocks) => {
+            for block in blocks {
+                match block.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+                    "text" | "thinking" => {
+                        if let Some(text) = text_from_anthropic_block(block) {
+                            parts.push(text_block(&text, block.get("cache_control")));
+                        }
+                    }
+                    "image" => {
+                        push_anthropic_image_as_openai_part(&mut parts, block);
+                    }
+                    "tool_use" => {
+                        let name = block.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                        let id = block
+                            .get("id")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("tool_call");
+                        let input = block.get("input").cloned().unwrap_or(Value::Null);
+                        let args =
+                            serde_json::to_string(&input).unwrap_or_else(|_| "{}".to_string());
+                        tool_calls.push(serde_json::json!({
+                            "id": id,
+                            "type": "function",
+                            "function": { "name": name, "arguments": args }
+                        }));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let content_value = if parts.is_empty() {
+        Value::String(String::new())
+    } else {
+        content_from_parts(parts)
+    };
+
+    (content_value, tool_calls)
+}
+
+/// Convert Anthropic response format to OpenAI format
+fn convert_anthropic_response_to_openai(response: &Value, model: &str) -> Value {
+    let id = response
+        .get("id")
+        .and_then(|v| v.as_str())
+        .map(|s| {
+            if s.starts_with("chatcmpl_") {
+                s.to_string()
+            } else {
+                format!("chatcmpl_{}", s)
+            }
+        })
+        .unwrap_or_else(|| format!("chatcmpl_{}", epoch_seconds()));
+
+    let created = epoch_seconds();
+
+    let (content, tool_calls) = response
+        .get("content")
+        .map(anthropic_content_to_openai_message)
+        .unwrap_or_else(|| (Value::String(String::new()), Vec::new()));
+    let (structured, tool_calls): (Vec<Value>, Vec<Value>) =
+        tool_calls.into_iter().partition(is_structured_output_call);
+    let content = match structured.first() {
+        Some(call) => call["function"]["arguments"].clone(),
+        None => content,
+    };
+    // The reply itself rather than a call the client has to answer
+    let answered = !structured.is_empty() && tool_calls.is_empty();
+
+    let mut message = serde_json::Map::new();
+    message.insert("role".to_string(), Value::String("assistant".to_string()));
+    message.insert("content".to_string(), content);
+    if !tool_calls.is_empty() {
+        message.insert("tool_calls".to_string(), Value::Array(tool_calls));
+    }
+
+    let finish_reason = if answered {
+        Some(Value::String("stop".to_string()))
+    } else {
+        response
+            .get("stop_reason")
+            .and_then(|v| v.as_str())
+            .map(map_anthropic_stop_reason)
+    };
+
+    let choice = serde_json::json!({
+        "index": 0,
+        "message": Value::Object(message),
+        "finish_reason": finish_reason
+    });
+
+    let usage = response
+        .get("usage")
+        .map(convert_anthropic_usage_to_openai)
+        .unwrap_or_else(|| {
+            serde_json::json!({
+                "prompt_tokens": 0,
+                "completion_tokens": 0,
+                "total_tokens": 0
+            })
+        });
+
+    serde_json::json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": response.get("model").cloned().unwrap_or(Value::String(model.to_string())),
+        "choices": [choice],
+        "usage": usage
+    })
+}
+
+pub struct AnthropicToOpenAIStreamState {
+    id: String,
+    created: i64,
+    model: String,
+    sent_role: bool,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cache_creation_tokens: i64,
+    cache_read_tokens: i64,
+    /// Whether the counts above came from the provider rather than estimates
+    prompt_reported: bool,
+    completion_reported: bool,
+    finished: bool,
+    /// Index of the `structured_output` block, streamed as content
+    structured_index: Option<u64>,
+    tool_called: bool,
+}
+
+impl AnthropicToOpenAIStreamState {
+    fn new(model: &str) -> Self {
+        let now = epoch_seconds();
+        Self {
+            id: format!("chatcmpl_{}", now),
+            created: now,
+            model: model.to_string(),
+            sent_role: false,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cache_creation_tokens: 0,
+            cache_read_tokens: 0,
+            prompt_reported: false,
+            completion_reported: false,
+            finished: false,
+            structured_index: None,
+            tool_called: false,
+        }
+    }
+
+    /// A chunk of this stream, with the usage so far if `include_usage`.
+    fn chunk(&self, delta: Value, finish_reason: Option<Value>, include_usage: bool) -> Value {
+        let usage = include_usage.then(|| {
+            serde_json::json!({
+                "prompt_tokens": self.prompt_tokens,
+                "completion_tokens": self.completion_tokens,
+                "total_tokens": self.prompt_tokens + self.completion_tokens,
+                "prompt_tokens_details": { "cached_tokens": self.cache_read_tokens }
+            })
+        });
+        stream_chunk(
+            &self.id,
+            self.created,
+            &self.model,
+            delta,
+            finish_reason,
+            usage,
+        )
+    }
+
+    /// Count streamed text until the provider reports output tokens.
+    fn add_estimated_output(&mut self, text: &str) {
+        if !self.completion_reported {
+            self.completion_tokens += estimate_tokens(text);
+        }
+    }
+
+    /// Adopt provider usage. `message_start` carries only a placeholder
+    /// output count, so output is taken from later events only.
+    fn apply_usage(&mut self, usage: &Value, include_output: bool) {
+        let parsed = parse_anthropic_usage(usage);
+        if parsed.prompt_tokens > 0 {
+            self.prompt_tokens = parsed.prompt_tokens;
+            self.prompt_reported = true;
+        }
+        if include_output && usage.get("output_tokens").is_some() {
+            self.completion_tokens = parsed.completion_tokens;
+            self.completion_reported = true;
+        }
+        if parsed.cache_creation_tokens > 0 {
+            self.cache_creation_tokens = parsed.cache_creation_tokens;
+        }
+        if parsed.cache_read_tokens > 0 {
+            self.cache_read_tokens = parsed.cache_read_tokens;
+        }
+    }
+}
+
+impl StreamMap for AnthropicToOpenAIStreamState {
+    fn map_event(&mut self, event: &Value) -> Vec<Value> {
+        convert_anthropic_event_to_openai_chunks(event, self)
+    }
+
+    fn usage(&self) -> TokenUsage {
+        TokenUsage {
+            cache_creation_tokens: self.cache_creation_tokens,
+            cache_read_tokens: self.cache_read_tokens,
+            prompt_estimated: !self.prompt_reported,
+            completion_estimated: !self.completion_reported,
+            ..TokenUsage::new(self.prompt_tokens, self.completion_tokens)
+        }
+    }
+}
+
+fn block_index(event: &Value) -> u64 {
+    event.get("index").and_then(|v| v.as_u64()).unwrap_or(0)
+}
+
+fn convert_anthropic_event_to_openai_chunks(
+    event: &Value,
+    state: &mut AnthropicToOpenAIStreamState,
+) -> Vec<Value> {
+    let mut out = Vec::new();
+    let event_type = event.get("type").and_then(|v| v.as_str()).unwrap_or("");
+
+    match event_type {
+        "message_start" => {
+            if let Some(message) = event.get("message") {
+                if let Some(id) = message.get("id").and_then(|v| v.as_str()) {
+                    state.id = if id.starts_with("chatcmpl_") {
+                        id.to_string()
+                    } else {
+                        format!("chatcmpl_{}", id)
+                    };
+                }
+                if let Some(model) = message.get("model").and_then(|v| v.as_str()) {
+                    state.model = model.to_string();
+                }
+                if let Some(usage) = message.get("usage") {
+                    state.apply_usage(usage, false);
+                }
+            }
+
+            if !state.sent_role {
+                out.push(state.chunk(serde_json::json!({ "role": "assistant" }), None, false));
+                state.sent_role = true;
+            }
+        }
+        "content_block_start" => {
+            if let Some(block) = event.get("content_block") {
+                let name = block.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                let is_tool_use = block.get("type").and_then(|v| v.as_str()) == Some("tool_use");
+                if is_tool_use && name == STRUCTURED_OUTPUT_TOOL {
+                    state.structured_index = Some(block_index(event));
+                } else if is_tool_use {
+                    state.tool_called = true;
+                    let id = block
+                        .get("id")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("tool_call");
+                    let input = block.get("input").cloned().unwrap_or(Value::Null);
+                    let args = serde_json::to_string(&input).unwrap_or_else(|_| "{}".to_string());
+                    let delta = serde_json::json!({
+                        "tool_calls": [{
+                            "index": 0,
+                            "id": id,
+                            "type": "function",
+                            "function": { "name": name, "arguments": args }
+                        }]
+                    });
+                    out.push(state.chunk(delta, None, false));
+                }
+            }
+        }
+        "content_block_delta" => {
+            if let Some(delta) = event.get("delta") {
+                let delta_type = delta.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                match delta_type {
+                    "text_delta" => {
+                        if let Some(text) = delta.get("text").and_then(|v| v.as_str()) {
+                            state.add_estimated_output(text);
+                            out.push(state.chunk(
+                                serde_json::json!({ "content": text }),
+                                None,
+                                false,
+                            ));
+                        }
+                    }
+                    "thinking_delta" => {
+                        if let Some(text) = delta.get("thinking").and_then(|v| v.as_str()) {
+                            state.add_estimated_output(text);
+                            out.push(state.chunk(
+                                serde_json::json!({ "reasoning_content": text }),
+                                None,
+                                false,
+                            ));
+                        }
+                    }
+                    "input_json_delta" if state.structured_index == Some(block_index(event)) => {
+                        if let Some(json) = delta.get("partial_json").and_then(|v| v.as_str()) {
+                            state.add_estimated_output(json);
+                            out.push(state.chunk(
+                                serde_json::json!({ "content": json }),
+                                None,
+                                false,
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        "message_delta" => {
+            if let Some(usage) = event.get("usage") {
+                state.apply_usage(usage, true);
+            }
+
+            let finish_reason = event
+                .get("delta")
+                .and_then(|v| v.get("stop_reason"))
+                .and_then(|v| v.as_str())
+                .map(|reason| {
+                    if reason == "tool_use"
+                        && state.structured_index.is_some()
+                        && !state.tool_called
+                    {
+                        Value::String("stop".to_string())
+                    } else {
+                        map_anthropic_stop_reason(reason)
+                    }
+                });
+
+            if finish_reason.is_some() {
+                state.finished = true;
+                out.push(state.chunk(serde_json::json!({}), finish_reason, true));
+            }
+        }
+        "message_stop" if !state.finished => {
+            out.push(state.chunk(
+                serde_json::json!({}),
+                Some(Value::String("stop".to_string())),
+                true,
+            ));
+            state.finished = true;
+        }
+        _ => {}
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_state_prefers_reported_usage() {
+        let mut state = AnthropicToOpenAIStreamState::new("claude");
+        state.prompt_tokens = 40;
+        let start = serde_json::json!({
+            "type": "message_start",
+            "message": {"id": "msg_1", "usage": {"input_tokens": 12, "output_tokens": 1}}
+        });
+        convert_anthropic_event_to_openai_chunks(&start, &mut state);
+        let delta = serde_json::json!({
+            "type": "content_block_delta",
+            "delta": {"type": "text_delta", "text": "Hello there, how are you?"}
+        });
+        convert_anthropic_event_to_openai_chunks(&delta, &mut state);
+
+        let partial = state.usage();
+        assert_eq!(partial.prompt_tokens, 12);
+        assert!(!partial.prompt_estimated);
+        assert_eq!(
+            partial.completion_tokens,
+            estimate_tokens("Hello there, how are you?")
+        );
+        assert!(partial.completion_estimated);
+
+        let end = serde_json::json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": "end_turn"},
+            "usage": {"output_tokens": 9}
+        });
+        convert_anthropic_event_to_openai_chunks(&end, &mut state);
+        let usage = state.usage();
+        assert_eq!(usage.completion_tokens, 9);
+        assert!(!usage.is_estimated());
+    }
+
+    #[test]
+    fn test_convert_openai_response_content_object() {
+        let response = serde_json::json!({
+            "id": "chatcmpl_test",
+            "model": "glm-4.7",
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": { "type": "text", "text": "hello" }
+                },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+        });
+
+        let converted = convert_openai_response_to_anthropic(&response, "glm-4.7", true);
+        let content = converted.get("content").unwrap().as_array().unwrap();
+        assert_eq!(content[0].get("type").unwrap(), "text");
+        assert_eq!(content[0].get("text").unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_convert_openai_response_choice_text() {
+        let response = serde_json::json!({
+            "id": "cmpl_test",
+            "model": "glm-4.7",
+            "choices": [{
+                "text": "fallback text",
+                "finish_reason": "stop"
+            }]
+        });
+
+        let converted = convert_openai_response_to_anthropic(&response, "glm-4.7", true);
+        let content = converted.get("content").unwrap().as_array().unwrap();
+        assert_eq!(content[0].get("type").unwrap(), "text");
+        assert_eq!(content[0].get("text").unwrap(), "fallback text");
+    }
+
+    #[test]
+    fn test_system_cache_control_survives_round_trip() {
+        let marker = serde_json::json!({"type": "ephemeral"});
+        let request = serde_json::json!({
+            "messages": [
+                {"role": "system", "content": "You are terse."},
+                {"role": "system", "content": [
+                    {"type": "text", "text": "Project notes", "cache_control": marker}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Long document", "cache_control": marker}
+                ]},
+                {"role": "system", "content": "Answer in French.", "cache_control": marker}
+            ]
+        });
+        let adapter = AnthropicAdapter::default();
+        let anthropic = adapter.request_from_openai(&request, "claude-sonnet-4");
+        assert_eq!(
+            anthropic["system"],
+            serde_json::json!([
+                {"type": "text", "text": "You are terse."},
+                {"type": "text", "text": "Project notes", "cache_control": marker},
+                {"type": "text", "text": "Answer in French.", "cache_control": marker}
+            ])
+        );
+        assert_eq!(
+            anthropic["messages"][0]["content"][0]["cache_control"],
+            marker
+        );
+
+        let openai = adapter.request_to_openai(&anthropic, "gpt-4o");
+        assert_eq!(
+            openai["messages"],
+            serde_json::json!([
+                {"role": "system", "content": "You are terse."},
+                {"role": "system", "content": [
+                    {"type": "text", "text": "Project notes", "cache_control": marker}
+                ]},
+                {"role": "system", "content": [
+                    {"type": "text", "text": "Answer in French.", "cache_control": marker}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Long document", "cache_control": marker}
+                ]}
+            ])
+        );
+        assert_eq!(
+            adapter.request_from_openai(&openai, "claude-sonnet-4"),
+            anthropic
+        );
+    }
+
+    #[test]
+    fn test_thinking_history_is_dropped_for_openai_unless_kept() {
+        let request = serde_json::json!({
+            "messages": [
+                {"role": "user", "content": "Is 91 prime?"},
+                {"role": "assistant", "content": [
+                    {"type": "thinking", "thinking": "91 = 7 * 13.", "signature": "EqQBCgIYAhIM1gbcDa9GJwZA"},
+                    {"type": "redacted_thinking", "data": "EmwKAhgBEgy3va3pzix/LafPsn4a"},
+                    {"type": "text", "text": "No, 91 = 7 × 13."}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "redacted_thinking", "data": "EmwKAhgBEgy3va3pzix/LafPsn4b"}
+                ]},
+                {"role": "user", "content": "And 97?"}
+            ]
+        });
+        let mut adapter = AnthropicAdapter::default();
+        let dropped = adapter.request_to_openai(&request, "gpt-4o");
+        assert_eq!(
+            dropped["messages"],
+            serde_json::json!([
+                {"role": "user", "content": "Is 91 prime?"},
+                {"role": "assistant", "content": "No, 91 = 7 × 13."},
+                {"role": "user", "content": "And 97?"}
+            ])
+        );
+
+        adapter.keep_thinking_history = true;
+        let kept = adapter.request_to_openai(&request, "gpt-4o");
+        assert_eq!(
+            kept["messages"][1]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "[Thinking] 91 = 7 * 13."},
+                {"type": "text", "text": "No, 91 = 7 × 13."}
+            ])
+        );
+        assert!(!kept.to_string().contains("EqQBCgIYAhIM1gbcDa9GJwZA"));
+    }
+
+    #[test]
+    fn test_tool_results_follow_their_tool_calls() {
+        // Two parallel calls answered out of order, with the reminder text
+        // Claude Code appends to the same user turn
+        let request = serde_json::json!({
+            "model": "claude-sonnet-4",
+            "messages": [
+                {"role": "user", "content": "Where is the retry delay computed?"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Let me search for it."},
+                    {"type": "tool_use", "id": "toolu_01A", "name": "Grep",
+                     "input": {"pattern": "retry_delay"}},
+                    {"type": "tool_use", "id": "toolu_01B", "name": "Glob",
+                     "input": {"pattern": "src/**/client.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_01B",
+                     "content": "src-tauri/src/forward/client.rs"},
+                    {"type": "tool_result", "tool_use_id": "toolu_01A",
+                     "content": [{"type": "text", "text": "client.rs:689: pub fn calculate_retry_delay"}]},
+                    {"type": "text", "text": "<system-reminder>Keep answers short.</system-reminder>"}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_01C", "name": "Read",
+                     "input": {"file_path": "src-tauri/src/forward/client.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_01C", "content": "fn calculate_retry_delay"}
+                ]}
+            ]
+        });
+        let openai = AnthropicAdapter::default().request_to_openai(&request, "gpt-4o");
+        let messages = openai["messages"].as_array().unwrap();
+        let shape: Vec<(&str, &str)> = messages
+            .iter()
+            .map(|m| {
+                let role = m["role"].as_str().unwrap();
+                (role, m["tool_call_id"].as_str().unwrap_or_default())
+            })
+            .collect();
+        assert_eq!(
+            shape,
+            [
+                ("user", ""),
+                ("assistant", ""),
+                ("tool", "toolu_01A"),
+                ("tool", "toolu_01B"),
+                ("user", ""),
+                ("assistant", ""),
+                ("tool", "toolu_01C"),
+            ]
+        );
+        assert_eq!(messages[1]["tool_calls"][1]["id"], "toolu_01B");
+        assert_eq!(
+            messages[2]["content"],
+            "client.rs:689: pub fn calculate_retry_delay"
+        );
+        assert_eq!(
+            messages[4]["content"],
+            "<system-reminder>Keep answers short.</system-reminder>"
+        );
+        assert_eq!(messages[5]["tool_calls"][0]["function"]["name"], "Read");
+    }
+}
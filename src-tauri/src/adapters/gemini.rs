@@ -1,21 +1,1206 @@
-use super::ModelAdapter;
+//! Gemini `generateContent`, mapped to and from OpenAI chat completions.
+//!
+//! Thought parts travel as OpenAI `reasoning_content`, function calls as
+//! `tool_calls` and `generationConfig` as the top-level sampling fields.
+
 use serde_json::Value;
 
-#[allow(dead_code)]
-pub struct GeminiAdapter;
+use super::openai::{
+    content_from_parts, marker_part, parse_data_url, parse_openai_usage, stream_chunk, Audio,
+    Document, AUDIO_DROPPED, DOCUMENT_DROPPED,
+};
+use super::params::effort_for_budget;
+use super::{epoch_seconds, schema, ModelAdapter, ParamPolicy, StreamMap};
+use crate::config::ReasoningBudgets;
+use crate::forward::context::{estimate_tokens, TokenUsage};
+use crate::forward::handlers::gemini::filter_generation_config;
+
+#[derive(Default)]
+pub struct GeminiAdapter {
+    /// Effort levels thinking budgets read as in OpenAI requests
+    pub reasoning_budgets: ReasoningBudgets,
+}
 
 impl ModelAdapter for GeminiAdapter {
-    fn map_chat_request(&self, input: Value) -> Value {
-        input
+    type StreamToOpenAI = GeminiToOpenAIStreamState;
+    type StreamFromOpenAI = OpenAIToGeminiStreamState;
+
+    fn request_from_openai(&self, request: &Value, model: &str) -> Value {
+        convert_openai_to_gemini_request(request, model)
+    }
+
+    fn request_to_openai(&self, request: &Value, model: &str) -> Value {
+        convert_gemini_to_openai_request(request, model, &self.reasoning_budgets)
     }
-    fn map_chat_response(&self, output: Value) -> Value {
-        output
+
+    fn response_from_openai(&self, response: &Value, model: &str) -> Value {
+        convert_openai_response_to_gemini(response, model)
+    }
+
+    fn response_to_openai(&self, response: &Value, model: &str) -> Value {
+        convert_gemini_response_to_openai(response, model)
+    }
+
+    fn normalize_request(&self, request: &mut Value, policy: &ParamPolicy) {
+        let Some(obj) = request.as_object_mut() else {
+            return;
+        };
+        // Left for here by `request_from_openai`
+        let budget = obj
+            .remove("reasoning_effort")
+            .and_then(|effort| policy.budget(&effort));
+        if let Some(budget) = budget {
+            let config = obj
+                .entry("generationConfig")
+                .or_insert_with(|| serde_json::json!({}));
+            if let Some(config) = config.as_object_mut() {
+                config.entry("thinkingConfig").or_insert_with(
+                    || serde_json::json!({ "thinkingBudget": budget, "includeThoughts": true }),
+                );
+            }
+        }
+        let Some(config) = obj
+            .get_mut("generationConfig")
+            .and_then(|v| v.as_object_mut())
+        else {
+            return;
+        };
+        policy.keep(config, "temperature", policy.temperature);
+        policy.keep(config, "topP", policy.top_p);
+        policy.keep(config, "topK", policy.top_k.unwrap_or(true));
+        policy.keep(config, "presencePenalty", policy.penalties);
+        policy.keep(config, "frequencyPenalty", policy.penalties);
+        policy.keep(config, "thinkingConfig", policy.reasoning);
+        policy.clamp(config, "temperature", 0.0, 2.0);
+        policy.clamp(config, "topP", 0.0, 1.0);
+        policy.integer(config, "topK", 1);
+        policy.clamp(config, "presencePenalty", -2.0, 2.0);
+        policy.clamp(config, "frequencyPenalty", -2.0, 2.0);
+    }
+
+    fn stream_from_openai(&self, _model: &str, prompt_tokens: i64) -> OpenAIToGeminiStreamState {
+        let mut state = OpenAIToGeminiStreamState::new();
+        state.prompt_tokens = prompt_tokens;
+        state
+    }
+
+    fn stream_to_openai(&self, model: &str, prompt_tokens: i64) -> GeminiToOpenAIStreamState {
+        let mut state = GeminiToOpenAIStreamState::new(model);
+        state.prompt_tokens = prompt_tokens;
+        state
     }
 }
 
-#[allow(dead_code)]
 impl GeminiAdapter {
+    /// The adapter with the configured thinking budgets.
+    pub fn configured() -> Self {
+        Self {
+            reasoning_budgets: crate::config::current().reasoning_budgets.clone(),
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn id() -> &'static str {
         "gemini"
     }
 }
+
+/// Parse Gemini `usageMetadata`. Thinking tokens are reported separately from
+/// candidates but billed as output, so they are added to `completion_tokens`.
+/// `promptTokenCount` already includes the cached tokens.
+pub fn parse_gemini_usage(metadata: &Value) -> TokenUsage {
+    let prompt = metadata
+        .get("promptTokenCount")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let candidates = metadata
+        .get("candidatesTokenCount")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let cached = metadata
+        .get("cachedContentTokenCount")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let thoughts = metadata
+        .get("thoughtsTokenCount")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+
+    TokenUsage {
+        cache_read_tokens: cached,
+        reasoning_tokens: thoughts,
+        ..TokenUsage::new(prompt, candidates + thoughts)
+    }
+}
+
+fn openai_content_to_gemini_parts(content: &Value) -> Vec<Value> {
+    let mut parts_out = Vec::new();
+    match content {
+        Value::String(text) if !text.is_empty() => {
+            parts_out.push(serde_json::json!({ "text": text }));
+        }
+        Value::Array(parts) => {
+            for part in parts {
+                let part_type = part.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                match part_type {
+                    "text" => {
+                        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                            parts_out.push(serde_json::json!({ "text": text }));
+                        }
+                    }
+                    "image_url" => {
+                        let url = part
+                            .get("image_url")
+                            .and_then(|v| v.get("url"))
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("");
+                        if let Some((mime, data)) = parse_data_url(url) {
+                            parts_out.push(serde_json::json!({
+                                "inline_data": { "mime_type": mime, "data": data }
+                            }));
+                        } else if !url.is_empty() {
+                            parts_out
+                                .push(serde_json::json!({ "text": format!("[Image] {}", url) }));
+                        }
+                    }
+                    "file" => match Document::from_file_part(part) {
+                        Some(doc) => parts_out.push(serde_json::json!({
+                            "inline_data": { "mime_type": doc.media_type, "data": doc.data }
+                        })),
+                        None => parts_out.push(serde_json::json!({ "text": DOCUMENT_DROPPED })),
+                    },
+                    "input_audio" => match Audio::from_input_audio(part) {
+                        Some(audio) => parts_out.push(serde_json::json!({
+                            "inline_data": { "mime_type": audio.media_type, "data": audio.data }
+                        })),
+                        None => parts_out.push(serde_json::json!({ "text": AUDIO_DROPPED })),
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Value::Object(obj) => {
+            if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
+                parts_out.push(serde_json::json!({ "text": text }));
+            }
+        }
+        _ => {}
+    }
+    parts_out
+}
+
+fn is_gemini_thought_part(part: &Value) -> bool {
+    part.get("thought")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+fn map_openai_tools_to_gemini(tools: &Value) -> Option<Value> {
+    let tools_array = tools.as_array()?;
+    let mut declarations = Vec::new();
+
+    for tool in tools_array {
+        let tool_type = tool.get("type").and_then(|v| v.as_str()).unwrap_or("");
+        if !tool_type.eq_ignore_ascii_case("function") {
+            continue;
+        }
+        let function = tool.get("function")?;
+        let name = function.get("name")?.as_str()?;
+
+        let mut decl = serde_json::Map::new();
+        decl.insert("name".to_string(), Value::String(name.to_string()));
+        if let Some(desc) = function.get("description") {
+            decl.insert("description".to_string(), desc.clone());
+        }
+        if let Some(params) = function.get("parameters") {
+            decl.insert("parameters".to_string(), params.clone());
+        }
+        declarations.push(Value::Object(decl));
+    }
+
+    if declarations.is_empty() {
+        None
+    } else {
+        Some(Value::Array(vec![serde_json::json!({
+            "functionDeclarations": declarations
+        })]))
+    }
+}
+
+fn map_openai_tool_choice_to_gemini(choice: &Value) -> Option<Value> {
+    let mode = match choice {
+        Value::String(raw) => match raw.to_ascii_uppercase().as_str() {
+            "AUTO" | "auto" => Some(("AUTO".to_string(), None)),
+            "NONE" | "none" => Some(("NONE".to_string(), None)),
+            _ => None,
+        },
+        Value::Object(obj) => {
+            if obj.get("type").and_then(|v| v.as_str()) == Some("function") {
+                let name = obj
+                    .get("function")
+                    .and_then(|v| v.get("name"))
+                    .and_then(|v| v.as_str())?;
+                Some(("ANY".to_string(), Some(vec![name.to_string()])))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }?;
+
+    let mut config = serde_json::Map::new();
+    config.insert("mode".to_string(), Value::String(mode.0));
+    if let Some(names) = mode.1 {
+        config.insert(
+            "allowedFunctionNames".to_string(),
+            Value::Array(names.into_iter().map(Value::String).collect()),
+        );
+    }
+
+    Some(serde_json::json!({
+        "functionCallingConfig": Value::Object(config)
+    }))
+}
+
+fn map_gemini_tools_to_openai(tools: &Value) -> Option<Value> {
+    let tools_array = tools.as_array()?;
+    let mut mapped = Vec::new();
+
+    for tool in tools_array {
+        let declarations = tool
+            .get("functionDeclarations")
+            .or_else(|| tool.get("function_declarations"))
+            .and_then(|v| v.as_array())?;
+        for decl in declarations {
+            let name = decl.get("name").and_then(|v| v.as_str())?;
+            let mut func = serde_json::Map::new();
+            func.insert("name".to_string(), Value::String(name.to_string()));
+            if let Some(desc) = decl.get("description") {
+                func.insert("description".to_string(), desc.clone());
+            }
+            if let Some(params) = decl.get("parameters") {
+                func.insert("parameters".to_string(), params.clone());
+            }
+            let mut entry = serde_json::Map::new();
+            entry.insert("type".to_string(), Value::String("function".to_string()));
+            entry.insert("function".to_string(), Value::Object(func));
+            mapped.push(Value::Object(entry));
+        }
+    }
+
+    if mapped.is_empty() {
+        None
+    } else {
+        Some(Value::Array(mapped))
+    }
+}
+
+fn map_gemini_tool_config_to_openai(tool_config: &Value) -> Option<Value> {
+    let config = tool_config
+        .get("functionCallingConfig")
+        .or_else(|| tool_config.get("function_calling_config"))?;
+    let mode = config.get("mode").and_then(|v| v.as_str()).unwrap_or("");
+    match mode.to_ascii_uppercase().as_str() {
+        "AUTO" | "ANY" => Some(Value::String("auto".to_string())),
+        "NONE" => Some(Value::String("none".to_string())),
+        _ => {
+            let names = config
+                .get("allowedFunctionNames")
+                .or_else(|| config.get("allowed_function_names"))
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default();
+            let name = names.iter().filter_map(|v| v.as_str()).next()?;
+            Some(serde_json::json!({
+                "type": "function",
+                "function": { "name": name }
+            }))
+        }
+    }
+}
+
+fn map_openai_finish_reason_to_gemini(reason: &str) -> String {
+    match reason {
+        "stop" => "STOP",
+        "length" => "MAX_TOKENS",
+        "content_filter" => "SAFETY",
+        "tool_calls" | "function_call" => "STOP",
+        _ => "STOP",
+    }
+    .to_string()
+}
+
+fn map_gemini_finish_reason_to_openai(reason: &str) -> Value {
+    let mapped = match reason.to_ascii_uppercase().as_str() {
+        "STOP" => "stop",
+        "MAX_TOKENS" => "length",
+        "SAFETY" | "RECITATION" => "content_filter",
+        _ => "stop",
+    };
+    Value::String(mapped.to_string())
+}
+
+fn openai_tool_calls_to_gemini_parts(tool_calls: &Value) -> Vec<Value> {
+    let mut parts = Vec::new();
+    if let Some(calls) = tool_calls.as_array() {
+        for call in calls {
+            let name = call
+                .get("function")
+                .and_then(|v| v.get("name"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("tool");
+            let args_raw = call
+                .get("function")
+                .and_then(|v| v.get("arguments"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            let args = serde_json::from_str::<Value>(args_raw)
+                .unwrap_or(Value::String(args_raw.to_string()));
+            parts.push(serde_json::json!({
+                "functionCall": { "name": name, "args": args }
+            }));
+        }
+    }
+    parts
+}
+
+fn build_generation_config_from_openai(payload: &Value) -> Option<Value> {
+    let mut config = serde_json::Map::new();
+
+    if let Some(max_tokens) = payload
+        .get("max_tokens")
+        .or_else(|| payload.get("max_completion_tokens"))
+    {
+        config.insert("maxOutputTokens".to_string(), max_tokens.clone());
+    }
+    if let Some(temperature) = payload.get("temperature") {
+        config.insert("temperature".to_string(), temperature.clone());
+    }
+    if let Some(top_p) = payload.get("top_p") {
+        config.insert("topP".to_string(), top_p.clone());
+    }
+    if let Some(penalty) = payload.get("presence_penalty") {
+        config.insert("presencePenalty".to_string(), penalty.clone());
+    }
+    if let Some(penalty) = payload.get("frequency_penalty") {
+        config.insert("frequencyPenalty".to_string(), penalty.clone());
+    }
+    if let Some(seed) = payload.get("seed") {
+        config.insert("seed".to_string(), seed.clone());
+    }
+    if let Some(stop) = payload.get("stop") {
+        let mapped = match stop {
+            Value::String(text) => Value::Array(vec![Value::String(text.clone())]),
+            _ => stop.clone(),
+        };
+        config.insert("stopSequences".to_string(), mapped);
+    }
+    match payload
+        .get("response_format")
+        .and_then(|f| f.get("type"))
+        .and_then(|v| v.as_str())
+    {
+        Some("json_schema") => {
+            config.insert(
+                "responseMimeType".to_string(),
+                Value::String("application/json".to_string()),
+            );
+            if let Some(schema) = payload
+                .get("response_format")
+                .and_then(|f| f.get("json_schema"))
+                .and_then(|s| s.get("schema"))
+            {
+                config.insert("responseSchema".to_string(), schema::to_gemini(schema));
+            }
+        }
+        Some("json_object") => {
+            config.insert(
+                "responseMimeType".to_string(),
+                Value::String("application/json".to_string()),
+            );
+        }
+        _ => {}
+    }
+
+    if config.is_empty() {
+        None
+    } else {
+        Some(Value::Object(config))
+    }
+}
+
+fn map_generation_config_to_openai(config: &Value) -> serde_json::Map<String, Value> {
+    let mut mapped = serde_json::Map::new();
+    if let Some(max) = config.get("maxOutputTokens") {
+        mapped.insert("max_tokens".to_string(), max.clone());
+    }
+    if let Some(temp) = config.get("temperature") {
+        mapped.insert("temperature".to_string(), temp.clone());
+    }
+    if let Some(top_p) = config.get("topP") {
+        mapped.insert("top_p".to_string(), top_p.clone());
+    }
+    if let Some(stop) = config.get("stopSequences") {
+        mapped.insert("stop".to_string(), stop.clone());
+    }
+    if let Some(penalty) = config.get("presencePenalty") {
+        mapped.insert("presence_penalty".to_string(), penalty.clone());
+    }
+    if let Some(penalty) = config.get("frequencyPenalty") {
+        mapped.insert("frequency_penalty".to_string(), penalty.clone());
+    }
+    if let Some(seed) = config.get("seed") {
+        mapped.insert("seed".to_string(), seed.clone());
+    }
+    if config.get("responseMimeType").and_then(|v| v.as_str()) == Some("application/json") {
+        let format = match config.get("responseSchema") {
+            Some(schema) => serde_json::json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema::from_gemini(schema) }
+            }),
+            None => serde_json::json!({ "type": "json_object" }),
+        };
+        mapped.insert("response_format".to_string(), format);
+    }
+    mapped
+}
+
+/// Convert OpenAI request format to Gemini format
+fn convert_openai_to_gemini_request(payload: &Value, _model: &str) -> Value {
+    let mut gemini_request = serde_json::Map::new();
+    let mut contents = Vec::new();
+    let mut system_parts = Vec::new();
+
+    if let Some(messages) = payload.get("messages").and_then(|v| v.as_array()) {
+        for msg in messages {
+            let role = msg.get("role").and_then(|v| v.as_str()).unwrap_or("user");
+            if role.eq_ignore_ascii_case("system") {
+                if let Some(content) = msg.get("content") {
+                    system_parts.extend(
+                        openai_content_to_gemini_parts(content)
+                            .into_iter()
+                            .filter(|p| {
+                                p.get("text")
+                                    .and_then(|v| v.as_str())
+                                    .is_some_and(|t| !t.is_empty())
+                            }),
+                    );
+                }
+                continue;
+            }
+            let gem_role = if role.eq_ignore_ascii_case("assistant") {
+                "model"
+            } else {
+                "user"
+            };
+            let mut parts = Vec::new();
+            if let Some(content) = msg.get("content") {
+                parts.extend(openai_content_to_gemini_parts(content));
+            }
+            if let Some(tool_calls) = msg.get("tool_calls") {
+                parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
+            }
+            if let Some(function_call) = msg.get("function_call") {
+                let name = function_call
+                    .get("name")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("tool");
+                let args_raw = function_call
+                    .get("arguments")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("");
+                let args = serde_json::from_str::<Value>(args_raw)
+                    .unwrap_or(Value::String(args_raw.to_string()));
+                parts.push(serde_json::json!({ "functionCall": { "name": name, "args": args } }));
+            }
+
+            if parts.is_empty() {
+                parts.push(serde_json::json!({ "text": "" }));
+            }
+
+            contents.push(serde_json::json!({
+                "role": gem_role,
+                "parts": parts
+            }));
+        }
+    }
+
+    if !contents.is_empty() {
+        gemini_request.insert("contents".to_string(), Value::Array(contents));
+    }
+
+    if !system_parts.is_empty() {
+        gemini_request.insert(
+            "systemInstruction".to_string(),
+            serde_json::json!({ "parts": system_parts }),
+        );
+    }
+
+    let mut generation_config = build_generation_config_from_openai(payload);
+    if let Some(extra_config) = payload
+        .get("generationConfig")
+        .and_then(filter_generation_config)
+    {
+        match (&mut generation_config, extra_config) {
+            (Some(Value::Object(base)), Value::Object(extra)) => {
+                for (key, value) in extra {
+                    base.insert(key, value);
+                }
+            }
+            (_, extra) => {
+                generation_config = Some(extra);
+            }
+        }
+    }
+    if let Some(gen) = generation_config {
+        gemini_request.insert("generationConfig".to_string(), gen);
+    }
+    // Turned into a thinking budget by `normalize_request`
+    if let Some(effort) = payload.get("reasoning_effort") {
+        gemini_request.insert("reasoning_effort".to_string(), effort.clone());
+    }
+
+    if let Some(tools) = payload.get("tools") {
+        if let Some(mapped) = map_openai_tools_to_gemini(tools) {
+            gemini_request.insert("tools".to_string(), mapped);
+        }
+    }
+
+    if let Some(tool_choice) = payload.get("tool_choice") {
+        if let Some(mapped) = map_openai_tool_choice_to_gemini(tool_choice) {
+            gemini_request.insert("toolConfig".to_string(), mapped);
+        }
+    }
+
+    Value::Object(gemini_request)
+}
+
+/// Convert Gemini request format to OpenAI format. A thinking budget
+/// becomes the `reasoning_effort` it reads as in `budgets`.
+fn convert_gemini_to_openai_request(
+    payload: &Value,
+    model: &str,
+    budgets: &ReasoningBudgets,
+) -> Value {
+    let mut openai_request = serde_json::Map::new();
+    openai_request.insert("model".to_string(), Value::String(model.to_string()));
+
+    let mut messages = Vec::new();
+
+    // One system message per text part, in order
+    for part in payload
+        .get("systemInstruction")
+        .and_then(|v| v.get("parts"))
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+    {
+        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+            messages.push(serde_json::json!({
+                "role": "system",
+                "content": text
+            }));
+        }
+    }
+
+    if let Some(contents) = payload.get("contents").and_then(|v| v.as_array()) {
+        let mut tool_index = 0usize;
+        for content in contents {
+            let role_raw = content
+                .get("role")
+                .and_then(|v| v.as_str())
+                .unwrap_or("user");
+            let role = if role_raw.eq_ignore_ascii_case("model") {
+                "assistant"
+            } else {
+                "user"
+            };
+
+            let mut parts_out = Vec::new();
+            let mut tool_calls = Vec::new();
+            let mut tool_messages = Vec::new();
+
+            if let Some(parts) = content.get("parts").and_then(|v| v.as_array()) {
+                for part in parts {
+                    if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                        parts_out.push(serde_json::json!({ "type": "text", "text": text }));
+                    } else if let Some(inline) =
+                        part.get("inline_data").or_else(|| part.get("inlineData"))
+                    {
+                        let mime = inline
+                            .get("mime_type")
+                            .or_else(|| inline.get("mimeType"))
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("application/octet-stream");
+                        let data = inline.get("data").and_then(|v| v.as_str()).unwrap_or("");
+                        if mime.starts_with("audio/") {
+                            parts_out.push(
+                                Audio::new(mime, data)
+                                    .and_then(|audio| audio.to_input_audio())
+                                    .unwrap_or_else(|| marker_part(AUDIO_DROPPED)),
+                            );
+                        } else if !mime.starts_with("image/") {
+                            parts_out.push(
+                                Document::new(mime, data, None)
+                                    .map(|doc| doc.to_file_part())
+                                    .unwrap_or_else(|| marker_part(DOCUMENT_DROPPED)),
+                            );
+                        } else if !data.is_empty() {
+                            let url = format!("data:{};base64,{}", mime, data);
+                            parts_out.push(serde_json::json!({
+                                "type": "image_url",
+                                "image_url": { "url": url }
+                            }));
+                        }
+                    } else if let Some(file) =
+                        part.get("file_data").or_else(|| part.get("fileData"))
+                    {
+                        let uri = file
+                            .get("file_uri")
+                            .or_else(|| file.get("fileUri"))
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("");
+                        if !uri.is_empty() {
+                            parts_out.push(serde_json::json!({ "type": "text", "text": format!("[File] {}", uri) }));
+                        }
+                    } else if let Some(call) = part.get("functionCall") {
+                        let name = call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                        let args = call.get("args").cloned().unwrap_or(Value::Null);
+                        let args_raw =
+                            serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string());
+                        let id = format!("gemini_call_{}", tool_index);
+                        tool_index += 1;
+                        tool_calls.push(serde_json::json!({
+                            "id": id,
+                            "type": "function",
+                            "function": { "name": name, "arguments": args_raw }
+                        }));
+                    } else if let Some(response) = part.get("functionResponse") {
+                        let name = response
+                            .get("name")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("tool");
+                        let value = response.get("response").cloned().unwrap_or(Value::Null);
+                        let content_text = value.to_string();
+                        tool_messages.push(serde_json::json!({
+                            "role": "tool",
+                            "tool_call_id": name,
+                            "content": content_text
+                        }));
+                    }
+                }
+            }
+
+            let mut msg = serde_json::Map::new();
+            msg.insert("role".to_string(), Value::String(role.to_string()));
+            if parts_out.is_empty() {
+                msg.insert("content".to_string(), Value::String(String::new()));
+            } else {
+                msg.insert("content".to_string(), content_from_parts(parts_out));
+            }
+            if !tool_calls.is_empty() {
+                msg.insert("tool_calls".to_string(), Value::Array(tool_calls));
+            }
+            messages.push(Value::Object(msg));
+            messages.extend(tool_messages);
+        }
+    }
+
+    if !messages.is_empty() {
+        openai_request.insert("messages".to_string(), Value::Array(messages));
+    }
+
+    if let Some(gen_config) = payload.get("generationConfig") {
+        for (key, value) in map_generation_config_to_openai(gen_config) {
+            openai_request.insert(key, value);
+        }
+        let budget = gen_config["thinkingConfig"].get("thinkingBudget");
+        if let Some(effort) = effort_for_budget(budget, budgets) {
+            openai_request.insert("reasoning_effort".to_string(), effort);
+        }
+    }
+
+    if let Some(tools) = payload.get("tools") {
+        if let Some(mapped) = map_gemini_tools_to_openai(tools) {
+            openai_request.insert("tools".to_string(), mapped);
+        }
+    }
+    if let Some(tool_config) = payload.get("toolConfig") {
+        if let Some(mapped) = map_gemini_tool_config_to_openai(tool_config) {
+            openai_request.insert("tool_choice".to_string(), mapped);
+        }
+    }
+
+    Value::Object(openai_request)
+}
+
+/// Convert Gemini response format to OpenAI format
+fn convert_gemini_response_to_openai(response: &Value, model: &str) -> Value {
+    let id = format!("chatcmpl_gemini_{}", epoch_seconds());
+    let created = epoch_seconds();
+
+    let mut choices = Vec::new();
+    if let Some(candidates) = response.get("candidates").and_then(|v| v.as_array()) {
+        for (idx, candidate) in candidates.iter().enumerate() {
+            let finish_reason = candidate
+                .get("finishReason")
+                .and_then(|v| v.as_str())
+                .map(map_gemini_finish_reason_to_openai);
+
+            let (content, tool_calls, reasoning) = candidate
+                .get("content")
+                .map(|c| {
+                    let mut tool_calls = Vec::new();
+                    let mut parts_out = Vec::new();
+                    let mut thoughts = Vec::new();
+                    if let Some(parts) = c.get("parts").and_then(|v| v.as_array()) {
+                        for (tool_idx, part) in parts.iter().enumerate() {
+                            if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                                if is_gemini_thought_part(part) {
+                                    thoughts.push(text.to_string());
+                                } else {
+                                    parts_out
+                                        .push(serde_json::json!({ "type": "text", "text": text }));
+                                }
+                            } else if let Some(call) = part.get("functionCall") {
+                                let name =
+                                    call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                                let args = call.get("args").cloned().unwrap_or(Value::Null);
+                                let args_raw = serde_json::to_string(&args)
+                                    .unwrap_or_else(|_| "{}".to_string());
+                                tool_calls.push(serde_json::json!({
+                                    "id": format!("gemini_call_{}", tool_idx),
+                                    "type": "function",
+                                    "function": { "name": name, "arguments": args_raw }
+                                }));
+                            }
+                        }
+                    }
+                    let content_value = if parts_out.is_empty() {
+                        Value::String(String::new())
+                    } else {
+                        content_from_parts(parts_out)
+                    };
+                    let reasoning_value = if thoughts.is_empty() {
+                        None
+                    } else {
+                        Some(Value::String(thoughts.join("\n")))
+                    };
+                    (content_value, tool_calls, reasoning_value)
+                })
+                .unwrap_or_else(|| (Value::String(String::new()), Vec::new(), None));
+
+            let mut message = serde_json::Map::new();
+            message.insert("role".to_string(), Value::String("assistant".to_string()));
+            message.insert("content".to_string(), content);
+            if let Some(reasoning_content) = reasoning {
+                message.insert("reasoning_content".to_string(), reasoning_content);
+            }
+            if !tool_calls.is_empty() {
+                message.insert("tool_calls".to_string(), Value::Array(tool_calls));
+            }
+
+            choices.push(serde_json::json!({
+                "index": idx as i64,
+                "message": Value::Object(message),
+                "finish_reason": finish_reason
+            }));
+        }
+    }
+
+    let usage = response
+        .get("usageMetadata")
+        .map(|usage| {
+            let parsed = parse_gemini_usage(usage);
+            serde_json::json!({
+                "prompt_tokens": parsed.prompt_tokens,
+                "completion_tokens": parsed.completion_tokens,
+                "total_tokens": parsed.total(),
+                "prompt_tokens_details": { "cached_tokens": parsed.cache_read_tokens },
+                "completion_tokens_details": { "reasoning_tokens": parsed.reasoning_tokens }
+            })
+        })
+        .unwrap_or_else(|| {
+            serde_json::json!({
+                "prompt_tokens": 0,
+                "completion_tokens": 0,
+                "total_tokens": 0
+            })
+        });
+
+    serde_json::json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": choices,
+        "usage": usage
+    })
+}
+
+/// Convert OpenAI response format to Gemini format
+fn convert_openai_response_to_gemini(response: &Value, model: &str) -> Value {
+    let mut candidates = Vec::new();
+    if let Some(choices) = response.get("choices").and_then(|v| v.as_array()) {
+        for (idx, choice) in choices.iter().enumerate() {
+            let finish_reason = choice
+                .get("finish_reason")
+                .and_then(|v| v.as_str())
+                .map(map_openai_finish_reason_to_gemini);
+
+            let message = choice.get("message").or_else(|| choice.get("delta"));
+            let mut parts = Vec::new();
+            if let Some(msg) = message {
+                if let Some(reasoning) = msg.get("reasoning_content") {
+                    if let Some(text) = reasoning.as_str() {
+                        parts.push(serde_json::json!({ "text": text, "thought": true }));
+                    } else if !reasoning.is_null() {
+                        parts.push(serde_json::json!({
+                            "text": reasoning.to_string(),
+                            "thought": true
+                        }));
+                    }
+                }
+                if let Some(content) = msg.get("content") {
+                    parts.extend(openai_content_to_gemini_parts(content));
+                }
+                if let Some(tool_calls) = msg.get("tool_calls") {
+                    parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
+                }
+            }
+            if parts.is_empty() {
+                parts.push(serde_json::json!({ "text": "" }));
+            }
+
+            candidates.push(serde_json::json!({
+                "index": idx as i64,
+                "content": { "role": "model", "parts": parts },
+                "finishReason": finish_reason
+            }));
+        }
+    }
+
+    let usage = response.get("usage").map(|usage| {
+        let prompt = usage
+            .get("prompt_tokens")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        let completion = usage
+            .get("completion_tokens")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        serde_json::json!({
+            "promptTokenCount": prompt,
+            "candidatesTokenCount": completion,
+            "totalTokenCount": prompt + completion
+        })
+    });
+
+    let mut response_obj = serde_json::Map::new();
+    response_obj.insert("candidates".to_string(), Value::Array(candidates));
+    response_obj.insert("modelVersion".to_string(), Value::String(model.to_string()));
+    if let Some(usage_metadata) = usage {
+        response_obj.insert("usageMetadata".to_string(), usage_metadata);
+    }
+
+    Value::Object(response_obj)
+}
+
+pub struct GeminiToOpenAIStreamState {
+    id: String,
+    created: i64,
+    model: String,
+    sent_role: bool,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cache_read_tokens: i64,
+    reasoning_tokens: i64,
+    /// Whether the counts above came from the provider rather than estimates
+    prompt_reported: bool,
+    completion_reported: bool,
+    finished: bool,
+}
+
+impl GeminiToOpenAIStreamState {
+    fn new(model: &str) -> Self {
+        let now = epoch_seconds();
+        Self {
+            id: format!("chatcmpl_gemini_{}", now),
+            created: now,
+            model: model.to_string(),
+            sent_role: false,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cache_read_tokens: 0,
+            reasoning_tokens: 0,
+            prompt_reported: false,
+            completion_reported: false,
+            finished: false,
+        }
+    }
+
+    /// A chunk of this stream, with the usage so far if `include_usage`.
+    fn chunk(&self, delta: Value, finish_reason: Option<Value>, include_usage: bool) -> Value {
+        let usage = include_usage.then(|| {
+            serde_json::json!({
+                "prompt_tokens": self.prompt_tokens,
+                "completion_tokens": self.completion_tokens,
+                "total_tokens": self.prompt_tokens + self.completion_tokens,
+                "prompt_tokens_details": { "cached_tokens": self.cache_read_tokens },
+                "completion_tokens_details": { "reasoning_tokens": self.reasoning_tokens }
+            })
+        });
+        stream_chunk(
+            &self.id,
+            self.created,
+            &self.model,
+            delta,
+            finish_reason,
+            usage,
+        )
+    }
+
+    /// Count streamed text until the provider reports output tokens.
+    fn add_estimated_output(&mut self, text: &str, reasoning: bool) {
+        if !self.completion_reported {
+            let tokens = estimate_tokens(text);
+            self.completion_tokens += tokens;
+            if reasoning {
+                self.reasoning_tokens += tokens;
+            }
+        }
+    }
+}
+
+impl StreamMap for GeminiToOpenAIStreamState {
+    fn map_event(&mut self, event: &Value) -> Vec<Value> {
+        convert_gemini_event_to_openai_chunks(event, self)
+    }
+
+    fn usage(&self) -> TokenUsage {
+        TokenUsage {
+            cache_read_tokens: self.cache_read_tokens,
+            reasoning_tokens: self.reasoning_tokens,
+            prompt_estimated: !self.prompt_reported,
+            completion_estimated: !self.completion_reported,
+            ..TokenUsage::new(self.prompt_tokens, self.completion_tokens)
+        }
+    }
+}
+
+fn convert_gemini_event_to_openai_chunks(
+    event: &Value,
+    state: &mut GeminiToOpenAIStreamState,
+) -> Vec<Value> {
+    let mut out = Vec::new();
+
+    if let Some(metadata) = event.get("usageMetadata") {
+        let usage = parse_gemini_usage(metadata);
+        if usage.prompt_tokens > 0 {
+            state.prompt_tokens = usage.prompt_tokens;
+            state.prompt_reported = true;
+        }
+        if usage.completion_tokens > 0 {
+            state.completion_tokens = usage.completion_tokens;
+            state.completion_reported = true;
+            state.reasoning_tokens = usage.reasoning_tokens;
+        }
+        if usage.cache_read_tokens > 0 {
+            state.cache_read_tokens = usage.cache_read_tokens;
+        }
+        if usage.reasoning_tokens > 0 {
+            state.reasoning_tokens = usage.reasoning_tokens;
+        }
+    }
+
+    if let Some(candidates) = event.get("candidates").and_then(|v| v.as_array()) {
+        for candidate in candidates {
+            if !state.sent_role {
+                out.push(state.chunk(serde_json::json!({ "role": "assistant" }), None, false));
+                state.sent_role = true;
+            }
+
+            if let Some(content) = candidate.get("content") {
+                if let Some(parts) = content.get("parts").and_then(|v| v.as_array()) {
+                    for part in parts {
+                        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
+                            state.add_estimated_output(text, is_gemini_thought_part(part));
+                            let delta = if is_gemini_thought_part(part) {
+                                serde_json::json!({ "reasoning_content": text })
+                            } else {
+                                serde_json::json!({ "content": text })
+                            };
+                            out.push(state.chunk(delta, None, false));
+                        } else if let Some(call) = part.get("functionCall") {
+                            let name = call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
+                            let args = call.get("args").cloned().unwrap_or(Value::Null);
+                            let args_raw =
+                                serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string());
+                            let delta = serde_json::json!({
+                                "tool_calls": [{
+                                    "index": 0,
+                                    "id": "gemini_call",
+                                    "type": "function",
+                                    "function": { "name": name, "arguments": args_raw }
+                                }]
+                            });
+                            out.push(state.chunk(delta, None, false));
+                        }
+                    }
+                }
+            }
+
+            let finish_reason = candidate
+                .get("finishReason")
+                .and_then(|v| v.as_str())
+                .map(map_gemini_finish_reason_to_openai);
+            if finish_reason.is_some() {
+                state.finished = true;
+                out.push(state.chunk(serde_json::json!({}), finish_reason, true));
+            }
+        }
+    }
+
+    out
+}
+
+pub struct OpenAIToGeminiStreamState {
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cache_read_tokens: i64,
+    reasoning_tokens: i64,
+    /// Whether the counts above came from the provider rather than estimates
+    prompt_reported: bool,
+    completion_reported: bool,
+}
+
+impl OpenAIToGeminiStreamState {
+    fn new() -> Self {
+        Self {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cache_read_tokens: 0,
+            reasoning_tokens: 0,
+            prompt_reported: false,
+            completion_reported: false,
+        }
+    }
+
+    /// Count streamed text until the provider reports output tokens.
+    fn add_estimated_output(&mut self, text: &str, reasoning: bool) {
+        if !self.completion_reported {
+            let tokens = estimate_tokens(text);
+            self.completion_tokens += tokens;
+            if reasoning {
+                self.reasoning_tokens += tokens;
+            }
+        }
+    }
+}
+
+impl StreamMap for OpenAIToGeminiStreamState {
+    fn map_event(&mut self, chunk: &Value) -> Vec<Value> {
+        convert_openai_chunk_to_gemini(chunk, self)
+    }
+
+    fn usage(&self) -> TokenUsage {
+        TokenUsage {
+            cache_read_tokens: self.cache_read_tokens,
+            reasoning_tokens: self.reasoning_tokens,
+            prompt_estimated: !self.prompt_reported,
+            completion_estimated: !self.completion_reported,
+            ..TokenUsage::new(self.prompt_tokens, self.completion_tokens)
+        }
+    }
+}
+
+fn convert_openai_chunk_to_gemini(
+    chunk: &Value,
+    state: &mut OpenAIToGeminiStreamState,
+) -> Vec<Value> {
+    let mut out = Vec::new();
+
+    if let Some(usage) = chunk.get("usage") {
+        if let Some(prompt) = usage.get("prompt_tokens").and_then(|v| v.as_i64()) {
+            state.prompt_tokens = prompt;
+            state.prompt_reported = true;
+        }
+        if let Some(completion) = usage.get("completion_tokens").and_then(|v| v.as_i64()) {
+            state.completion_tokens = completion;
+            state.completion_reported = true;
+            state.reasoning_tokens = 0;
+        }
+        let details = parse_openai_usage(usage);
+        if details.cache_read_tokens > 0 {
+            state.cache_read_tokens = details.cache_read_tokens;
+        }
+        if details.reasoning_tokens > 0 {
+            state.reasoning_tokens = details.reasoning_tokens;
+        }
+    }
+
+    if let Some(choices) = chunk.get("choices").and_then(|v| v.as_array()) {
+        for choice in choices {
+            let delta = choice.get("delta").unwrap_or(&Value::Null);
+            let mut parts = Vec::new();
+
+            if let Some(text) = delta.get("content").and_then(|v| v.as_str()) {
+                state.add_estimated_output(text, false);
+                parts.push(serde_json::json!({ "text": text }));
+            }
+            if let Some(reasoning) = delta.get("reasoning_content") {
+                if let Some(text) = reasoning.as_str() {
+                    state.add_estimated_output(text, true);
+                    parts.push(serde_json::json!({ "text": text, "thought": true }));
+                } else if !reasoning.is_null() {
+                    parts.push(serde_json::json!({
+                        "text": reasoning.to_string(),
+                        "thought": true
+                    }));
+                }
+            }
+            if let Some(tool_calls) = delta.get("tool_calls") {
+                parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
+            }
+
+            let finish_reason = choice
+                .get("finish_reason")
+                .and_then(|v| v.as_str())
+                .map(map_openai_finish_reason_to_gemini);
+
+            if parts.is_empty() && finish_reason.is_none() && chunk.get("usage").is_none() {
+                continue;
+            }
+
+            let mut candidate = serde_json::Map::new();
+            candidate.insert(
+                "content".to_string(),
+                serde_json::json!({ "role": "model", "parts": parts }),
+            );
+            if let Some(reason) = finish_reason {
+                candidate.insert("finishReason".to_string(), Value::String(reason));
+            }
+
+            let mut event = serde_json::Map::new();
+            event.insert(
+                "candidates".to_string(),
+                Value::Array(vec![Value::Object(candidate)]),
+            );
+
+            if state.prompt_tokens > 0 || state.completion_tokens > 0 {
+                event.insert(
+                    "usageMetadata".to_string(),
+                    serde_json::json!({
+                        "promptTokenCount": state.prompt_tokens,
+                        "candidatesTokenCount": state.completion_tokens,
+                        "totalTokenCount": state.prompt_tokens + state.completion_tokens
+                    }),
+                );
+            }
+
+            out.push(Value::Object(event));
+        }
+    }
+
+    out
+}
@@ -1,12 +1,478 @@
+//! Conversions between the chat formats of the providers.
+//!
+//! OpenAI chat completions is the pivot: each adapter maps requests,
+//! responses and stream events of its provider to and from OpenAI's
+//! format, and a conversion between two other providers goes through it.
+//! Adapters only reshape JSON; `forward::handlers` sends the requests and
+//! reads the streams.
+
 pub mod anthropic;
 pub mod gemini;
 pub mod openai;
+pub mod params;
+pub mod schema;
+
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde_json::Value;
 
-// 这些适配器为将来的请求/响应转换功能保留
-#[allow(dead_code)]
+use crate::forward::context::TokenUsage;
+
+pub use anthropic::AnthropicAdapter;
+pub use gemini::GeminiAdapter;
+pub use params::ParamPolicy;
+
+/// A provider's chat format, mapped to and from OpenAI's.
 pub trait ModelAdapter {
-    fn map_chat_request(&self, input: Value) -> Value;
-    fn map_chat_response(&self, output: Value) -> Value;
+    /// Maps the provider's stream events to OpenAI chunks
+    type StreamToOpenAI: StreamMap;
+    /// Maps OpenAI chunks to the provider's stream events
+    type StreamFromOpenAI: StreamMap;
+
+    /// An OpenAI chat request in the provider's format, for `model`.
+    fn request_from_openai(&self, request: &Value, model: &str) -> Value;
+    /// A request in the provider's format as an OpenAI chat request.
+    fn request_to_openai(&self, request: &Value, model: &str) -> Value;
+    /// An OpenAI chat completion in the provider's format.
+    fn response_from_openai(&self, response: &Value, model: &str) -> Value;
+    /// A response in the provider's format as an OpenAI chat completion.
+    fn response_to_openai(&self, response: &Value, model: &str) -> Value;
+    /// Fits a request in the provider's format to what `policy` allows.
+    fn normalize_request(&self, request: &mut Value, policy: &ParamPolicy);
+    /// `prompt_tokens` is the estimate kept until the stream reports usage.
+    fn stream_from_openai(&self, model: &str, prompt_tokens: i64) -> Self::StreamFromOpenAI;
+    fn stream_to_openai(&self, model: &str, prompt_tokens: i64) -> Self::StreamToOpenAI;
+}
+
+/// Maps the events of one stream, keeping what it needs between them.
+pub trait StreamMap: Send {
+    fn map_event(&mut self, event: &Value) -> Vec<Value>;
+    /// Events still owed once the upstream stream has ended. Later calls
+    /// return nothing.
+    fn finish(&mut self) -> Vec<Value> {
+        Vec::new()
+    }
+    /// Usage so far, reported counts preferred over estimates.
+    fn usage(&self) -> TokenUsage;
+}
+
+/// `request` in `from`'s format converted to `to`'s.
+pub fn convert_request(
+    from: &impl ModelAdapter,
+    to: &impl ModelAdapter,
+    request: &Value,
+    model: &str,
+) -> Value {
+    to.request_from_openai(&from.request_to_openai(request, model), model)
+}
+
+/// `response` in `from`'s format converted to `to`'s.
+pub fn convert_response(
+    from: &impl ModelAdapter,
+    to: &impl ModelAdapter,
+    response: &Value,
+    model: &str,
+) -> Value {
+    to.response_from_openai(&from.response_to_openai(response, model), model)
+}
+
+/// Maps a stream of `from`'s events to `to`'s. Its usage is counted on
+/// the `from` side, which sees what the upstream reports.
+pub fn convert_stream<F: ModelAdapter, T: ModelAdapter>(
+    from: &F,
+    to: &T,
+    model: &str,
+    prompt_tokens: i64,
+) -> StreamChain<F::StreamToOpenAI, T::StreamFromOpenAI> {
+    StreamChain {
+        to_openai: from.stream_to_openai(model, prompt_tokens),
+        from_openai: to.stream_from_openai(model, prompt_tokens),
+    }
+}
+
+/// Two stream maps meeting in OpenAI chunks.
+pub struct StreamChain<A, B> {
+    to_openai: A,
+    from_openai: B,
+}
+
+impl<A: StreamMap, B: StreamMap> StreamMap for StreamChain<A, B> {
+    fn map_event(&mut self, event: &Value) -> Vec<Value> {
+        let chunks = self.to_openai.map_event(event);
+        chunks
+            .iter()
+            .flat_map(|chunk| self.from_openai.map_event(chunk))
+            .collect()
+    }
+
+    fn finish(&mut self) -> Vec<Value> {
+        let chunks = self.to_openai.finish();
+        let mut events: Vec<Value> = chunks
+            .iter()
+            .flat_map(|chunk| self.from_openai.map_event(chunk))
+            .collect();
+        events.extend(self.from_openai.finish());
+        events
+    }
+
+    fn usage(&self) -> TokenUsage {
+        self.to_openai.usage()
+    }
+}
+
+/// Audio parts of a converted request that were replaced by a marker
+/// because its target takes no such audio.
+pub fn dropped_audio(request: &Value) -> usize {
+    match request {
+        Value::String(text) => usize::from(text == openai::AUDIO_DROPPED),
+        Value::Array(items) => items.iter().map(dropped_audio).sum(),
+        Value::Object(map) => map.values().map(dropped_audio).sum(),
+        _ => 0,
+    }
+}
+
+fn epoch_seconds() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::OpenAIAdapter;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            (
+                $name,
+                include_str!(concat!("../../tests/fixtures/adapters/", $name, ".json")),
+            )
+        };
+    }
+
+    /// A golden transcript, as (name, JSON), and the conversion it records.
+    type Case<'a> = (
+        (&'static str, &'static str),
+        Box<dyn Fn(&Value) -> Value + 'a>,
+    );
+
+    /// Zeroes timestamps and the ids made from them.
+    fn scrub(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, item) in map.iter_mut() {
+                    match (key.as_str(), &item) {
+                        ("created", Value::Number(_)) => *item = Value::from(0),
+                        ("id", Value::String(id)) => {
+                            let stem = id.trim_end_matches(|c: char| c.is_ascii_digit());
+                            if id.len() - stem.len() >= 9 && stem.ends_with('_') {
+                                *item = Value::String(format!("{}0", stem));
+                            }
+                        }
+                        _ => scrub(item),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(scrub),
+            _ => {}
+        }
+    }
+
+    fn stream(mut map: impl StreamMap, events: &Value) -> Value {
+        let mut out: Vec<Value> = events
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|event| map.map_event(event))
+            .collect();
+        out.extend(map.finish());
+        Value::Array(out)
+    }
+
+    #[test]
+    fn test_conversions_match_golden_transcripts() {
+        let anthropic = AnthropicAdapter::default();
+        let claude = "claude-sonnet-4";
+        let gemini = "gemini-2.5-pro";
+        let cases: Vec<Case> = vec![
+            (
+                fixture!("anthropic-request-from-openai"),
+                Box::new(|v| anthropic.request_from_openai(v, claude)),
+            ),
+            (
+                fixture!("anthropic-request-to-openai"),
+                Box::new(|v| anthropic.request_to_openai(v, "gpt-4o")),
+            ),
+            (
+                fixture!("anthropic-response-from-openai"),
+                Box::new(|v| anthropic.response_from_openai(v, claude)),
+            ),
+            (
+                fixture!("anthropic-response-to-openai"),
+                Box::new(|v| anthropic.response_to_openai(v, claude)),
+            ),
+            (
+                fixture!("anthropic-stream-from-openai"),
+                Box::new(|v| stream(anthropic.stream_from_openai(claude, 50), v)),
+            ),
+            (
+                fixture!("anthropic-stream-to-openai"),
+                Box::new(|v| stream(anthropic.stream_to_openai(claude, 50), v)),
+            ),
+            (
+                fixture!("gemini-request-from-openai"),
+                Box::new(|v| GeminiAdapter::default().request_from_openai(v, gemini)),
+            ),
+            (
+                fixture!("gemini-request-to-openai"),
+                Box::new(|v| GeminiAdapter::default().request_to_openai(v, "gpt-4o")),
+            ),
+            (
+                fixture!("gemini-response-from-openai"),
+                Box::new(|v| GeminiAdapter::default().response_from_openai(v, gemini)),
+            ),
+            (
+                fixture!("gemini-response-to-openai"),
+                Box::new(|v| GeminiAdapter::default().response_to_openai(v, gemini)),
+            ),
+            (
+                fixture!("gemini-stream-from-openai"),
+                Box::new(|v| stream(GeminiAdapter::default().stream_from_openai(gemini, 50), v)),
+            ),
+            (
+                fixture!("gemini-stream-to-openai"),
+                Box::new(|v| stream(GeminiAdapter::default().stream_to_openai(gemini, 50), v)),
+            ),
+        ];
+
+        for ((name, fixture), convert) in cases {
+            let golden: Value = serde_json::from_str(fixture).unwrap();
+            let mut output = convert(&golden["input"]);
+            scrub(&mut output);
+            assert_eq!(output, golden["output"], "{}", name);
+        }
+
+        // Converting between two providers goes through OpenAI's format, and
+        // OpenAI's own adapter passes everything on as it is.
+        let (_, fixture) = fixture!("gemini-request-from-openai");
+        let golden: Value = serde_json::from_str(fixture).unwrap();
+        assert_eq!(
+            convert_request(
+                &OpenAIAdapter,
+                &GeminiAdapter::default(),
+                &golden["input"],
+                gemini
+            ),
+            golden["output"]
+        );
+        let (_, fixture) = fixture!("anthropic-stream-to-openai");
+        let golden: Value = serde_json::from_str(fixture).unwrap();
+        let mut output = stream(
+            convert_stream(&AnthropicAdapter::default(), &OpenAIAdapter, claude, 50),
+            &golden["input"],
+        );
+        scrub(&mut output);
+        assert_eq!(output, golden["output"]);
+    }
+
+    #[test]
+    fn test_documents_round_trip_between_providers() {
+        let pdf = "JVBERi0xLjQKJcOkw7zDtsOfCjEgMCBvYmoKPDwvVHlwZS9DYXRhbG9nPj4KZW5kb2JqCnRyYWlsZXIKPDwvUm9vdCAxIDAgUj4+CiUlRU9G";
+        let file = serde_json::json!({
+            "type": "file",
+            "file": {"filename": "report.pdf", "file_data": format!("data:application/pdf;base64,{}", pdf)}
+        });
+        let request = serde_json::json!({"messages": [{"role": "user", "content": [
+            {"type": "text", "text": "Summarize this."},
+            file.clone(),
+            {"type": "file", "file": {"file_id": "file-abc123"}}
+        ]}]});
+        let anthropic = AnthropicAdapter::default();
+
+        let claude = anthropic.request_from_openai(&request, "claude-sonnet-4");
+        let blocks = &claude["messages"][0]["content"];
+        assert_eq!(
+            blocks[1],
+            serde_json::json!({
+                "type": "document",
+                "title": "report.pdf",
+                "source": {"type": "base64", "media_type": "application/pdf", "data": pdf}
+            })
+        );
+        assert_eq!(blocks[2]["text"], openai::DOCUMENT_DROPPED);
+        let back = anthropic.request_to_openai(&claude, "gpt-4o");
+        assert_eq!(back["messages"][0]["content"][1], file);
+
+        let gemini = GeminiAdapter::default().request_from_openai(&request, "gemini-2.5-pro");
+        let parts = &gemini["contents"][0]["parts"];
+        assert_eq!(
+            parts[1],
+            serde_json::json!({"inline_data": {"mime_type": "application/pdf", "data": pdf}})
+        );
+        assert_eq!(parts[2]["text"], openai::DOCUMENT_DROPPED);
+
+        // Gemini to Anthropic goes through OpenAI's `file` part; the name is lost
+        let claude = convert_request(
+            &GeminiAdapter::default(),
+            &anthropic,
+            &gemini,
+            "claude-sonnet-4",
+        );
+        let document = &claude["messages"][0]["content"][1];
+        assert_eq!(document["type"], "document");
+        assert_eq!(document["source"]["data"], pdf);
+
+        let text = serde_json::json!({"messages": [{"role": "user", "content": [
+            {"type": "document", "source": {"type": "text", "media_type": "text/plain", "data": "Notes"}},
+            {"type": "document", "source": {"type": "url", "url": "https://example.com/a.pdf"}}
+        ]}]});
+        let back = anthropic.request_to_openai(&text, "gpt-4o");
+        assert_eq!(
+            back["messages"][0]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "Notes"},
+                {"type": "text", "text": openai::DOCUMENT_DROPPED}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_audio_is_converted_or_marked() {
+        let audio = serde_json::json!({"type": "input_audio", "input_audio": {"data": "UklGRg==", "format": "wav"}});
+        let request = serde_json::json!({"messages": [{"role": "user", "content": [
+            {"type": "text", "text": "Transcribe this."},
+            audio.clone()
+        ]}]});
+
+        let gemini = GeminiAdapter::default().request_from_openai(&request, "gemini-2.5-pro");
+        assert_eq!(
+            gemini["contents"][0]["parts"][1],
+            serde_json::json!({"inline_data": {"mime_type": "audio/wav", "data": "UklGRg=="}})
+        );
+        assert_eq!(dropped_audio(&gemini), 0);
+        let back = GeminiAdapter::default().request_to_openai(&gemini, "gpt-4o-audio-preview");
+        assert_eq!(back["messages"][0]["content"][1], audio);
+
+        let claude = AnthropicAdapter::default().request_from_openai(&request, "claude-sonnet-4");
+        assert_eq!(
+            claude["messages"][0]["content"][1]["text"],
+            openai::AUDIO_DROPPED
+        );
+        assert_eq!(dropped_audio(&claude), 1);
+
+        // OpenAI takes WAV and MP3 only
+        let ogg = serde_json::json!({"contents": [{"role": "user", "parts": [
+            {"inlineData": {"mimeType": "audio/ogg", "data": "T2dnUw=="}},
+            {"inlineData": {"mimeType": "audio/mpeg", "data": "SUQz"}}
+        ]}]});
+        let openai = GeminiAdapter::default().request_to_openai(&ogg, "gpt-4o-audio-preview");
+        assert_eq!(
+            openai["messages"][0]["content"],
+            serde_json::json!([
+                {"type": "text", "text": openai::AUDIO_DROPPED},
+                {"type": "input_audio", "input_audio": {"data": "SUQz", "format": "mp3"}}
+            ])
+        );
+        assert_eq!(dropped_audio(&openai), 1);
+    }
+
+    #[test]
+    fn test_structured_output_reaches_every_provider() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}, "temp": {"type": ["number", "null"]}},
+            "required": ["city", "temp"],
+            "additionalProperties": false
+        });
+        let request = serde_json::json!({
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "response_format": {"type": "json_schema", "json_schema": {"name": "weather", "strict": true, "schema": schema}}
+        });
+        let parsed =
+            |content: &Value| -> Value { serde_json::from_str(content.as_str().unwrap()).unwrap() };
+
+        let gemini = GeminiAdapter::default().request_from_openai(&request, "gemini-2.5-pro");
+        let config = &gemini["generationConfig"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(
+            config["responseSchema"]["properties"]["temp"],
+            serde_json::json!({"type": "NUMBER", "nullable": true})
+        );
+        let back = GeminiAdapter::default().request_to_openai(&gemini, "gpt-4o");
+        let format = &back["response_format"];
+        assert_eq!(format["type"], "json_schema");
+        assert_eq!(
+            format["json_schema"]["schema"]["required"],
+            schema["required"]
+        );
+        let reply = GeminiAdapter::default().response_to_openai(
+            &serde_json::json!({"candidates": [{"content": {"role": "model", "parts": [
+                {"text": "{\"city\": \"Paris\", \"temp\": 18}"}
+            ]}, "finishReason": "STOP"}]}),
+            "gemini-2.5-pro",
+        );
+        assert_eq!(
+            parsed(&reply["choices"][0]["message"]["content"])["city"],
+            "Paris"
+        );
+
+        let anthropic = AnthropicAdapter::default();
+        let mut thinking = request.clone();
+        thinking["reasoning_effort"] = serde_json::json!("high");
+        thinking["max_tokens"] = serde_json::json!(4096);
+        let mut claude = anthropic.request_from_openai(&thinking, "claude-sonnet-4");
+        assert_eq!(claude["tools"][0]["name"], "structured_output");
+        assert_eq!(claude["tools"][0]["input_schema"], schema);
+        assert_eq!(
+            claude["tool_choice"],
+            serde_json::json!({"type": "tool", "name": "structured_output"})
+        );
+        let policy = ParamPolicy::new("claude-sonnet-4", &Default::default(), Default::default());
+        anthropic.normalize_request(&mut claude, &policy);
+        assert!(claude.get("thinking").is_none());
+
+        let reply = anthropic.response_to_openai(
+            &serde_json::json!({
+                "id": "msg_1",
+                "content": [{"type": "tool_use", "id": "toolu_1", "name": "structured_output", "input": {"city": "Paris", "temp": null}}],
+                "stop_reason": "tool_use"
+            }),
+            "claude-sonnet-4",
+        );
+        let choice = &reply["choices"][0];
+        assert_eq!(choice["finish_reason"], "stop");
+        assert!(choice["message"].get("tool_calls").is_none());
+        assert_eq!(
+            parsed(&choice["message"]["content"]),
+            serde_json::json!({"city": "Paris", "temp": null})
+        );
+
+        let events = serde_json::json!([
+            {"type": "message_start", "message": {"id": "msg_2", "usage": {"input_tokens": 20, "output_tokens": 1}}},
+            {"type": "content_block_start", "index": 0, "content_block": {"type": "tool_use", "id": "toolu_2", "name": "structured_output", "input": {}}},
+            {"type": "content_block_delta", "index": 0, "delta": {"type": "input_json_delta", "partial_json": "{\"city\": \"Pa"}},
+            {"type": "content_block_delta", "index": 0, "delta": {"type": "input_json_delta", "partial_json": "ris\", \"temp\": 18}"}},
+            {"type": "content_block_stop", "index": 0},
+            {"type": "message_delta", "delta": {"stop_reason": "tool_use"}, "usage": {"output_tokens": 12}},
+            {"type": "message_stop"}
+        ]);
+        let chunks = stream(anthropic.stream_to_openai("claude-sonnet-4", 20), &events);
+        let chunks = chunks.as_array().unwrap();
+        assert!(chunks
+            .iter()
+            .all(|c| c["choices"][0]["delta"].get("tool_calls").is_none()));
+        let content: String = chunks
+            .iter()
+            .filter_map(|c| c["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(
+            parsed(&Value::String(content)),
+            serde_json::json!({"city": "Paris", "temp": 18})
+        );
+        assert!(chunks
+            .iter()
+            .any(|c| c["choices"][0]["finish_reason"] == "stop"));
+    }
 }
@@ -1,15 +1,58 @@
-use super::ModelAdapter;
+//! OpenAI chat completions, the format the other adapters map to and from;
+//! mapping it to itself leaves everything as it is.
+
 use serde_json::Value;
 
-#[allow(dead_code)]
+use super::{ModelAdapter, ParamPolicy, StreamMap};
+use crate::forward::context::{estimate_tokens, TokenUsage};
+
 pub struct OpenAIAdapter;
 
 impl ModelAdapter for OpenAIAdapter {
-    fn map_chat_request(&self, input: Value) -> Value {
-        input
+    type StreamToOpenAI = OpenAIStreamState;
+    type StreamFromOpenAI = OpenAIStreamState;
+
+    fn request_from_openai(&self, request: &Value, _model: &str) -> Value {
+        request.clone()
+    }
+
+    fn request_to_openai(&self, request: &Value, _model: &str) -> Value {
+        request.clone()
+    }
+
+    fn response_from_openai(&self, response: &Value, _model: &str) -> Value {
+        response.clone()
     }
-    fn map_chat_response(&self, output: Value) -> Value {
-        output
+
+    fn response_to_openai(&self, response: &Value, _model: &str) -> Value {
+        response.clone()
+    }
+
+    fn normalize_request(&self, request: &mut Value, policy: &ParamPolicy) {
+        let Some(obj) = request.as_object_mut() else {
+            return;
+        };
+        policy.keep(obj, "temperature", policy.temperature);
+        policy.keep(obj, "top_p", policy.top_p);
+        policy.keep(obj, "top_k", policy.top_k.unwrap_or(false));
+        policy.keep(obj, "presence_penalty", policy.penalties);
+        policy.keep(obj, "frequency_penalty", policy.penalties);
+        policy.keep(obj, "reasoning_effort", policy.reasoning);
+        policy.clamp(obj, "temperature", 0.0, 2.0);
+        policy.clamp(obj, "top_p", 0.0, 1.0);
+        policy.integer(obj, "top_k", 1);
+        policy.clamp(obj, "presence_penalty", -2.0, 2.0);
+        policy.clamp(obj, "frequency_penalty", -2.0, 2.0);
+    }
+
+    fn stream_from_openai(&self, _model: &str, prompt_tokens: i64) -> OpenAIStreamState {
+        OpenAIStreamState {
+            usage: TokenUsage::estimated(prompt_tokens),
+        }
+    }
+
+    fn stream_to_openai(&self, model: &str, prompt_tokens: i64) -> OpenAIStreamState {
+        self.stream_from_openai(model, prompt_tokens)
     }
 }
 
@@ -19,3 +62,227 @@ impl OpenAIAdapter {
         "openai"
     }
 }
+
+/// Passes OpenAI chunks on, counting their usage.
+pub struct OpenAIStreamState {
+    usage: TokenUsage,
+}
+
+impl StreamMap for OpenAIStreamState {
+    fn map_event(&mut self, chunk: &Value) -> Vec<Value> {
+        for choice in chunk
+            .get("choices")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let delta = choice.get("delta").unwrap_or(&Value::Null);
+            if let Some(text) = delta.get("content").and_then(|v| v.as_str()) {
+                self.usage.add_estimated_output(estimate_tokens(text));
+            }
+            if let Some(text) = delta.get("reasoning_content").and_then(|v| v.as_str()) {
+                self.usage.add_estimated_reasoning(estimate_tokens(text));
+            }
+        }
+        if let Some(usage) = chunk.get("usage").filter(|u| u.is_object()) {
+            self.usage.apply_reported(&parse_openai_usage(usage));
+        }
+        vec![chunk.clone()]
+    }
+
+    fn usage(&self) -> TokenUsage {
+        self.usage.clone()
+    }
+}
+
+/// Parse an OpenAI `usage` object (Chat Completions or Responses naming),
+/// including cached prompt tokens and reasoning tokens when reported.
+pub fn parse_openai_usage(usage: &Value) -> TokenUsage {
+    let prompt_tokens = usage
+        .get("prompt_tokens")
+        .or_else(|| usage.get("input_tokens"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let completion_tokens = usage
+        .get("completion_tokens")
+        .or_else(|| usage.get("output_tokens"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let cache_read_tokens = usage
+        .get("prompt_tokens_details")
+        .or_else(|| usage.get("input_tokens_details"))
+        .and_then(|d| d.get("cached_tokens"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let reasoning_tokens = usage
+        .get("completion_tokens_details")
+        .or_else(|| usage.get("output_tokens_details"))
+        .and_then(|d| d.get("reasoning_tokens"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+
+    TokenUsage {
+        cache_read_tokens,
+        reasoning_tokens,
+        ..TokenUsage::new(prompt_tokens, completion_tokens)
+    }
+}
+
+/// Message content from parts, a lone text part collapsed to a string
+/// unless it carries a `cache_control` marker.
+pub(super) fn content_from_parts(parts: Vec<Value>) -> Value {
+    if parts.len() == 1 {
+        if let Some(obj) = parts[0].as_object() {
+            if obj.get("type").and_then(|v| v.as_str()) == Some("text")
+                && !obj.contains_key("cache_control")
+            {
+                if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
+                    return Value::String(text.to_string());
+                }
+            }
+        }
+    }
+    Value::Array(parts)
+}
+
+/// Media type and base64 data of a `data:` URL.
+pub(super) fn parse_data_url(url: &str) -> Option<(String, String)> {
+    let trimmed = url.trim();
+    if !trimmed.starts_with("data:") {
+        return None;
+    }
+    let rest = trimmed.trim_start_matches("data:");
+    let mut parts = rest.splitn(2, ";base64,");
+    let mime = parts.next()?.to_string();
+    let data = parts.next()?.to_string();
+    if mime.is_empty() || data.is_empty() {
+        None
+    } else {
+        Some((mime, data))
+    }
+}
+
+/// Shown in place of a document a conversion cannot carry over.
+pub(super) const DOCUMENT_DROPPED: &str = "[Unsupported document dropped]";
+/// Largest document converted, decoded; Gemini takes no more inline
+const MAX_DOCUMENT_BYTES: usize = 20 * 1024 * 1024;
+
+/// A base64 document: media type, data and file name.
+pub(super) struct Document {
+    pub media_type: String,
+    pub data: String,
+    pub filename: Option<String>,
+}
+
+impl Document {
+    /// `None` when the document is over the size cap.
+    pub fn new(media_type: &str, data: &str, filename: Option<&str>) -> Option<Self> {
+        if data.is_empty() || data.len() / 4 * 3 > MAX_DOCUMENT_BYTES {
+            return None;
+        }
+        Some(Self {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+            filename: filename.map(str::to_string),
+        })
+    }
+
+    /// The document of an OpenAI `file` part that carries its data; file
+    /// ids cannot be resolved here.
+    pub fn from_file_part(part: &Value) -> Option<Self> {
+        let file = part.get("file")?;
+        let (media_type, data) = parse_data_url(file.get("file_data")?.as_str()?)?;
+        let filename = file.get("filename").and_then(|v| v.as_str());
+        Self::new(&media_type, &data, filename)
+    }
+
+    /// As an OpenAI `file` part.
+    pub fn to_file_part(&self) -> Value {
+        let mut file = serde_json::json!({
+            "file_data": format!("data:{};base64,{}", self.media_type, self.data)
+        });
+        if let (Some(filename), Some(obj)) = (&self.filename, file.as_object_mut()) {
+            obj.insert("filename".to_string(), Value::String(filename.clone()));
+        }
+        serde_json::json!({ "type": "file", "file": file })
+    }
+}
+
+/// Shown in place of audio the upstream cannot take.
+pub(super) const AUDIO_DROPPED: &str = "[Unsupported audio dropped]";
+/// Largest audio clip converted, decoded; Gemini takes no more inline
+const MAX_AUDIO_BYTES: usize = 20 * 1024 * 1024;
+
+/// Base64 audio and its media type.
+pub(super) struct Audio {
+    pub media_type: String,
+    pub data: String,
+}
+
+impl Audio {
+    /// `None` when the clip is empty or over the size cap.
+    pub fn new(media_type: &str, data: &str) -> Option<Self> {
+        if data.is_empty() || data.len() / 4 * 3 > MAX_AUDIO_BYTES {
+            return None;
+        }
+        Some(Self {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        })
+    }
+
+    /// The clip of an OpenAI `input_audio` part.
+    pub fn from_input_audio(part: &Value) -> Option<Self> {
+        let audio = part.get("input_audio")?;
+        let format = audio
+            .get("format")
+            .and_then(|v| v.as_str())
+            .unwrap_or("wav");
+        let data = audio.get("data")?.as_str()?;
+        Self::new(&format!("audio/{}", format.to_ascii_lowercase()), data)
+    }
+
+    /// As an OpenAI `input_audio` part, which takes WAV and MP3 only.
+    pub fn to_input_audio(&self) -> Option<Value> {
+        let format = match self.media_type.to_ascii_lowercase().as_str() {
+            "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+            "audio/mp3" | "audio/mpeg" => "mp3",
+            _ => return None,
+        };
+        Some(serde_json::json!({
+            "type": "input_audio",
+            "input_audio": { "data": self.data, "format": format }
+        }))
+    }
+}
+
+/// A text part standing in for content that was dropped.
+pub(super) fn marker_part(marker: &str) -> Value {
+    serde_json::json!({ "type": "text", "text": marker })
+}
+
+/// A `chat.completion.chunk` with a single choice.
+pub(super) fn stream_chunk(
+    id: &str,
+    created: i64,
+    model: &str,
+    delta: Value,
+    finish_reason: Option<Value>,
+    usage: Option<Value>,
+) -> Value {
+    let mut chunk = serde_json::json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "delta": delta,
+            "finish_reason": finish_reason
+        }]
+    });
+    if let (Some(usage), Some(obj)) = (usage, chunk.as_object_mut()) {
+        obj.insert("usage".to_string(), usage);
+    }
+    chunk
+}
@@ -0,0 +1,351 @@
+//! Request parameters fitted to what the upstream model takes.
+//!
+//! Each adapter's `normalize_request` runs on a request in its provider's
+//! format just before it is sent: numbers are clamped into the provider's
+//! range, `top_k` is rounded to an integer, parameters the model does not
+//! take are dropped with a debug log rather than failing upstream, and
+//! `reasoning_effort` becomes a thinking budget. Temperatures are clamped,
+//! not rescaled, so 1.0 stays every provider's default.
+//!
+//! The other way round, [`effort_for_budget`] reads the thinking budget of
+//! an Anthropic or Gemini request as a `reasoning_effort` when it is
+//! converted to OpenAI's format, by the same table.
+
+use serde_json::{Map, Value};
+
+use crate::config::{self, ModelCapabilities, ReasoningBudgets};
+use crate::forward::context::ModelInfo;
+use crate::logger;
+
+/// What a request to one model may carry.
+#[derive(Debug, Clone)]
+pub struct ParamPolicy {
+    pub temperature: bool,
+    pub top_p: bool,
+    /// Unset for the provider's default: OpenAI has no `top_k`
+    pub top_k: Option<bool>,
+    pub penalties: bool,
+    pub reasoning: bool,
+    pub budgets: ReasoningBudgets,
+    /// Upstream model name, for the logs
+    model: String,
+}
+
+impl ParamPolicy {
+    /// Policy for `model` with the configured thinking budgets.
+    pub fn for_model(model: &ModelInfo) -> Self {
+        Self::new(
+            model.upstream_model(),
+            &model.capabilities,
+            config::current().reasoning_budgets.clone(),
+        )
+    }
+
+    pub fn new(model: &str, capabilities: &ModelCapabilities, budgets: ReasoningBudgets) -> Self {
+        let sampling = !is_openai_reasoning_model(model);
+        let reasoning = !is_without_reasoning(model);
+        Self {
+            temperature: capabilities.temperature.unwrap_or(sampling),
+            top_p: capabilities.top_p.unwrap_or(sampling),
+            top_k: capabilities.top_k,
+            penalties: capabilities.penalties.unwrap_or(sampling),
+            reasoning: capabilities.reasoning.unwrap_or(reasoning),
+            budgets,
+            model: model.to_string(),
+        }
+    }
+
+    /// Drop `key` unless `allowed`.
+    pub(super) fn keep(&self, obj: &mut Map<String, Value>, key: &str, allowed: bool) {
+        if !allowed && obj.remove(key).is_some() {
+            self.dropped(key, "not supported by the model");
+        }
+    }
+
+    /// Clamp a numeric `key` into `min..=max`; other values are dropped.
+    pub(super) fn clamp(&self, obj: &mut Map<String, Value>, key: &str, min: f64, max: f64) {
+        let Some(value) = obj.get(key) else {
+            return;
+        };
+        let Some(number) = value.as_f64() else {
+            if !value.is_null() {
+                self.dropped(key, "not a number");
+            }
+            obj.remove(key);
+            return;
+        };
+        let clamped = number.clamp(min, max);
+        if clamped != number {
+            logger::debug(
+                "params",
+                &format!(
+                    "Clamped {} from {} to {} for model {}",
+                    key, number, clamped, self.model
+                ),
+            );
+            obj.insert(key.to_string(), Value::from(clamped));
+        }
+    }
+
+    /// Round a numeric `key` to an integer of at least `min`.
+    pub(super) fn integer(&self, obj: &mut Map<String, Value>, key: &str, min: i64) {
+        let Some(value) = obj.get(key) else {
+            return;
+        };
+        if value.is_i64() && value.as_i64() >= Some(min) {
+            return;
+        }
+        match value.as_f64() {
+            Some(number) => {
+                let rounded = (number.round() as i64).max(min);
+                logger::debug(
+                    "params",
+                    &format!(
+                        "Rounded {} from {} to {} for model {}",
+                        key, number, rounded, self.model
+                    ),
+                );
+                obj.insert(key.to_string(), Value::from(rounded));
+            }
+            None => {
+                if !value.is_null() {
+                    self.dropped(key, "not a number");
+                }
+                obj.remove(key);
+            }
+        }
+    }
+
+    /// Thinking budget for a `reasoning_effort`; `None` for `none`, levels
+    /// it does not know, or models without reasoning.
+    pub(super) fn budget(&self, effort: &Value) -> Option<i64> {
+        if !self.reasoning {
+            self.dropped("reasoning_effort", "not supported by the model");
+            return None;
+        }
+        let effort = effort.as_str().unwrap_or_default();
+        let budget = self.budgets.for_effort(effort);
+        if budget.is_none() && !effort.eq_ignore_ascii_case("none") {
+            self.dropped("reasoning_effort", &format!("unknown level '{}'", effort));
+        }
+        budget
+    }
+
+    pub(super) fn dropped(&self, key: &str, reason: &str) {
+        logger::debug(
+            "params",
+            &format!("Dropped {} for model {}: {}", key, self.model, reason),
+        );
+    }
+}
+
+/// `reasoning_effort` a thinking budget reads as. Budgets of 0 or less
+/// turn thinking off or leave it to the model and read as nothing.
+pub(super) fn effort_for_budget(
+    budget: Option<&Value>,
+    budgets: &ReasoningBudgets,
+) -> Option<Value> {
+    let budget = budget?.as_i64().filter(|budget| *budget > 0)?;
+    Some(Value::from(budgets.effort_for(budget)))
+}
+
+/// Model name without its vendor prefix, lowercased.
+fn base_name(model: &str) -> String {
+    model
+        .rsplit('/')
+        .next()
+        .unwrap_or(model)
+        .to_ascii_lowercase()
+}
+
+/// o1, o3, o4-mini, gpt-5 and their versions, which reject sampling
+/// parameters. gpt-5-chat takes them.
+fn is_openai_reasoning_model(model: &str) -> bool {
+    let name = base_name(model);
+    if name.starts_with("gpt-5") {
+        return !name.contains("chat");
+    }
+    let mut chars = name.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// GPT-3.5 and GPT-4 models, Claude 3 before 3.7 and Gemini before 2.5,
+/// which reject reasoning control. Their thinking variants take it.
+fn is_without_reasoning(model: &str) -> bool {
+    let name = base_name(model);
+    if name.contains("thinking") {
+        return false;
+    }
+    if name.starts_with("claude-3-") {
+        return !name.starts_with("claude-3-7");
+    }
+    [
+        "gpt-3.5",
+        "gpt-4",
+        "chatgpt-4",
+        "claude-instant",
+        "claude-2",
+        "gemini-1.",
+        "gemini-2.0",
+        "gemini-pro",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::openai::OpenAIAdapter;
+    use crate::adapters::{AnthropicAdapter, GeminiAdapter, ModelAdapter};
+    use serde_json::json;
+
+    fn policy(model: &str, capabilities: ModelCapabilities) -> ParamPolicy {
+        ParamPolicy::new(model, &capabilities, ReasoningBudgets::default())
+    }
+
+    #[test]
+    fn test_requests_are_fitted_to_the_target() {
+        let mut request = json!({
+            "model": "o3-mini",
+            "temperature": 0.7,
+            "top_p": 0.9,
+            "top_k": 20,
+            "frequency_penalty": 3,
+            "reasoning_effort": "high"
+        });
+        OpenAIAdapter.normalize_request(&mut request, &policy("o3-mini", Default::default()));
+        assert_eq!(
+            request,
+            json!({"model": "o3-mini", "reasoning_effort": "high"})
+        );
+
+        let mut request = json!({"temperature": 2.5, "frequency_penalty": 3, "top_k": 20});
+        let vllm = ModelCapabilities {
+            top_k: Some(true),
+            ..Default::default()
+        };
+        OpenAIAdapter.normalize_request(&mut request, &policy("qwen3", vllm));
+        assert_eq!(
+            request,
+            json!({"temperature": 2.0, "frequency_penalty": 2.0, "top_k": 20})
+        );
+
+        let anthropic = AnthropicAdapter::default();
+        let mut request = anthropic.request_from_openai(
+            &json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "max_tokens": 4096,
+                "temperature": 1.5,
+                "top_p": 0.5,
+                "reasoning_effort": "high"
+            }),
+            "claude-sonnet-4",
+        );
+        anthropic.normalize_request(&mut request, &policy("claude-sonnet-4", Default::default()));
+        assert_eq!(
+            request["thinking"],
+            json!({"type": "enabled", "budget_tokens": 4095})
+        );
+        assert!(request.get("temperature").is_none());
+        assert!(request.get("reasoning_effort").is_none());
+        assert_eq!(request["top_p"], json!(0.95));
+
+        let mut request = json!({"max_tokens": 512, "temperature": 1.5, "top_k": 7.6});
+        anthropic.normalize_request(&mut request, &policy("claude-sonnet-4", Default::default()));
+        assert_eq!(
+            request,
+            json!({"max_tokens": 512, "temperature": 1.0, "top_k": 8})
+        );
+
+        let mut request = GeminiAdapter::default().request_from_openai(
+            &json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "temperature": 3,
+                "reasoning_effort": "low"
+            }),
+            "gemini-2.5-pro",
+        );
+        request["generationConfig"]["topK"] = json!(40.4);
+        GeminiAdapter::default()
+            .normalize_request(&mut request, &policy("gemini-2.5-pro", Default::default()));
+        assert!(request.get("reasoning_effort").is_none());
+        let config = &request["generationConfig"];
+        assert_eq!(config["temperature"], json!(2.0));
+        assert_eq!(config["topK"], json!(40));
+        assert_eq!(
+            config["thinkingConfig"],
+            json!({"thinkingBudget": 2048, "includeThoughts": true})
+        );
+
+        let no_reasoning = ModelCapabilities {
+            reasoning: Some(false),
+            ..Default::default()
+        };
+        let mut request = json!({"reasoning_effort": "medium", "generationConfig": {}});
+        GeminiAdapter::default()
+            .normalize_request(&mut request, &policy("gemini-2.0-flash", no_reasoning));
+        assert_eq!(request, json!({"generationConfig": {}}));
+    }
+
+    #[test]
+    fn test_thinking_budgets_map_across_providers() {
+        // Anthropic's "think harder" reaches Gemini as the budget of its
+        // effort level
+        let anthropic = AnthropicAdapter::default();
+        let gemini = GeminiAdapter::default();
+        let request = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 16000,
+            "thinking": {"type": "enabled", "budget_tokens": 10000},
+            "messages": [{"role": "user", "content": "think harder"}]
+        });
+        let openai = anthropic.request_to_openai(&request, "gemini-2.5-pro");
+        assert_eq!(openai["reasoning_effort"], "medium");
+        let mut converted =
+            crate::adapters::convert_request(&anthropic, &gemini, &request, "gemini-2.5-pro");
+        gemini.normalize_request(
+            &mut converted,
+            &policy("gemini-2.5-pro", Default::default()),
+        );
+        assert_eq!(
+            converted["generationConfig"]["thinkingConfig"]["thinkingBudget"],
+            8192
+        );
+
+        // and OpenAI models only if they take reasoning control
+        let mut to_o3 = openai.clone();
+        OpenAIAdapter.normalize_request(&mut to_o3, &policy("o3", Default::default()));
+        assert_eq!(to_o3["reasoning_effort"], "medium");
+        let mut to_gpt4o = openai;
+        OpenAIAdapter.normalize_request(&mut to_gpt4o, &policy("gpt-4o", Default::default()));
+        assert!(to_gpt4o.get("reasoning_effort").is_none());
+
+        let request = |budget: i64| {
+            json!({
+                "contents": [{"role": "user", "parts": [{"text": "hi"}]}],
+                "generationConfig": {"thinkingConfig": {"thinkingBudget": budget}}
+            })
+        };
+        let effort =
+            |budget| gemini.request_to_openai(&request(budget), "o3")["reasoning_effort"].clone();
+        assert_eq!(effort(32768), "high");
+        assert_eq!(effort(2048), "low");
+        assert_eq!(effort(128), "minimal");
+        assert_eq!(effort(0), Value::Null);
+        assert_eq!(effort(-1), Value::Null);
+
+        let mut request = anthropic.request_from_openai(
+            &json!({"messages": [{"role": "user", "content": "hi"}], "reasoning_effort": "high"}),
+            "claude-3-5-sonnet-20241022",
+        );
+        anthropic.normalize_request(
+            &mut request,
+            &policy("anthropic/claude-3-5-sonnet-20241022", Default::default()),
+        );
+        assert!(request.get("thinking").is_none());
+        assert!(policy("claude-3-7-sonnet-latest", Default::default()).reasoning);
+        assert!(policy("gemini-2.0-flash-thinking-exp", Default::default()).reasoning);
+        assert!(!policy("gemini-1.5-pro", Default::default()).reasoning);
+    }
+}
@@ -0,0 +1,230 @@
+//! JSON Schema as Gemini takes it, and back.
+//!
+//! Gemini's `responseSchema` is a subset of OpenAPI 3.0: upper-case
+//! `type`s, `nullable` instead of `"null"` in a type list, `anyOf` but no
+//! `oneOf`, and no `$ref`, `const` or `additionalProperties`. References
+//! are inlined from `$defs` (or `definitions`), `const` becomes a one-value
+//! `enum` and keywords Gemini rejects are dropped.
+
+use serde_json::{Map, Value};
+
+/// Keywords Gemini's schema takes besides `type`
+const GEMINI_KEYWORDS: &[&str] = &[
+    "format",
+    "description",
+    "nullable",
+    "enum",
+    "properties",
+    "required",
+    "items",
+    "anyOf",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "propertyOrdering",
+    "title",
+];
+/// `$ref`s followed at most, so recursive schemas end
+const MAX_REF_DEPTH: usize = 8;
+
+/// A JSON Schema in Gemini's dialect.
+pub fn to_gemini(schema: &Value) -> Value {
+    let defs = schema
+        .get("$defs")
+        .or_else(|| schema.get("definitions"))
+        .cloned()
+        .unwrap_or(Value::Null);
+    convert_to_gemini(schema, &defs, 0)
+}
+
+fn convert_to_gemini(schema: &Value, defs: &Value, depth: usize) -> Value {
+    let Some(obj) = schema.as_object() else {
+        return schema.clone();
+    };
+    if let Some(target) = obj
+        .get("$ref")
+        .and_then(|v| v.as_str())
+        .and_then(|r| r.rsplit('/').next())
+        .and_then(|name| defs.get(name))
+    {
+        if depth < MAX_REF_DEPTH {
+            return convert_to_gemini(target, defs, depth + 1);
+        }
+        return serde_json::json!({ "type": "OBJECT" });
+    }
+
+    let mut out = Map::new();
+    let mut nullable = false;
+    match obj.get("type") {
+        Some(Value::String(kind)) => {
+            out.insert("type".to_string(), Value::String(kind.to_ascii_uppercase()));
+        }
+        Some(Value::Array(kinds)) => {
+            nullable = kinds.iter().any(|k| k == "null");
+            if let Some(kind) = kinds
+                .iter()
+                .filter_map(|k| k.as_str())
+                .find(|k| *k != "null")
+            {
+                out.insert("type".to_string(), Value::String(kind.to_ascii_uppercase()));
+            }
+        }
+        _ => {}
+    }
+    for (key, value) in obj {
+        match key.as_str() {
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, prop)| (name.clone(), convert_to_gemini(prop, defs, depth)))
+                    .collect();
+                out.insert(key.clone(), Value::Object(properties));
+            }
+            "items" => {
+                out.insert(key.clone(), convert_to_gemini(value, defs, depth));
+            }
+            "anyOf" | "oneOf" => {
+                let variants = value.as_array().cloned().unwrap_or_default();
+                let (nulls, variants): (Vec<Value>, Vec<Value>) = variants
+                    .into_iter()
+                    .partition(|v| v.get("type").and_then(|t| t.as_str()) == Some("null"));
+                nullable |= !nulls.is_empty();
+                match variants.as_slice() {
+                    [single] => {
+                        if let Value::Object(inner) = convert_to_gemini(single, defs, depth) {
+                            out.extend(inner);
+                        }
+                    }
+                    _ => {
+                        let variants = variants
+                            .iter()
+                            .map(|v| convert_to_gemini(v, defs, depth))
+                            .collect();
+                        out.insert("anyOf".to_string(), Value::Array(variants));
+                    }
+                }
+            }
+            "const" => {
+                out.insert("enum".to_string(), Value::Array(vec![value.clone()]));
+            }
+            _ if GEMINI_KEYWORDS.contains(&key.as_str()) => {
+                out.insert(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+    if out.contains_key("enum") && !out.contains_key("type") {
+        out.insert("type".to_string(), Value::String("STRING".to_string()));
+    }
+    if nullable {
+        out.insert("nullable".to_string(), Value::Bool(true));
+    }
+    Value::Object(out)
+}
+
+/// A Gemini schema as JSON Schema.
+pub fn from_gemini(schema: &Value) -> Value {
+    let Some(obj) = schema.as_object() else {
+        return schema.clone();
+    };
+    let nullable = obj.get("nullable").and_then(|v| v.as_bool()) == Some(true);
+    let mut out = Map::new();
+    for (key, value) in obj {
+        match key.as_str() {
+            "type" => {
+                let kind = Value::String(value.as_str().unwrap_or("object").to_ascii_lowercase());
+                let kind = if nullable {
+                    Value::Array(vec![kind, Value::String("null".to_string())])
+                } else {
+                    kind
+                };
+                out.insert(key.clone(), kind);
+            }
+            "nullable" | "propertyOrdering" => {}
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, prop)| (name.clone(), from_gemini(prop)))
+                    .collect();
+                out.insert(key.clone(), Value::Object(properties));
+            }
+            "items" => {
+                out.insert(key.clone(), from_gemini(value));
+            }
+            "anyOf" => {
+                let variants = value
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(from_gemini)
+                    .collect();
+                out.insert(key.clone(), Value::Array(variants));
+            }
+            _ => {
+                out.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Value::Object(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_schemas_are_translated_for_gemini() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "city": {"type": "string", "description": "City name"},
+                "unit": {"const": "celsius"},
+                "temp": {"type": ["number", "null"]},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}},
+                "source": {"anyOf": [{"type": "string"}, {"type": "null"}]}
+            },
+            "required": ["city", "temp"],
+            "$defs": {"tag": {"type": "string", "enum": ["hot", "cold"]}}
+        });
+        let gemini = to_gemini(&schema);
+        assert_eq!(
+            gemini,
+            json!({
+                "type": "OBJECT",
+                "properties": {
+                    "city": {"type": "STRING", "description": "City name"},
+                    "unit": {"type": "STRING", "enum": ["celsius"]},
+                    "temp": {"type": "NUMBER", "nullable": true},
+                    "tags": {"type": "ARRAY", "items": {"type": "STRING", "enum": ["hot", "cold"]}},
+                    "source": {"type": "STRING", "nullable": true}
+                },
+                "required": ["city", "temp"]
+            })
+        );
+
+        let back = from_gemini(&gemini);
+        assert_eq!(back["type"], "object");
+        assert_eq!(
+            back["properties"]["temp"]["type"],
+            json!(["number", "null"])
+        );
+        assert_eq!(back["properties"]["tags"]["items"]["type"], "string");
+
+        // Recursive references stop instead of looping
+        let tree = json!({"$ref": "#/$defs/node", "$defs": {"node": {
+            "type": "object", "properties": {"child": {"$ref": "#/$defs/node"}}
+        }}});
+        assert_eq!(to_gemini(&tree)["type"], "OBJECT");
+    }
+}
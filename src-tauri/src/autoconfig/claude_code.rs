@@ -0,0 +1,166 @@
+//! Claude Code: the `env` block of `~/.claude/settings.json`.
+//!
+//! Sets `ANTHROPIC_BASE_URL` to the relay's `/anthropic` endpoint and
+//! `ANTHROPIC_AUTH_TOKEN` to the forward token, plus `ANTHROPIC_MODEL` when
+//! a model is chosen. For a shell profile instead, see the `claude-code`
+//! [`super::editor_snippet`].
+
+use std::path::{Path, PathBuf};
+
+use super::edit::{self, AppliedChanges, Edit};
+use super::verify::{self, Probe};
+use crate::{config, logger};
+
+pub const TARGET: &str = "claude_code";
+
+/// Point Claude Code at the relay, or with `dry_run` only report what that
+/// would change.
+pub fn apply(model_id: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    if let Some(model) = model_id {
+        super::ensure_model_supports_provider(&config::load(), model, "anthropic")?;
+    }
+    let path = settings_path()?;
+    let base_url = format!("{}/anthropic", super::ccr_base_url());
+    let changes = apply_at(
+        &path,
+        &base_url,
+        &super::get_forward_token(),
+        model_id,
+        dry_run,
+    )?;
+    if changes.applied {
+        logger::info(
+            "autoconfig",
+            &format!("Pointed Claude Code at {} ({})", base_url, path.display()),
+        );
+    }
+    Ok(changes)
+}
+
+/// Restore the settings file from before [`apply`].
+pub fn revert() -> Result<AppliedChanges, String> {
+    edit::revert_target(TARGET, &settings_path()?)
+}
+
+/// The request Claude Code makes with its current settings. Without
+/// `ANTHROPIC_MODEL` it uses its own default, so a relay model stands in.
+pub(super) fn probe() -> Result<Probe, String> {
+    let file = Edit::open(&settings_path()?)?;
+    let env = |key: &str| verify::text(file.get(&["env", key]));
+    let base_url = verify::relay_url(env("ANTHROPIC_BASE_URL"))
+        .ok_or_else(|| verify::not_applied("Claude Code"))?;
+    let model = match env("ANTHROPIC_MODEL") {
+        Some(model) => model,
+        None => verify::default_model("anthropic")?,
+    };
+    Ok(Probe::anthropic(
+        format!("{}/v1/messages", base_url.trim_end_matches('/')),
+        env("ANTHROPIC_AUTH_TOKEN").unwrap_or_default(),
+        model,
+        true,
+    ))
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    super::settings_path("claude", super::get_claude_config_path())
+}
+
+fn apply_at(
+    path: &Path,
+    base_url: &str,
+    token: &str,
+    model_id: Option<&str>,
+    dry_run: bool,
+) -> Result<AppliedChanges, String> {
+    let mut file = Edit::open(path)?;
+    let mut warnings: Vec<String> =
+        edit::repointed(&file, &["env", "ANTHROPIC_BASE_URL"], base_url)
+            .into_iter()
+            .collect();
+    if file.get(&["env", "ANTHROPIC_API_KEY"]).is_some() {
+        warnings.push(
+            "env also sets ANTHROPIC_API_KEY, which Claude Code may send instead of the relay token"
+                .to_string(),
+        );
+    }
+    file.set(&["env", "ANTHROPIC_BASE_URL"], base_url.into(), false)?;
+    file.set(&["env", "ANTHROPIC_AUTH_TOKEN"], token.into(), true)?;
+    if let Some(model) = model_id {
+        file.set(&["env", "ANTHROPIC_MODEL"], model.into(), false)?;
+    }
+    let file = file.finish(dry_run)?;
+    Ok(AppliedChanges {
+        target: TARGET.to_string(),
+        applied: !dry_run,
+        files: vec![file],
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoconfig::edit::FileAction;
+    use serde_json::Value;
+
+    const RELAY: &str = "http://127.0.0.1:9000/anthropic";
+    const TOKEN: &str = "ccr-token-abcdef1234";
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ccr-claude-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_apply_and_revert() {
+        // A missing file is created, and revert removes it again
+        let path = temp_dir("missing").join(".claude").join("settings.json");
+        let preview = apply_at(&path, RELAY, TOKEN, Some("sonnet"), true).unwrap();
+        assert_eq!(preview.files[0].action, FileAction::Created);
+        assert_eq!(preview.files[0].changes.len(), 3);
+        assert_eq!(
+            preview.files[0].changes[1].after,
+            Some(Value::from("****1234"))
+        );
+        assert!(!path.exists());
+        apply_at(&path, RELAY, TOKEN, Some("sonnet"), false).unwrap();
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["env"]["ANTHROPIC_MODEL"], "sonnet");
+        assert_eq!(revert_at(&path), FileAction::Removed);
+        assert!(!path.exists());
+
+        // Another relay is replaced with a warning; other keys stay
+        let dir = temp_dir("other");
+        let path = dir.join("settings.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = r#"{"env": {"ANTHROPIC_BASE_URL": "https://other.example/api", "DEBUG": "1"}, "theme": "dark"}"#;
+        std::fs::write(&path, original).unwrap();
+        let applied = apply_at(&path, RELAY, TOKEN, None, false).unwrap();
+        assert_eq!(applied.files[0].action, FileAction::Updated);
+        assert!(applied.warnings[0].contains("https://other.example/api"));
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["env"]["DEBUG"], "1");
+        assert_eq!(written["theme"], "dark");
+        // Applying again changes nothing and keeps the first backup
+        let again = apply_at(&path, RELAY, TOKEN, None, false).unwrap();
+        assert_eq!(again.files[0].action, FileAction::Unchanged);
+        assert!(again.warnings.is_empty());
+        assert_eq!(revert_at(&path), FileAction::Restored);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        assert!(edit::revert(&path).is_err());
+
+        // Invalid JSON is left alone
+        std::fs::write(&path, "{\"env\": ").unwrap();
+        let err = apply_at(&path, RELAY, TOKEN, None, false).unwrap_err();
+        assert!(err.contains("not valid JSON"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"env\": ");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn revert_at(path: &Path) -> FileAction {
+        edit::revert(path).unwrap().action
+    }
+}
@@ -0,0 +1,219 @@
+//! Continue: a model entry in `~/.continue/config.yaml`, or in the older
+//! `config.json` when only that one exists.
+//!
+//! The entry is named after the relay model and uses Continue's `openai`
+//! or `anthropic` provider to match the model's preferred route, at `/v1`
+//! or `/anthropic/v1`. An entry of the same name gets only the relay's
+//! fields updated.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::edit::{self, AppliedChanges, Edit};
+use super::verify::{self, Probe};
+use super::RelayModel;
+use crate::{config, logger};
+
+pub const TARGET: &str = "continue";
+
+/// Add `model_id` to Continue, or with `dry_run` only report what that
+/// would change.
+pub fn apply(model_id: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    let model_id = model_id.ok_or("Choose the model to add to Continue")?;
+    let model = super::relay_model(&config::load(), model_id)?;
+    let path = settings_path()?;
+    let changes = apply_at(
+        &path,
+        &super::ccr_base_url(),
+        &super::get_forward_token(),
+        &model,
+        dry_run,
+    )?;
+    if changes.applied {
+        logger::info(
+            "autoconfig",
+            &format!("Added {} to Continue ({})", model.id, path.display()),
+        );
+    }
+    Ok(changes)
+}
+
+/// Restore the config file from before [`apply`].
+pub fn revert() -> Result<AppliedChanges, String> {
+    edit::revert_target(TARGET, &settings_path()?)
+}
+
+/// The request Continue makes for its first model at the relay.
+pub(super) fn probe() -> Result<Probe, String> {
+    let file = Edit::open(&settings_path()?)?;
+    let (entry, api_base) = file
+        .entries(&["models"])
+        .into_iter()
+        .find_map(|entry| {
+            let api_base = verify::relay_url(verify::text(entry.get("apiBase").cloned()))?;
+            Some((entry, api_base))
+        })
+        .ok_or_else(|| verify::not_applied("Continue"))?;
+    let field = |key: &str| verify::text(entry.get(key).cloned());
+    let model = field("model").ok_or("The Continue model entry has no model")?;
+    let token = field("apiKey").unwrap_or_default();
+    Ok(match field("provider").as_deref() {
+        Some("anthropic") => Probe::anthropic(
+            format!("{}/messages", api_base.trim_end_matches('/')),
+            token,
+            model,
+            false,
+        ),
+        _ => Probe::openai(&api_base, token, model),
+    })
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    let default = dirs::home_dir().map(|home| {
+        let dir = home.join(".continue");
+        let (yaml, json) = (dir.join("config.yaml"), dir.join("config.json"));
+        if !yaml.exists() && json.exists() {
+            json
+        } else {
+            yaml
+        }
+    });
+    super::settings_path(TARGET, default)
+}
+
+fn apply_at(
+    path: &Path,
+    base_url: &str,
+    token: &str,
+    model: &RelayModel,
+    dry_run: bool,
+) -> Result<AppliedChanges, String> {
+    let mut file = Edit::open(path)?;
+    let api_base = match model.provider {
+        "anthropic" => format!("{}/anthropic/v1", base_url),
+        _ => format!("{}/v1", base_url),
+    };
+    let yaml = path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml");
+    // config.yaml names its entries, config.json titles them
+    let id_field = if yaml { "name" } else { "title" };
+    if yaml {
+        for (key, value) in [
+            ("name", "Local Assistant"),
+            ("version", "1.0.0"),
+            ("schema", "v1"),
+        ] {
+            if file.get(&[key]).is_none() {
+                file.set(&[key], value.into(), false)?;
+            }
+        }
+    }
+    let previous_base = file
+        .entry(&["models"], id_field, &model.id)
+        .and_then(|entry| entry.get("apiBase").cloned());
+    let mut warnings = Vec::new();
+    if let Some(Value::String(previous)) = previous_base {
+        if previous.trim_end_matches('/') != api_base {
+            warnings.push(format!(
+                "{} pointed at {}; revert puts it back",
+                model.id, previous
+            ));
+        }
+    }
+    file.upsert(
+        &["models"],
+        id_field,
+        &model.id,
+        &[
+            ("provider", model.provider.into(), false),
+            ("model", model.id.as_str().into(), false),
+            ("apiBase", api_base.into(), false),
+            ("apiKey", token.into(), true),
+        ],
+    )?;
+    Ok(AppliedChanges {
+        target: TARGET.to_string(),
+        applied: !dry_run,
+        files: vec![file.finish(dry_run)?],
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoconfig::edit::FileAction;
+
+    const YAML: &str = include_str!("../../tests/fixtures/autoconfig/continue-config.yaml");
+    const JSON: &str = include_str!("../../tests/fixtures/autoconfig/continue-config.json");
+
+    fn model(id: &str, provider: &'static str) -> RelayModel {
+        RelayModel {
+            id: id.to_string(),
+            name: id.to_string(),
+            provider,
+        }
+    }
+
+    #[test]
+    fn test_sample_configs_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ccr-continue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = "http://127.0.0.1:9000";
+        let token = "ccr-token-abcdef1234";
+
+        // config.yaml: the existing entry is repointed, a new one is added
+        let path = dir.join("config.yaml");
+        std::fs::write(&path, YAML).unwrap();
+        let claude = model("claude-sonnet", "anthropic");
+        let applied = apply_at(&path, base, token, &claude, false).unwrap();
+        assert!(applied.warnings[0].contains("https://other-relay.example/anthropic/v1"));
+        let keys: Vec<&str> = applied.files[0]
+            .changes
+            .iter()
+            .map(|c| c.key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "models[claude-sonnet].model",
+                "models[claude-sonnet].apiBase",
+                "models[claude-sonnet].apiKey",
+            ]
+        );
+        assert_eq!(
+            applied.files[0].changes[2].before,
+            Some(Value::from("****-key"))
+        );
+        apply_at(&path, base, token, &model("gpt-4o", "openai"), false).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains(
+            "    apiBase: \"http://127.0.0.1:9000/anthropic/v1\"\n    apiKey: \"ccr-token-abcdef1234\"\n  - name: \"gpt-4o\"\n    provider: \"openai\"\n    model: \"gpt-4o\"\n    apiBase: \"http://127.0.0.1:9000/v1\"\n"
+        ));
+        assert!(written.contains("      - autocomplete\n"));
+        assert!(written.ends_with("\ncontext:\n  - provider: code\n"));
+        edit::revert(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), YAML);
+
+        // A new config.yaml gets the header Continue requires
+        let path = dir.join("new").join("config.yaml");
+        let preview = apply_at(&path, base, token, &model("gpt-4o", "openai"), true).unwrap();
+        assert_eq!(preview.files[0].action, FileAction::Created);
+        assert_eq!(preview.files[0].changes[0].key, "name");
+
+        // config.json: entries are titled
+        let path = dir.join("config.json");
+        std::fs::write(&path, JSON).unwrap();
+        apply_at(&path, base, token, &model("gpt-4o", "openai"), false).unwrap();
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["models"][1]["title"], "gpt-4o");
+        assert_eq!(written["models"][1]["apiBase"], "http://127.0.0.1:9000/v1");
+        assert_eq!(written["tabAutocompleteModel"]["model"], "starcoder2:3b");
+        edit::revert(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), JSON);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,126 @@
+//! Cursor: the OpenAI override in its user `settings.json`.
+//!
+//! Sets the base URL to the relay's `/v1` endpoint and the API key to the
+//! forward token, plus the default model when one is chosen. The file is
+//! found like VS Code's, under `Cursor/User` in the OS config directory,
+//! unless `backup.tool_paths` has a "cursor" entry.
+
+use std::path::{Path, PathBuf};
+
+use super::edit::{self, AppliedChanges, Edit};
+use super::verify::{self, Probe};
+use crate::{config, logger};
+
+pub const TARGET: &str = "cursor";
+
+const BASE_URL_KEY: &str = "cursor.openaiBaseUrl";
+const API_KEY_KEY: &str = "cursor.openaiApiKey";
+const MODEL_KEY: &str = "cursor.defaultModel";
+
+/// Point Cursor at the relay, or with `dry_run` only report what that would
+/// change.
+pub fn apply(model_id: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    if let Some(model) = model_id {
+        super::ensure_model_supports_provider(&config::load(), model, "openai")?;
+    }
+    let path = settings_path()?;
+    let base_url = format!("{}/v1", super::ccr_base_url());
+    let changes = apply_at(
+        &path,
+        &base_url,
+        &super::get_forward_token(),
+        model_id,
+        dry_run,
+    )?;
+    if changes.applied {
+        logger::info(
+            "autoconfig",
+            &format!("Pointed Cursor at {} ({})", base_url, path.display()),
+        );
+    }
+    Ok(changes)
+}
+
+/// Restore the settings file from before [`apply`].
+pub fn revert() -> Result<AppliedChanges, String> {
+    edit::revert_target(TARGET, &settings_path()?)
+}
+
+/// The request Cursor makes with its current settings.
+pub(super) fn probe() -> Result<Probe, String> {
+    let file = Edit::open(&settings_path()?)?;
+    let base_url = verify::relay_url(verify::text(file.get(&[BASE_URL_KEY])))
+        .ok_or_else(|| verify::not_applied("Cursor"))?;
+    let model = match verify::text(file.get(&[MODEL_KEY])) {
+        Some(model) => model,
+        None => verify::default_model("openai")?,
+    };
+    let token = verify::text(file.get(&[API_KEY_KEY])).unwrap_or_default();
+    Ok(Probe::openai(&base_url, token, model))
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    super::settings_path(TARGET, super::vscode_settings_path(&["Cursor"]))
+}
+
+fn apply_at(
+    path: &Path,
+    base_url: &str,
+    token: &str,
+    model_id: Option<&str>,
+    dry_run: bool,
+) -> Result<AppliedChanges, String> {
+    let mut file = Edit::open(path)?;
+    let warnings = edit::repointed(&file, &[BASE_URL_KEY], base_url)
+        .into_iter()
+        .collect();
+    file.set(&[BASE_URL_KEY], base_url.into(), false)?;
+    file.set(&[API_KEY_KEY], token.into(), true)?;
+    if let Some(model) = model_id {
+        file.set(&[MODEL_KEY], model.into(), false)?;
+    }
+    Ok(AppliedChanges {
+        target: TARGET.to_string(),
+        applied: !dry_run,
+        files: vec![file.finish(dry_run)?],
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoconfig::edit::FileAction;
+
+    const FIXTURE: &str = include_str!("../../tests/fixtures/autoconfig/cursor-settings.json");
+
+    #[test]
+    fn test_fixture_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ccr-cursor-{}", std::process::id()));
+        let path = dir.join("User").join("settings.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, FIXTURE).unwrap();
+
+        let base = "http://127.0.0.1:9000/v1";
+        let applied = apply_at(&path, base, "ccr-token-abcdef1234", Some("gpt-4o"), false).unwrap();
+        assert_eq!(applied.files[0].action, FileAction::Updated);
+        assert!(applied.warnings[0].contains("https://old-relay.example/v1"));
+        let written = std::fs::read_to_string(&path).unwrap();
+        // Comments, trailing commas and other keys are untouched
+        assert!(written.starts_with(
+            "{\n    // Synced from another machine\n    \"workbench.colorTheme\": \"Cursor Dark\",\n"
+        ));
+        assert!(written.contains(
+            "/* Previous relay, replaced by autoconfig */\n    \"cursor.openaiBaseUrl\": \"http://127.0.0.1:9000/v1\",\n"
+        ));
+        assert!(written.contains("        \"**/.git\": true,\n    },\n    \"cursor.openaiApiKey\""));
+        assert_eq!(
+            super::super::jsonc::get(&written, &[MODEL_KEY]),
+            Some("gpt-4o".into())
+        );
+
+        edit::revert(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FIXTURE);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,389 @@
+//! Shared plumbing of the per-tool generators.
+//!
+//! An [`Edit`] sets a few keys of a JSON or YAML config file and leaves
+//! everything else as it was, comments included (see [`super::jsonc`] and
+//! [`super::yaml`]). Before the first write the previous file is saved next
+//! to it as `<name>.ccr-backup`, or a note that there was none, so
+//! [`revert`] can put it back or remove the file again. Applying twice keeps
+//! the first backup, so revert always returns to the state before the relay.
+//! A file that isn't valid JSON is never overwritten.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{jsonc, yaml};
+use crate::logger;
+
+/// What happened, or would happen, to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+    Created,
+    Updated,
+    Unchanged,
+    Restored,
+    Removed,
+}
+
+/// One key set in a file. Secrets show only their last characters.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyChange {
+    /// Levels joined by dots, e.g. `env.ANTHROPIC_BASE_URL`, with list
+    /// entries by id: `models[gpt-4o].apiBase`
+    pub key: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub action: FileAction,
+    pub changes: Vec<KeyChange>,
+    /// Where the previous file was saved, once written
+    pub backup: Option<String>,
+}
+
+/// Result of a preview, apply or revert.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedChanges {
+    pub target: String,
+    /// `false` for a preview: nothing was written
+    pub applied: bool,
+    pub files: Vec<FileChange>,
+    pub warnings: Vec<String>,
+}
+
+/// Contents of a `.ccr-backup` file.
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    /// `None` when the file didn't exist
+    content: Option<String>,
+}
+
+pub(super) fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ccr-backup");
+    path.with_file_name(name)
+}
+
+/// A scalar as YAML writes it.
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn mask(value: &Value) -> Value {
+    let text = value.as_str().unwrap_or_default();
+    let tail: String = if text.chars().count() > 8 {
+        let skip = text.chars().count() - 4;
+        text.chars().skip(skip).collect()
+    } else {
+        String::new()
+    };
+    Value::String(format!("****{}", tail))
+}
+
+/// Pending changes to one config file, JSON or, by its extension, YAML.
+pub(super) struct Edit {
+    path: PathBuf,
+    existed: bool,
+    yaml: bool,
+    text: String,
+    changes: Vec<KeyChange>,
+}
+
+impl Edit {
+    /// Read `path`. A missing or empty file starts empty, as `{}` for JSON;
+    /// a JSON file that isn't an object, comments and trailing commas
+    /// allowed, is an error rather than something to replace.
+    pub fn open(path: &Path) -> Result<Edit, String> {
+        let existing = match fs::read_to_string(path) {
+            Ok(text) => Some(text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let text = match existing.as_deref() {
+            Some(text) if !text.trim().is_empty() => text.to_string(),
+            _ if yaml => String::new(),
+            _ => "{}\n".to_string(),
+        };
+        if !yaml {
+            match serde_json::from_str(&jsonc::strip(&text)) {
+                Ok(Value::Object(_)) => {}
+                Ok(_) => return Err(format!("{} is not a JSON object", path.display())),
+                Err(e) => {
+                    return Err(format!(
+                        "{} is not valid JSON ({}). Fix or move it, then try again",
+                        path.display(),
+                        e
+                    ))
+                }
+            }
+        }
+        Ok(Edit {
+            path: path.to_path_buf(),
+            existed: existing.is_some(),
+            yaml,
+            text,
+            changes: Vec::new(),
+        })
+    }
+
+    /// The value at `key`, one element per level. YAML files only have
+    /// top-level scalars this way.
+    pub fn get(&self, key: &[&str]) -> Option<Value> {
+        match (self.yaml, key) {
+            (false, _) => jsonc::get(&self.text, key),
+            (true, [key]) => yaml::scalar(&self.text, key).map(Value::String),
+            (true, _) => None,
+        }
+    }
+
+    /// Set `key`, creating the objects on the way. A `secret` is masked in
+    /// the reported change.
+    pub fn set(&mut self, key: &[&str], value: Value, secret: bool) -> Result<(), String> {
+        let before = self.get(key);
+        if before.as_ref() == Some(&value) {
+            return Ok(());
+        }
+        self.text = match (self.yaml, key) {
+            (false, _) => jsonc::set(&self.text, key, &value)
+                .map_err(|e| format!("{} in {}", e, self.path.display()))?,
+            (true, [key]) => yaml::set_scalar(&self.text, key, &as_text(&value)),
+            (true, _) => return Err(format!("Nested keys in {}", self.path.display())),
+        };
+        self.record(key.join("."), before, value, secret);
+        Ok(())
+    }
+
+    /// The entry of the list at `list` whose `id_field` is `id`.
+    pub fn entry(&self, list: &[&str], id_field: &str, id: &str) -> Option<Map<String, Value>> {
+        self.entries(list)
+            .into_iter()
+            .find(|item| item.get(id_field).and_then(Value::as_str) == Some(id))
+    }
+
+    /// The object entries of the list at `list`.
+    pub fn entries(&self, list: &[&str]) -> Vec<Map<String, Value>> {
+        if self.yaml {
+            let [list] = list else {
+                return Vec::new();
+            };
+            return yaml::entries(&self.text, list).unwrap_or_default();
+        }
+        let Some(Value::Array(items)) = self.get(list) else {
+            return Vec::new();
+        };
+        items
+            .into_iter()
+            .filter_map(|item| match item {
+                Value::Object(item) => Some(item),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Set `fields`, as `(name, value, secret)`, on the entry of the list at
+    /// `list` whose `id_field` is `id`. A missing entry is appended, and a
+    /// missing list created. Other fields and entries stay as they are.
+    pub fn upsert(
+        &mut self,
+        list: &[&str],
+        id_field: &str,
+        id: &str,
+        fields: &[(&str, Value, bool)],
+    ) -> Result<(), String> {
+        let before = self.entry(list, id_field, id);
+        let mut changed: Vec<(&str, Value, bool)> = fields
+            .iter()
+            .filter(|(name, value, _)| before.as_ref().and_then(|b| b.get(*name)) != Some(value))
+            .cloned()
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        if before.is_none() {
+            changed.insert(0, (id_field, Value::from(id), false));
+        }
+        let located = |e: String| format!("{} in {}", e, self.path.display());
+        self.text = if self.yaml {
+            let [key] = list else {
+                return Err(located("Nested lists".to_string()));
+            };
+            let fields: Vec<(&str, String)> = changed
+                .iter()
+                .map(|(name, value, _)| (*name, as_text(value)))
+                .collect();
+            yaml::upsert(&self.text, key, id_field, id, &fields).map_err(located)?
+        } else {
+            match self.get(list) {
+                None => {
+                    let entry: Map<String, Value> = changed
+                        .iter()
+                        .map(|(name, value, _)| (name.to_string(), value.clone()))
+                        .collect();
+                    jsonc::set(&self.text, list, &Value::Array(vec![entry.into()]))
+                        .map_err(located)?
+                }
+                Some(Value::Array(items)) => {
+                    let index = items
+                        .iter()
+                        .position(|item| item.get(id_field).and_then(Value::as_str) == Some(id));
+                    let mut text = self.text.clone();
+                    match index {
+                        Some(index) => {
+                            let index = index.to_string();
+                            for (name, value, _) in &changed {
+                                let path: Vec<&str> = list
+                                    .iter()
+                                    .copied()
+                                    .chain([index.as_str(), *name])
+                                    .collect();
+                                text = jsonc::set(&text, &path, value).map_err(located)?;
+                            }
+                        }
+                        None => {
+                            let entry: Map<String, Value> = changed
+                                .iter()
+                                .map(|(name, value, _)| (name.to_string(), value.clone()))
+                                .collect();
+                            let index = items.len().to_string();
+                            let path: Vec<&str> =
+                                list.iter().copied().chain([index.as_str()]).collect();
+                            text = jsonc::set(&text, &path, &entry.into()).map_err(located)?;
+                        }
+                    }
+                    text
+                }
+                Some(_) => return Err(located(format!("'{}' is not a list", list.join(".")))),
+            }
+        };
+        for (name, value, secret) in changed {
+            let previous = before.as_ref().and_then(|b| b.get(name)).cloned();
+            self.record(
+                format!("{}[{}].{}", list.join("."), id, name),
+                previous,
+                value,
+                secret,
+            );
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, key: String, before: Option<Value>, after: Value, secret: bool) {
+        let show = |v: Value| if secret { mask(&v) } else { v };
+        self.changes.push(KeyChange {
+            key,
+            before: before.map(show),
+            after: Some(show(after)),
+        });
+    }
+
+    /// Write the file unless `dry_run` or nothing changed, saving the
+    /// previous one first.
+    pub fn finish(self, dry_run: bool) -> Result<FileChange, String> {
+        let action = if self.changes.is_empty() {
+            FileAction::Unchanged
+        } else if self.existed {
+            FileAction::Updated
+        } else {
+            FileAction::Created
+        };
+        let mut change = FileChange {
+            path: self.path.to_string_lossy().to_string(),
+            action,
+            changes: self.changes,
+            backup: None,
+        };
+        if dry_run || action == FileAction::Unchanged {
+            return Ok(change);
+        }
+        let backup = backup_path(&self.path);
+        if !backup.exists() {
+            let content = if self.existed {
+                Some(
+                    fs::read_to_string(&self.path)
+                        .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?,
+                )
+            } else {
+                None
+            };
+            let saved = serde_json::to_string(&Backup { content })
+                .map_err(|e| format!("Failed to serialize backup: {}", e))?;
+            super::write_file_content(&backup, &saved)?;
+        }
+        super::write_file_content(&self.path, &self.text)?;
+        change.backup = Some(backup.to_string_lossy().to_string());
+        Ok(change)
+    }
+}
+
+/// Warning when the URL at `key` points elsewhere than `url`, typically
+/// another relay.
+pub(super) fn repointed(file: &Edit, key: &[&str], url: &str) -> Option<String> {
+    let Some(Value::String(previous)) = file.get(key) else {
+        return None;
+    };
+    (previous.trim_end_matches('/') != url.trim_end_matches('/')).then(|| {
+        format!(
+            "{} pointed at {}; revert puts it back",
+            key.join("."),
+            previous
+        )
+    })
+}
+
+/// [`revert`] `path` as the result for `target`.
+pub(super) fn revert_target(target: &str, path: &Path) -> Result<AppliedChanges, String> {
+    let file = revert(path)?;
+    logger::info(
+        "autoconfig",
+        &format!("Reverted {} settings ({})", target, path.display()),
+    );
+    Ok(AppliedChanges {
+        target: target.to_string(),
+        applied: true,
+        files: vec![file],
+        warnings: Vec::new(),
+    })
+}
+
+/// Put back the file saved by the first [`Edit::finish`], or remove it if
+/// the relay created it.
+pub(super) fn revert(path: &Path) -> Result<FileChange, String> {
+    let backup = backup_path(path);
+    let saved = fs::read_to_string(&backup)
+        .map_err(|_| format!("No backup of {} to revert to", path.display()))?;
+    let saved: Backup = serde_json::from_str(&saved)
+        .map_err(|e| format!("Backup {} is damaged: {}", backup.display(), e))?;
+    let action = match saved.content {
+        Some(content) => {
+            super::write_file_content(path, &content)?;
+            FileAction::Restored
+        }
+        None => {
+            if path.exists() {
+                fs::remove_file(path)
+                    .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            }
+            FileAction::Removed
+        }
+    };
+    fs::remove_file(&backup)
+        .map_err(|e| format!("Failed to remove {}: {}", backup.display(), e))?;
+    Ok(FileChange {
+        path: path.to_string_lossy().to_string(),
+        action,
+        changes: Vec::new(),
+        backup: None,
+    })
+}
@@ -0,0 +1,365 @@
+//! Just enough of a JSON-with-comments reader to edit settings files in
+//! place. VS Code, Cursor and Zed accept `//` and `/* */` comments and
+//! trailing commas, and rewriting the whole document would drop them along
+//! with the user's key order. [`set`] only replaces the one value, or
+//! inserts one member or element, and leaves every other byte alone.
+
+use serde_json::Value;
+
+/// `text` with comments and trailing commas blanked to spaces. Offsets stay
+/// the same, so spans found in the result apply to `text`.
+pub(super) fn strip(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = bytes.to_vec();
+    let mut i = 0;
+    // Offset of the last comma outside a string, still to be judged
+    let mut comma: Option<usize> = None;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                comma = None;
+                i = string_end(bytes, i);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    out[i] = b' ';
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = text[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 2);
+                for b in &mut out[i..end] {
+                    if *b != b'\n' {
+                        *b = b' ';
+                    }
+                }
+                i = end;
+                continue;
+            }
+            b',' => comma = Some(i),
+            b'}' | b']' => {
+                if let Some(at) = comma.take() {
+                    out[at] = b' ';
+                }
+            }
+            b if b.is_ascii_whitespace() => {}
+            _ => comma = None,
+        }
+        i += 1;
+    }
+    // Only ASCII bytes inside comments were replaced, by ASCII
+    String::from_utf8(out).unwrap_or_default()
+}
+
+/// Offset just past the string starting at `start`.
+fn string_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn skip_ws(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// Offset just past the value starting at `start`.
+fn value_end(bytes: &[u8], start: usize) -> usize {
+    match bytes.get(start) {
+        Some(b'"') => string_end(bytes, start),
+        Some(b'{') | Some(b'[') => {
+            let mut depth = 0;
+            let mut i = start;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'"' => {
+                        i = string_end(bytes, i);
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return i + 1;
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+            bytes.len()
+        }
+        _ => {
+            let mut i = start;
+            while i < bytes.len() && !b",}] \t\r\n".contains(&bytes[i]) {
+                i += 1;
+            }
+            i
+        }
+    }
+}
+
+/// A member of an object, or an element of an array with its index as key.
+struct Child {
+    key: String,
+    /// Where the key starts, or the value for an element
+    start: usize,
+    value_start: usize,
+    value_end: usize,
+}
+
+/// Children of the object or array opening at `open`, in `stripped` text.
+fn children(stripped: &str, open: usize) -> Vec<Child> {
+    let bytes = stripped.as_bytes();
+    let is_array = bytes[open] == b'[';
+    let mut children = Vec::new();
+    let mut i = skip_ws(bytes, open + 1);
+    while i < bytes.len() && !matches!(bytes[i], b'}' | b']') {
+        let start = i;
+        let key = if is_array {
+            children.len().to_string()
+        } else {
+            let key_end = string_end(bytes, i);
+            let key = serde_json::from_str(&stripped[i..key_end]).unwrap_or_default();
+            i = skip_ws(bytes, skip_ws(bytes, key_end) + 1);
+            key
+        };
+        let end = value_end(bytes, i);
+        children.push(Child {
+            key,
+            start,
+            value_start: i,
+            value_end: end,
+        });
+        i = skip_ws(bytes, end);
+        if bytes.get(i) == Some(&b',') {
+            i = skip_ws(bytes, i + 1);
+        }
+    }
+    children
+}
+
+/// Whitespace before `at` on its line, if nothing else precedes it there.
+fn line_indent(text: &str, at: usize) -> Option<&str> {
+    let start = text[..at].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &text[start..at];
+    indent.trim().is_empty().then_some(indent)
+}
+
+/// Indentation step of the document: that of its first member on a line of
+/// its own, else two spaces.
+pub(super) fn indent_unit(text: &str) -> String {
+    let stripped = strip(text);
+    let root = skip_ws(stripped.as_bytes(), 0);
+    if stripped.as_bytes().get(root) == Some(&b'{') {
+        if let Some(member) = children(&stripped, root).first() {
+            if let Some(indent) = line_indent(text, member.start).filter(|i| !i.is_empty()) {
+                return indent.to_string();
+            }
+        }
+    }
+    "  ".to_string()
+}
+
+/// `value` pretty-printed with `unit` steps, continuing at `indent`.
+fn render(value: &Value, indent: &str, unit: &str) -> String {
+    use serde::Serialize;
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(unit.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut serializer).unwrap_or_default();
+    String::from_utf8(out)
+        .unwrap_or_default()
+        .replace('\n', &format!("\n{}", indent))
+}
+
+/// The value at `path` of the document. Array elements are addressed by
+/// their index.
+pub(super) fn get(text: &str, path: &[&str]) -> Option<Value> {
+    let stripped = strip(text);
+    let mut start = skip_ws(stripped.as_bytes(), 0);
+    for key in path {
+        if !matches!(stripped.as_bytes().get(start), Some(b'{') | Some(b'[')) {
+            return None;
+        }
+        start = children(&stripped, start)
+            .into_iter()
+            .find(|c| c.key == *key)?
+            .value_start;
+    }
+    let end = value_end(stripped.as_bytes(), start);
+    serde_json::from_str(&stripped[start..end]).ok()
+}
+
+/// `text` with the value at `path` set to `value`. Missing objects on the
+/// way are created, and the index just past the end of an array appends to
+/// it; anything else in the way is an error.
+pub(super) fn set(text: &str, path: &[&str], value: &Value) -> Result<String, String> {
+    let stripped = strip(text);
+    let bytes = stripped.as_bytes();
+    let unit = indent_unit(text);
+    let mut open = skip_ws(bytes, 0);
+    if bytes.get(open) != Some(&b'{') {
+        return Err("The document is not a JSON object".to_string());
+    }
+    for (depth, key) in path.iter().enumerate() {
+        let children = children(&stripped, open);
+        let is_array = bytes[open] == b'[';
+        let Some(child) = children.iter().find(|c| c.key == *key) else {
+            if is_array && *key != children.len().to_string() {
+                return Err(format!(
+                    "'{}' has no element {}",
+                    path[..depth].join("."),
+                    key
+                ));
+            }
+            // Wrap the value in the objects still missing below `key`
+            let nested = path[depth + 1..]
+                .iter()
+                .rev()
+                .fold(value.clone(), |inner, k| {
+                    Value::Object([(k.to_string(), inner)].into_iter().collect())
+                });
+            let key = (!is_array).then_some(*key);
+            return Ok(insert(
+                text, &stripped, open, &children, key, &nested, &unit,
+            ));
+        };
+        if depth + 1 == path.len() {
+            let indent = line_indent(text, child.start).unwrap_or("");
+            let rendered = render(value, indent, &unit);
+            return Ok(format!(
+                "{}{}{}",
+                &text[..child.value_start],
+                rendered,
+                &text[child.value_end..]
+            ));
+        }
+        if !matches!(bytes.get(child.value_start), Some(b'{') | Some(b'[')) {
+            return Err(format!("'{}' is not an object", path[..=depth].join(".")));
+        }
+        open = child.value_start;
+    }
+    Err("Empty key path".to_string())
+}
+
+/// Add `"key": value`, or just `value` in an array, as the last child of
+/// the object or array opening at `open`.
+fn insert(
+    text: &str,
+    stripped: &str,
+    open: usize,
+    children: &[Child],
+    key: Option<&str>,
+    value: &Value,
+    unit: &str,
+) -> String {
+    let key = key.map_or(String::new(), |k| format!("{}: ", Value::from(k)));
+    let (at, addition) = match children.last() {
+        Some(last) => match line_indent(text, last.start) {
+            Some(indent) => {
+                let rendered = render(value, indent, unit);
+                let end = last.value_end;
+                // After a comma or comment ending the line, on the next line
+                let line_end = text[end..].find('\n').map(|i| end + i).filter(|&nl| {
+                    stripped[end..nl].trim().is_empty() && !text[end..nl].contains("/*")
+                });
+                if let Some(nl) = line_end {
+                    // Follow the document in using trailing commas
+                    let trailing = text[end..nl].trim_start().starts_with(',');
+                    let (before, after) = if trailing { ("", ",") } else { (",", "") };
+                    return format!(
+                        "{}{}{}\n{}{}{}{}{}",
+                        &text[..end],
+                        before,
+                        &text[end..nl],
+                        indent,
+                        key,
+                        rendered,
+                        after,
+                        &text[nl..]
+                    );
+                }
+                (end, format!(",\n{}{}{}", indent, key, rendered))
+            }
+            // Children on one line: keep it that way
+            None => (last.value_end, format!(", {}{}", key, value)),
+        },
+        None => {
+            let outer = line_indent(text, open).unwrap_or_else(|| {
+                let start = text[..open].rfind('\n').map_or(0, |i| i + 1);
+                let line = &text[start..open];
+                &line[..line.len() - line.trim_start().len()]
+            });
+            let inner = format!("{}{}", outer, unit);
+            let close = value_end(stripped.as_bytes(), open) - 1;
+            let child = format!("\n{}{}{}", inner, key, render(value, &inner, unit));
+            if text[open + 1..close].trim().is_empty() {
+                return format!("{}{}\n{}{}", &text[..=open], child, outer, &text[close..]);
+            }
+            // Keep comments inside the empty object after the new child
+            (open + 1, child)
+        }
+    };
+    format!("{}{}{}", &text[..at], addition, &text[at..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_edits_keep_comments_and_order() {
+        let text = "{\n    // theme\n    \"workbench.colorTheme\": \"Dark+\", /* inline */\n    \"editor\": {\"fontSize\": 14,},\n}\n";
+        let stripped = strip(text);
+        assert_eq!(stripped.len(), text.len());
+        assert!(serde_json::from_str::<Value>(&stripped).is_ok());
+        assert_eq!(indent_unit(text), "    ");
+        assert_eq!(get(text, &["editor", "fontSize"]), Some(json!(14)));
+
+        let text = set(text, &["workbench.colorTheme"], &json!("Light+")).unwrap();
+        assert!(text.contains("// theme\n    \"workbench.colorTheme\": \"Light+\", /* inline */"));
+        let text = set(text.as_str(), &["editor", "tabSize"], &json!(2)).unwrap();
+        assert!(text.contains("{\"fontSize\": 14, \"tabSize\": 2,}"));
+        let text = set(&text, &["a.b", "c"], &json!({"d": true})).unwrap();
+        assert!(text.ends_with(
+            "\"editor\": {\"fontSize\": 14, \"tabSize\": 2,},\n    \"a.b\": {\n        \"c\": {\n            \"d\": true\n        }\n    },\n}\n"
+        ));
+        assert_eq!(get(&text, &["a.b", "c", "d"]), Some(json!(true)));
+        assert!(set(&text, &["workbench.colorTheme", "x"], &json!(1)).is_err());
+
+        assert_eq!(
+            set("{}", &["env", "A"], &json!("1")).unwrap(),
+            "{\n  \"env\": {\n    \"A\": \"1\"\n  }\n}"
+        );
+        let text = "{\n  \"models\": [\n    {\"title\": \"a\"}, // first\n  ]\n}";
+        let text = set(text, &["models", "1"], &json!({"title": "b"})).unwrap();
+        assert_eq!(
+            text,
+            "{\n  \"models\": [\n    {\"title\": \"a\"}, // first\n    {\n      \"title\": \"b\"\n    },\n  ]\n}"
+        );
+        let text = set(&text, &["models", "0", "apiBase"], &json!("x")).unwrap();
+        assert_eq!(
+            get(&text, &["models", "0"]),
+            Some(json!({"title": "a", "apiBase": "x"}))
+        );
+        assert!(set(&text, &["models", "5"], &json!({})).is_err());
+        assert_eq!(
+            set("{ // none yet\n}", &["a"], &json!(1)).unwrap(),
+            "{\n  \"a\": 1 // none yet\n}"
+        );
+    }
+}
@@ -8,12 +8,43 @@ use uuid::Uuid;
 use crate::config;
 use crate::logger;
 
-const CCR_BASE_URL: &str = "http://127.0.0.1:8787";
+pub mod claude_code;
+pub mod continue_dev;
+pub mod cursor;
+mod edit;
+mod jsonc;
+mod verify;
+pub mod vscode;
+mod yaml;
+pub mod zed;
+
+pub use edit::AppliedChanges;
+pub use verify::VerifyResult;
+
 const FILE_KIND_SETTINGS: &str = "settings";
 const FILE_KIND_CONFIG: &str = "config";
 const FILE_KIND_AUTH: &str = "auth";
 const FILE_KIND_ENV: &str = "env";
 
+/// Base URL of the relay tools are pointed at: the adopted remote relay
+/// (`discovery.remote_url`) or this one, following `server.host` /
+/// `server.port`.
+fn ccr_base_url() -> String {
+    let remote = config::load().discovery.remote_url;
+    if remote.trim().is_empty() {
+        crate::server::base_url()
+    } else {
+        remote.trim().trim_end_matches('/').to_string()
+    }
+}
+
+/// Whether a tool config points at this relay.
+fn points_at_ccr(text: &str) -> bool {
+    let url = ccr_base_url();
+    let authority = url.split_once("://").map(|(_, rest)| rest).unwrap_or(&url);
+    text.contains(authority)
+}
+
 // Backup structures
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ToolConfigBackup {
@@ -146,6 +177,48 @@ fn get_claude_config_path() -> Option<PathBuf> {
     dirs::home_dir().map(|h| h.join(".claude").join("settings.json"))
 }
 
+/// User `settings.json` of a VS Code based editor: under the first of
+/// `apps` (folder names in the OS config directory, e.g. "Code") that has
+/// one, else the first.
+fn vscode_settings_path(apps: &[&str]) -> Option<PathBuf> {
+    find_settings(&dirs::config_dir()?, apps)
+}
+
+fn find_settings(config_dir: &Path, apps: &[&str]) -> Option<PathBuf> {
+    let candidates: Vec<PathBuf> = apps
+        .iter()
+        .map(|app| config_dir.join(app).join("User").join("settings.json"))
+        .collect();
+    candidates
+        .iter()
+        .find(|path| path.exists())
+        .or(candidates.first())
+        .cloned()
+}
+
+/// Settings file a generator writes for `tool`: an enabled
+/// `backup.tool_paths` entry of kind "settings", or without a kind, else
+/// `default`.
+fn settings_path(tool: &str, default: Option<PathBuf>) -> Result<PathBuf, String> {
+    let settings = config::load();
+    let configured = settings.backup.tool_paths.iter().find(|p| {
+        p.enabled
+            && p.tool == tool
+            && !p.config_path.trim().is_empty()
+            && p.file_kind
+                .as_deref()
+                .is_none_or(|k| k == FILE_KIND_SETTINGS)
+    });
+    if let Some(entry) = configured {
+        let path = entry.config_path.trim();
+        return Ok(match (path.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => PathBuf::from(path),
+        });
+    }
+    default.ok_or_else(|| format!("Cannot determine the {} settings path", tool))
+}
+
 fn get_codex_config_dir() -> Option<PathBuf> {
     dirs::home_dir().map(|h| h.join(".codex"))
 }
@@ -213,6 +286,9 @@ fn tool_paths_for_kind(entries: &[ToolFileEntry], kind: &str) -> Vec<PathBuf> {
 
 fn get_forward_token() -> String {
     let settings = config::load();
+    if !settings.discovery.remote_url.trim().is_empty() {
+        return settings.discovery.remote_token;
+    }
     settings
         .forward_token
         .unwrap_or_else(|| "ccr-token".to_string())
@@ -251,6 +327,39 @@ fn ensure_model_supports_provider(
     Ok(())
 }
 
+/// A relay model as the editor generators describe it.
+struct RelayModel {
+    id: String,
+    name: String,
+    /// "anthropic" when the preferred route is Anthropic, else "openai":
+    /// the OpenAI-compatible endpoint serves every provider
+    provider: &'static str,
+}
+
+fn relay_model(settings: &config::Settings, model_id: &str) -> Result<RelayModel, String> {
+    let model = settings
+        .models
+        .iter()
+        .find(|m| m.id.eq_ignore_ascii_case(model_id))
+        .ok_or_else(|| format!("Model '{}' not found in configuration", model_id))?;
+    let mut routes = collect_model_routes(settings, model_id);
+    routes.sort_by_key(|route| std::cmp::Reverse(route.priority.unwrap_or(0)));
+    let provider = match routes.first() {
+        Some(route) if route.provider.eq_ignore_ascii_case("anthropic") => "anthropic",
+        _ => "openai",
+    };
+    let name = if model.display_name.trim().is_empty() {
+        model.id.clone()
+    } else {
+        model.display_name.clone()
+    };
+    Ok(RelayModel {
+        id: model.id.clone(),
+        name,
+        provider,
+    })
+}
+
 fn select_route_for_provider(
     settings: &config::Settings,
     model_id: &str,
@@ -304,7 +413,7 @@ fn get_claude_status() -> ToolConfigStatus {
                     let is_ccr = env
                         .anthropic_base_url
                         .as_ref()
-                        .map(|url| url.contains("127.0.0.1:8787"))
+                        .map(|url| points_at_ccr(url))
                         .unwrap_or(false);
 
                     if is_ccr {
@@ -338,7 +447,7 @@ fn get_codex_status() -> ToolConfigStatus {
 
         if let Ok(content) = fs::read_to_string(&path) {
             // Simple check for CCR configuration
-            let is_ccr = content.contains("127.0.0.1:8787");
+            let is_ccr = points_at_ccr(&content);
             if is_ccr {
                 // Extract model from config
                 let model = content
@@ -350,7 +459,7 @@ fn get_codex_status() -> ToolConfigStatus {
                 return ToolConfigStatus {
                     configured: true,
                     model,
-                    base_url: Some(format!("{}/v1", CCR_BASE_URL)),
+                    base_url: Some(format!("{}/v1", ccr_base_url())),
                 };
             }
         }
@@ -379,7 +488,7 @@ fn get_gemini_status() -> ToolConfigStatus {
                     .model
                     .as_ref()
                     .and_then(|m| m.base_url.as_ref())
-                    .map(|url| url.contains("127.0.0.1:8787"))
+                    .map(|url| points_at_ccr(url))
                     .unwrap_or(false);
 
                 if is_ccr {
@@ -416,7 +525,7 @@ fn check_legacy_env(env_path: &Path) -> ToolConfigStatus {
     match fs::read_to_string(env_path) {
         Ok(content) => {
             // Check for CCR configuration in .env
-            let is_ccr = content.contains("127.0.0.1:8787");
+            let is_ccr = points_at_ccr(&content);
             if is_ccr {
                 // Extract values from .env
                 let mut model = None;
@@ -510,7 +619,7 @@ fn configure_claude(model_id: &str, fast_model_id: Option<&str>) -> Result<(), S
 
         // Update env section
         let mut env = settings.env.unwrap_or_default();
-        env.anthropic_base_url = Some(format!("{}/anthropic", CCR_BASE_URL));
+        env.anthropic_base_url = Some(format!("{}/anthropic", ccr_base_url()));
         env.anthropic_auth_token = Some(token.clone());
 
         // Use special reserved model names for Claude Code
@@ -593,8 +702,20 @@ fn create_claude_code_special_models(
         }],
         price_prompt_per_1k: 0.0,
         price_completion_per_1k: 0.0,
+        price_cache_write_per_1k: None,
+        price_cache_read_per_1k: None,
+        price_reasoning_per_1k: None,
+        price_tiers: Vec::new(),
+        capabilities: config::ModelCapabilities::default(),
+        price_source: None,
+        price_updated_at: None,
         priority: 100, // System reserved
         is_temporary: true,
+        mirror: None,
+        max_concurrency: None,
+        max_queue_wait_ms: None,
+        context_guard: None,
+        safety_settings: Vec::new(),
     };
 
     // Create the Haiku special model (points to fast model)
@@ -612,8 +733,20 @@ fn create_claude_code_special_models(
         }],
         price_prompt_per_1k: 0.0,
         price_completion_per_1k: 0.0,
+        price_cache_write_per_1k: None,
+        price_cache_read_per_1k: None,
+        price_reasoning_per_1k: None,
+        price_tiers: Vec::new(),
+        capabilities: config::ModelCapabilities::default(),
+        price_source: None,
+        price_updated_at: None,
         priority: 100, // System reserved
         is_temporary: true,
+        mirror: None,
+        max_concurrency: None,
+        max_queue_wait_ms: None,
+        context_guard: None,
+        safety_settings: Vec::new(),
     };
 
     // Add to settings
@@ -673,7 +806,7 @@ requires_openai_auth = true
 "#,
         provider_name = provider_name,
         model_id = model_id,
-        base_url = CCR_BASE_URL
+        base_url = ccr_base_url()
     );
 
     for config_path in config_paths {
@@ -695,7 +828,7 @@ fn configure_gemini(model_id: &str) -> Result<(), String> {
     }
 
     let token = get_forward_token();
-    let base_url = format!("{}/gemini", CCR_BASE_URL);
+    let base_url = format!("{}/gemini", ccr_base_url());
 
     // 1. Configure settings.json with model settings
     for settings_path in settings_paths {
@@ -1000,3 +1133,198 @@ pub fn delete_backup(backup_id: &str) -> Result<(), String> {
 
     Ok(())
 }
+
+// Generators
+
+/// Tools [`apply`] can point at the relay.
+pub const TARGETS: [&str; 5] = [
+    claude_code::TARGET,
+    cursor::TARGET,
+    vscode::TARGET,
+    continue_dev::TARGET,
+    zed::TARGET,
+];
+
+fn unknown_target(target: &str) -> String {
+    format!(
+        "Unknown autoconfig target '{}', expected one of: {}",
+        target,
+        TARGETS.join(", ")
+    )
+}
+
+/// Point `target` at the relay, optionally with `model` as its default.
+/// With `dry_run` nothing is written.
+pub fn apply(target: &str, model: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    match target {
+        claude_code::TARGET => claude_code::apply(model, dry_run),
+        cursor::TARGET => cursor::apply(model, dry_run),
+        vscode::TARGET => vscode::apply(model, dry_run),
+        continue_dev::TARGET => continue_dev::apply(model, dry_run),
+        zed::TARGET => zed::apply(model, dry_run),
+        _ => Err(unknown_target(target)),
+    }
+}
+
+/// Undo [`apply`] for `target`.
+pub fn revert(target: &str) -> Result<AppliedChanges, String> {
+    match target {
+        claude_code::TARGET => claude_code::revert(),
+        cursor::TARGET => cursor::revert(),
+        vscode::TARGET => vscode::revert(),
+        continue_dev::TARGET => continue_dev::revert(),
+        zed::TARGET => zed::revert(),
+        _ => Err(unknown_target(target)),
+    }
+}
+
+/// Send the request `target` would make with the settings it now has
+/// through the relay, and report whether and how it got an answer.
+pub async fn verify(target: &str) -> Result<VerifyResult, String> {
+    let probe = match target {
+        claude_code::TARGET => claude_code::probe(),
+        cursor::TARGET => cursor::probe(),
+        vscode::TARGET => vscode::probe(),
+        continue_dev::TARGET => continue_dev::probe(),
+        zed::TARGET => zed::probe(),
+        _ => Err(unknown_target(target)),
+    }?;
+    verify::run(target, probe).await
+}
+
+// Editor snippets
+
+/// Editors [`editor_snippet`] knows, as `(id, label)`.
+pub const SNIPPET_EDITORS: [(&str, &str); 4] = [
+    ("claude-code", "Claude Code"),
+    ("cursor", "Cursor"),
+    ("continue", "Continue"),
+    ("openai-sdk", "OpenAI SDK"),
+];
+
+/// Ready-to-paste configuration pointing an editor at the relay.
+#[derive(Debug, Clone, Serialize)]
+pub struct EditorSnippet {
+    pub editor: String,
+    pub label: String,
+    /// "shell", "text", "yaml" or "python", for highlighting
+    pub language: &'static str,
+    /// Where the snippet goes
+    pub target: &'static str,
+    pub content: String,
+}
+
+/// Configuration for `editor` using `model`, with the current base URL and
+/// forward token. Built on every call, so it follows port and token changes.
+pub fn editor_snippet(editor: &str, model: &str) -> Result<EditorSnippet, String> {
+    let settings = config::load();
+    let provider = if editor == "claude-code" {
+        "anthropic"
+    } else {
+        "openai"
+    };
+    if SNIPPET_EDITORS.iter().any(|(id, _)| *id == editor) {
+        ensure_model_supports_provider(&settings, model, provider)?;
+    }
+    render_snippet(editor, &ccr_base_url(), &get_forward_token(), model)
+}
+
+fn render_snippet(
+    editor: &str,
+    base_url: &str,
+    token: &str,
+    model: &str,
+) -> Result<EditorSnippet, String> {
+    let (language, target, content) = match editor {
+        "claude-code" => (
+            "shell",
+            "Shell profile, or the env section of ~/.claude/settings.json",
+            format!(
+                "export ANTHROPIC_BASE_URL=\"{base_url}/anthropic\"\n\
+                 export ANTHROPIC_AUTH_TOKEN=\"{token}\"\n\
+                 export ANTHROPIC_MODEL=\"{model}\"\n"
+            ),
+        ),
+        "cursor" => (
+            "text",
+            "Cursor Settings > Models > OpenAI API Key",
+            format!(
+                "OpenAI API Key: {token}\n\
+                 Override OpenAI Base URL: {base_url}/v1\n\
+                 Model: {model}\n"
+            ),
+        ),
+        "continue" => (
+            "yaml",
+            "~/.continue/config.yaml",
+            format!(
+                "models:\n  \
+                 - name: {model}\n    \
+                 provider: openai\n    \
+                 model: {model}\n    \
+                 apiBase: {base_url}/v1\n    \
+                 apiKey: {token}\n"
+            ),
+        ),
+        "openai-sdk" => (
+            "python",
+            "Python with the openai package",
+            format!(
+                "from openai import OpenAI\n\n\
+                 client = OpenAI(base_url=\"{base_url}/v1\", api_key=\"{token}\")\n\
+                 response = client.chat.completions.create(\n    \
+                 model=\"{model}\",\n    \
+                 messages=[{{\"role\": \"user\", \"content\": \"Hello\"}}],\n\
+                 )\n"
+            ),
+        ),
+        _ => {
+            let known: Vec<&str> = SNIPPET_EDITORS.iter().map(|(id, _)| *id).collect();
+            return Err(format!(
+                "Unknown editor '{}', expected one of: {}",
+                editor,
+                known.join(", ")
+            ));
+        }
+    };
+    let label = SNIPPET_EDITORS
+        .iter()
+        .find(|(id, _)| *id == editor)
+        .map(|(_, label)| label.to_string())
+        .unwrap_or_default();
+    Ok(EditorSnippet {
+        editor: editor.to_string(),
+        label,
+        language,
+        target,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_editor_snippets() {
+        let base = "http://127.0.0.1:9000";
+        let snippet = render_snippet("claude-code", base, "tok", "sonnet").unwrap();
+        assert_eq!(
+            snippet.content,
+            "export ANTHROPIC_BASE_URL=\"http://127.0.0.1:9000/anthropic\"\n\
+             export ANTHROPIC_AUTH_TOKEN=\"tok\"\n\
+             export ANTHROPIC_MODEL=\"sonnet\"\n"
+        );
+        assert_eq!(snippet.label, "Claude Code");
+
+        let snippet = render_snippet("continue", base, "tok", "gpt-4o").unwrap();
+        assert!(snippet
+            .content
+            .contains("\n    apiBase: http://127.0.0.1:9000/v1\n"));
+        let snippet = render_snippet("openai-sdk", base, "tok", "gpt-4o").unwrap();
+        assert!(snippet.content.contains("{\"role\": \"user\""));
+
+        let err = render_snippet("vim", base, "tok", "gpt-4o").unwrap_err();
+        assert!(err.contains("claude-code, cursor, continue, openai-sdk"));
+    }
+}
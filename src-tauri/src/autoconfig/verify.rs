@@ -0,0 +1,327 @@
+//! Checks an applied target end to end.
+//!
+//! Each target reads back the URL, token and model its settings now hold,
+//! and the relay is sent the request the editor would make: a one-token
+//! chat completion, or an Anthropic messages call with `max_tokens: 1`. It
+//! carries the [`middleware::SYNTHETIC_HEADER`], so its usage row is logged
+//! but left out of cost and budget totals. A failure is classified so the
+//! UI can offer the matching fix.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::forward::activity::{self, Activity, Phase};
+use crate::forward::{client, middleware};
+use crate::{config, logger};
+
+const TIMEOUT_SECS: u64 = 60;
+/// How long to wait for the activity feed to report the upstream.
+const UPSTREAM_WAIT: Duration = Duration::from_millis(500);
+
+/// The request an editor makes, as read from its settings.
+pub(super) struct Probe {
+    /// "anthropic" or "openai"
+    provider: &'static str,
+    /// Full endpoint URL
+    url: String,
+    token: String,
+    model: String,
+    /// Token sent as `Authorization: Bearer` instead of `x-api-key`
+    bearer: bool,
+}
+
+impl Probe {
+    /// A chat completion below the OpenAI-style `base_url`.
+    pub fn openai(base_url: &str, token: String, model: String) -> Probe {
+        Probe {
+            provider: "openai",
+            url: format!("{}/chat/completions", base_url.trim_end_matches('/')),
+            token,
+            model,
+            bearer: true,
+        }
+    }
+
+    /// A messages call to `url`.
+    pub fn anthropic(url: String, token: String, model: String, bearer: bool) -> Probe {
+        Probe {
+            provider: "anthropic",
+            url,
+            token,
+            model,
+            bearer,
+        }
+    }
+}
+
+/// Why a verification failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// Nothing answered at the configured URL
+    RelayDown,
+    /// The token was rejected, by the relay or the upstream
+    Auth,
+    /// The relay or the upstream does not know the model
+    ModelNotFound,
+    /// The upstream failed or could not be reached
+    UpstreamDown,
+    Other,
+}
+
+impl Problem {
+    fn fix(self) -> Option<&'static str> {
+        match self {
+            Problem::RelayDown => {
+                Some("Start the relay server, or apply the target again if its address changed")
+            }
+            Problem::Auth => Some(
+                "Apply the target again to write the current forward token, or check the upstream's API key",
+            ),
+            Problem::ModelNotFound => {
+                Some("Add the model to the relay, or apply the target again with one of its models")
+            }
+            Problem::UpstreamDown => Some("Check the upstream's URL, API key and status"),
+            Problem::Other => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyResult {
+    pub target: String,
+    pub ok: bool,
+    pub model: String,
+    pub url: String,
+    /// Upstream that served the request, or the last one tried
+    pub upstream_id: Option<String>,
+    pub latency_ms: u64,
+    /// `None` when the relay could not be reached
+    pub status: Option<u16>,
+    pub problem: Option<Problem>,
+    /// Error message of the response
+    pub message: Option<String>,
+    /// What to do about `problem`
+    pub fix: Option<String>,
+}
+
+/// A non-empty string setting.
+pub(super) fn text(value: Option<Value>) -> Option<String> {
+    match value {
+        Some(Value::String(s)) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// `url` if it points at the relay.
+pub(super) fn relay_url(url: Option<String>) -> Option<String> {
+    url.filter(|url| super::points_at_ccr(url))
+}
+
+pub(super) fn not_applied(tool: &str) -> String {
+    format!("{} is not pointed at the relay: apply it first", tool)
+}
+
+/// The first model whose preferred route is `provider`, for tools that fall
+/// back to their own default model.
+pub(super) fn default_model(provider: &str) -> Result<String, String> {
+    let settings = config::load();
+    settings
+        .models
+        .iter()
+        .filter(|m| !m.is_temporary)
+        .find(|m| super::relay_model(&settings, &m.id).is_ok_and(|r| r.provider == provider))
+        .map(|m| m.id.clone())
+        .ok_or_else(|| format!("No model with an {} route to verify with", provider))
+}
+
+/// Send `probe` through the relay and report how it went.
+pub(super) async fn run(target: &str, probe: Probe) -> Result<VerifyResult, String> {
+    let client = client::create_client(TIMEOUT_SECS).map_err(|e| e.to_string())?;
+    let request_id = format!("verify-{}", uuid::Uuid::new_v4());
+    let body = match probe.provider {
+        "anthropic" => json!({
+            "model": probe.model,
+            "max_tokens": 1,
+            "messages": [{"role": "user", "content": "ping"}],
+        }),
+        _ => json!({
+            "model": probe.model,
+            "max_tokens": 1,
+            "stream": false,
+            "messages": [{"role": "user", "content": "ping"}],
+        }),
+    };
+    let mut request = client
+        .post(&probe.url)
+        .header("x-request-id", &request_id)
+        .header(middleware::SYNTHETIC_HEADER, middleware::synthetic_token())
+        .json(&body);
+    request = if probe.bearer {
+        request.bearer_auth(&probe.token)
+    } else {
+        request.header("x-api-key", &probe.token)
+    };
+    if probe.provider == "anthropic" {
+        request = request.header("anthropic-version", "2023-06-01");
+    }
+
+    let mut events = activity::subscribe();
+    let started = Instant::now();
+    let response = request.send().await;
+    let (status, text) = match response {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            (Some(status), response.text().await.unwrap_or_default())
+        }
+        Err(e) => (None, e.to_string()),
+    };
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let upstream_id = match status {
+        Some(_) => upstream_of(&mut events, &request_id).await,
+        None => None,
+    };
+
+    let ok = status.is_some_and(|s| (200..300).contains(&s));
+    let (problem, message) = match status {
+        _ if ok => (None, None),
+        Some(status) => {
+            let message = error_message(&text);
+            (Some(classify(status, &message)), Some(message))
+        }
+        None => (Some(Problem::RelayDown), Some(text)),
+    };
+    let result = VerifyResult {
+        target: target.to_string(),
+        ok,
+        model: probe.model,
+        url: probe.url,
+        upstream_id,
+        latency_ms,
+        status,
+        problem,
+        message,
+        fix: problem.and_then(Problem::fix).map(str::to_string),
+    };
+    if result.ok {
+        logger::info(
+            "autoconfig",
+            &format!(
+                "Verified {}: {} via {} in {} ms",
+                target,
+                result.model,
+                result.upstream_id.as_deref().unwrap_or("unknown upstream"),
+                latency_ms
+            ),
+        );
+    } else {
+        logger::warn(
+            "autoconfig",
+            &format!(
+                "Verification of {} failed ({:?}): {}",
+                target,
+                problem.unwrap_or(Problem::Other),
+                result.message.as_deref().unwrap_or("")
+            ),
+        );
+    }
+    Ok(result)
+}
+
+/// The upstream the activity feed last reported for `request_id`.
+async fn upstream_of(
+    events: &mut broadcast::Receiver<Activity>,
+    request_id: &str,
+) -> Option<String> {
+    let deadline = tokio::time::Instant::now() + UPSTREAM_WAIT;
+    let mut upstream = None;
+    loop {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Ok(event)) if event.request_id == request_id => {
+                if !event.upstream_id.is_empty() {
+                    upstream = Some(event.upstream_id);
+                }
+                if !matches!(event.phase, Phase::Started | Phase::FirstToken) {
+                    return upstream;
+                }
+            }
+            Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => {}
+            Ok(Err(RecvError::Closed)) | Err(_) => return upstream,
+        }
+    }
+}
+
+/// The `error.message` of an OpenAI or Anthropic error body, else the body.
+fn error_message(body: &str) -> String {
+    let parsed: Option<Value> = serde_json::from_str(body).ok();
+    let message = parsed.as_ref().and_then(|v| {
+        v.pointer("/error/message")
+            .or_else(|| v.get("message"))
+            .and_then(Value::as_str)
+    });
+    match message {
+        Some(message) => message.to_string(),
+        None => body.chars().take(300).collect(),
+    }
+}
+
+fn classify(status: u16, message: &str) -> Problem {
+    let message = message.to_ascii_lowercase();
+    let about_model = message.contains("model")
+        && [
+            "not found",
+            "not configured",
+            "does not exist",
+            "unknown",
+            "not supported",
+            "invalid model",
+        ]
+        .iter()
+        .any(|phrase| message.contains(phrase));
+    match status {
+        401 | 403 => Problem::Auth,
+        404 if message.contains("upstream") && !about_model => Problem::UpstreamDown,
+        404 => Problem::ModelNotFound,
+        _ if about_model => Problem::ModelNotFound,
+        500..=599 => Problem::UpstreamDown,
+        _ => Problem::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_are_classified() {
+        let relay_404 =
+            r#"{"error":{"type":"model_not_found","message":"Model 'gpt-5' not configured"}}"#;
+        assert_eq!(error_message(relay_404), "Model 'gpt-5' not configured");
+        assert_eq!(
+            classify(404, &error_message(relay_404)),
+            Problem::ModelNotFound
+        );
+
+        let upstream_400 = r#"{"type":"error","error":{"type":"invalid_request_error","message":"model: claude-x does not exist"}}"#;
+        assert_eq!(
+            classify(400, &error_message(upstream_400)),
+            Problem::ModelNotFound
+        );
+        assert_eq!(classify(401, "Invalid API key"), Problem::Auth);
+        assert_eq!(classify(403, "forbidden"), Problem::Auth);
+        assert_eq!(
+            classify(502, "Request failed: connection refused"),
+            Problem::UpstreamDown
+        );
+        assert_eq!(
+            classify(404, "No upstream for provider"),
+            Problem::UpstreamDown
+        );
+        assert_eq!(classify(429, "slow down"), Problem::Other);
+        assert_eq!(error_message("Bad Gateway"), "Bad Gateway");
+        assert!(Problem::Other.fix().is_none());
+    }
+}
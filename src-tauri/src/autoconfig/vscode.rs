@@ -0,0 +1,218 @@
+//! VS Code: relay models for Copilot Chat's custom OpenAI-compatible
+//! endpoint (`github.copilot.chat.customOAIModels` in the user
+//! `settings.json`).
+//!
+//! Registers the chosen model, or every model with an openai route, at the
+//! relay's `/v1` endpoint. Existing entries only get their URL and key
+//! requirement updated, so tuned token limits survive. Copilot keeps API
+//! keys out of `settings.json` and asks for one per model on first use.
+//! The file is the first of VS Code, Insiders and VSCodium that exists,
+//! unless `backup.tool_paths` has a "vscode" entry.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use super::edit::{self, AppliedChanges, Edit};
+use super::verify::{self, Probe};
+use crate::{config, logger};
+
+pub const TARGET: &str = "vscode";
+
+const MODELS_KEY: &str = "github.copilot.chat.customOAIModels";
+
+/// Register relay models in VS Code, or with `dry_run` only report what
+/// that would change.
+pub fn apply(model_id: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    let settings = config::load();
+    let models = models_to_register(&settings, model_id)?;
+    let path = settings_path()?;
+    let base_url = format!("{}/v1", super::ccr_base_url());
+    let changes = apply_at(&path, &base_url, &models, dry_run)?;
+    if changes.applied {
+        logger::info(
+            "autoconfig",
+            &format!(
+                "Registered {} relay model(s) in VS Code ({})",
+                models.len(),
+                path.display()
+            ),
+        );
+    }
+    Ok(changes)
+}
+
+/// Restore the settings file from before [`apply`].
+pub fn revert() -> Result<AppliedChanges, String> {
+    edit::revert_target(TARGET, &settings_path()?)
+}
+
+/// The request Copilot Chat makes for the first model registered at the
+/// relay, with the forward token it was told to enter.
+pub(super) fn probe() -> Result<Probe, String> {
+    let file = Edit::open(&settings_path()?)?;
+    let models = match file.get(&[MODELS_KEY]) {
+        Some(Value::Object(models)) => models,
+        _ => Default::default(),
+    };
+    let (id, url) = models
+        .into_iter()
+        .find_map(|(id, entry)| {
+            let url = verify::relay_url(verify::text(entry.get("url").cloned()))?;
+            Some((id, url))
+        })
+        .ok_or_else(|| verify::not_applied("VS Code"))?;
+    Ok(Probe::openai(&url, super::get_forward_token(), id))
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    super::settings_path(
+        TARGET,
+        super::vscode_settings_path(&["Code", "Code - Insiders", "VSCodium"]),
+    )
+}
+
+/// `(id, name)` of `model_id`, or of every model with an openai route.
+fn models_to_register(
+    settings: &config::Settings,
+    model_id: Option<&str>,
+) -> Result<Vec<(String, String)>, String> {
+    if let Some(model) = model_id {
+        super::ensure_model_supports_provider(settings, model, "openai")?;
+    }
+    let mut models: Vec<(String, String)> = Vec::new();
+    for model in &settings.models {
+        let wanted = match model_id {
+            Some(id) => model.id.eq_ignore_ascii_case(id),
+            None => {
+                !model.is_temporary
+                    && model
+                        .resolved_routes()
+                        .iter()
+                        .any(|r| r.provider.eq_ignore_ascii_case("openai"))
+            }
+        };
+        if wanted && !models.iter().any(|(id, _)| *id == model.id) {
+            let name = if model.display_name.trim().is_empty() {
+                model.id.clone()
+            } else {
+                model.display_name.clone()
+            };
+            models.push((model.id.clone(), name));
+        }
+    }
+    if models.is_empty() {
+        return Err("No model with an openai route to register".to_string());
+    }
+    Ok(models)
+}
+
+fn apply_at(
+    path: &Path,
+    base_url: &str,
+    models: &[(String, String)],
+    dry_run: bool,
+) -> Result<AppliedChanges, String> {
+    let mut file = Edit::open(path)?;
+    let mut warnings = Vec::new();
+    for (id, name) in models {
+        if file.get(&[MODELS_KEY, id]).is_none() {
+            let entry = json!({
+                "name": name,
+                "url": base_url,
+                "toolCalling": true,
+                "vision": false,
+                "maxInputTokens": 128000,
+                "maxOutputTokens": 16000,
+                "requiresAPIKey": true,
+            });
+            file.set(&[MODELS_KEY, id], entry, false)?;
+            continue;
+        }
+        warnings.extend(edit::repointed(&file, &[MODELS_KEY, id, "url"], base_url));
+        file.set(&[MODELS_KEY, id, "url"], base_url.into(), false)?;
+        file.set(&[MODELS_KEY, id, "requiresAPIKey"], true.into(), false)?;
+    }
+    warnings.push(
+        "Copilot Chat asks for each model's API key on first use: enter the forward token"
+            .to_string(),
+    );
+    Ok(AppliedChanges {
+        target: TARGET.to_string(),
+        applied: !dry_run,
+        files: vec![file.finish(dry_run)?],
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoconfig::{edit::FileAction, jsonc};
+
+    const FIXTURE: &str = include_str!("../../tests/fixtures/autoconfig/vscode-settings.json");
+
+    #[test]
+    fn test_fixture_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ccr-vscode-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // Discovery takes the first editor that has a settings file
+        assert_eq!(
+            super::super::find_settings(&dir, &["Code", "VSCodium"]),
+            Some(dir.join("Code").join("User").join("settings.json"))
+        );
+        let path = dir.join("VSCodium").join("User").join("settings.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, FIXTURE).unwrap();
+        assert_eq!(
+            super::super::find_settings(&dir, &["Code", "VSCodium"]),
+            Some(path.clone())
+        );
+
+        let base = "http://127.0.0.1:9000/v1";
+        let models = vec![
+            ("gpt-4o".to_string(), "GPT-4o".to_string()),
+            ("deepseek-chat".to_string(), "DeepSeek".to_string()),
+        ];
+        let preview = apply_at(&path, base, &models, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FIXTURE);
+        let keys: Vec<&str> = preview.files[0]
+            .changes
+            .iter()
+            .map(|c| c.key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "github.copilot.chat.customOAIModels.gpt-4o.url",
+                "github.copilot.chat.customOAIModels.deepseek-chat",
+            ]
+        );
+        assert!(preview.warnings[0].contains("https://other-relay.example/v1"));
+
+        let applied = apply_at(&path, base, &models, false).unwrap();
+        assert_eq!(applied.files[0].action, FileAction::Updated);
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("  // Bring-your-own-key models for Copilot Chat\n"));
+        assert_eq!(
+            jsonc::get(&written, &[MODELS_KEY, "local-llama", "url"]),
+            Some("http://localhost:11434/v1".into())
+        );
+        assert_eq!(
+            jsonc::get(&written, &[MODELS_KEY, "gpt-4o", "maxInputTokens"]),
+            Some(128000.into())
+        );
+        assert_eq!(
+            jsonc::get(&written, &[MODELS_KEY, "deepseek-chat", "url"]),
+            Some(base.into())
+        );
+        assert_eq!(
+            jsonc::get(&written, &["[markdown]", "editor.wordWrap"]),
+            Some("on".into())
+        );
+
+        edit::revert(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FIXTURE);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,294 @@
+//! Line-based edits of YAML files shaped like Continue's `config.yaml`:
+//! top-level scalars and block lists of flat maps. There is no YAML parser
+//! in the tree, and a full one would rewrite the file anyway. An entry of a
+//! list is found by one of its fields, and only the lines of the keys being
+//! set are touched, so comments and everything else stay as they were.
+//! Values are written double-quoted, which YAML reads like JSON strings.
+
+use serde_json::{Map, Value};
+
+struct Entry {
+    /// Line of the `- `
+    first: usize,
+    /// Line after its last field
+    end: usize,
+    /// `(key, value, line)` of the fields at the entry's own level
+    fields: Vec<(String, String, usize)>,
+}
+
+struct List {
+    /// Line of `key:`
+    line: usize,
+    /// Written inline as `key: []`
+    inline_empty: bool,
+    item_indent: usize,
+    entries: Vec<Entry>,
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// The value of `key: value`, unquoted and without a trailing comment.
+fn scalar_value(rest: &str) -> String {
+    let rest = rest.trim();
+    if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted
+            .char_indices()
+            .scan(false, |escaped, (i, c)| {
+                let close = c == '"' && !*escaped;
+                *escaped = c == '\\' && !*escaped;
+                Some((i, close))
+            })
+            .find(|(_, close)| *close)
+            .map_or(rest.len(), |(i, _)| i + 2);
+        return serde_json::from_str(&rest[..end]).unwrap_or_default();
+    }
+    if let Some(quoted) = rest.strip_prefix('\'') {
+        let end = quoted.find('\'').unwrap_or(quoted.len());
+        return quoted[..end].to_string();
+    }
+    let value = match rest.find(" #") {
+        Some(i) => &rest[..i],
+        None => rest,
+    };
+    value.trim().to_string()
+}
+
+/// `(key, rest)` of a `key: rest` line, already without indentation.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let (key, rest) = line.split_once(':')?;
+    let key = key.trim();
+    let simple = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    (simple && (rest.is_empty() || rest.starts_with(' '))).then_some((key, rest))
+}
+
+fn find_list(lines: &[&str], key: &str) -> Result<Option<List>, String> {
+    let Some(line) = lines
+        .iter()
+        .position(|l| key_value(l).is_some_and(|(k, _)| k == key) && indent(l) == 0)
+    else {
+        return Ok(None);
+    };
+    let rest = key_value(lines[line]).map(|(_, rest)| rest).unwrap_or("");
+    let inline = scalar_value(rest);
+    let inline_empty = inline == "[]";
+    if !inline.is_empty() && !inline_empty {
+        return Err(format!("'{}' is not a block list", key));
+    }
+    // The block runs until the next top-level key
+    let mut end = line + 1;
+    while end < lines.len()
+        && (is_blank_or_comment(lines[end])
+            || lines[end].starts_with(' ')
+            || lines[end].starts_with('-'))
+    {
+        end += 1;
+    }
+    let items: Vec<usize> = (line + 1..end)
+        .filter(|&i| {
+            let trimmed = lines[i].trim_start();
+            trimmed == "-" || trimmed.starts_with("- ")
+        })
+        .collect();
+    let item_indent = items.first().map_or(2, |&i| indent(lines[i]));
+    let items: Vec<usize> = items
+        .into_iter()
+        .filter(|&i| indent(lines[i]) == item_indent)
+        .collect();
+    let mut entries = Vec::new();
+    for (n, &first) in items.iter().enumerate() {
+        let mut last = items.get(n + 1).copied().unwrap_or(end);
+        while last > first + 1 && is_blank_or_comment(lines[last - 1]) {
+            last -= 1;
+        }
+        let field_indent = item_indent + 2;
+        let mut fields = Vec::new();
+        let dash = &lines[first][item_indent + 1..];
+        if let Some((k, rest)) = key_value(dash.trim_start()) {
+            fields.push((k.to_string(), scalar_value(rest), first));
+        }
+        for (i, l) in lines.iter().enumerate().take(last).skip(first + 1) {
+            if indent(l) != field_indent || is_blank_or_comment(l) {
+                continue;
+            }
+            if let Some((k, rest)) = key_value(l.trim_start()) {
+                fields.push((k.to_string(), scalar_value(rest), i));
+            }
+        }
+        entries.push(Entry {
+            first,
+            end: last,
+            fields,
+        });
+    }
+    Ok(Some(List {
+        line,
+        inline_empty,
+        item_indent,
+        entries,
+    }))
+}
+
+fn quote(value: &str) -> String {
+    Value::from(value).to_string()
+}
+
+/// The top-level scalar `key`.
+pub(super) fn scalar(text: &str, key: &str) -> Option<String> {
+    text.lines()
+        .filter(|l| indent(l) == 0)
+        .find_map(|l| key_value(l).filter(|(k, _)| *k == key))
+        .map(|(_, rest)| scalar_value(rest))
+        .filter(|v| !v.is_empty())
+}
+
+/// `text` with the top-level scalar `key` set, appended if missing.
+pub(super) fn set_scalar(text: &str, key: &str, value: &str) -> String {
+    let line = format!("{}: {}", key, quote(value));
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    match lines
+        .iter()
+        .position(|l| indent(l) == 0 && key_value(l).is_some_and(|(k, _)| k == key))
+    {
+        Some(i) => lines[i] = &line,
+        None => return append(text, &line),
+    }
+    lines.join("\n")
+}
+
+fn append(text: &str, addition: &str) -> String {
+    if text.is_empty() {
+        format!("{}\n", addition)
+    } else if text.ends_with('\n') {
+        format!("{}{}\n", text, addition)
+    } else {
+        format!("{}\n{}\n", text, addition)
+    }
+}
+
+/// Fields of every entry of list `list`.
+pub(super) fn entries(text: &str, list: &str) -> Result<Vec<Map<String, Value>>, String> {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let Some(list) = find_list(&lines, list)? else {
+        return Ok(Vec::new());
+    };
+    Ok(list
+        .entries
+        .into_iter()
+        .map(|e| {
+            e.fields
+                .into_iter()
+                .map(|(k, v, _)| (k, Value::String(v)))
+                .collect()
+        })
+        .collect())
+}
+
+/// `text` with `fields` set on the entry of list `key` whose `id_field` is
+/// `id`. A missing entry is added at the end of the list, with `id_field`
+/// first, and a missing list at the end of the file.
+pub(super) fn upsert(
+    text: &str,
+    key: &str,
+    id_field: &str,
+    id: &str,
+    fields: &[(&str, String)],
+) -> Result<String, String> {
+    let mut lines: Vec<String> = text.split('\n').map(str::to_string).collect();
+    let borrowed: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let list = find_list(&borrowed, key)?;
+    let new_entry = |item_indent: usize| {
+        let pad = " ".repeat(item_indent);
+        let mut entry = vec![format!("{}- {}: {}", pad, id_field, quote(id))];
+        entry.extend(
+            fields
+                .iter()
+                .filter(|(k, _)| *k != id_field)
+                .map(|(k, v)| format!("{}  {}: {}", pad, k, quote(v))),
+        );
+        entry
+    };
+    let Some(list) = list else {
+        let block = format!("{}:\n{}", key, new_entry(2).join("\n"));
+        return Ok(append(text, &block));
+    };
+    let existing = list
+        .entries
+        .iter()
+        .find(|e| e.fields.iter().any(|(k, v, _)| k == id_field && v == id));
+    match existing {
+        Some(entry) => {
+            let pad = " ".repeat(list.item_indent);
+            let mut added = Vec::new();
+            for (k, v) in fields {
+                match entry.fields.iter().find(|(name, _, _)| name == k) {
+                    Some((_, _, line)) if *line == entry.first => {
+                        lines[*line] = format!("{}- {}: {}", pad, k, quote(v));
+                    }
+                    Some((_, _, line)) => lines[*line] = format!("{}  {}: {}", pad, k, quote(v)),
+                    None => added.push(format!("{}  {}: {}", pad, k, quote(v))),
+                }
+            }
+            lines.splice(entry.end..entry.end, added);
+        }
+        None => {
+            let at = list.entries.last().map_or(list.line + 1, |e| e.end);
+            lines.splice(at..at, new_entry(list.item_indent));
+            if list.inline_empty {
+                lines[list.line] = format!("{}:", key);
+            }
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_edited_in_place() {
+        let text = "name: Local\n# My models\nmodels:\n  - name: gpt-4o # direct\n    provider: openai\n    apiBase: 'https://other.example/v1'\n    roles:\n      - chat\n\n  - name: llama\n    provider: ollama\ncontext: []\n";
+        assert_eq!(scalar(text, "name"), Some("Local".to_string()));
+        let entries = entries(text, "models").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["apiBase"], "https://other.example/v1");
+        assert!(entries[0].get("roles").is_some());
+
+        let fields = [
+            ("apiBase", "http://127.0.0.1:9000/v1".to_string()),
+            ("apiKey", "tok".to_string()),
+        ];
+        let edited = upsert(text, "models", "name", "gpt-4o", &fields).unwrap();
+        assert_eq!(
+            edited,
+            "name: Local\n# My models\nmodels:\n  - name: gpt-4o # direct\n    provider: openai\n    apiBase: \"http://127.0.0.1:9000/v1\"\n    roles:\n      - chat\n    apiKey: \"tok\"\n\n  - name: llama\n    provider: ollama\ncontext: []\n"
+        );
+        let added = upsert(text, "models", "name", "qwen", &fields).unwrap();
+        assert!(added.ends_with(
+            "    provider: ollama\n  - name: \"qwen\"\n    apiBase: \"http://127.0.0.1:9000/v1\"\n    apiKey: \"tok\"\ncontext: []\n"
+        ));
+        assert_eq!(
+            upsert("", "models", "name", "qwen", &fields[1..]).unwrap(),
+            "models:\n  - name: \"qwen\"\n    apiKey: \"tok\"\n"
+        );
+        assert_eq!(
+            upsert("models: []\n", "models", "name", "qwen", &fields[1..]).unwrap(),
+            "models:\n  - name: \"qwen\"\n    apiKey: \"tok\"\n"
+        );
+        assert!(upsert("models: [a]\n", "models", "name", "qwen", &fields).is_err());
+        assert_eq!(
+            set_scalar(text, "name", "Relay"),
+            text.replacen("name: Local", "name: \"Relay\"", 1)
+        );
+    }
+}
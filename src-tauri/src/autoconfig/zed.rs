@@ -0,0 +1,178 @@
+//! Zed: the `language_models` block of its `settings.json`.
+//!
+//! Points Zed's `openai` or `anthropic` provider, whichever matches the
+//! model's preferred route, at the relay and lists the model in its
+//! `available_models`. Zed appends `/v1/messages` to the Anthropic URL
+//! itself, so that one is the relay's `/anthropic` endpoint, while the
+//! OpenAI URL is `/v1`. Zed keeps API keys out of `settings.json` and asks
+//! for one in its agent settings.
+
+use std::path::{Path, PathBuf};
+
+use super::edit::{self, AppliedChanges, Edit};
+use super::verify::{self, Probe};
+use super::RelayModel;
+use crate::{config, logger};
+
+pub const TARGET: &str = "zed";
+
+/// List `model_id` in Zed, or with `dry_run` only report what that would
+/// change.
+pub fn apply(model_id: Option<&str>, dry_run: bool) -> Result<AppliedChanges, String> {
+    let model_id = model_id.ok_or("Choose the model to add to Zed")?;
+    let model = super::relay_model(&config::load(), model_id)?;
+    let path = settings_path()?;
+    let changes = apply_at(&path, &super::ccr_base_url(), &model, dry_run)?;
+    if changes.applied {
+        logger::info(
+            "autoconfig",
+            &format!("Added {} to Zed ({})", model.id, path.display()),
+        );
+    }
+    Ok(changes)
+}
+
+/// Restore the settings file from before [`apply`].
+pub fn revert() -> Result<AppliedChanges, String> {
+    edit::revert_target(TARGET, &settings_path()?)
+}
+
+/// The request Zed makes for the first model of a provider pointed at the
+/// relay, with the forward token it was told to enter.
+pub(super) fn probe() -> Result<Probe, String> {
+    let file = Edit::open(&settings_path()?)?;
+    for provider in ["anthropic", "openai"] {
+        let block = ["language_models", provider];
+        let Some(api_url) =
+            verify::relay_url(verify::text(file.get(&[block[0], block[1], "api_url"])))
+        else {
+            continue;
+        };
+        let models = file.entries(&[block[0], block[1], "available_models"]);
+        let Some(model) = models
+            .iter()
+            .find_map(|m| verify::text(m.get("name").cloned()))
+        else {
+            continue;
+        };
+        let token = super::get_forward_token();
+        return Ok(match provider {
+            "anthropic" => Probe::anthropic(
+                format!("{}/v1/messages", api_url.trim_end_matches('/')),
+                token,
+                model,
+                false,
+            ),
+            _ => Probe::openai(&api_url, token, model),
+        });
+    }
+    Err(verify::not_applied("Zed"))
+}
+
+fn settings_path() -> Result<PathBuf, String> {
+    let default = if cfg!(windows) {
+        dirs::config_dir().map(|d| d.join("Zed").join("settings.json"))
+    } else {
+        dirs::home_dir().map(|h| h.join(".config").join("zed").join("settings.json"))
+    };
+    super::settings_path(TARGET, default)
+}
+
+fn apply_at(
+    path: &Path,
+    base_url: &str,
+    model: &RelayModel,
+    dry_run: bool,
+) -> Result<AppliedChanges, String> {
+    let mut file = Edit::open(path)?;
+    let (api_url, max_tokens) = match model.provider {
+        "anthropic" => (format!("{}/anthropic", base_url), 200_000),
+        _ => (format!("{}/v1", base_url), 128_000),
+    };
+    let block = ["language_models", model.provider];
+    let url_key = [block[0], block[1], "api_url"];
+    let mut warnings: Vec<String> = edit::repointed(&file, &url_key, &api_url)
+        .into_iter()
+        .collect();
+    file.set(&url_key, api_url.into(), false)?;
+    file.upsert(
+        &[block[0], block[1], "available_models"],
+        "name",
+        &model.id,
+        &[
+            ("display_name", model.name.as_str().into(), false),
+            ("max_tokens", max_tokens.into(), false),
+        ],
+    )?;
+    warnings.push(format!(
+        "Zed asks for the {} API key in its agent settings: enter the forward token",
+        if model.provider == "anthropic" {
+            "Anthropic"
+        } else {
+            "OpenAI"
+        }
+    ));
+    Ok(AppliedChanges {
+        target: TARGET.to_string(),
+        applied: !dry_run,
+        files: vec![file.finish(dry_run)?],
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autoconfig::jsonc;
+
+    const FIXTURE: &str = include_str!("../../tests/fixtures/autoconfig/zed-settings.json");
+
+    #[test]
+    fn test_sample_settings_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ccr-zed-{}", std::process::id()));
+        let path = dir.join("settings.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, FIXTURE).unwrap();
+        let base = "http://127.0.0.1:9000";
+
+        let claude = RelayModel {
+            id: "claude-sonnet".to_string(),
+            name: "Claude Sonnet".to_string(),
+            provider: "anthropic",
+        };
+        let gpt = RelayModel {
+            id: "gpt-4o".to_string(),
+            name: "GPT-4o".to_string(),
+            provider: "openai",
+        };
+        apply_at(&path, base, &claude, false).unwrap();
+        apply_at(&path, base, &gpt, false).unwrap();
+        let again = apply_at(&path, base, &gpt, false).unwrap();
+        assert!(again.files[0].changes.is_empty());
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("// Zed settings\n//\n"));
+        assert_eq!(
+            jsonc::get(&written, &["language_models", "anthropic", "api_url"]),
+            Some("http://127.0.0.1:9000/anthropic".into())
+        );
+        assert_eq!(
+            jsonc::get(&written, &["language_models", "openai", "api_url"]),
+            Some("http://127.0.0.1:9000/v1".into())
+        );
+        assert_eq!(
+            jsonc::get(&written, &["language_models", "openai", "available_models"]),
+            Some(serde_json::json!([
+                {"name": "gpt-4o", "display_name": "GPT-4o", "max_tokens": 128000}
+            ]))
+        );
+        assert_eq!(
+            jsonc::get(&written, &["language_models", "ollama", "api_url"]),
+            Some("http://localhost:11434".into())
+        );
+
+        edit::revert(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), FIXTURE);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
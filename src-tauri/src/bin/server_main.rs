@@ -1,4 +1,8 @@
 #[tokio::main]
 async fn main() {
-    tauri_app_lib::server::serve().await
+    tokio::select! {
+        _ = tauri_app_lib::server::serve() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tauri_app_lib::server::shutdown();
 }
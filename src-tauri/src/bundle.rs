@@ -0,0 +1,820 @@
+//! Portable configuration bundles.
+//!
+//! A bundle carries the parts of the configuration worth syncing between
+//! machines: upstreams, models (with their routes and prices) and the routing
+//! settings. Machine-local settings such as the forward token, proxy, theme and
+//! backup paths are left out. Upstream keys are either omitted or encrypted
+//! with a passphrase (PBKDF2-HMAC-SHA256 + AES-256-GCM).
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use ring::{aead, pbkdf2, rand::SecureRandom};
+
+use crate::config::{self, ModelCfg, Settings, Upstream, UpstreamKey};
+
+const FORMAT: &str = "ccr-config-bundle";
+const VERSION: u32 = 1;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Routing and retry settings carried by a bundle.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RoutingSettings {
+    pub retry_max_attempts: Option<u32>,
+    pub retry_initial_ms: Option<u64>,
+    pub retry_max_ms: Option<u64>,
+    pub preferred_api_style: Option<String>,
+    pub enable_retry_fallback: Option<bool>,
+    pub enable_dynamic_model: Option<bool>,
+    pub limits: config::RateLimitConfig,
+    pub price_sync: config::PriceSyncConfig,
+}
+
+impl RoutingSettings {
+    fn from_settings(cfg: &Settings) -> Self {
+        Self {
+            retry_max_attempts: cfg.retry_max_attempts,
+            retry_initial_ms: cfg.retry_initial_ms,
+            retry_max_ms: cfg.retry_max_ms,
+            preferred_api_style: cfg.preferred_api_style.clone(),
+            enable_retry_fallback: cfg.enable_retry_fallback,
+            enable_dynamic_model: cfg.enable_dynamic_model,
+            limits: cfg.limits.clone(),
+            price_sync: cfg.price_sync.clone(),
+        }
+    }
+
+    fn apply(self, cfg: &mut Settings) {
+        cfg.retry_max_attempts = self.retry_max_attempts;
+        cfg.retry_initial_ms = self.retry_initial_ms;
+        cfg.retry_max_ms = self.retry_max_ms;
+        cfg.preferred_api_style = self.preferred_api_style;
+        cfg.enable_retry_fallback = self.enable_retry_fallback;
+        cfg.enable_dynamic_model = self.enable_dynamic_model;
+        cfg.limits = self.limits;
+        cfg.price_sync = self.price_sync;
+    }
+}
+
+/// Upstream keys encrypted with a passphrase.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct EncryptedSecrets {
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Keys of one upstream, as stored inside `EncryptedSecrets`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+struct UpstreamSecrets {
+    api_key: Option<String>,
+    api_keys: Vec<UpstreamKey>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: i64,
+    /// Upstreams with their keys stripped
+    pub upstreams: Vec<Upstream>,
+    pub models: Vec<ModelCfg>,
+    #[serde(default)]
+    pub routing: RoutingSettings,
+    /// Present when the bundle was exported with a passphrase
+    #[serde(default)]
+    pub secrets: Option<EncryptedSecrets>,
+}
+
+/// How an import combines the bundle with the current configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Replace upstreams, models and routing settings with the bundle's
+    Replace,
+    /// Add the bundle's entries; ids present on both sides follow `Prefer`
+    Merge,
+}
+
+impl ImportMode {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "replace" => Some(ImportMode::Replace),
+            "merge" | "" => Some(ImportMode::Merge),
+            _ => None,
+        }
+    }
+}
+
+/// Which side wins when an id exists both locally and in the bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefer {
+    Existing,
+    Imported,
+}
+
+impl Prefer {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "existing" | "" => Some(Prefer::Existing),
+            "imported" => Some(Prefer::Imported),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    pub upstreams_added: usize,
+    pub upstreams_replaced: usize,
+    pub upstreams_skipped: usize,
+    pub models_added: usize,
+    pub models_replaced: usize,
+    pub models_skipped: usize,
+    /// Whether upstream keys were restored from the bundle
+    pub secrets_imported: bool,
+    /// Keys from a keys file added to existing upstreams
+    pub keys_added: usize,
+    /// Upstream ids in a keys file that match no configured upstream
+    pub unknown_upstreams: Vec<String>,
+    pub warnings: Vec<config::ValidationIssue>,
+}
+
+/// What a dropped or picked file turned out to be.
+#[derive(Clone)]
+pub enum ImportSource {
+    Bundle(Box<Bundle>),
+    /// Keys by upstream id, from a keys file
+    Keys(Vec<(String, Vec<String>)>),
+}
+
+const SUPPORTED_FORMATS: &str = "Supported formats: a configuration bundle exported \
+     from this app (.json), a full backup from /api/export/backup (.json with \
+     \"settings\"), a settings file, or a keys file: a JSON object of upstream id \
+     to key (or list of keys), or .env lines such as OPENAI_API_KEY=sk-...";
+
+/// Work out what `text` is. Backups and settings files are turned into a
+/// bundle that keeps the upstream keys.
+pub fn parse(text: &str) -> Result<ImportSource, String> {
+    let unsupported = |what: &str| format!("{}. {}", what, SUPPORTED_FORMATS);
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value,
+        Err(_) => {
+            return parse_env(text)
+                .map(ImportSource::Keys)
+                .ok_or_else(|| unsupported("Not JSON and no KEY=value lines found"))
+        }
+    };
+    let Some(object) = value.as_object() else {
+        return Err(unsupported("Expected a JSON object"));
+    };
+
+    if let Some(format) = object.get("format") {
+        if format.as_str() != Some(FORMAT) {
+            return Err(unsupported(&format!("Unknown file format {}", format)));
+        }
+        return serde_json::from_value(value)
+            .map(|bundle| ImportSource::Bundle(Box::new(bundle)))
+            .map_err(|e| format!("Invalid bundle file: {}", e));
+    }
+    // A full backup, or the settings file itself
+    let settings = match object.get("settings") {
+        Some(settings) => Some(settings.clone()),
+        None if object.contains_key("upstreams") => Some(value.clone()),
+        None => None,
+    };
+    if let Some(settings) = settings {
+        let cfg: Settings =
+            serde_json::from_value(settings).map_err(|e| format!("Invalid backup file: {}", e))?;
+        let mut bundle = export(&cfg, None)?;
+        bundle.upstreams = cfg.upstreams;
+        return Ok(ImportSource::Bundle(Box::new(bundle)));
+    }
+
+    let mut keys = Vec::new();
+    for (id, value) in object {
+        let list = match value {
+            serde_json::Value::String(key) => vec![key.clone()],
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| unsupported(&format!("Keys for '{}' must be strings", id)))?,
+            _ => return Err(unsupported(&format!("Unexpected field '{}'", id))),
+        };
+        keys.push((id.clone(), list));
+    }
+    if keys.is_empty() {
+        return Err(unsupported("The file is empty"));
+    }
+    Ok(ImportSource::Keys(keys))
+}
+
+/// `OPENAI_API_KEY=sk-...` lines, keyed by upstream id ("openai"). Comments,
+/// `export ` prefixes and quotes are allowed as in a shell.
+fn parse_env(text: &str) -> Option<Vec<(String, Vec<String>)>> {
+    let mut keys: Vec<(String, Vec<String>)> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line.split_once('=')?;
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        let Some(id) = name.trim().strip_suffix("_API_KEY") else {
+            continue;
+        };
+        if id.is_empty() || value.is_empty() {
+            continue;
+        }
+        let id = id.to_lowercase().replace('_', "-");
+        match keys.iter_mut().find(|(existing, _)| *existing == id) {
+            Some((_, list)) => list.push(value.to_string()),
+            None => keys.push((id, vec![value.to_string()])),
+        }
+    }
+    (!keys.is_empty()).then_some(keys)
+}
+
+/// Add keys to the upstreams with those ids: the first fills an empty
+/// `api_key`, the rest go to `api_keys`. Keys already present are skipped.
+fn apply_keys(cfg: &mut Settings, keys: Vec<(String, Vec<String>)>, report: &mut ImportReport) {
+    for (id, list) in keys {
+        let Some(up) = cfg
+            .upstreams
+            .iter_mut()
+            .find(|up| up.id.eq_ignore_ascii_case(&id))
+        else {
+            report.unknown_upstreams.push(id);
+            continue;
+        };
+        for key in list.into_iter().map(|k| k.trim().to_string()) {
+            let known = up.api_key.as_deref() == Some(key.as_str())
+                || up.api_keys.iter().any(|k| k.key == key);
+            if key.is_empty() || known {
+                continue;
+            }
+            if up.api_key.is_none() {
+                up.api_key = Some(key);
+            } else {
+                up.api_keys.push(UpstreamKey { label: None, key });
+            }
+            report.keys_added += 1;
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32], String> {
+    let iterations =
+        NonZeroU32::new(iterations).ok_or_else(|| "Invalid iteration count".to_string())?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Ok(key)
+}
+
+fn aead_key(key: &[u8; 32]) -> Result<aead::LessSafeKey, String> {
+    aead::UnboundKey::new(&aead::AES_256_GCM, key)
+        .map(aead::LessSafeKey::new)
+        .map_err(|_| "Failed to initialise cipher".to_string())
+}
+
+fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<EncryptedSecrets, String> {
+    let rng = ring::rand::SystemRandom::new();
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| "Failed to generate random bytes".to_string())?;
+
+    let key = aead_key(&derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?)?;
+    let mut data = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut data,
+    )
+    .map_err(|_| "Encryption failed".to_string())?;
+
+    Ok(EncryptedSecrets {
+        iterations: PBKDF2_ITERATIONS,
+        salt: B64.encode(salt),
+        nonce: B64.encode(nonce),
+        ciphertext: B64.encode(data),
+    })
+}
+
+fn decrypt(passphrase: &str, secrets: &EncryptedSecrets) -> Result<Vec<u8>, String> {
+    let decode = |s: &str| B64.decode(s).map_err(|e| format!("Corrupt bundle: {}", e));
+    let salt = decode(&secrets.salt)?;
+    let nonce: [u8; aead::NONCE_LEN] = decode(&secrets.nonce)?
+        .try_into()
+        .map_err(|_| "Corrupt bundle: bad nonce".to_string())?;
+    let mut data = decode(&secrets.ciphertext)?;
+
+    let key = aead_key(&derive_key(passphrase, &salt, secrets.iterations)?)?;
+    let plaintext = key
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut data,
+        )
+        .map_err(|_| "Wrong passphrase or corrupt bundle".to_string())?;
+    Ok(plaintext.to_vec())
+}
+
+/// Build a bundle from `cfg`. Keys are included only when a passphrase is
+/// given, and then only in encrypted form.
+pub fn export(cfg: &Settings, passphrase: Option<&str>) -> Result<Bundle, String> {
+    let mut secrets: HashMap<String, UpstreamSecrets> = HashMap::new();
+    let upstreams = cfg
+        .upstreams
+        .iter()
+        .map(|up| {
+            if up.api_key.is_some() || !up.api_keys.is_empty() {
+                secrets.insert(
+                    up.id.clone(),
+                    UpstreamSecrets {
+                        api_key: up.api_key.clone(),
+                        api_keys: up.api_keys.clone(),
+                    },
+                );
+            }
+            Upstream {
+                api_key: None,
+                api_keys: Vec::new(),
+                ..up.clone()
+            }
+        })
+        .collect();
+
+    let secrets = match passphrase.filter(|p| !p.is_empty()) {
+        Some(passphrase) => {
+            let plaintext = serde_json::to_vec(&secrets).map_err(|e| e.to_string())?;
+            Some(encrypt(passphrase, &plaintext)?)
+        }
+        None => None,
+    };
+
+    Ok(Bundle {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        exported_at: chrono::Utc::now().timestamp(),
+        upstreams,
+        models: cfg
+            .models
+            .iter()
+            .filter(|m| !m.is_temporary)
+            .cloned()
+            .collect(),
+        routing: RoutingSettings::from_settings(cfg),
+        secrets,
+    })
+}
+
+/// Upsert `incoming` into `current` by case-insensitive id, returning
+/// (added, replaced, skipped).
+fn merge_by_id<T: Clone>(
+    current: &mut Vec<T>,
+    incoming: Vec<T>,
+    id: impl Fn(&T) -> &str,
+    prefer: Prefer,
+    mut carry_over: impl FnMut(&T, &mut T),
+) -> (usize, usize, usize) {
+    let (mut added, mut replaced, mut skipped) = (0, 0, 0);
+    for mut item in incoming {
+        match current
+            .iter_mut()
+            .find(|c| id(c).eq_ignore_ascii_case(id(&item)))
+        {
+            Some(_) if prefer == Prefer::Existing => skipped += 1,
+            Some(existing) => {
+                carry_over(existing, &mut item);
+                *existing = item;
+                replaced += 1;
+            }
+            None => {
+                current.push(item);
+                added += 1;
+            }
+        }
+    }
+    (added, replaced, skipped)
+}
+
+/// Combine `bundle` with `cfg` without saving. Imported upstreams that come
+/// without keys keep the keys of the local upstream with the same id.
+pub fn apply(
+    cfg: &mut Settings,
+    bundle: Bundle,
+    mode: ImportMode,
+    prefer: Prefer,
+    passphrase: Option<&str>,
+) -> Result<ImportReport, String> {
+    if bundle.format != FORMAT {
+        return Err("Not a configuration bundle".to_string());
+    }
+    if bundle.version > VERSION {
+        return Err(format!(
+            "Bundle version {} is newer than supported ({})",
+            bundle.version, VERSION
+        ));
+    }
+
+    let mut report = ImportReport::default();
+    let secrets: HashMap<String, UpstreamSecrets> = match (&bundle.secrets, passphrase) {
+        (Some(encrypted), Some(passphrase)) if !passphrase.is_empty() => {
+            report.secrets_imported = true;
+            serde_json::from_slice(&decrypt(passphrase, encrypted)?)
+                .map_err(|e| format!("Corrupt bundle: {}", e))?
+        }
+        (Some(_), _) => return Err("This bundle is encrypted; a passphrase is required".into()),
+        (None, _) => HashMap::new(),
+    };
+
+    let mut upstreams = bundle.upstreams;
+    for up in &mut upstreams {
+        if let Some(s) = secrets.get(&up.id) {
+            up.api_key = s.api_key.clone();
+            up.api_keys = s.api_keys.clone();
+        }
+    }
+    let keep_keys = |existing: &Upstream, incoming: &mut Upstream| {
+        if incoming.api_key.is_none() && incoming.api_keys.is_empty() {
+            incoming.api_key = existing.api_key.clone();
+            incoming.api_keys = existing.api_keys.clone();
+        }
+    };
+
+    match mode {
+        ImportMode::Replace => {
+            let previous = std::mem::take(&mut cfg.upstreams);
+            for mut up in upstreams {
+                if let Some(existing) = previous.iter().find(|p| p.id.eq_ignore_ascii_case(&up.id))
+                {
+                    keep_keys(existing, &mut up);
+                }
+                cfg.upstreams.push(up);
+            }
+            report.upstreams_added = cfg.upstreams.len();
+            report.models_added = bundle.models.len();
+            // Temporary models are regenerated by the tools that own them
+            cfg.models.retain(|m| m.is_temporary);
+            cfg.models.extend(bundle.models);
+            bundle.routing.apply(cfg);
+        }
+        ImportMode::Merge => {
+            (
+                report.upstreams_added,
+                report.upstreams_replaced,
+                report.upstreams_skipped,
+            ) = merge_by_id(&mut cfg.upstreams, upstreams, |u| &u.id, prefer, keep_keys);
+            (
+                report.models_added,
+                report.models_replaced,
+                report.models_skipped,
+            ) = merge_by_id(&mut cfg.models, bundle.models, |m| &m.id, prefer, |_, _| {});
+            if prefer == Prefer::Imported {
+                bundle.routing.apply(cfg);
+            }
+        }
+    }
+
+    report.warnings = config::check(cfg)?;
+    Ok(report)
+}
+
+/// [`apply`] for any [`ImportSource`]; `mode` and `prefer` only matter for
+/// bundles.
+pub fn apply_source(
+    cfg: &mut Settings,
+    source: ImportSource,
+    mode: ImportMode,
+    prefer: Prefer,
+    passphrase: Option<&str>,
+) -> Result<ImportReport, String> {
+    match source {
+        ImportSource::Bundle(bundle) => apply(cfg, *bundle, mode, prefer, passphrase),
+        ImportSource::Keys(keys) => {
+            let mut report = ImportReport::default();
+            apply_keys(cfg, keys, &mut report);
+            report.warnings = config::check(cfg)?;
+            Ok(report)
+        }
+    }
+}
+
+/// Apply `source` to the current configuration and save it.
+pub fn import(
+    source: ImportSource,
+    mode: ImportMode,
+    prefer: Prefer,
+    passphrase: Option<&str>,
+) -> Result<ImportReport, String> {
+    let mut cfg = config::load_raw();
+    let report = apply_source(&mut cfg, source, mode, prefer, passphrase)?;
+    config::save(&cfg)?;
+    crate::logger::info(
+        "config",
+        &format!(
+            "Imported configuration: {} upstreams and {} models added, {} and {} replaced, {} keys added",
+            report.upstreams_added,
+            report.models_added,
+            report.upstreams_replaced,
+            report.models_replaced,
+            report.keys_added
+        ),
+    );
+    Ok(report)
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One entry of an import preview. Only field names are listed, never
+/// their values, so keys don't end up in the UI.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Change {
+    /// "upstream", "model" or "routing"
+    pub section: &'static str,
+    pub id: String,
+    pub kind: ChangeKind,
+    pub fields: Vec<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ImportPreview {
+    pub report: ImportReport,
+    pub changes: Vec<Change>,
+}
+
+/// What importing `source` into `cfg` would change, without touching `cfg`.
+pub fn preview(
+    cfg: &Settings,
+    source: ImportSource,
+    mode: ImportMode,
+    prefer: Prefer,
+    passphrase: Option<&str>,
+) -> Result<ImportPreview, String> {
+    let mut after = cfg.clone();
+    let report = apply_source(&mut after, source, mode, prefer, passphrase)?;
+    let mut changes = diff_by_id("upstream", &cfg.upstreams, &after.upstreams, |u| &u.id);
+    changes.extend(diff_by_id("model", &cfg.models, &after.models, |m| &m.id));
+    let fields = changed_fields(
+        &RoutingSettings::from_settings(cfg),
+        &RoutingSettings::from_settings(&after),
+    );
+    if !fields.is_empty() {
+        changes.push(Change {
+            section: "routing",
+            id: String::new(),
+            kind: ChangeKind::Changed,
+            fields,
+        });
+    }
+    Ok(ImportPreview { report, changes })
+}
+
+fn diff_by_id<T: serde::Serialize>(
+    section: &'static str,
+    before: &[T],
+    after: &[T],
+    id: impl Fn(&T) -> &str,
+) -> Vec<Change> {
+    let find = |items: &'_ [T], wanted: &str| {
+        items
+            .iter()
+            .position(|item| id(item).eq_ignore_ascii_case(wanted))
+    };
+    let mut changes = Vec::new();
+    for item in after {
+        let (kind, fields) = match find(before, id(item)) {
+            Some(i) => {
+                let fields = changed_fields(&before[i], item);
+                if fields.is_empty() {
+                    continue;
+                }
+                (ChangeKind::Changed, fields)
+            }
+            None => (ChangeKind::Added, Vec::new()),
+        };
+        changes.push(Change {
+            section,
+            id: id(item).to_string(),
+            kind,
+            fields,
+        });
+    }
+    for item in before.iter().filter(|item| find(after, id(item)).is_none()) {
+        changes.push(Change {
+            section,
+            id: id(item).to_string(),
+            kind: ChangeKind::Removed,
+            fields: Vec::new(),
+        });
+    }
+    changes
+}
+
+/// Top-level fields that differ between the JSON forms of `a` and `b`.
+fn changed_fields<T: serde::Serialize>(a: &T, b: &T) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+        (serde_json::to_value(a), serde_json::to_value(b))
+    else {
+        return Vec::new();
+    };
+    let mut fields: Vec<String> = a
+        .keys()
+        .chain(b.keys())
+        .filter(|field| a.get(*field) != b.get(*field))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        let mut cfg = Settings::default();
+        cfg.upstreams.push(Upstream {
+            id: "main".to_string(),
+            endpoints: vec!["https://api.example.com".to_string()],
+            api_key: Some("sk-local".to_string()),
+            ..Default::default()
+        });
+        cfg.models.push(ModelCfg {
+            id: "gpt".to_string(),
+            provider: "openai".to_string(),
+            upstream_id: "main".to_string(),
+            price_prompt_per_1k: 1.0,
+            ..Default::default()
+        });
+        cfg
+    }
+
+    #[test]
+    fn test_export_strips_or_encrypts_keys() {
+        let cfg = settings();
+        let plain = export(&cfg, None).unwrap();
+        assert!(plain.secrets.is_none());
+        assert!(plain.upstreams[0].api_key.is_none());
+
+        let sealed = export(&cfg, Some("hunter2")).unwrap();
+        let json = serde_json::to_string(&sealed).unwrap();
+        assert!(!json.contains("sk-local"));
+
+        let mut target = Settings::default();
+        assert!(apply(
+            &mut target,
+            sealed.clone(),
+            ImportMode::Replace,
+            Prefer::Existing,
+            Some("wrong")
+        )
+        .is_err());
+        let report = apply(
+            &mut target,
+            sealed,
+            ImportMode::Replace,
+            Prefer::Existing,
+            Some("hunter2"),
+        )
+        .unwrap();
+        assert!(report.secrets_imported);
+        assert_eq!(target.upstreams[0].api_key.as_deref(), Some("sk-local"));
+    }
+
+    #[test]
+    fn test_merge_collisions() {
+        let mut remote = settings();
+        remote.models[0].price_prompt_per_1k = 2.0;
+        remote.models.push(ModelCfg {
+            id: "claude".to_string(),
+            provider: "anthropic".to_string(),
+            upstream_id: "main".to_string(),
+            ..Default::default()
+        });
+        let bundle = export(&remote, None).unwrap();
+
+        let mut local = settings();
+        let report = apply(
+            &mut local,
+            bundle.clone(),
+            ImportMode::Merge,
+            Prefer::Existing,
+            None,
+        )
+        .unwrap();
+        assert_eq!((report.models_added, report.models_skipped), (1, 1));
+        assert_eq!(local.models[0].price_prompt_per_1k, 1.0);
+
+        let mut local = settings();
+        let report = apply(
+            &mut local,
+            bundle,
+            ImportMode::Merge,
+            Prefer::Imported,
+            None,
+        )
+        .unwrap();
+        assert_eq!(report.models_replaced, 1);
+        assert_eq!(local.models[0].price_prompt_per_1k, 2.0);
+        // Keys are not in the bundle, so the local ones are kept
+        assert_eq!(local.upstreams[0].api_key.as_deref(), Some("sk-local"));
+    }
+
+    #[test]
+    fn test_parse_file_shapes() {
+        let bundle = serde_json::to_string(&export(&settings(), None).unwrap()).unwrap();
+        assert!(matches!(parse(&bundle), Ok(ImportSource::Bundle(_))));
+
+        let backup = serde_json::json!({ "settings": settings(), "projects": [] }).to_string();
+        let Ok(ImportSource::Bundle(restored)) = parse(&backup) else {
+            panic!("backup not recognised");
+        };
+        assert_eq!(restored.upstreams[0].api_key.as_deref(), Some("sk-local"));
+
+        let Ok(ImportSource::Keys(keys)) = parse(r#"{"main": ["sk-a", "sk-b"], "other": "sk-c"}"#)
+        else {
+            panic!("keys file not recognised");
+        };
+        assert_eq!(keys.len(), 2);
+
+        let env = "# keys\nexport OPENAI_API_KEY=\"sk-1\"\nAZURE_OPENAI_API_KEY=sk-2\nDEBUG=1\n";
+        let Ok(ImportSource::Keys(keys)) = parse(env) else {
+            panic!(".env file not recognised");
+        };
+        assert_eq!(
+            keys,
+            vec![
+                ("openai".to_string(), vec!["sk-1".to_string()]),
+                ("azure-openai".to_string(), vec!["sk-2".to_string()]),
+            ]
+        );
+
+        for bad in [
+            "[1, 2]",
+            r#"{"format": "other"}"#,
+            r#"{"main": 1}"#,
+            "hello",
+        ] {
+            let err = parse(bad).err().unwrap();
+            assert!(err.contains("Supported formats"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_preview_keys_and_bundle() {
+        let cfg = settings();
+        let keys = ImportSource::Keys(vec![
+            (
+                "MAIN".to_string(),
+                vec!["sk-local".to_string(), "sk-new".to_string()],
+            ),
+            ("missing".to_string(), vec!["sk-x".to_string()]),
+        ]);
+        let preview =
+            super::preview(&cfg, keys, ImportMode::Merge, Prefer::Imported, None).unwrap();
+        assert_eq!(preview.report.keys_added, 1);
+        assert_eq!(preview.report.unknown_upstreams, vec!["missing"]);
+        assert_eq!(preview.changes.len(), 1);
+        assert_eq!(preview.changes[0].fields, vec!["api_keys"]);
+        // Nothing applied to the source configuration
+        assert!(cfg.upstreams[0].api_keys.is_empty());
+
+        let mut remote = settings();
+        remote.models[0].price_prompt_per_1k = 2.0;
+        remote.models[0].id = "renamed".to_string();
+        let bundle = ImportSource::Bundle(Box::new(export(&remote, None).unwrap()));
+        let preview =
+            super::preview(&cfg, bundle, ImportMode::Replace, Prefer::Imported, None).unwrap();
+        let kinds: Vec<_> = preview
+            .changes
+            .iter()
+            .map(|c| (c.section, c.id.as_str(), c.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("model", "renamed", ChangeKind::Added),
+                ("model", "gpt", ChangeKind::Removed),
+            ]
+        );
+    }
+}
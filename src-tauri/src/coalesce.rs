@@ -0,0 +1,124 @@
+//! Single-flight caching for the read-only endpoints clients poll.
+//!
+//! Editors tend to fire several `/v1/models` calls at once when they open,
+//! and the UI can ask for the same latency test or health report more than
+//! once. A [`Coalesced`] runs one computation per key: requests arriving
+//! while it runs wait for it, and requests within `server.probe_cache_secs`
+//! after it reuse its answer. Answers belong to the configuration version
+//! they were computed under, so a save or reload is visible at once.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::OnceCell;
+
+use crate::config;
+
+/// Keys kept at most; all are dropped when full
+const MAX_KEYS: usize = 64;
+
+/// A computation, finished or not, with the configuration it ran under.
+struct Flight<V> {
+    version: u64,
+    answer: Arc<OnceCell<(Instant, V)>>,
+}
+
+impl<V> Flight<V> {
+    fn is_fresh(&self, version: u64, ttl: Duration) -> bool {
+        self.version == version && self.answer.get().is_none_or(|(at, _)| at.elapsed() < ttl)
+    }
+}
+
+/// Answers by key, shared between identical concurrent requests.
+pub struct Coalesced<V> {
+    flights: Mutex<HashMap<String, Flight<V>>>,
+}
+
+impl<V: Clone> Coalesced<V> {
+    pub fn new() -> Self {
+        Coalesced {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The answer for `key`: the one being computed or computed lately,
+    /// else what `compute` yields.
+    pub async fn get<F, Fut>(&self, key: &str, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cfg = config::current();
+        let ttl = Duration::from_secs(cfg.server.probe_cache_secs);
+        let version = config::version();
+        let answer = {
+            let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+            match flights.get(key) {
+                Some(flight) if flight.is_fresh(version, ttl) => Arc::clone(&flight.answer),
+                _ => {
+                    if flights.len() >= MAX_KEYS {
+                        flights.clear();
+                    }
+                    let answer = Arc::new(OnceCell::new());
+                    flights.insert(
+                        key.to_string(),
+                        Flight {
+                            version,
+                            answer: Arc::clone(&answer),
+                        },
+                    );
+                    answer
+                }
+            }
+        };
+        let (_, value) = answer
+            .get_or_init(|| async { (Instant::now(), compute().await) })
+            .await;
+        value.clone()
+    }
+}
+
+impl<V: Clone> Default for Coalesced<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_computation() {
+        let coalesced = Coalesced::new();
+        let runs = AtomicUsize::new(0);
+        let compute = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "models".to_string()
+        };
+        let answers =
+            futures_util::future::join_all((0..5).map(|_| coalesced.get("models", compute))).await;
+        assert!(answers.iter().all(|a| a == "models"));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Another key is computed on its own
+        coalesced.get("latency:a", compute).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // A flight from an older configuration is not reused
+        let version = config::version();
+        coalesced
+            .flights
+            .lock()
+            .unwrap()
+            .get_mut("models")
+            .unwrap()
+            .version = version.wrapping_sub(1);
+        coalesced.get("models", compute).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,778 @@
+//! Tauri commands exposed to the frontend
+//!
+//! Each command wraps the same module functions used by the HTTP API so the
+//! desktop UI and the REST endpoints share one code path.
+
+use std::sync::OnceLock;
+
+use tauri::Emitter;
+use tauri_plugin_autostart::ManagerExt;
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_dialog::DialogExt;
+use tauri_plugin_notification::NotificationExt;
+
+use crate::{
+    autoconfig, bundle, config, db, diagnostics, discovery, forward, logger, maintenance,
+    notifications, price_sync, profile, projects, server, webhooks,
+};
+
+static APP: OnceLock<tauri::AppHandle> = OnceLock::new();
+
+/// Remember the app handle so backend code can emit events to the UI.
+pub fn set_app_handle(app: tauri::AppHandle) {
+    let _ = APP.set(app);
+}
+
+/// Emit `usage-updated` with the request that was just recorded, so the
+/// dashboard can update without polling. No-op in the headless server.
+pub fn notify_usage_updated(event: &webhooks::RequestEvent) {
+    if let Some(app) = APP.get() {
+        if let Err(e) = app.emit("usage-updated", event) {
+            logger::debug("commands", &format!("Failed to emit usage-updated: {}", e));
+        }
+    }
+}
+
+/// Relay new log entries to the webview as `log-entry` events, the embedded
+/// counterpart of `GET /api/logs/stream`. The UI filters them itself and
+/// backfills with `get_live_logs`.
+pub fn spawn_log_events() {
+    let (_, mut receiver) = logger::subscribe(&logger::LiveFilter::default(), 0);
+    tauri::async_runtime::spawn(async move {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            match receiver.recv().await {
+                Ok(entry) => {
+                    if let Some(app) = APP.get() {
+                        // Not logged: that would publish another entry
+                        let _ = app.emit("log-entry", &entry);
+                    }
+                }
+                // The UI has one subscription; skip what it missed
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Send request lifecycle transitions to the UI as `activity` events, each
+/// carrying a batch of at most one entry per request.
+pub fn spawn_activity_events() {
+    let mut receiver = forward::activity::subscribe();
+    tauri::async_runtime::spawn(async move {
+        while let Some(batch) = forward::activity::next_batch(&mut receiver).await {
+            emit("activity", &batch);
+        }
+    });
+}
+
+/// Forward server state changes to the UI as `server-state` events.
+pub fn spawn_server_events() {
+    tauri::async_runtime::spawn(server::watch_state(|state| emit("server-state", &state)));
+}
+
+/// Show desktop notifications, and send each to the UI as a `notification`
+/// event so it can link to the page with the details.
+pub fn spawn_notifications() {
+    tauri::async_runtime::spawn(notifications::run(|notification| {
+        if let Some(app) = APP.get() {
+            let shown = app
+                .notification()
+                .builder()
+                .title(&notification.title)
+                .body(&notification.body)
+                .show();
+            if let Err(e) = shown {
+                logger::warn(
+                    "notifications",
+                    &format!("Failed to show notification: {}", e),
+                );
+            }
+        }
+        emit("notification", &notification);
+    }));
+}
+
+/// Register or remove the login item to match `desktop.autostart`.
+pub fn sync_autostart() {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let wanted = config::load().desktop.autostart;
+    let autolaunch = app.autolaunch();
+    if autolaunch.is_enabled().ok() == Some(wanted) {
+        return;
+    }
+    let result = if wanted {
+        autolaunch.enable()
+    } else {
+        autolaunch.disable()
+    };
+    match result {
+        Ok(()) => logger::info(
+            "app",
+            if wanted {
+                "Starting at login"
+            } else {
+                "No longer starting at login"
+            },
+        ),
+        Err(e) => logger::warn("app", &format!("Failed to update autostart: {}", e)),
+    }
+}
+
+/// Send `event` to every window.
+fn emit<S: serde::Serialize + Clone>(event: &str, payload: S) {
+    if let Some(app) = APP.get() {
+        if let Err(e) = app.emit(event, payload) {
+            logger::debug("commands", &format!("Failed to emit {}: {}", event, e));
+        }
+    }
+}
+
+/// Usage totals. `period` ("daily", "weekly" or "monthly") sets the start of
+/// the window when the query has none.
+#[tauri::command]
+pub fn get_usage_summary(
+    query: Option<db::UsageSummaryQuery>,
+    period: Option<String>,
+) -> Result<db::UsageSummary, String> {
+    let mut query = query.unwrap_or_default();
+    if let Some(period) = period.as_deref() {
+        query.from = query.from.or(Some(db::range_start(period)));
+    }
+    db::usage_summary(&query)
+}
+
+#[tauri::command]
+pub fn get_top_models(period: Option<String>, n: Option<i64>) -> Vec<db::ModelStats> {
+    db::top_models(period.as_deref().unwrap_or("daily"), n.unwrap_or(5))
+}
+
+/// Most recent error-level log entries, newest first.
+#[tauri::command]
+pub fn get_recent_errors(n: Option<i64>) -> Vec<logger::LogEntry> {
+    logger::query_logs(&logger::LogQuery {
+        limit: Some(n.unwrap_or(20)),
+        level: Some(logger::LogLevel::Error),
+        ..Default::default()
+    })
+}
+
+#[tauri::command]
+pub fn get_active_streams() -> Vec<forward::inflight::ActiveStream> {
+    forward::inflight::active_streams()
+}
+
+#[tauri::command]
+pub fn get_queue_stats() -> Vec<forward::queue::QueueStats> {
+    forward::queue::stats()
+}
+
+/// Latest rate limits each key of an upstream reported.
+#[tauri::command]
+pub fn get_upstream_ratelimits(id: String) -> Vec<forward::ratelimits::KeyRateLimits> {
+    forward::ratelimits::of_upstream(&id)
+}
+
+/// Requests in flight in their latest state, to fill the activity feed
+/// before the first `activity` event.
+#[tauri::command]
+pub fn get_activity() -> Vec<forward::activity::Activity> {
+    forward::activity::in_flight()
+}
+
+/// Token and cost totals per upstream API key.
+#[tauri::command]
+pub fn get_key_usage(from: Option<i64>, to: Option<i64>) -> Result<db::UsageSummary, String> {
+    db::usage_summary(&db::UsageSummaryQuery {
+        from,
+        to,
+        group_by: Some("key".to_string()),
+        ..Default::default()
+    })
+}
+
+#[tauri::command]
+pub fn get_budget_status() -> Vec<forward::budget::BudgetStatus> {
+    forward::budget::status()
+}
+
+/// Requests, tokens, cost, latency and errors of each experiment variant.
+#[tauri::command]
+pub async fn get_experiment_report(
+    id: String,
+) -> Result<forward::experiments::ExperimentReport, String> {
+    tokio::task::spawn_blocking(move || forward::experiments::report(&id))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// End an experiment, leaving `winner` ("a" or "b") to serve its model.
+#[tauri::command]
+pub fn end_experiment(
+    id: String,
+    winner: String,
+) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::end_experiment(&id, &winner)?;
+    config_changed("experiments");
+    Ok(warnings)
+}
+
+/// Ask for a destination with the save dialog and stream the usage export
+/// into it. Returns the written path, or `None` if the dialog was cancelled.
+#[tauri::command]
+pub async fn export_usage_file(
+    app: tauri::AppHandle,
+    format: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    tag: Option<String>,
+) -> Result<Option<String>, String> {
+    let format = db::ExportFormat::from_str(&format)
+        .ok_or_else(|| format!("Unsupported format: {}", format))?;
+    let default_name = format!(
+        "ccr-usage-{}.{}",
+        chrono::Utc::now().format("%Y%m"),
+        format.extension()
+    );
+    let Some(target) = app
+        .dialog()
+        .file()
+        .add_filter(format.extension(), &[format.extension()])
+        .set_file_name(default_name)
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let path = target.into_path().map_err(|e| e.to_string())?;
+
+    let file = std::fs::File::create(&path).map_err(|e| e.to_string())?;
+    let mut out = std::io::BufWriter::new(file);
+    let rows = db::export_usage(from, to, tag.as_deref(), format, &mut out)?;
+    crate::logger::info(
+        "export",
+        &format!("Exported {} usage rows to {}", rows, path.display()),
+    );
+    Ok(Some(path.display().to_string()))
+}
+
+/// Prune and compact the database now; reports rows removed and bytes
+/// reclaimed.
+#[tauri::command]
+pub async fn run_maintenance() -> Result<db::MaintenanceReport, String> {
+    tokio::task::spawn_blocking(maintenance::run_now)
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Fetch the OpenRouter catalog and list the price changes a refresh would
+/// make, so the UI can ask for confirmation first.
+#[tauri::command]
+pub async fn preview_model_prices() -> Result<Vec<price_sync::PriceChange>, String> {
+    price_sync::preview().await
+}
+
+/// Apply catalog prices to the given models (all unprotected models when
+/// omitted). Manually priced models are never changed.
+#[tauri::command]
+pub async fn refresh_model_prices(
+    model_ids: Option<Vec<String>>,
+) -> Result<Vec<price_sync::PriceChange>, String> {
+    price_sync::apply(model_ids).await
+}
+
+/// Post a sample event to a webhook URL and return the HTTP status.
+#[tauri::command]
+pub async fn test_webhook(url: String, secret: Option<String>) -> Result<u16, String> {
+    webhooks::send_test(&url, secret.as_deref()).await
+}
+
+/// Re-read the settings file now instead of waiting for the watcher.
+#[tauri::command]
+pub fn reload_config() -> Result<config::ReloadReport, String> {
+    config::reload()
+}
+
+/// Save a configuration bundle to a file picked by the user. Upstream keys are
+/// included, encrypted, only when a passphrase is given.
+#[tauri::command]
+pub async fn export_config_file(
+    app: tauri::AppHandle,
+    passphrase: Option<String>,
+) -> Result<Option<String>, String> {
+    let bundle = bundle::export(&config::load_raw(), passphrase.as_deref())?;
+    let default_name = format!("ccr-config-{}.json", chrono::Local::now().format("%Y%m%d"));
+    let Some(target) = app
+        .dialog()
+        .file()
+        .add_filter("json", &["json"])
+        .set_file_name(default_name)
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let path = target.into_path().map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(&bundle).map_err(|e| e.to_string())?;
+    std::fs::write(&path, json).map_err(|e| e.to_string())?;
+    crate::logger::info(
+        "export",
+        &format!("Exported configuration bundle to {}", path.display()),
+    );
+    Ok(Some(path.display().to_string()))
+}
+
+/// Save a diagnostics zip (redacted config, health, recent errors and the
+/// last `log_mb` MiB of each log) to a file picked by the user.
+#[tauri::command]
+pub async fn export_diagnostics(
+    app: tauri::AppHandle,
+    log_mb: Option<u64>,
+) -> Result<Option<String>, String> {
+    let default_name = format!(
+        "ccr-diagnostics-{}.zip",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    let Some(target) = app
+        .dialog()
+        .file()
+        .add_filter("zip", &["zip"])
+        .set_file_name(default_name)
+        .blocking_save_file()
+    else {
+        return Ok(None);
+    };
+    let path = target.into_path().map_err(|e| e.to_string())?;
+    let log_mb = log_mb.unwrap_or(diagnostics::DEFAULT_LOG_MB);
+    let written = path.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::create(&written).map_err(|e| e.to_string())?;
+        diagnostics::write(std::io::BufWriter::new(file), log_mb)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    crate::logger::info(
+        "export",
+        &format!(
+            "Exported diagnostics ({}) to {}",
+            entries.join(", "),
+            path.display()
+        ),
+    );
+    Ok(Some(path.display().to_string()))
+}
+
+/// Import a configuration bundle from a file picked by the user.
+/// `mode` is "merge" or "replace"; `prefer` ("existing" or "imported")
+/// decides which side wins on id collisions when merging.
+#[tauri::command]
+pub async fn import_config_file(
+    app: tauri::AppHandle,
+    mode: Option<String>,
+    prefer: Option<String>,
+    passphrase: Option<String>,
+) -> Result<Option<bundle::ImportReport>, String> {
+    let mode = mode.unwrap_or_default();
+    let mode = bundle::ImportMode::from_str(&mode)
+        .ok_or_else(|| format!("Unknown import mode: {}", mode))?;
+    let prefer = prefer.unwrap_or_default();
+    let prefer = bundle::Prefer::from_str(&prefer)
+        .ok_or_else(|| format!("Unknown prefer value: {}", prefer))?;
+    let Some(source) = app
+        .dialog()
+        .file()
+        .add_filter("json", &["json"])
+        .blocking_pick_file()
+    else {
+        return Ok(None);
+    };
+    let path = source.into_path().map_err(|e| e.to_string())?;
+    let source = read_import_file(&path)?;
+    let report = bundle::import(source, mode, prefer, passphrase.as_deref())?;
+    config_changed("upstreams");
+    Ok(Some(report))
+}
+
+fn read_import_file(path: &std::path::Path) -> Result<bundle::ImportSource, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    bundle::parse(&text)
+}
+
+fn import_options(
+    mode: Option<String>,
+    prefer: Option<String>,
+) -> Result<(bundle::ImportMode, bundle::Prefer), String> {
+    let mode = mode.unwrap_or_default();
+    let mode = bundle::ImportMode::from_str(&mode)
+        .ok_or_else(|| format!("Unknown import mode: {}", mode))?;
+    // Dropping a file onto the window means "use what's in it"
+    let prefer = prefer.unwrap_or_else(|| "imported".to_string());
+    let prefer = bundle::Prefer::from_str(&prefer)
+        .ok_or_else(|| format!("Unknown prefer value: {}", prefer))?;
+    Ok((mode, prefer))
+}
+
+/// Preview a file dropped onto the window and tell the UI through an
+/// "import-preview" event, which then calls [`apply_import_file`] if the
+/// user approves.
+pub fn on_file_drop(paths: &[std::path::PathBuf]) {
+    for path in paths {
+        let payload = match preview_import_file(path.display().to_string(), None, None, None) {
+            Ok(preview) => serde_json::json!({ "path": path, "preview": preview }),
+            Err(err) => {
+                logger::warn(
+                    "config",
+                    &format!("Can't import {}: {}", path.display(), err),
+                );
+                serde_json::json!({ "path": path, "error": err })
+            }
+        };
+        emit("import-preview", payload);
+    }
+}
+
+/// What importing the file at `path` would change. Upstream keys are
+/// reported by field name only.
+#[tauri::command]
+pub fn preview_import_file(
+    path: String,
+    mode: Option<String>,
+    prefer: Option<String>,
+    passphrase: Option<String>,
+) -> Result<bundle::ImportPreview, String> {
+    let (mode, prefer) = import_options(mode, prefer)?;
+    let source = read_import_file(std::path::Path::new(&path))?;
+    bundle::preview(
+        &config::load_raw(),
+        source,
+        mode,
+        prefer,
+        passphrase.as_deref(),
+    )
+}
+
+/// Import the file at `path`, e.g. after the user approved its preview.
+#[tauri::command]
+pub fn apply_import_file(
+    path: String,
+    mode: Option<String>,
+    prefer: Option<String>,
+    passphrase: Option<String>,
+) -> Result<bundle::ImportReport, String> {
+    let (mode, prefer) = import_options(mode, prefer)?;
+    let source = read_import_file(std::path::Path::new(&path))?;
+    let report = bundle::import(source, mode, prefer, passphrase.as_deref())?;
+    config_changed("upstreams");
+    Ok(report)
+}
+
+#[tauri::command]
+pub fn list_profiles() -> Vec<profile::ProfileInfo> {
+    profile::list()
+}
+
+/// Create a profile, optionally starting from a copy of another profile's
+/// settings.
+#[tauri::command]
+pub fn create_profile(
+    name: String,
+    copy_from: Option<String>,
+) -> Result<profile::ProfileInfo, String> {
+    profile::create(&name, copy_from.as_deref())
+}
+
+/// Switch to another profile. The HTTP server restarts on the new profile's
+/// config and database, and `profile-switched` is emitted with its info.
+#[tauri::command]
+pub fn switch_profile(name: String) -> Result<profile::ProfileInfo, String> {
+    let info = profile::switch(&name)?;
+    emit("profile-switched", &info);
+    Ok(info)
+}
+
+/// Change log levels (e.g. "info, anthropic=debug") immediately and keep
+/// them in the settings file.
+#[tauri::command]
+pub fn set_log_levels(levels: String) -> Result<(), String> {
+    logger::LevelFilter::parse(&levels)?;
+    let mut cfg = config::load_raw();
+    cfg.logging.levels = levels;
+    config::save(&cfg)
+}
+
+/// Roll the log file over now. Returns the archive path, if anything was
+/// written since the last rotation.
+#[tauri::command]
+pub fn rotate_logs_now() -> Result<Option<String>, String> {
+    logger::rotate_now().map(|archive| archive.map(|p| p.display().to_string()))
+}
+
+/// Open the folder holding the log file and its archives.
+#[tauri::command]
+pub fn open_log_folder() -> Result<(), String> {
+    open::that(logger::log_dir()).map_err(|e| e.to_string())
+}
+
+/// Most recent buffered log entries matching the filter, oldest first.
+#[tauri::command]
+pub fn get_live_logs(
+    filter: Option<logger::LiveFilter>,
+    n: Option<usize>,
+) -> Vec<logger::LiveEntry> {
+    logger::subscribe(&filter.unwrap_or_default(), n.unwrap_or(100)).0
+}
+
+/// Restart the HTTP server, first saving a new listen address if one is
+/// given. Resolves once the server listens again; a bind failure comes back
+/// as a `BindError`.
+#[tauri::command]
+pub async fn restart_server(
+    host: Option<String>,
+    port: Option<u16>,
+) -> Result<server::ServerInfo, server::BindError> {
+    if host.is_some() || port.is_some() {
+        let mut cfg = config::load_raw();
+        if let Some(host) = host {
+            cfg.server.host = host;
+        }
+        if let Some(port) = port {
+            cfg.server.port = port;
+        }
+        config::save(&cfg).map_err(|message| server::BindError {
+            address: format!("{}:{}", cfg.server.host, cfg.server.port),
+            kind: "invalid_address".to_string(),
+            port: Some(cfg.server.port),
+            message,
+        })?;
+    }
+    server::restart_and_wait().await
+}
+
+/// Configured and actually bound address of the HTTP server.
+#[tauri::command]
+pub fn get_server_info() -> server::ServerInfo {
+    server::info()
+}
+
+/// `starting`, `running` (with its address), `stopped` or `error`; the same
+/// as the latest `server-state` event.
+#[tauri::command]
+pub fn server_status() -> server::ServerState {
+    server::state()
+}
+
+/// Start a stopped server; resolves once it listens (or failed to bind).
+#[tauri::command]
+pub async fn start_server() -> Result<server::ServerInfo, server::BindError> {
+    if matches!(server::state(), server::ServerState::Running { .. }) {
+        return Ok(server::info());
+    }
+    server::restart_and_wait().await
+}
+
+/// Stop listening. In-flight requests get a few seconds to finish; the
+/// server stays down until `start_server` or `restart_server`.
+#[tauri::command]
+pub async fn stop_server() -> Result<(), String> {
+    server::stop_and_wait().await
+}
+
+/// Relays advertised on the local network, listening for `wait_ms`
+/// (default 3s) for answers.
+#[tauri::command]
+pub async fn discover_relays(wait_ms: Option<u64>) -> Result<Vec<discovery::RemoteRelay>, String> {
+    let wait = std::time::Duration::from_millis(wait_ms.unwrap_or(3000));
+    tokio::task::spawn_blocking(move || discovery::discover(wait))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Configure tools against the relay at `url` from now on; without `url`,
+/// against this relay again. Tools already configured keep their settings
+/// until auto-configuration is applied again.
+#[tauri::command]
+pub fn adopt_relay(url: Option<String>, token: Option<String>) -> Result<(), String> {
+    discovery::adopt(
+        url.as_deref().unwrap_or_default(),
+        token.as_deref().unwrap_or_default(),
+    )
+}
+
+/// Tell every window that `section` of the settings was saved.
+fn config_changed(section: &str) {
+    emit("config-changed", serde_json::json!({ "section": section }));
+}
+
+/// Upstreams as written in the settings file, `${VAR}` references kept.
+#[tauri::command]
+pub fn list_upstreams() -> Vec<config::Upstream> {
+    config::load_raw().upstreams
+}
+
+/// Add or replace an upstream; `original_id` renames one. Returns the
+/// warnings of the saved configuration.
+#[tauri::command]
+pub fn save_upstream(
+    upstream: config::Upstream,
+    original_id: Option<String>,
+) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::save_upstream(upstream, original_id.as_deref())?;
+    config_changed("upstreams");
+    Ok(warnings)
+}
+
+#[tauri::command]
+pub fn delete_upstream(id: String) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::delete_upstream(&id)?;
+    config_changed("upstreams");
+    Ok(warnings)
+}
+
+#[tauri::command]
+pub fn list_models() -> Vec<config::ModelCfg> {
+    config::load_raw().models
+}
+
+/// Add or replace a model; `original_id` renames one.
+#[tauri::command]
+pub fn save_model(
+    model: config::ModelCfg,
+    original_id: Option<String>,
+) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::save_model(model, original_id.as_deref())?;
+    config_changed("models");
+    Ok(warnings)
+}
+
+#[tauri::command]
+pub fn delete_model(id: String) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::delete_model(&id)?;
+    config_changed("models");
+    Ok(warnings)
+}
+
+#[tauri::command]
+pub fn list_projects() -> Vec<projects::Project> {
+    projects::list()
+}
+
+#[tauri::command]
+pub fn get_project(id: i64) -> Option<projects::Project> {
+    projects::get(id)
+}
+
+#[tauri::command]
+pub fn create_project(
+    input: projects::ProjectInput,
+) -> Result<projects::Project, config::SaveError> {
+    let project = projects::create(input)?;
+    config_changed("projects");
+    Ok(project)
+}
+
+#[tauri::command]
+pub fn update_project(
+    id: i64,
+    input: projects::ProjectInput,
+) -> Result<projects::Project, config::SaveError> {
+    let project =
+        projects::update(id, input)?.ok_or_else(|| format!("Project {} not found", id))?;
+    config_changed("projects");
+    Ok(project)
+}
+
+/// Delete a project and revoke its token; its usage history is kept.
+#[tauri::command]
+pub fn delete_project(id: i64) -> Result<(), String> {
+    if !projects::remove(id) {
+        return Err(format!("Project {} not found", id));
+    }
+    config_changed("projects");
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_settings() -> config::Settings {
+    config::load_raw()
+}
+
+/// Save every setting except upstreams and models.
+#[tauri::command]
+pub fn save_settings(
+    settings: config::Settings,
+) -> Result<Vec<config::ValidationIssue>, config::SaveError> {
+    let warnings = config::save_settings(settings)?;
+    config_changed("settings");
+    sync_autostart();
+    Ok(warnings)
+}
+
+/// Turn starting at login on or off.
+#[tauri::command]
+pub fn set_autostart(enabled: bool) -> Result<(), String> {
+    let mut cfg = config::load_raw();
+    cfg.desktop.autostart = enabled;
+    config::save(&cfg)?;
+    config_changed("settings");
+    sync_autostart();
+    Ok(())
+}
+
+/// Configuration for `editor` ("claude-code", "cursor", "continue" or
+/// "openai-sdk") using `model`, with the current address and forward token.
+#[tauri::command]
+pub fn get_editor_snippet(
+    editor: String,
+    model: String,
+) -> Result<autoconfig::EditorSnippet, String> {
+    autoconfig::editor_snippet(&editor, &model)
+}
+
+/// The same, put on the clipboard.
+#[tauri::command]
+pub fn copy_editor_snippet(
+    app: tauri::AppHandle,
+    editor: String,
+    model: String,
+) -> Result<autoconfig::EditorSnippet, String> {
+    let snippet = autoconfig::editor_snippet(&editor, &model)?;
+    app.clipboard()
+        .write_text(snippet.content.clone())
+        .map_err(|e| format!("Failed to copy to the clipboard: {}", e))?;
+    Ok(snippet)
+}
+
+/// What pointing `target` ("claude_code", "cursor", "vscode", "continue"
+/// or "zed") at the relay would change, without writing anything. Continue
+/// and Zed need the `model` to add.
+#[tauri::command]
+pub fn preview_autoconfig(
+    target: String,
+    model: Option<String>,
+) -> Result<autoconfig::AppliedChanges, String> {
+    autoconfig::apply(&target, model.as_deref(), true)
+}
+
+/// Point `target` at the relay, backing up the files it changes.
+#[tauri::command]
+pub fn apply_autoconfig(
+    target: String,
+    model: Option<String>,
+) -> Result<autoconfig::AppliedChanges, String> {
+    autoconfig::apply(&target, model.as_deref(), false)
+}
+
+/// Put back the files [`apply_autoconfig`] changed.
+#[tauri::command]
+pub fn revert_autoconfig(target: String) -> Result<autoconfig::AppliedChanges, String> {
+    autoconfig::revert(&target)
+}
+
+/// Send the request `target` makes with its written settings through the
+/// relay. The usage row is marked synthetic and not billed.
+#[tauri::command]
+pub async fn verify_autoconfig(target: String) -> Result<autoconfig::VerifyResult, String> {
+    autoconfig::verify(&target).await
+}
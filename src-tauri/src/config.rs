@@ -1,6 +1,11 @@
-use dirs::data_dir;
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, SystemTime};
 use std::{fs, path::PathBuf};
 
+use arc_swap::ArcSwapOption;
+use notify::{RecursiveMode, Watcher};
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct Settings {
@@ -11,6 +16,8 @@ pub struct Settings {
     pub retry_max_ms: Option<u64>,
     /// Forward token used to protect proxy endpoints.
     pub forward_token: Option<String>,
+    /// Grace period and schedule for replacing the forward token
+    pub token_rotation: TokenRotationConfig,
     /// UI / upstream preference hints (e.g. "openai", "anthropic", "gemini").
     pub preferred_api_style: Option<String>,
     /// Optional accent color so the frontend can switch to a light blue theme.
@@ -27,6 +34,856 @@ pub struct Settings {
     pub theme: ThemeConfig,
     /// Backup configuration
     pub backup: BackupConfig,
+    /// Request/response capture for debugging conversions
+    pub capture: CaptureConfig,
+    /// Stored answers to repeated deterministic requests
+    pub response_cache: ResponseCacheConfig,
+    /// Database retention and scheduled maintenance
+    pub maintenance: MaintenanceConfig,
+    /// Automatic price updates from the OpenRouter catalog
+    pub price_sync: PriceSyncConfig,
+    /// URLs notified about completed requests and exhausted budgets
+    pub webhooks: Vec<WebhookConfig>,
+    /// Handling of requests for model ids that aren't configured
+    pub unknown_model: UnknownModelConfig,
+    /// Log levels and log file output
+    pub logging: LoggingConfig,
+    /// OpenTelemetry trace export
+    pub telemetry: TelemetryConfig,
+    /// Address the HTTP server listens on
+    pub server: ServerConfig,
+    /// Finding relays on the local network
+    pub discovery: DiscoveryConfig,
+    /// Connection reuse towards upstreams
+    pub http_client: HttpClientConfig,
+    /// Desktop notifications about failures and budgets
+    pub notifications: NotificationsConfig,
+    /// Login autostart and tray-only mode of the desktop app
+    pub desktop: DesktopConfig,
+    /// Offline detection and what pauses while offline
+    pub network: NetworkConfig,
+    /// Replacements applied to model output before it reaches the client
+    pub redaction: RedactionConfig,
+    /// Personal data and secrets removed from prompts before they are sent
+    pub prompt_redaction: PromptRedactionConfig,
+    /// MCP servers whose tools the relay runs for its clients
+    pub mcp: McpConfig,
+    /// Built-in `web_fetch` tool the relay offers and runs itself
+    pub web_fetch: WebFetchConfig,
+    /// Downloads of remote `image_url`s for upstreams that take images
+    /// inline only
+    pub image_fetch: ImageFetchConfig,
+    /// Thinking budgets that OpenAI `reasoning_effort` maps to on Anthropic
+    /// and Gemini upstreams, and back when their budgets go to OpenAI
+    pub reasoning_budgets: ReasoningBudgets,
+    /// Pass the thinking blocks of earlier Anthropic turns on as text when
+    /// the request goes to another provider. Off drops them: their
+    /// signatures mean nothing there and the text only fills the context
+    pub keep_thinking_history: bool,
+    /// A/B tests splitting the requests for a model id between two models
+    pub experiments: Vec<ExperimentConfig>,
+    /// Fixed estimates for attachments in local token counts
+    pub token_count: TokenCountConfig,
+}
+
+/// Tokens counted for one attachment by `POST /v1/token-count`, whatever
+/// its encoded size: providers bill images by pixels and documents by
+/// pages, neither of which the relay looks at.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TokenCountConfig {
+    pub image_tokens: u32,
+    pub document_tokens: u32,
+}
+
+impl Default for TokenCountConfig {
+    fn default() -> Self {
+        Self {
+            image_tokens: 1000,
+            document_tokens: 3000,
+        }
+    }
+}
+
+/// Requests for `model` split between two configured models. The split is
+/// by client: the payload's `user`, else the session, else the client
+/// token, so a conversation stays with one variant.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ExperimentConfig {
+    /// Name the variant of each request is recorded under
+    pub id: String,
+    /// Model id clients request
+    pub model: String,
+    /// Configured model serving variant A
+    pub variant_a: String,
+    /// Configured model serving variant B
+    pub variant_b: String,
+    /// Percentage of clients on variant A, the rest get B
+    pub split_percent: u8,
+    /// Set once the experiment ended: "a" or "b", which then serves every
+    /// request for `model`
+    pub winner: Option<String>,
+}
+
+impl Default for ExperimentConfig {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            model: String::new(),
+            variant_a: String::new(),
+            variant_b: String::new(),
+            split_percent: 50,
+            winner: None,
+        }
+    }
+}
+
+impl ExperimentConfig {
+    /// Configured model serving `variant` ("a" or "b")
+    pub fn variant_model(&self, variant: &str) -> Option<&str> {
+        match variant.trim().to_ascii_lowercase().as_str() {
+            "a" => Some(&self.variant_a),
+            "b" => Some(&self.variant_b),
+            _ => None,
+        }
+    }
+}
+
+/// Thinking tokens granted for each `reasoning_effort`, and the effort a
+/// budget reads as. Anthropic takes no less than 1024.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ReasoningBudgets {
+    pub minimal: i64,
+    pub low: i64,
+    pub medium: i64,
+    pub high: i64,
+}
+
+impl Default for ReasoningBudgets {
+    fn default() -> Self {
+        Self {
+            minimal: 1024,
+            low: 2048,
+            medium: 8192,
+            high: 24576,
+        }
+    }
+}
+
+impl ReasoningBudgets {
+    /// Budget for an effort level; `none` means no thinking.
+    pub fn for_effort(&self, effort: &str) -> Option<i64> {
+        match effort.trim().to_ascii_lowercase().as_str() {
+            "minimal" => Some(self.minimal),
+            "low" => Some(self.low),
+            "medium" => Some(self.medium),
+            "high" => Some(self.high),
+            _ => None,
+        }
+    }
+
+    /// Effort level of a thinking budget: the highest level it covers,
+    /// `minimal` below all of them.
+    pub fn effort_for(&self, budget: i64) -> &'static str {
+        [
+            ("high", self.high),
+            ("medium", self.medium),
+            ("low", self.low),
+        ]
+        .into_iter()
+        .find(|(_, level)| budget >= *level)
+        .map_or("minimal", |(effort, _)| effort)
+    }
+}
+
+/// Replacing the forward token. The token it replaces is still accepted
+/// for `grace_secs`, so connected editors keep working until they pick up
+/// the new one.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TokenRotationConfig {
+    /// Seconds the previous token stays valid; 0 ends it at once
+    pub grace_secs: u64,
+    /// Unix time of a planned rotation, cleared once it ran
+    pub scheduled_at: Option<i64>,
+    /// Number of the current token, raised by every rotation
+    pub version: u64,
+    /// The token last rotated out
+    pub previous_token: Option<String>,
+    /// Unix time the previous token stops being accepted
+    pub previous_expires_at: Option<i64>,
+}
+
+impl Default for TokenRotationConfig {
+    fn default() -> Self {
+        Self {
+            grace_secs: 3600,
+            scheduled_at: None,
+            version: 1,
+            previous_token: None,
+            previous_expires_at: None,
+        }
+    }
+}
+
+/// Which forward token a request presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenVersion {
+    pub version: u64,
+    /// The previous token, accepted until its grace period ends
+    pub deprecated: bool,
+}
+
+/// Outcome of a forward token rotation.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct TokenRotation {
+    pub token: String,
+    pub version: u64,
+    /// Unix time the replaced token stops working, unset if it already did
+    pub previous_expires_at: Option<i64>,
+}
+
+impl Settings {
+    /// The forward token `token` is: the current one or, until `now`
+    /// reaches its expiry, the previous one.
+    pub fn forward_token_version(&self, token: &str, now: i64) -> Option<TokenVersion> {
+        let rotation = &self.token_rotation;
+        if self
+            .forward_token
+            .as_deref()
+            .is_some_and(|current| !current.is_empty() && current == token)
+        {
+            return Some(TokenVersion {
+                version: rotation.version,
+                deprecated: false,
+            });
+        }
+        let previous = rotation.previous_token.as_deref()?;
+        let valid = rotation.previous_expires_at.is_some_and(|at| now < at);
+        (valid && !previous.is_empty() && previous == token).then(|| TokenVersion {
+            version: rotation.version.saturating_sub(1),
+            deprecated: true,
+        })
+    }
+}
+
+/// The relay's own `web_fetch(url)` tool (see `tools::web_fetch`). Rounds
+/// of it count towards `mcp.max_loops`
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebFetchConfig {
+    pub enabled: bool,
+    /// Domains that may be fetched, subdomains included; empty for any
+    pub allow_domains: Vec<String>,
+    /// Domains that may never be fetched, checked before `allow_domains`
+    pub deny_domains: Vec<String>,
+    pub timeout_secs: u64,
+    /// Bytes of a response read at most
+    pub max_bytes: usize,
+    /// Characters of extracted text returned to the model
+    pub max_chars: usize,
+}
+
+impl Default for WebFetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow_domains: Vec::new(),
+            deny_domains: Vec::new(),
+            timeout_secs: 20,
+            max_bytes: 2 * 1024 * 1024,
+            max_chars: 20_000,
+        }
+    }
+}
+
+/// Remote images of OpenAI requests inlined as base64 for Anthropic and
+/// Gemini upstreams (see `forward::images`). Off by default, remote images
+/// then reach those upstreams as a text placeholder.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ImageFetchConfig {
+    pub enabled: bool,
+    /// Domains images may come from, subdomains included; empty for any
+    pub allow_domains: Vec<String>,
+    /// Domains never fetched, checked before `allow_domains`
+    pub deny_domains: Vec<String>,
+    pub timeout_secs: u64,
+    /// Largest image downloaded
+    pub max_bytes: usize,
+    /// How long a fetched image is reused for the same URL
+    pub cache_secs: u64,
+}
+
+impl Default for ImageFetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow_domains: Vec::new(),
+            deny_domains: Vec::new(),
+            timeout_secs: 10,
+            max_bytes: 5 * 1024 * 1024,
+            cache_secs: 300,
+        }
+    }
+}
+
+/// Relay-managed tools (see `tools::mcp` and `forward::tool_loop`)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct McpConfig {
+    pub servers: Vec<McpServer>,
+    /// Tool rounds run for one request, built-in tools included; the turn
+    /// after the last one is asked to answer without tools
+    pub max_loops: u32,
+}
+
+impl Default for McpConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            max_loops: 8,
+        }
+    }
+}
+
+/// An MCP server reached over the streamable HTTP transport
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct McpServer {
+    /// Prefix of its tool names, as `<id>__<tool>`
+    pub id: String,
+    pub url: String,
+    /// Sent with every request, e.g. `Authorization`
+    pub headers: BTreeMap<String, String>,
+    pub timeout_secs: u64,
+    pub enabled: bool,
+}
+
+impl Default for McpServer {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            url: String::new(),
+            headers: BTreeMap::new(),
+            timeout_secs: 60,
+            enabled: true,
+        }
+    }
+}
+
+/// Redaction of model output (see `forward::redact`)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub rules: Vec<RedactionRule>,
+    /// Characters of streamed text held back so that a match split across
+    /// chunks is still caught; longer matches can slip through
+    pub max_lookback: usize,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_lookback: 256,
+        }
+    }
+}
+
+/// Redaction of prompts on their way upstream (see `forward::scrub`)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PromptRedactionConfig {
+    pub enabled: bool,
+    /// Built-in patterns applied to every request: "email", "phone" and
+    /// "api_key"
+    pub builtin: Vec<String>,
+    /// Patterns of secrets of your own
+    pub rules: Vec<PromptRedactionRule>,
+}
+
+impl Default for PromptRedactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            builtin: vec!["email".to_string(), "phone".to_string(), "api_key".to_string()],
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// A regular expression whose matches are replaced with `[NAME]`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PromptRedactionRule {
+    pub name: String,
+    pub pattern: String,
+    /// Name of the project the rule is limited to; unset for every request
+    pub project: Option<String>,
+    pub enabled: bool,
+}
+
+impl Default for PromptRedactionRule {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            pattern: String::new(),
+            project: None,
+            enabled: true,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RedactionRule {
+    /// Text to find, or a regular expression when `regex` is set
+    pub pattern: String,
+    pub regex: bool,
+    /// Written instead of each match; regex rules can use `$1` for groups
+    pub replacement: String,
+    /// Name of the project the rule is limited to; unset for every request
+    pub project: Option<String>,
+    pub enabled: bool,
+}
+
+impl Default for RedactionRule {
+    fn default() -> Self {
+        Self {
+            pattern: String::new(),
+            regex: false,
+            replacement: "[REDACTED]".to_string(),
+            project: None,
+            enabled: true,
+        }
+    }
+}
+
+/// Connectivity check (see `network`). While offline, price sync, webhook
+/// delivery and telemetry export wait, and requests to remote upstreams fail
+/// right away
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub detect_offline: bool,
+    /// `host:port` pairs tried in turn; reaching any of them means online.
+    /// With a custom global proxy the proxy is checked instead
+    pub check_targets: Vec<String>,
+    pub check_interval_secs: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            detect_offline: true,
+            check_targets: vec!["1.1.1.1:443".to_string(), "8.8.8.8:443".to_string()],
+            check_interval_secs: 30,
+        }
+    }
+}
+
+/// How the desktop app starts and closes; the standalone server ignores it
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DesktopConfig {
+    /// Start the app in the background when the user logs in
+    pub autostart: bool,
+    /// Start with only the tray icon and no window, and hide the window
+    /// instead of quitting when it is closed. Quit in the tray exits
+    pub background: bool,
+}
+
+/// Which events raise a desktop notification (see `notifications`)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    pub enabled: bool,
+    /// An upstream starts failing repeatedly
+    pub upstream_failing: bool,
+    /// A budget passes 80% or 100% of its limit
+    pub budget: bool,
+    /// The forward token is used from an address not seen since the start
+    pub new_client_ip: bool,
+    /// At most one notification per event and subject (upstream, budget,
+    /// address) within this many seconds
+    pub min_interval_secs: u64,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            upstream_failing: true,
+            budget: true,
+            new_client_ip: true,
+            min_interval_secs: 300,
+        }
+    }
+}
+
+/// Connection pool of the clients that reach upstreams. Changes apply to
+/// requests started afterwards, on fresh connections
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct HttpClientConfig {
+    /// Close pooled connections idle for longer than this. 0 = keep them
+    pub pool_idle_timeout_secs: u64,
+    /// Idle connections kept per upstream host
+    pub pool_max_idle_per_host: usize,
+    /// Send HTTP/2 pings this often, also on idle connections, so they
+    /// survive NAT and load balancer timeouts. 0 = off
+    pub http2_keep_alive_secs: u64,
+    /// Drop an HTTP/2 connection whose ping isn't answered within this
+    pub http2_keep_alive_timeout_secs: u64,
+    /// TCP keepalive probes on idle connections. 0 = off
+    pub tcp_keepalive_secs: u64,
+    /// Give up connecting (DNS, TCP and TLS) to an endpoint after this and
+    /// try the next one
+    pub connect_timeout_secs: u64,
+    /// Fail a request when the upstream sends nothing for this long, on
+    /// top of the overall request timeout. 0 = off
+    pub read_timeout_secs: u64,
+    /// Keep resolved upstream addresses this long; configured endpoints
+    /// are resolved ahead. 0 = look up every new connection
+    pub dns_cache_secs: u64,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout_secs: 90,
+            pool_max_idle_per_host: 16,
+            http2_keep_alive_secs: 30,
+            http2_keep_alive_timeout_secs: 10,
+            tcp_keepalive_secs: 60,
+            connect_timeout_secs: 5,
+            read_timeout_secs: 120,
+            dns_cache_secs: 300,
+        }
+    }
+}
+
+/// Listen address of the HTTP server; address changes apply on
+/// `restart_server` and are reported as needing it (see
+/// [`ServerConfig::restart_required`]), the timeouts on the next request
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// "127.0.0.1" for this machine only, "0.0.0.0" (or a LAN address) to
+    /// accept other machines
+    pub host: String,
+    /// 0 lets the system pick a free port
+    pub port: u16,
+    /// Listen on `host`:`port`; turn off to serve only on `unix_socket`
+    pub tcp: bool,
+    /// Serve HTTPS instead of plain HTTP
+    pub tls: TlsConfig,
+    /// Also serve on this unix socket (user-only permissions), relative to
+    /// the data directory unless absolute. Not available on Windows
+    pub unix_socket: String,
+    /// End-to-end limit for a non-streaming request, retries and fallbacks
+    /// included; answered with 504 when exceeded. 0 = no limit
+    pub request_timeout_secs: u64,
+    /// Non-streaming requests slower than this are logged with a breakdown
+    /// of where the time went. 0 = off
+    pub slow_request_ms: u64,
+    /// Requests in flight at once, streams included; more get a 503.
+    /// 0 = no limit
+    pub max_connections: usize,
+    /// The same, per client address. 0 = no limit
+    pub max_connections_per_ip: usize,
+    /// Model lists, latency tests and health reports are reused for this
+    /// long, until the configuration changes; identical requests in flight
+    /// at once always share one answer
+    pub probe_cache_secs: u64,
+    /// Upstream response headers passed on to the client on non-streaming
+    /// answers (case-insensitive; a trailing `*` matches any suffix)
+    pub passthrough_headers: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8787,
+            tcp: true,
+            tls: TlsConfig::default(),
+            unix_socket: String::new(),
+            request_timeout_secs: 600,
+            slow_request_ms: 30_000,
+            max_connections: 512,
+            max_connections_per_ip: 128,
+            probe_cache_secs: 3,
+            passthrough_headers: vec![
+                "x-request-id".to_string(),
+                "request-id".to_string(),
+                "openai-processing-ms".to_string(),
+                "x-ratelimit-*".to_string(),
+                "anthropic-ratelimit-*".to_string(),
+            ],
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Keys of the listener settings that differ between `self` and `next`.
+    /// The running server keeps its sockets until `restart_server`.
+    pub fn restart_required(&self, next: &ServerConfig) -> Vec<String> {
+        let changed = [
+            ("server.host", self.host != next.host),
+            ("server.port", self.port != next.port),
+            ("server.tcp", self.tcp != next.tcp),
+            ("server.tls", self.tls != next.tls),
+            ("server.unix_socket", self.unix_socket != next.unix_socket),
+        ];
+        changed
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+}
+
+/// mDNS advertisement as `_ai-relay._tcp` (see `discovery`) and the relay
+/// tools are pointed at. Advertising changes apply on `restart_server`
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Announce this relay on the local network. Never done while the
+    /// server listens on a loopback address only
+    pub advertise: bool,
+    /// Instance name other devices see; empty = the host name
+    pub name: String,
+    /// Base URL of a relay on another machine that tool auto-configuration
+    /// uses instead of this one; empty = this relay
+    pub remote_url: String,
+    /// Forward token of `remote_url`
+    pub remote_token: String,
+}
+
+/// HTTPS settings (see `tls`)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain
+    pub cert_path: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: String,
+    /// Without `cert_path`, generate and use a self-signed certificate
+    pub self_signed: bool,
+}
+
+/// Logging configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Default level and per-module overrides, e.g. "info, anthropic=debug"
+    pub levels: String,
+    /// Log file format: "text" or "json" (one object per line)
+    pub format: String,
+    /// Append entries to `logs/ccr.log` in the data directory
+    pub file: bool,
+    /// Roll the log file over once it reaches this size
+    pub max_file_mb: u64,
+    /// Roll the log file over once it is this old (0 disables)
+    pub max_age_hours: u64,
+    /// Number of gzipped archives kept next to the log file
+    pub keep_archives: usize,
+    /// Write one line per HTTP request to `logs/access.log` (rotated like
+    /// the log file)
+    pub access_log: bool,
+    /// Also store access log lines in the `access_log` table
+    pub access_log_db: bool,
+    /// Levels whose repeated entries are collapsed: "info", "warn" and/or
+    /// "error" (debug is never deduplicated)
+    pub dedup_levels: Vec<String>,
+    /// How long repeats are collapsed before the count is logged (0 disables)
+    pub dedup_window_secs: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            levels: "debug".to_string(),
+            format: "text".to_string(),
+            file: true,
+            max_file_mb: 20,
+            max_age_hours: 24,
+            keep_archives: 5,
+            access_log: true,
+            access_log_db: false,
+            dedup_levels: vec!["warn".to_string(), "error".to_string()],
+            dedup_window_secs: 60,
+        }
+    }
+}
+
+/// OpenTelemetry trace export (OTLP over HTTP with JSON bodies)
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// OTLP traces endpoint of the collector
+    pub endpoint: String,
+    /// Headers sent with every export, e.g. the API key of a hosted backend
+    pub headers: BTreeMap<String, String>,
+    /// `service.name` of the exported resource
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            headers: BTreeMap::new(),
+            service_name: "ccr-relay".to_string(),
+        }
+    }
+}
+
+/// What to do when a request names a model that isn't configured
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct UnknownModelConfig {
+    /// "reject" (404), "default_to" (serve `default_to` instead) or
+    /// "suggest" (404 with a `did_you_mean` list of similar ids)
+    pub mode: String,
+    /// Model id used in "default_to" mode
+    pub default_to: Option<String>,
+}
+
+impl Default for UnknownModelConfig {
+    fn default() -> Self {
+        Self {
+            mode: "reject".to_string(),
+            default_to: None,
+        }
+    }
+}
+
+/// Webhook endpoint configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Shared secret used to sign each body (HMAC-SHA256)
+    pub secret: Option<String>,
+    /// Event types to send ("request.completed", "budget.exceeded"); empty sends all
+    pub events: Vec<String>,
+    pub enabled: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            secret: None,
+            events: Vec::new(),
+            enabled: true,
+        }
+    }
+}
+
+/// Price catalog sync configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PriceSyncConfig {
+    /// Periodically fetch the catalog and apply new prices (opt-in)
+    pub enabled: bool,
+    pub interval_hours: u32,
+    pub catalog_url: String,
+}
+
+impl Default for PriceSyncConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 24,
+            catalog_url: "https://openrouter.ai/api/v1/models".to_string(),
+        }
+    }
+}
+
+/// Database maintenance configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// Run the scheduled maintenance job
+    pub enabled: bool,
+    /// Local hour (0-23) at which the daily job runs
+    pub hour: u32,
+    /// Delete usage rows older than this many days (None keeps them forever).
+    /// Values below 35 are raised to 35 so monthly stats and budgets stay exact.
+    pub usage_retention_days: Option<u32>,
+    /// Fold pruned usage rows into `usage_rollup_daily` before deleting them
+    pub rollup: bool,
+    /// Reclaim free pages after pruning
+    pub vacuum: bool,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hour: 3,
+            usage_retention_days: None,
+            rollup: true,
+            vacuum: true,
+        }
+    }
+}
+
+/// Request capture configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// "off", "headers" (request line + headers only) or "full" (bodies too)
+    pub mode: String,
+    /// Bodies longer than this are truncated
+    pub max_bytes: usize,
+    /// Captures older than this many days are pruned
+    pub retention_days: u32,
+    /// Also write each answered exchange as a replay fixture (see
+    /// `forward::fixtures`), whatever the mode
+    pub fixtures: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            mode: "off".to_string(),
+            max_bytes: 64 * 1024,
+            retention_days: 7,
+            fixtures: false,
+        }
+    }
+}
+
+/// Cache of non-streaming answers (see `forward::cache`). Off unless
+/// enabled; clients steer it per request with `x-relay-cache`
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponseCacheConfig {
+    pub enabled: bool,
+    /// Answers older than this are not reused
+    pub ttl_secs: u64,
+    /// Least recently used answers are evicted beyond this size
+    pub max_mb: u64,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 24 * 60 * 60,
+            max_mb: 256,
+        }
+    }
 }
 
 /// Proxy configuration
@@ -70,6 +927,41 @@ pub struct RateLimitConfig {
     pub budget_weekly_usd: Option<f64>,
     /// Monthly budget in USD
     pub budget_monthly_usd: Option<f64>,
+    /// Scoped budgets (per model, upstream, token or project)
+    pub budgets: Vec<BudgetRule>,
+    /// Day of month (1-28) on which monthly budgets reset
+    pub budget_reset_day: u32,
+    /// Timezone offset in minutes used to align budget periods
+    pub budget_utc_offset_minutes: i32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BudgetRule {
+    /// "global", "model", "upstream", "token" or "project"
+    pub scope: String,
+    /// Model id, upstream id, token fingerprint or project name; unused for global
+    pub target: Option<String>,
+    /// "daily", "weekly" or "monthly"
+    pub period: String,
+    pub limit_usd: f64,
+    /// "block", "downgrade" or "warn"
+    pub action: String,
+    /// Model to route to when the action is "downgrade"
+    pub fallback_model: Option<String>,
+}
+
+impl Default for BudgetRule {
+    fn default() -> Self {
+        Self {
+            scope: "global".to_string(),
+            target: None,
+            period: "monthly".to_string(),
+            limit_usd: 0.0,
+            action: "block".to_string(),
+            fallback_model: None,
+        }
+    }
 }
 
 impl Default for RateLimitConfig {
@@ -81,6 +973,9 @@ impl Default for RateLimitConfig {
             budget_daily_usd: None,
             budget_weekly_usd: None,
             budget_monthly_usd: None,
+            budgets: Vec::new(),
+            budget_reset_day: 1,
+            budget_utc_offset_minutes: 0,
         }
     }
 }
@@ -184,6 +1079,106 @@ pub struct Upstream {
     pub api_style: Option<String>,
     /// Optional API key for this upstream. If not set, will use client headers or environment variables.
     pub api_key: Option<String>,
+    /// Additional keys rotated round-robin together with `api_key`.
+    pub api_keys: Vec<UpstreamKey>,
+    /// Static headers sent with every request, after (and overriding) the defaults.
+    pub extra_headers: BTreeMap<String, String>,
+    /// Path templates replacing `/chat/completions`, `/v1/messages` and
+    /// `/{api_version}/models/{model}:generateContent`.
+    /// `{model}` and `{api_version}` are substituted per request.
+    pub chat_path: Option<String>,
+    pub messages_path: Option<String>,
+    pub generate_path: Option<String>,
+    /// Value for `{api_version}`; Gemini paths fall back to the request's version.
+    pub api_version: Option<String>,
+    /// Proxy for this upstream (`http://`, `https://` or `socks5://`, with
+    /// optional `user:pass@`). Overrides the global proxy.
+    pub proxy: Option<String>,
+    /// Connect directly even when a global proxy is configured.
+    pub no_proxy: bool,
+    /// PEM file (one or more certificates) trusted in addition to the
+    /// built-in roots, for servers behind a private CA or TLS interception.
+    pub ca_cert_path: Option<String>,
+    /// Accept any certificate, including self-signed and expired ones.
+    /// Only for lab setups; logged as a warning whenever it is used.
+    pub insecure_skip_verify: bool,
+    /// Answers of an upstream with `api_style = "mock"`, which needs no
+    /// endpoints and never leaves the machine
+    pub mock: MockConfig,
+    /// Requests to this upstream run at once, over all its models; more
+    /// wait their turn
+    pub max_concurrency: Option<u32>,
+    /// How long a request waits for its turn before failing with 429;
+    /// 30 s if unset
+    pub max_queue_wait_ms: Option<u64>,
+    /// `safetySettings` sent to Gemini when the request has none for a
+    /// category; the model's own settings take precedence
+    pub safety_settings: Vec<SafetySetting>,
+}
+
+/// Block threshold of one Gemini harm category, e.g.
+/// `HARM_CATEGORY_HARASSMENT` and `BLOCK_ONLY_HIGH`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct SafetySetting {
+    pub category: String,
+    pub threshold: String,
+}
+
+impl Upstream {
+    pub fn is_mock(&self) -> bool {
+        self.api_style
+            .as_deref()
+            .is_some_and(|style| style.eq_ignore_ascii_case("mock"))
+    }
+}
+
+/// Canned answers of a mock upstream, in the dialect of the model routed
+/// to it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MockConfig {
+    /// "echo" (the last user message), "lorem", "text" or "tool_call"
+    pub response: String,
+    /// Answer of `text`, with `{prompt}` and `{model}` filled in
+    pub text: String,
+    /// Tool `tool_call` calls; the request's first tool if unset. Once the
+    /// request carries a tool result, that result is echoed instead
+    pub tool_name: Option<String>,
+    /// JSON arguments of the call
+    pub tool_arguments: String,
+    /// Milliseconds before the answer starts
+    pub latency_ms: u64,
+    /// Characters per streamed chunk
+    pub chunk_chars: usize,
+    /// Milliseconds between streamed chunks
+    pub chunk_delay_ms: u64,
+    /// Statuses answered in turn before each success: `[429, 429]` fails
+    /// twice, succeeds, then starts over
+    pub fail_statuses: Vec<u16>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            response: "echo".to_string(),
+            text: String::new(),
+            tool_name: None,
+            tool_arguments: "{}".to_string(),
+            latency_ms: 0,
+            chunk_chars: 16,
+            chunk_delay_ms: 20,
+            fail_statuses: Vec::new(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct UpstreamKey {
+    /// Name shown in usage breakdowns; defaults to the key's last four characters.
+    pub label: Option<String>,
+    pub key: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
@@ -199,42 +1194,1015 @@ pub struct ModelRoute {
     pub priority: Option<u32>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
-#[serde(default)]
-pub struct ModelCfg {
-    pub id: String,
-    pub display_name: String,
-    pub provider: String,
-    pub upstream_id: String,
-    /// Model name to use when forwarding to upstream API. If empty, uses `id`.
-    pub upstream_model_id: Option<String>,
-    /// Multi-route support: each route can target a provider + upstream.
-    #[serde(default)]
-    pub routes: Vec<ModelRoute>,
-    pub price_prompt_per_1k: f64,
-    pub price_completion_per_1k: f64,
-    /// Priority for model selection (0-100, where 100 is highest priority)
-    /// Priority 100 is reserved for temporary auto-generated models
-    pub priority: u32,
-    /// Mark this model as temporary (auto-generated, should be cleaned up)
-    #[serde(default)]
-    pub is_temporary: bool,
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct ModelCfg {
+    pub id: String,
+    pub display_name: String,
+    pub provider: String,
+    pub upstream_id: String,
+    /// Model name to use when forwarding to upstream API. If empty, uses `id`.
+    pub upstream_model_id: Option<String>,
+    /// Multi-route support: each route can target a provider + upstream.
+    #[serde(default)]
+    pub routes: Vec<ModelRoute>,
+    pub price_prompt_per_1k: f64,
+    pub price_completion_per_1k: f64,
+    /// Cache write (creation) rate; defaults to the prompt rate
+    pub price_cache_write_per_1k: Option<f64>,
+    /// Cache read rate; defaults to the prompt rate
+    pub price_cache_read_per_1k: Option<f64>,
+    /// Reasoning/thinking output rate; defaults to the completion rate
+    pub price_reasoning_per_1k: Option<f64>,
+    /// Rates that replace the base prices once the prompt exceeds a size
+    pub price_tiers: Vec<PriceTier>,
+    /// Parameters the model takes, beyond what its provider accepts
+    pub capabilities: ModelCapabilities,
+    /// Where the prices came from: "manual" or "openrouter". Unset prices that
+    /// are non-zero are treated as manual and never overwritten by a sync.
+    pub price_source: Option<String>,
+    /// Unix time the prices were last updated by a sync
+    pub price_updated_at: Option<i64>,
+    /// Priority for model selection (0-100, where 100 is highest priority)
+    /// Priority 100 is reserved for temporary auto-generated models
+    pub priority: u32,
+    /// Mark this model as temporary (auto-generated, should be cleaned up)
+    #[serde(default)]
+    pub is_temporary: bool,
+    /// Shadow traffic sent to another upstream for comparison
+    pub mirror: Option<MirrorConfig>,
+    /// Requests of this model run at once; more wait their turn
+    pub max_concurrency: Option<u32>,
+    /// How long a request waits for its turn before failing with 429;
+    /// 30 s if unset
+    pub max_queue_wait_ms: Option<u64>,
+    /// Keep prompts inside the model's context window; off if unset
+    pub context_guard: Option<ContextGuardConfig>,
+    /// `safetySettings` sent to Gemini when the request has none for a
+    /// category, before the upstream's
+    pub safety_settings: Vec<SafetySetting>,
+}
+
+/// Prompts larger than the context window of a model, less the room its
+/// answer needs, either lose their oldest messages or are rejected with 400
+/// before reaching the upstream. Sizes are the estimates of
+/// `forward::tokens`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ContextGuardConfig {
+    /// Tokens the model takes, prompt and answer together
+    pub context_length: u32,
+    /// Drop the oldest messages until the prompt fits; off rejects it
+    pub truncate: bool,
+    /// Room left for the answer of requests that don't set max tokens
+    pub default_max_tokens: u32,
+}
+
+impl Default for ContextGuardConfig {
+    fn default() -> Self {
+        Self {
+            context_length: 0,
+            truncate: true,
+            default_max_tokens: 4096,
+        }
+    }
+}
+
+/// Shadow traffic for a model: a sample of its non-streaming requests is
+/// sent again to another upstream once answered, and the two compared.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Upstream the copies go to
+    pub upstream_id: String,
+    /// Model name sent to that upstream; the primary's if unset
+    pub model: Option<String>,
+    /// Share of requests mirrored, from 0 to 1
+    pub sample_rate: f64,
+}
+
+/// Request parameters a model accepts. Unset flags are guessed: OpenAI's
+/// reasoning models (o1, o3, o4, gpt-5) take no sampling parameters, GPT-4,
+/// Claude 3 before 3.7 and Gemini before 2.5 no thinking, and `top_k` is
+/// dropped for OpenAI-style upstreams only.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct ModelCapabilities {
+    pub temperature: Option<bool>,
+    pub top_p: Option<bool>,
+    pub top_k: Option<bool>,
+    /// `presence_penalty` and `frequency_penalty`
+    pub penalties: Option<bool>,
+    /// Thinking, from `reasoning_effort`, `thinking` or `thinkingConfig`
+    pub reasoning: Option<bool>,
+}
+
+/// Context-size price tier. Unset rates inherit the model's base rates.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct PriceTier {
+    /// Applies to requests whose prompt is larger than this many tokens
+    pub above_prompt_tokens: i64,
+    pub price_prompt_per_1k: Option<f64>,
+    pub price_completion_per_1k: Option<f64>,
+    pub price_cache_write_per_1k: Option<f64>,
+    pub price_cache_read_per_1k: Option<f64>,
+    pub price_reasoning_per_1k: Option<f64>,
+}
+
+impl ModelCfg {
+    pub fn resolved_routes(&self) -> Vec<ModelRoute> {
+        if !self.routes.is_empty() {
+            return self.routes.clone();
+        }
+        if self.provider.trim().is_empty() || self.upstream_id.trim().is_empty() {
+            return Vec::new();
+        }
+        vec![ModelRoute {
+            provider: self.provider.clone(),
+            upstream_id: self.upstream_id.clone(),
+            upstream_model_id: self.upstream_model_id.clone(),
+            priority: None,
+        }]
+    }
+}
+
+/// How serious a validation issue is. Errors block saving and reloading.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a candidate configuration.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ValidationIssue {
+    /// JSON pointer to the offending field, e.g. `/models/2/upstream_id`
+    pub path: String,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Issues(Vec<ValidationIssue>);
+
+impl Issues {
+    fn error(&mut self, path: String, message: String) {
+        self.0.push(ValidationIssue {
+            path,
+            message,
+            severity: Severity::Error,
+        });
+    }
+
+    fn warning(&mut self, path: String, message: String) {
+        self.0.push(ValidationIssue {
+            path,
+            message,
+            severity: Severity::Warning,
+        });
+    }
+
+    fn url(&mut self, path: String, value: &str) {
+        match reqwest::Url::parse(value.trim()) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => self.error(
+                path,
+                format!(
+                    "unsupported URL scheme '{}', expected http or https",
+                    url.scheme()
+                ),
+            ),
+            Err(e) => self.error(path, format!("invalid URL '{}': {}", value, e)),
+        }
+    }
+
+    fn price(&mut self, path: String, value: Option<f64>) {
+        if let Some(v) = value {
+            if !v.is_finite() || v < 0.0 {
+                self.error(
+                    path,
+                    format!("price must be a non-negative number, got {}", v),
+                );
+            }
+        }
+    }
+}
+
+/// Expand `${VAR}` references in every string of `raw`. Unset variables
+/// without a default are reported as errors and expand to nothing.
+pub fn expand(raw: &Settings) -> (Settings, Vec<ValidationIssue>) {
+    let mut value = match serde_json::to_value(raw) {
+        Ok(value) => value,
+        Err(_) => return (raw.clone(), Vec::new()),
+    };
+    let mut missing = Vec::new();
+    crate::interpolate::expand_value(&mut value, &mut missing);
+    let issues = missing
+        .into_iter()
+        .map(|(path, names)| ValidationIssue {
+            path,
+            message: format!("undefined environment variable: {}", names.join(", ")),
+            severity: Severity::Error,
+        })
+        .collect();
+    let expanded = serde_json::from_value(value).unwrap_or_else(|_| raw.clone());
+    (expanded, issues)
+}
+
+fn ca_cert(issues: &mut Issues, path: String, file: &str) {
+    match fs::read(file) {
+        Ok(pem) => match reqwest::Certificate::from_pem_bundle(&pem) {
+            Ok(certs) if !certs.is_empty() => {}
+            Ok(_) => issues.error(path, format!("no certificates found in '{}'", file)),
+            Err(e) => issues.error(path, format!("invalid PEM in '{}': {}", file, e)),
+        },
+        Err(e) => issues.error(path, format!("cannot read '{}': {}", file, e)),
+    }
+}
+
+fn proxy_url(issues: &mut Issues, path: String, value: &str) {
+    let value = value.trim();
+    let url = if value.contains("://") {
+        value.to_string()
+    } else {
+        format!("http://{}", value)
+    };
+    match reqwest::Url::parse(&url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") => {}
+        Ok(url) => issues.error(
+            path,
+            format!(
+                "unsupported proxy scheme '{}', expected http, https or socks5",
+                url.scheme()
+            ),
+        ),
+        Err(e) => issues.error(path, format!("invalid proxy URL '{}': {}", value, e)),
+    }
+}
+
+fn safety_settings(issues: &mut Issues, base: &str, settings: &[SafetySetting]) {
+    for (i, setting) in settings.iter().enumerate() {
+        let path = format!("{}/safety_settings/{}", base, i);
+        if setting.category.trim().is_empty() {
+            issues.error(format!("{}/category", path), "category is empty".to_string());
+        }
+        if setting.threshold.trim().is_empty() {
+            issues.error(format!("{}/threshold", path), "threshold is empty".to_string());
+        }
+    }
+}
+
+fn mock(issues: &mut Issues, base: &str, mock: &MockConfig) {
+    if !matches!(
+        mock.response.to_lowercase().as_str(),
+        "echo" | "lorem" | "text" | "tool_call"
+    ) {
+        issues.error(
+            format!("{}/mock/response", base),
+            format!(
+                "unknown mock response '{}', expected echo, lorem, text or tool_call",
+                mock.response
+            ),
+        );
+    }
+    if serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&mock.tool_arguments)
+        .is_err()
+    {
+        issues.error(
+            format!("{}/mock/tool_arguments", base),
+            "tool arguments must be a JSON object".to_string(),
+        );
+    }
+    if mock.chunk_chars == 0 {
+        issues.error(
+            format!("{}/mock/chunk_chars", base),
+            "must be at least 1".to_string(),
+        );
+    }
+    for (i, status) in mock.fail_statuses.iter().enumerate() {
+        if !(400..=599).contains(status) {
+            issues.error(
+                format!("{}/mock/fail_statuses/{}", base, i),
+                format!("{} is not an error status", status),
+            );
+        }
+    }
+}
+
+/// Check a candidate configuration for mistakes that would otherwise only
+/// surface as failed requests: dangling references, duplicate ids, malformed
+/// URLs, unknown enum values, out-of-range numbers and undefined environment
+/// variables. `cfg` is the configuration as written, before expansion.
+pub fn validate(cfg: &Settings) -> Vec<ValidationIssue> {
+    let (expanded, mut issues) = expand(cfg);
+    issues.extend(validate_expanded(&expanded));
+    issues
+}
+
+/// Check `project` against the other live `projects` and the models of
+/// `cfg`: names and tokens must be unique, a token may not be the forward
+/// token, and overrides must route to configured models.
+pub fn validate_project(
+    cfg: &Settings,
+    project: &crate::projects::Project,
+    projects: &[crate::projects::Project],
+) -> Vec<ValidationIssue> {
+    let mut issues = Issues::default();
+    let others = || projects.iter().filter(|p| p.id != project.id);
+    let name = project.name.trim();
+    if name.is_empty() {
+        issues.error("/name".to_string(), "project name is empty".to_string());
+    } else if others().any(|p| p.name.trim().eq_ignore_ascii_case(name)) {
+        issues.error(
+            "/name".to_string(),
+            format!("a project named '{}' already exists", name),
+        );
+    }
+
+    if let Some(token) = project.token.as_deref() {
+        if cfg.forward_token.as_deref() == Some(token) {
+            issues.error(
+                "/token".to_string(),
+                "the token is the forward token".to_string(),
+            );
+        } else if others().any(|p| p.token.as_deref() == Some(token)) {
+            issues.error(
+                "/token".to_string(),
+                "another project uses this token".to_string(),
+            );
+        } else if token.len() < 16 {
+            issues.warning(
+                "/token".to_string(),
+                "the token is shorter than 16 characters".to_string(),
+            );
+        }
+    }
+
+    for (requested, model) in &project.model_overrides {
+        let path = format!(
+            "/model_overrides/{}",
+            requested.replace('~', "~0").replace('/', "~1")
+        );
+        if model.trim().is_empty() {
+            issues.error(path, "override has no model".to_string());
+        } else if !cfg
+            .models
+            .iter()
+            .any(|m| m.id.eq_ignore_ascii_case(model.trim()))
+        {
+            issues.error(path, format!("model '{}' is not configured", model));
+        }
+    }
+
+    if let Some(budget) = project.budget_usd {
+        if !budget.is_finite() || budget < 0.0 {
+            issues.error(
+                "/budget_usd".to_string(),
+                "budget must be a positive amount".to_string(),
+            );
+        }
+    }
+    issues.0
+}
+
+fn validate_expanded(cfg: &Settings) -> Vec<ValidationIssue> {
+    use crate::forward::{self, budget, context::Provider};
+
+    let mut issues = Issues::default();
+    let styles = forward::api_styles();
+
+    let mut upstream_ids = std::collections::HashSet::new();
+    for (i, up) in cfg.upstreams.iter().enumerate() {
+        let base = format!("/upstreams/{}", i);
+        if up.id.trim().is_empty() {
+            issues.error(format!("{}/id", base), "upstream id is empty".to_string());
+        } else if !upstream_ids.insert(up.id.to_lowercase()) {
+            issues.error(
+                format!("{}/id", base),
+                format!("duplicate upstream id '{}'", up.id),
+            );
+        }
+        if up.is_mock() {
+            mock(&mut issues, &base, &up.mock);
+        } else if up.endpoints.is_empty() {
+            issues.error(
+                format!("{}/endpoints", base),
+                "upstream has no endpoints".to_string(),
+            );
+        }
+        for (j, endpoint) in up.endpoints.iter().enumerate() {
+            issues.url(format!("{}/endpoints/{}", base, j), endpoint);
+        }
+        for (name, value) in &up.extra_headers {
+            let path = format!(
+                "{}/extra_headers/{}",
+                base,
+                name.replace('~', "~0").replace('/', "~1")
+            );
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                issues.error(path, format!("illegal header name '{}'", name));
+            } else if reqwest::header::HeaderValue::from_str(value).is_err() {
+                issues.error(path, format!("illegal value for header '{}'", name));
+            }
+        }
+        if let Some(url) = up.proxy.as_deref().filter(|u| !u.trim().is_empty()) {
+            proxy_url(&mut issues, format!("{}/proxy", base), url);
+        }
+        if let Some(path) = up.ca_cert_path.as_deref().filter(|p| !p.trim().is_empty()) {
+            ca_cert(&mut issues, format!("{}/ca_cert_path", base), path.trim());
+        }
+        if up.max_concurrency == Some(0) {
+            issues.error(
+                format!("{}/max_concurrency", base),
+                "max_concurrency must be at least 1".to_string(),
+            );
+        }
+        safety_settings(&mut issues, &base, &up.safety_settings);
+        if up.insecure_skip_verify {
+            issues.warning(
+                format!("{}/insecure_skip_verify", base),
+                "TLS certificate verification is disabled for this upstream".to_string(),
+            );
+        }
+        let templates = [
+            ("chat_path", &up.chat_path),
+            ("messages_path", &up.messages_path),
+            ("generate_path", &up.generate_path),
+        ];
+        for (field, template) in templates {
+            if let Some(t) = template.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+                if !t.starts_with('/') {
+                    issues.error(
+                        format!("{}/{}", base, field),
+                        format!("path '{}' must start with '/'", t),
+                    );
+                }
+            }
+        }
+        if let Some(style) = up.api_style.as_deref().filter(|s| !s.is_empty()) {
+            if !styles.iter().any(|s| s.eq_ignore_ascii_case(style)) {
+                issues.error(
+                    format!("{}/api_style", base),
+                    format!(
+                        "unknown api_style '{}', expected one of: {}",
+                        style,
+                        styles.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+
+    let upstream_exists = |id: &str| {
+        upstream_ids.contains(&id.to_lowercase())
+            || id
+                .parse::<usize>()
+                .map(|idx| idx < cfg.upstreams.len())
+                .unwrap_or(false)
+    };
+    let check_route = |issues: &mut Issues, path: &str, provider: &str, upstream_id: &str| {
+        if Provider::from_str(provider).is_none() {
+            issues.error(
+                format!("{}/provider", path),
+                format!("unknown provider '{}'", provider),
+            );
+        }
+        if upstream_id.trim().is_empty() {
+            issues.error(
+                format!("{}/upstream_id", path),
+                "upstream_id is empty".to_string(),
+            );
+        } else if !upstream_exists(upstream_id) {
+            issues.error(
+                format!("{}/upstream_id", path),
+                format!("upstream '{}' does not exist", upstream_id),
+            );
+        }
+    };
+
+    let mut model_ids = std::collections::HashSet::new();
+    for (i, model) in cfg.models.iter().enumerate() {
+        let base = format!("/models/{}", i);
+        if model.id.trim().is_empty() {
+            issues.error(format!("{}/id", base), "model id is empty".to_string());
+        } else if !model_ids.insert(model.id.to_lowercase()) {
+            issues.error(
+                format!("{}/id", base),
+                format!("duplicate model id '{}'", model.id),
+            );
+        }
+        if model.routes.is_empty() {
+            if model.provider.trim().is_empty() && model.upstream_id.trim().is_empty() {
+                issues.warning(base.clone(), "model has no routes".to_string());
+            } else {
+                check_route(&mut issues, &base, &model.provider, &model.upstream_id);
+            }
+        }
+        for (j, route) in model.routes.iter().enumerate() {
+            let path = format!("{}/routes/{}", base, j);
+            check_route(&mut issues, &path, &route.provider, &route.upstream_id);
+        }
+        if let Some(mirror) = &model.mirror {
+            if !upstream_exists(&mirror.upstream_id) {
+                issues.error(
+                    format!("{}/mirror/upstream_id", base),
+                    format!("upstream '{}' does not exist", mirror.upstream_id),
+                );
+            }
+            if !(0.0..=1.0).contains(&mirror.sample_rate) {
+                issues.error(
+                    format!("{}/mirror/sample_rate", base),
+                    "sample_rate must be between 0 and 1".to_string(),
+                );
+            }
+        }
+
+        let prices = [
+            ("price_prompt_per_1k", Some(model.price_prompt_per_1k)),
+            (
+                "price_completion_per_1k",
+                Some(model.price_completion_per_1k),
+            ),
+            ("price_cache_write_per_1k", model.price_cache_write_per_1k),
+            ("price_cache_read_per_1k", model.price_cache_read_per_1k),
+            ("price_reasoning_per_1k", model.price_reasoning_per_1k),
+        ];
+        for (field, value) in prices {
+            issues.price(format!("{}/{}", base, field), value);
+        }
+        for (j, tier) in model.price_tiers.iter().enumerate() {
+            let path = format!("{}/price_tiers/{}", base, j);
+            if tier.above_prompt_tokens < 0 {
+                issues.error(
+                    format!("{}/above_prompt_tokens", path),
+                    "threshold must not be negative".to_string(),
+                );
+            }
+            let prices = [
+                ("price_prompt_per_1k", tier.price_prompt_per_1k),
+                ("price_completion_per_1k", tier.price_completion_per_1k),
+                ("price_cache_write_per_1k", tier.price_cache_write_per_1k),
+                ("price_cache_read_per_1k", tier.price_cache_read_per_1k),
+                ("price_reasoning_per_1k", tier.price_reasoning_per_1k),
+            ];
+            for (field, value) in prices {
+                issues.price(format!("{}/{}", path, field), value);
+            }
+        }
+        if model.max_concurrency == Some(0) {
+            issues.error(
+                format!("{}/max_concurrency", base),
+                "max_concurrency must be at least 1".to_string(),
+            );
+        }
+        if let Some(guard) = &model.context_guard {
+            if guard.default_max_tokens >= guard.context_length {
+                issues.error(
+                    format!("{}/context_guard/context_length", base),
+                    format!(
+                        "context_length must be larger than default_max_tokens ({})",
+                        guard.default_max_tokens
+                    ),
+                );
+            }
+        }
+        safety_settings(&mut issues, &base, &model.safety_settings);
+        if model.priority > 100 {
+            issues.error(
+                format!("{}/priority", base),
+                format!("priority must be between 0 and 100, got {}", model.priority),
+            );
+        }
+    }
+
+    if let Some(attempts) = cfg.retry_max_attempts {
+        if !(1..=20).contains(&attempts) {
+            issues.error(
+                "/retry_max_attempts".to_string(),
+                format!("must be between 1 and 20, got {}", attempts),
+            );
+        }
+    }
+    if let (Some(initial), Some(max)) = (cfg.retry_initial_ms, cfg.retry_max_ms) {
+        if initial > max {
+            issues.error(
+                "/retry_initial_ms".to_string(),
+                format!(
+                    "initial backoff ({} ms) is larger than retry_max_ms ({} ms)",
+                    initial, max
+                ),
+            );
+        }
+    }
+
+    if let Some(proxy) = cfg.proxy.as_ref().filter(|p| p.enabled) {
+        match proxy.proxy_type.as_str() {
+            "system" | "none" => {}
+            "custom" => match proxy.url.as_deref().filter(|u| !u.trim().is_empty()) {
+                Some(url) => proxy_url(&mut issues, "/proxy/url".to_string(), url),
+                None => issues.error(
+                    "/proxy/url".to_string(),
+                    "custom proxy requires a URL".to_string(),
+                ),
+            },
+            other => issues.error(
+                "/proxy/type".to_string(),
+                format!(
+                    "unknown proxy type '{}', expected system, custom or none",
+                    other
+                ),
+            ),
+        }
+    }
+
+    let limits = &cfg.limits;
+    if !(1..=28).contains(&limits.budget_reset_day) {
+        issues.warning(
+            "/limits/budget_reset_day".to_string(),
+            format!(
+                "must be between 1 and 28, got {}; it will be clamped",
+                limits.budget_reset_day
+            ),
+        );
+    }
+    for (i, rule) in limits.budgets.iter().enumerate() {
+        let base = format!("/limits/budgets/{}", i);
+        if budget::BudgetScope::from_str(&rule.scope).is_none() {
+            issues.error(
+                format!("{}/scope", base),
+                format!("unknown budget scope '{}'", rule.scope),
+            );
+        }
+        if budget::BudgetPeriod::from_str(&rule.period).is_none() {
+            issues.error(
+                format!("{}/period", base),
+                format!("unknown budget period '{}'", rule.period),
+            );
+        }
+        match budget::BudgetAction::from_str(&rule.action) {
+            None => issues.error(
+                format!("{}/action", base),
+                format!("unknown budget action '{}'", rule.action),
+            ),
+            Some(budget::BudgetAction::Downgrade) => {
+                match rule.fallback_model.as_deref().filter(|m| !m.is_empty()) {
+                    None => issues.error(
+                        format!("{}/fallback_model", base),
+                        "downgrade requires a fallback_model".to_string(),
+                    ),
+                    Some(m) if !model_ids.contains(&m.to_lowercase()) => issues.error(
+                        format!("{}/fallback_model", base),
+                        format!("model '{}' does not exist", m),
+                    ),
+                    Some(_) => {}
+                }
+            }
+            Some(_) => {}
+        }
+        if !rule.limit_usd.is_finite() || rule.limit_usd < 0.0 {
+            issues.error(
+                format!("{}/limit_usd", base),
+                format!(
+                    "limit must be a non-negative number, got {}",
+                    rule.limit_usd
+                ),
+            );
+        }
+    }
+
+    if !matches!(
+        cfg.capture.mode.to_lowercase().as_str(),
+        "off" | "headers" | "full"
+    ) {
+        issues.error(
+            "/capture/mode".to_string(),
+            format!(
+                "unknown capture mode '{}', expected off, headers or full",
+                cfg.capture.mode
+            ),
+        );
+    }
+    match cfg.unknown_model.mode.to_lowercase().as_str() {
+        "reject" | "suggest" => {}
+        "default_to" => match cfg
+            .unknown_model
+            .default_to
+            .as_deref()
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+        {
+            Some(m) if !model_ids.contains(&m.to_lowercase()) => issues.error(
+                "/unknown_model/default_to".to_string(),
+                format!("unknown model '{}'", m),
+            ),
+            Some(_) => {}
+            None => issues.error(
+                "/unknown_model/default_to".to_string(),
+                "default_to mode requires a model id".to_string(),
+            ),
+        },
+        _ => issues.error(
+            "/unknown_model/mode".to_string(),
+            format!(
+                "unknown mode '{}', expected reject, default_to or suggest",
+                cfg.unknown_model.mode
+            ),
+        ),
+    }
+    let mut experiment_ids = std::collections::HashSet::new();
+    let mut experiment_models = std::collections::HashSet::new();
+    for (i, experiment) in cfg.experiments.iter().enumerate() {
+        let base = format!("/experiments/{}", i);
+        if experiment.id.trim().is_empty() {
+            issues.error(format!("{}/id", base), "experiment id is empty".to_string());
+        } else if !experiment_ids.insert(experiment.id.to_lowercase()) {
+            issues.error(
+                format!("{}/id", base),
+                format!("duplicate experiment id '{}'", experiment.id),
+            );
+        }
+        if experiment.model.trim().is_empty() {
+            issues.error(format!("{}/model", base), "model is empty".to_string());
+        } else if experiment.winner.is_none()
+            && !experiment_models.insert(experiment.model.to_lowercase())
+        {
+            issues.error(
+                format!("{}/model", base),
+                format!(
+                    "model '{}' is already split by another experiment",
+                    experiment.model
+                ),
+            );
+        }
+        for (field, m) in [
+            ("variant_a", &experiment.variant_a),
+            ("variant_b", &experiment.variant_b),
+        ] {
+            if !model_ids.contains(&m.to_lowercase()) {
+                issues.error(
+                    format!("{}/{}", base, field),
+                    format!("model '{}' does not exist", m),
+                );
+            }
+        }
+        if experiment.split_percent > 100 {
+            issues.error(
+                format!("{}/split_percent", base),
+                format!(
+                    "must be between 0 and 100, got {}",
+                    experiment.split_percent
+                ),
+            );
+        }
+        if let Some(winner) = &experiment.winner {
+            if experiment.variant_model(winner).is_none() {
+                issues.error(
+                    format!("{}/winner", base),
+                    format!("unknown variant '{}', expected a or b", winner),
+                );
+            }
+        }
+    }
+    for (i, rule) in cfg.redaction.rules.iter().enumerate() {
+        let path = format!("/redaction/rules/{}/pattern", i);
+        if rule.pattern.is_empty() {
+            issues.error(path, "pattern is empty".to_string());
+        } else if rule.regex {
+            if let Err(e) = regex::Regex::new(&rule.pattern) {
+                issues.error(path, format!("invalid regular expression: {}", e));
+            }
+        }
+    }
+    for (i, name) in cfg.prompt_redaction.builtin.iter().enumerate() {
+        if crate::forward::scrub::builtin(name).is_none() {
+            issues.error(
+                format!("/prompt_redaction/builtin/{}", i),
+                format!("unknown pattern '{}', expected email, phone or api_key", name),
+            );
+        }
+    }
+    for (i, rule) in cfg.prompt_redaction.rules.iter().enumerate() {
+        let base = format!("/prompt_redaction/rules/{}", i);
+        if rule.name.trim().is_empty() {
+            issues.error(format!("{}/name", base), "name is empty".to_string());
+        }
+        if rule.pattern.is_empty() {
+            issues.error(format!("{}/pattern", base), "pattern is empty".to_string());
+        } else if let Err(e) = regex::Regex::new(&rule.pattern) {
+            issues.error(
+                format!("{}/pattern", base),
+                format!("invalid regular expression: {}", e),
+            );
+        }
+    }
+    let mut mcp_ids = std::collections::HashSet::new();
+    for (i, server) in cfg.mcp.servers.iter().enumerate() {
+        let path = format!("/mcp/servers/{}", i);
+        if server.id.is_empty()
+            || !server
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            issues.error(
+                format!("{}/id", path),
+                "id must be letters, digits, '-' or '_'".to_string(),
+            );
+        } else if !mcp_ids.insert(server.id.as_str()) {
+            issues.error(
+                format!("{}/id", path),
+                format!("duplicate MCP server '{}'", server.id),
+            );
+        }
+        if !server.url.starts_with("http://") && !server.url.starts_with("https://") {
+            issues.error(
+                format!("{}/url", path),
+                "url must start with http:// or https://".to_string(),
+            );
+        }
+    }
+    for (key, domains) in [
+        ("web_fetch/allow_domains", &cfg.web_fetch.allow_domains),
+        ("web_fetch/deny_domains", &cfg.web_fetch.deny_domains),
+        ("image_fetch/allow_domains", &cfg.image_fetch.allow_domains),
+        ("image_fetch/deny_domains", &cfg.image_fetch.deny_domains),
+    ] {
+        for (i, domain) in domains.iter().enumerate() {
+            if domain.trim().is_empty() || domain.contains("://") || domain.contains('/') {
+                issues.error(
+                    format!("/{}/{}", key, i),
+                    "expected a domain such as example.com".to_string(),
+                );
+            }
+        }
+    }
+    let budgets = &cfg.reasoning_budgets;
+    for (key, budget) in [
+        ("minimal", budgets.minimal),
+        ("low", budgets.low),
+        ("medium", budgets.medium),
+        ("high", budgets.high),
+    ] {
+        if budget < 1024 {
+            issues.error(
+                format!("/reasoning_budgets/{}", key),
+                "thinking budgets must be at least 1024 tokens".to_string(),
+            );
+        }
+    }
+    let unix_socket = !cfg.server.unix_socket.trim().is_empty() && cfg!(unix);
+    if !cfg.server.unix_socket.trim().is_empty() && cfg!(not(unix)) {
+        issues.error(
+            "/server/unix_socket".to_string(),
+            "unix sockets are not supported on this platform".to_string(),
+        );
+    }
+    if !cfg.server.tcp && !unix_socket {
+        issues.error(
+            "/server/tcp".to_string(),
+            "with TCP disabled, set unix_socket to have something to listen on".to_string(),
+        );
+    }
+    let open_to_lan =
+        |ip: std::net::IpAddr| cfg.server.tcp && !ip.is_loopback() && cfg.forward_token.is_none();
+    match cfg.server.host.trim().parse::<std::net::IpAddr>() {
+        Ok(ip) if open_to_lan(ip) => issues.warning(
+            "/server/host".to_string(),
+            "the server is reachable from other machines without a forward token".to_string(),
+        ),
+        Ok(_) => {}
+        Err(_) => issues.error(
+            "/server/host".to_string(),
+            format!(
+                "'{}' is not an IP address, use 127.0.0.1 (this machine only) or 0.0.0.0 (LAN)",
+                cfg.server.host
+            ),
+        ),
+    }
+    let loopback_only = !cfg.server.tcp
+        || cfg
+            .server
+            .host
+            .trim()
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    if cfg.discovery.advertise && loopback_only {
+        issues.warning(
+            "/discovery/advertise".to_string(),
+            "not advertised while the server listens on this machine only".to_string(),
+        );
+    }
+    let tls = &cfg.server.tls;
+    if tls.enabled && tls.cert_path.trim().is_empty() && !tls.self_signed {
+        issues.error(
+            "/server/tls/cert_path".to_string(),
+            "set cert_path and key_path, or enable self_signed".to_string(),
+        );
+    }
+    if tls.enabled && !tls.cert_path.trim().is_empty() && tls.key_path.trim().is_empty() {
+        issues.error(
+            "/server/tls/key_path".to_string(),
+            "key_path is required with cert_path".to_string(),
+        );
+    }
+    if cfg.network.detect_offline {
+        for (i, target) in cfg.network.check_targets.iter().enumerate() {
+            let valid = target
+                .trim()
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid {
+                issues.error(
+                    format!("/network/check_targets/{}", i),
+                    format!("'{}' is not host:port", target),
+                );
+            }
+        }
+    }
+    if let Err(e) = crate::logger::LevelFilter::parse(&cfg.logging.levels) {
+        issues.error("/logging/levels".to_string(), e);
+    }
+    for (i, level) in cfg.logging.dedup_levels.iter().enumerate() {
+        let path = format!("/logging/dedup_levels/{}", i);
+        match crate::logger::LogLevel::from_str(level) {
+            Some(crate::logger::LogLevel::Debug) => {
+                issues.error(path, "debug entries are never deduplicated".to_string())
+            }
+            Some(_) => {}
+            None => issues.error(
+                path,
+                format!(
+                    "unknown log level '{}', expected info, warn or error",
+                    level
+                ),
+            ),
+        }
+    }
+    if !matches!(cfg.logging.format.to_lowercase().as_str(), "text" | "json") {
+        issues.error(
+            "/logging/format".to_string(),
+            format!(
+                "unknown log format '{}', expected text or json",
+                cfg.logging.format
+            ),
+        );
+    }
+    if cfg.logging.max_file_mb == 0 {
+        issues.error(
+            "/logging/max_file_mb".to_string(),
+            "must be at least 1".to_string(),
+        );
+    }
+    if cfg.maintenance.hour > 23 {
+        issues.error(
+            "/maintenance/hour".to_string(),
+            format!("must be between 0 and 23, got {}", cfg.maintenance.hour),
+        );
+    }
+    if cfg.price_sync.enabled {
+        issues.url(
+            "/price_sync/catalog_url".to_string(),
+            &cfg.price_sync.catalog_url,
+        );
+    }
+    for (i, hook) in cfg.webhooks.iter().enumerate() {
+        if hook.enabled {
+            issues.url(format!("/webhooks/{}/url", i), &hook.url);
+        }
+    }
+    if cfg.telemetry.enabled {
+        issues.url("/telemetry/endpoint".to_string(), &cfg.telemetry.endpoint);
+    }
+    if !cfg.discovery.remote_url.trim().is_empty() {
+        issues.url(
+            "/discovery/remote_url".to_string(),
+            &cfg.discovery.remote_url,
+        );
+    }
+
+    issues.0
 }
 
-impl ModelCfg {
-    pub fn resolved_routes(&self) -> Vec<ModelRoute> {
-        if !self.routes.is_empty() {
-            return self.routes.clone();
-        }
-        if self.provider.trim().is_empty() || self.upstream_id.trim().is_empty() {
-            return Vec::new();
-        }
-        vec![ModelRoute {
-            provider: self.provider.clone(),
-            upstream_id: self.upstream_id.clone(),
-            upstream_model_id: self.upstream_model_id.clone(),
-            priority: None,
-        }]
+/// Validate `cfg` and fail with a readable summary when it has errors.
+pub fn check(cfg: &Settings) -> Result<Vec<ValidationIssue>, String> {
+    let issues = validate(cfg);
+    let errors: Vec<String> = issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .map(|i| i.to_string())
+        .collect();
+    if errors.is_empty() {
+        Ok(issues)
+    } else {
+        Err(format!("Invalid configuration: {}", errors.join("; ")))
     }
 }
 
@@ -248,34 +2216,122 @@ fn gen_forward_token() -> String {
     format!("ccr_{token}")
 }
 
+/// File name of the settings file inside a profile directory.
+pub const SETTINGS_FILE: &str = "settings.toml";
+
 fn settings_path() -> PathBuf {
-    let mut p = data_dir().unwrap_or_else(|| PathBuf::from("."));
-    p.push("CCR");
-    fs::create_dir_all(&p).ok();
-    p.push("settings.toml");
-    p
+    crate::profile::dir().join(SETTINGS_FILE)
 }
 
-pub fn load() -> Settings {
-    let p = settings_path();
-    eprintln!("Loading config from: {:?}", p);
-    let mut cfg = if p.exists() {
-        let s = fs::read_to_string(&p).unwrap_or_default();
-        eprintln!("Config file size: {} bytes", s.len());
-        toml::from_str(&s).unwrap_or_else(|e| {
-            eprintln!("Failed to parse config: {}", e);
-            Settings::default()
-        })
-    } else {
-        eprintln!("Config file does not exist, using default");
-        Settings::default()
+/// The installed configuration and the version it was installed as.
+///
+/// Requests take an `Arc` of the snapshot when they start, so a reload never
+/// changes the settings seen by a request that is already in flight.
+#[derive(Clone)]
+struct Snapshot {
+    version: u64,
+    /// Settings with environment variables expanded
+    settings: Arc<Settings>,
+    /// Settings as written in the file
+    raw: Arc<Settings>,
+    /// Modification time of the file the snapshot was read from
+    modified: Option<SystemTime>,
+}
+
+/// Swapped whole on every save and reload; reading it never blocks.
+static SNAPSHOT: ArcSwapOption<Snapshot> = ArcSwapOption::const_empty();
+
+/// Outcome of a successful reload.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ReloadReport {
+    pub version: u64,
+    pub upstreams: usize,
+    pub models: usize,
+    /// Non-blocking issues found in the new configuration
+    pub warnings: Vec<ValidationIssue>,
+    /// Listener settings that differ from what the server is bound with
+    /// and only apply on `restart_server`, e.g. `server.port`
+    pub restart_required: Vec<String>,
+}
+
+fn file_modified(p: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(p).and_then(|m| m.modified()).ok()
+}
+
+fn install(raw: Settings, modified: Option<SystemTime>) -> u64 {
+    let (settings, missing) = expand(&raw);
+    crate::logger::configure(&settings.logging);
+    crate::redact::set_secrets(&settings);
+    crate::telemetry::configure(&settings.telemetry);
+    crate::access_log::configure(&settings.logging);
+    for issue in &missing {
+        crate::logger::warn("config", &issue.to_string());
+    }
+    let (settings, raw) = (Arc::new(settings), Arc::new(raw));
+    let previous = SNAPSHOT.rcu(|old| {
+        Some(Arc::new(Snapshot {
+            version: old.as_ref().map(|s| s.version + 1).unwrap_or(1),
+            settings: Arc::clone(&settings),
+            raw: Arc::clone(&raw),
+            modified,
+        }))
+    });
+    previous.map(|s| s.version + 1).unwrap_or(1)
+}
+
+/// Listener settings the running server is bound with; `None` while it
+/// isn't listening.
+static LISTENING: RwLock<Option<ServerConfig>> = RwLock::new(None);
+
+/// Record the listener settings the server is bound with, or that it
+/// stopped listening (see `server::serve`).
+pub fn set_listening(server: Option<&ServerConfig>) {
+    *LISTENING.write().unwrap_or_else(|e| e.into_inner()) = server.cloned();
+}
+
+/// Listener settings of `next` that differ from what the server is bound
+/// with, however many saves ago they changed.
+fn restart_required(next: &Settings) -> Vec<String> {
+    LISTENING
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|bound| bound.restart_required(&expand(next).0.server))
+        .unwrap_or_default()
+}
+
+/// Held by tests that install settings, which are process-wide.
+#[cfg(test)]
+static TEST_SETTINGS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Install `cfg` without reading or writing the settings file, so a test
+/// runs against known settings. Such tests run one at a time: the settings
+/// stay theirs until the returned guard is dropped.
+#[cfg(test)]
+pub(crate) fn install_for_tests(cfg: Settings) -> std::sync::MutexGuard<'static, ()> {
+    let guard = TEST_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    install(cfg, None);
+    guard
+}
+
+/// Put back the `${VAR}` templates of the installed configuration wherever
+/// `cfg` still holds their expanded values, so saving a loaded configuration
+/// never writes values taken from the environment to disk.
+fn restore_templates(cfg: &Settings) -> Settings {
+    let raw = match SNAPSHOT.load_full() {
+        Some(snapshot) => Arc::clone(&snapshot.raw),
+        None => return cfg.clone(),
     };
-    eprintln!(
-        "Loaded {} models, {} upstreams",
-        cfg.models.len(),
-        cfg.upstreams.len()
-    );
+    let (Ok(mut value), Ok(raw)) = (serde_json::to_value(cfg), serde_json::to_value(&*raw)) else {
+        return cfg.clone();
+    };
+    crate::interpolate::restore(&mut value, &raw);
+    serde_json::from_value(value).unwrap_or_else(|_| cfg.clone())
+}
 
+/// Fill in generated or defaulted values. Returns true when anything changed
+/// and the file should be rewritten.
+fn normalize(cfg: &mut Settings) -> bool {
     let mut changed = false;
     if cfg
         .forward_token
@@ -341,14 +2397,342 @@ pub fn load() -> Settings {
         changed = true;
     }
 
-    if changed {
-        let _ = save(&cfg); // Ignore errors during initial load
+    changed
+}
+
+/// Layout version of the settings file written by this build.
+///
+/// - 0: unversioned; upstreams have no `api_style`, retry settings live in
+///   a `[retry]` table
+/// - 1: per-upstream `api_style`
+/// - 2: retry settings as top-level `retry_*` keys
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// `MIGRATIONS[n]` upgrades a version `n` document to version `n + 1`.
+const MIGRATIONS: [fn(&mut toml::Table); SCHEMA_VERSION as usize] =
+    [migrate_v0_api_style, migrate_v1_retry];
+
+/// Give upstreams without an `api_style` the provider of the models routed
+/// to them, when those models agree on one.
+fn migrate_v0_api_style(doc: &mut toml::Table) {
+    let mut providers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let models = doc.get("models").and_then(|m| m.as_array()).cloned();
+    for model in models.iter().flatten().filter_map(|m| m.as_table()) {
+        let provider = model.get("provider").and_then(|p| p.as_str());
+        let upstream = model.get("upstream_id").and_then(|u| u.as_str());
+        if let (Some(provider), Some(upstream)) = (provider, upstream) {
+            providers
+                .entry(upstream.to_lowercase())
+                .or_default()
+                .push(provider.to_lowercase());
+        }
+    }
+    let styles = crate::forward::api_styles();
+    let upstreams = doc.get_mut("upstreams").and_then(|u| u.as_array_mut());
+    for upstream in upstreams.into_iter().flatten() {
+        let Some(upstream) = upstream.as_table_mut() else {
+            continue;
+        };
+        let has_style = upstream
+            .get("api_style")
+            .and_then(|s| s.as_str())
+            .is_some_and(|s| !s.is_empty());
+        let id = upstream.get("id").and_then(|i| i.as_str()).unwrap_or("");
+        if has_style {
+            continue;
+        }
+        let Some(found) = providers.get(&id.to_lowercase()) else {
+            continue;
+        };
+        if found.iter().all(|p| p == &found[0]) && styles.contains(&found[0].as_str()) {
+            upstream.insert("api_style".to_string(), found[0].clone().into());
+        }
+    }
+}
+
+/// Lift `[retry]` into the top-level `retry_*` keys.
+fn migrate_v1_retry(doc: &mut toml::Table) {
+    let Some(toml::Value::Table(retry)) = doc.remove("retry") else {
+        return;
+    };
+    for (old, new) in [
+        ("max_attempts", "retry_max_attempts"),
+        ("initial_ms", "retry_initial_ms"),
+        ("max_ms", "retry_max_ms"),
+        ("fallback", "enable_retry_fallback"),
+    ] {
+        if let Some(value) = retry.get(old) {
+            doc.entry(new).or_insert_with(|| value.clone());
+        }
     }
+}
 
-    cfg
+/// Upgrade `doc` to `SCHEMA_VERSION` in place. Returns the version the
+/// document was at.
+fn migrate(doc: &mut toml::Table) -> Result<u32, String> {
+    let from = match doc.get("schema_version") {
+        None => 0,
+        Some(toml::Value::Integer(v)) => {
+            u32::try_from(*v).map_err(|_| format!("Invalid schema_version {} in config file", v))?
+        }
+        Some(other) => return Err(format!("Invalid schema_version {} in config file", other)),
+    };
+    if from > SCHEMA_VERSION {
+        return Err(format!(
+            "Config file has schema version {}, but this version of the app supports up to {}. \
+             Update the app, or restore an older settings file.",
+            from, SCHEMA_VERSION
+        ));
+    }
+    for step in &MIGRATIONS[from as usize..] {
+        step(doc);
+    }
+    doc.insert(
+        "schema_version".to_string(),
+        toml::Value::Integer(SCHEMA_VERSION as i64),
+    );
+    Ok(from)
 }
 
-pub fn save(cfg: &Settings) -> Result<(), String> {
+/// Parse a settings file of any supported version. Returns the settings and
+/// the version the file was written with.
+fn parse_settings(s: &str) -> Result<(Settings, u32), String> {
+    let mut doc: toml::Table =
+        toml::from_str(s).map_err(|e| format!("Failed to parse config: {}", e))?;
+    let from = migrate(&mut doc)?;
+    let cfg = toml::Value::Table(doc)
+        .try_into()
+        .map_err(|e| format!("Failed to parse config: {}", e))?;
+    Ok((cfg, from))
+}
+
+/// Read and parse the settings file. A missing file yields the defaults; a
+/// file that fails to parse is an error. Older files are migrated and
+/// rewritten, keeping the original next to it as `settings.toml.v<N>.bak`.
+fn read_from_disk() -> Result<Settings, String> {
+    let p = settings_path();
+    eprintln!("Loading config from: {:?}", p);
+    let (mut cfg, migrated) = if p.exists() {
+        let s = fs::read_to_string(&p)
+            .map_err(|e| format!("Failed to read config file {:?}: {}", p, e))?;
+        eprintln!("Config file size: {} bytes", s.len());
+        let (cfg, from) = parse_settings(&s)?;
+        let migrated = from < SCHEMA_VERSION;
+        if migrated {
+            let backup = p.with_file_name(format!("{}.v{}.bak", SETTINGS_FILE, from));
+            fs::write(&backup, &s)
+                .map_err(|e| format!("Failed to back up config to {:?}: {}", backup, e))?;
+            eprintln!(
+                "Migrated config from schema version {} to {} (backup: {:?})",
+                from, SCHEMA_VERSION, backup
+            );
+        }
+        (cfg, migrated)
+    } else {
+        eprintln!("Config file does not exist, using default");
+        (Settings::default(), false)
+    };
+    eprintln!(
+        "Loaded {} models, {} upstreams",
+        cfg.models.len(),
+        cfg.upstreams.len()
+    );
+
+    if normalize(&mut cfg) || migrated {
+        let _ = write_file(&cfg); // Ignore errors during initial load
+    }
+    Ok(cfg)
+}
+
+/// The current configuration snapshot, read from disk on first use.
+pub fn current() -> Arc<Settings> {
+    if let Some(snapshot) = SNAPSHOT.load_full() {
+        return Arc::clone(&snapshot.settings);
+    }
+    let cfg = read_from_disk().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Settings::default()
+    });
+    install(cfg, file_modified(&settings_path()));
+    current()
+}
+
+/// Number of the current configuration snapshot, bumped on every save and
+/// reload.
+pub fn version() -> u64 {
+    current();
+    SNAPSHOT.load_full().map(|s| s.version).unwrap_or_default()
+}
+
+/// An owned copy of the current configuration, with environment variables
+/// expanded.
+pub fn load() -> Settings {
+    (*current()).clone()
+}
+
+/// The current configuration as written in the file, with `${VAR}`
+/// references left in place. This is what the UI edits and exports.
+pub fn load_raw() -> Settings {
+    current();
+    SNAPSHOT
+        .load_full()
+        .map(|s| (*s.raw).clone())
+        .unwrap_or_default()
+}
+
+/// Re-read the settings file and swap it in. On failure the previous
+/// configuration stays active and the error is returned.
+pub fn reload() -> Result<ReloadReport, String> {
+    let cfg = read_from_disk()?;
+    // After reading, which may rewrite a file it migrated or normalized
+    let modified = file_modified(&settings_path());
+    reload_from(cfg, modified)
+}
+
+fn reload_from(cfg: Settings, modified: Option<SystemTime>) -> Result<ReloadReport, String> {
+    let warnings = check(&cfg)?;
+    let (upstreams, models) = (cfg.upstreams.len(), cfg.models.len());
+    let restart_required = restart_required(&cfg);
+    let version = install(cfg, modified);
+    Ok(ReloadReport {
+        version,
+        upstreams,
+        models,
+        warnings,
+        restart_required,
+    })
+}
+
+/// Reload the settings file if it changed on disk since the snapshot was
+/// read from it. A file that fails to load is reported once, not again
+/// until it changes.
+fn reload_if_changed() -> Option<Result<ReloadReport, String>> {
+    let known = SNAPSHOT.load_full()?.modified;
+    if known == file_modified(&settings_path()) {
+        return None;
+    }
+    let result = reload();
+    if result.is_err() {
+        // Read again: loading may have rewritten the file
+        let on_disk = file_modified(&settings_path());
+        SNAPSHOT.rcu(|old| {
+            old.as_ref().map(|s| {
+                Arc::new(Snapshot {
+                    modified: on_disk,
+                    ..(**s).clone()
+                })
+            })
+        });
+    }
+    Some(result)
+}
+
+/// A watcher sending on `tx` whenever a settings file changes in the
+/// directories it watches, or `None` when it can't be started.
+fn settings_watcher(tx: mpsc::Sender<()>) -> Option<notify::RecommendedWatcher> {
+    let on_event = move |event: notify::Result<notify::Event>| {
+        let touches_settings = event.is_ok_and(|event| {
+            event
+                .paths
+                .iter()
+                .any(|p| p.file_name().is_some_and(|name| name == SETTINGS_FILE))
+        });
+        if touches_settings {
+            let _ = tx.send(());
+        }
+    };
+    notify::recommended_watcher(on_event)
+        .map_err(|e| {
+            crate::logger::warn(
+                "config",
+                &format!("Cannot watch the settings file, polling it instead: {}", e),
+            )
+        })
+        .ok()
+}
+
+/// Watch the settings file and reload it when it changes on disk, and run
+/// a planned forward token rotation once it is due.
+///
+/// The directory holding the file is watched rather than the file, so
+/// editors that replace the file instead of writing in place are seen too.
+/// The modification time decides whether it changed, and is also checked
+/// every few seconds in case an event was missed or the watcher could not
+/// start.
+pub fn watch() {
+    const TICK: Duration = Duration::from_secs(2);
+    // Editors save in several steps; read once they are done
+    const SETTLE: Duration = Duration::from_millis(200);
+    let spawned = std::thread::Builder::new()
+        .name("ccr-config-watch".to_string())
+        .spawn(|| {
+            // `tx` lives as long as the loop, so `rx` stays open even
+            // without a watcher
+            let (tx, rx) = mpsc::channel();
+            let mut watcher = settings_watcher(tx.clone());
+            let mut watched: Option<PathBuf> = None;
+            loop {
+                // Switching profiles moves the file to another directory
+                let dir = settings_path().parent().map(PathBuf::from);
+                if dir != watched {
+                    if let Some(watcher) = watcher.as_mut() {
+                        if let Some(old) = &watched {
+                            let _ = watcher.unwatch(old);
+                        }
+                        if let Some(dir) = &dir {
+                            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                                crate::logger::warn(
+                                    "config",
+                                    &format!("Cannot watch {}: {}", dir.display(), e),
+                                );
+                            }
+                        }
+                    }
+                    watched = dir;
+                }
+
+                if rx.recv_timeout(TICK).is_ok() {
+                    std::thread::sleep(SETTLE);
+                    while rx.try_recv().is_ok() {}
+                }
+                rotate_forward_token_if_due();
+                match reload_if_changed() {
+                    None => {}
+                    Some(Ok(report)) => {
+                        crate::logger::info(
+                            "config",
+                            &format!("Configuration reloaded (version {})", report.version),
+                        );
+                        if !report.restart_required.is_empty() {
+                            crate::logger::warn(
+                                "config",
+                                &format!(
+                                    "{} changed; restart the server to apply",
+                                    report.restart_required.join(", ")
+                                ),
+                            );
+                        }
+                    }
+                    Some(Err(e)) => crate::logger::warn(
+                        "config",
+                        &format!("Keeping previous configuration: {}", e),
+                    ),
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        crate::logger::error("config", &format!("Failed to start config watcher: {}", e));
+    }
+}
+
+/// The settings file contents for `cfg`, stamped with `SCHEMA_VERSION`.
+fn to_toml(cfg: &Settings) -> Result<String, String> {
+    let body =
+        toml::to_string_pretty(cfg).map_err(|e| format!("Failed to serialize config: {}", e))?;
+    Ok(format!("schema_version = {}\n{}", SCHEMA_VERSION, body))
+}
+
+fn write_file(cfg: &Settings) -> Result<(), String> {
     let p = settings_path();
     eprintln!("Saving config to: {:?}", p);
     eprintln!(
@@ -363,29 +2747,253 @@ pub fn save(cfg: &Settings) -> Result<(), String> {
             .map_err(|e| format!("Failed to create config directory: {}", e))?;
     }
 
-    let s =
-        toml::to_string_pretty(cfg).map_err(|e| format!("Failed to serialize config: {}", e))?;
+    let s = to_toml(cfg)?;
     eprintln!("Config serialized to {} bytes", s.len());
     fs::write(&p, &s).map_err(|e| format!("Failed to write config file to {:?}: {}", p, e))?;
     eprintln!("Config saved successfully");
     Ok(())
 }
 
+/// Validate and write the settings file, then make it the current snapshot.
+/// `cfg` may come from either `load` or `load_raw`.
+pub fn save(cfg: &Settings) -> Result<(), String> {
+    let raw = restore_templates(cfg);
+    check(&raw)?;
+    for key in restart_required(&raw) {
+        crate::logger::warn(
+            "config",
+            &format!("{} changed; restart the server to apply", key),
+        );
+    }
+    write_file(&raw)?;
+    install(raw, file_modified(&settings_path()));
+    Ok(())
+}
+
+/// A change that was not saved. `issues` carries the validation problems
+/// behind it, so the UI can show them next to the offending fields.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct SaveError {
+    pub message: String,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl From<String> for SaveError {
+    fn from(message: String) -> Self {
+        SaveError {
+            message,
+            issues: Vec::new(),
+        }
+    }
+}
+
+/// `save`, keeping the validation issues structured. Returns the warnings
+/// of the saved configuration, including one per listener setting that
+/// only applies on `restart_server`.
+pub fn save_validated(cfg: &Settings) -> Result<Vec<ValidationIssue>, SaveError> {
+    let raw = restore_templates(cfg);
+    let mut issues = validate(&raw);
+    if issues.iter().any(|i| i.severity == Severity::Error) {
+        return Err(SaveError {
+            message: "Invalid configuration".to_string(),
+            issues,
+        });
+    }
+    issues.extend(restart_required(&raw).into_iter().map(restart_warning));
+    write_file(&raw)?;
+    install(raw, file_modified(&settings_path()));
+    Ok(issues)
+}
+
+/// Warning that the listener setting `key` applies once the server restarts.
+fn restart_warning(key: String) -> ValidationIssue {
+    ValidationIssue {
+        path: format!("/{}", key.replace('.', "/")),
+        message: "Saved; takes effect when the server is restarted".to_string(),
+        severity: Severity::Warning,
+    }
+}
+
+/// Add `upstream`, or replace the one with id `original_id` (its own id if
+/// not given). A rename carries over to the models routed to it.
+pub fn save_upstream(
+    upstream: Upstream,
+    original_id: Option<&str>,
+) -> Result<Vec<ValidationIssue>, SaveError> {
+    let mut cfg = load_raw();
+    upsert_upstream(&mut cfg, upstream, original_id);
+    save_validated(&cfg)
+}
+
+fn upsert_upstream(cfg: &mut Settings, upstream: Upstream, original_id: Option<&str>) {
+    let id = original_id.unwrap_or(&upstream.id).to_string();
+    if id != upstream.id {
+        for model in &mut cfg.models {
+            if model.upstream_id == id {
+                model.upstream_id = upstream.id.clone();
+            }
+            for route in &mut model.routes {
+                if route.upstream_id == id {
+                    route.upstream_id = upstream.id.clone();
+                }
+            }
+        }
+    }
+    match cfg.upstreams.iter_mut().find(|up| up.id == id) {
+        Some(existing) => *existing = upstream,
+        None => cfg.upstreams.push(upstream),
+    }
+}
+
+/// Remove an upstream. Refused while models are still routed to it.
+pub fn delete_upstream(id: &str) -> Result<Vec<ValidationIssue>, SaveError> {
+    let mut cfg = load_raw();
+    let before = cfg.upstreams.len();
+    cfg.upstreams.retain(|up| up.id != id);
+    if cfg.upstreams.len() == before {
+        return Err(format!("Upstream '{}' not found", id).into());
+    }
+    save_validated(&cfg)
+}
+
+/// Add `model`, or replace the one with id `original_id` (its own id if
+/// not given).
+pub fn save_model(
+    model: ModelCfg,
+    original_id: Option<&str>,
+) -> Result<Vec<ValidationIssue>, SaveError> {
+    let mut cfg = load_raw();
+    let id = original_id.unwrap_or(&model.id).to_string();
+    match cfg.models.iter_mut().find(|m| m.id == id) {
+        Some(existing) => *existing = model,
+        None => cfg.models.push(model),
+    }
+    save_validated(&cfg)
+}
+
+/// End experiment `id`: every request for its model goes to `winner`
+/// ("a" or "b") from now on. The experiment stays configured so its report
+/// can still be read.
+pub fn end_experiment(id: &str, winner: &str) -> Result<Vec<ValidationIssue>, SaveError> {
+    let mut cfg = load_raw();
+    set_winner(&mut cfg, id, winner)?;
+    save_validated(&cfg)
+}
+
+fn set_winner(cfg: &mut Settings, id: &str, winner: &str) -> Result<(), String> {
+    let experiment = cfg
+        .experiments
+        .iter_mut()
+        .find(|e| e.id.eq_ignore_ascii_case(id))
+        .ok_or_else(|| format!("Experiment '{}' not found", id))?;
+    if experiment.variant_model(winner).is_none() {
+        return Err(format!("Unknown variant '{}', expected a or b", winner));
+    }
+    experiment.winner = Some(winner.trim().to_ascii_lowercase());
+    Ok(())
+}
+
+pub fn delete_model(id: &str) -> Result<Vec<ValidationIssue>, SaveError> {
+    let mut cfg = load_raw();
+    let before = cfg.models.len();
+    cfg.models.retain(|m| m.id != id);
+    if cfg.models.len() == before {
+        return Err(format!("Model '{}' not found", id).into());
+    }
+    save_validated(&cfg)
+}
+
+/// Save everything but upstreams and models, which have their own calls;
+/// an editor holding an older copy can't undo their changes this way.
+pub fn save_settings(settings: Settings) -> Result<Vec<ValidationIssue>, SaveError> {
+    let current = load_raw();
+    let cfg = Settings {
+        upstreams: current.upstreams,
+        models: current.models,
+        ..settings
+    };
+    save_validated(&cfg)
+}
+
 pub fn reset() -> Result<(), String> {
     let p = settings_path();
     if p.exists() {
         fs::remove_file(&p)
             .map_err(|e| format!("Failed to remove config file {:?}: {}", p, e))?;
     }
+    // Next access starts over from the defaults
+    unload();
     Ok(())
 }
 
-/// Force refresh of the forward token and persist the new value.
-pub fn refresh_forward_token() -> String {
+/// Drop the current snapshot so the next access reads the settings file
+/// again (used when the active profile changes).
+pub fn unload() {
+    SNAPSHOT.store(None);
+}
+
+/// Replace the forward token in `cfg`. The old one stays valid for the
+/// grace period; a planned rotation is done with.
+fn rotate_forward_token(cfg: &mut Settings, now: i64) -> TokenRotation {
+    let rotation = &mut cfg.token_rotation;
+    let grace = rotation.grace_secs as i64;
+    rotation.previous_token = cfg
+        .forward_token
+        .take()
+        .filter(|token| !token.is_empty() && grace > 0);
+    rotation.previous_expires_at = rotation.previous_token.as_ref().map(|_| now + grace);
+    rotation.version += 1;
+    rotation.scheduled_at = None;
+    let token = gen_forward_token();
+    cfg.forward_token = Some(token.clone());
+    TokenRotation {
+        token,
+        version: rotation.version,
+        previous_expires_at: rotation.previous_expires_at,
+    }
+}
+
+/// Force refresh of the forward token and persist the new value. The
+/// previous token is accepted for `token_rotation.grace_secs` more.
+pub fn refresh_forward_token() -> TokenRotation {
     let mut cfg = load();
-    cfg.forward_token = Some(gen_forward_token());
+    let rotation = rotate_forward_token(&mut cfg, chrono::Utc::now().timestamp());
     let _ = save(&cfg); // Ignore errors for this operation
-    cfg.forward_token.clone().unwrap_or_default()
+    rotation
+}
+
+/// Plan a forward token rotation for unix time `at`; `None` cancels it.
+pub fn schedule_forward_token_rotation(at: Option<i64>) -> Result<(), String> {
+    let mut cfg = load();
+    cfg.token_rotation.scheduled_at = at;
+    save(&cfg)
+}
+
+/// Run the planned forward token rotation if its time has come.
+fn rotate_forward_token_if_due() {
+    let now = chrono::Utc::now().timestamp();
+    if !current()
+        .token_rotation
+        .scheduled_at
+        .is_some_and(|at| at <= now)
+    {
+        return;
+    }
+    let mut cfg = load();
+    let rotation = rotate_forward_token(&mut cfg, now);
+    match save(&cfg) {
+        Ok(()) => crate::logger::info(
+            "auth",
+            &format!(
+                "Forward token rotated as planned (version {})",
+                rotation.version
+            ),
+        ),
+        Err(e) => crate::logger::warn(
+            "auth",
+            &format!("Planned forward token rotation failed: {}", e),
+        ),
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -498,3 +3106,372 @@ pub fn protect(data: &[u8]) -> Vec<u8> {
 pub fn unprotect(data: &[u8]) -> Vec<u8> {
     data.to_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str, upstream_id: &str) -> ModelCfg {
+        ModelCfg {
+            id: id.to_string(),
+            provider: "openai".to_string(),
+            upstream_id: upstream_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_upstream_rename_moves_models() {
+        let mut cfg = Settings::default();
+        let upstream = |id: &str| Upstream {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        upsert_upstream(&mut cfg, upstream("old"), None);
+        let mut routed = model("gpt", "old");
+        routed.routes.push(ModelRoute {
+            upstream_id: "old".to_string(),
+            ..Default::default()
+        });
+        cfg.models.push(routed);
+        cfg.models.push(model("other", "elsewhere"));
+
+        upsert_upstream(&mut cfg, upstream("new"), Some("old"));
+        assert_eq!(cfg.upstreams.len(), 1);
+        assert_eq!(cfg.upstreams[0].id, "new");
+        assert_eq!(cfg.models[0].upstream_id, "new");
+        assert_eq!(cfg.models[0].routes[0].upstream_id, "new");
+        assert_eq!(cfg.models[1].upstream_id, "elsewhere");
+
+        upsert_upstream(&mut cfg, upstream("second"), None);
+        assert_eq!(cfg.upstreams.len(), 2);
+    }
+
+    #[test]
+    fn test_validate_default_is_clean() {
+        assert!(validate(&Settings::default()).is_empty());
+    }
+
+    #[test]
+    fn test_validate_reports_paths() {
+        let mut cfg = Settings::default();
+        cfg.upstreams.push(Upstream {
+            id: "main".to_string(),
+            endpoints: vec![
+                "https://api.example.com".to_string(),
+                "api.example".to_string(),
+            ],
+            api_style: Some("opneai".to_string()),
+            ..Default::default()
+        });
+        cfg.models.push(model("gpt", "main"));
+        let mut dup = model("GPT", "missing");
+        dup.price_prompt_per_1k = -1.0;
+        dup.mirror = Some(MirrorConfig {
+            upstream_id: "shadow".to_string(),
+            model: None,
+            sample_rate: 1.5,
+        });
+        cfg.models.push(dup);
+        cfg.retry_max_attempts = Some(0);
+        cfg.upstreams[0]
+            .extra_headers
+            .insert("bad header".to_string(), "x".to_string());
+        cfg.upstreams[0].chat_path = Some("v4/chat/completions".to_string());
+
+        let issues = validate(&cfg);
+        let paths: Vec<&str> = issues.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/upstreams/0/endpoints/1",
+                "/upstreams/0/extra_headers/bad header",
+                "/upstreams/0/chat_path",
+                "/upstreams/0/api_style",
+                "/models/1/id",
+                "/models/1/upstream_id",
+                "/models/1/mirror/upstream_id",
+                "/models/1/mirror/sample_rate",
+                "/models/1/price_prompt_per_1k",
+                "/retry_max_attempts",
+            ]
+        );
+        assert!(issues.iter().all(|i| i.severity == Severity::Error));
+        assert!(check(&cfg).unwrap_err().contains("/models/1/upstream_id"));
+    }
+
+    #[test]
+    fn test_mock_upstreams_need_no_endpoints() {
+        let mut cfg = Settings::default();
+        cfg.upstreams.push(Upstream {
+            id: "offline".to_string(),
+            api_style: Some("mock".to_string()),
+            ..Default::default()
+        });
+        cfg.models.push(model("gpt", "offline"));
+        let errors = |cfg: &Settings| -> Vec<String> {
+            validate(cfg)
+                .into_iter()
+                .filter(|i| i.severity == Severity::Error)
+                .map(|i| i.path)
+                .collect()
+        };
+        assert!(errors(&cfg).is_empty());
+
+        let mock = &mut cfg.upstreams[0].mock;
+        mock.response = "haiku".to_string();
+        mock.tool_arguments = "[1]".to_string();
+        mock.fail_statuses = vec![429, 200];
+        assert_eq!(
+            errors(&cfg),
+            vec![
+                "/upstreams/0/mock/response",
+                "/upstreams/0/mock/tool_arguments",
+                "/upstreams/0/mock/fail_statuses/1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_experiments_are_validated_and_ended() {
+        let mut cfg = Settings::default();
+        cfg.upstreams.push(Upstream {
+            id: "main".to_string(),
+            endpoints: vec!["https://api.example.com".to_string()],
+            ..Default::default()
+        });
+        cfg.models.push(model("gpt-4o", "main"));
+        cfg.models.push(model("gpt-4o-mini", "main"));
+        let experiment = ExperimentConfig {
+            id: "mini".to_string(),
+            model: "gpt".to_string(),
+            variant_a: "gpt-4o".to_string(),
+            variant_b: "gpt-4o-mini".to_string(),
+            split_percent: 20,
+            winner: None,
+        };
+        cfg.experiments.push(experiment.clone());
+        let errors = |cfg: &Settings| -> Vec<String> {
+            validate(cfg)
+                .into_iter()
+                .filter(|i| i.severity == Severity::Error)
+                .map(|i| i.path)
+                .collect()
+        };
+        assert!(errors(&cfg).is_empty());
+
+        cfg.experiments.push(ExperimentConfig {
+            id: "MINI".to_string(),
+            variant_b: "gpt-5".to_string(),
+            split_percent: 120,
+            ..experiment
+        });
+        assert_eq!(
+            errors(&cfg),
+            vec![
+                "/experiments/1/id",
+                "/experiments/1/model",
+                "/experiments/1/variant_b",
+                "/experiments/1/split_percent",
+            ]
+        );
+        cfg.experiments.pop();
+
+        assert!(set_winner(&mut cfg, "mini", "c").is_err());
+        assert!(set_winner(&mut cfg, "other", "a").is_err());
+        set_winner(&mut cfg, "Mini", "B").unwrap();
+        assert_eq!(cfg.experiments[0].winner.as_deref(), Some("b"));
+        assert!(errors(&cfg).is_empty());
+    }
+
+    #[test]
+    fn test_rotated_token_keeps_working_for_the_grace_period() {
+        let mut cfg = Settings {
+            forward_token: Some("ccr_old_0123456789abcdef".to_string()),
+            ..Default::default()
+        };
+        cfg.token_rotation.scheduled_at = Some(900);
+        let rotation = rotate_forward_token(&mut cfg, 1_000);
+        assert_eq!(rotation.version, 2);
+        assert_eq!(rotation.previous_expires_at, Some(4_600));
+        assert_eq!(cfg.token_rotation.scheduled_at, None);
+        assert_eq!(
+            cfg.forward_token_version(&rotation.token, 1_000),
+            Some(TokenVersion {
+                version: 2,
+                deprecated: false
+            })
+        );
+        let old = "ccr_old_0123456789abcdef";
+        assert_eq!(
+            cfg.forward_token_version(old, 4_599),
+            Some(TokenVersion {
+                version: 1,
+                deprecated: true
+            })
+        );
+        assert_eq!(cfg.forward_token_version(old, 4_600), None);
+
+        cfg.token_rotation.grace_secs = 0;
+        let rotation = rotate_forward_token(&mut cfg, 5_000);
+        assert_eq!(rotation.previous_expires_at, None);
+        assert_eq!(cfg.token_rotation.previous_token, None);
+        assert_eq!(cfg.forward_token_version("", 5_000), None);
+    }
+
+    const FIXTURE_V0: &str = include_str!("../tests/fixtures/settings/v0.toml");
+    const FIXTURE_V1: &str = include_str!("../tests/fixtures/settings/v1.toml");
+    const FIXTURE_V2: &str = include_str!("../tests/fixtures/settings/v2.toml");
+
+    #[test]
+    fn test_migrate_fixtures() {
+        for (fixture, version) in [(FIXTURE_V0, 0), (FIXTURE_V1, 1), (FIXTURE_V2, 2)] {
+            let (cfg, from) = parse_settings(fixture).unwrap();
+            assert_eq!(from, version);
+            assert_eq!(cfg.upstreams[0].api_style.as_deref(), Some("openai"));
+            assert_eq!(cfg.upstreams[1].api_style.as_deref(), Some("anthropic"));
+            assert_eq!(cfg.retry_max_attempts, Some(5));
+            assert_eq!(cfg.retry_initial_ms, Some(250));
+            assert_eq!(cfg.retry_max_ms, Some(4000));
+            assert_eq!(cfg.models.len(), 2);
+        }
+
+        // An upstream shared by models of different providers is left alone
+        let (cfg, _) = parse_settings(
+            r#"
+            [[upstreams]]
+            id = "mixed"
+            endpoints = ["https://relay.example.com"]
+            [[models]]
+            id = "a"
+            provider = "openai"
+            upstream_id = "mixed"
+            [[models]]
+            id = "b"
+            provider = "gemini"
+            upstream_id = "mixed"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.upstreams[0].api_style, None);
+    }
+
+    #[test]
+    fn test_newer_schema_rejected() {
+        let newer = format!("schema_version = {}\n", SCHEMA_VERSION + 1);
+        let err = parse_settings(&newer).err().unwrap();
+        assert!(err.contains("supports up to"));
+    }
+
+    #[test]
+    fn test_written_file_is_current() {
+        let s = to_toml(&Settings::default()).unwrap();
+        assert_eq!(parse_settings(&s).unwrap().1, SCHEMA_VERSION);
+    }
+
+    /// Write the settings file, dated `secs` seconds ahead so every write
+    /// is seen as a change.
+    fn write_settings(contents: &str, secs: u64) {
+        let path = settings_path();
+        fs::write(&path, contents).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_edits_reload_only_when_valid() {
+        let _settings = install_for_tests(Settings::default());
+        crate::profile::use_temp_dir_for_tests("config-reload");
+        assert!(reload_if_changed().is_none());
+
+        let valid = "forward_token = \"relay-reload-test-token\"\n\n\
+            [[upstreams]]\nid = \"a\"\nendpoints = [\"https://a.example/v1\"]\n";
+        write_settings(valid, 1);
+        let before = version();
+        let report = reload_if_changed().unwrap().unwrap();
+        assert_eq!(report.upstreams, 1);
+        assert!(report.version > before);
+        assert_eq!(current().upstreams[0].id, "a");
+        assert!(reload_if_changed().is_none());
+
+        // Two upstreams named alike fail validation
+        let duplicate = format!(
+            "{}\n[[upstreams]]\nid = \"a\"\nendpoints = [\"https://b.example/v1\"]\n",
+            valid
+        );
+        write_settings(&duplicate, 2);
+        let err = reload_if_changed().unwrap().unwrap_err();
+        assert!(err.contains("duplicate upstream id 'a'"), "{}", err);
+        assert_eq!(current().upstreams.len(), 1);
+        assert_eq!(version(), report.version);
+        // Reported once, not on every check
+        assert!(reload_if_changed().is_none());
+
+        write_settings("upstreams = [", 3);
+        assert!(reload_if_changed().unwrap().is_err());
+        assert_eq!(current().upstreams[0].endpoints, ["https://a.example/v1"]);
+        assert_eq!(version(), report.version);
+    }
+
+    #[test]
+    fn test_desktop_settings() {
+        let (cfg, _) = parse_settings("").unwrap();
+        assert!(!cfg.desktop.autostart && !cfg.desktop.background);
+        let (cfg, _) = parse_settings("[desktop]\nautostart = true\n").unwrap();
+        assert!(cfg.desktop.autostart);
+        assert!(!cfg.desktop.background);
+
+        let (saved, _) = parse_settings(&to_toml(&cfg).unwrap()).unwrap();
+        assert!(saved.desktop.autostart && !saved.desktop.background);
+    }
+
+    #[test]
+    fn test_validate_tls_overrides() {
+        let mut cfg = Settings::default();
+        cfg.upstreams.push(Upstream {
+            id: "lab".to_string(),
+            endpoints: vec!["https://10.0.0.5".to_string()],
+            ca_cert_path: Some("/nonexistent/ccr-ca.pem".to_string()),
+            insecure_skip_verify: true,
+            ..Default::default()
+        });
+        let issues = validate(&cfg);
+        let found: Vec<(&str, Severity)> = issues
+            .iter()
+            .map(|i| (i.path.as_str(), i.severity))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("/upstreams/0/ca_cert_path", Severity::Error),
+                ("/upstreams/0/insecure_skip_verify", Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reload_reports_listener_changes() {
+        let _settings = install_for_tests(Settings::default());
+        set_listening(Some(&ServerConfig::default()));
+        let mut cfg = Settings::default();
+        cfg.server.port = 9797;
+        cfg.server.request_timeout_secs = 30;
+        let report = reload_from(cfg.clone(), None).unwrap();
+        // Timeouts apply on the next request, the port on restart
+        assert_eq!(report.restart_required, vec!["server.port".to_string()]);
+        assert_eq!(current().server.port, 9797);
+
+        // Still bound to the old port until the server restarts
+        let report = reload_from(cfg.clone(), None).unwrap();
+        assert_eq!(report.restart_required, vec!["server.port".to_string()]);
+
+        set_listening(Some(&cfg.server));
+        let report = reload_from(cfg, None).unwrap();
+        assert!(report.restart_required.is_empty());
+        set_listening(None);
+    }
+}
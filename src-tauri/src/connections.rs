@@ -0,0 +1,237 @@
+//! Caps on concurrent requests, overall and per client IP.
+//!
+//! [`layer`] wraps the whole router. Every request holds a slot until its
+//! response body is finished, so an event stream counts for as long as it is
+//! open. Past `server.max_connections`, or `server.max_connections_per_ip`
+//! for one address, new requests get a 503 with `retry-after` instead of
+//! another file descriptor and upstream call. The caps are read from the
+//! live configuration on every request, so changing them needs no restart.
+//!
+//! Requests over the unix socket have no address and only count towards the
+//! overall cap. The health endpoints are never refused, so monitoring still
+//! sees a saturated relay.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config;
+
+/// Suggested wait before retrying a refused request.
+const RETRY_AFTER_SECS: u64 = 1;
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+static COUNTS: Lazy<Mutex<Counts>> = Lazy::new(|| Mutex::new(Counts::default()));
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// `server.max_connections` and `server.max_connections_per_ip`, 0 as `None`.
+#[derive(Debug, Clone, Copy)]
+struct Caps {
+    total: Option<usize>,
+    per_ip: Option<usize>,
+}
+
+impl Caps {
+    fn from_config(server: &config::ServerConfig) -> Self {
+        Self {
+            total: (server.max_connections > 0).then_some(server.max_connections),
+            per_ip: (server.max_connections_per_ip > 0).then_some(server.max_connections_per_ip),
+        }
+    }
+}
+
+/// Which cap refused a request.
+#[derive(Debug, PartialEq)]
+enum Refused {
+    Total(usize),
+    PerIp(IpAddr, usize),
+}
+
+/// A held slot, released on drop.
+struct Slot {
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        COUNTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .release(self.ip);
+    }
+}
+
+impl Counts {
+    fn admit(&mut self, caps: Caps, ip: Option<IpAddr>) -> Result<(), Refused> {
+        if let Some(max) = caps.total {
+            if self.total >= max {
+                return Err(Refused::Total(max));
+            }
+        }
+        if let (Some(max), Some(ip)) = (caps.per_ip, ip) {
+            if self.by_ip.get(&ip).copied().unwrap_or(0) >= max {
+                return Err(Refused::PerIp(ip, max));
+            }
+        }
+        self.total += 1;
+        if let Some(ip) = ip {
+            *self.by_ip.entry(ip).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, ip: Option<IpAddr>) {
+        self.total = self.total.saturating_sub(1);
+        if let Some(ip) = ip {
+            if let Some(count) = self.by_ip.get_mut(&ip) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+fn acquire(caps: Caps, ip: Option<IpAddr>) -> Result<Slot, Refused> {
+    COUNTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .admit(caps, ip)?;
+    Ok(Slot { ip })
+}
+
+/// Router layer enforcing the caps.
+pub async fn layer(req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    if path == "/health" || path.starts_with("/api/health") {
+        return next.run(req).await;
+    }
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let caps = Caps::from_config(&config::current().server);
+    match acquire(caps, ip) {
+        Ok(slot) => hold(slot, next.run(req).await),
+        Err(refused) => refuse(refused),
+    }
+}
+
+/// Keep `slot` until the response body has been sent.
+fn hold(slot: Slot, response: Response) -> Response {
+    let (parts, body) = response.into_parts();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let _ = &slot;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+fn refuse(refused: Refused) -> Response {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    let message = match refused {
+        Refused::Total(max) => format!("Too many concurrent requests (limit {})", max),
+        Refused::PerIp(ip, max) => {
+            format!("Too many concurrent requests from {} (limit {})", ip, max)
+        }
+    };
+    crate::logger::warn("server", &message);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        Json(serde_json::json!({
+            "error": { "type": "overloaded", "message": message }
+        })),
+    )
+        .into_response()
+}
+
+/// Requests held by one client address.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientCount {
+    pub ip: String,
+    pub active: usize,
+}
+
+/// Current counts, for the detailed health report.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub active: usize,
+    /// Busiest clients first
+    pub by_ip: Vec<ClientCount>,
+    /// Requests refused since the server started
+    pub rejected: u64,
+}
+
+pub fn snapshot() -> Snapshot {
+    let counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut by_ip: Vec<ClientCount> = counts
+        .by_ip
+        .iter()
+        .map(|(ip, active)| ClientCount {
+            ip: ip.to_string(),
+            active: *active,
+        })
+        .collect();
+    by_ip.sort_by(|a, b| b.active.cmp(&a.active).then_with(|| a.ip.cmp(&b.ip)));
+    Snapshot {
+        active: counts.total,
+        by_ip,
+        rejected: REJECTED.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps() {
+        let a: IpAddr = "203.0.113.7".parse().unwrap();
+        let b: IpAddr = "203.0.113.8".parse().unwrap();
+        let caps = Caps {
+            total: Some(4),
+            per_ip: Some(2),
+        };
+        let mut counts = Counts::default();
+
+        counts.admit(caps, Some(a)).unwrap();
+        counts.admit(caps, Some(a)).unwrap();
+        assert_eq!(counts.admit(caps, Some(a)), Err(Refused::PerIp(a, 2)));
+        counts.admit(caps, Some(b)).unwrap();
+        // Unix socket requests have no address
+        counts.admit(caps, None).unwrap();
+        assert_eq!(counts.admit(caps, Some(b)), Err(Refused::Total(4)));
+
+        counts.release(Some(a));
+        counts.admit(caps, Some(a)).unwrap();
+        counts.release(Some(b));
+        assert!(!counts.by_ip.contains_key(&b));
+        assert_eq!(counts.total, 3);
+    }
+
+    #[test]
+    fn test_refused_with_retry_after() {
+        let response = refuse(Refused::Total(1));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        assert!(snapshot().rejected >= 1);
+    }
+}
@@ -1,7 +1,10 @@
 use chrono::Datelike;
-use dirs::data_dir;
+use once_cell::sync::Lazy;
 use rusqlite::{params, Connection};
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, serde::Serialize, Clone)]
 pub struct ChannelStats {
@@ -16,18 +19,94 @@ pub struct ModelStats {
     pub requests: i64,
     pub tokens: i64,
     pub price_usd: f64,
+    pub cache_creation_tokens: i64,
+    pub cache_read_tokens: i64,
+    pub reasoning_tokens: i64,
 }
 
+/// Cache and reasoning token totals over a time window.
+#[derive(Debug, serde::Serialize, Clone, Default)]
+pub struct UsageDetails {
+    pub cache_creation_tokens: i64,
+    pub cache_read_tokens: i64,
+    pub reasoning_tokens: i64,
+    pub unpriced_requests: i64,
+    /// Requests whose token counts were partly estimated locally
+    pub estimated_requests: i64,
+    /// Tokens recorded on those requests
+    pub estimated_tokens: i64,
+    pub total_tokens: i64,
+}
+
+/// One completed request, as written to `usage_logs`.
+///
+/// `price_usd` and `price` are `None` for models without configured prices,
+/// which are stored as NULL so unpriced traffic is distinguishable from free.
+#[derive(Debug, Clone, Default)]
+pub struct UsageRecord {
+    pub channel: String,
+    pub tool: String,
+    pub model: String,
+    pub upstream_id: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub cache_creation_tokens: i64,
+    pub cache_read_tokens: i64,
+    pub reasoning_tokens: i64,
+    pub price_usd: Option<f64>,
+    pub price: Option<crate::pricing::PriceSnapshot>,
+    pub status: u16,
+    pub latency_ms: Option<i64>,
+    pub client_token: String,
+    pub project: Option<String>,
+    /// Id of the configured project `project` names, if it is one
+    pub project_id: Option<i64>,
+    /// JSON object with free-form request context (session, user agent)
+    pub metadata: Option<String>,
+    /// Label or fingerprint of the upstream API key used
+    pub api_key_id: Option<String>,
+    /// Some token counts are local estimates (provider reported no usage)
+    pub estimated: bool,
+    /// Correlation id of the request, also returned as `x-relay-request-id`
+    pub request_id: Option<String>,
+    /// Sent by the relay itself (autoconfig verification): kept in the log
+    /// but left out of cost, budget and summary totals
+    pub synthetic: bool,
+    /// Answered from the response cache, at no cost
+    pub cached: bool,
+    /// The request failed for good (stored as `success = 0`): kept for
+    /// reliability reports, left out of cost totals
+    pub failed: bool,
+    /// Upstream endpoint the failure came from
+    pub endpoint: Option<String>,
+    /// `ForwardError::class` of the failure
+    pub error_class: Option<String>,
+    /// Upstream attempts made, when known
+    pub attempts: Option<i64>,
+    /// Experiment the request took part in, and the variant it got
+    pub experiment: Option<String>,
+    pub variant: Option<String>,
+    /// JSON array of the client's tags (`x-relay-tags`)
+    pub tags: Option<String>,
+    /// Matches redacted from the prompt before it was sent
+    pub prompt_redactions: i64,
+    /// Request id of the captured request this one replays
+    pub replay_of: Option<String>,
+}
+
+/// Usage database of the active profile.
 fn db_path() -> PathBuf {
-    let mut p = data_dir().unwrap_or_else(|| PathBuf::from("."));
-    p.push("CCR");
-    std::fs::create_dir_all(&p).ok();
-    p.push("ccr.db");
-    p
+    crate::profile::dir().join("ccr.db")
 }
 
+/// How long a connection waits on a locked database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn open_conn() -> Connection {
-    Connection::open(db_path()).unwrap()
+    let conn = Connection::open(db_path()).unwrap();
+    conn.busy_timeout(BUSY_TIMEOUT).ok();
+    conn
 }
 
 fn optimize_connection(conn: &Connection) {
@@ -41,30 +120,156 @@ fn optimize_connection(conn: &Connection) {
 pub fn init() {
     let conn = open_conn();
     optimize_connection(&conn);
+    init_schema(&conn);
+}
+
+/// Open the database, query it and take the write lock without changing
+/// anything, returning how long that took.
+pub fn probe() -> Result<Duration, String> {
+    let started = Instant::now();
+    let conn = Connection::open(db_path()).map_err(|e| e.to_string())?;
+    conn.busy_timeout(BUSY_TIMEOUT).ok();
+    conn.query_row("select count(*) from sqlite_master", [], |r| {
+        r.get::<_, i64>(0)
+    })
+    .map_err(|e| e.to_string())?;
+    conn.execute_batch("begin immediate; rollback;")
+        .map_err(|e| format!("Database is not writable: {}", e))?;
+    Ok(started.elapsed())
+}
+
+fn init_schema(conn: &Connection) {
     conn.execute("create table if not exists usage_logs (id integer primary key autoincrement, timestamp integer, channel text, tool text, model text, prompt_tokens integer, completion_tokens integer, total_tokens integer, price_usd real, upstream_id text)", []).unwrap();
-    conn.execute("create table if not exists projects (id integer primary key autoincrement, name text, path text, description text, tags text, created_at integer)", []).unwrap();
     conn.execute("create table if not exists tools (id integer primary key autoincrement, name text, version text, installed integer, config_path text)", []).unwrap();
     conn.execute("create table if not exists models (id text primary key, display_name text, provider text, upstream_id text, price_prompt_per_1k real, price_completion_per_1k real)", []).unwrap();
     conn.execute("create table if not exists usage_daily (bucket text primary key, requests integer, tokens integer, price_usd real)", []).ok();
     conn.execute("create table if not exists usage_weekly (bucket text primary key, requests integer, tokens integer, price_usd real)", []).ok();
     conn.execute("create table if not exists usage_monthly (bucket text primary key, requests integer, tokens integer, price_usd real)", []).ok();
 
+    conn.execute("create table if not exists usage_rollup_daily (day text not null, model text not null, upstream_id text not null, requests integer not null default 0, errors integer not null default 0, prompt_tokens integer not null default 0, completion_tokens integer not null default 0, total_tokens integer not null default 0, cache_creation_tokens integer not null default 0, cache_read_tokens integer not null default 0, reasoning_tokens integer not null default 0, price_usd real not null default 0, primary key (day, model, upstream_id))", []).ok();
+    conn.execute("create table if not exists request_log (id integer primary key autoincrement, timestamp integer not null, model text, upstream_id text, url text, streaming integer not null default 0, status integer, latency_ms integer, request_headers text, request_body text, response_body text, error text, truncated integer not null default 0)", []).ok();
+
+    conn.execute("create table if not exists response_cache (key text primary key, model text, body text not null, bytes integer not null, created_at integer not null, used_at integer not null, hits integer not null default 0)", []).ok();
+
+    conn.execute("create table if not exists access_log (id integer primary key autoincrement, timestamp integer not null, method text not null, path text not null, status integer not null, duration_ms integer not null, bytes integer not null, model text, upstream_id text, client_token text, request_id text)", []).ok();
+
+    conn.execute("create table if not exists mirror_log (id integer primary key autoincrement, timestamp integer not null, request_id text, model text, upstream_id text, client_token text, project text, primary_upstream text, primary_status integer, primary_latency_ms integer, primary_prompt_tokens integer, primary_completion_tokens integer, status integer, latency_ms integer, prompt_tokens integer, completion_tokens integer, price_usd real, error text)", []).ok();
+
+    migrate_usage_logs(conn);
+    init_projects(conn);
+    ensure_column(conn, "request_log", "request_id", "text");
+    ensure_column(conn, "request_log", "redacted", "integer not null default 0");
+    ensure_column(conn, "access_log", "token_version", "integer");
+
     conn.execute("create index if not exists idx_usage_logs_timestamp on usage_logs(timestamp desc)", []).ok();
     conn.execute("create index if not exists idx_usage_logs_channel_timestamp on usage_logs(channel, timestamp desc)", []).ok();
     conn.execute("create index if not exists idx_usage_logs_model_timestamp on usage_logs(model, timestamp desc)", []).ok();
     conn.execute("create index if not exists idx_usage_logs_summary on usage_logs(date(timestamp, 'unixepoch'), total_tokens, price_usd)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_upstream_timestamp on usage_logs(upstream_id, timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_client_token_timestamp on usage_logs(client_token, timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_project_timestamp on usage_logs(project, timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_api_key_timestamp on usage_logs(upstream_id, api_key_id, timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_request_log_timestamp on request_log(timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_request_id on usage_logs(request_id)", []).ok();
+    conn.execute("create index if not exists idx_usage_logs_experiment on usage_logs(experiment, variant) where experiment is not null", []).ok();
+    conn.execute("create index if not exists idx_request_log_request_id on request_log(request_id)", []).ok();
+    conn.execute("create index if not exists idx_access_log_timestamp on access_log(timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_mirror_log_timestamp on mirror_log(timestamp desc)", []).ok();
+    conn.execute("create index if not exists idx_response_cache_used_at on response_cache(used_at desc)", []).ok();
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+    let sql = format!("pragma table_info({table})");
+    let Ok(mut stmt) = conn.prepare(&sql) else {
+        return false;
+    };
+    let names = stmt.query_map([], |r| r.get::<_, String>(1));
+    match names {
+        Ok(rows) => rows.filter_map(|x| x.ok()).any(|name| name == column),
+        Err(_) => false,
+    }
+}
+
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) {
+    if !has_column(conn, table, column) {
+        let sql = format!("alter table {table} add column {column} {decl}");
+        conn.execute(&sql, []).ok();
+    }
+}
+
+/// Add columns introduced after the original usage_logs schema.
+fn migrate_usage_logs(conn: &Connection) {
+    ensure_column(conn, "usage_logs", "cache_creation_tokens", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "cache_read_tokens", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "reasoning_tokens", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "price_prompt_per_1k", "real");
+    ensure_column(conn, "usage_logs", "price_completion_per_1k", "real");
+    ensure_column(conn, "usage_logs", "status", "integer not null default 200");
+    ensure_column(conn, "usage_logs", "latency_ms", "integer");
+    ensure_column(conn, "usage_logs", "client_token", "text");
+    ensure_column(conn, "usage_logs", "project", "text");
+    ensure_column(conn, "usage_logs", "metadata", "text");
+    ensure_column(conn, "usage_logs", "api_key_id", "text");
+    ensure_column(conn, "usage_logs", "estimated", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "request_id", "text");
+    ensure_column(conn, "usage_logs", "synthetic", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "project_id", "integer");
+    ensure_column(conn, "usage_logs", "redactions", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "prompt_redactions", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "cached", "integer not null default 0");
+    ensure_column(conn, "usage_logs", "success", "integer not null default 1");
+    ensure_column(conn, "usage_logs", "endpoint", "text");
+    ensure_column(conn, "usage_logs", "error_class", "text");
+    ensure_column(conn, "usage_logs", "attempts", "integer");
+    ensure_column(conn, "usage_logs", "experiment", "text");
+    ensure_column(conn, "usage_logs", "variant", "text");
+    ensure_column(conn, "usage_logs", "tags", "text");
+    ensure_column(conn, "usage_logs", "replay_of", "text");
+}
+
+/// Connection to the database holding `projects`. Projects are shared by
+/// all profiles, so they live in the data directory's own database, which
+/// `init` only prepares for the default profile; the table is created there
+/// on first use.
+pub(crate) fn open_projects_conn() -> Connection {
+    static READY: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+    let path = crate::profile::base_dir().join("ccr.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.busy_timeout(BUSY_TIMEOUT).ok();
+    let mut ready = READY.lock().unwrap_or_else(|e| e.into_inner());
+    if ready.insert(path) {
+        init_projects(&conn);
+    }
+    conn
+}
+
+fn init_projects(conn: &Connection) {
+    conn.execute("create table if not exists projects (id integer primary key autoincrement, name text, path text, description text, tags text, created_at integer)", []).ok();
+    migrate_projects(conn);
+}
+
+/// Add the routing columns of `projects` (overrides and lists as JSON),
+/// its budget and the deletion time of tombstoned projects.
+fn migrate_projects(conn: &Connection) {
+    ensure_column(conn, "projects", "token", "text");
+    ensure_column(conn, "projects", "model_overrides", "text");
+    ensure_column(conn, "projects", "system_prefix", "text");
+    ensure_column(conn, "projects", "system_prefix_mode", "text");
+    ensure_column(conn, "projects", "user_patterns", "text");
+    ensure_column(conn, "projects", "budget_usd", "real");
+    ensure_column(conn, "projects", "deleted_at", "integer");
 }
 
 pub fn summary_daily() -> (i64, i64, f64) {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where date(timestamp,'unixepoch')=date('now')").unwrap();
+    let mut stmt = conn.prepare_cached("select count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 and date(timestamp,'unixepoch')=date('now')").unwrap();
     stmt.query_row([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
         .unwrap()
 }
 
 pub fn summary_since(days: i64) -> (i64, i64, f64) {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where timestamp>= strftime('%s','now','-'||?1||' day')").unwrap();
+    let mut stmt = conn.prepare_cached("select count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 and timestamp>= strftime('%s','now','-'||?1||' day')").unwrap();
     stmt.query_row(params![days], |row| {
         Ok((row.get(0)?, row.get(1)?, row.get(2)?))
     })
@@ -79,21 +284,74 @@ pub fn summary_for_range(range: &str) -> (i64, i64, f64) {
     }
 }
 
-pub fn log_usage(
-    channel: &str,
-    tool: &str,
-    model: &str,
-    prompt_tokens: i64,
-    completion_tokens: i64,
-    total_tokens: i64,
-    price_usd: f64,
-    upstream_id: &str,
-) {
+/// Unix start of the `summary_for_range` window.
+pub fn range_start(range: &str) -> i64 {
+    let now = chrono::Utc::now();
+    match range {
+        "weekly" => (now - chrono::Duration::days(7)).timestamp(),
+        "monthly" => (now - chrono::Duration::days(30)).timestamp(),
+        _ => now
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .map(|t| t.and_utc().timestamp())
+            .unwrap_or_else(|| now.timestamp()),
+    }
+}
+
+/// SQL condition selecting the `summary_for_range` window.
+fn range_filter(range: &str) -> &'static str {
+    match range {
+        "weekly" => "timestamp>= strftime('%s','now','-7 day')",
+        "monthly" => "timestamp>= strftime('%s','now','-30 day')",
+        _ => "date(timestamp,'unixepoch')=date('now')",
+    }
+}
+
+/// Cache/reasoning token totals and the unpriced request count for the same
+/// windows as `summary_for_range`.
+pub fn token_details_for_range(range: &str) -> UsageDetails {
+    let filter = range_filter(range);
     let conn = open_conn();
-    let ts = chrono::Utc::now();
+    token_details_with(&conn, filter)
+}
+
+fn token_details_with(conn: &Connection, filter: &str) -> UsageDetails {
+    let sql = format!("select ifnull(sum(cache_creation_tokens),0), ifnull(sum(cache_read_tokens),0), ifnull(sum(reasoning_tokens),0), count(*) - count(price_usd), ifnull(sum(estimated),0), ifnull(sum(case when estimated=1 then total_tokens else 0 end),0), ifnull(sum(total_tokens),0) from usage_logs where synthetic=0 and success=1 and {filter}");
+    conn.query_row(&sql, [], |row| {
+        Ok(UsageDetails {
+            cache_creation_tokens: row.get(0)?,
+            cache_read_tokens: row.get(1)?,
+            reasoning_tokens: row.get(2)?,
+            unpriced_requests: row.get(3)?,
+            estimated_requests: row.get(4)?,
+            estimated_tokens: row.get(5)?,
+            total_tokens: row.get(6)?,
+        })
+    })
+    .unwrap_or_default()
+}
+
+/// Queue a usage row for the background writer.
+pub fn log_usage(record: &UsageRecord) {
+    enqueue(WriteOp::Usage(Box::new(record.clone()), chrono::Utc::now()));
+}
+
+/// Add `count` redacted matches to the usage row of `request_id`. Queued
+/// behind the row itself, so it lands after it.
+pub fn add_redactions(request_id: &str, count: i64) {
+    enqueue(WriteOp::Redactions(request_id.to_string(), count));
+}
+
+fn insert_usage(
+    conn: &Connection,
+    record: &UsageRecord,
+    ts: chrono::DateTime<chrono::Utc>,
+) -> rusqlite::Result<()> {
     let unix_ts = ts.timestamp();
-    conn.execute("insert into usage_logs(timestamp,channel,tool,model,prompt_tokens,completion_tokens,total_tokens,price_usd,upstream_id) values(?,?,?,?,?,?,?,?,?)",
-        params![unix_ts, channel, tool, model, prompt_tokens, completion_tokens, total_tokens, price_usd, upstream_id]).unwrap();
+    let price_prompt = record.price.map(|p| p.prompt_per_1k);
+    let price_completion = record.price.map(|p| p.completion_per_1k);
+    conn.execute("insert into usage_logs(timestamp,channel,tool,model,prompt_tokens,completion_tokens,total_tokens,price_usd,upstream_id,cache_creation_tokens,cache_read_tokens,reasoning_tokens,price_prompt_per_1k,price_completion_per_1k,status,latency_ms,client_token,project,metadata,api_key_id,estimated,request_id,synthetic,project_id,cached,success,endpoint,error_class,attempts,experiment,variant,tags,prompt_redactions,replay_of) values(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)",
+        params![unix_ts, record.channel, record.tool, record.model, record.prompt_tokens, record.completion_tokens, record.total_tokens, record.price_usd, record.upstream_id, record.cache_creation_tokens, record.cache_read_tokens, record.reasoning_tokens, price_prompt, price_completion, record.status, record.latency_ms, record.client_token, record.project, record.metadata, record.api_key_id, record.estimated, record.request_id, record.synthetic, record.project_id, record.cached, !record.failed, record.endpoint, record.error_class, record.attempts, record.experiment, record.variant, record.tags, record.prompt_redactions, record.replay_of])?;
     fn bucket_day(ts: &chrono::DateTime<chrono::Utc>) -> String {
         ts.format("%Y-%m-%d").to_string()
     }
@@ -109,32 +367,635 @@ pub fn log_usage(
             on conflict(bucket) do update set requests=requests+1, tokens=tokens+excluded.tokens, price_usd=price_usd+excluded.price_usd");
         let _ = conn.execute(&sql, params![bucket, tokens, price]);
     }
+    if record.synthetic || record.failed {
+        return Ok(());
+    }
+    let price_usd = record.price_usd.unwrap_or(0.0);
     upsert(
-        &conn,
+        conn,
         "usage_daily",
         &bucket_day(&ts),
-        total_tokens,
+        record.total_tokens,
         price_usd,
     );
     upsert(
-        &conn,
+        conn,
         "usage_weekly",
         &bucket_week(&ts),
-        total_tokens,
+        record.total_tokens,
         price_usd,
     );
     upsert(
-        &conn,
+        conn,
         "usage_monthly",
         &bucket_month(&ts),
-        total_tokens,
+        record.total_tokens,
         price_usd,
     );
+    Ok(())
+}
+
+// ============================================
+// Background writer
+// ============================================
+
+/// Rows are committed once this many are queued...
+const WRITE_BATCH_MAX: usize = 256;
+/// ...or this long after the first one arrived, whichever comes first.
+const WRITE_BATCH_INTERVAL: Duration = Duration::from_millis(200);
+/// Upper bound on how long `flush_writes` waits for the writer.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum WriteOp {
+    Usage(Box<UsageRecord>, chrono::DateTime<chrono::Utc>),
+    Capture(Box<CapturedRequest>),
+    Access(AccessRecord),
+    /// Matches redacted from the response to a request already logged
+    Redactions(String, i64),
+    /// An answer for the response cache, stored within `max_bytes`
+    CacheStore(CachedResponse),
+    /// A cached answer was reused
+    CacheHit(String),
+    /// A mirrored request next to the one it copied
+    Mirror(MirrorRecord),
+    Flush(mpsc::Sender<()>),
+    /// Write what is queued, then switch to the active profile's database
+    Reopen,
+}
+
+/// Usage and capture rows are written by one dedicated thread that batches
+/// them into transactions, so request handlers never block on SQLite.
+static WRITER: Lazy<mpsc::Sender<WriteOp>> = Lazy::new(|| {
+    start_writer(|| {
+        let conn = open_conn();
+        optimize_connection(&conn);
+        conn
+    })
+});
+
+fn start_writer<F>(open: F) -> mpsc::Sender<WriteOp>
+where
+    F: FnOnce() -> Connection + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("ccr-db-writer".to_string())
+        .spawn(move || writer_loop(open(), rx))
+        .expect("failed to spawn database writer");
+    tx
+}
+
+fn writer_loop(mut conn: Connection, rx: mpsc::Receiver<WriteOp>) {
+    let mut batch = Vec::new();
+    let mut waiters = Vec::new();
+    let mut disconnected = false;
+    let mut reopen = false;
+
+    while !disconnected {
+        match rx.recv() {
+            Ok(WriteOp::Flush(ack)) => waiters.push(ack),
+            Ok(WriteOp::Reopen) => reopen = true,
+            Ok(op) => batch.push(op),
+            Err(_) => break,
+        }
+
+        let deadline = Instant::now() + WRITE_BATCH_INTERVAL;
+        while waiters.is_empty() && !reopen && batch.len() < WRITE_BATCH_MAX {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(WriteOp::Flush(ack)) => waiters.push(ack),
+                Ok(WriteOp::Reopen) => reopen = true,
+                Ok(op) => batch.push(op),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        write_batch(&conn, &mut batch);
+        for ack in waiters.drain(..) {
+            let _ = ack.send(());
+        }
+        if reopen {
+            conn = open_conn();
+            optimize_connection(&conn);
+            reopen = false;
+        }
+    }
+    write_batch(&conn, &mut batch);
+}
+
+fn write_op(conn: &Connection, op: &WriteOp) -> Result<(), String> {
+    match op {
+        WriteOp::Usage(record, ts) => insert_usage(conn, record, *ts).map_err(|e| e.to_string()),
+        WriteOp::Capture(entry) => insert_captured_request_with(conn, entry).map(|_| ()),
+        WriteOp::Access(record) => insert_access(conn, record),
+        WriteOp::Redactions(request_id, count) => conn
+            .execute(
+                "update usage_logs set redactions=redactions+? where request_id=?",
+                params![count, request_id],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        WriteOp::CacheStore(entry) => store_cached_response_with(conn, entry),
+        WriteOp::CacheHit(key) => conn
+            .execute(
+                "update response_cache set used_at=?, hits=hits+1 where key=?",
+                params![chrono::Utc::now().timestamp(), key],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        WriteOp::Mirror(record) => insert_mirror(conn, record),
+        WriteOp::Flush(_) | WriteOp::Reopen => Ok(()),
+    }
+}
+
+fn write_batch(conn: &Connection, batch: &mut Vec<WriteOp>) {
+    if batch.is_empty() {
+        return;
+    }
+    let result = conn.unchecked_transaction().map_err(|e| e.to_string()).and_then(|tx| {
+        for op in batch.iter() {
+            write_op(&tx, op)?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        // Retry row by row so one bad row doesn't drop the whole batch.
+        crate::logger::warn("db", &format!("Batch write failed ({}), retrying {} rows individually", e, batch.len()));
+        for op in batch.iter() {
+            if let Err(e) = write_op(conn, op) {
+                crate::logger::error("db", &format!("Dropping row after write failure: {}", e));
+            }
+        }
+    }
+    batch.clear();
+}
+
+fn enqueue(op: WriteOp) {
+    if let Err(mpsc::SendError(op)) = WRITER.send(op) {
+        // Writer thread is gone; fall back to a synchronous write.
+        if let Err(e) = write_op(&open_conn(), &op) {
+            crate::logger::error("db", &format!("Synchronous write failed: {}", e));
+        }
+    }
+}
+
+fn flush_sender(sender: &mpsc::Sender<WriteOp>) -> bool {
+    let (ack_tx, ack_rx) = mpsc::channel();
+    if sender.send(WriteOp::Flush(ack_tx)).is_err() {
+        return false;
+    }
+    ack_rx.recv_timeout(FLUSH_TIMEOUT).is_ok()
+}
+
+/// Point the writer thread at the active profile's database. Rows queued
+/// before this call still go to the previous one.
+pub fn reopen_writer() {
+    let _ = WRITER.send(WriteOp::Reopen);
+}
+
+/// Block until every queued write has been committed (used on shutdown).
+pub fn flush_writes() {
+    if !flush_sender(&WRITER) {
+        crate::logger::warn("db", "Timed out flushing pending database writes");
+    }
+}
+
+/// Filters and grouping for `usage_summary`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct UsageSummaryQuery {
+    /// Inclusive start, unix seconds (defaults to 7 days ago)
+    pub from: Option<i64>,
+    /// Exclusive end, unix seconds (defaults to now)
+    pub to: Option<i64>,
+    /// `model`, `upstream`, `token`, `project`, `project_id`, `key`
+    /// (upstream API key) or `tag`. By tag, a request counts once for each
+    /// of its tags and untagged requests are left out
+    pub group_by: Option<String>,
+    /// Only requests carrying this tag
+    pub tag: Option<String>,
+    /// `hour` or `day`
+    pub bucket: Option<String>,
+    /// Maximum number of rows returned (defaults to 1000)
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize, Clone, PartialEq)]
+pub struct UsageSummaryRow {
+    pub bucket: Option<String>,
+    pub group: Option<String>,
+    pub requests: i64,
+    /// Failed requests and error statuses passed on to the client
+    pub errors: i64,
+    /// `errors` over `requests`
+    pub error_rate: f64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub cache_creation_tokens: i64,
+    pub cache_read_tokens: i64,
+    pub reasoning_tokens: i64,
+    /// `None` when every request in the group was unpriced
+    pub price_usd: Option<f64>,
+    pub avg_latency_ms: Option<f64>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct UsageSummary {
+    pub from: i64,
+    pub to: i64,
+    pub group_by: Option<String>,
+    pub bucket: Option<String>,
+    pub rows: Vec<UsageSummaryRow>,
+    /// True when more rows matched than `limit`
+    pub truncated: bool,
+}
+
+const SUMMARY_DEFAULT_LIMIT: i64 = 1000;
+const SUMMARY_MAX_LIMIT: i64 = 10_000;
+
+pub fn usage_summary(query: &UsageSummaryQuery) -> Result<UsageSummary, String> {
+    let conn = open_conn();
+    usage_summary_with(&conn, query)
+}
+
+/// Map a public group name onto its `usage_logs` column.
+fn group_column(group_by: &str) -> Option<&'static str> {
+    match group_by {
+        "model" => Some("model"),
+        "upstream" => Some("upstream_id"),
+        "token" => Some("client_token"),
+        "project" => Some("project"),
+        "project_id" => Some("cast(project_id as text)"),
+        "key" => Some("api_key_id"),
+        _ => None,
+    }
+}
+
+/// Total cost since `since` (unix seconds), optionally restricted to rows
+/// whose group column (as in `group_by`) equals a value.
+pub fn spent_since(since: i64, filter: Option<(&str, &str)>) -> f64 {
+    let conn = open_conn();
+    spent_since_with(&conn, since, filter).unwrap_or(0.0)
+}
+
+fn spent_since_with(
+    conn: &Connection,
+    since: i64,
+    filter: Option<(&str, &str)>,
+) -> Result<f64, String> {
+    Ok(usage_spent_since(conn, since, filter)? + mirror_spent_since(conn, since, filter)?)
+}
+
+fn usage_spent_since(
+    conn: &Connection,
+    since: i64,
+    filter: Option<(&str, &str)>,
+) -> Result<f64, String> {
+    match filter {
+        None => conn.query_row(
+            "select ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 and timestamp>=?1",
+            params![since],
+            |row| row.get(0),
+        ),
+        Some((group, value)) => {
+            let col = group_column(group).ok_or_else(|| format!("Unsupported group: {}", group))?;
+            let sql = format!(
+                "select ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 and timestamp>=?1 and {col} = ?2 collate nocase"
+            );
+            conn.query_row(&sql, params![since, value], |row| row.get(0))
+        }
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn usage_summary_with(conn: &Connection, query: &UsageSummaryQuery) -> Result<UsageSummary, String> {
+    let mut source = "usage_logs";
+    let group_col = match query.group_by.as_deref() {
+        None | Some("") => None,
+        Some("tag") => {
+            source = "usage_logs join json_each(usage_logs.tags) as tag";
+            Some("tag.value")
+        }
+        Some(other) => Some(
+            group_column(other).ok_or_else(|| format!("Unsupported group_by: {}", other))?,
+        ),
+    };
+    let bucket_expr = match query.bucket.as_deref() {
+        None | Some("") => None,
+        Some("hour") => Some("strftime('%Y-%m-%dT%H:00:00Z', timestamp, 'unixepoch')"),
+        Some("day") => Some("date(timestamp, 'unixepoch')"),
+        Some(other) => return Err(format!("Unsupported bucket: {}", other)),
+    };
+
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 7 * 86_400);
+    if from > to {
+        return Err("`from` must not be after `to`".to_string());
+    }
+    let limit = query
+        .limit
+        .unwrap_or(SUMMARY_DEFAULT_LIMIT)
+        .clamp(1, SUMMARY_MAX_LIMIT);
+
+    let mut keys = Vec::new();
+    if let Some(expr) = bucket_expr {
+        keys.push(expr.to_string());
+    }
+    if let Some(col) = group_col {
+        keys.push(col.to_string());
+    }
+    let bucket_select = bucket_expr.unwrap_or("null");
+    let group_select = group_col.unwrap_or("null");
+    let group_clause = if keys.is_empty() {
+        String::new()
+    } else {
+        format!("group by {} order by {}", keys.join(", "), keys.join(", "))
+    };
+    let sql = format!(
+        "select {bucket_select}, {group_select}, count(*), \
+         ifnull(sum(case when status >= 400 or success = 0 then 1 else 0 end),0), \
+         ifnull(sum(prompt_tokens),0), ifnull(sum(completion_tokens),0), ifnull(sum(total_tokens),0), \
+         ifnull(sum(cache_creation_tokens),0), ifnull(sum(cache_read_tokens),0), ifnull(sum(reasoning_tokens),0), \
+         sum(price_usd), avg(latency_ms) \
+         from {source} where synthetic = 0 and timestamp >= ?1 and timestamp < ?2 and {tag_filter} \
+         {group_clause} limit ?3",
+        tag_filter = tag_filter(4)
+    );
+
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let mut rows = stmt
+        .query(params![
+            from,
+            to,
+            limit + 1,
+            query.tag.as_deref().filter(|t| !t.is_empty())
+        ])
+        .map_err(|e| e.to_string())?;
+    let mut out = Vec::new();
+    let mut truncated = false;
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        if out.len() as i64 == limit {
+            truncated = true;
+            break;
+        }
+        let requests: i64 = row.get(2).map_err(|e| e.to_string())?;
+        let errors: i64 = row.get(3).map_err(|e| e.to_string())?;
+        out.push(UsageSummaryRow {
+            bucket: row.get(0).map_err(|e| e.to_string())?,
+            group: row.get(1).map_err(|e| e.to_string())?,
+            requests,
+            errors,
+            error_rate: if requests > 0 {
+                errors as f64 / requests as f64
+            } else {
+                0.0
+            },
+            prompt_tokens: row.get(4).map_err(|e| e.to_string())?,
+            completion_tokens: row.get(5).map_err(|e| e.to_string())?,
+            total_tokens: row.get(6).map_err(|e| e.to_string())?,
+            cache_creation_tokens: row.get(7).map_err(|e| e.to_string())?,
+            cache_read_tokens: row.get(8).map_err(|e| e.to_string())?,
+            reasoning_tokens: row.get(9).map_err(|e| e.to_string())?,
+            price_usd: row.get(10).map_err(|e| e.to_string())?,
+            avg_latency_ms: row.get(11).map_err(|e| e.to_string())?,
+        });
+    }
+
+    Ok(UsageSummary {
+        from,
+        to,
+        group_by: query.group_by.clone().filter(|s| !s.is_empty()),
+        bucket: query.bucket.clone().filter(|s| !s.is_empty()),
+        rows: out,
+        truncated,
+    })
+}
+
+/// Condition on `usage_logs` rows carrying the tag bound to `?{param}`;
+/// true for every row when it is null.
+fn tag_filter(param: usize) -> String {
+    format!(
+        "(?{param} is null or exists (select 1 from json_each(usage_logs.tags) where value = ?{param}))"
+    )
+}
+
+/// Output format for `export_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "jsonl" | "ndjson" => Some(ExportFormat::Jsonl),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct UsageExportRow {
+    id: i64,
+    timestamp: String,
+    channel: Option<String>,
+    tool: Option<String>,
+    model: Option<String>,
+    upstream_id: Option<String>,
+    client_token: Option<String>,
+    project: Option<String>,
+    status: i64,
+    latency_ms: Option<i64>,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    total_tokens: i64,
+    cache_creation_tokens: i64,
+    cache_read_tokens: i64,
+    reasoning_tokens: i64,
+    price_usd: Option<f64>,
+    price_prompt_per_1k: Option<f64>,
+    price_completion_per_1k: Option<f64>,
+    metadata: Option<String>,
+    tags: Option<String>,
+}
+
+const EXPORT_COLUMNS: &[&str] = &[
+    "id",
+    "timestamp",
+    "channel",
+    "tool",
+    "model",
+    "upstream_id",
+    "client_token",
+    "project",
+    "status",
+    "latency_ms",
+    "prompt_tokens",
+    "completion_tokens",
+    "total_tokens",
+    "cache_creation_tokens",
+    "cache_read_tokens",
+    "reasoning_tokens",
+    "price_usd",
+    "price_prompt_per_1k",
+    "price_completion_per_1k",
+    "metadata",
+    "tags",
+];
+
+/// Quote a CSV field when it contains a delimiter, quote or line break (RFC 4180).
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        std::borrow::Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        std::borrow::Cow::Borrowed(value)
+    }
+}
+
+fn opt_to_string<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl UsageExportRow {
+    fn csv_line(&self) -> String {
+        let fields = [
+            self.id.to_string(),
+            self.timestamp.clone(),
+            opt_to_string(&self.channel),
+            opt_to_string(&self.tool),
+            opt_to_string(&self.model),
+            opt_to_string(&self.upstream_id),
+            opt_to_string(&self.client_token),
+            opt_to_string(&self.project),
+            self.status.to_string(),
+            opt_to_string(&self.latency_ms),
+            self.prompt_tokens.to_string(),
+            self.completion_tokens.to_string(),
+            self.total_tokens.to_string(),
+            self.cache_creation_tokens.to_string(),
+            self.cache_read_tokens.to_string(),
+            self.reasoning_tokens.to_string(),
+            opt_to_string(&self.price_usd),
+            opt_to_string(&self.price_prompt_per_1k),
+            opt_to_string(&self.price_completion_per_1k),
+            opt_to_string(&self.metadata),
+            opt_to_string(&self.tags),
+        ];
+        let mut line = fields
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<_>>()
+            .join(",");
+        line.push_str("\r\n");
+        line
+    }
+}
+
+/// Write usage rows in `[from, to)`, only those carrying `tag` if given, to
+/// `out` one row at a time, so the export never holds more than a single row
+/// in memory. Returns the number of rows.
+pub fn export_usage<W: std::io::Write>(
+    from: Option<i64>,
+    to: Option<i64>,
+    tag: Option<&str>,
+    format: ExportFormat,
+    out: &mut W,
+) -> Result<usize, String> {
+    let conn = open_conn();
+    export_usage_with(&conn, from, to, tag, format, out)
+}
+
+fn export_usage_with<W: std::io::Write>(
+    conn: &Connection,
+    from: Option<i64>,
+    to: Option<i64>,
+    tag: Option<&str>,
+    format: ExportFormat,
+    out: &mut W,
+) -> Result<usize, String> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(i64::MAX);
+    let mut stmt = conn
+        .prepare(&format!("select id, timestamp, channel, tool, model, upstream_id, client_token, project, status, latency_ms, prompt_tokens, completion_tokens, total_tokens, cache_creation_tokens, cache_read_tokens, reasoning_tokens, price_usd, price_prompt_per_1k, price_completion_per_1k, metadata, tags from usage_logs where timestamp >= ?1 and timestamp < ?2 and {} order by timestamp, id", tag_filter(3)))
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![from, to, tag.filter(|t| !t.is_empty())], |r| {
+            let ts: i64 = r.get(1)?;
+            Ok(UsageExportRow {
+                id: r.get(0)?,
+                timestamp: chrono::DateTime::from_timestamp(ts, 0)
+                    .map(|t| t.to_rfc3339())
+                    .unwrap_or_else(|| ts.to_string()),
+                channel: r.get(2)?,
+                tool: r.get(3)?,
+                model: r.get(4)?,
+                upstream_id: r.get(5)?,
+                client_token: r.get(6)?,
+                project: r.get(7)?,
+                status: r.get(8)?,
+                latency_ms: r.get(9)?,
+                prompt_tokens: r.get(10)?,
+                completion_tokens: r.get(11)?,
+                total_tokens: r.get(12)?,
+                cache_creation_tokens: r.get(13)?,
+                cache_read_tokens: r.get(14)?,
+                reasoning_tokens: r.get(15)?,
+                price_usd: r.get(16)?,
+                price_prompt_per_1k: r.get(17)?,
+                price_completion_per_1k: r.get(18)?,
+                metadata: r.get(19)?,
+                tags: r.get(20)?,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+
+    if format == ExportFormat::Csv {
+        let header = EXPORT_COLUMNS.join(",");
+        out.write_all(format!("{}\r\n", header).as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+
+    let mut count = 0;
+    for row in rows {
+        let row = row.map_err(|e| e.to_string())?;
+        let line = match format {
+            ExportFormat::Csv => row.csv_line(),
+            ExportFormat::Jsonl => {
+                let mut json = serde_json::to_string(&row).map_err(|e| e.to_string())?;
+                json.push('\n');
+                json
+            }
+        };
+        out.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        count += 1;
+    }
+    out.flush().map_err(|e| e.to_string())?;
+    Ok(count)
 }
 
 pub fn series_tokens(days: i64) -> Vec<(String, i64)> {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select date(timestamp,'unixepoch'), ifnull(sum(total_tokens),0) from usage_logs where timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 1").unwrap();
+    let mut stmt = conn.prepare_cached("select date(timestamp,'unixepoch'), ifnull(sum(total_tokens),0) from usage_logs where synthetic=0 and success=1 and timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 1").unwrap();
     let rows = stmt
         .query_map(params![days], |r| {
             Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?))
@@ -145,7 +1006,7 @@ pub fn series_tokens(days: i64) -> Vec<(String, i64)> {
 
 pub fn series_price(days: i64) -> Vec<(String, f64)> {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select date(timestamp,'unixepoch'), ifnull(sum(price_usd),0) from usage_logs where timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 1").unwrap();
+    let mut stmt = conn.prepare_cached("select date(timestamp,'unixepoch'), ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 and timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 1").unwrap();
     let rows = stmt
         .query_map(params![days], |r| {
             Ok((r.get::<_, String>(0)?, r.get::<_, f64>(1)?))
@@ -156,7 +1017,7 @@ pub fn series_price(days: i64) -> Vec<(String, f64)> {
 
 pub fn channels_breakdown() -> Vec<ChannelStats> {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select channel, ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs group by 1 order by 2 desc").unwrap();
+    let mut stmt = conn.prepare_cached("select channel, ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where synthetic=0 and success=1 group by 1 order by 2 desc").unwrap();
     let rows = stmt
         .query_map([], |r| {
             Ok(ChannelStats {
@@ -171,7 +1032,7 @@ pub fn channels_breakdown() -> Vec<ChannelStats> {
 
 pub fn models_cost_since(days: i64) -> Vec<ModelStats> {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select model, count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0) from usage_logs where timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 4 desc").unwrap();
+    let mut stmt = conn.prepare_cached("select model, count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0), ifnull(sum(cache_creation_tokens),0), ifnull(sum(cache_read_tokens),0), ifnull(sum(reasoning_tokens),0) from usage_logs where synthetic=0 and success=1 and timestamp>= strftime('%s','now','-'||?1||' day') group by 1 order by 4 desc").unwrap();
     let rows = stmt
         .query_map(params![days], |r| {
             Ok(ModelStats {
@@ -179,12 +1040,42 @@ pub fn models_cost_since(days: i64) -> Vec<ModelStats> {
                 requests: r.get(1)?,
                 tokens: r.get(2)?,
                 price_usd: r.get(3)?,
+                cache_creation_tokens: r.get(4)?,
+                cache_read_tokens: r.get(5)?,
+                reasoning_tokens: r.get(6)?,
             })
         })
         .unwrap();
     rows.filter_map(|x| x.ok()).collect()
 }
 
+/// The `n` most expensive models (then most used) in a `summary_for_range`
+/// window.
+pub fn top_models(range: &str, n: i64) -> Vec<ModelStats> {
+    let conn = open_conn();
+    top_models_with(&conn, range, n)
+}
+
+fn top_models_with(conn: &Connection, range: &str, n: i64) -> Vec<ModelStats> {
+    let sql = format!("select model, count(*), ifnull(sum(total_tokens),0), ifnull(sum(price_usd),0), ifnull(sum(cache_creation_tokens),0), ifnull(sum(cache_read_tokens),0), ifnull(sum(reasoning_tokens),0) from usage_logs where synthetic=0 and success=1 and {} group by 1 order by 4 desc, 2 desc limit ?1", range_filter(range));
+    let Ok(mut stmt) = conn.prepare(&sql) else {
+        return Vec::new();
+    };
+    stmt.query_map(params![n.max(0)], |r| {
+        Ok(ModelStats {
+            model: r.get(0)?,
+            requests: r.get(1)?,
+            tokens: r.get(2)?,
+            price_usd: r.get(3)?,
+            cache_creation_tokens: r.get(4)?,
+            cache_read_tokens: r.get(5)?,
+            reasoning_tokens: r.get(6)?,
+        })
+    })
+    .map(|rows| rows.filter_map(|x| x.ok()).collect())
+    .unwrap_or_default()
+}
+
 #[derive(Debug, serde::Serialize, Clone)]
 pub struct RequestLog {
     pub id: i64,
@@ -195,13 +1086,35 @@ pub struct RequestLog {
     pub prompt_tokens: i64,
     pub completion_tokens: i64,
     pub total_tokens: i64,
-    pub price_usd: f64,
+    pub price_usd: Option<f64>,
     pub upstream_id: String,
+    pub cache_creation_tokens: i64,
+    pub cache_read_tokens: i64,
+    pub reasoning_tokens: i64,
+    pub price_prompt_per_1k: Option<f64>,
+    pub price_completion_per_1k: Option<f64>,
+    pub status: i64,
+    pub latency_ms: Option<i64>,
+    pub project: Option<String>,
+    pub estimated: bool,
+    /// Sent by the relay itself, not billed
+    pub synthetic: bool,
+    /// Matches redacted from the response
+    pub redactions: i64,
+    /// Matches redacted from the prompt
+    pub prompt_redactions: i64,
+    /// Request id of the captured request this one replays
+    pub replay_of: Option<String>,
+    /// False for requests that failed for good
+    pub success: bool,
+    pub endpoint: Option<String>,
+    pub error_class: Option<String>,
+    pub attempts: Option<i64>,
 }
 
 pub fn recent_logs(limit: i64, offset: i64) -> Vec<RequestLog> {
     let conn = open_conn();
-    let mut stmt = conn.prepare_cached("select id, timestamp, channel, tool, model, prompt_tokens, completion_tokens, total_tokens, price_usd, upstream_id from usage_logs order by timestamp desc limit ?1 offset ?2").unwrap();
+    let mut stmt = conn.prepare_cached("select id, timestamp, channel, tool, model, prompt_tokens, completion_tokens, total_tokens, price_usd, upstream_id, cache_creation_tokens, cache_read_tokens, reasoning_tokens, price_prompt_per_1k, price_completion_per_1k, status, latency_ms, project, estimated, synthetic, redactions, success, endpoint, error_class, attempts, prompt_redactions, replay_of from usage_logs order by timestamp desc limit ?1 offset ?2").unwrap();
     let rows = stmt
         .query_map(params![limit, offset], |r| {
             Ok(RequestLog {
@@ -215,6 +1128,23 @@ pub fn recent_logs(limit: i64, offset: i64) -> Vec<RequestLog> {
                 total_tokens: r.get(7)?,
                 price_usd: r.get(8)?,
                 upstream_id: r.get(9)?,
+                cache_creation_tokens: r.get(10)?,
+                cache_read_tokens: r.get(11)?,
+                reasoning_tokens: r.get(12)?,
+                price_prompt_per_1k: r.get(13)?,
+                price_completion_per_1k: r.get(14)?,
+                status: r.get(15)?,
+                latency_ms: r.get(16)?,
+                project: r.get(17)?,
+                estimated: r.get(18)?,
+                synthetic: r.get(19)?,
+                redactions: r.get(20)?,
+                success: r.get(21)?,
+                endpoint: r.get(22)?,
+                error_class: r.get(23)?,
+                attempts: r.get(24)?,
+                prompt_redactions: r.get(25)?,
+                replay_of: r.get(26)?,
             })
         })
         .unwrap();
@@ -227,10 +1157,553 @@ pub fn logs_count() -> i64 {
     stmt.query_row([], |row| row.get(0)).unwrap_or(0)
 }
 
+/// What a maintenance run removed and how much space it gave back.
+#[derive(Debug, serde::Serialize, Clone, Default)]
+pub struct MaintenanceReport {
+    pub usage_rows_deleted: usize,
+    pub usage_rows_rolled_up: usize,
+    pub captures_deleted: usize,
+    pub vacuumed: bool,
+    pub size_before_bytes: i64,
+    pub size_after_bytes: i64,
+    pub reclaimed_bytes: i64,
+}
+
+/// Cut-off timestamps for a maintenance run; `None` skips that table.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub usage_before: Option<i64>,
+    pub captures_before: Option<i64>,
+    pub rollup: bool,
+    pub vacuum: bool,
+}
+
+pub fn run_maintenance(policy: &RetentionPolicy) -> Result<MaintenanceReport, String> {
+    let conn = open_conn();
+    run_maintenance_with(&conn, policy)
+}
+
+fn db_size_bytes(conn: &Connection) -> i64 {
+    let pages: i64 = conn.query_row("pragma page_count", [], |r| r.get(0)).unwrap_or(0);
+    let page_size: i64 = conn.query_row("pragma page_size", [], |r| r.get(0)).unwrap_or(0);
+    pages * page_size
+}
+
+fn run_maintenance_with(conn: &Connection, policy: &RetentionPolicy) -> Result<MaintenanceReport, String> {
+    let mut report = MaintenanceReport {
+        size_before_bytes: db_size_bytes(conn),
+        ..Default::default()
+    };
+
+    if let Some(before) = policy.usage_before {
+        let tx = conn.unchecked_transaction().map_err(|e| e.to_string())?;
+        if policy.rollup {
+            report.usage_rows_rolled_up = tx
+                .query_row("select count(*) from usage_logs where timestamp < ?1 and synthetic = 0", params![before], |r| r.get::<_, i64>(0))
+                .map_err(|e| e.to_string())? as usize;
+            tx.execute(
+                "insert into usage_rollup_daily (day, model, upstream_id, requests, errors, prompt_tokens, completion_tokens, total_tokens, cache_creation_tokens, cache_read_tokens, reasoning_tokens, price_usd)
+                 select date(timestamp, 'unixepoch'), ifnull(model, ''), ifnull(upstream_id, ''), count(*), sum(case when status >= 400 or success = 0 then 1 else 0 end), ifnull(sum(prompt_tokens), 0), ifnull(sum(completion_tokens), 0), ifnull(sum(total_tokens), 0), ifnull(sum(cache_creation_tokens), 0), ifnull(sum(cache_read_tokens), 0), ifnull(sum(reasoning_tokens), 0), ifnull(sum(price_usd), 0)
+                 from usage_logs where timestamp < ?1 and synthetic = 0
+                 group by 1, 2, 3
+                 on conflict(day, model, upstream_id) do update set
+                    requests = requests + excluded.requests,
+                    errors = errors + excluded.errors,
+                    prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                    completion_tokens = completion_tokens + excluded.completion_tokens,
+                    total_tokens = total_tokens + excluded.total_tokens,
+                    cache_creation_tokens = cache_creation_tokens + excluded.cache_creation_tokens,
+                    cache_read_tokens = cache_read_tokens + excluded.cache_read_tokens,
+                    reasoning_tokens = reasoning_tokens + excluded.reasoning_tokens,
+                    price_usd = price_usd + excluded.price_usd",
+                params![before],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        report.usage_rows_deleted = tx
+            .execute("delete from usage_logs where timestamp < ?1", params![before])
+            .map_err(|e| e.to_string())?;
+        tx.execute("delete from mirror_log where timestamp < ?1", params![before])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+
+    if let Some(before) = policy.captures_before {
+        report.captures_deleted = conn
+            .execute("delete from request_log where timestamp < ?1", params![before])
+            .map_err(|e| e.to_string())?;
+    }
+
+    let deleted = report.usage_rows_deleted + report.captures_deleted;
+    if policy.vacuum && deleted > 0 {
+        let auto_vacuum: i64 = conn.query_row("pragma auto_vacuum", [], |r| r.get(0)).unwrap_or(0);
+        let result = if auto_vacuum == 2 {
+            conn.execute_batch("pragma incremental_vacuum;")
+        } else {
+            conn.execute_batch("vacuum;")
+        };
+        result.map_err(|e| e.to_string())?;
+        conn.execute_batch("pragma wal_checkpoint(truncate);").ok();
+        report.vacuumed = true;
+    }
+
+    report.size_after_bytes = db_size_bytes(conn);
+    report.reclaimed_bytes = (report.size_before_bytes - report.size_after_bytes).max(0);
+    Ok(report)
+}
+
+/// A mirrored request and the primary request it copied (see
+/// `forward::mirror`). Kept apart from `usage_logs`: mirrors count against
+/// budgets but are nobody's usage.
+#[derive(Debug, Clone, Default)]
+pub struct MirrorRecord {
+    pub timestamp: i64,
+    pub request_id: String,
+    /// Model name sent to the mirror upstream
+    pub model: String,
+    pub upstream_id: String,
+    pub client_token: String,
+    pub project: Option<String>,
+    pub primary_upstream: String,
+    pub primary_status: u16,
+    pub primary_latency_ms: i64,
+    pub primary_prompt_tokens: i64,
+    pub primary_completion_tokens: i64,
+    pub status: u16,
+    pub latency_ms: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub price_usd: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Queue a mirror comparison row for the background writer.
+pub fn log_mirror(record: MirrorRecord) {
+    enqueue(WriteOp::Mirror(record));
+}
+
+fn insert_mirror(conn: &Connection, record: &MirrorRecord) -> Result<(), String> {
+    conn.execute(
+        "insert into mirror_log (timestamp, request_id, model, upstream_id, client_token, project, primary_upstream, primary_status, primary_latency_ms, primary_prompt_tokens, primary_completion_tokens, status, latency_ms, prompt_tokens, completion_tokens, price_usd, error) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+        params![
+            record.timestamp,
+            record.request_id,
+            record.model,
+            record.upstream_id,
+            record.client_token,
+            record.project,
+            record.primary_upstream,
+            record.primary_status,
+            record.primary_latency_ms,
+            record.primary_prompt_tokens,
+            record.primary_completion_tokens,
+            record.status,
+            record.latency_ms,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.price_usd,
+            record.error,
+        ],
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+/// Mirror spend since `since`, filtered like `spent_since`. Mirrors have no
+/// project id or API key, so those groups spent nothing on them.
+fn mirror_spent_since(
+    conn: &Connection,
+    since: i64,
+    filter: Option<(&str, &str)>,
+) -> Result<f64, String> {
+    let base = "select ifnull(sum(price_usd),0) from mirror_log where timestamp>=?1";
+    match filter {
+        None => conn.query_row(base, params![since], |row| row.get(0)),
+        Some((group, value)) => match group_column(group) {
+            Some(col @ ("model" | "upstream_id" | "client_token" | "project")) => conn.query_row(
+                &format!("{base} and {col} = ?2 collate nocase"),
+                params![since, value],
+                |row| row.get(0),
+            ),
+            Some(_) => Ok(0.0),
+            None => return Err(format!("Unsupported group: {}", group)),
+        },
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// How a mirror upstream compares with the primary it shadows.
+#[derive(Debug, serde::Serialize, Clone, PartialEq)]
+pub struct MirrorReportRow {
+    pub primary_upstream: String,
+    pub upstream_id: String,
+    pub model: String,
+    pub requests: i64,
+    pub primary_avg_latency_ms: Option<f64>,
+    pub avg_latency_ms: Option<f64>,
+    /// Mirror minus primary, over requests both answered
+    pub latency_delta_ms: Option<f64>,
+    pub primary_error_rate: f64,
+    pub error_rate: f64,
+    /// Requests where exactly one side failed
+    pub divergent: i64,
+    pub primary_completion_tokens: i64,
+    pub completion_tokens: i64,
+    pub price_usd: f64,
+}
+
+/// Mirror comparisons since `since`, one row per primary upstream, mirror
+/// upstream and model.
+pub fn mirror_report(since: i64) -> Result<Vec<MirrorReportRow>, String> {
+    mirror_report_with(&open_conn(), since)
+}
+
+fn mirror_report_with(conn: &Connection, since: i64) -> Result<Vec<MirrorReportRow>, String> {
+    let mut stmt = conn
+        .prepare_cached(
+            "select primary_upstream, upstream_id, model, count(*),
+                avg(case when primary_status < 400 then primary_latency_ms end),
+                avg(case when status < 400 then latency_ms end),
+                avg(case when status < 400 and primary_status < 400 then latency_ms - primary_latency_ms end),
+                sum(primary_status >= 400), sum(status >= 400),
+                sum((status >= 400) != (primary_status >= 400)),
+                ifnull(sum(primary_completion_tokens),0), ifnull(sum(completion_tokens),0),
+                ifnull(sum(price_usd),0)
+             from mirror_log where timestamp >= ?1
+             group by 1, 2, 3 order by 4 desc",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![since], |r| {
+            let requests: i64 = r.get(3)?;
+            let rate = |errors: i64| {
+                if requests > 0 {
+                    errors as f64 / requests as f64
+                } else {
+                    0.0
+                }
+            };
+            Ok(MirrorReportRow {
+                primary_upstream: r.get(0)?,
+                upstream_id: r.get(1)?,
+                model: r.get(2)?,
+                requests,
+                primary_avg_latency_ms: r.get(4)?,
+                avg_latency_ms: r.get(5)?,
+                latency_delta_ms: r.get(6)?,
+                primary_error_rate: rate(r.get(7)?),
+                error_rate: rate(r.get(8)?),
+                divergent: r.get(9)?,
+                primary_completion_tokens: r.get(10)?,
+                completion_tokens: r.get(11)?,
+                price_usd: r.get(12)?,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// Totals of one variant of an A/B experiment.
+#[derive(Debug, serde::Serialize, Clone, PartialEq)]
+pub struct ExperimentVariantRow {
+    pub variant: String,
+    /// Models that answered, as recorded in usage
+    pub models: Vec<String>,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub price_usd: f64,
+    /// Over successful requests
+    pub avg_latency_ms: Option<f64>,
+    pub error_rate: f64,
+}
+
+/// Per-variant totals of experiment `experiment`, synthetic requests left
+/// out.
+pub fn experiment_report(experiment: &str) -> Result<Vec<ExperimentVariantRow>, String> {
+    experiment_report_with(&open_conn(), experiment)
+}
+
+fn experiment_report_with(
+    conn: &Connection,
+    experiment: &str,
+) -> Result<Vec<ExperimentVariantRow>, String> {
+    let mut stmt = conn
+        .prepare_cached(
+            "select variant, group_concat(distinct model), count(*),
+                ifnull(sum(prompt_tokens),0), ifnull(sum(completion_tokens),0),
+                ifnull(sum(price_usd),0),
+                avg(case when success=1 then latency_ms end), sum(success=0)
+             from usage_logs where experiment = ?1 and synthetic=0
+             group by variant order by variant",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![experiment], |r| {
+            let requests: i64 = r.get(2)?;
+            let failed: i64 = r.get(7)?;
+            let models: Option<String> = r.get(1)?;
+            Ok(ExperimentVariantRow {
+                variant: r.get(0)?,
+                models: models
+                    .map(|m| m.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+                requests,
+                prompt_tokens: r.get(3)?,
+                completion_tokens: r.get(4)?,
+                price_usd: r.get(5)?,
+                avg_latency_ms: r.get(6)?,
+                error_rate: if requests > 0 {
+                    failed as f64 / requests as f64
+                } else {
+                    0.0
+                },
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// One captured upstream exchange (see `forward::capture`).
+#[derive(Debug, serde::Serialize, Clone, Default)]
+pub struct CapturedRequest {
+    pub id: i64,
+    pub timestamp: i64,
+    pub model: String,
+    pub upstream_id: String,
+    pub url: Option<String>,
+    pub streaming: bool,
+    pub status: Option<i64>,
+    pub latency_ms: Option<i64>,
+    pub request_headers: Option<String>,
+    pub request_body: Option<String>,
+    pub response_body: Option<String>,
+    pub error: Option<String>,
+    pub truncated: bool,
+    pub request_id: Option<String>,
+    /// Credentials or inline data in the request body were replaced before
+    /// it was stored, so it can't be replayed as sent
+    pub redacted: bool,
+}
+
+/// One line of the HTTP access log (see `access_log`).
+#[derive(Debug, Clone, Default)]
+pub struct AccessRecord {
+    pub timestamp: i64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: i64,
+    pub bytes: i64,
+    pub model: Option<String>,
+    pub upstream_id: Option<String>,
+    pub client_token: Option<String>,
+    /// Version of the forward token the request presented
+    pub token_version: Option<u64>,
+    pub request_id: Option<String>,
+}
+
+/// Queue an access log row for the background writer.
+pub fn log_access(record: AccessRecord) {
+    enqueue(WriteOp::Access(record));
+}
+
+fn insert_access(conn: &Connection, record: &AccessRecord) -> Result<(), String> {
+    conn.execute(
+        "insert into access_log (timestamp, method, path, status, duration_ms, bytes, model, upstream_id, client_token, request_id, token_version) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            record.timestamp,
+            record.method,
+            record.path,
+            record.status,
+            record.duration_ms,
+            record.bytes,
+            record.model,
+            record.upstream_id,
+            record.client_token,
+            record.request_id,
+            record.token_version.map(|v| v as i64)
+        ],
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+/// Filters for `GET /api/requests`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct CapturedRequestQuery {
+    pub model: Option<String>,
+    pub status: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+const CAPTURE_LIST_DEFAULT_LIMIT: i64 = 50;
+const CAPTURE_LIST_MAX_LIMIT: i64 = 500;
+
+/// Queue a capture for the background writer.
+pub fn log_captured_request(entry: CapturedRequest) {
+    enqueue(WriteOp::Capture(Box::new(entry)));
+}
+
+fn insert_captured_request_with(conn: &Connection, entry: &CapturedRequest) -> Result<i64, String> {
+    conn.execute(
+        "insert into request_log (timestamp, model, upstream_id, url, streaming, status, latency_ms, request_headers, request_body, response_body, error, truncated, request_id, redacted) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            entry.timestamp,
+            entry.model,
+            entry.upstream_id,
+            entry.url,
+            entry.streaming,
+            entry.status,
+            entry.latency_ms,
+            entry.request_headers,
+            entry.request_body,
+            entry.response_body,
+            entry.error,
+            entry.truncated,
+            entry.request_id,
+            entry.redacted
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn captured_from_row(r: &rusqlite::Row<'_>, with_bodies: bool) -> rusqlite::Result<CapturedRequest> {
+    Ok(CapturedRequest {
+        id: r.get(0)?,
+        timestamp: r.get(1)?,
+        model: r.get::<_, Option<String>>(2)?.unwrap_or_default(),
+        upstream_id: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        url: r.get(4)?,
+        streaming: r.get(5)?,
+        status: r.get(6)?,
+        latency_ms: r.get(7)?,
+        error: r.get(8)?,
+        truncated: r.get(9)?,
+        request_headers: if with_bodies { r.get(10)? } else { None },
+        request_body: if with_bodies { r.get(11)? } else { None },
+        response_body: if with_bodies { r.get(12)? } else { None },
+        request_id: r.get(13)?,
+        redacted: r.get(14)?,
+    })
+}
+
+const CAPTURE_COLUMNS: &str = "id, timestamp, model, upstream_id, url, streaming, status, latency_ms, error, truncated, request_headers, request_body, response_body, request_id, redacted";
+
+/// Most recent captures, newest first. Bodies are omitted from the listing.
+pub fn list_captured_requests(query: &CapturedRequestQuery) -> Vec<CapturedRequest> {
+    let conn = open_conn();
+    list_captured_requests_with(&conn, query).unwrap_or_default()
+}
+
+fn list_captured_requests_with(
+    conn: &Connection,
+    query: &CapturedRequestQuery,
+) -> Result<Vec<CapturedRequest>, String> {
+    let limit = query
+        .limit
+        .unwrap_or(CAPTURE_LIST_DEFAULT_LIMIT)
+        .clamp(1, CAPTURE_LIST_MAX_LIMIT);
+    let sql = format!(
+        "select {CAPTURE_COLUMNS} from request_log where (?1 is null or model = ?1) and (?2 is null or status = ?2) order by timestamp desc, id desc limit ?3"
+    );
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![query.model, query.status, limit], |r| {
+            captured_from_row(r, false)
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(rows.filter_map(|x| x.ok()).collect())
+}
+
+pub fn get_captured_request(id: i64) -> Option<CapturedRequest> {
+    let conn = open_conn();
+    get_captured_request_with(&conn, id)
+}
+
+fn get_captured_request_with(conn: &Connection, id: i64) -> Option<CapturedRequest> {
+    let sql = format!("select {CAPTURE_COLUMNS} from request_log where id = ?1");
+    conn.query_row(&sql, params![id], |r| captured_from_row(r, true))
+        .ok()
+}
+
+// ============================================
+// Response cache
+// ============================================
+
+/// An answer to keep in `response_cache`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub key: String,
+    pub model: String,
+    pub body: String,
+    /// Evict the least recently used answers beyond this total
+    pub max_bytes: i64,
+}
+
+/// Queue an answer for the response cache.
+pub fn store_cached_response(entry: CachedResponse) {
+    enqueue(WriteOp::CacheStore(entry));
+}
+
+fn store_cached_response_with(conn: &Connection, entry: &CachedResponse) -> Result<(), String> {
+    let now = chrono::Utc::now().timestamp();
+    conn.execute(
+        "insert or replace into response_cache (key, model, body, bytes, created_at, used_at, hits) values (?1, ?2, ?3, ?4, ?5, ?5, 0)",
+        params![entry.key, entry.model, entry.body, entry.body.len() as i64, now],
+    )
+    .map_err(|e| e.to_string())?;
+    // Keep the most recently used answers that fit
+    conn.execute(
+        "delete from response_cache where key in (select key from (select key, sum(bytes) over (order by used_at desc, created_at desc, key) as kept from response_cache) where kept > ?1)",
+        params![entry.max_bytes],
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+/// The cached answer for `key` if it is at most `max_age_secs` old; the
+/// hit is recorded in the background.
+pub fn cached_response(key: &str, max_age_secs: u64) -> Option<String> {
+    let body = cached_response_with(&open_conn(), key, max_age_secs)?;
+    enqueue(WriteOp::CacheHit(key.to_string()));
+    Some(body)
+}
+
+fn cached_response_with(conn: &Connection, key: &str, max_age_secs: u64) -> Option<String> {
+    let oldest = chrono::Utc::now().timestamp() - max_age_secs as i64;
+    conn.query_row(
+        "select body from response_cache where key = ?1 and created_at >= ?2",
+        params![key, oldest],
+        |r| r.get(0),
+    )
+    .ok()
+}
+
+/// Entries and bytes held by the response cache.
+pub fn response_cache_size() -> (i64, i64) {
+    open_conn()
+        .query_row(
+            "select count(*), coalesce(sum(bytes), 0) from response_cache",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap_or_default()
+}
+
 pub fn clear_all_data() -> Result<(), String> {
     let conn = open_conn();
     conn.execute_batch(
         "DELETE FROM usage_logs;
+        DELETE FROM request_log;
+        DELETE FROM access_log;
+        DELETE FROM mirror_log;
+        DELETE FROM response_cache;
+        DELETE FROM usage_rollup_daily;
         DELETE FROM usage_daily;
         DELETE FROM usage_weekly;
         DELETE FROM usage_monthly;
@@ -239,5 +1712,614 @@ pub fn clear_all_data() -> Result<(), String> {
         DELETE FROM models;",
     )
     .map_err(|e| e.to_string())?;
+    crate::projects::invalidate();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn seeded_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let record = |model: &str, upstream: &str, status: u16, price: Option<f64>, latency: i64| UsageRecord {
+            channel: "cli".to_string(),
+            tool: "test".to_string(),
+            model: model.to_string(),
+            upstream_id: upstream.to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            total_tokens: 150,
+            cache_read_tokens: 20,
+            price_usd: price,
+            status,
+            latency_ms: Some(latency),
+            client_token: "ccr_...abcd".to_string(),
+            project: Some("relay".to_string()),
+            metadata: Some(r#"{"user_agent":"Mozilla/5.0 (X11, Linux)\nline two"}"#.to_string()),
+            api_key_id: Some(format!("{}-key", upstream)),
+            ..Default::default()
+        };
+        let at = |hour: u32| chrono::Utc.with_ymd_and_hms(2024, 5, 1, hour, 15, 0).unwrap();
+        insert_usage(&conn, &record("gpt-4o", "openai", 200, Some(0.01), 100), at(9)).unwrap();
+        insert_usage(&conn, &record("gpt-4o", "openai", 500, Some(0.03), 300), at(9)).unwrap();
+        insert_usage(&conn, &record("claude", "anthropic", 200, None, 200), at(10)).unwrap();
+        conn
+    }
+
+    fn window() -> (i64, i64) {
+        let from = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap().timestamp();
+        (from, from + 86_400)
+    }
+
+    #[test]
+    fn token_details_count_estimated_usage() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let now = chrono::Utc::now();
+        for (tokens, estimated) in [(300, true), (100, false)] {
+            let record = UsageRecord {
+                total_tokens: tokens,
+                estimated,
+                status: 200,
+                ..Default::default()
+            };
+            insert_usage(&conn, &record, now).unwrap();
+        }
+        let details = token_details_with(&conn, range_filter("weekly"));
+        assert_eq!(details.estimated_requests, 1);
+        assert_eq!(details.estimated_tokens, 300);
+        assert_eq!(details.total_tokens, 400);
+    }
+
+    #[test]
+    fn top_models_orders_by_cost_and_limits() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let now = chrono::Utc::now();
+        for (model, price) in [("a", 0.01), ("b", 0.05), ("b", 0.01), ("c", 0.0)] {
+            let record = UsageRecord {
+                model: model.to_string(),
+                price_usd: Some(price),
+                status: 200,
+                ..Default::default()
+            };
+            insert_usage(&conn, &record, now).unwrap();
+        }
+        let top = top_models_with(&conn, "weekly", 2);
+        let names: Vec<_> = top.iter().map(|m| (m.model.as_str(), m.requests)).collect();
+        assert_eq!(names, vec![("b", 2), ("a", 1)]);
+    }
+
+    #[test]
+    fn summary_groups_by_model() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let summary = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                from: Some(from),
+                to: Some(to),
+                group_by: Some("model".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.rows.len(), 2);
+        let claude = &summary.rows[0];
+        assert_eq!(claude.group.as_deref(), Some("claude"));
+        assert_eq!(claude.price_usd, None);
+        let gpt = &summary.rows[1];
+        assert_eq!(gpt.requests, 2);
+        assert_eq!(gpt.errors, 1);
+        assert_eq!(gpt.cache_read_tokens, 40);
+        assert!((gpt.price_usd.unwrap() - 0.04).abs() < 1e-9);
+        assert_eq!(gpt.avg_latency_ms, Some(200.0));
+        assert!(!summary.truncated);
+    }
+
+    #[test]
+    fn summary_and_export_by_tag() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let at = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 11, 0, 0).unwrap();
+        for (tags, price) in [(r#"["batch-eval","run-42"]"#, 0.5), (r#"["run-42"]"#, 0.25)] {
+            let record = UsageRecord {
+                model: "gpt-4o".to_string(),
+                status: 200,
+                price_usd: Some(price),
+                tags: Some(tags.to_string()),
+                ..Default::default()
+            };
+            insert_usage(&conn, &record, at).unwrap();
+        }
+
+        let query = |group_by: Option<&str>, tag: Option<&str>| UsageSummaryQuery {
+            from: Some(from),
+            to: Some(to),
+            group_by: group_by.map(str::to_string),
+            tag: tag.map(str::to_string),
+            ..Default::default()
+        };
+        let by_tag = usage_summary_with(&conn, &query(Some("tag"), None)).unwrap();
+        let groups: Vec<_> = by_tag
+            .rows
+            .iter()
+            .map(|r| (r.group.as_deref().unwrap(), r.requests))
+            .collect();
+        assert_eq!(groups, vec![("batch-eval", 1), ("run-42", 2)]);
+        assert!((by_tag.rows[1].price_usd.unwrap() - 0.75).abs() < 1e-9);
+
+        let tagged = usage_summary_with(&conn, &query(None, Some("run-42"))).unwrap();
+        assert_eq!(tagged.rows[0].requests, 2);
+        let all = usage_summary_with(&conn, &query(None, None)).unwrap();
+        assert_eq!(all.rows[0].requests, 5);
+        // Tags match whole
+        let none = usage_summary_with(&conn, &query(None, Some("run"))).unwrap();
+        assert_eq!(none.rows[0].requests, 0);
+
+        let mut jsonl = Vec::new();
+        let count = export_usage_with(
+            &conn,
+            None,
+            None,
+            Some("batch-eval"),
+            ExportFormat::Jsonl,
+            &mut jsonl,
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        let row: serde_json::Value = serde_json::from_slice(&jsonl).unwrap();
+        assert_eq!(row["tags"], r#"["batch-eval","run-42"]"#);
+    }
+
+    #[test]
+    fn failed_requests_count_as_errors_but_not_cost() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let now = chrono::Utc::now();
+        let ok = UsageRecord {
+            model: "gpt-4o".to_string(),
+            upstream_id: "openai".to_string(),
+            total_tokens: 150,
+            price_usd: Some(0.02),
+            status: 200,
+            ..Default::default()
+        };
+        let failed = UsageRecord {
+            price_usd: Some(0.0),
+            total_tokens: 0,
+            status: 503,
+            failed: true,
+            endpoint: Some("https://api.openai.com/v1".to_string()),
+            error_class: Some("server_error".to_string()),
+            attempts: Some(3),
+            ..ok.clone()
+        };
+        insert_usage(&conn, &ok, now).unwrap();
+        insert_usage(&conn, &failed, now).unwrap();
+
+        let summary = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                from: Some(now.timestamp() - 60),
+                to: Some(now.timestamp() + 60),
+                group_by: Some("upstream".to_string()),
+                bucket: Some("day".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let row = &summary.rows[0];
+        assert_eq!((row.requests, row.errors), (2, 1));
+        assert!((row.error_rate - 0.5).abs() < 1e-9);
+
+        let top = top_models_with(&conn, "weekly", 5);
+        assert_eq!(top[0].requests, 1);
+        assert_eq!(spent_since_with(&conn, 0, Some(("model", "gpt-4o"))), Ok(0.02));
+        let bucketed: i64 = conn
+            .query_row("select requests from usage_daily", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(bucketed, 1);
+        let (success, class, attempts): (bool, String, i64) = conn
+            .query_row(
+                "select success, error_class, attempts from usage_logs where status = 503",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((success, class.as_str(), attempts), (false, "server_error", 3));
+    }
+
+    #[test]
+    fn summary_buckets_by_hour_and_limits_rows() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let summary = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                from: Some(from),
+                to: Some(to),
+                bucket: Some("hour".to_string()),
+                limit: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.rows.len(), 1);
+        assert_eq!(summary.rows[0].bucket.as_deref(), Some("2024-05-01T09:00:00Z"));
+        assert_eq!(summary.rows[0].requests, 2);
+        assert!(summary.truncated);
+    }
+
+    #[test]
+    fn spent_since_filters_by_group() {
+        let conn = seeded_conn();
+        let (from, _) = window();
+        let total = spent_since_with(&conn, from, None).unwrap();
+        assert!((total - 0.04).abs() < 1e-9);
+        let openai = spent_since_with(&conn, from, Some(("upstream", "OpenAI"))).unwrap();
+        assert!((openai - 0.04).abs() < 1e-9);
+        let claude = spent_since_with(&conn, from, Some(("model", "claude"))).unwrap();
+        assert_eq!(claude, 0.0);
+        assert!(spent_since_with(&conn, from + 86_400, None).unwrap() < 1e-9);
+        assert!(spent_since_with(&conn, from, Some(("tool", "test"))).is_err());
+    }
+
+    #[test]
+    fn synthetic_usage_is_logged_but_not_spent() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let probe = UsageRecord {
+            model: "gpt-4o".to_string(),
+            total_tokens: 2,
+            price_usd: Some(0.5),
+            status: 200,
+            synthetic: true,
+            ..Default::default()
+        };
+        let at = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 11, 0, 0).unwrap();
+        insert_usage(&conn, &probe, at).unwrap();
+        let rows: i64 = conn
+            .query_row("select count(*) from usage_logs", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 4);
+        let total = spent_since_with(&conn, from, None).unwrap();
+        assert!((total - 0.04).abs() < 1e-9);
+        let query = UsageSummaryQuery {
+            from: Some(from),
+            to: Some(to),
+            ..Default::default()
+        };
+        assert_eq!(usage_summary_with(&conn, &query).unwrap().rows[0].requests, 3);
+    }
+
+    #[test]
+    fn mirrors_are_reported_and_spent_but_not_usage() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let mirror = |status: u16, latency_ms: i64| MirrorRecord {
+            timestamp: from + 60,
+            model: "gpt-4o".to_string(),
+            upstream_id: "shadow".to_string(),
+            client_token: "...abcd".to_string(),
+            primary_upstream: "OpenAI".to_string(),
+            primary_status: 200,
+            primary_latency_ms: 400,
+            primary_completion_tokens: 50,
+            status,
+            latency_ms,
+            completion_tokens: 40,
+            price_usd: Some(0.01),
+            ..Default::default()
+        };
+        insert_mirror(&conn, &mirror(200, 300)).unwrap();
+        insert_mirror(&conn, &mirror(502, 30)).unwrap();
+
+        let report = mirror_report_with(&conn, from).unwrap();
+        assert_eq!(report.len(), 1);
+        let row = &report[0];
+        assert_eq!((row.requests, row.divergent), (2, 1));
+        assert_eq!(row.latency_delta_ms, Some(-100.0));
+        assert_eq!(row.avg_latency_ms, Some(300.0));
+        assert!((row.error_rate - 0.5).abs() < 1e-9);
+        assert_eq!(row.primary_error_rate, 0.0);
+
+        let total = spent_since_with(&conn, from, None).unwrap();
+        assert!((total - 0.06).abs() < 1e-9);
+        let shadow = spent_since_with(&conn, from, Some(("upstream", "shadow"))).unwrap();
+        assert!((shadow - 0.02).abs() < 1e-9);
+        assert_eq!(spent_since_with(&conn, from, Some(("key", "k1"))), Ok(0.0));
+        let query = UsageSummaryQuery {
+            from: Some(from),
+            to: Some(to),
+            ..Default::default()
+        };
+        assert_eq!(usage_summary_with(&conn, &query).unwrap().rows[0].requests, 3);
+    }
+
+    #[test]
+    fn experiment_report_totals_each_variant() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let now = chrono::Utc::now();
+        let row = |variant: &str, model: &str, latency_ms: i64| UsageRecord {
+            model: model.to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            total_tokens: 120,
+            price_usd: Some(0.01),
+            status: 200,
+            latency_ms: Some(latency_ms),
+            experiment: Some("mini-vs-4o".to_string()),
+            variant: Some(variant.to_string()),
+            ..Default::default()
+        };
+        insert_usage(&conn, &row("a", "gpt-4o", 800), now).unwrap();
+        insert_usage(&conn, &row("b", "gpt-4o-mini", 200), now).unwrap();
+        insert_usage(&conn, &row("b", "gpt-4o-mini", 400), now).unwrap();
+        let failed = UsageRecord {
+            status: 502,
+            failed: true,
+            price_usd: Some(0.0),
+            ..row("b", "gpt-4o-mini", 50)
+        };
+        insert_usage(&conn, &failed, now).unwrap();
+        insert_usage(&conn, &row("a", "gpt-4o", 100), now).unwrap();
+        conn.execute(
+            "update usage_logs set experiment = 'other' where id = 5",
+            [],
+        )
+        .unwrap();
+
+        let report = experiment_report_with(&conn, "mini-vs-4o").unwrap();
+        assert_eq!(report.len(), 2);
+        let (a, b) = (&report[0], &report[1]);
+        assert_eq!((a.variant.as_str(), a.requests), ("a", 1));
+        assert_eq!(a.models, vec!["gpt-4o".to_string()]);
+        assert_eq!((b.requests, b.completion_tokens), (3, 60));
+        assert_eq!(b.avg_latency_ms, Some(300.0));
+        assert!((b.error_rate - 1.0 / 3.0).abs() < 1e-9);
+        assert!((b.price_usd - 0.02).abs() < 1e-9);
+    }
+
+    #[test]
+    fn captured_requests_filter_and_hide_bodies_in_listing() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let entry = |model: &str, status: i64, timestamp: i64| CapturedRequest {
+            timestamp,
+            model: model.to_string(),
+            upstream_id: "openai".to_string(),
+            status: Some(status),
+            request_body: Some("{}".to_string()),
+            response_body: Some("{\"ok\":true}".to_string()),
+            ..Default::default()
+        };
+        insert_captured_request_with(&conn, &entry("gpt-4o", 200, 10)).unwrap();
+        let failed = insert_captured_request_with(&conn, &entry("gpt-4o", 400, 20)).unwrap();
+        insert_captured_request_with(&conn, &entry("claude", 200, 30)).unwrap();
+
+        let listed = list_captured_requests_with(
+            &conn,
+            &CapturedRequestQuery {
+                model: Some("gpt-4o".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].status, Some(400));
+        assert!(listed[0].request_body.is_none());
+
+        let only_errors = list_captured_requests_with(
+            &conn,
+            &CapturedRequestQuery {
+                status: Some(400),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(only_errors.len(), 1);
+
+        let detail = get_captured_request_with(&conn, failed).unwrap();
+        assert_eq!(detail.response_body.as_deref(), Some("{\"ok\":true}"));
+        assert!(get_captured_request_with(&conn, 9999).is_none());
+    }
+
+    #[test]
+    fn writer_keeps_every_row_under_concurrency() {
+        let path = std::env::temp_dir().join(format!("ccr-writer-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        optimize_connection(&conn);
+        init_schema(&conn);
+
+        let writer_path = path.clone();
+        let sender = start_writer(move || Connection::open(writer_path).unwrap());
+        let threads: Vec<_> = (0..20)
+            .map(|_| {
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let record = UsageRecord {
+                            model: "gpt-4o".to_string(),
+                            total_tokens: 10,
+                            price_usd: Some(0.001),
+                            status: 200,
+                            ..Default::default()
+                        };
+                        sender
+                            .send(WriteOp::Usage(Box::new(record), chrono::Utc::now()))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(flush_sender(&sender));
+
+        let rows: i64 = conn
+            .query_row("select count(*) from usage_logs", [], |r| r.get(0))
+            .unwrap();
+        let daily: i64 = conn
+            .query_row("select sum(requests) from usage_daily", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 500);
+        assert_eq!(daily, 500);
+        drop(sender);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn maintenance_rolls_up_before_pruning() {
+        let conn = seeded_conn();
+        let (from, _) = window();
+        // everything before 10:00 goes: the two gpt-4o rows
+        let report = run_maintenance_with(
+            &conn,
+            &RetentionPolicy {
+                usage_before: Some(from + 10 * 3600),
+                captures_before: Some(from),
+                rollup: true,
+                vacuum: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(report.usage_rows_deleted, 2);
+        assert_eq!(report.usage_rows_rolled_up, 2);
+        assert!(report.vacuumed);
+
+        let remaining: i64 = conn
+            .query_row("select count(*) from usage_logs", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(remaining, 1);
+        let (requests, errors, tokens, price): (i64, i64, i64, f64) = conn
+            .query_row(
+                "select requests, errors, total_tokens, price_usd from usage_rollup_daily where day = '2024-05-01' and model = 'gpt-4o'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((requests, errors, tokens), (2, 1, 300));
+        assert!((price - 0.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn summary_groups_by_api_key() {
+        let conn = seeded_conn();
+        let (from, to) = window();
+        let summary = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                from: Some(from),
+                to: Some(to),
+                group_by: Some("key".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let openai = summary
+            .rows
+            .iter()
+            .find(|r| r.group.as_deref() == Some("openai-key"))
+            .unwrap();
+        assert_eq!(openai.requests, 2);
+        assert_eq!(openai.total_tokens, 300);
+    }
+
+    #[test]
+    fn summary_respects_time_range_and_rejects_bad_group() {
+        let conn = seeded_conn();
+        let (from, _) = window();
+        let summary = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                from: Some(from),
+                to: Some(from + 10 * 3600),
+                group_by: Some("project".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.rows.len(), 1);
+        assert_eq!(summary.rows[0].group.as_deref(), Some("relay"));
+        assert_eq!(summary.rows[0].requests, 2);
+
+        let err = usage_summary_with(
+            &conn,
+            &UsageSummaryQuery {
+                group_by: Some("tool".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn response_cache_keeps_recently_used_answers() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn);
+        let store = |key: &str, body: &str| {
+            store_cached_response_with(
+                &conn,
+                &CachedResponse {
+                    key: key.to_string(),
+                    model: "gpt-4o".to_string(),
+                    body: body.to_string(),
+                    max_bytes: 10,
+                },
+            )
+            .unwrap()
+        };
+        store("a", "12345");
+        conn.execute("update response_cache set used_at = used_at - 10", []).unwrap();
+        store("b", "67890");
+        assert_eq!(cached_response_with(&conn, "a", 60).as_deref(), Some("12345"));
+
+        // "a" was used least recently and no longer fits
+        store("c", "abc");
+        assert!(cached_response_with(&conn, "a", 60).is_none());
+        assert_eq!(cached_response_with(&conn, "b", 60).as_deref(), Some("67890"));
+        assert_eq!(cached_response_with(&conn, "c", 60).as_deref(), Some("abc"));
+
+        conn.execute("update response_cache set created_at = created_at - 120", []).unwrap();
+        assert!(cached_response_with(&conn, "b", 60).is_none());
+    }
+
+    #[test]
+    fn csv_field_quotes_special_characters() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line1\nline2"), "\"line1\nline2\"");
+    }
+
+    #[test]
+    fn export_writes_csv_and_jsonl() {
+        let conn = seeded_conn();
+        let mut csv = Vec::new();
+        let count = export_usage_with(&conn, None, None, None, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(count, 3);
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("id,timestamp,channel"));
+        assert!(csv.contains(r#""{""user_agent"":""Mozilla/5.0 (X11, Linux)"#));
+        // header + 3 rows; the embedded newline stays inside a quoted field
+        assert_eq!(csv.matches("\r\n").count(), 4);
+
+        let mut jsonl = Vec::new();
+        export_usage_with(&conn, None, None, None, ExportFormat::Jsonl, &mut jsonl).unwrap();
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        let lines: Vec<_> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["model"], "gpt-4o");
+        assert_eq!(first["cache_read_tokens"], 20);
+    }
+}
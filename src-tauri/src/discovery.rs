@@ -0,0 +1,344 @@
+//! Finding relays on the local network with mDNS (Bonjour).
+//!
+//! With `discovery.advertise` on, [`advertise`] announces the running server
+//! as `_ai-relay._tcp` with a TXT record describing it: version, scheme,
+//! whether a forward token is required and the APIs it serves. A server that
+//! listens on a loopback address only is never announced.
+//!
+//! [`discover`] browses for other instances so the UI can list them, and
+//! [`adopt`] makes one of them the relay that tool auto-configuration points
+//! at (`discovery.remote_url`).
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{config, logger};
+
+const SERVICE_TYPE: &str = "_ai-relay._tcp.local.";
+/// APIs every relay serves, advertised as `api`.
+const APIS: &str = "openai,anthropic,gemini";
+
+/// Started on first use; it runs its own thread.
+static DAEMON: Lazy<Mutex<Option<ServiceDaemon>>> = Lazy::new(|| Mutex::new(None));
+/// Full name of this relay's advertisement, left out of [`discover`].
+static OWN: Mutex<Option<String>> = Mutex::new(None);
+
+fn daemon() -> Result<ServiceDaemon, String> {
+    let mut daemon = DAEMON.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(daemon) = daemon.as_ref() {
+        return Ok(daemon.clone());
+    }
+    let started =
+        ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS daemon: {}", e))?;
+    *daemon = Some(started.clone());
+    Ok(started)
+}
+
+/// A relay found on the network.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RemoteRelay {
+    pub name: String,
+    pub host: String,
+    pub addresses: Vec<String>,
+    pub port: u16,
+    /// Base URL on the first address, IPv4 preferred; what [`adopt`] takes
+    pub url: String,
+    pub version: Option<String>,
+    pub tls: bool,
+    /// Requests need the relay's forward token
+    pub auth: bool,
+    pub apis: Vec<String>,
+}
+
+/// Announcement of this relay, withdrawn on drop.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        *OWN.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            logger::debug(
+                "discovery",
+                &format!("Failed to withdraw advertisement: {}", e),
+            );
+        }
+    }
+}
+
+/// Announce the server bound to `addr`, if `discovery.advertise` is on and
+/// the address is reachable from other machines.
+pub fn advertise(addr: SocketAddr, tls: bool) -> Option<Advertisement> {
+    let cfg = config::load();
+    if !should_advertise(&cfg.discovery, addr) {
+        return None;
+    }
+    let name = instance_name(&cfg.discovery.name);
+    let result = daemon().and_then(|daemon| {
+        let host = format!("{}.local.", dns_label(&name));
+        let ip = if addr.ip().is_unspecified() {
+            String::new()
+        } else {
+            addr.ip().to_string()
+        };
+        let mut info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &name,
+            &host,
+            ip.as_str(),
+            addr.port(),
+            txt_record(&cfg, tls),
+        )
+        .map_err(|e| e.to_string())?;
+        if addr.ip().is_unspecified() {
+            info = info.enable_addr_auto();
+        }
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info).map_err(|e| e.to_string())?;
+        Ok(Advertisement { daemon, fullname })
+    });
+    match result {
+        Ok(advertisement) => {
+            logger::info(
+                "discovery",
+                &format!("Advertising '{}' on the local network", name),
+            );
+            *OWN.lock().unwrap_or_else(|e| e.into_inner()) = Some(advertisement.fullname.clone());
+            Some(advertisement)
+        }
+        Err(e) => {
+            logger::warn("discovery", &format!("Failed to advertise: {}", e));
+            None
+        }
+    }
+}
+
+/// Relays that answer within `wait`, this one excluded, sorted by name.
+/// Blocking.
+pub fn discover(wait: Duration) -> Result<Vec<RemoteRelay>, String> {
+    let daemon = daemon()?;
+    let events = daemon
+        .browse(SERVICE_TYPE)
+        .map_err(|e| format!("Failed to browse for relays: {}", e))?;
+    let own = OWN.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let deadline = Instant::now() + wait;
+    let mut found = BTreeMap::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let fullname = info.get_fullname().to_string();
+                if own.as_deref() == Some(fullname.as_str()) {
+                    continue;
+                }
+                let relay = remote_relay(
+                    &fullname,
+                    info.get_hostname(),
+                    info.get_addresses().iter().copied().collect(),
+                    info.get_port(),
+                    |key| info.get_property_val_str(key).map(str::to_string),
+                );
+                if let Some(relay) = relay {
+                    found.insert(relay.name.clone(), relay);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    if let Err(e) = daemon.stop_browse(SERVICE_TYPE) {
+        logger::debug("discovery", &format!("Failed to stop browsing: {}", e));
+    }
+    Ok(found.into_values().collect())
+}
+
+/// Point tool auto-configuration at the relay at `url`, authenticating with
+/// `token`. An empty `url` goes back to this relay.
+pub fn adopt(url: &str, token: &str) -> Result<(), String> {
+    let mut cfg = config::load_raw();
+    cfg.discovery.remote_url = url.trim().trim_end_matches('/').to_string();
+    cfg.discovery.remote_token = if cfg.discovery.remote_url.is_empty() {
+        String::new()
+    } else {
+        token.trim().to_string()
+    };
+    config::save(&cfg)
+}
+
+fn should_advertise(discovery: &config::DiscoveryConfig, addr: SocketAddr) -> bool {
+    if discovery.advertise && addr.ip().is_loopback() {
+        logger::info(
+            "discovery",
+            "Not advertising: the server listens on this machine only",
+        );
+    }
+    discovery.advertise && !addr.ip().is_loopback()
+}
+
+fn txt_record(cfg: &config::Settings, tls: bool) -> HashMap<String, String> {
+    let auth = cfg.forward_token.as_deref().is_some_and(|t| !t.is_empty());
+    HashMap::from([
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "scheme".to_string(),
+            if tls { "https" } else { "http" }.to_string(),
+        ),
+        (
+            "auth".to_string(),
+            if auth { "token" } else { "none" }.to_string(),
+        ),
+        ("api".to_string(), APIS.to_string()),
+    ])
+}
+
+fn remote_relay(
+    fullname: &str,
+    host: &str,
+    mut addresses: Vec<IpAddr>,
+    port: u16,
+    txt: impl Fn(&str) -> Option<String>,
+) -> Option<RemoteRelay> {
+    addresses.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+    let first = *addresses.first()?;
+    let tls = txt("scheme").as_deref() == Some("https");
+    let url = format!(
+        "{}://{}",
+        if tls { "https" } else { "http" },
+        SocketAddr::new(first, port)
+    );
+    let name = fullname
+        .strip_suffix(SERVICE_TYPE)
+        .map(|name| name.trim_end_matches('.'))
+        .unwrap_or(fullname)
+        .to_string();
+    Some(RemoteRelay {
+        name,
+        host: host.trim_end_matches('.').to_string(),
+        addresses: addresses.iter().map(|ip| ip.to_string()).collect(),
+        port,
+        url,
+        version: txt("version"),
+        tls,
+        auth: txt("auth").as_deref() != Some("none"),
+        apis: txt("api")
+            .map(|apis| apis.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+    })
+}
+
+/// `discovery.name`, or the host name when that is empty.
+fn instance_name(configured: &str) -> String {
+    let configured = configured.trim();
+    if !configured.is_empty() {
+        return configured.to_string();
+    }
+    host_name()
+        .and_then(|host| host.split('.').next().map(str::to_string))
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "ai-relay".to_string())
+}
+
+/// `name` as a DNS label: lowercase letters, digits and dashes.
+fn dns_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "ai-relay".to_string()
+    } else {
+        label.chars().take(63).collect()
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..end]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_txt_record_round_trip() {
+        let cfg = config::Settings {
+            forward_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let txt = txt_record(&cfg, true);
+        assert!(!txt.values().any(|v| v.contains("secret")));
+
+        let addresses = vec!["fe80::1".parse().unwrap(), "192.168.1.20".parse().unwrap()];
+        let relay = remote_relay(
+            "Desk PC._ai-relay._tcp.local.",
+            "desk-pc.local.",
+            addresses,
+            8787,
+            |key| txt.get(key).cloned(),
+        )
+        .unwrap();
+        assert_eq!(relay.name, "Desk PC");
+        assert_eq!(relay.host, "desk-pc.local");
+        assert_eq!(relay.url, "https://192.168.1.20:8787");
+        assert_eq!(relay.addresses, vec!["192.168.1.20", "fe80::1"]);
+        assert!(relay.tls && relay.auth);
+        assert_eq!(relay.apis, vec!["openai", "anthropic", "gemini"]);
+
+        let no_address = remote_relay("x", "x.local.", Vec::new(), 1, |_| None);
+        assert!(no_address.is_none());
+    }
+
+    #[test]
+    fn test_dns_label() {
+        assert_eq!(dns_label("Desk PC"), "desk-pc");
+        assert_eq!(dns_label("  "), "ai-relay");
+        assert_eq!(dns_label("测试"), "ai-relay");
+    }
+
+    #[test]
+    fn test_loopback_is_never_advertised() {
+        let discovery = config::DiscoveryConfig {
+            advertise: true,
+            ..Default::default()
+        };
+        let port = 8787;
+        assert!(!should_advertise(
+            &discovery,
+            SocketAddr::from(([127, 0, 0, 1], port))
+        ));
+        assert!(!should_advertise(&discovery, "[::1]:8787".parse().unwrap()));
+        assert!(should_advertise(
+            &discovery,
+            SocketAddr::from(([0, 0, 0, 0], port))
+        ));
+        assert!(!should_advertise(
+            &config::DiscoveryConfig::default(),
+            SocketAddr::from(([0, 0, 0, 0], port))
+        ));
+    }
+}
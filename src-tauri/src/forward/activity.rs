@@ -0,0 +1,293 @@
+//! Live feed of forwarded requests for the dashboard and
+//! `GET /api/activity/stream`.
+//!
+//! [`super::correlate`] opens a [`Tracker`] for every request. The request
+//! is announced as `started` once its first upstream attempt begins, so
+//! requests rejected before routing (bad token, unknown model) stay out of
+//! the feed. Streams report `first_token` with their first chunk; the
+//! tracker ends as `completed`, `failed` or, when the client goes away
+//! first, `cancelled`. Token counts come from the usage writer.
+//!
+//! Subscribers read through [`next_batch`], which coalesces a burst into one
+//! batch per [`THROTTLE`] holding the latest state of each request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Minimum time between two batches.
+pub const THROTTLE: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Started,
+    FirstToken,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// One lifecycle transition of a request.
+#[derive(Debug, Clone, Serialize)]
+pub struct Activity {
+    pub request_id: String,
+    pub phase: Phase,
+    pub model: String,
+    pub upstream_id: String,
+    pub streaming: bool,
+    /// Tokens recorded so far; streams usually report them at the end
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub elapsed_ms: u64,
+    /// Response status, once known
+    pub status: Option<u16>,
+    /// Last upstream error, redacted
+    pub error: Option<String>,
+    /// Tags the client put on the request
+    pub tags: Vec<String>,
+}
+
+struct Entry {
+    activity: Activity,
+    started: Instant,
+    announced: bool,
+}
+
+impl Entry {
+    fn snapshot(&self, phase: Phase) -> Activity {
+        Activity {
+            phase,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            ..self.activity.clone()
+        }
+    }
+}
+
+static ACTIVE: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static EVENTS: Lazy<broadcast::Sender<Activity>> = Lazy::new(|| broadcast::channel(1024).0);
+
+fn publish(activity: Activity) {
+    // No receivers is fine: nobody is watching
+    let _ = EVENTS.send(activity);
+}
+
+fn with_entry(request_id: &str, f: impl FnOnce(&mut Entry)) {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(entry) = active.get_mut(request_id) {
+        f(entry);
+    }
+}
+
+/// Follows one request until it is finished or dropped.
+pub struct Tracker {
+    request_id: String,
+    first_token: bool,
+    finished: bool,
+}
+
+/// Start tracking `request_id`. Nothing is published until [`attempt`].
+pub fn begin(request_id: &str) -> Tracker {
+    let entry = Entry {
+        activity: Activity {
+            request_id: request_id.to_string(),
+            phase: Phase::Started,
+            model: String::new(),
+            upstream_id: String::new(),
+            streaming: false,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            elapsed_ms: 0,
+            status: None,
+            error: None,
+            tags: Vec::new(),
+        },
+        started: Instant::now(),
+        announced: false,
+    };
+    ACTIVE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(request_id.to_string(), entry);
+    Tracker {
+        request_id: request_id.to_string(),
+        first_token: false,
+        finished: false,
+    }
+}
+
+impl Tracker {
+    /// Record the first chunk of a streamed response.
+    pub fn first_token(&mut self) {
+        if std::mem::replace(&mut self.first_token, true) {
+            return;
+        }
+        with_entry(&self.request_id, |entry| {
+            entry.activity.phase = Phase::FirstToken;
+            if entry.announced {
+                publish(entry.snapshot(Phase::FirstToken));
+            }
+        });
+    }
+
+    pub fn finish(&mut self, phase: Phase, status: Option<u16>) {
+        if std::mem::replace(&mut self.finished, true) {
+            return;
+        }
+        let entry = ACTIVE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.request_id);
+        if let Some(mut entry) = entry.filter(|e| e.announced) {
+            entry.activity.status = status.or(entry.activity.status);
+            publish(entry.snapshot(phase));
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.finish(Phase::Cancelled, None);
+    }
+}
+
+/// An upstream attempt is starting. The first one announces the request;
+/// fallbacks only update the upstream.
+pub fn attempt(request_id: &str, model: &str, upstream_id: &str, streaming: bool) {
+    with_entry(request_id, |entry| {
+        entry.activity.model = model.to_string();
+        entry.activity.upstream_id = upstream_id.to_string();
+        entry.activity.streaming = streaming;
+        if !std::mem::replace(&mut entry.announced, true) {
+            publish(entry.snapshot(Phase::Started));
+        }
+    });
+}
+
+/// The request carries `tags`; set before the first attempt is announced.
+pub fn tagged(request_id: &str, tags: &[String]) {
+    with_entry(request_id, |entry| {
+        entry.activity.tags = tags.to_vec();
+    });
+}
+
+/// An attempt failed with `error`; reported if the request ends as failed.
+pub fn attempt_failed(request_id: &str, error: &str) {
+    with_entry(request_id, |entry| {
+        entry.activity.error = Some(crate::redact::text(error));
+    });
+}
+
+/// Tokens recorded by the usage writer.
+pub fn usage(request_id: &str, upstream_id: &str, prompt_tokens: i64, completion_tokens: i64) {
+    with_entry(request_id, |entry| {
+        entry.activity.upstream_id = upstream_id.to_string();
+        entry.activity.prompt_tokens = prompt_tokens;
+        entry.activity.completion_tokens = completion_tokens;
+    });
+}
+
+/// Requests in flight, oldest first, as their latest state.
+pub fn in_flight() -> Vec<Activity> {
+    let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut entries: Vec<&Entry> = active.values().filter(|e| e.announced).collect();
+    entries.sort_by_key(|e| e.started);
+    entries
+        .into_iter()
+        .map(|e| e.snapshot(e.activity.phase))
+        .collect()
+}
+
+pub fn subscribe() -> broadcast::Receiver<Activity> {
+    EVENTS.subscribe()
+}
+
+/// Wait for the next transitions, then keep collecting for [`THROTTLE`].
+/// Several transitions of one request collapse into the latest. `None` once
+/// the feed is closed.
+pub async fn next_batch(receiver: &mut broadcast::Receiver<Activity>) -> Option<Vec<Activity>> {
+    let mut batch = Vec::new();
+    let first = loop {
+        match receiver.recv().await {
+            Ok(activity) => break activity,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    };
+    merge(&mut batch, first);
+    let deadline = tokio::time::Instant::now() + THROTTLE;
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(activity)) => merge(&mut batch, activity),
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+fn merge(batch: &mut Vec<Activity>, activity: Activity) {
+    match batch
+        .iter_mut()
+        .find(|a| a.request_id == activity.request_id)
+    {
+        Some(existing) => *existing = activity,
+        None => batch.push(activity),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases_of(batch: &[Activity], request_id: &str) -> Vec<Phase> {
+        batch
+            .iter()
+            .filter(|a| a.request_id == request_id)
+            .map(|a| a.phase)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_and_coalescing() {
+        let mut receiver = subscribe();
+
+        // Never routed: not part of the feed
+        drop(begin("activity-rejected"));
+
+        let mut tracker = begin("activity-stream");
+        attempt("activity-stream", "gpt-4o", "primary", true);
+        attempt_failed("activity-stream", "timeout");
+        attempt("activity-stream", "gpt-4o", "backup", true);
+        tracker.first_token();
+        tracker.first_token();
+        assert_eq!(phases_of(&in_flight(), "activity-stream").len(), 1);
+        usage("activity-stream", "backup", 12, 34);
+        drop(tracker);
+
+        let batch = next_batch(&mut receiver).await.unwrap();
+        assert!(phases_of(&batch, "activity-rejected").is_empty());
+        // started and first_token collapse into the final state
+        let done: Vec<&Activity> = batch
+            .iter()
+            .filter(|a| a.request_id == "activity-stream")
+            .collect();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].phase, Phase::Cancelled);
+        assert_eq!(done[0].upstream_id, "backup");
+        assert_eq!((done[0].prompt_tokens, done[0].completion_tokens), (12, 34));
+        assert_eq!(done[0].error.as_deref(), Some("timeout"));
+        assert!(phases_of(&in_flight(), "activity-stream").is_empty());
+
+        let mut tracker = begin("activity-plain");
+        attempt("activity-plain", "claude", "main", false);
+        tracker.finish(Phase::Completed, Some(200));
+        drop(tracker);
+        let batch = next_batch(&mut receiver).await.unwrap();
+        assert_eq!(phases_of(&batch, "activity-plain"), vec![Phase::Completed]);
+    }
+}
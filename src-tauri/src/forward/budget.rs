@@ -0,0 +1,526 @@
+//! Scoped spend budgets.
+//!
+//! Budgets are configured under `limits.budgets` and apply globally or to a
+//! single model, upstream, client token or project. Accumulated spend for the
+//! current period is cached in memory, seeded from the usage table on first
+//! use and bumped on every `log_usage`, so checks never aggregate the whole
+//! table on the hot path.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, TimeZone, Utc};
+use once_cell::sync::Lazy;
+
+use crate::config::{self, BudgetRule};
+use crate::{db, logger, webhooks};
+
+use super::context::{ForwardContext, ForwardPlan};
+use super::error::{ForwardError, ForwardResult};
+
+/// What a budget rule covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetScope {
+    Global,
+    Model,
+    Upstream,
+    Token,
+    Project,
+}
+
+impl BudgetScope {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "global" | "" => Some(BudgetScope::Global),
+            "model" => Some(BudgetScope::Model),
+            "upstream" => Some(BudgetScope::Upstream),
+            "token" => Some(BudgetScope::Token),
+            "project" => Some(BudgetScope::Project),
+            _ => None,
+        }
+    }
+
+    /// Group name understood by `db::spent_since`.
+    fn group(&self) -> Option<&'static str> {
+        match self {
+            BudgetScope::Global => None,
+            BudgetScope::Model => Some("model"),
+            BudgetScope::Upstream => Some("upstream"),
+            BudgetScope::Token => Some("token"),
+            BudgetScope::Project => Some("project"),
+        }
+    }
+}
+
+/// Length of a budget period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl BudgetPeriod {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "daily" | "day" => Some(BudgetPeriod::Daily),
+            "weekly" | "week" => Some(BudgetPeriod::Weekly),
+            "monthly" | "month" | "" => Some(BudgetPeriod::Monthly),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when a budget is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetAction {
+    Warn,
+    Downgrade,
+    Block,
+}
+
+impl BudgetAction {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "warn" => Some(BudgetAction::Warn),
+            "downgrade" => Some(BudgetAction::Downgrade),
+            "block" | "" => Some(BudgetAction::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Identity of a request as seen by budget rules.
+#[derive(Debug, Clone, Default)]
+pub struct SpendKey {
+    pub model: String,
+    pub upstream_id: String,
+    pub client_token: String,
+    pub project: Option<String>,
+}
+
+impl SpendKey {
+    pub fn from_context(ctx: &ForwardContext) -> Self {
+        Self {
+            model: ctx.model.upstream_model().to_string(),
+            upstream_id: ctx.upstream.id.clone(),
+            client_token: ctx.meta.client_token.clone(),
+            project: ctx.meta.project.clone(),
+        }
+    }
+
+    fn value_for(&self, scope: BudgetScope) -> Option<&str> {
+        match scope {
+            BudgetScope::Global => None,
+            BudgetScope::Model => Some(&self.model),
+            BudgetScope::Upstream => Some(&self.upstream_id),
+            BudgetScope::Token => Some(&self.client_token),
+            BudgetScope::Project => self.project.as_deref(),
+        }
+    }
+}
+
+struct CachedSpend {
+    scope: BudgetScope,
+    target: Option<String>,
+    period_start: i64,
+    spent: f64,
+    limit_usd: f64,
+    action: String,
+    warned: bool,
+}
+
+static SPEND_CACHE: Lazy<Mutex<HashMap<String, CachedSpend>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn rule_key(rule: &BudgetRule) -> String {
+    format!(
+        "{}:{}:{}",
+        rule.scope.to_lowercase(),
+        rule.target.as_deref().unwrap_or("*"),
+        rule.period.to_lowercase()
+    )
+}
+
+fn rule_matches(scope: BudgetScope, target: Option<&str>, key: &SpendKey) -> bool {
+    match scope {
+        BudgetScope::Global => true,
+        _ => match (target, key.value_for(scope)) {
+            (Some(target), Some(value)) => target.eq_ignore_ascii_case(value),
+            _ => false,
+        },
+    }
+}
+
+/// Start of the period containing `now`, in unix seconds.
+///
+/// Periods are aligned to local midnight at `utc_offset_minutes`; weekly
+/// periods start on Monday and monthly periods on `reset_day` (clamped to
+/// 1..=28 so every month has one).
+pub fn period_start(
+    period: BudgetPeriod,
+    now: DateTime<Utc>,
+    reset_day: u32,
+    utc_offset_minutes: i32,
+) -> i64 {
+    let offset = FixedOffset::east_opt(utc_offset_minutes.clamp(-14 * 60, 14 * 60) * 60)
+        .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    let local = now.with_timezone(&offset);
+    let today = local.date_naive();
+    let start_date = match period {
+        BudgetPeriod::Daily => today,
+        BudgetPeriod::Weekly => {
+            today - Duration::days(today.weekday().num_days_from_monday() as i64)
+        }
+        BudgetPeriod::Monthly => {
+            let day = reset_day.clamp(1, 28);
+            if today.day() >= day {
+                NaiveDate::from_ymd_opt(today.year(), today.month(), day).unwrap_or(today)
+            } else {
+                let (year, month) = if today.month() == 1 {
+                    (today.year() - 1, 12)
+                } else {
+                    (today.year(), today.month() - 1)
+                };
+                NaiveDate::from_ymd_opt(year, month, day).unwrap_or(today)
+            }
+        }
+    };
+    let midnight = start_date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    offset
+        .from_local_datetime(&midnight)
+        .single()
+        .map(|t| t.timestamp())
+        .unwrap_or_else(|| now.timestamp())
+}
+
+struct ParsedRule<'a> {
+    rule: &'a BudgetRule,
+    scope: BudgetScope,
+    period: BudgetPeriod,
+    action: BudgetAction,
+}
+
+fn parse_rule(rule: &BudgetRule) -> Option<ParsedRule<'_>> {
+    let parsed = ParsedRule {
+        rule,
+        scope: BudgetScope::from_str(&rule.scope)?,
+        period: BudgetPeriod::from_str(&rule.period)?,
+        action: BudgetAction::from_str(&rule.action)?,
+    };
+    Some(parsed)
+}
+
+/// The configured rules, plus a monthly block rule for every project with a
+/// budget of its own.
+fn rules(limits: &config::RateLimitConfig) -> Vec<BudgetRule> {
+    let mut rules = limits.budgets.clone();
+    rules.extend(
+        crate::projects::budgets()
+            .into_iter()
+            .map(|(name, limit_usd)| BudgetRule {
+                scope: "project".to_string(),
+                target: Some(name),
+                limit_usd,
+                ..BudgetRule::default()
+            }),
+    );
+    rules
+}
+
+/// Current-period spend for a rule, seeding the cache from the database when
+/// the rule is new or its period rolled over.
+fn current_spend(rule: &ParsedRule<'_>, limits: &config::RateLimitConfig) -> (i64, f64, bool) {
+    let start = period_start(
+        rule.period,
+        Utc::now(),
+        limits.budget_reset_day,
+        limits.budget_utc_offset_minutes,
+    );
+    let key = rule_key(rule.rule);
+    {
+        let cache = SPEND_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = cache.get(&key) {
+            if entry.period_start == start {
+                return (start, entry.spent, entry.warned);
+            }
+        }
+    }
+
+    let filter = rule.scope.group().zip(rule.rule.target.as_deref());
+    let spent = db::spent_since(start, filter);
+    let mut cache = SPEND_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.insert(
+        key,
+        CachedSpend {
+            scope: rule.scope,
+            target: rule.rule.target.clone(),
+            period_start: start,
+            spent,
+            limit_usd: rule.rule.limit_usd,
+            action: rule.rule.action.clone(),
+            warned: false,
+        },
+    );
+    (start, spent, false)
+}
+
+fn mark_warned(rule: &BudgetRule) {
+    let mut cache = SPEND_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(entry) = cache.get_mut(&rule_key(rule)) {
+        entry.warned = true;
+    }
+}
+
+/// Add a completed request's cost to every cached counter it belongs to and
+/// announce budgets whose limit this request crossed.
+pub fn record_spend(key: &SpendKey, cost: f64) {
+    if cost <= 0.0 {
+        return;
+    }
+    let mut crossed = Vec::new();
+    {
+        let mut cache = SPEND_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        for (rule, entry) in cache.iter_mut() {
+            if rule_matches(entry.scope, entry.target.as_deref(), key) {
+                let before = entry.spent;
+                entry.spent += cost;
+                if before < entry.limit_usd && entry.spent >= entry.limit_usd {
+                    crossed.push(webhooks::BudgetEvent {
+                        rule: rule.clone(),
+                        spent_usd: entry.spent,
+                        limit_usd: entry.limit_usd,
+                        action: entry.action.clone(),
+                    });
+                }
+            }
+        }
+    }
+    if !crossed.is_empty() {
+        crate::relay_state::poke();
+    }
+    for event in crossed {
+        webhooks::emit(webhooks::WebhookEvent::BudgetExceeded(event));
+    }
+}
+
+/// Forget cached counters (after data is cleared or budgets are edited).
+pub fn reset_cache() {
+    SPEND_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+}
+
+/// Outcome of checking one request against all budgets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetDecision {
+    Allow,
+    Downgrade { model: String, reason: String },
+    Block(String),
+}
+
+/// Evaluate every exhausted budget that covers `key`; the most severe
+/// action wins. Warnings are logged once per rule and period.
+pub fn evaluate(key: &SpendKey, limits: &config::RateLimitConfig) -> BudgetDecision {
+    let mut decision = BudgetDecision::Allow;
+    for rule in &rules(limits) {
+        let Some(parsed) = parse_rule(rule) else {
+            logger::warn(
+                "budget",
+                &format!("Ignoring invalid budget rule '{}'", rule_key(rule)),
+            );
+            continue;
+        };
+        if !rule_matches(parsed.scope, rule.target.as_deref(), key) {
+            continue;
+        }
+        let (_, spent, warned) = current_spend(&parsed, limits);
+        if spent < rule.limit_usd {
+            continue;
+        }
+        let reason = format!(
+            "{} budget '{}' exhausted: spent ${:.4} / limit ${:.4}",
+            rule.period,
+            rule_key(rule),
+            spent,
+            rule.limit_usd
+        );
+        match parsed.action {
+            BudgetAction::Warn => {
+                if !warned {
+                    logger::warn("budget", &reason);
+                    mark_warned(rule);
+                }
+            }
+            BudgetAction::Downgrade => match rule.fallback_model.as_deref() {
+                Some(model) if !model.eq_ignore_ascii_case(&key.model) => {
+                    if !matches!(decision, BudgetDecision::Block(_)) {
+                        decision = BudgetDecision::Downgrade {
+                            model: model.to_string(),
+                            reason,
+                        };
+                    }
+                }
+                _ => decision = BudgetDecision::Block(reason),
+            },
+            BudgetAction::Block => decision = BudgetDecision::Block(reason),
+        }
+    }
+    decision
+}
+
+/// Apply budgets to a freshly built plan. Routes whose budget is blocked are
+/// dropped; a downgrade rebuilds the plan once via `rebuild` for the fallback
+/// model, and a plan with no usable route is rejected with `BudgetExceeded`.
+pub fn enforce<F>(
+    plan: ForwardPlan,
+    limits: &config::RateLimitConfig,
+    rebuild: F,
+) -> ForwardResult<ForwardPlan>
+where
+    F: Fn(&str) -> ForwardResult<ForwardPlan>,
+{
+    if rules(limits).is_empty() {
+        return Ok(plan);
+    }
+
+    match filter_plan(plan, limits)? {
+        Ok(plan) => Ok(plan),
+        Err((model, reason)) => {
+            logger::info(
+                "budget",
+                &format!("{}; downgrading to model '{}'", reason, model),
+            );
+            let downgraded = rebuild(&model)?;
+            match filter_plan(downgraded, limits)? {
+                Ok(plan) => Ok(plan),
+                Err((_, reason)) => Err(ForwardError::BudgetExceeded(reason)),
+            }
+        }
+    }
+}
+
+type FilteredPlan = Result<ForwardPlan, (String, String)>;
+
+fn filter_plan(plan: ForwardPlan, limits: &config::RateLimitConfig) -> ForwardResult<FilteredPlan> {
+    let mut last_block = None;
+    let mut downgrade = None;
+    let mut allowed = Vec::new();
+    for ctx in std::iter::once(plan.primary).chain(plan.fallbacks) {
+        match evaluate(&SpendKey::from_context(&ctx), limits) {
+            BudgetDecision::Allow => allowed.push(ctx),
+            BudgetDecision::Downgrade { model, reason } => {
+                downgrade.get_or_insert((model, reason));
+            }
+            BudgetDecision::Block(reason) => last_block = Some(reason),
+        }
+    }
+
+    if allowed.is_empty() {
+        if let Some(downgrade) = downgrade {
+            return Ok(Err(downgrade));
+        }
+        return Err(ForwardError::BudgetExceeded(
+            last_block.unwrap_or_else(|| "Budget exceeded".to_string()),
+        ));
+    }
+    let mut contexts = allowed.into_iter();
+    let primary = contexts.next().expect("allowed is not empty");
+    Ok(Ok(ForwardPlan {
+        primary,
+        fallbacks: contexts.collect(),
+    }))
+}
+
+/// Consumption of one configured budget, for the UI.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BudgetStatus {
+    pub scope: String,
+    pub target: Option<String>,
+    pub period: String,
+    pub action: String,
+    pub limit_usd: f64,
+    pub spent_usd: f64,
+    pub remaining_usd: f64,
+    pub period_start: i64,
+    pub exceeded: bool,
+    pub valid: bool,
+}
+
+pub fn status() -> Vec<BudgetStatus> {
+    let limits = config::load().limits;
+    rules(&limits)
+        .iter()
+        .map(|rule| {
+            let (period_start, spent, valid) = match parse_rule(rule) {
+                Some(parsed) => {
+                    let (start, spent, _) = current_spend(&parsed, &limits);
+                    (start, spent, true)
+                }
+                None => (0, 0.0, false),
+            };
+            BudgetStatus {
+                scope: rule.scope.clone(),
+                target: rule.target.clone(),
+                period: rule.period.clone(),
+                action: rule.action.clone(),
+                limit_usd: rule.limit_usd,
+                spent_usd: spent,
+                remaining_usd: (rule.limit_usd - spent).max(0.0),
+                period_start,
+                exceeded: valid && spent >= rule.limit_usd,
+                valid,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_period_start_daily_with_offset() {
+        // 2024-03-10 02:00 UTC is still 2024-03-09 in UTC-5
+        let start = period_start(BudgetPeriod::Daily, at(2024, 3, 10, 2), 1, -300);
+        assert_eq!(start, at(2024, 3, 9, 5).timestamp());
+    }
+
+    #[test]
+    fn test_period_start_monthly_reset_day() {
+        let before = period_start(BudgetPeriod::Monthly, at(2024, 1, 10, 12), 15, 0);
+        assert_eq!(before, at(2023, 12, 15, 0).timestamp());
+        let after = period_start(BudgetPeriod::Monthly, at(2024, 1, 20, 12), 15, 0);
+        assert_eq!(after, at(2024, 1, 15, 0).timestamp());
+        // reset days past 28 are clamped
+        let clamped = period_start(BudgetPeriod::Monthly, at(2024, 2, 29, 12), 31, 0);
+        assert_eq!(clamped, at(2024, 2, 28, 0).timestamp());
+    }
+
+    #[test]
+    fn test_period_start_weekly() {
+        // 2024-05-02 is a Thursday
+        let start = period_start(BudgetPeriod::Weekly, at(2024, 5, 2, 9), 1, 0);
+        assert_eq!(start, at(2024, 4, 29, 0).timestamp());
+    }
+
+    #[test]
+    fn test_rule_matches_scopes() {
+        let key = SpendKey {
+            model: "gpt-4o".to_string(),
+            upstream_id: "openai".to_string(),
+            client_token: "ccr_...abcd".to_string(),
+            project: None,
+        };
+        assert!(rule_matches(BudgetScope::Global, None, &key));
+        assert!(rule_matches(BudgetScope::Model, Some("GPT-4o"), &key));
+        assert!(!rule_matches(BudgetScope::Upstream, Some("anthropic"), &key));
+        assert!(!rule_matches(BudgetScope::Project, Some("relay"), &key));
+        assert!(!rule_matches(BudgetScope::Model, None, &key));
+    }
+}
@@ -0,0 +1,220 @@
+//! Optional cache of non-streaming answers.
+//!
+//! With `response_cache.enabled`, a request meant to be reproducible
+//! (`temperature` 0 or a `seed`) is answered from the `response_cache`
+//! table when the same request was answered within `ttl_secs`. The key is a
+//! hash of the model, its upstream name and the payload as the handlers see
+//! it, project prompt included. Clients steer it with `x-relay-cache`: `use`
+//! caches a request that isn't deterministic, `bypass` neither reads nor
+//! stores, `refresh` stores a new answer without reading. Answers carry
+//! `x-relay-cache: hit` or `miss`, and hits are logged at no cost.
+//!
+//! Streams, requests carrying tool results and requests whose tools the
+//! relay runs itself are never cached.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::http::HeaderValue;
+use axum::response::Response;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::context::ForwardContext;
+use crate::{config, db};
+
+pub const HEADER: &str = "x-relay-cache";
+
+/// What the client's `x-relay-cache` asks for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Directive {
+    /// Deterministic requests only
+    #[default]
+    Auto,
+    Use,
+    Bypass,
+    Refresh,
+}
+
+impl Directive {
+    /// Unknown values count as no header at all.
+    pub fn parse(value: &str) -> Self {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "use" => Directive::Use,
+            "bypass" => Directive::Bypass,
+            "refresh" => Directive::Refresh,
+            _ => Directive::Auto,
+        }
+    }
+}
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static STORED: AtomicU64 = AtomicU64::new(0);
+
+/// Lookups since the server started and what the cache holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+    pub stored: u64,
+    /// Hits over lookups; 0 before the first
+    pub hit_rate: f64,
+    pub entries: i64,
+    pub bytes: i64,
+}
+
+/// Blocking: it reads the size of the cache from the database.
+pub fn stats() -> CacheStats {
+    let (hits, misses) = (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed));
+    let (entries, bytes) = db::response_cache_size();
+    CacheStats {
+        enabled: config::current().response_cache.enabled,
+        hits,
+        misses,
+        stored: STORED.load(Ordering::Relaxed),
+        hit_rate: if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        },
+        entries,
+        bytes,
+    }
+}
+
+/// The key `payload` is cached under for `ctx`, or `None` if it isn't
+/// cached at all.
+pub fn key_for(ctx: &ForwardContext, payload: &Value) -> Option<String> {
+    let directive = ctx.meta.cache;
+    if !config::current().response_cache.enabled
+        || ctx.is_streaming
+        || directive == Directive::Bypass
+        || carries_tool_results(payload)
+        || (directive == Directive::Auto && !is_deterministic(payload))
+    {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for part in [ctx.model.id.as_str(), ctx.model.upstream_model()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    // Object keys serialize sorted, so equal payloads hash alike
+    hasher.update(payload.to_string().as_bytes());
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+/// `temperature` 0 or a fixed `seed`, in any dialect.
+fn is_deterministic(payload: &Value) -> bool {
+    let params = [Some(payload), payload.get("generationConfig")];
+    params.into_iter().flatten().any(|params| {
+        params.get("temperature").and_then(Value::as_f64) == Some(0.0)
+            || params.get("seed").is_some_and(|seed| !seed.is_null())
+    })
+}
+
+/// OpenAI `tool` messages, Anthropic `tool_result` blocks or Gemini
+/// `functionResponse` parts.
+fn carries_tool_results(payload: &Value) -> bool {
+    let turns = ["messages", "contents"]
+        .into_iter()
+        .filter_map(|field| payload.get(field).and_then(Value::as_array))
+        .flatten();
+    turns.into_iter().any(|turn| {
+        let role = turn.get("role").and_then(Value::as_str);
+        let parts = ["content", "parts"]
+            .into_iter()
+            .filter_map(|field| turn.get(field).and_then(Value::as_array))
+            .flatten();
+        matches!(role, Some("tool" | "function"))
+            || parts.into_iter().any(|part| {
+                part.get("type").and_then(Value::as_str) == Some("tool_result")
+                    || part.get("functionResponse").is_some()
+            })
+    })
+}
+
+/// The cached answer for `key`, unless the client asked for a fresh one.
+pub async fn lookup(ctx: &ForwardContext, key: &str) -> Option<Value> {
+    if ctx.meta.cache == Directive::Refresh {
+        return None;
+    }
+    let ttl = config::current().response_cache.ttl_secs;
+    let owned = key.to_string();
+    let body = tokio::task::spawn_blocking(move || db::cached_response(&owned, ttl))
+        .await
+        .ok()
+        .flatten()
+        .and_then(|body| serde_json::from_str(&body).ok());
+    match body {
+        Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+        None => MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+    body
+}
+
+/// Keep `body`, the answer of `ctx`'s upstream, under `key`.
+pub fn store(key: &str, ctx: &ForwardContext, body: &Value) {
+    let max_mb = config::current().response_cache.max_mb;
+    STORED.fetch_add(1, Ordering::Relaxed);
+    db::store_cached_response(db::CachedResponse {
+        key: key.to_string(),
+        model: ctx.model.id.clone(),
+        body: body.to_string(),
+        max_bytes: (max_mb * 1024 * 1024) as i64,
+    });
+}
+
+/// Tell the client whether its answer came from the cache.
+pub fn mark(mut response: Response, hit: bool) -> Response {
+    let value = if hit { "hit" } else { "miss" };
+    response
+        .headers_mut()
+        .insert(HEADER, HeaderValue::from_static(value));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_only_deterministic_requests_without_tool_results_qualify() {
+        assert_eq!(Directive::parse(" Refresh "), Directive::Refresh);
+        assert_eq!(Directive::parse("always"), Directive::Auto);
+
+        let openai = json!({"model": "gpt-4o", "temperature": 0, "messages": [
+            {"role": "user", "content": "Name a prime."}
+        ]});
+        assert!(is_deterministic(&openai));
+        assert!(!carries_tool_results(&openai));
+        assert!(is_deterministic(&json!({"seed": 7, "temperature": 0.7})));
+        assert!(!is_deterministic(&json!({"temperature": 0.7})));
+        assert!(is_deterministic(
+            &json!({"generationConfig": {"temperature": 0.0}})
+        ));
+
+        assert!(carries_tool_results(&json!({"messages": [
+            {"role": "tool", "tool_call_id": "call_1", "content": "42"}
+        ]})));
+        assert!(carries_tool_results(&json!({"messages": [
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": "42"}
+            ]}
+        ]})));
+        assert!(carries_tool_results(&json!({"contents": [
+            {"role": "user", "parts": [
+                {"functionResponse": {"name": "lookup", "response": {"value": 42}}}
+            ]}
+        ]})));
+    }
+}
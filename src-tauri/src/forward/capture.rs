@@ -0,0 +1,476 @@
+//! Optional capture of upstream exchanges for debugging conversions.
+//!
+//! When `capture.mode` is not `off`, each forwarding attempt runs inside a
+//! [`scope`]. The HTTP helpers call [`record_request`] with the transformed
+//! request they are about to send, and when the attempt finishes the entry is
+//! redacted (see [`crate::redact`]), truncated and queued for the database writer so the hot path
+//! never waits on SQLite. Old captures are pruned by the
+//! maintenance job according to `capture.retention_days`.
+//!
+//! Streamed responses are recorded without a response body.
+//!
+//! The same scope records replay fixtures when `capture.fixtures` is on (see
+//! [`super::fixtures`]): [`record_response`] and [`tap`] hand it the
+//! upstream's answer, streamed or not.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Instant;
+
+use axum::body::Bytes;
+use axum::response::Response;
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+
+use crate::redact::REDACTED;
+use crate::{config, db, redact};
+
+use super::context::{ForwardContext, UpstreamResponse};
+use super::error::ForwardResult;
+use super::fixtures::Recording;
+
+/// How much of each exchange is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    Off,
+    Headers,
+    Full,
+}
+
+impl CaptureMode {
+    pub fn from_str(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "headers" => CaptureMode::Headers,
+            "full" => CaptureMode::Full,
+            _ => CaptureMode::Off,
+        }
+    }
+}
+
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "x-goog-api-key",
+    "api-key",
+    "cookie",
+    "x-ccr-forward-token",
+];
+
+const SENSITIVE_FIELDS: &[&str] = &["api_key", "apikey", "access_token", "secret", "password"];
+
+static DATA_URL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"data:([\w.+\-]+/[\w.+\-]+);base64,([A-Za-z0-9+/=]+)").unwrap());
+
+tokio::task_local! {
+    static CURRENT: Arc<Mutex<Pending>>;
+}
+
+/// Which route an attempt is going to; taken before the context moves into
+/// the handler.
+pub struct Target {
+    pub model: String,
+    pub upstream_id: String,
+    pub streaming: bool,
+    pub request_id: String,
+    /// Project of the request, for the prompt redaction rules
+    pub project: Option<String>,
+    /// Upstream key the attempt uses, for its rate limits
+    pub api_key_id: Option<String>,
+    /// Fixture recorded of the attempt, see [`Target::recorded`]
+    pub fixture: Option<Arc<Recording>>,
+}
+
+impl Target {
+    pub fn of(ctx: &ForwardContext) -> Self {
+        Self {
+            model: ctx.model.upstream_model().to_string(),
+            upstream_id: ctx.upstream.id.clone(),
+            streaming: ctx.is_streaming,
+            request_id: ctx.meta.request_id.clone(),
+            project: ctx.meta.project.clone(),
+            api_key_id: ctx.api_key_id(),
+            fixture: None,
+        }
+    }
+
+    /// Like [`Target::of`], also recording a fixture of the handler
+    /// answering `payload` when fixtures are on.
+    pub fn recorded(ctx: &ForwardContext, payload: &Value) -> Self {
+        Self {
+            fixture: Recording::start(ctx, payload),
+            ..Self::of(ctx)
+        }
+    }
+}
+
+struct Pending {
+    mode: CaptureMode,
+    max_bytes: usize,
+    target: Target,
+    url: Option<String>,
+    request_headers: Option<String>,
+    request_body: Option<String>,
+    /// The stored request body differs from the one sent
+    request_redacted: bool,
+}
+
+/// Outcome of a forwarding attempt as seen by the capture log.
+pub trait Captured {
+    fn status(&self) -> u16;
+    fn response_body(&self) -> Option<String>;
+}
+
+impl Captured for UpstreamResponse {
+    fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn response_body(&self) -> Option<String> {
+        Some(self.body.to_string())
+    }
+}
+
+impl Captured for Response {
+    fn status(&self) -> u16 {
+        self.status().as_u16()
+    }
+
+    fn response_body(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Run one forwarding attempt with capture enabled (if configured).
+pub async fn scope<T, F>(target: Target, fut: F) -> ForwardResult<T>
+where
+    T: Captured,
+    F: Future<Output = ForwardResult<T>>,
+{
+    let cfg = config::load().capture;
+    let mode = CaptureMode::from_str(&cfg.mode);
+    if mode == CaptureMode::Off && target.fixture.is_none() {
+        return fut.await;
+    }
+
+    let fixture = target.fixture.clone();
+    let pending = Arc::new(Mutex::new(Pending {
+        mode,
+        max_bytes: cfg.max_bytes.max(256),
+        target,
+        url: None,
+        request_headers: None,
+        request_body: None,
+        request_redacted: false,
+    }));
+    let started = Instant::now();
+    let result = CURRENT.scope(pending.clone(), fut).await;
+    let latency_ms = started.elapsed().as_millis() as i64;
+    if let (Some(fixture), Err(_)) = (&fixture, &result) {
+        fixture.fail();
+    }
+    if mode == CaptureMode::Off {
+        return result;
+    }
+
+    let (status, response, error) = match &result {
+        Ok(resp) => (Some(resp.status() as i64), resp.response_body(), None),
+        Err(err) => {
+            let message = err.to_string();
+            let status = err.upstream_status().map(|s| s as i64);
+            (status, None, Some(message))
+        }
+    };
+
+    let entry = {
+        let pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        build_entry(&pending, status, latency_ms, response, error)
+    };
+    db::log_captured_request(entry);
+
+    result
+}
+
+/// Remember the transformed request of the current attempt. No-op outside a
+/// capture [`scope`].
+pub fn record_request(url: &str, headers: &HeaderMap, body: &Value) {
+    let _ = CURRENT.try_with(|pending| {
+        let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.url = Some(redact::text(url));
+        pending.request_headers = Some(redact_headers(headers));
+        if pending.mode == CaptureMode::Full {
+            let mut stored = body.clone();
+            redact_value(&mut stored);
+            pending.request_redacted = stored != *body;
+            pending.request_body = Some(stored.to_string());
+        }
+        if let Some(fixture) = &pending.target.fixture {
+            fixture.request(url, body);
+        }
+    });
+}
+
+/// Remember the non-streaming answer of the current attempt for its fixture.
+/// No-op unless one is being recorded.
+pub fn record_response(headers: &HeaderMap, text: &str) {
+    if let Some(fixture) = current_fixture() {
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        fixture.response(content_type, text);
+    }
+}
+
+/// `stream`, with its chunks added to the fixture of the current attempt
+/// when one is being recorded. The fixture is complete once the stream ends.
+pub fn tap<S, E>(stream: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let fixture = current_fixture();
+    let mut end = fixture.clone();
+    stream
+        .inspect(move |item| {
+            if let Some(fixture) = &fixture {
+                match item {
+                    Ok(bytes) => fixture.chunk(bytes),
+                    Err(_) => fixture.fail(),
+                }
+            }
+        })
+        .chain(futures_util::stream::poll_fn(move |_| {
+            if let Some(fixture) = end.take() {
+                fixture.finish();
+            }
+            Poll::Ready(None)
+        }))
+}
+
+fn current_fixture() -> Option<Arc<Recording>> {
+    CURRENT
+        .try_with(|pending| {
+            let pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+            pending.target.fixture.clone()
+        })
+        .ok()
+        .flatten()
+}
+
+fn build_entry(
+    pending: &Pending,
+    status: Option<i64>,
+    latency_ms: i64,
+    response: Option<String>,
+    error: Option<String>,
+) -> db::CapturedRequest {
+    let mut truncated = false;
+    let mut limit = |text: Option<String>| {
+        text.map(|t| {
+            let (t, cut) = truncate(t, pending.max_bytes);
+            truncated |= cut;
+            t
+        })
+    };
+    let (request_body, response_body) = if pending.mode == CaptureMode::Full {
+        (
+            limit(pending.request_body.clone()),
+            limit(response.map(|r| redact_text(&r))),
+        )
+    } else {
+        (None, None)
+    };
+    let error = limit(error.map(|e| redact_text(&e)));
+
+    db::CapturedRequest {
+        id: 0,
+        timestamp: chrono::Utc::now().timestamp(),
+        model: pending.target.model.clone(),
+        upstream_id: pending.target.upstream_id.clone(),
+        url: pending.url.clone(),
+        streaming: pending.target.streaming,
+        status,
+        latency_ms: Some(latency_ms),
+        request_headers: pending.request_headers.clone(),
+        request_body,
+        response_body,
+        error,
+        truncated,
+        request_id: Some(pending.target.request_id.clone()),
+        redacted: pending.request_redacted,
+    }
+}
+
+fn redact_headers(headers: &HeaderMap) -> String {
+    let map: serde_json::Map<String, Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str().to_string();
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                redact_text(&String::from_utf8_lossy(value.as_bytes()))
+            };
+            (name, Value::String(value))
+        })
+        .collect();
+    Value::Object(map).to_string()
+}
+
+/// Strip inline base64 payloads and anything that looks like an API key.
+pub(super) fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                if child.is_string() && SENSITIVE_FIELDS.contains(&key.to_lowercase().as_str()) {
+                    *child = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(child);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        Value::String(s) => {
+            if looks_like_base64_blob(s) {
+                *s = format!("[base64 {} bytes]", s.len());
+            } else {
+                let redacted = redact_str(s);
+                if redacted != *s {
+                    *s = redacted;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Redact a body that may or may not be JSON (SSE, error text, ...).
+fn redact_text(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) if value.is_object() || value.is_array() => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        _ => redact_str(text),
+    }
+}
+
+pub(super) fn redact_str(text: &str) -> String {
+    let text = DATA_URL_RE.replace_all(text, |caps: &regex::Captures<'_>| {
+        format!("data:{};base64,[{} bytes]", &caps[1], caps[2].len())
+    });
+    redact::text(&text)
+}
+
+fn looks_like_base64_blob(s: &str) -> bool {
+    s.len() >= 512
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_'))
+}
+
+fn truncate(mut text: String, max_bytes: usize) -> (String, bool) {
+    if text.len() <= max_bytes {
+        return (text, false);
+    }
+    let mut cut = max_bytes;
+    while !text.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    text.truncate(cut);
+    text.push_str("…[truncated]");
+    (text, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_redact_value_strips_images_and_keys() {
+        let blob = "A".repeat(600);
+        let mut body = serde_json::json!({
+            "messages": [{
+                "content": [
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                    {"type": "image", "source": {"type": "base64", "data": blob}},
+                    {"type": "text", "text": "my key is sk-abcdefghijklmnopqrstuv"}
+                ]
+            }],
+            "api_key": "secret"
+        });
+        redact_value(&mut body);
+        let content = &body["messages"][0]["content"];
+        assert_eq!(content[0]["image_url"]["url"], "data:image/png;base64,[12 bytes]");
+        assert_eq!(content[1]["source"]["data"], "[base64 600 bytes]");
+        assert_eq!(content[2]["text"], "my key is [REDACTED]");
+        assert_eq!(body["api_key"], REDACTED);
+    }
+
+    #[test]
+    fn test_redact_headers_masks_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer sk-live"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let json: Value = serde_json::from_str(&redact_headers(&headers)).unwrap();
+        assert_eq!(json["authorization"], REDACTED);
+        assert_eq!(json["content-type"], "application/json");
+    }
+
+    #[test]
+    fn test_seeded_key_never_captured() {
+        let key = "AIzaSeededKey0123456789abcdefghijklmn";
+        let mut headers = HeaderMap::new();
+        headers.insert("x-goog-api-key", HeaderValue::from_str(key).unwrap());
+        headers.insert(
+            "x-debug",
+            HeaderValue::from_str(&format!("k={}", key)).unwrap(),
+        );
+        let pending = Pending {
+            mode: CaptureMode::Full,
+            max_bytes: 4096,
+            target: Target {
+                model: "gemini-pro".to_string(),
+                upstream_id: "g".to_string(),
+                streaming: false,
+                request_id: "01J0000000000000000000TEST".to_string(),
+                project: None,
+                api_key_id: None,
+                fixture: None,
+            },
+            url: Some(redact::text(&format!(
+                "https://host/v1beta/models/gemini-pro:generateContent?key={}",
+                key
+            ))),
+            request_headers: Some(redact_headers(&headers)),
+            request_body: None,
+            request_redacted: false,
+        };
+        let entry = build_entry(
+            &pending,
+            Some(400),
+            10,
+            Some(format!(
+                "{{\"error\":{{\"message\":\"API key {} not valid\"}}}}",
+                key
+            )),
+            Some(format!("400: bad key {}", key)),
+        );
+        let stored = serde_json::to_string(&entry).unwrap();
+        assert!(!stored.contains(key), "{}", stored);
+    }
+
+    #[test]
+    fn test_truncate_respects_char_boundaries() {
+        let (text, cut) = truncate("héllo".to_string(), 2);
+        assert!(cut);
+        assert!(text.starts_with('h'));
+        let (text, cut) = truncate("short".to_string(), 64);
+        assert!(!cut);
+        assert_eq!(text, "short");
+    }
+}
@@ -50,7 +50,7 @@ pub enum AuthMode {
 }
 
 /// Upstream endpoint information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct UpstreamInfo {
     /// Upstream ID
     pub id: String,
@@ -58,8 +58,25 @@ pub struct UpstreamInfo {
     pub endpoints: Vec<String>,
     /// API style (openai/anthropic/gemini)
     pub api_style: Option<String>,
-    /// API key for this upstream (if configured)
+    /// API key selected for this context from the upstream's key pool
     pub api_key: Option<String>,
+    /// Label or fingerprint of `api_key`, recorded on usage rows
+    pub api_key_id: Option<String>,
+    /// Static headers added to every request
+    pub extra_headers: Vec<(String, String)>,
+    /// Path template overrides (see `config::Upstream`)
+    pub chat_path: Option<String>,
+    pub messages_path: Option<String>,
+    pub generate_path: Option<String>,
+    pub api_version: Option<String>,
+    /// Proxy URL for this upstream, overriding the global proxy
+    pub proxy: Option<String>,
+    /// Connect directly, ignoring any global proxy
+    pub no_proxy: bool,
+    /// PEM file added to the client's root certificates
+    pub ca_cert_path: Option<String>,
+    /// Accept any server certificate
+    pub insecure_skip_verify: bool,
 }
 
 /// Model configuration information
@@ -77,10 +94,10 @@ pub struct ModelInfo {
     pub upstream_id: String,
     /// Model name to use when forwarding to upstream (if different from id)
     pub upstream_model_id: Option<String>,
-    /// Price per 1k prompt tokens
-    pub price_prompt_per_1k: f64,
-    /// Price per 1k completion tokens
-    pub price_completion_per_1k: f64,
+    /// Configured prices (base, cache, reasoning and context tiers)
+    pub pricing: crate::pricing::ModelPricing,
+    /// Parameters the model takes (see `adapters::params`)
+    pub capabilities: crate::config::ModelCapabilities,
 }
 
 impl ModelInfo {
@@ -101,6 +118,52 @@ pub struct RequestMeta {
     pub channel: String,
     /// Tool identifier (e.g., "dashboard", "claude-code")
     pub tool: String,
+    /// Fingerprint of the client token (never the token itself)
+    pub client_token: String,
+    /// Project the request belongs to, if the client reported one
+    pub project: Option<String>,
+    /// Id of the configured project, when `project` is one
+    pub project_id: Option<i64>,
+    /// Client session identifier, if provided
+    pub session_id: Option<String>,
+    /// Client User-Agent header
+    pub user_agent: Option<String>,
+    /// When the relay received the request
+    pub started_at: Option<std::time::Instant>,
+    /// Client-supplied `X-Request-Id`, or a generated id
+    pub request_id: String,
+    /// Sent by the relay itself: logged, but not billed or budgeted
+    pub synthetic: bool,
+    /// A copy sent to a mirror upstream: recorded only with the comparison
+    /// (see `forward::mirror`), never as usage
+    pub mirrored: bool,
+    /// What the client's `x-relay-cache` header asks of the response cache
+    pub cache: super::cache::Directive,
+    /// Variant of the A/B experiment the request was assigned to
+    pub experiment: Option<super::experiments::Assignment>,
+    /// Labels the client put on the request to slice usage by later
+    pub tags: Vec<String>,
+    /// Request id of the captured request this one replays, see
+    /// `forward::replay`
+    pub replay_of: Option<String>,
+}
+
+impl RequestMeta {
+    /// Free-form request context stored with the usage row as a JSON object.
+    pub fn metadata_json(&self) -> Option<String> {
+        let mut map = serde_json::Map::new();
+        if let Some(session) = &self.session_id {
+            map.insert("session_id".to_string(), session.clone().into());
+        }
+        if let Some(agent) = &self.user_agent {
+            map.insert("user_agent".to_string(), agent.clone().into());
+        }
+        if map.is_empty() {
+            None
+        } else {
+            Some(serde_json::Value::Object(map).to_string())
+        }
+    }
 }
 
 /// Forward context containing all information needed for request forwarding
@@ -122,6 +185,31 @@ pub struct ForwardContext {
     pub is_streaming: bool,
     /// Optional override for max retry attempts (used for upstream fallback)
     pub retry_max_attempts_override: Option<u32>,
+    /// Why the plan picked this route
+    pub route: RouteReason,
+}
+
+/// Why a route is where it is in the plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RouteReason {
+    /// The only route of the highest priority group
+    #[default]
+    Primary,
+    /// Picked at random among routes of equal priority
+    GroupMember,
+    /// The n-th fallback, counted from 1
+    Fallback(usize),
+}
+
+impl RouteReason {
+    /// Value of the `x-relay-route-reason` header.
+    pub fn label(&self) -> String {
+        match self {
+            RouteReason::Primary => "primary".to_string(),
+            RouteReason::GroupMember => "group-member".to_string(),
+            RouteReason::Fallback(n) => format!("fallback-{}", n),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -148,14 +236,33 @@ impl ForwardContext {
         }
     }
 
-    /// Get API key from environment variables based on provider
-    fn get_env_api_key(&self) -> Option<String> {
-        let env_var = match self.model.provider {
+    /// Identifier of the key `get_api_key` returns, for per-key usage.
+    pub fn api_key_id(&self) -> Option<String> {
+        match &self.auth_mode {
+            AuthMode::UseRequestToken(token) => {
+                Some(super::middleware::token_fingerprint(token))
+            }
+            AuthMode::UseConfiguredKey => {
+                if self.upstream.api_key.as_deref().is_some_and(|k| !k.is_empty()) {
+                    return self.upstream.api_key_id.clone();
+                }
+                self.get_env_api_key()
+                    .map(|_| format!("env:{}", self.env_api_key_var()))
+            }
+        }
+    }
+
+    fn env_api_key_var(&self) -> &'static str {
+        match self.model.provider {
             Provider::Anthropic => "CCR_ANTHROPIC_KEY",
             Provider::Gemini => "CCR_GEMINI_KEY",
             Provider::OpenAI => "CCR_OPENAI_KEY",
-        };
-        std::env::var(env_var).ok()
+        }
+    }
+
+    /// Get API key from environment variables based on provider
+    fn get_env_api_key(&self) -> Option<String> {
+        std::env::var(self.env_api_key_var()).ok()
     }
 
     /// Get the primary endpoint URL
@@ -168,6 +275,79 @@ impl ForwardContext {
         &self.upstream.endpoints
     }
 
+    /// Path for OpenAI chat completions on this upstream.
+    pub fn chat_path(&self) -> String {
+        self.render_path(self.upstream.chat_path.as_deref(), "/chat/completions", "")
+    }
+
+    /// Path for Anthropic messages on this upstream.
+    pub fn messages_path(&self) -> String {
+        self.render_path(self.upstream.messages_path.as_deref(), "/v1/messages", "")
+    }
+
+    /// Path for Gemini generateContent on this upstream. Streaming swaps in
+    /// `streamGenerateContent` and asks for SSE.
+    pub fn generate_path(&self, model: &str, stream: bool) -> String {
+        let path = self.render_path_for(
+            self.upstream.generate_path.as_deref(),
+            "/{api_version}/models/{model}:generateContent",
+            self.gemini_version(),
+            model,
+        );
+        if !stream {
+            return path;
+        }
+        let path = path.replacen(":generateContent", ":streamGenerateContent", 1);
+        let sep = if path.contains('?') { '&' } else { '?' };
+        format!("{}{}alt=sse", path, sep)
+    }
+
+    fn render_path(&self, template: Option<&str>, default: &str, api_version: &str) -> String {
+        self.render_path_for(template, default, api_version, self.model.upstream_model())
+    }
+
+    fn render_path_for(
+        &self,
+        template: Option<&str>,
+        default: &str,
+        api_version: &str,
+        model: &str,
+    ) -> String {
+        let template = template.map(str::trim).filter(|t| !t.is_empty());
+        let api_version = self
+            .upstream
+            .api_version
+            .as_deref()
+            .filter(|v| !v.is_empty())
+            .unwrap_or(api_version);
+        template
+            .unwrap_or(default)
+            .replace("{model}", model)
+            .replace("{api_version}", api_version)
+    }
+
+    /// Add the upstream's static headers, replacing any defaults of the same name.
+    pub fn apply_extra_headers(&self, headers: &mut reqwest::header::HeaderMap) {
+        use reqwest::header::{HeaderName, HeaderValue};
+        for (name, value) in &self.upstream.extra_headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => crate::logger::warn(
+                    "forward",
+                    &format!(
+                        "Skipping invalid extra header '{}' for upstream {}",
+                        name, self.upstream.id
+                    ),
+                ),
+            }
+        }
+    }
+
     /// Get retry configuration for this request (with optional override).
     pub fn retry_config(&self) -> RetryConfig {
         let mut config = RetryConfig::from_config();
@@ -184,14 +364,138 @@ impl ForwardContext {
             .unwrap_or("v1beta")
     }
 
-    /// Calculate cost for given token usage
-    pub fn calculate_cost(&self, usage: &TokenUsage) -> f64 {
-        crate::pricing::cost_usd(
+    /// Prices in effect for a request of this size (picks the context tier)
+    pub fn price_snapshot(&self, usage: &TokenUsage) -> crate::pricing::PriceSnapshot {
+        self.model.pricing.snapshot_for(usage.prompt_tokens)
+    }
+
+    /// Calculate cost for given token usage (`None` if the model is unpriced)
+    pub fn calculate_cost(&self, usage: &TokenUsage) -> Option<f64> {
+        crate::pricing::request_cost(&usage.billed(), &self.price_snapshot(usage))
+    }
+
+    /// Fields identifying this request in structured log entries
+    pub fn log_fields(&self) -> Vec<(&'static str, serde_json::Value)> {
+        let mut fields = vec![
+            ("request_id", self.meta.request_id.clone().into()),
+            ("model", self.model.id.clone().into()),
+            ("upstream", self.upstream.id.clone().into()),
+        ];
+        if let Some(started) = self.meta.started_at {
+            fields.push(("latency_ms", (started.elapsed().as_millis() as u64).into()));
+        }
+        fields
+    }
+
+    /// Use upstream_model_id for statistics if available (for temporary models)
+    ///
+    /// This ensures temporary models are counted under their actual target model.
+    fn model_for_stats(&self) -> &str {
+        self.model.upstream_model_id
+            .as_ref()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_str())
+            .unwrap_or(&self.model.id)
+    }
+
+    /// The usage row of this request, unpriced
+    fn usage_record(&self, usage: &TokenUsage) -> crate::db::UsageRecord {
+        crate::db::UsageRecord {
+            channel: self.meta.channel.clone(),
+            tool: self.meta.tool.clone(),
+            model: self.model_for_stats().to_string(),
+            upstream_id: self.upstream.id.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total(),
+            cache_creation_tokens: usage.cache_creation_tokens,
+            cache_read_tokens: usage.cache_read_tokens,
+            reasoning_tokens: usage.reasoning_tokens,
+            price_usd: None,
+            price: None,
+            status: 200,
+            latency_ms: self.meta.started_at.map(|t| t.elapsed().as_millis() as i64),
+            client_token: self.meta.client_token.clone(),
+            project: self.meta.project.clone(),
+            project_id: self.meta.project_id,
+            metadata: self.meta.metadata_json(),
+            api_key_id: self.api_key_id(),
+            estimated: usage.is_estimated(),
+            request_id: Some(self.meta.request_id.clone()),
+            synthetic: self.meta.synthetic,
+            experiment: self.meta.experiment.as_ref().map(|a| a.experiment.clone()),
+            variant: self.meta.experiment.as_ref().map(|a| a.variant.to_string()),
+            tags: (!self.meta.tags.is_empty())
+                .then(|| serde_json::to_string(&self.meta.tags).ok())
+                .flatten(),
+            prompt_redactions: super::scrub::take(&self.meta.request_id),
+            replay_of: self.meta.replay_of.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Log an answer served from the response cache: free, and neither
+    /// budgeted nor announced to webhooks since no upstream was called.
+    pub fn log_cached_usage(&self, usage: &TokenUsage) {
+        crate::db::log_usage(&crate::db::UsageRecord {
+            price_usd: Some(0.0),
+            cached: true,
+            ..self.usage_record(usage)
+        });
+        super::activity::usage(
+            &self.meta.request_id,
+            &self.upstream.id,
             usage.prompt_tokens,
             usage.completion_tokens,
-            self.model.price_prompt_per_1k,
-            self.model.price_completion_per_1k,
-        )
+        );
+        let mut fields = self.log_fields();
+        fields.push(("prompt_tokens", usage.prompt_tokens.into()));
+        fields.push(("completion_tokens", usage.completion_tokens.into()));
+        fields.push(("cached", true.into()));
+        crate::logger::log_with_fields(
+            crate::logger::LogLevel::Info,
+            "forward",
+            &format!(
+                "API request served from cache: model={}, tokens={}/{}",
+                self.model_for_stats(),
+                usage.prompt_tokens,
+                usage.completion_tokens
+            ),
+            &fields,
+        );
+    }
+
+    /// Log a request that failed for good after `attempts` upstream
+    /// attempts: no tokens and no cost, so it only shows in error rates.
+    pub fn log_failure(&self, err: &super::ForwardError, attempts: usize) {
+        if self.meta.mirrored {
+            return;
+        }
+        let status = err.status_code();
+        crate::db::log_usage(&crate::db::UsageRecord {
+            price_usd: Some(0.0),
+            status,
+            failed: true,
+            endpoint: err
+                .endpoint()
+                .or_else(|| self.primary_endpoint())
+                .map(str::to_string),
+            error_class: Some(err.class().to_string()),
+            attempts: Some(attempts as i64),
+            ..self.usage_record(&TokenUsage::default())
+        });
+        let event = crate::webhooks::RequestEvent {
+            model: self.model_for_stats().to_string(),
+            upstream_id: self.upstream.id.clone(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+            cost_usd: None,
+            latency_ms: self.meta.started_at.map(|t| t.elapsed().as_millis() as i64),
+            status: status.into(),
+            project: self.meta.project.clone(),
+        };
+        crate::commands::notify_usage_updated(&event);
     }
 
     /// Log usage to database
@@ -200,37 +504,62 @@ impl ForwardContext {
     /// we log the actual upstream model ID instead of the temporary model ID
     /// to ensure correct statistics aggregation.
     pub fn log_usage(&self, usage: &TokenUsage) {
+        if self.meta.mirrored {
+            return;
+        }
+        let price = self.price_snapshot(usage);
         let cost = self.calculate_cost(usage);
-
-        // Use upstream_model_id for statistics if available (for temporary models)
-        // This ensures temporary models are counted under their actual target model
-        let model_for_stats = self.model.upstream_model_id
-            .as_ref()
-            .filter(|s| !s.is_empty())
-            .map(|s| s.as_str())
-            .unwrap_or(&self.model.id);
-
-        crate::db::log_usage(
-            &self.meta.channel,
-            &self.meta.tool,
-            model_for_stats,
+        let model_for_stats = self.model_for_stats();
+
+        crate::db::log_usage(&crate::db::UsageRecord {
+            price_usd: cost,
+            price: price.is_priced().then_some(price),
+            ..self.usage_record(usage)
+        });
+        super::activity::usage(
+            &self.meta.request_id,
+            &self.upstream.id,
             usage.prompt_tokens,
             usage.completion_tokens,
-            usage.total(),
-            cost,
-            &self.upstream.id,
         );
+        if let Some(cost) = cost.filter(|_| !self.meta.synthetic) {
+            super::budget::record_spend(&super::budget::SpendKey::from_context(self), cost);
+        }
+        crate::telemetry::annotate("gen_ai.usage.input_tokens", usage.prompt_tokens);
+        crate::telemetry::annotate("gen_ai.usage.output_tokens", usage.completion_tokens);
+        crate::telemetry::annotate("relay.total_tokens", usage.total());
+        let event = crate::webhooks::RequestEvent {
+            model: model_for_stats.to_string(),
+            upstream_id: self.upstream.id.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total(),
+            cost_usd: cost,
+            latency_ms: self.meta.started_at.map(|t| t.elapsed().as_millis() as i64),
+            status: 200,
+            project: self.meta.project.clone(),
+        };
+        crate::commands::notify_usage_updated(&event);
+        if !self.meta.synthetic {
+            crate::webhooks::emit(crate::webhooks::WebhookEvent::RequestCompleted(event));
+        }
 
         // Log to system logger for visibility
-        crate::logger::info(
+        let cost_label = cost
+            .map(|c| format!("${:.6}", c))
+            .unwrap_or_else(|| "unpriced".to_string());
+        let mut fields = self.log_fields();
+        fields.push(("prompt_tokens", usage.prompt_tokens.into()));
+        fields.push(("completion_tokens", usage.completion_tokens.into()));
+        fields.push(("cost_usd", cost.into()));
+        crate::logger::log_with_fields(
+            crate::logger::LogLevel::Info,
             "forward",
             &format!(
-                "API request completed: model={}, tokens={}/{}, cost=${:.6}",
-                model_for_stats,
-                usage.prompt_tokens,
-                usage.completion_tokens,
-                cost
+                "API request completed: model={}, tokens={}/{}, cost={}",
+                model_for_stats, usage.prompt_tokens, usage.completion_tokens, cost_label
             ),
+            &fields,
         );
     }
 }
@@ -274,23 +603,39 @@ pub struct UpstreamResponse {
     /// Response body
     pub body: serde_json::Value,
     /// Latency in milliseconds
-    #[allow(dead_code)]
     pub latency_ms: u64,
     /// HTTP status code
-    #[allow(dead_code)]
     pub status: u16,
     /// Token usage extracted from response
-    #[allow(dead_code)]
     pub usage: TokenUsage,
+    /// Audio parts of the request the upstream could not take, see
+    /// [`crate::forward::attach_dropped_audio`]
+    pub audio_dropped: usize,
+    /// Response headers of the upstream, see [`crate::forward::upstream_reply`]
+    pub headers: reqwest::header::HeaderMap,
 }
 
 /// Token usage information
+///
+/// `prompt_tokens` and `completion_tokens` are the billable totals. The cache
+/// and reasoning counters are breakdowns that are already included in those
+/// totals, so `total()` never double counts them.
 #[derive(Debug, Clone, Default)]
 pub struct TokenUsage {
-    /// Number of prompt/input tokens
+    /// Number of prompt/input tokens (including cached input)
     pub prompt_tokens: i64,
-    /// Number of completion/output tokens
+    /// Number of completion/output tokens (including reasoning)
     pub completion_tokens: i64,
+    /// Input tokens written to the provider prompt cache
+    pub cache_creation_tokens: i64,
+    /// Input tokens served from the provider prompt cache
+    pub cache_read_tokens: i64,
+    /// Output tokens spent on hidden reasoning/thinking
+    pub reasoning_tokens: i64,
+    /// `prompt_tokens` is a local estimate, not provider-reported
+    pub prompt_estimated: bool,
+    /// `completion_tokens` is a local estimate, not provider-reported
+    pub completion_estimated: bool,
 }
 
 impl TokenUsage {
@@ -299,6 +644,18 @@ impl TokenUsage {
         Self {
             prompt_tokens: prompt,
             completion_tokens: completion,
+            ..Default::default()
+        }
+    }
+
+    /// Starting point for a streamed response: an estimated prompt and no
+    /// output yet, both to be replaced by provider-reported numbers.
+    pub fn estimated(prompt: i64) -> Self {
+        Self {
+            prompt_tokens: prompt,
+            prompt_estimated: true,
+            completion_estimated: true,
+            ..Default::default()
         }
     }
 
@@ -307,11 +664,87 @@ impl TokenUsage {
         self.prompt_tokens + self.completion_tokens
     }
 
+    /// Whether any billable count is still an estimate
+    pub fn is_estimated(&self) -> bool {
+        self.prompt_estimated || self.completion_estimated
+    }
+
+    /// Count streamed output locally until the provider reports it.
+    pub fn add_estimated_output(&mut self, tokens: i64) {
+        if self.completion_estimated {
+            self.completion_tokens += tokens;
+        }
+    }
+
+    /// Like `add_estimated_output`, for reasoning/thinking text.
+    pub fn add_estimated_reasoning(&mut self, tokens: i64) {
+        if self.completion_estimated {
+            self.completion_tokens += tokens;
+            self.reasoning_tokens += tokens;
+        }
+    }
+
+    /// Provider-reported prompt count; replaces any estimate.
+    pub fn set_reported_prompt(&mut self, tokens: i64) {
+        self.prompt_tokens = tokens;
+        self.prompt_estimated = false;
+    }
+
+    /// Provider-reported completion count; replaces any estimate and stops
+    /// further local counting.
+    pub fn set_reported_completion(&mut self, tokens: i64) {
+        self.completion_tokens = tokens;
+        self.completion_estimated = false;
+    }
+
+    /// Adopt a provider usage report. Counts it carries replace the current
+    /// ones (estimated or not); counts it omits are kept.
+    pub fn apply_reported(&mut self, reported: &TokenUsage) {
+        if reported.prompt_tokens > 0 {
+            self.set_reported_prompt(reported.prompt_tokens);
+        }
+        if reported.completion_tokens > 0 {
+            self.set_reported_completion(reported.completion_tokens);
+            self.reasoning_tokens = reported.reasoning_tokens;
+        }
+        self.merge_details(reported);
+    }
+
+    /// Counts in the shape the pricing module bills.
+    pub fn billed(&self) -> crate::pricing::BilledTokens {
+        crate::pricing::BilledTokens {
+            prompt: self.prompt_tokens,
+            completion: self.completion_tokens,
+            cache_creation: self.cache_creation_tokens,
+            cache_read: self.cache_read_tokens,
+            reasoning: self.reasoning_tokens,
+        }
+    }
+
     /// Add another usage to this one
     #[allow(dead_code)]
     pub fn add(&mut self, other: &TokenUsage) {
         self.prompt_tokens += other.prompt_tokens;
         self.completion_tokens += other.completion_tokens;
+        self.cache_creation_tokens += other.cache_creation_tokens;
+        self.cache_read_tokens += other.cache_read_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+        self.prompt_estimated |= other.prompt_estimated;
+        self.completion_estimated |= other.completion_estimated;
+    }
+
+    /// Copy the cache/reasoning breakdown from another usage snapshot,
+    /// keeping any counter the snapshot does not report.
+    pub fn merge_details(&mut self, other: &TokenUsage) {
+        if other.cache_creation_tokens > 0 {
+            self.cache_creation_tokens = other.cache_creation_tokens;
+        }
+        if other.cache_read_tokens > 0 {
+            self.cache_read_tokens = other.cache_read_tokens;
+        }
+        if other.reasoning_tokens > 0 {
+            self.reasoning_tokens = other.reasoning_tokens;
+        }
     }
 }
 
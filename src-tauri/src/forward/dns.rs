@@ -0,0 +1,141 @@
+//! Cached DNS lookups for the upstream clients.
+//!
+//! Every client resolves through [`Resolver`], which keeps the addresses of
+//! a host for `http_client.dns_cache_secs`. Retries and failovers to another
+//! endpoint on the same host then don't wait for DNS again, which matters
+//! most when the resolver itself is what's slow. [`spawn`] resolves the hosts
+//! of the configured endpoints when the server starts and again before their
+//! entries expire, so the first request to each finds its addresses ready.
+//! Failed lookups aren't cached.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+
+use crate::{config, logger, network};
+
+/// Hosts cached at most; expired ones are dropped first
+const CACHE_ENTRIES: usize = 256;
+/// Pre-resolution runs at least this far apart
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// Addresses by host, with when they were resolved
+type Cache = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+static CACHE: Lazy<Mutex<Cache>> = Lazy::new(Default::default);
+
+/// The resolver of the upstream clients.
+pub struct Resolver;
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = lookup(&host).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn ttl() -> Duration {
+    Duration::from_secs(config::current().http_client.dns_cache_secs)
+}
+
+/// The addresses of `host`, from the cache while they are fresh.
+async fn lookup(host: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let ttl = ttl();
+    let cached = CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(host)
+        .filter(|(at, _)| at.elapsed() < ttl)
+        .map(|(_, addrs)| addrs.clone());
+    match cached {
+        Some(addrs) => Ok(addrs),
+        None => resolve(host, ttl).await,
+    }
+}
+
+/// Look `host` up and cache what it resolves to for `ttl`.
+async fn resolve(host: &str, ttl: Duration) -> std::io::Result<Vec<SocketAddr>> {
+    // Port 0 is replaced by the one of the URL
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+    if !ttl.is_zero() && !addrs.is_empty() {
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= CACHE_ENTRIES {
+            cache.retain(|_, (at, _)| at.elapsed() < ttl);
+        }
+        if cache.len() >= CACHE_ENTRIES {
+            cache.clear();
+        }
+        cache.insert(host.to_string(), (Instant::now(), addrs.clone()));
+    }
+    Ok(addrs)
+}
+
+/// Host names of the configured endpoints; IP addresses need no lookup.
+fn endpoint_hosts(cfg: &config::Settings) -> HashSet<String> {
+    cfg.upstreams
+        .iter()
+        .flat_map(|upstream| &upstream.endpoints)
+        .filter_map(|endpoint| Url::parse(endpoint.trim()).ok())
+        .filter_map(|url| url.domain().map(str::to_string))
+        .collect()
+}
+
+/// Spawn the pre-resolution loop on the current tokio runtime.
+pub fn spawn() {
+    tokio::spawn(run());
+}
+
+async fn run() {
+    loop {
+        let cfg = config::current();
+        let ttl = ttl();
+        if !ttl.is_zero() && network::is_online() {
+            for host in endpoint_hosts(&cfg) {
+                if let Err(e) = resolve(&host, ttl).await {
+                    logger::debug("dns", &format!("Failed to resolve {}: {}", host, e));
+                }
+            }
+        }
+        // Ahead of expiry, so requests keep finding fresh entries
+        tokio::time::sleep((ttl * 9 / 10).max(MIN_REFRESH)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lookups_are_cached() {
+        let addrs = lookup("localhost").await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        let (at, cached) = CACHE.lock().unwrap().get("localhost").cloned().unwrap();
+        assert_eq!(cached, addrs);
+        assert_eq!(lookup("localhost").await.unwrap(), addrs);
+        assert_eq!(CACHE.lock().unwrap()["localhost"].0, at);
+
+        let cfg = config::Settings {
+            upstreams: vec![config::Upstream {
+                endpoints: vec![
+                    "https://api.example.com/v1".to_string(),
+                    "http://10.0.0.2:8080".to_string(),
+                    "https://api.example.com".to_string(),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            endpoint_hosts(&cfg),
+            HashSet::from(["api.example.com".to_string()])
+        );
+    }
+}
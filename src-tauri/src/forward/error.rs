@@ -3,10 +3,11 @@
 //! Defines all error types used in the forward module for request handling.
 
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use serde_json::Value;
 
 /// Error types for the forward module
 #[derive(Debug, Clone)]
@@ -18,16 +19,28 @@ pub enum ForwardError {
     Forbidden(String),
     /// Requested model not found in configuration
     ModelNotFound(String),
+    /// Requested model not configured, with similar configured ids
+    UnknownModel {
+        model: String,
+        did_you_mean: Vec<String>,
+    },
     /// Upstream provider not found in configuration
     UpstreamNotFound(String),
     /// Request to upstream provider failed
-    RequestFailed(String),
+    RequestFailed(Box<UpstreamError>),
     /// Invalid request format or parameters
     InvalidRequest(String),
     /// Request rejected by rate limiting or quotas
     RateLimited(String),
+    /// The upstream's safety filters blocked the prompt; holds the reason
+    /// it gave, such as Gemini's `SAFETY`
+    ContentFiltered(String),
+    /// Request rejected because a spend budget is exhausted
+    BudgetExceeded(String),
     /// Request timeout
     Timeout(String),
+    /// The machine is offline and the upstream is not on the local network
+    Offline(String),
     /// Internal server error
     Internal(String),
 }
@@ -38,11 +51,17 @@ impl std::fmt::Display for ForwardError {
             ForwardError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
             ForwardError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
             ForwardError::ModelNotFound(msg) => write!(f, "Model not found: {}", msg),
+            ForwardError::UnknownModel { model, .. } => {
+                write!(f, "Model not found: Model '{}' not configured", model)
+            }
             ForwardError::UpstreamNotFound(msg) => write!(f, "Upstream not found: {}", msg),
-            ForwardError::RequestFailed(msg) => write!(f, "Request failed: {}", msg),
+            ForwardError::RequestFailed(err) => write!(f, "Request failed: {}", err.message),
             ForwardError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
             ForwardError::RateLimited(msg) => write!(f, "Rate limited: {}", msg),
+            ForwardError::ContentFiltered(reason) => write!(f, "Content blocked: {}", reason),
+            ForwardError::BudgetExceeded(msg) => write!(f, "Budget exceeded: {}", msg),
             ForwardError::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            ForwardError::Offline(msg) => write!(f, "Offline: {}", msg),
             ForwardError::Internal(msg) => write!(f, "Internal error: {}", msg),
         }
     }
@@ -50,9 +69,172 @@ impl std::fmt::Display for ForwardError {
 
 impl std::error::Error for ForwardError {}
 
-impl IntoResponse for ForwardError {
-    fn into_response(self) -> Response {
-        let (status, error_type, message) = match &self {
+/// What went wrong talking to an upstream.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamError {
+    pub message: String,
+    /// Status the upstream answered with; unset when no answer came
+    pub status: Option<u16>,
+    /// The upstream's error body, as JSON when it was
+    pub upstream_body: Option<Value>,
+    /// Whether another attempt, or another upstream, may do better
+    pub retryable: bool,
+    pub upstream_id: Option<String>,
+    pub endpoint: Option<String>,
+    /// The upstream's `Retry-After`, passed on to the client
+    pub retry_after: Option<String>,
+    /// No connection could be made, so the request never left; the next
+    /// endpoint can be tried at once
+    pub unreached: bool,
+}
+
+impl ForwardError {
+    /// The upstream could not be reached or its reply not read; worth
+    /// trying again.
+    pub fn request_failed(message: impl Into<String>) -> Self {
+        ForwardError::RequestFailed(Box::new(UpstreamError {
+            message: message.into(),
+            retryable: true,
+            ..Default::default()
+        }))
+    }
+
+    /// No connection to the upstream could be made.
+    pub fn unreached(message: impl Into<String>) -> Self {
+        ForwardError::RequestFailed(Box::new(UpstreamError {
+            message: message.into(),
+            retryable: true,
+            unreached: true,
+            ..Default::default()
+        }))
+    }
+
+    /// The upstream answered `status` with `body`.
+    pub fn upstream(status: u16, body: &str) -> Self {
+        let upstream_body = serde_json::from_str(body)
+            .ok()
+            .or_else(|| (!body.is_empty()).then(|| Value::String(body.to_string())));
+        ForwardError::RequestFailed(Box::new(UpstreamError {
+            message: if body.is_empty() {
+                format!("Upstream returned {}", status)
+            } else {
+                format!("Upstream returned {}: {}", status, body)
+            },
+            status: Some(status),
+            upstream_body,
+            retryable: super::client::should_retry(status),
+            ..Default::default()
+        }))
+    }
+
+    /// Keep the `Retry-After` of the upstream's reply.
+    pub fn with_retry_after(mut self, headers: &HeaderMap) -> Self {
+        if let ForwardError::RequestFailed(err) = &mut self {
+            err.retry_after = headers
+                .get(header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+        }
+        self
+    }
+
+    /// Note the endpoint the error came from, unless already known.
+    pub fn at_endpoint(mut self, endpoint: &str) -> Self {
+        if let ForwardError::RequestFailed(err) = &mut self {
+            err.endpoint.get_or_insert_with(|| endpoint.to_string());
+        }
+        self
+    }
+
+    /// Note the upstream the error came from, unless already known.
+    pub fn for_upstream(mut self, upstream_id: &str) -> Self {
+        if let ForwardError::RequestFailed(err) = &mut self {
+            err.upstream_id
+                .get_or_insert_with(|| upstream_id.to_string());
+        }
+        self
+    }
+
+    /// Status the upstream answered with, if it did.
+    pub fn upstream_status(&self) -> Option<u16> {
+        match self {
+            ForwardError::RequestFailed(err) => err.status,
+            _ => None,
+        }
+    }
+
+    /// Endpoint the error came from, if it came from one.
+    pub fn endpoint(&self) -> Option<&str> {
+        match self {
+            ForwardError::RequestFailed(err) => err.endpoint.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Status the client is answered with.
+    pub fn status_code(&self) -> u16 {
+        self.parts().0.as_u16()
+    }
+
+    /// Coarse kind of failure: "timeout", "auth", "rate_limited",
+    /// "client_error", "server_error", "connection", "offline",
+    /// "content_filter" or "other".
+    pub fn class(&self) -> &'static str {
+        match self {
+            ForwardError::Timeout(_) => "timeout",
+            ForwardError::ContentFiltered(_) => "content_filter",
+            ForwardError::Offline(_) => "offline",
+            ForwardError::RequestFailed(err) => match err.status {
+                Some(401 | 403) => "auth",
+                Some(429) => "rate_limited",
+                Some(408) => "timeout",
+                Some(status) if status < 500 => "client_error",
+                Some(_) => "server_error",
+                None => "connection",
+            },
+            _ => "other",
+        }
+    }
+
+    /// Whether the request failed before reaching the upstream.
+    pub fn is_unreached(&self) -> bool {
+        matches!(self, ForwardError::RequestFailed(err) if err.unreached)
+    }
+
+    /// Whether the request may succeed on another attempt.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ForwardError::Timeout(_) => true,
+            ForwardError::RequestFailed(err) => err.retryable,
+            _ => false,
+        }
+    }
+}
+
+/// Error body shape a client expects, decided by the route it called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    OpenAI,
+    Anthropic,
+    Gemini,
+}
+
+impl Dialect {
+    pub fn of_path(path: &str) -> Self {
+        if path.starts_with("/anthropic/") || path.starts_with("/v1/messages") {
+            Dialect::Anthropic
+        } else if path.starts_with("/gemini/") && !path.contains("/openai/") {
+            Dialect::Gemini
+        } else {
+            Dialect::OpenAI
+        }
+    }
+}
+
+impl ForwardError {
+    /// Status, error type and message shared by every dialect.
+    fn parts(&self) -> (StatusCode, &'static str, String) {
+        match self {
             ForwardError::Unauthorized(msg) => {
                 (StatusCode::UNAUTHORIZED, "unauthorized", msg.clone())
             }
@@ -60,12 +242,23 @@ impl IntoResponse for ForwardError {
             ForwardError::ModelNotFound(msg) => {
                 (StatusCode::NOT_FOUND, "model_not_found", msg.clone())
             }
+            ForwardError::UnknownModel { model, .. } => (
+                StatusCode::NOT_FOUND,
+                "model_not_found",
+                format!("Model '{}' not configured", model),
+            ),
             ForwardError::UpstreamNotFound(msg) => {
                 (StatusCode::NOT_FOUND, "upstream_not_found", msg.clone())
             }
-            ForwardError::RequestFailed(msg) => {
-                (StatusCode::BAD_GATEWAY, "request_failed", msg.clone())
-            }
+            ForwardError::RequestFailed(err) => (
+                err.status
+                    .and_then(|s| StatusCode::from_u16(s).ok())
+                    .filter(|s| s.is_client_error() || s.is_server_error())
+                    .unwrap_or(StatusCode::BAD_GATEWAY),
+                "request_failed",
+                err.upstream_message()
+                    .unwrap_or_else(|| err.message.clone()),
+            ),
             ForwardError::InvalidRequest(msg) => {
                 (StatusCode::BAD_REQUEST, "invalid_request", msg.clone())
             }
@@ -74,13 +267,31 @@ impl IntoResponse for ForwardError {
                 "rate_limited",
                 msg.clone(),
             ),
+            ForwardError::ContentFiltered(reason) => (
+                StatusCode::BAD_REQUEST,
+                "content_filter",
+                format!("The prompt was blocked by the upstream's safety filters ({})", reason),
+            ),
+            ForwardError::BudgetExceeded(msg) => (
+                StatusCode::PAYMENT_REQUIRED,
+                "budget_exceeded",
+                msg.clone(),
+            ),
             ForwardError::Timeout(msg) => (StatusCode::GATEWAY_TIMEOUT, "timeout", msg.clone()),
+            ForwardError::Offline(msg) => (StatusCode::SERVICE_UNAVAILABLE, "offline", msg.clone()),
             ForwardError::Internal(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "internal_error",
                 msg.clone(),
             ),
-        };
+        }
+    }
+
+    /// Error response in the format of `dialect`'s API. An upstream's
+    /// error already in that format is passed on as it is; one in another
+    /// provider's format is converted, keeping its status and message.
+    pub fn into_response_for(self, dialect: Dialect) -> Response {
+        let (status, error_type, message) = self.parts();
 
         // Log all errors being returned to client
         crate::logger::error(
@@ -93,18 +304,285 @@ impl IntoResponse for ForwardError {
             ),
         );
 
-        (
-            status,
-            Json(serde_json::json!({
+        let upstream = match &self {
+            ForwardError::RequestFailed(err) => Some(err.as_ref()),
+            _ => None,
+        };
+        let native = upstream
+            .filter(|err| err.status.is_some() && err.body_dialect() == Some(dialect))
+            .and_then(|err| err.upstream_body.clone());
+        let body = native.unwrap_or_else(|| self.body_for(dialect, status, error_type, message));
+
+        let mut response = (status, Json(body)).into_response();
+        if let Some(retry_after) = upstream
+            .and_then(|err| err.retry_after.as_deref())
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after);
+        }
+        response
+    }
+
+    /// Error body of our own in `dialect`'s format.
+    fn body_for(
+        &self,
+        dialect: Dialect,
+        status: StatusCode,
+        error_type: &str,
+        message: String,
+    ) -> Value {
+        let upstream = match self {
+            ForwardError::RequestFailed(err) => Some(err.as_ref()),
+            _ => None,
+        };
+        let mut body = match dialect {
+            Dialect::OpenAI => {
+                let mut error = serde_json::json!({
+                    "type": if upstream.is_some() { openai_type(status) } else { error_type },
+                    "message": message,
+                    "code": upstream.and_then(UpstreamError::upstream_code)
+                });
+                if let ForwardError::UnknownModel { did_you_mean, .. } = self {
+                    error["did_you_mean"] = serde_json::json!(did_you_mean);
+                }
+                serde_json::json!({ "error": error })
+            }
+            Dialect::Anthropic => serde_json::json!({
+                "type": "error",
                 "error": {
-                    "type": error_type,
+                    "type": anthropic_type(status),
                     "message": message
                 }
-            })),
-        )
-            .into_response()
+            }),
+            Dialect::Gemini => serde_json::json!({
+                "error": {
+                    "code": status.as_u16(),
+                    "message": message,
+                    "status": gemini_status(status)
+                }
+            }),
+        };
+        if let ForwardError::ContentFiltered(reason) = self {
+            // OpenAI's own code for filtered content
+            if dialect == Dialect::OpenAI {
+                body["error"]["type"] = "invalid_request_error".into();
+                body["error"]["code"] = "content_filter".into();
+            }
+            body["error"]["block_reason"] = reason.as_str().into();
+        }
+        if let Some(err) = upstream {
+            body["error"]["upstream"] = serde_json::json!({
+                "id": err.upstream_id,
+                "status": err.status,
+                "body": err.upstream_body
+            });
+        }
+        body
+    }
+}
+
+impl IntoResponse for ForwardError {
+    fn into_response(self) -> Response {
+        self.into_response_for(Dialect::OpenAI)
+    }
+}
+
+impl UpstreamError {
+    /// The message of the upstream's error body, whichever provider's
+    /// shape it has.
+    fn upstream_message(&self) -> Option<String> {
+        let body = self.upstream_body.as_ref()?;
+        let message = body
+            .get("error")
+            .and_then(|e| e.get("message").or(Some(e)))
+            .or_else(|| body.get("message"))?;
+        message.as_str().map(str::to_string)
+    }
+
+    /// The provider whose error shape the upstream's body has.
+    fn body_dialect(&self) -> Option<Dialect> {
+        let body = self.upstream_body.as_ref()?;
+        let error = body.get("error")?;
+        if body.get("type").and_then(|v| v.as_str()) == Some("error") {
+            Some(Dialect::Anthropic)
+        } else if error.get("status").is_some_and(Value::is_string)
+            && error.get("code").is_some_and(Value::is_number)
+        {
+            Some(Dialect::Gemini)
+        } else if error.get("message").is_some_and(Value::is_string) {
+            Some(Dialect::OpenAI)
+        } else {
+            None
+        }
+    }
+
+    /// The upstream's own name for the error: OpenAI's `code`, Anthropic's
+    /// error `type` or Gemini's `status`.
+    fn upstream_code(&self) -> Option<Value> {
+        let error = self.upstream_body.as_ref()?.get("error")?;
+        let code = match self.body_dialect()? {
+            Dialect::OpenAI => error.get("code"),
+            Dialect::Anthropic => error.get("type"),
+            Dialect::Gemini => error.get("status"),
+        };
+        code.filter(|c| !c.is_null()).cloned()
+    }
+}
+
+fn openai_type(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        401 => "authentication_error",
+        403 => "permission_error",
+        429 => "rate_limit_exceeded",
+        400..=499 => "invalid_request_error",
+        _ => "server_error",
+    }
+}
+
+fn anthropic_type(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        402 => "billing_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        504 => "timeout_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+fn gemini_status(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "INVALID_ARGUMENT",
+        401 => "UNAUTHENTICATED",
+        402 => "FAILED_PRECONDITION",
+        403 => "PERMISSION_DENIED",
+        404 => "NOT_FOUND",
+        429 => "RESOURCE_EXHAUSTED",
+        502 | 503 => "UNAVAILABLE",
+        504 => "DEADLINE_EXCEEDED",
+        _ => "INTERNAL",
     }
 }
 
 /// Result type alias for forward operations
 pub type ForwardResult<T> = Result<T, ForwardError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upstream_errors_keep_status_and_body() {
+        let body = r#"{"error": {"message": "Rate limit reached for gpt-4o", "type": "requests"}}"#;
+        let err = ForwardError::upstream(429, body)
+            .at_endpoint("https://api.openai.com/v1")
+            .for_upstream("openai");
+        assert!(err.is_retryable());
+        assert_eq!(err.upstream_status(), Some(429));
+        assert!(!ForwardError::upstream(400, "bad").is_retryable());
+        // A bare message is never read as a status
+        let refused = ForwardError::request_failed("Connection refused at 2024-05-01");
+        assert_eq!(refused.upstream_status(), None);
+        assert!(refused.is_retryable());
+
+        let response = err.into_response_for(Dialect::Anthropic);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["type"], "rate_limit_error");
+        assert_eq!(body["error"]["message"], "Rate limit reached for gpt-4o");
+        assert_eq!(body["error"]["upstream"]["id"], "openai");
+        assert_eq!(
+            body["error"]["upstream"]["body"]["error"]["type"],
+            "requests"
+        );
+
+        // Statuses that are not errors become a bad gateway
+        let response = ForwardError::upstream(200, "").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    async fn body_of(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upstream_errors_answer_in_the_client_dialect() {
+        let anthropic = r#"{"type": "error", "error": {"type": "invalid_request_error", "message": "max_tokens: Field required"}}"#;
+        let response = ForwardError::upstream(400, anthropic).into_response_for(Dialect::Anthropic);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(anthropic).unwrap();
+        assert_eq!(body_of(response).await, body);
+
+        let gemini = r#"{"error": {"code": 429, "message": "Quota exceeded", "status": "RESOURCE_EXHAUSTED"}}"#;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("30"));
+        let err = ForwardError::upstream(429, gemini).with_retry_after(&headers);
+        let response = err.clone().into_response_for(Dialect::OpenAI);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        let body = body_of(response).await;
+        assert_eq!(body["error"]["type"], "rate_limit_exceeded");
+        assert_eq!(body["error"]["code"], "RESOURCE_EXHAUSTED");
+        assert_eq!(body["error"]["message"], "Quota exceeded");
+
+        let response = err.clone().into_response_for(Dialect::Gemini);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        assert_eq!(
+            body_of(response).await,
+            serde_json::from_str::<Value>(gemini).unwrap()
+        );
+
+        let body = body_of(err.into_response_for(Dialect::Anthropic)).await;
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "rate_limit_error");
+    }
+
+    #[tokio::test]
+    async fn test_content_filtered_in_each_dialect() {
+        let err = ForwardError::ContentFiltered("SAFETY".to_string());
+        assert!(!err.is_retryable());
+        assert_eq!(err.class(), "content_filter");
+
+        let response = err.clone().into_response_for(Dialect::OpenAI);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = body_of(response).await;
+        assert_eq!(body["error"]["code"], "content_filter");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["block_reason"], "SAFETY");
+
+        let body = body_of(err.clone().into_response_for(Dialect::Anthropic)).await;
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["block_reason"], "SAFETY");
+        let body = body_of(err.into_response_for(Dialect::Gemini)).await;
+        assert_eq!(body["error"]["status"], "INVALID_ARGUMENT");
+        assert_eq!(body["error"]["block_reason"], "SAFETY");
+    }
+
+    #[test]
+    fn test_dialect_of_path() {
+        assert_eq!(Dialect::of_path("/anthropic/v1/messages"), Dialect::Anthropic);
+        assert_eq!(Dialect::of_path("/v1/messages"), Dialect::Anthropic);
+        assert_eq!(
+            Dialect::of_path("/v1/messages/count_tokens"),
+            Dialect::Anthropic
+        );
+        assert_eq!(Dialect::of_path("/v1/models"), Dialect::OpenAI);
+        assert_eq!(Dialect::of_path("/gemini/v1beta/models"), Dialect::Gemini);
+        assert_eq!(
+            Dialect::of_path("/gemini/v1beta/openai/chat/completions"),
+            Dialect::OpenAI
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! A/B experiments between two models.
+//!
+//! An entry of `experiments` splits the requests for its `model` between
+//! the configured models `variant_a` and `variant_b`. Clients are assigned
+//! by a hash of the experiment id and their `user` (Anthropic:
+//! `metadata.user_id`), else their session id, else their token, so each
+//! one stays on its variant across requests and a conversation never
+//! switches models halfway. Usage rows carry the experiment and variant,
+//! which `GET /api/experiments/:id/report` totals per variant.
+//!
+//! Ending an experiment names a winner, which from then on serves every
+//! request for the model. The entry stays, so its report can still be read.
+
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::context::RequestMeta;
+use crate::config::{self, ExperimentConfig};
+use crate::db;
+
+/// The variant a request was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub experiment: String,
+    /// "a" or "b"
+    pub variant: &'static str,
+}
+
+/// The model serving a request for `model_id`, and its variant when an
+/// experiment on that id is running.
+pub fn route(
+    experiments: &[ExperimentConfig],
+    model_id: String,
+    payload: &Value,
+    meta: &RequestMeta,
+) -> (String, Option<Assignment>) {
+    let mut matching = experiments
+        .iter()
+        .filter(|e| e.model.eq_ignore_ascii_case(&model_id));
+    let Some(experiment) = matching
+        .clone()
+        .find(|e| e.winner.is_none())
+        .or_else(|| matching.next())
+    else {
+        return (model_id, None);
+    };
+    if let Some(winner) = experiment
+        .winner
+        .as_deref()
+        .and_then(|w| experiment.variant_model(w))
+    {
+        return (winner.to_string(), None);
+    }
+
+    let variant = variant_for(experiment, &client_key(payload, meta));
+    let model = experiment
+        .variant_model(variant)
+        .unwrap_or(model_id.as_str());
+    crate::logger::debug(
+        "experiments",
+        &format!(
+            "{} -> {} (experiment {}, variant {})",
+            model_id, model, experiment.id, variant
+        ),
+    );
+    (
+        model.to_string(),
+        Some(Assignment {
+            experiment: experiment.id.clone(),
+            variant,
+        }),
+    )
+}
+
+/// What keeps a client on its variant. Requests carrying none of them are
+/// assigned one by one.
+fn client_key(payload: &Value, meta: &RequestMeta) -> String {
+    payload
+        .get("user")
+        .or_else(|| payload.pointer("/metadata/user_id"))
+        .and_then(Value::as_str)
+        .filter(|user| !user.is_empty())
+        .map(|user| format!("user:{}", user))
+        .or_else(|| {
+            meta.session_id
+                .as_ref()
+                .map(|session| format!("session:{}", session))
+        })
+        .or_else(|| {
+            Some(meta.client_token.as_str())
+                .filter(|token| !token.is_empty() && *token != "none")
+                .map(|token| format!("token:{}", token))
+        })
+        .unwrap_or_else(|| format!("request:{}", meta.request_id))
+}
+
+fn variant_for(experiment: &ExperimentConfig, key: &str) -> &'static str {
+    let digest = Sha256::new()
+        .chain_update(experiment.id.to_lowercase().as_bytes())
+        .chain_update([0u8])
+        .chain_update(key.as_bytes())
+        .finalize();
+    let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap_or_default()) % 100;
+    if bucket < u64::from(experiment.split_percent) {
+        "a"
+    } else {
+        "b"
+    }
+}
+
+/// An experiment with the totals of its variants so far.
+#[derive(Debug, Serialize)]
+pub struct ExperimentReport {
+    #[serde(flatten)]
+    pub experiment: ExperimentConfig,
+    pub variants: Vec<db::ExperimentVariantRow>,
+}
+
+pub fn report(id: &str) -> Result<ExperimentReport, String> {
+    let experiment = config::current()
+        .experiments
+        .iter()
+        .find(|e| e.id.eq_ignore_ascii_case(id))
+        .cloned()
+        .ok_or_else(|| format!("Experiment '{}' not found", id))?;
+    let variants = db::experiment_report(&experiment.id)?;
+    Ok(ExperimentReport {
+        experiment,
+        variants,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn experiment(split_percent: u8) -> ExperimentConfig {
+        ExperimentConfig {
+            id: "mini".to_string(),
+            model: "gpt".to_string(),
+            variant_a: "gpt-4o".to_string(),
+            variant_b: "gpt-4o-mini".to_string(),
+            split_percent,
+            winner: None,
+        }
+    }
+
+    #[test]
+    fn test_clients_keep_their_variant_and_split_by_share() {
+        let experiments = vec![experiment(30)];
+        let meta = RequestMeta::default();
+        let mut on_a = 0;
+        for i in 0..1000 {
+            let payload = json!({ "model": "gpt", "user": format!("user-{}", i) });
+            let (model, assignment) = route(&experiments, "GPT".to_string(), &payload, &meta);
+            let assignment = assignment.unwrap();
+            assert_eq!(assignment.experiment, "mini");
+            let again = route(&experiments, "gpt".to_string(), &payload, &meta);
+            assert_eq!(again.0, model);
+            if assignment.variant == "a" {
+                assert_eq!(model, "gpt-4o");
+                on_a += 1;
+            }
+        }
+        assert!((220..380).contains(&on_a), "{} of 1000 on a", on_a);
+
+        let none = vec![experiment(0)];
+        let all = vec![experiment(100)];
+        let payload = json!({ "user": "someone" });
+        assert_eq!(
+            route(&none, "gpt".to_string(), &payload, &meta).0,
+            "gpt-4o-mini"
+        );
+        assert_eq!(route(&all, "gpt".to_string(), &payload, &meta).0, "gpt-4o");
+    }
+
+    #[test]
+    fn test_client_key_prefers_user_then_session_then_token() {
+        let mut meta = RequestMeta {
+            client_token: "...abcd".to_string(),
+            request_id: "req-1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(client_key(&json!({}), &meta), "token:...abcd");
+        meta.session_id = Some("s1".to_string());
+        assert_eq!(client_key(&json!({}), &meta), "session:s1");
+        let anthropic = json!({ "metadata": { "user_id": "u1" } });
+        assert_eq!(client_key(&anthropic, &meta), "user:u1");
+        meta.session_id = None;
+        meta.client_token = "none".to_string();
+        assert_eq!(client_key(&json!({ "user": "" }), &meta), "request:req-1");
+    }
+
+    #[test]
+    fn test_ended_experiment_routes_to_winner_untagged() {
+        let mut ended = experiment(50);
+        ended.winner = Some("b".to_string());
+        let meta = RequestMeta::default();
+        let payload = json!({ "user": "someone" });
+        assert_eq!(
+            route(&[ended.clone()], "gpt".to_string(), &payload, &meta),
+            ("gpt-4o-mini".to_string(), None)
+        );
+
+        // A new experiment on the same model takes over from the ended one
+        let running = ExperimentConfig {
+            id: "next".to_string(),
+            ..experiment(100)
+        };
+        let (model, assignment) = route(&[ended, running], "gpt".to_string(), &payload, &meta);
+        assert_eq!(model, "gpt-4o");
+        assert_eq!(assignment.unwrap().experiment, "next");
+        assert_eq!(
+            route(&[], "gpt".to_string(), &payload, &meta),
+            ("gpt".to_string(), None)
+        );
+    }
+}
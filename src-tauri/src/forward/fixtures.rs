@@ -0,0 +1,419 @@
+//! Recorded upstream exchanges, replayed as conversion tests.
+//!
+//! With `capture.fixtures` on, every answered chat, messages or
+//! generateContent attempt is written as a JSON fixture to the `fixtures`
+//! folder of the data directory: the payload the handler was given, the
+//! request it sent upstream and the answer, a stream as the chunks it arrived
+//! in. Bodies are redacted like captures (see [`super::capture`]) and the
+//! upstream's key never leaves the request headers, which are not kept.
+//!
+//! A fixture copied to `tests/fixtures/replay` is replayed by `cargo test`:
+//! its handler runs against a loopback server answering with the recording,
+//! and both the request sent upstream and every byte the client gets must
+//! match the fixture. The client's side, `expected`, is written by running
+//! the tests once with `CCR_BLESS_FIXTURES=1`; review it before committing.
+//! Timestamps and the ids made from them are zeroed before comparing.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::capture;
+use super::context::ForwardContext;
+use crate::{config, logger};
+
+/// One recorded exchange.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Fixture {
+    /// What the exchange covers
+    pub description: String,
+    /// Provider of the handler the request went through
+    pub provider: String,
+    pub upstream_id: String,
+    /// API style of the upstream
+    pub api_style: String,
+    pub gemini_api_version: Option<String>,
+    pub model: String,
+    pub upstream_model: String,
+    pub streaming: bool,
+    /// Payload the handler was given
+    pub request: Value,
+    pub upstream: Exchange,
+    /// Lines of the body the client got; written by the test harness
+    pub expected: Option<Vec<String>>,
+}
+
+/// What went to and came back from the upstream.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Exchange {
+    /// Path and query below the endpoint, without an API key
+    pub path: String,
+    pub body: Value,
+    pub content_type: String,
+    /// The answer as it arrived: a single chunk unless streamed
+    pub chunks: Vec<String>,
+}
+
+/// A fixture being recorded, written once the answer is complete.
+pub struct Recording {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    fixture: Fixture,
+    endpoints: Vec<String>,
+    /// Start of a character split between stream chunks
+    partial: Vec<u8>,
+    complete: bool,
+    failed: bool,
+}
+
+impl Recording {
+    /// A recording of the handler of `ctx` answering `payload`, if fixtures
+    /// are on.
+    pub fn start(ctx: &ForwardContext, payload: &Value) -> Option<Arc<Self>> {
+        if !config::current().capture.fixtures {
+            return None;
+        }
+        let mut request = payload.clone();
+        capture::redact_value(&mut request);
+        let fixture = Fixture {
+            provider: ctx.model.provider.as_str().to_string(),
+            upstream_id: ctx.upstream.id.clone(),
+            api_style: ctx.upstream.api_style.clone().unwrap_or_default(),
+            gemini_api_version: ctx.gemini_api_version.clone(),
+            model: ctx.model.id.clone(),
+            upstream_model: ctx.model.upstream_model().to_string(),
+            streaming: ctx.is_streaming,
+            request,
+            ..Default::default()
+        };
+        Some(Arc::new(Self::new(fixture, ctx.all_endpoints().to_vec())))
+    }
+
+    fn new(fixture: Fixture, endpoints: Vec<String>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                fixture,
+                endpoints,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The request sent upstream. A retry replaces it.
+    pub fn request(&self, url: &str, body: &Value) {
+        let mut body = body.clone();
+        capture::redact_value(&mut body);
+        let mut state = self.state();
+        state.fixture.upstream.path = path_below(url, &state.endpoints);
+        state.fixture.upstream.body = body;
+        state.fixture.upstream.chunks.clear();
+        state.partial.clear();
+    }
+
+    /// The whole answer to a non-streaming request.
+    pub fn response(&self, content_type: &str, text: &str) {
+        let mut state = self.state();
+        state.fixture.upstream.content_type = content_type.to_string();
+        state.fixture.upstream.chunks = vec![capture::redact_str(text)];
+        state.complete = true;
+    }
+
+    /// The next chunk of a streamed answer.
+    pub fn chunk(&self, bytes: &[u8]) {
+        let mut state = self.state();
+        state.partial.extend_from_slice(bytes);
+        let valid = match std::str::from_utf8(&state.partial) {
+            Ok(text) => text.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        if valid == 0 {
+            return;
+        }
+        let rest = state.partial.split_off(valid);
+        let text = String::from_utf8_lossy(&state.partial).into_owned();
+        state.partial = rest;
+        state
+            .fixture
+            .upstream
+            .chunks
+            .push(capture::redact_str(&text));
+    }
+
+    /// The stream ended.
+    pub fn finish(&self) {
+        let mut state = self.state();
+        if state.fixture.upstream.content_type.is_empty() {
+            state.fixture.upstream.content_type = "text/event-stream".to_string();
+        }
+        state.complete = true;
+    }
+
+    /// The attempt failed, so there is nothing worth replaying.
+    pub fn fail(&self) {
+        self.state().failed = true;
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if !state.complete || state.failed {
+            return;
+        }
+        match save(&state.fixture) {
+            Ok(path) => logger::info("fixtures", &format!("Recorded {}", path.display())),
+            Err(e) => logger::warn("fixtures", &format!("Could not write fixture: {}", e)),
+        }
+    }
+}
+
+/// Folder recorded fixtures are written to.
+pub fn dir() -> PathBuf {
+    crate::profile::base_dir().join("fixtures")
+}
+
+fn save(fixture: &Fixture) -> Result<PathBuf, String> {
+    let dir = dir();
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let model: String = fixture
+        .model
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = format!(
+        "{}-{}-{}{}.json",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S%3f"),
+        fixture.provider,
+        model,
+        if fixture.streaming { "-stream" } else { "" }
+    );
+    let path = dir.join(name);
+    let json = serde_json::to_string_pretty(fixture).map_err(|e| e.to_string())?;
+    fs::write(&path, json + "\n").map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+/// Path and query of `url` below the endpoint it was sent to, without an
+/// API key.
+fn path_below(url: &str, endpoints: &[String]) -> String {
+    let rest = endpoints
+        .iter()
+        .find_map(|endpoint| url.strip_prefix(endpoint.trim_end_matches('/')))
+        .unwrap_or(url);
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("key="))
+        .collect();
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward::context::{AuthMode, ModelInfo, Provider, RequestMeta, UpstreamInfo};
+    use crate::forward::handlers;
+    use axum::body::{Body, Bytes};
+    use axum::http::Uri;
+    use axum::response::Response;
+    use once_cell::sync::Lazy;
+    use regex::Regex;
+    use serde_json::json;
+    use std::convert::Infallible;
+
+    const DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+
+    /// Path and body of the request the replay server got.
+    type Sent = Arc<Mutex<Option<(String, Value)>>>;
+
+    /// Answer with the recording of `fixture` on a loopback port.
+    async fn serve(fixture: &Fixture) -> (String, Sent) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let sent = Sent::default();
+        let seen = sent.clone();
+        let upstream = fixture.upstream.clone();
+        let app = axum::Router::new().fallback(move |uri: Uri, body: Bytes| {
+            let seen = seen.clone();
+            let upstream = upstream.clone();
+            async move {
+                let path = uri
+                    .path_and_query()
+                    .map(|p| p.to_string())
+                    .unwrap_or_default();
+                let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                *seen.lock().unwrap() = Some((path, body));
+                let chunks = upstream
+                    .chunks
+                    .into_iter()
+                    .map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+                Response::builder()
+                    .header("content-type", upstream.content_type)
+                    .body(Body::from_stream(futures_util::stream::iter(chunks)))
+                    .unwrap()
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, sent)
+    }
+
+    fn context(fixture: &Fixture, endpoint: String) -> ForwardContext {
+        ForwardContext {
+            auth_mode: AuthMode::UseConfiguredKey,
+            model: ModelInfo {
+                id: fixture.model.clone(),
+                display_name: fixture.model.clone(),
+                provider: Provider::from_str(&fixture.provider).unwrap(),
+                upstream_id: fixture.upstream_id.clone(),
+                upstream_model_id: Some(fixture.upstream_model.clone()),
+                pricing: Default::default(),
+                capabilities: Default::default(),
+            },
+            upstream: UpstreamInfo {
+                id: fixture.upstream_id.clone(),
+                endpoints: vec![endpoint],
+                api_style: Some(fixture.api_style.clone()).filter(|s| !s.is_empty()),
+                no_proxy: true,
+                ..Default::default()
+            },
+            gemini_api_version: fixture.gemini_api_version.clone(),
+            // Like a mirror copy, a replay is never logged as usage
+            meta: RequestMeta {
+                mirrored: true,
+                ..Default::default()
+            },
+            is_streaming: fixture.streaming,
+            retry_max_attempts_override: Some(1),
+            route: Default::default(),
+        }
+    }
+
+    /// Lines of the body the client gets when `fixture` is replayed, and
+    /// what was sent upstream.
+    async fn replay(name: &str, fixture: &Fixture) -> (Vec<String>, (String, Value)) {
+        let (endpoint, sent) = serve(fixture).await;
+        let ctx = context(fixture, endpoint);
+        let handler = handlers::get_handler(ctx.model.provider);
+        let body = if fixture.streaming {
+            let response = handler
+                .handle_stream(ctx, fixture.request.clone())
+                .await
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        } else {
+            let response = handler
+                .handle_request(ctx, Arc::new(fixture.request.clone()))
+                .await
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            response.body.to_string()
+        };
+        let sent = sent
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| panic!("{}: nothing sent upstream", name));
+        (body.split('\n').map(normalize).collect(), sent)
+    }
+
+    static CREATED: Lazy<Regex> = Lazy::new(|| Regex::new(r#""created":\s*\d+"#).unwrap());
+    static STAMPED_ID: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#""id":\s*"([A-Za-z0-9_]*_)\d{9,}""#).unwrap());
+
+    /// `line` with timestamps and the ids made from them zeroed, leaving
+    /// every other byte as it was.
+    fn normalize(line: &str) -> String {
+        let line = CREATED.replace_all(line, r#""created":0"#);
+        STAMPED_ID
+            .replace_all(&line, r#""id":"${1}0""#)
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_fixtures_replay_unchanged() {
+        let _settings = config::install_for_tests(config::Settings::default());
+        let bless = std::env::var_os("CCR_BLESS_FIXTURES").is_some();
+        let mut paths: Vec<PathBuf> = fs::read_dir(DIR)
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no fixtures in {}", DIR);
+
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let mut fixture: Fixture = serde_json::from_str(&fs::read_to_string(&path).unwrap())
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let (lines, (sent_path, mut sent_body)) = replay(&name, &fixture).await;
+            capture::redact_value(&mut sent_body);
+            assert_eq!(sent_path, fixture.upstream.path, "{}: upstream path", name);
+            assert_eq!(
+                sent_body, fixture.upstream.body,
+                "{}: upstream request",
+                name
+            );
+
+            if bless {
+                fixture.expected = Some(lines);
+                let json = serde_json::to_string_pretty(&fixture).unwrap();
+                fs::write(&path, json + "\n").unwrap();
+                continue;
+            }
+            let expected = fixture.expected.as_ref().unwrap_or_else(|| {
+                panic!(
+                    "{}: no expected output yet, run with CCR_BLESS_FIXTURES=1",
+                    name
+                )
+            });
+            assert_eq!(&lines, expected, "{}: client output", name);
+        }
+    }
+
+    #[test]
+    fn test_recording_keeps_split_characters_and_drops_keys() {
+        let recording =
+            Recording::new(Fixture::default(), vec!["https://host/v1beta/".to_string()]);
+        recording.request(
+            "https://host/v1beta/models/g:streamGenerateContent?alt=sse&key=AIza-secret",
+            &json!({ "contents": [], "api_key": "secret" }),
+        );
+        let text = "data: {\"text\":\"héllo\"}\n\n";
+        let split = text.find('é').unwrap() + 1;
+        recording.chunk(&text.as_bytes()[..split]);
+        recording.chunk(&text.as_bytes()[split..]);
+
+        let state = recording.state();
+        let upstream = &state.fixture.upstream;
+        assert_eq!(upstream.path, "/models/g:streamGenerateContent?alt=sse");
+        assert_eq!(upstream.body["api_key"], crate::redact::REDACTED);
+        assert_eq!(upstream.chunks.concat(), text);
+        assert_eq!(upstream.chunks.len(), 2);
+        // Never complete, so dropping it writes nothing
+        assert!(!state.complete);
+    }
+}
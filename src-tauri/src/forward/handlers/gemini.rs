@@ -12,11 +12,19 @@ use futures_util::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde_json::Value;
 use std::sync::{Arc, Mutex};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 
+use crate::adapters::gemini::parse_gemini_usage;
+use crate::adapters::openai::OpenAIAdapter;
+use crate::adapters::{
+    self, AnthropicAdapter, GeminiAdapter, ModelAdapter, ParamPolicy, StreamMap,
+};
+use crate::forward::{self, capture};
 use crate::forward::client::{self, drain_sse_lines, is_sse_done, parse_sse_data};
 use crate::forward::context::{estimate_tokens, ForwardContext, Provider, TokenUsage, UpstreamResponse};
 use crate::forward::error::{ForwardError, ForwardResult};
+use crate::forward::timing;
+use crate::config::{self, SafetySetting};
 use crate::logger;
 
 use super::{anthropic, ProviderHandlerImpl};
@@ -105,11 +113,15 @@ impl ProviderHandlerImpl for GeminiHandler {
             }
         }
 
+        ctx.apply_extra_headers(&mut headers);
         headers
     }
 
     fn transform_request(&self, ctx: &ForwardContext, payload: &Value) -> Value {
-        let filtered = filter_payload(payload, ALLOWED_FIELDS, ctx);
+        let mut filtered = filter_payload(payload, ALLOWED_FIELDS, ctx);
+        GeminiAdapter::default()
+            .normalize_request(&mut filtered, &ParamPolicy::for_model(&ctx.model));
+        apply_safety_settings(ctx, &mut filtered);
 
         // Log the transformed request
         crate::logger::debug(
@@ -140,41 +152,39 @@ impl ProviderHandlerImpl for GeminiHandler {
     async fn handle_request(
         &self,
         ctx: ForwardContext,
-        payload: Value,
+        payload: Arc<Value>,
     ) -> ForwardResult<UpstreamResponse> {
         let upstream_style = upstream_style(&ctx);
         if upstream_style != Provider::Gemini {
             return match upstream_style {
-                Provider::OpenAI => handle_gemini_to_openai_request(ctx, payload).await,
-                Provider::Anthropic => handle_gemini_to_anthropic_request(ctx, payload).await,
+                Provider::OpenAI => handle_gemini_to_openai_request(ctx, &payload).await,
+                Provider::Anthropic => handle_gemini_to_anthropic_request(ctx, &payload).await,
                 Provider::Gemini => unreachable!(),
             };
         }
 
         let start = Instant::now();
 
-        crate::logger::info(
+        crate::logger::log_with_fields(
+            crate::logger::LogLevel::Info,
             "gemini",
             &format!(
                 "Request started: model={}, upstream={}, streaming=false",
                 ctx.model.id,
                 ctx.upstream.id
             ),
+            &ctx.log_fields(),
         );
 
         // Build request
         let _headers = self.build_headers(&ctx);
         let body = self.transform_request(&ctx, &payload);
         let config = ctx.retry_config();
-        let client = client::default_client()?;
+        let client = client::default_client_for(&ctx.upstream)?;
 
         // Build URL with model
         let model = ctx.model.upstream_model();
-        let path = format!(
-            "/{}/models/{}:generateContent",
-            ctx.gemini_version(),
-            model
-        );
+        let path = ctx.generate_path(model, false);
 
         // Log the request URL
         let endpoint = ctx.primary_endpoint().unwrap_or("unknown");
@@ -192,7 +202,8 @@ impl ProviderHandlerImpl for GeminiHandler {
                 let base = format!("{}{}", ep.trim_end_matches('/'), path);
                 if use_query_key {
                     if let Some(api_key) = ctx.get_api_key() {
-                        format!("{}?key={}", base, api_key)
+                        let separator = if base.contains('?') { "&" } else { "?" };
+                        format!("{}{}key={}", base, separator, api_key)
                     } else {
                         base
                     }
@@ -215,37 +226,36 @@ impl ProviderHandlerImpl for GeminiHandler {
 
         // Parse response
         let status = result.response.status();
+        let upstream_headers = result.response.headers().clone();
         let status_code = status.as_u16();
+        let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
+            ForwardError::request_failed(format!("Failed to read response: {}", e))
+        })?;
         let response_body: Value =
-            result.response.json().await.map_err(|e| {
-                ForwardError::RequestFailed(format!("Failed to parse response: {}", e))
-            })?;
+            client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id)
+                .map_err(|e| {
+                    ForwardError::request_failed(format!("Failed to parse response: {}", e))
+                })?;
 
         // Check if response indicates an error
         if !status.is_success() {
-            // Don't log usage for failed requests
-            return Err(ForwardError::RequestFailed(format!(
-                "Upstream returned {}: {}",
+            // Logged as a failed request once no attempt is left
+            return Err(ForwardError::upstream(
                 status_code,
-                response_body.to_string()
-            )));
+                &response_body.to_string(),
+            ));
         }
 
         // Check for blocked content
-        if let Some(block_reason) = response_body
-            .get("promptFeedback")
-            .and_then(|pf| pf.get("blockReason"))
-        {
-            return Err(ForwardError::RequestFailed(format!(
-                "Content blocked: {:?}",
-                block_reason
-            )));
+        if let Some(err) = blocked(&response_body) {
+            return Err(err);
         }
 
         // Extract usage
         let mut usage = extract_usage(&response_body);
         if usage.prompt_tokens == 0 {
             usage.prompt_tokens = self.estimate_request_tokens(&payload);
+            usage.prompt_estimated = true;
         }
 
         let latency_ms = start.elapsed().as_millis() as u64;
@@ -258,6 +268,8 @@ impl ProviderHandlerImpl for GeminiHandler {
             latency_ms,
             status: status_code,
             usage,
+            audio_dropped: 0,
+            headers: upstream_headers,
         })
     }
 
@@ -274,7 +286,7 @@ impl ProviderHandlerImpl for GeminiHandler {
         // Build request
         let body = self.transform_request(&ctx, &payload);
 
-        let client = client::streaming_client()?;
+        let client = client::streaming_client_for(&ctx.upstream)?;
         let endpoint = ctx
             .primary_endpoint()
             .ok_or_else(|| ForwardError::UpstreamNotFound("No endpoints configured".to_string()))?;
@@ -282,11 +294,10 @@ impl ProviderHandlerImpl for GeminiHandler {
         // Build streaming URL with ?alt=sse
         let model = ctx.model.upstream_model();
         let mut url = format!(
-            "{}/{}/models/{}:streamGenerateContent?alt=sse",
-            endpoint.trim_end_matches('/'),
-            ctx.gemini_version(),
-            model
-        );
+        "{}{}",
+        endpoint.trim_end_matches('/'),
+        ctx.generate_path(model, true)
+    );
 
         // Add API key if using Gemini-style query auth
         if should_use_query_key(&ctx, endpoint) {
@@ -296,18 +307,25 @@ impl ProviderHandlerImpl for GeminiHandler {
         }
 
         // Make request
+        let headers = self.build_headers(&ctx);
+        let body = forward::scrub::outbound(&body);
+        capture::record_request(&url, &headers, &body);
         let response = client
             .post(&url)
-            .headers(self.build_headers(&ctx))
+            .headers(headers)
             .json(&body)
             .send()
             .await
-            .map_err(|e| ForwardError::RequestFailed(e.to_string()))?;
+            .map_err(|e| ForwardError::request_failed(e.to_string()))?;
+        forward::ratelimits::observe(response.headers());
 
         if !response.status().is_success() {
             let status = response.status();
+            let response_headers = response.headers().clone();
             let text = response.text().await.unwrap_or_default();
-            return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+            return Err(ForwardError::upstream(status.as_u16(), &text)
+                .with_retry_after(&response_headers)
+                .at_endpoint(endpoint));
         }
 
         // Clone context for use in stream processing
@@ -315,11 +333,11 @@ impl ProviderHandlerImpl for GeminiHandler {
         let estimated_prompt_tokens = self.estimate_request_tokens(&payload);
 
         // Create usage tracker
-        let usage_tracker = Arc::new(Mutex::new(TokenUsage::new(estimated_prompt_tokens, 0)));
+        let usage_tracker = Arc::new(Mutex::new(TokenUsage::estimated(estimated_prompt_tokens)));
         let usage_tracker_clone = Arc::clone(&usage_tracker);
 
         // Stream the response and parse SSE events
-        let stream = response.bytes_stream().map(move |result| {
+        let stream = capture::tap(response.bytes_stream()).map(move |result| {
             match result {
                 Ok(bytes) => {
                     if let Ok(text) = std::str::from_utf8(&bytes) {
@@ -328,22 +346,11 @@ impl ProviderHandlerImpl for GeminiHandler {
                                 if let Ok(json) = serde_json::from_str::<Value>(data) {
                                     // Extract usage from usageMetadata
                                     if let Some(metadata) = json.get("usageMetadata") {
-                                        let prompt_tokens = metadata
-                                            .get("promptTokenCount")
-                                            .and_then(|v| v.as_i64())
-                                            .unwrap_or(0);
-                                        let candidates_tokens = metadata
-                                            .get("candidatesTokenCount")
-                                            .and_then(|v| v.as_i64())
-                                            .unwrap_or(0);
+                                        let chunk_usage = parse_gemini_usage(metadata);
 
                                         if let Ok(mut tracker) = usage_tracker_clone.lock() {
-                                            if prompt_tokens > 0 {
-                                                tracker.prompt_tokens = prompt_tokens;
-                                            }
-                                            if candidates_tokens > tracker.completion_tokens {
-                                                tracker.completion_tokens = candidates_tokens;
-                                            }
+                                            // usageMetadata is cumulative; the last one wins
+                                            tracker.apply_reported(&chunk_usage);
                                         }
                                     }
 
@@ -365,10 +372,7 @@ impl ProviderHandlerImpl for GeminiHandler {
                                                             if let Ok(mut tracker) =
                                                                 usage_tracker_clone.lock()
                                                             {
-                                                                if tracker.completion_tokens == 0 {
-                                                                    tracker.completion_tokens +=
-                                                                        tokens;
-                                                                }
+                                                                tracker.add_estimated_output(tokens);
                                                             }
                                                         }
                                                     }
@@ -439,7 +443,7 @@ fn filter_payload(payload: &Value, allowed: &[&str], _ctx: &ForwardContext) -> V
 }
 
 /// Filter generationConfig to only include allowed fields
-fn filter_generation_config(config: &Value) -> Option<Value> {
+pub(crate) fn filter_generation_config(config: &Value) -> Option<Value> {
     if let Some(obj) = config.as_object() {
         let has_max_output = obj
             .get("maxOutputTokens")
@@ -495,6 +499,62 @@ fn filter_generation_config(config: &Value) -> Option<Value> {
     }
 }
 
+/// Add the default `safetySettings` of `ctx`'s model, then of its upstream,
+/// for each harm category the request doesn't set itself.
+pub(crate) fn apply_safety_settings(ctx: &ForwardContext, body: &mut Value) {
+    let cfg = config::current();
+    let model = cfg
+        .models
+        .iter()
+        .find(|m| m.id == ctx.model.id)
+        .map(|m| m.safety_settings.as_slice())
+        .unwrap_or_default();
+    let upstream = cfg
+        .upstreams
+        .iter()
+        .find(|u| u.id == ctx.upstream.id)
+        .map(|u| u.safety_settings.as_slice())
+        .unwrap_or_default();
+    merge_safety_settings(body, model.iter().chain(upstream));
+}
+
+fn merge_safety_settings<'a>(body: &mut Value, defaults: impl Iterator<Item = &'a SafetySetting>) {
+    let Some(obj) = body.as_object_mut() else {
+        return;
+    };
+    let mut settings = match obj.remove("safetySettings") {
+        Some(Value::Array(settings)) => settings,
+        _ => Vec::new(),
+    };
+    for setting in defaults {
+        let category = setting.category.trim();
+        let set = settings
+            .iter()
+            .any(|s| s.get("category").and_then(Value::as_str) == Some(category));
+        if !set {
+            settings.push(serde_json::json!({
+                "category": category,
+                "threshold": setting.threshold.trim(),
+            }));
+        }
+    }
+    if !settings.is_empty() {
+        obj.insert("safetySettings".to_string(), Value::Array(settings));
+    }
+}
+
+/// The error for a response whose prompt Gemini's safety filters blocked.
+pub(crate) fn blocked(response: &Value) -> Option<ForwardError> {
+    let reason = response
+        .get("promptFeedback")
+        .and_then(|pf| pf.get("blockReason"))?;
+    let reason = reason
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| reason.to_string());
+    Some(ForwardError::ContentFiltered(reason))
+}
+
 pub(crate) fn should_use_query_key(ctx: &ForwardContext, endpoint: &str) -> bool {
     if let Some(style) = ctx.upstream.api_style.as_deref() {
         if style.eq_ignore_ascii_case("gemini") {
@@ -513,6 +573,42 @@ fn is_google_gemini_endpoint(endpoint: &str) -> bool {
     normalized.contains("googleapis.com")
 }
 
+/// Body a non-streaming request is sent upstream with, converted for the
+/// upstream's API style like `handle_request` does.
+pub(crate) fn upstream_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    match upstream_style(ctx) {
+        Provider::Gemini => GeminiHandler.transform_request(ctx, payload),
+        Provider::OpenAI => {
+            let mut body = openai_body(ctx, payload);
+            client::normalize_stream_flag(&mut body);
+            body
+        }
+        Provider::Anthropic => {
+            let mut body = anthropic_body(ctx, payload);
+            client::normalize_stream_flag(&mut body);
+            body
+        }
+    }
+}
+
+fn openai_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    let mut body =
+        GeminiAdapter::configured().request_to_openai(payload, ctx.model.upstream_model());
+    OpenAIAdapter.normalize_request(&mut body, &ParamPolicy::for_model(&ctx.model));
+    body
+}
+
+fn anthropic_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    let mut body = adapters::convert_request(
+        &GeminiAdapter::configured(),
+        &AnthropicAdapter::default(),
+        payload,
+        ctx.model.upstream_model(),
+    );
+    AnthropicAdapter::default().normalize_request(&mut body, &ParamPolicy::for_model(&ctx.model));
+    body
+}
+
 fn upstream_style(ctx: &ForwardContext) -> Provider {
     ctx.upstream
         .api_style
@@ -563,31 +659,10 @@ fn is_undefined_string(value: &str) -> bool {
 
 /// Extract usage from Gemini response
 fn extract_usage(response: &Value) -> TokenUsage {
-    if let Some(metadata) = response.get("usageMetadata") {
-        let prompt = metadata
-            .get("promptTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let candidates = metadata
-            .get("candidatesTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let cached = metadata
-            .get("cachedContentTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-
-        TokenUsage::new(prompt + cached, candidates)
-    } else {
-        TokenUsage::default()
-    }
-}
-
-fn epoch_seconds() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs() as i64)
-        .unwrap_or(0)
+    response
+        .get("usageMetadata")
+        .map(parse_gemini_usage)
+        .unwrap_or_default()
 }
 
 pub(crate) fn build_gemini_endpoints(ctx: &ForwardContext, path: &str) -> Vec<String> {
@@ -601,7 +676,8 @@ pub(crate) fn build_gemini_endpoints(ctx: &ForwardContext, path: &str) -> Vec<St
             let base = format!("{}{}", ep.trim_end_matches('/'), path);
             if use_query_key {
                 if let Some(api_key) = ctx.get_api_key() {
-                    format!("{}?key={}", base, api_key)
+                    let separator = if base.contains('?') { "&" } else { "?" };
+                    format!("{}{}key={}", base, separator, api_key)
                 } else {
                     base
                 }
@@ -615,10 +691,9 @@ pub(crate) fn build_gemini_endpoints(ctx: &ForwardContext, path: &str) -> Vec<St
 pub(crate) fn build_gemini_stream_url(ctx: &ForwardContext, model: &str) -> Option<String> {
     let endpoint = ctx.primary_endpoint()?;
     let mut url = format!(
-        "{}/{}/models/{}:streamGenerateContent?alt=sse",
+        "{}{}",
         endpoint.trim_end_matches('/'),
-        ctx.gemini_version(),
-        model
+        ctx.generate_path(model, true)
     );
     if should_use_query_key(ctx, endpoint) {
         if let Some(api_key) = ctx.get_api_key() {
@@ -628,940 +703,6 @@ pub(crate) fn build_gemini_stream_url(ctx: &ForwardContext, model: &str) -> Opti
     Some(url)
 }
 
-fn openai_content_from_parts(parts: Vec<Value>) -> Value {
-    if parts.len() == 1 {
-        if let Some(obj) = parts[0].as_object() {
-            if obj.get("type").and_then(|v| v.as_str()) == Some("text") {
-                if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
-                    return Value::String(text.to_string());
-                }
-            }
-        }
-    }
-    Value::Array(parts)
-}
-
-fn openai_content_to_gemini_parts(content: &Value) -> Vec<Value> {
-    let mut parts_out = Vec::new();
-    match content {
-        Value::String(text) => {
-            if !text.is_empty() {
-                parts_out.push(serde_json::json!({ "text": text }));
-            }
-        }
-        Value::Array(parts) => {
-            for part in parts {
-                let part_type = part.get("type").and_then(|v| v.as_str()).unwrap_or("");
-                match part_type {
-                    "text" => {
-                        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-                            parts_out.push(serde_json::json!({ "text": text }));
-                        }
-                    }
-                    "image_url" => {
-                        let url = part
-                            .get("image_url")
-                            .and_then(|v| v.get("url"))
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("");
-                        if let Some((mime, data)) = parse_data_url(url) {
-                            parts_out.push(serde_json::json!({
-                                "inline_data": { "mime_type": mime, "data": data }
-                            }));
-                        } else if !url.is_empty() {
-                            parts_out.push(serde_json::json!({ "text": format!("[Image] {}", url) }));
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        }
-        Value::Object(obj) => {
-            if let Some(text) = obj.get("text").and_then(|v| v.as_str()) {
-                parts_out.push(serde_json::json!({ "text": text }));
-            }
-        }
-        _ => {}
-    }
-    parts_out
-}
-
-fn parse_data_url(url: &str) -> Option<(String, String)> {
-    let trimmed = url.trim();
-    if !trimmed.starts_with("data:") {
-        return None;
-    }
-    let rest = trimmed.trim_start_matches("data:");
-    let mut parts = rest.splitn(2, ";base64,");
-    let mime = parts.next()?.to_string();
-    let data = parts.next()?.to_string();
-    if mime.is_empty() || data.is_empty() {
-        None
-    } else {
-        Some((mime, data))
-    }
-}
-
-fn is_gemini_thought_part(part: &Value) -> bool {
-    part.get("thought")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false)
-}
-
-fn map_openai_tools_to_gemini(tools: &Value) -> Option<Value> {
-    let tools_array = tools.as_array()?;
-    let mut declarations = Vec::new();
-
-    for tool in tools_array {
-        let tool_type = tool.get("type").and_then(|v| v.as_str()).unwrap_or("");
-        if !tool_type.eq_ignore_ascii_case("function") {
-            continue;
-        }
-        let function = tool.get("function")?;
-        let name = function.get("name")?.as_str()?;
-
-        let mut decl = serde_json::Map::new();
-        decl.insert("name".to_string(), Value::String(name.to_string()));
-        if let Some(desc) = function.get("description") {
-            decl.insert("description".to_string(), desc.clone());
-        }
-        if let Some(params) = function.get("parameters") {
-            decl.insert("parameters".to_string(), params.clone());
-        }
-        declarations.push(Value::Object(decl));
-    }
-
-    if declarations.is_empty() {
-        None
-    } else {
-        Some(Value::Array(vec![serde_json::json!({
-            "functionDeclarations": declarations
-        })]))
-    }
-}
-
-fn map_openai_tool_choice_to_gemini(choice: &Value) -> Option<Value> {
-    let mode = match choice {
-        Value::String(raw) => match raw.to_ascii_uppercase().as_str() {
-            "AUTO" | "auto" => Some(("AUTO".to_string(), None)),
-            "NONE" | "none" => Some(("NONE".to_string(), None)),
-            _ => None,
-        },
-        Value::Object(obj) => {
-            if obj.get("type").and_then(|v| v.as_str()) == Some("function") {
-                let name = obj
-                    .get("function")
-                    .and_then(|v| v.get("name"))
-                    .and_then(|v| v.as_str())?;
-                Some(("ANY".to_string(), Some(vec![name.to_string()])))
-            } else {
-                None
-            }
-        }
-        _ => None,
-    }?;
-
-    let mut config = serde_json::Map::new();
-    config.insert("mode".to_string(), Value::String(mode.0));
-    if let Some(names) = mode.1 {
-        config.insert(
-            "allowedFunctionNames".to_string(),
-            Value::Array(names.into_iter().map(Value::String).collect()),
-        );
-    }
-
-    Some(serde_json::json!({
-        "functionCallingConfig": Value::Object(config)
-    }))
-}
-
-fn map_gemini_tools_to_openai(tools: &Value) -> Option<Value> {
-    let tools_array = tools.as_array()?;
-    let mut mapped = Vec::new();
-
-    for tool in tools_array {
-        let declarations = tool
-            .get("functionDeclarations")
-            .or_else(|| tool.get("function_declarations"))
-            .and_then(|v| v.as_array())?;
-        for decl in declarations {
-            let name = decl.get("name").and_then(|v| v.as_str())?;
-            let mut func = serde_json::Map::new();
-            func.insert("name".to_string(), Value::String(name.to_string()));
-            if let Some(desc) = decl.get("description") {
-                func.insert("description".to_string(), desc.clone());
-            }
-            if let Some(params) = decl.get("parameters") {
-                func.insert("parameters".to_string(), params.clone());
-            }
-            let mut entry = serde_json::Map::new();
-            entry.insert("type".to_string(), Value::String("function".to_string()));
-            entry.insert("function".to_string(), Value::Object(func));
-            mapped.push(Value::Object(entry));
-        }
-    }
-
-    if mapped.is_empty() {
-        None
-    } else {
-        Some(Value::Array(mapped))
-    }
-}
-
-fn map_gemini_tool_config_to_openai(tool_config: &Value) -> Option<Value> {
-    let config = tool_config
-        .get("functionCallingConfig")
-        .or_else(|| tool_config.get("function_calling_config"))?;
-    let mode = config.get("mode").and_then(|v| v.as_str()).unwrap_or("");
-    match mode.to_ascii_uppercase().as_str() {
-        "AUTO" | "ANY" => Some(Value::String("auto".to_string())),
-        "NONE" => Some(Value::String("none".to_string())),
-        _ => {
-            let names = config
-                .get("allowedFunctionNames")
-                .or_else(|| config.get("allowed_function_names"))
-                .and_then(|v| v.as_array())
-                .cloned()
-                .unwrap_or_default();
-            let name = names
-                .iter()
-                .filter_map(|v| v.as_str())
-                .next()?;
-            Some(serde_json::json!({
-                "type": "function",
-                "function": { "name": name }
-            }))
-        }
-    }
-}
-
-fn map_openai_finish_reason_to_gemini(reason: &str) -> String {
-    match reason {
-        "stop" => "STOP",
-        "length" => "MAX_TOKENS",
-        "content_filter" => "SAFETY",
-        "tool_calls" | "function_call" => "STOP",
-        _ => "STOP",
-    }
-    .to_string()
-}
-
-fn map_gemini_finish_reason_to_openai(reason: &str) -> Value {
-    let mapped = match reason.to_ascii_uppercase().as_str() {
-        "STOP" => "stop",
-        "MAX_TOKENS" => "length",
-        "SAFETY" | "RECITATION" => "content_filter",
-        _ => "stop",
-    };
-    Value::String(mapped.to_string())
-}
-
-fn openai_tool_calls_to_gemini_parts(tool_calls: &Value) -> Vec<Value> {
-    let mut parts = Vec::new();
-    if let Some(calls) = tool_calls.as_array() {
-        for call in calls {
-            let name = call
-                .get("function")
-                .and_then(|v| v.get("name"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("tool");
-            let args_raw = call
-                .get("function")
-                .and_then(|v| v.get("arguments"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            let args = serde_json::from_str::<Value>(args_raw)
-                .unwrap_or(Value::String(args_raw.to_string()));
-            parts.push(serde_json::json!({
-                "functionCall": { "name": name, "args": args }
-            }));
-        }
-    }
-    parts
-}
-
-fn build_generation_config_from_openai(payload: &Value) -> Option<Value> {
-    let mut config = serde_json::Map::new();
-
-    if let Some(max_tokens) = payload.get("max_tokens").or_else(|| payload.get("max_completion_tokens")) {
-        config.insert("maxOutputTokens".to_string(), max_tokens.clone());
-    }
-    if let Some(temperature) = payload.get("temperature") {
-        config.insert("temperature".to_string(), temperature.clone());
-    }
-    if let Some(top_p) = payload.get("top_p") {
-        config.insert("topP".to_string(), top_p.clone());
-    }
-    if let Some(penalty) = payload.get("presence_penalty") {
-        config.insert("presencePenalty".to_string(), penalty.clone());
-    }
-    if let Some(penalty) = payload.get("frequency_penalty") {
-        config.insert("frequencyPenalty".to_string(), penalty.clone());
-    }
-    if let Some(seed) = payload.get("seed") {
-        config.insert("seed".to_string(), seed.clone());
-    }
-    if let Some(stop) = payload.get("stop") {
-        let mapped = match stop {
-            Value::String(text) => Value::Array(vec![Value::String(text.clone())]),
-            _ => stop.clone(),
-        };
-        config.insert("stopSequences".to_string(), mapped);
-    }
-
-    if config.is_empty() {
-        None
-    } else {
-        Some(Value::Object(config))
-    }
-}
-
-fn map_generation_config_to_openai(config: &Value) -> serde_json::Map<String, Value> {
-    let mut mapped = serde_json::Map::new();
-    if let Some(max) = config.get("maxOutputTokens") {
-        mapped.insert("max_tokens".to_string(), max.clone());
-    }
-    if let Some(temp) = config.get("temperature") {
-        mapped.insert("temperature".to_string(), temp.clone());
-    }
-    if let Some(top_p) = config.get("topP") {
-        mapped.insert("top_p".to_string(), top_p.clone());
-    }
-    if let Some(stop) = config.get("stopSequences") {
-        mapped.insert("stop".to_string(), stop.clone());
-    }
-    if let Some(penalty) = config.get("presencePenalty") {
-        mapped.insert("presence_penalty".to_string(), penalty.clone());
-    }
-    if let Some(penalty) = config.get("frequencyPenalty") {
-        mapped.insert("frequency_penalty".to_string(), penalty.clone());
-    }
-    if let Some(seed) = config.get("seed") {
-        mapped.insert("seed".to_string(), seed.clone());
-    }
-    mapped
-}
-
-/// Convert OpenAI request format to Gemini format
-pub(crate) fn convert_openai_to_gemini_request(payload: &Value, _model: &str) -> Value {
-    let mut gemini_request = serde_json::Map::new();
-    let mut contents = Vec::new();
-    let mut system_parts = Vec::new();
-
-    if let Some(messages) = payload.get("messages").and_then(|v| v.as_array()) {
-        for msg in messages {
-            let role = msg.get("role").and_then(|v| v.as_str()).unwrap_or("user");
-            if role.eq_ignore_ascii_case("system") {
-                if let Some(content) = msg.get("content") {
-                    let text = openai_content_to_gemini_parts(content)
-                        .iter()
-                        .filter_map(|p| p.get("text").and_then(|v| v.as_str()))
-                        .collect::<Vec<_>>()
-                        .join("\n");
-                    if !text.is_empty() {
-                        system_parts.push(text);
-                    }
-                }
-                continue;
-            }
-            let gem_role = if role.eq_ignore_ascii_case("assistant") {
-                "model"
-            } else {
-                "user"
-            };
-            let mut parts = Vec::new();
-            if let Some(content) = msg.get("content") {
-                parts.extend(openai_content_to_gemini_parts(content));
-            }
-            if let Some(tool_calls) = msg.get("tool_calls") {
-                parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
-            }
-            if let Some(function_call) = msg.get("function_call") {
-                let name = function_call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
-                let args_raw = function_call
-                    .get("arguments")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("");
-                let args = serde_json::from_str::<Value>(args_raw)
-                    .unwrap_or(Value::String(args_raw.to_string()));
-                parts.push(serde_json::json!({ "functionCall": { "name": name, "args": args } }));
-            }
-
-            if parts.is_empty() {
-                parts.push(serde_json::json!({ "text": "" }));
-            }
-
-            contents.push(serde_json::json!({
-                "role": gem_role,
-                "parts": parts
-            }));
-        }
-    }
-
-    if !contents.is_empty() {
-        gemini_request.insert("contents".to_string(), Value::Array(contents));
-    }
-
-    if !system_parts.is_empty() {
-        gemini_request.insert(
-            "systemInstruction".to_string(),
-            serde_json::json!({ "parts": [ { "text": system_parts.join("\n\n") } ] }),
-        );
-    }
-
-    let mut generation_config = build_generation_config_from_openai(payload);
-    if let Some(extra_config) = payload.get("generationConfig").and_then(filter_generation_config) {
-        match (&mut generation_config, extra_config) {
-            (Some(Value::Object(base)), Value::Object(extra)) => {
-                for (key, value) in extra {
-                    base.insert(key, value);
-                }
-            }
-            (_, extra) => {
-                generation_config = Some(extra);
-            }
-        }
-    }
-    if let Some(gen) = generation_config {
-        gemini_request.insert("generationConfig".to_string(), gen);
-    }
-
-    if let Some(tools) = payload.get("tools") {
-        if let Some(mapped) = map_openai_tools_to_gemini(tools) {
-            gemini_request.insert("tools".to_string(), mapped);
-        }
-    }
-
-    if let Some(tool_choice) = payload.get("tool_choice") {
-        if let Some(mapped) = map_openai_tool_choice_to_gemini(tool_choice) {
-            gemini_request.insert("toolConfig".to_string(), mapped);
-        }
-    }
-
-    Value::Object(gemini_request)
-}
-
-/// Convert Gemini request format to OpenAI format
-pub(crate) fn convert_gemini_to_openai_request(payload: &Value, model: &str) -> Value {
-    let mut openai_request = serde_json::Map::new();
-    openai_request.insert("model".to_string(), Value::String(model.to_string()));
-
-    let mut messages = Vec::new();
-
-    if let Some(system_instruction) = payload.get("systemInstruction") {
-        if let Some(text) = system_instruction
-            .get("parts")
-            .and_then(|v| v.as_array())
-            .and_then(|parts| parts.first())
-            .and_then(|p| p.get("text"))
-            .and_then(|v| v.as_str())
-        {
-            messages.push(serde_json::json!({
-                "role": "system",
-                "content": text
-            }));
-        }
-    }
-
-    if let Some(contents) = payload.get("contents").and_then(|v| v.as_array()) {
-        let mut tool_index = 0usize;
-        for content in contents {
-            let role_raw = content.get("role").and_then(|v| v.as_str()).unwrap_or("user");
-            let role = if role_raw.eq_ignore_ascii_case("model") {
-                "assistant"
-            } else {
-                "user"
-            };
-
-            let mut parts_out = Vec::new();
-            let mut tool_calls = Vec::new();
-            let mut tool_messages = Vec::new();
-
-            if let Some(parts) = content.get("parts").and_then(|v| v.as_array()) {
-                for part in parts {
-                    if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-                        parts_out.push(serde_json::json!({ "type": "text", "text": text }));
-                    } else if let Some(inline) = part.get("inline_data") {
-                        let mime = inline.get("mime_type").and_then(|v| v.as_str()).unwrap_or("application/octet-stream");
-                        let data = inline.get("data").and_then(|v| v.as_str()).unwrap_or("");
-                        if !data.is_empty() {
-                            let url = format!("data:{};base64,{}", mime, data);
-                            parts_out.push(serde_json::json!({
-                                "type": "image_url",
-                                "image_url": { "url": url }
-                            }));
-                        }
-                    } else if let Some(file) = part.get("file_data") {
-                        let uri = file.get("file_uri").and_then(|v| v.as_str()).unwrap_or("");
-                        if !uri.is_empty() {
-                            parts_out.push(serde_json::json!({ "type": "text", "text": format!("[File] {}", uri) }));
-                        }
-                    } else if let Some(call) = part.get("functionCall") {
-                        let name = call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
-                        let args = call.get("args").cloned().unwrap_or(Value::Null);
-                        let args_raw = serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string());
-                        let id = format!("gemini_call_{}", tool_index);
-                        tool_index += 1;
-                        tool_calls.push(serde_json::json!({
-                            "id": id,
-                            "type": "function",
-                            "function": { "name": name, "arguments": args_raw }
-                        }));
-                    } else if let Some(response) = part.get("functionResponse") {
-                        let name = response.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
-                        let value = response.get("response").cloned().unwrap_or(Value::Null);
-                        let content_text = value.to_string();
-                        tool_messages.push(serde_json::json!({
-                            "role": "tool",
-                            "tool_call_id": name,
-                            "content": content_text
-                        }));
-                    }
-                }
-            }
-
-            let mut msg = serde_json::Map::new();
-            msg.insert("role".to_string(), Value::String(role.to_string()));
-            if parts_out.is_empty() {
-                msg.insert("content".to_string(), Value::String(String::new()));
-            } else {
-                msg.insert("content".to_string(), openai_content_from_parts(parts_out));
-            }
-            if !tool_calls.is_empty() {
-                msg.insert("tool_calls".to_string(), Value::Array(tool_calls));
-            }
-            messages.push(Value::Object(msg));
-            messages.extend(tool_messages);
-        }
-    }
-
-    if !messages.is_empty() {
-        openai_request.insert("messages".to_string(), Value::Array(messages));
-    }
-
-    if let Some(gen_config) = payload.get("generationConfig") {
-        for (key, value) in map_generation_config_to_openai(gen_config) {
-            openai_request.insert(key, value);
-        }
-    }
-
-    if let Some(tools) = payload.get("tools") {
-        if let Some(mapped) = map_gemini_tools_to_openai(tools) {
-            openai_request.insert("tools".to_string(), mapped);
-        }
-    }
-    if let Some(tool_config) = payload.get("toolConfig") {
-        if let Some(mapped) = map_gemini_tool_config_to_openai(tool_config) {
-            openai_request.insert("tool_choice".to_string(), mapped);
-        }
-    }
-
-    Value::Object(openai_request)
-}
-
-/// Convert Gemini response format to OpenAI format
-pub(crate) fn convert_gemini_response_to_openai(response: &Value, model: &str) -> Value {
-    let id = format!("chatcmpl_gemini_{}", epoch_seconds());
-    let created = epoch_seconds();
-
-    let mut choices = Vec::new();
-    if let Some(candidates) = response.get("candidates").and_then(|v| v.as_array()) {
-        for (idx, candidate) in candidates.iter().enumerate() {
-            let finish_reason = candidate
-                .get("finishReason")
-                .and_then(|v| v.as_str())
-                .map(map_gemini_finish_reason_to_openai);
-
-            let (content, tool_calls, reasoning) = candidate
-                .get("content")
-                .map(|c| {
-                    let mut tool_calls = Vec::new();
-                    let mut parts_out = Vec::new();
-                    let mut thoughts = Vec::new();
-                    if let Some(parts) = c.get("parts").and_then(|v| v.as_array()) {
-                        for (tool_idx, part) in parts.iter().enumerate() {
-                            if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-                                if is_gemini_thought_part(part) {
-                                    thoughts.push(text.to_string());
-                                } else {
-                                    parts_out.push(serde_json::json!({ "type": "text", "text": text }));
-                                }
-                            } else if let Some(call) = part.get("functionCall") {
-                                let name = call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
-                                let args = call.get("args").cloned().unwrap_or(Value::Null);
-                                let args_raw = serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string());
-                                tool_calls.push(serde_json::json!({
-                                    "id": format!("gemini_call_{}", tool_idx),
-                                    "type": "function",
-                                    "function": { "name": name, "arguments": args_raw }
-                                }));
-                            }
-                        }
-                    }
-                    let content_value = if parts_out.is_empty() {
-                        Value::String(String::new())
-                    } else {
-                        openai_content_from_parts(parts_out)
-                    };
-                    let reasoning_value = if thoughts.is_empty() {
-                        None
-                    } else {
-                        Some(Value::String(thoughts.join("\n")))
-                    };
-                    (content_value, tool_calls, reasoning_value)
-                })
-                .unwrap_or_else(|| (Value::String(String::new()), Vec::new(), None));
-
-            let mut message = serde_json::Map::new();
-            message.insert("role".to_string(), Value::String("assistant".to_string()));
-            message.insert("content".to_string(), content);
-            if let Some(reasoning_content) = reasoning {
-                message.insert("reasoning_content".to_string(), reasoning_content);
-            }
-            if !tool_calls.is_empty() {
-                message.insert("tool_calls".to_string(), Value::Array(tool_calls));
-            }
-
-            choices.push(serde_json::json!({
-                "index": idx as i64,
-                "message": Value::Object(message),
-                "finish_reason": finish_reason
-            }));
-        }
-    }
-
-    let usage = response.get("usageMetadata").map(|usage| {
-        let prompt = usage
-            .get("promptTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let cached = usage
-            .get("cachedContentTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let completion = usage
-            .get("candidatesTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        serde_json::json!({
-            "prompt_tokens": prompt + cached,
-            "completion_tokens": completion,
-            "total_tokens": prompt + cached + completion
-        })
-    }).unwrap_or_else(|| serde_json::json!({
-        "prompt_tokens": 0,
-        "completion_tokens": 0,
-        "total_tokens": 0
-    }));
-
-    serde_json::json!({
-        "id": id,
-        "object": "chat.completion",
-        "created": created,
-        "model": model,
-        "choices": choices,
-        "usage": usage
-    })
-}
-
-/// Convert OpenAI response format to Gemini format
-pub(crate) fn convert_openai_response_to_gemini(response: &Value, model: &str) -> Value {
-    let mut candidates = Vec::new();
-    if let Some(choices) = response.get("choices").and_then(|v| v.as_array()) {
-        for (idx, choice) in choices.iter().enumerate() {
-            let finish_reason = choice
-                .get("finish_reason")
-                .and_then(|v| v.as_str())
-                .map(map_openai_finish_reason_to_gemini);
-
-            let message = choice.get("message").or_else(|| choice.get("delta"));
-            let mut parts = Vec::new();
-            if let Some(msg) = message {
-                if let Some(reasoning) = msg.get("reasoning_content") {
-                    if let Some(text) = reasoning.as_str() {
-                        parts.push(serde_json::json!({ "text": text, "thought": true }));
-                    } else if !reasoning.is_null() {
-                        parts.push(serde_json::json!({
-                            "text": reasoning.to_string(),
-                            "thought": true
-                        }));
-                    }
-                }
-                if let Some(content) = msg.get("content") {
-                    parts.extend(openai_content_to_gemini_parts(content));
-                }
-                if let Some(tool_calls) = msg.get("tool_calls") {
-                    parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
-                }
-            }
-            if parts.is_empty() {
-                parts.push(serde_json::json!({ "text": "" }));
-            }
-
-            candidates.push(serde_json::json!({
-                "index": idx as i64,
-                "content": { "role": "model", "parts": parts },
-                "finishReason": finish_reason
-            }));
-        }
-    }
-
-    let usage = response.get("usage").map(|usage| {
-        let prompt = usage
-            .get("prompt_tokens")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let completion = usage
-            .get("completion_tokens")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        serde_json::json!({
-            "promptTokenCount": prompt,
-            "candidatesTokenCount": completion,
-            "totalTokenCount": prompt + completion
-        })
-    });
-
-    let mut response_obj = serde_json::Map::new();
-    response_obj.insert("candidates".to_string(), Value::Array(candidates));
-    response_obj.insert("modelVersion".to_string(), Value::String(model.to_string()));
-    if let Some(usage_metadata) = usage {
-        response_obj.insert("usageMetadata".to_string(), usage_metadata);
-    }
-
-    Value::Object(response_obj)
-}
-
-pub(crate) struct GeminiToOpenAIStreamState {
-    pub id: String,
-    pub created: i64,
-    pub model: String,
-    pub sent_role: bool,
-    pub prompt_tokens: i64,
-    pub completion_tokens: i64,
-    pub finished: bool,
-}
-
-impl GeminiToOpenAIStreamState {
-    pub fn new(model: &str) -> Self {
-        let now = epoch_seconds();
-        Self {
-            id: format!("chatcmpl_gemini_{}", now),
-            created: now,
-            model: model.to_string(),
-            sent_role: false,
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            finished: false,
-        }
-    }
-}
-
-fn build_openai_stream_chunk(
-    state: &GeminiToOpenAIStreamState,
-    delta: Value,
-    finish_reason: Option<Value>,
-    include_usage: bool,
-) -> Value {
-    let mut chunk = serde_json::json!({
-        "id": state.id,
-        "object": "chat.completion.chunk",
-        "created": state.created,
-        "model": state.model,
-        "choices": [{
-            "index": 0,
-            "delta": delta,
-            "finish_reason": finish_reason
-        }]
-    });
-    if include_usage {
-        if let Some(obj) = chunk.as_object_mut() {
-            obj.insert(
-                "usage".to_string(),
-                serde_json::json!({
-                    "prompt_tokens": state.prompt_tokens,
-                    "completion_tokens": state.completion_tokens,
-                    "total_tokens": state.prompt_tokens + state.completion_tokens
-                }),
-            );
-        }
-    }
-    chunk
-}
-
-pub(crate) fn convert_gemini_event_to_openai_chunks(
-    event: &Value,
-    state: &mut GeminiToOpenAIStreamState,
-) -> Vec<Value> {
-    let mut out = Vec::new();
-
-    if let Some(metadata) = event.get("usageMetadata") {
-        let prompt = metadata
-            .get("promptTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let completion = metadata
-            .get("candidatesTokenCount")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        if prompt > 0 {
-            state.prompt_tokens = prompt;
-        }
-        if completion > 0 {
-            state.completion_tokens = completion;
-        }
-    }
-
-    if let Some(candidates) = event.get("candidates").and_then(|v| v.as_array()) {
-        for candidate in candidates {
-            if !state.sent_role {
-                out.push(build_openai_stream_chunk(
-                    state,
-                    serde_json::json!({ "role": "assistant" }),
-                    None,
-                    false,
-                ));
-                state.sent_role = true;
-            }
-
-            if let Some(content) = candidate.get("content") {
-                if let Some(parts) = content.get("parts").and_then(|v| v.as_array()) {
-                    for part in parts {
-                        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-                            let delta = if is_gemini_thought_part(part) {
-                                serde_json::json!({ "reasoning_content": text })
-                            } else {
-                                serde_json::json!({ "content": text })
-                            };
-                            out.push(build_openai_stream_chunk(state, delta, None, false));
-                        } else if let Some(call) = part.get("functionCall") {
-                            let name = call.get("name").and_then(|v| v.as_str()).unwrap_or("tool");
-                            let args = call.get("args").cloned().unwrap_or(Value::Null);
-                            let args_raw =
-                                serde_json::to_string(&args).unwrap_or_else(|_| "{}".to_string());
-                            let delta = serde_json::json!({
-                                "tool_calls": [{
-                                    "index": 0,
-                                    "id": "gemini_call",
-                                    "type": "function",
-                                    "function": { "name": name, "arguments": args_raw }
-                                }]
-                            });
-                            out.push(build_openai_stream_chunk(state, delta, None, false));
-                        }
-                    }
-                }
-            }
-
-            let finish_reason = candidate
-                .get("finishReason")
-                .and_then(|v| v.as_str())
-                .map(map_gemini_finish_reason_to_openai);
-            if finish_reason.is_some() {
-                state.finished = true;
-                out.push(build_openai_stream_chunk(
-                    state,
-                    serde_json::json!({}),
-                    finish_reason,
-                    true,
-                ));
-            }
-        }
-    }
-
-    out
-}
-
-pub(crate) struct OpenAIToGeminiStreamState {
-    pub prompt_tokens: i64,
-    pub completion_tokens: i64,
-}
-
-impl OpenAIToGeminiStreamState {
-    pub fn new() -> Self {
-        Self {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-        }
-    }
-}
-
-pub(crate) fn convert_openai_chunk_to_gemini(
-    chunk: &Value,
-    state: &mut OpenAIToGeminiStreamState,
-) -> Vec<Value> {
-    let mut out = Vec::new();
-
-    if let Some(usage) = chunk.get("usage") {
-        if let Some(prompt) = usage.get("prompt_tokens").and_then(|v| v.as_i64()) {
-            state.prompt_tokens = prompt;
-        }
-        if let Some(completion) = usage.get("completion_tokens").and_then(|v| v.as_i64()) {
-            state.completion_tokens = completion;
-        }
-    }
-
-    if let Some(choices) = chunk.get("choices").and_then(|v| v.as_array()) {
-        for choice in choices {
-            let delta = choice.get("delta").unwrap_or(&Value::Null);
-            let mut parts = Vec::new();
-
-            if let Some(text) = delta.get("content").and_then(|v| v.as_str()) {
-                parts.push(serde_json::json!({ "text": text }));
-            }
-            if let Some(reasoning) = delta.get("reasoning_content") {
-                if let Some(text) = reasoning.as_str() {
-                    parts.push(serde_json::json!({ "text": text, "thought": true }));
-                } else if !reasoning.is_null() {
-                    parts.push(serde_json::json!({
-                        "text": reasoning.to_string(),
-                        "thought": true
-                    }));
-                }
-            }
-            if let Some(tool_calls) = delta.get("tool_calls") {
-                parts.extend(openai_tool_calls_to_gemini_parts(tool_calls));
-            }
-
-            let finish_reason = choice
-                .get("finish_reason")
-                .and_then(|v| v.as_str())
-                .map(map_openai_finish_reason_to_gemini);
-
-            if parts.is_empty() && finish_reason.is_none() && chunk.get("usage").is_none() {
-                continue;
-            }
-
-            let mut candidate = serde_json::Map::new();
-            candidate.insert(
-                "content".to_string(),
-                serde_json::json!({ "role": "model", "parts": parts }),
-            );
-            if let Some(reason) = finish_reason {
-                candidate.insert("finishReason".to_string(), Value::String(reason));
-            }
-
-            let mut event = serde_json::Map::new();
-            event.insert("candidates".to_string(), Value::Array(vec![Value::Object(candidate)]));
-
-            if state.prompt_tokens > 0 || state.completion_tokens > 0 {
-                event.insert(
-                    "usageMetadata".to_string(),
-                    serde_json::json!({
-                        "promptTokenCount": state.prompt_tokens,
-                        "candidatesTokenCount": state.completion_tokens,
-                        "totalTokenCount": state.prompt_tokens + state.completion_tokens
-                    }),
-                );
-            }
-
-            out.push(Value::Object(event));
-        }
-    }
-
-    out
-}
-
 fn estimate_gemini_prompt_tokens(payload: &Value) -> i64 {
     let contents = payload
         .get("contents")
@@ -1578,27 +719,29 @@ fn build_openai_headers(ctx: &ForwardContext) -> HeaderMap {
         }
     }
     headers.insert("content-type", HeaderValue::from_static("application/json"));
+    ctx.apply_extra_headers(&mut headers);
     headers
 }
 
 async fn handle_gemini_to_openai_request(
     ctx: ForwardContext,
-    payload: Value,
+    payload: &Value,
 ) -> ForwardResult<UpstreamResponse> {
     let start = Instant::now();
     let upstream_ctx = with_provider(&ctx, Provider::OpenAI);
 
-    let mut body = convert_gemini_to_openai_request(&payload, ctx.model.upstream_model());
+    let mut body = openai_body(&ctx, payload);
+    let audio_dropped = adapters::dropped_audio(&body);
     client::normalize_stream_flag(&mut body);
 
     let config = ctx.retry_config();
-    let client = client::default_client()?;
+    let client = client::default_client_for(&ctx.upstream)?;
     let headers = build_openai_headers(&upstream_ctx);
 
     let result = client::send_with_retry(
         &client,
         upstream_ctx.all_endpoints(),
-        "/chat/completions",
+        &upstream_ctx.chat_path(),
         headers,
         &body,
         &config,
@@ -1606,25 +749,27 @@ async fn handle_gemini_to_openai_request(
     .await?;
 
     let status = result.response.status();
+    let upstream_headers = result.response.headers().clone();
     let status_code = status.as_u16();
-    let response_text = result.response.text().await.map_err(|e| {
-        ForwardError::RequestFailed(format!("Failed to read response: {}", e))
+    let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
+        ForwardError::request_failed(format!("Failed to read response: {}", e))
     })?;
-    let response_body: Value = client::parse_json_response(&response_text)
-        .map_err(|e| ForwardError::RequestFailed(format!("Failed to parse response: {}", e)))?;
+    let response_body: Value = client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id)
+        .map_err(|e| ForwardError::request_failed(format!("Failed to parse response: {}", e)))?;
 
     if !status.is_success() {
-        return Err(ForwardError::RequestFailed(format!(
-            "Upstream returned {}: {}",
+        return Err(ForwardError::upstream(
             status_code,
-            response_body.to_string()
-        )));
+            &response_body.to_string(),
+        ));
     }
 
-    let gemini_body = convert_openai_response_to_gemini(&response_body, ctx.model.upstream_model());
+    let gemini_body = GeminiAdapter::default()
+        .response_from_openai(&response_body, ctx.model.upstream_model());
     let mut usage = extract_usage(&gemini_body);
     if usage.prompt_tokens == 0 {
-        usage.prompt_tokens = estimate_gemini_prompt_tokens(&payload);
+        usage.prompt_tokens = estimate_gemini_prompt_tokens(payload);
+        usage.prompt_estimated = true;
     }
 
     let latency_ms = start.elapsed().as_millis() as u64;
@@ -1635,19 +780,20 @@ async fn handle_gemini_to_openai_request(
         latency_ms,
         status: status_code,
         usage,
+        audio_dropped,
+        headers: upstream_headers,
     })
 }
 
 async fn handle_gemini_to_anthropic_request(
     ctx: ForwardContext,
-    payload: Value,
+    payload: &Value,
 ) -> ForwardResult<UpstreamResponse> {
     let start = Instant::now();
     let upstream_ctx = with_provider(&ctx, Provider::Anthropic);
 
-    let openai_payload = convert_gemini_to_openai_request(&payload, ctx.model.upstream_model());
-    let mut anthropic_payload =
-        anthropic::convert_openai_to_anthropic_request(&openai_payload, ctx.model.upstream_model());
+    let mut anthropic_payload = anthropic_body(&ctx, payload);
+    let audio_dropped = adapters::dropped_audio(&anthropic_payload);
     client::normalize_stream_flag(&mut anthropic_payload);
 
     let handler = anthropic::AnthropicHandler;
@@ -1655,11 +801,11 @@ async fn handle_gemini_to_anthropic_request(
     headers.insert("accept", HeaderValue::from_static("application/json"));
 
     let config = ctx.retry_config();
-    let client = client::default_client()?;
+    let client = client::default_client_for(&ctx.upstream)?;
     let result = client::send_with_retry(
         &client,
         upstream_ctx.all_endpoints(),
-        "/v1/messages",
+        &upstream_ctx.messages_path(),
         headers,
         &anthropic_payload,
         &config,
@@ -1667,27 +813,31 @@ async fn handle_gemini_to_anthropic_request(
     .await?;
 
     let status = result.response.status();
+    let upstream_headers = result.response.headers().clone();
     let status_code = status.as_u16();
-    let response_text = result.response.text().await.map_err(|e| {
-        ForwardError::RequestFailed(format!("Failed to read response: {}", e))
+    let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
+        ForwardError::request_failed(format!("Failed to read response: {}", e))
     })?;
-    let response_body: Value = client::parse_json_response(&response_text)
-        .map_err(|e| ForwardError::RequestFailed(format!("Failed to parse response: {}", e)))?;
+    let response_body: Value = client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id)
+        .map_err(|e| ForwardError::request_failed(format!("Failed to parse response: {}", e)))?;
 
     if !status.is_success() {
-        return Err(ForwardError::RequestFailed(format!(
-            "Upstream returned {}: {}",
+        return Err(ForwardError::upstream(
             status_code,
-            response_body.to_string()
-        )));
+            &response_body.to_string(),
+        ));
     }
 
-    let openai_response =
-        anthropic::convert_anthropic_response_to_openai(&response_body, ctx.model.upstream_model());
-    let gemini_body = convert_openai_response_to_gemini(&openai_response, ctx.model.upstream_model());
+    let gemini_body = adapters::convert_response(
+        &AnthropicAdapter::default(),
+        &GeminiAdapter::default(),
+        &response_body,
+        ctx.model.upstream_model(),
+    );
     let mut usage = extract_usage(&gemini_body);
     if usage.prompt_tokens == 0 {
-        usage.prompt_tokens = estimate_gemini_prompt_tokens(&payload);
+        usage.prompt_tokens = estimate_gemini_prompt_tokens(payload);
+        usage.prompt_estimated = true;
     }
 
     let latency_ms = start.elapsed().as_millis() as u64;
@@ -1698,6 +848,8 @@ async fn handle_gemini_to_anthropic_request(
         latency_ms,
         status: status_code,
         usage,
+        audio_dropped,
+        headers: upstream_headers,
     })
 }
 
@@ -1706,7 +858,8 @@ async fn handle_gemini_to_openai_stream(
     payload: Value,
 ) -> ForwardResult<Response> {
     let upstream_ctx = with_provider(&ctx, Provider::OpenAI);
-    let mut body = convert_gemini_to_openai_request(&payload, ctx.model.upstream_model());
+    let mut body = openai_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&body);
     if let Some(obj) = body.as_object_mut() {
         obj.insert("stream".to_string(), Value::Bool(true));
         obj.insert(
@@ -1716,11 +869,14 @@ async fn handle_gemini_to_openai_stream(
     }
 
     let headers = build_openai_headers(&upstream_ctx);
-    let client = client::streaming_client()?;
+    let client = client::streaming_client_for(&ctx.upstream)?;
     let endpoint = upstream_ctx.primary_endpoint().ok_or_else(|| {
         ForwardError::UpstreamNotFound("No endpoints configured".to_string())
     })?;
-    let url = format!("{}/chat/completions", endpoint.trim_end_matches('/'));
+    let url = format!("{}{}", endpoint.trim_end_matches('/'), upstream_ctx.chat_path());
+
+    let body = forward::scrub::outbound(&body);
+    capture::record_request(&url, &headers, &body);
 
     let response = client
         .post(&url)
@@ -1728,25 +884,28 @@ async fn handle_gemini_to_openai_stream(
         .json(&body)
         .send()
         .await
-        .map_err(|e| ForwardError::RequestFailed(e.to_string()))?;
+        .map_err(|e| ForwardError::request_failed(e.to_string()))?;
+    forward::ratelimits::observe(response.headers());
 
     if !response.status().is_success() {
         let status = response.status();
+        let response_headers = response.headers().clone();
         let text = response.text().await.unwrap_or_default();
-        return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+        return Err(ForwardError::upstream(status.as_u16(), &text)
+            .with_retry_after(&response_headers)
+            .at_endpoint(endpoint));
     }
 
     let estimated_prompt_tokens = estimate_gemini_prompt_tokens(&payload);
-    let state = Arc::new(Mutex::new({
-        let mut s = OpenAIToGeminiStreamState::new();
-        s.prompt_tokens = estimated_prompt_tokens;
-        s
-    }));
+    let state = Arc::new(Mutex::new(
+        GeminiAdapter::default()
+            .stream_from_openai(ctx.model.upstream_model(), estimated_prompt_tokens),
+    ));
     let state_clone = Arc::clone(&state);
     let line_buffer = Arc::new(Mutex::new(Vec::new()));
     let line_buffer_clone = Arc::clone(&line_buffer);
 
-    let stream = response.bytes_stream().map(move |result| match result {
+    let stream = capture::tap(response.bytes_stream()).map(move |result| match result {
         Ok(bytes) => {
             let lines = {
                 let mut buffer = line_buffer_clone.lock().unwrap();
@@ -1762,8 +921,7 @@ async fn handle_gemini_to_openai_stream(
                     match serde_json::from_str::<Value>(data) {
                         Ok(json) => {
                             if let Ok(mut state) = state_clone.lock() {
-                                let events = convert_openai_chunk_to_gemini(&json, &mut state);
-                                for event in events {
+                                for event in state.map_event(&json) {
                                     output.extend_from_slice(
                                         format!("data: {}\n\n", event).as_bytes(),
                                     );
@@ -1787,7 +945,7 @@ async fn handle_gemini_to_openai_stream(
     let logged_stream = stream
         .chain(futures_util::stream::once(async move {
             if let Ok(state) = state_for_log.lock() {
-                let usage = TokenUsage::new(state.prompt_tokens, state.completion_tokens);
+                let usage = state.usage();
                 ctx_for_log.log_usage(&usage);
             }
             Ok(Bytes::new())
@@ -1802,13 +960,14 @@ async fn handle_gemini_to_openai_stream(
             }
         });
 
-    Ok(Response::builder()
+    let response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", "text/event-stream")
         .header("cache-control", "no-cache")
         .header("connection", "keep-alive")
         .body(Body::from_stream(logged_stream))
-        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response()))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    Ok(forward::attach_dropped_audio(response, audio_dropped))
 }
 
 async fn handle_gemini_to_anthropic_stream(
@@ -1816,9 +975,8 @@ async fn handle_gemini_to_anthropic_stream(
     payload: Value,
 ) -> ForwardResult<Response> {
     let upstream_ctx = with_provider(&ctx, Provider::Anthropic);
-    let openai_payload = convert_gemini_to_openai_request(&payload, ctx.model.upstream_model());
-    let mut anthropic_payload =
-        anthropic::convert_openai_to_anthropic_request(&openai_payload, ctx.model.upstream_model());
+    let mut anthropic_payload = anthropic_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&anthropic_payload);
     if let Some(obj) = anthropic_payload.as_object_mut() {
         obj.insert("stream".to_string(), Value::Bool(true));
     }
@@ -1828,11 +986,14 @@ async fn handle_gemini_to_anthropic_stream(
     headers.insert("accept", HeaderValue::from_static("text/event-stream"));
     headers.insert("accept-encoding", HeaderValue::from_static("identity"));
 
-    let client = client::streaming_client()?;
+    let client = client::streaming_client_for(&ctx.upstream)?;
     let endpoint = upstream_ctx.primary_endpoint().ok_or_else(|| {
         ForwardError::UpstreamNotFound("No endpoints configured".to_string())
     })?;
-    let url = format!("{}/v1/messages", endpoint.trim_end_matches('/'));
+    let url = format!("{}{}", endpoint.trim_end_matches('/'), upstream_ctx.messages_path());
+
+    let anthropic_payload = forward::scrub::outbound(&anthropic_payload);
+    capture::record_request(&url, &headers, &anthropic_payload);
 
     let response = client
         .post(&url)
@@ -1840,32 +1001,31 @@ async fn handle_gemini_to_anthropic_stream(
         .json(&anthropic_payload)
         .send()
         .await
-        .map_err(|e| ForwardError::RequestFailed(e.to_string()))?;
+        .map_err(|e| ForwardError::request_failed(e.to_string()))?;
+    forward::ratelimits::observe(response.headers());
 
     if !response.status().is_success() {
         let status = response.status();
+        let response_headers = response.headers().clone();
         let text = response.text().await.unwrap_or_default();
-        return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+        return Err(ForwardError::upstream(status.as_u16(), &text)
+            .with_retry_after(&response_headers)
+            .at_endpoint(endpoint));
     }
 
     let estimated_prompt_tokens = estimate_gemini_prompt_tokens(&payload);
-    let anthropic_state = Arc::new(Mutex::new({
-        let mut s = anthropic::AnthropicToOpenAIStreamState::new(ctx.model.upstream_model());
-        s.prompt_tokens = estimated_prompt_tokens;
-        s
-    }));
-    let anthropic_state_clone = Arc::clone(&anthropic_state);
-    let gemini_state = Arc::new(Mutex::new({
-        let mut s = OpenAIToGeminiStreamState::new();
-        s.prompt_tokens = estimated_prompt_tokens;
-        s
-    }));
-    let gemini_state_clone = Arc::clone(&gemini_state);
+    let converter = Arc::new(Mutex::new(adapters::convert_stream(
+        &AnthropicAdapter::default(),
+        &GeminiAdapter::default(),
+        ctx.model.upstream_model(),
+        estimated_prompt_tokens,
+    )));
+    let converter_clone = Arc::clone(&converter);
 
     let line_buffer = Arc::new(Mutex::new(Vec::new()));
     let line_buffer_clone = Arc::clone(&line_buffer);
 
-    let stream = response.bytes_stream().map(move |result| match result {
+    let stream = capture::tap(response.bytes_stream()).map(move |result| match result {
         Ok(bytes) => {
             let lines = {
                 let mut buffer = line_buffer_clone.lock().unwrap();
@@ -1880,20 +1040,11 @@ async fn handle_gemini_to_anthropic_stream(
                     }
                     match serde_json::from_str::<Value>(data) {
                         Ok(json) => {
-                            if let Ok(mut state) = anthropic_state_clone.lock() {
-                                let openai_chunks =
-                                    anthropic::convert_anthropic_event_to_openai_chunks(
-                                        &json, &mut state,
+                            if let Ok(mut converter) = converter_clone.lock() {
+                                for event in converter.map_event(&json) {
+                                    output.extend_from_slice(
+                                        format!("data: {}\n\n", event).as_bytes(),
                                     );
-                                for chunk in openai_chunks {
-                                    let mut gemini_state = gemini_state_clone.lock().unwrap();
-                                    let events =
-                                        convert_openai_chunk_to_gemini(&chunk, &mut gemini_state);
-                                    for event in events {
-                                        output.extend_from_slice(
-                                            format!("data: {}\n\n", event).as_bytes(),
-                                        );
-                                    }
                                 }
                             }
                         }
@@ -1909,12 +1060,12 @@ async fn handle_gemini_to_anthropic_stream(
     });
 
     let ctx_for_log = ctx.clone();
-    let gemini_state_for_log = Arc::clone(&gemini_state);
+    let converter_for_log = Arc::clone(&converter);
 
     let logged_stream = stream
         .chain(futures_util::stream::once(async move {
-            if let Ok(state) = gemini_state_for_log.lock() {
-                let usage = TokenUsage::new(state.prompt_tokens, state.completion_tokens);
+            if let Ok(state) = converter_for_log.lock() {
+                let usage = state.usage();
                 ctx_for_log.log_usage(&usage);
             }
             Ok(Bytes::new())
@@ -1929,19 +1080,68 @@ async fn handle_gemini_to_anthropic_stream(
             }
         });
 
-    Ok(Response::builder()
+    let response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", "text/event-stream")
         .header("cache-control", "no-cache")
         .header("connection", "keep-alive")
         .body(Body::from_stream(logged_stream))
-        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response()))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    Ok(forward::attach_dropped_audio(response, audio_dropped))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_merge_safety_settings_keeps_client_values() {
+        let defaults = [
+            SafetySetting {
+                category: "HARM_CATEGORY_HARASSMENT".to_string(),
+                threshold: "BLOCK_ONLY_HIGH".to_string(),
+            },
+            SafetySetting {
+                category: "HARM_CATEGORY_HATE_SPEECH".to_string(),
+                threshold: "BLOCK_ONLY_HIGH".to_string(),
+            },
+            // The upstream's, after the model's
+            SafetySetting {
+                category: "HARM_CATEGORY_HATE_SPEECH".to_string(),
+                threshold: "BLOCK_NONE".to_string(),
+            },
+        ];
+        let mut body = serde_json::json!({
+            "contents": [],
+            "safetySettings": [
+                {"category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE"}
+            ]
+        });
+        merge_safety_settings(&mut body, defaults.iter());
+        assert_eq!(
+            body["safetySettings"],
+            serde_json::json!([
+                {"category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE"},
+                {"category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "BLOCK_ONLY_HIGH"}
+            ])
+        );
+
+        let mut untouched = serde_json::json!({"contents": []});
+        merge_safety_settings(&mut untouched, [].iter());
+        assert!(untouched.get("safetySettings").is_none());
+    }
+
+    #[test]
+    fn test_blocked_prompt() {
+        let response = serde_json::json!({"promptFeedback": {"blockReason": "SAFETY"}});
+        assert!(matches!(
+            blocked(&response),
+            Some(ForwardError::ContentFiltered(reason)) if reason == "SAFETY"
+        ));
+        let answered = serde_json::json!({"candidates": [], "promptFeedback": {}});
+        assert!(blocked(&answered).is_none());
+    }
+
     #[test]
     fn test_filter_payload() {
         let payload = serde_json::json!({
@@ -2053,13 +1253,45 @@ mod tests {
                 "promptTokenCount": 100,
                 "candidatesTokenCount": 50,
                 "cachedContentTokenCount": 20,
-                "totalTokenCount": 170
+                "totalTokenCount": 150
             }
         });
 
         let usage = extract_usage(&response);
-        assert_eq!(usage.prompt_tokens, 120); // 100 + 20 cached
+        // The prompt count already includes the cached tokens
+        assert_eq!(usage.prompt_tokens, 100);
         assert_eq!(usage.completion_tokens, 50);
+        assert_eq!(usage.cache_read_tokens, 20);
+        assert_eq!(usage.total(), 150);
+
+        let pricing = crate::pricing::ModelPricing {
+            price_prompt_per_1k: 1.0,
+            price_completion_per_1k: 2.0,
+            price_cache_read_per_1k: Some(0.25),
+            ..Default::default()
+        };
+        let billed = usage.billed();
+        let cost =
+            crate::pricing::request_cost(&billed, &pricing.snapshot_for(billed.prompt)).unwrap();
+        // 80 uncached * 1 + 20 cached * 0.25 + 50 out * 2, per 1k
+        assert!((cost - (0.08 + 0.005 + 0.1)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_extract_usage_with_thoughts() {
+        let response = serde_json::json!({
+            "usageMetadata": {
+                "promptTokenCount": 40,
+                "candidatesTokenCount": 10,
+                "thoughtsTokenCount": 30,
+                "totalTokenCount": 80
+            }
+        });
+
+        let usage = extract_usage(&response);
+        assert_eq!(usage.completion_tokens, 40); // 10 candidates + 30 thoughts
+        assert_eq!(usage.reasoning_tokens, 30);
+        assert_eq!(usage.total(), 80);
     }
 
     #[test]
@@ -2123,6 +1355,78 @@ mod tests {
         assert!(parts[1].get("file_data").is_some());
     }
 
+    #[test]
+    fn test_stream_url_with_path_template() {
+        let mut ctx = create_test_context();
+        assert_eq!(
+            build_gemini_stream_url(&ctx, "gemini-pro").unwrap(),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-pro:streamGenerateContent?alt=sse&key=test-key"
+        );
+
+        ctx.upstream.generate_path =
+            Some("/proxy/{api_version}/{model}:generateContent?tenant=a".to_string());
+        ctx.upstream.api_version = Some("v1".to_string());
+        assert_eq!(
+            build_gemini_stream_url(&ctx, "gemini-pro").unwrap(),
+            "https://generativelanguage.googleapis.com/proxy/v1/gemini-pro:streamGenerateContent?tenant=a&alt=sse&key=test-key"
+        );
+        assert_eq!(
+            build_gemini_endpoints(&ctx, &ctx.generate_path("gemini-pro", false)),
+            vec!["https://generativelanguage.googleapis.com/proxy/v1/gemini-pro:generateContent?tenant=a&key=test-key"]
+        );
+
+        ctx.upstream.extra_headers = vec![("x-portkey-config".to_string(), "pc-1".to_string())];
+        let headers = GeminiHandler.build_headers(&ctx);
+        assert_eq!(headers.get("x-portkey-config").unwrap(), "pc-1");
+    }
+
+    /// A 5 MB request retried three times, copied for every attempt as it
+    /// used to be and shared by the attempts. Run it with
+    /// `cargo test --release bench_ -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_attempts_share_a_large_payload() {
+        let text = "x".repeat(1024);
+        let contents: Vec<Value> = (0..5 * 1024)
+            .map(|_| serde_json::json!({"role": "user", "parts": [{"text": text}]}))
+            .collect();
+        let payload = serde_json::json!({"contents": contents});
+        let ctx = create_test_context();
+        let (runs, attempts) = (20, 3);
+
+        let start = Instant::now();
+        for _ in 0..runs {
+            for _ in 0..attempts {
+                let attempt = payload.clone();
+                std::hint::black_box(GeminiHandler.transform_request(&ctx, &attempt));
+            }
+        }
+        let cloned = start.elapsed();
+
+        let shared = Arc::new(payload.clone());
+        let start = Instant::now();
+        for _ in 0..runs {
+            for _ in 0..attempts {
+                let attempt = Arc::clone(&shared);
+                std::hint::black_box(GeminiHandler.transform_request(&ctx, &attempt));
+            }
+        }
+        let arced = start.elapsed();
+
+        println!(
+            "{} attempts of a {} byte payload: cloned {:?}, shared {:?}",
+            attempts,
+            payload.to_string().len(),
+            cloned / runs,
+            arced / runs
+        );
+        assert_eq!(
+            GeminiHandler.transform_request(&ctx, &shared),
+            GeminiHandler.transform_request(&ctx, &payload)
+        );
+        assert!(arced < cloned);
+    }
+
     fn create_test_context() -> ForwardContext {
         use crate::forward::context::*;
 
@@ -2134,19 +1438,21 @@ mod tests {
                 provider: Provider::Gemini,
                 upstream_id: "gemini".to_string(),
                 upstream_model_id: None,
-                price_prompt_per_1k: 0.0,
-                price_completion_per_1k: 0.0,
+                pricing: Default::default(),
+                capabilities: Default::default(),
             },
             upstream: UpstreamInfo {
                 id: "gemini".to_string(),
                 endpoints: vec!["https://generativelanguage.googleapis.com".to_string()],
                 api_style: Some("gemini".to_string()),
                 api_key: Some("test-key".to_string()),
+                ..Default::default()
             },
             gemini_api_version: None,
             meta: RequestMeta::default(),
             is_streaming: false,
             retry_max_attempts_override: None,
+            route: Default::default(),
         }
     }
 }
@@ -9,6 +9,7 @@ pub mod openai;
 use axum::response::Response;
 use reqwest::header::HeaderMap;
 use serde_json::Value;
+use std::sync::Arc;
 
 use super::context::{ForwardContext, Provider, TokenUsage, UpstreamResponse};
 use super::error::ForwardResult;
@@ -31,6 +32,15 @@ impl ProviderHandler {
         }
     }
 
+    /// The provider whose API this handler speaks
+    pub fn provider(&self) -> Provider {
+        match self {
+            ProviderHandler::OpenAI(_) => Provider::OpenAI,
+            ProviderHandler::Anthropic(_) => Provider::Anthropic,
+            ProviderHandler::Gemini(_) => Provider::Gemini,
+        }
+    }
+
     /// Build the request URL
     pub fn build_url(&self, ctx: &ForwardContext, path: &str) -> String {
         match self {
@@ -78,11 +88,21 @@ impl ProviderHandler {
 }
 
 impl ProviderHandler {
+    /// Body a non-streaming request would be sent upstream with, through
+    /// the same conversions `handle_request` applies
+    pub fn upstream_body(&self, ctx: &ForwardContext, payload: &Value) -> Value {
+        match self {
+            ProviderHandler::OpenAI(_) => openai::upstream_body(ctx, payload),
+            ProviderHandler::Anthropic(_) => anthropic::upstream_body(ctx, payload),
+            ProviderHandler::Gemini(_) => gemini::upstream_body(ctx, payload),
+        }
+    }
+
     /// Handle non-streaming request
     pub async fn handle_request(
         &self,
         ctx: ForwardContext,
-        payload: Value,
+        payload: Arc<Value>,
     ) -> ForwardResult<UpstreamResponse> {
         match self {
             ProviderHandler::OpenAI(h) => h.handle_request(ctx, payload).await,
@@ -141,11 +161,12 @@ pub trait ProviderHandlerImpl: Send + Sync {
     /// Estimate tokens from request (fallback when response doesn't include usage)
     fn estimate_request_tokens(&self, payload: &Value) -> i64;
 
-    /// Handle non-streaming request
+    /// Handle non-streaming request. The payload is shared with the retry
+    /// and fallback attempts, so it is only copied where it is rewritten.
     fn handle_request(
         &self,
         ctx: ForwardContext,
-        payload: Value,
+        payload: Arc<Value>,
     ) -> impl std::future::Future<Output = ForwardResult<UpstreamResponse>> + Send;
 
     /// Handle streaming request
@@ -14,9 +14,16 @@ use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
 
+use crate::adapters::openai::{parse_openai_usage, OpenAIAdapter};
+use crate::adapters::{
+    self, AnthropicAdapter, GeminiAdapter, ModelAdapter, ParamPolicy, StreamMap,
+};
+use crate::forward::{self, capture};
 use crate::forward::client::{self, drain_sse_lines, is_sse_done, parse_sse_data};
 use crate::forward::context::{estimate_tokens, ForwardContext, Provider, TokenUsage, UpstreamResponse};
 use crate::forward::error::{ForwardError, ForwardResult};
+use crate::forward::images;
+use crate::forward::timing;
 use crate::logger;
 
 use super::{anthropic, gemini, ProviderHandlerImpl};
@@ -78,12 +85,17 @@ impl ProviderHandlerImpl for OpenAIHandler {
         // Content-Type
         headers.insert("content-type", HeaderValue::from_static("application/json"));
 
+        ctx.apply_extra_headers(&mut headers);
         headers
     }
 
     fn transform_request(&self, ctx: &ForwardContext, payload: &Value) -> Value {
         // Determine allowed fields based on upstream capabilities
-        let mut filtered = sanitize_openai_payload_for_upstream(payload, &ctx.upstream.id);
+        let allowed_fields = get_allowed_fields_for_upstream(&ctx.upstream.id);
+        let mut filtered = sanitize_openai_payload_for_upstream(
+            filter_payload(payload, allowed_fields),
+            &ctx.upstream.id,
+        );
 
         // Replace model with upstream model name
         if let Some(obj) = filtered.as_object_mut() {
@@ -92,6 +104,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
                 Value::String(ctx.model.upstream_model().to_string()),
             );
         }
+        OpenAIAdapter.normalize_request(&mut filtered, &ParamPolicy::for_model(&ctx.model));
 
         // Log the transformed request
         logger::debug(
@@ -123,7 +136,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
     async fn handle_request(
         &self,
         ctx: ForwardContext,
-        payload: Value,
+        payload: Arc<Value>,
     ) -> ForwardResult<UpstreamResponse> {
         let upstream_style = upstream_style(&ctx);
         if upstream_style != Provider::OpenAI {
@@ -136,24 +149,27 @@ impl ProviderHandlerImpl for OpenAIHandler {
 
         let start = Instant::now();
 
-        logger::info(
+        logger::log_with_fields(
+            logger::LogLevel::Info,
             "openai",
             &format!(
                 "Request started: model={}, upstream={}, streaming=false",
                 ctx.model.id,
                 ctx.upstream.id
             ),
+            &ctx.log_fields(),
         );
 
         // Build request
         let headers = self.build_headers(&ctx);
         let body = self.transform_request(&ctx, &payload);
         let config = ctx.retry_config();
-        let client = client::default_client()?;
+        let client = client::default_client_for(&ctx.upstream)?;
 
         // Log the request URL
         let endpoint = ctx.primary_endpoint().unwrap_or("unknown");
-        let full_url = format!("{}{}", endpoint.trim_end_matches('/'), "/chat/completions");
+        let path = ctx.chat_path();
+        let full_url = format!("{}{}", endpoint.trim_end_matches('/'), path);
         logger::debug(
             "openai",
             &format!("Request URL: {}", full_url),
@@ -164,7 +180,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
         let result = client::send_with_retry(
             &client,
             ctx.all_endpoints(),
-            "/chat/completions",
+            &path,
             headers,
             &body,
             &config,
@@ -173,10 +189,11 @@ impl ProviderHandlerImpl for OpenAIHandler {
 
         // Parse response
         let status = result.response.status();
+        let upstream_headers = result.response.headers().clone();
         let status_code = status.as_u16();
-        let response_text = result.response.text().await.map_err(|e| {
+        let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
             logger::error("openai", &format!("Failed to read response body: {}", e));
-            ForwardError::RequestFailed(format!("Failed to read response: {}", e))
+            ForwardError::request_failed(format!("Failed to read response: {}", e))
         })?;
 
         // Log response for debugging empty responses
@@ -184,9 +201,9 @@ impl ProviderHandlerImpl for OpenAIHandler {
             logger::warn("openai", "Received empty response body from upstream");
         }
 
-        let response_body: Value = client::parse_json_response(&response_text).map_err(|e| {
+        let response_body: Value = client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id).map_err(|e| {
             logger::error("openai", &format!("Failed to parse response JSON: {}, body: {}", e, &response_text[..response_text.len().min(500)]));
-            ForwardError::RequestFailed(format!("Failed to parse response: {}", e))
+            ForwardError::request_failed(format!("Failed to parse response: {}", e))
         })?;
 
         // Check if response indicates an error
@@ -195,12 +212,11 @@ impl ProviderHandlerImpl for OpenAIHandler {
                 "openai",
                 &format!("Request failed: status={}, response={}", status_code, response_body),
             );
-            // Don't log usage for failed requests
-            return Err(ForwardError::RequestFailed(format!(
-                "Upstream returned {}: {}",
+            // Logged as a failed request once no attempt is left
+            return Err(ForwardError::upstream(
                 status_code,
-                response_body.to_string()
-            )));
+                &response_body.to_string(),
+            ));
         }
 
         // Extract usage
@@ -208,11 +224,13 @@ impl ProviderHandlerImpl for OpenAIHandler {
         if usage.prompt_tokens == 0 {
             // Estimate if not provided
             usage.prompt_tokens = self.estimate_request_tokens(&payload);
+            usage.prompt_estimated = true;
         }
 
         let latency_ms = start.elapsed().as_millis() as u64;
 
-        logger::info(
+        logger::log_with_fields(
+            logger::LogLevel::Info,
             "openai",
             &format!(
                 "Request completed: model={}, latency={}ms, tokens={}/{}",
@@ -221,6 +239,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
                 usage.prompt_tokens,
                 usage.completion_tokens
             ),
+            &ctx.log_fields(),
         );
 
         // Log usage to database only for successful requests
@@ -231,6 +250,8 @@ impl ProviderHandlerImpl for OpenAIHandler {
             latency_ms,
             status: status_code,
             usage,
+            audio_dropped: 0,
+            headers: upstream_headers,
         })
     }
 
@@ -260,22 +281,26 @@ impl ProviderHandlerImpl for OpenAIHandler {
             }
         }
 
-        let client = client::streaming_client()?;
+        let client = client::streaming_client_for(&ctx.upstream)?;
         let endpoint = ctx
             .primary_endpoint()
             .ok_or_else(|| ForwardError::UpstreamNotFound("No endpoints configured".to_string()))?;
         // Use /chat/completions instead of /v1/chat/completions to support custom API paths like /v4/chat/completions
-        let url = format!("{}/chat/completions", endpoint.trim_end_matches('/'));
+        let url = format!("{}{}", endpoint.trim_end_matches('/'), ctx.chat_path());
 
-        logger::info(
+        logger::log_with_fields(
+            logger::LogLevel::Info,
             "openai",
             &format!(
                 "Starting stream request: model={}, upstream={}, url={}",
                 ctx.model.id, ctx.upstream.id, url
             ),
+            &ctx.log_fields(),
         );
 
         // Make request
+        let body = forward::scrub::outbound(&body);
+        capture::record_request(&url, &headers, &body);
         let response = client
             .post(&url)
             .headers(headers.clone())
@@ -287,11 +312,13 @@ impl ProviderHandlerImpl for OpenAIHandler {
                     "openai",
                     &format!("Stream request failed: url={}, error={}", url, e),
                 );
-                ForwardError::RequestFailed(e.to_string())
+                ForwardError::request_failed(e.to_string())
             })?;
+        forward::ratelimits::observe(response.headers());
 
         let status = response.status();
         if !status.is_success() {
+            let response_headers = response.headers().clone();
             let text = response.text().await.unwrap_or_default();
             logger::error(
                 "openai",
@@ -301,7 +328,9 @@ impl ProviderHandlerImpl for OpenAIHandler {
                     &text[..text.len().min(500)]
                 ),
             );
-            return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+            return Err(ForwardError::upstream(status.as_u16(), &text)
+                .with_retry_after(&response_headers)
+                .at_endpoint(endpoint));
         }
 
         logger::debug(
@@ -314,13 +343,13 @@ impl ProviderHandlerImpl for OpenAIHandler {
         let estimated_prompt_tokens = self.estimate_request_tokens(&payload);
 
         // Create usage tracker for accumulating streaming usage
-        let usage_tracker = Arc::new(Mutex::new(TokenUsage::new(estimated_prompt_tokens, 0)));
+        let usage_tracker = Arc::new(Mutex::new(TokenUsage::estimated(estimated_prompt_tokens)));
         let usage_tracker_clone = Arc::clone(&usage_tracker);
         let line_buffer = Arc::new(Mutex::new(Vec::new()));
         let line_buffer_clone = Arc::clone(&line_buffer);
 
         // Stream the response
-        let stream = response.bytes_stream().map(move |result| {
+        let stream = capture::tap(response.bytes_stream()).map(move |result| {
             match result {
                 Ok(bytes) => {
                     let lines = {
@@ -339,18 +368,9 @@ impl ProviderHandlerImpl for OpenAIHandler {
                                     Ok(json) => {
                                         // Check for final usage in streaming response
                                         if let Some(usage) = json.get("usage") {
-                                            let chunk_usage = TokenUsage::new(
-                                                usage
-                                                    .get("prompt_tokens")
-                                                    .and_then(|v| v.as_i64())
-                                                    .unwrap_or(0),
-                                                usage
-                                                    .get("completion_tokens")
-                                                    .and_then(|v| v.as_i64())
-                                                    .unwrap_or(0),
-                                            );
+                                            let chunk_usage = parse_openai_usage(usage);
                                             if let Ok(mut tracker) = usage_tracker_clone.lock() {
-                                                *tracker = chunk_usage;
+                                                tracker.apply_reported(&chunk_usage);
                                             }
                                         }
                                         // Also count completion tokens from delta content
@@ -368,7 +388,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
                                                         if let Ok(mut tracker) =
                                                             usage_tracker_clone.lock()
                                                         {
-                                                            tracker.completion_tokens += tokens;
+                                                            tracker.add_estimated_output(tokens);
                                                         }
                                                     }
                                                     // Handle GLM reasoning_content field
@@ -380,7 +400,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
                                                         if let Ok(mut tracker) =
                                                             usage_tracker_clone.lock()
                                                         {
-                                                            tracker.completion_tokens += tokens;
+                                                            tracker.add_estimated_reasoning(tokens);
                                                         }
                                                     }
                                                 }
@@ -425,7 +445,8 @@ impl ProviderHandlerImpl for OpenAIHandler {
             .chain(futures_util::stream::once(async move {
                 // Log final usage when stream completes
                 if let Ok(usage) = usage_for_log.lock() {
-                    logger::info(
+                    logger::log_with_fields(
+                        logger::LogLevel::Info,
                         "openai",
                         &format!(
                             "Stream completed: model={}, tokens={}/{}",
@@ -433,6 +454,7 @@ impl ProviderHandlerImpl for OpenAIHandler {
                             usage.prompt_tokens,
                             usage.completion_tokens
                         ),
+                        &ctx_for_log.log_fields(),
                     );
                     ctx_for_log.log_usage(&usage);
                 } else {
@@ -478,7 +500,7 @@ impl OpenAIHandler {
     pub async fn handle_responses_request(
         &self,
         ctx: ForwardContext,
-        payload: Value,
+        payload: &Value,
     ) -> ForwardResult<UpstreamResponse> {
         ensure_responses_supported(&ctx)?;
 
@@ -494,11 +516,11 @@ impl OpenAIHandler {
         );
 
         let headers = self.build_headers(&ctx);
-        let mut body = transform_responses_request(&ctx, &payload);
+        let mut body = transform_responses_request(&ctx, payload);
         client::normalize_stream_flag(&mut body);
 
         let config = ctx.retry_config();
-        let client = client::default_client()?;
+        let client = client::default_client_for(&ctx.upstream)?;
 
         let endpoint = ctx.primary_endpoint().unwrap_or("unknown");
         let full_url = format!("{}{}", endpoint.trim_end_matches('/'), "/responses");
@@ -515,17 +537,18 @@ impl OpenAIHandler {
         .await?;
 
         let status = result.response.status();
+        let upstream_headers = result.response.headers().clone();
         let status_code = status.as_u16();
-        let response_text = result.response.text().await.map_err(|e| {
+        let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
             logger::error("openai", &format!("Failed to read response body: {}", e));
-            ForwardError::RequestFailed(format!("Failed to read response: {}", e))
+            ForwardError::request_failed(format!("Failed to read response: {}", e))
         })?;
 
         if response_text.is_empty() {
             logger::warn("openai", "Received empty response body from upstream (responses)");
         }
 
-        let response_body: Value = client::parse_json_response(&response_text).map_err(|e| {
+        let response_body: Value = client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id).map_err(|e| {
             logger::error(
                 "openai",
                 &format!(
@@ -534,7 +557,7 @@ impl OpenAIHandler {
                     &response_text[..response_text.len().min(500)]
                 ),
             );
-            ForwardError::RequestFailed(format!("Failed to parse response: {}", e))
+            ForwardError::request_failed(format!("Failed to parse response: {}", e))
         })?;
 
         if !status.is_success() {
@@ -545,16 +568,16 @@ impl OpenAIHandler {
                     status_code, response_body
                 ),
             );
-            return Err(ForwardError::RequestFailed(format!(
-                "Upstream returned {}: {}",
+            return Err(ForwardError::upstream(
                 status_code,
-                response_body.to_string()
-            )));
+                &response_body.to_string(),
+            ));
         }
 
         let mut usage = extract_responses_usage(&response_body);
         if usage.prompt_tokens == 0 {
-            usage.prompt_tokens = estimate_responses_prompt_tokens(&payload);
+            usage.prompt_tokens = estimate_responses_prompt_tokens(payload);
+            usage.prompt_estimated = true;
         }
 
         let latency_ms = start.elapsed().as_millis() as u64;
@@ -577,6 +600,8 @@ impl OpenAIHandler {
             latency_ms,
             status: status_code,
             usage,
+            audio_dropped: 0,
+            headers: upstream_headers,
         })
     }
 
@@ -594,7 +619,7 @@ impl OpenAIHandler {
             obj.insert("stream".to_string(), Value::Bool(true));
         }
 
-        let client = client::streaming_client()?;
+        let client = client::streaming_client_for(&ctx.upstream)?;
         let endpoint = ctx
             .primary_endpoint()
             .ok_or_else(|| ForwardError::UpstreamNotFound("No endpoints configured".to_string()))?;
@@ -608,6 +633,9 @@ impl OpenAIHandler {
             ),
         );
 
+        let body = forward::scrub::outbound(&body);
+        capture::record_request(&url, &headers, &body);
+
         let response = client
             .post(&url)
             .headers(headers.clone())
@@ -619,11 +647,13 @@ impl OpenAIHandler {
                     "openai",
                     &format!("Responses stream request failed: url={}, error={}", url, e),
                 );
-                ForwardError::RequestFailed(e.to_string())
+                ForwardError::request_failed(e.to_string())
             })?;
+        forward::ratelimits::observe(response.headers());
 
         let status = response.status();
         if !status.is_success() {
+            let response_headers = response.headers().clone();
             let text = response.text().await.unwrap_or_default();
             logger::error(
                 "openai",
@@ -633,7 +663,9 @@ impl OpenAIHandler {
                     &text[..text.len().min(500)]
                 ),
             );
-            return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+            return Err(ForwardError::upstream(status.as_u16(), &text)
+                .with_retry_after(&response_headers)
+                .at_endpoint(endpoint));
         }
 
         logger::debug(
@@ -644,12 +676,12 @@ impl OpenAIHandler {
         let ctx_clone = ctx.clone();
         let estimated_prompt_tokens = estimate_responses_prompt_tokens(&payload);
 
-        let usage_tracker = Arc::new(Mutex::new(TokenUsage::new(estimated_prompt_tokens, 0)));
+        let usage_tracker = Arc::new(Mutex::new(TokenUsage::estimated(estimated_prompt_tokens)));
         let usage_tracker_clone = Arc::clone(&usage_tracker);
         let line_buffer = Arc::new(Mutex::new(Vec::new()));
         let line_buffer_clone = Arc::clone(&line_buffer);
 
-        let stream = response.bytes_stream().map(move |result| match result {
+        let stream = capture::tap(response.bytes_stream()).map(move |result| match result {
             Ok(bytes) => {
                 let lines = {
                     let mut buffer = line_buffer_clone.lock().unwrap();
@@ -750,20 +782,10 @@ fn filter_payload(payload: &Value, allowed: &[&str]) -> Value {
 
 /// Extract usage from OpenAI response
 fn extract_usage(response: &Value) -> TokenUsage {
-    if let Some(usage) = response.get("usage") {
-        TokenUsage::new(
-            usage
-                .get("prompt_tokens")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(0),
-            usage
-                .get("completion_tokens")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(0),
-        )
-    } else {
-        TokenUsage::default()
-    }
+    response
+        .get("usage")
+        .map(parse_openai_usage)
+        .unwrap_or_default()
 }
 
 /// Get allowed fields based on upstream capabilities
@@ -799,19 +821,23 @@ fn get_allowed_fields_for_upstream(upstream_id: &str) -> &'static [&'static str]
     }
 }
 
+/// Keep the fields `upstream_id` takes out of an owned payload, so that a
+/// request just converted from another dialect is not copied again.
 pub(crate) fn sanitize_openai_payload_for_upstream(
-    payload: &Value,
+    mut payload: Value,
     upstream_id: &str,
 ) -> Value {
     let allowed_fields = get_allowed_fields_for_upstream(upstream_id);
-    let mut filtered = filter_payload(payload, allowed_fields);
+    if let Some(obj) = payload.as_object_mut() {
+        obj.retain(|key, _| allowed_fields.contains(&key.as_str()));
+    }
 
     // Transform messages for GLM compatibility (convert multimodal content array to string)
     if is_glm_upstream(upstream_id) {
-        transform_messages_for_glm(&mut filtered);
+        transform_messages_for_glm(&mut payload);
     }
 
-    filtered
+    payload
 }
 
 /// Check if upstream is GLM/Z.ai
@@ -860,6 +886,33 @@ fn with_provider(ctx: &ForwardContext, provider: Provider) -> ForwardContext {
     next
 }
 
+/// Body a non-streaming request is sent upstream with, converted for the
+/// upstream's API style like `handle_request` does. Remote images are left
+/// as links.
+pub(crate) fn upstream_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    match upstream_style(ctx) {
+        Provider::OpenAI => OpenAIHandler.transform_request(ctx, payload),
+        Provider::Anthropic => anthropic_body(ctx, payload),
+        Provider::Gemini => gemini_body(ctx, payload),
+    }
+}
+
+fn anthropic_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    let mut body =
+        AnthropicAdapter::default().request_from_openai(payload, ctx.model.upstream_model());
+    AnthropicAdapter::default().normalize_request(&mut body, &ParamPolicy::for_model(&ctx.model));
+    client::normalize_stream_flag(&mut body);
+    body
+}
+
+fn gemini_body(ctx: &ForwardContext, payload: &Value) -> Value {
+    let mut body =
+        GeminiAdapter::default().request_from_openai(payload, ctx.model.upstream_model());
+    GeminiAdapter::default().normalize_request(&mut body, &ParamPolicy::for_model(&ctx.model));
+    gemini::apply_safety_settings(ctx, &mut body);
+    body
+}
+
 fn estimate_openai_prompt_tokens(payload: &Value) -> i64 {
     let messages = payload
         .get("messages")
@@ -906,26 +959,12 @@ fn estimate_responses_prompt_tokens(payload: &Value) -> i64 {
     estimate_tokens(&fallback)
 }
 
-fn parse_responses_usage(usage: &Value) -> TokenUsage {
-    let prompt_tokens = usage
-        .get("prompt_tokens")
-        .or_else(|| usage.get("input_tokens"))
-        .and_then(|v| v.as_i64())
-        .unwrap_or(0);
-    let completion_tokens = usage
-        .get("completion_tokens")
-        .or_else(|| usage.get("output_tokens"))
-        .and_then(|v| v.as_i64())
-        .unwrap_or(0);
-    TokenUsage::new(prompt_tokens, completion_tokens)
-}
-
 fn extract_responses_usage_from_value(value: &Value) -> Option<TokenUsage> {
     if let Some(usage) = value.get("usage") {
-        return Some(parse_responses_usage(usage));
+        return Some(parse_openai_usage(usage));
     }
     if let Some(usage) = value.get("response").and_then(|r| r.get("usage")) {
-        return Some(parse_responses_usage(usage));
+        return Some(parse_openai_usage(usage));
     }
     None
 }
@@ -936,27 +975,27 @@ fn extract_responses_usage(response: &Value) -> TokenUsage {
 
 fn apply_responses_stream_usage(event: &Value, usage: &mut TokenUsage) {
     if let Some(new_usage) = extract_responses_usage_from_value(event) {
-        *usage = new_usage;
+        usage.apply_reported(&new_usage);
         return;
     }
 
     if let Some(delta) = event.get("delta").and_then(|v| v.as_str()) {
-        usage.completion_tokens += estimate_tokens(delta);
+        usage.add_estimated_output(estimate_tokens(delta));
         return;
     }
 
     if let Some(content) = event.get("content").and_then(|v| v.as_str()) {
-        usage.completion_tokens += estimate_tokens(content);
+        usage.add_estimated_output(estimate_tokens(content));
         return;
     }
 
     if let Some(output) = event.get("output_text").and_then(|v| v.as_str()) {
-        usage.completion_tokens += estimate_tokens(output);
+        usage.add_estimated_output(estimate_tokens(output));
         return;
     }
 
     if let Some(text) = event.get("text").and_then(|v| v.as_str()) {
-        usage.completion_tokens += estimate_tokens(text);
+        usage.add_estimated_output(estimate_tokens(text));
     }
 }
 
@@ -1021,7 +1060,7 @@ impl Drop for StreamUsageFinalizer {
 
 async fn handle_openai_to_anthropic_request(
     ctx: ForwardContext,
-    payload: Value,
+    mut payload: Arc<Value>,
 ) -> ForwardResult<UpstreamResponse> {
     let start = Instant::now();
     let upstream_ctx = with_provider(&ctx, Provider::Anthropic);
@@ -1038,16 +1077,18 @@ async fn handle_openai_to_anthropic_request(
     let mut headers = handler.build_headers(&upstream_ctx);
     headers.insert("accept", HeaderValue::from_static("application/json"));
 
-    let mut body =
-        anthropic::convert_openai_to_anthropic_request(&payload, ctx.model.upstream_model());
-    client::normalize_stream_flag(&mut body);
+    images::inline_shared_remote_images(&mut payload)
+        .await
+        .map_err(ForwardError::InvalidRequest)?;
+    let body = anthropic_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&body);
 
     let config = ctx.retry_config();
-    let client = client::default_client()?;
+    let client = client::default_client_for(&ctx.upstream)?;
     let result = client::send_with_retry(
         &client,
         upstream_ctx.all_endpoints(),
-        "/v1/messages",
+        &upstream_ctx.messages_path(),
         headers,
         &body,
         &config,
@@ -1055,12 +1096,13 @@ async fn handle_openai_to_anthropic_request(
     .await?;
 
     let status = result.response.status();
+    let upstream_headers = result.response.headers().clone();
     let status_code = status.as_u16();
-    let response_text = result.response.text().await.map_err(|e| {
+    let response_text = timing::upstream(result.response.text()).await.map_err(|e| {
         logger::error("openai", &format!("Failed to read response body: {}", e));
-        ForwardError::RequestFailed(format!("Failed to read response: {}", e))
+        ForwardError::request_failed(format!("Failed to read response: {}", e))
     })?;
-    let response_body: Value = client::parse_json_response(&response_text).map_err(|e| {
+    let response_body: Value = client::parse_upstream_body(&upstream_headers, &response_text, &ctx.upstream.id).map_err(|e| {
         logger::error(
             "openai",
             &format!(
@@ -1069,7 +1111,7 @@ async fn handle_openai_to_anthropic_request(
                 &response_text[..response_text.len().min(500)]
             ),
         );
-        ForwardError::RequestFailed(format!("Failed to parse response: {}", e))
+        ForwardError::request_failed(format!("Failed to parse response: {}", e))
     })?;
 
     if !status.is_success() {
@@ -1077,18 +1119,18 @@ async fn handle_openai_to_anthropic_request(
             "openai",
             &format!("Request failed: status={}, response={}", status_code, response_body),
         );
-        return Err(ForwardError::RequestFailed(format!(
-            "Upstream returned {}: {}",
+        return Err(ForwardError::upstream(
             status_code,
-            response_body.to_string()
-        )));
+            &response_body.to_string(),
+        ));
     }
 
     let openai_body =
-        anthropic::convert_anthropic_response_to_openai(&response_body, ctx.model.upstream_model());
+        AnthropicAdapter::default().response_to_openai(&response_body, ctx.model.upstream_model());
     let mut usage = extract_usage(&openai_body);
     if usage.prompt_tokens == 0 {
         usage.prompt_tokens = estimate_openai_prompt_tokens(&payload);
+        usage.prompt_estimated = true;
     }
 
     let latency_ms = start.elapsed().as_millis() as u64;
@@ -1099,12 +1141,14 @@ async fn handle_openai_to_anthropic_request(
         latency_ms,
         status: status_code,
         usage,
+        audio_dropped,
+        headers: upstream_headers,
     })
 }
 
 async fn handle_openai_to_gemini_request(
     ctx: ForwardContext,
-    payload: Value,
+    mut payload: Arc<Value>,
 ) -> ForwardResult<UpstreamResponse> {
     let start = Instant::now();
     let upstream_ctx = with_provider(&ctx, Provider::Gemini);
@@ -1119,50 +1163,43 @@ async fn handle_openai_to_gemini_request(
 
     let handler = gemini::GeminiHandler;
     let headers = handler.build_headers(&upstream_ctx);
-    let body = gemini::convert_openai_to_gemini_request(&payload, ctx.model.upstream_model());
+    images::inline_shared_remote_images(&mut payload)
+        .await
+        .map_err(ForwardError::InvalidRequest)?;
+    let body = gemini_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&body);
     let config = ctx.retry_config();
-    let client = client::default_client()?;
+    let client = client::default_client_for(&ctx.upstream)?;
 
-    let path = format!(
-        "/{}/models/{}:generateContent",
-        upstream_ctx.gemini_version(),
-        ctx.model.upstream_model()
-    );
+    let path = upstream_ctx.generate_path(ctx.model.upstream_model(), false);
     let endpoints = gemini::build_gemini_endpoints(&upstream_ctx, &path);
 
     let result = client::send_with_retry(&client, &endpoints, "", headers, &body, &config).await?;
 
     let status = result.response.status();
+    let upstream_headers = result.response.headers().clone();
     let status_code = status.as_u16();
-    let response_body: Value = result
-        .response
-        .json()
+    let response_body: Value = timing::upstream(result.response.json())
         .await
-        .map_err(|e| ForwardError::RequestFailed(format!("Failed to parse response: {}", e)))?;
+        .map_err(|e| ForwardError::request_failed(format!("Failed to parse response: {}", e)))?;
 
     if !status.is_success() {
-        return Err(ForwardError::RequestFailed(format!(
-            "Upstream returned {}: {}",
+        return Err(ForwardError::upstream(
             status_code,
-            response_body.to_string()
-        )));
+            &response_body.to_string(),
+        ));
     }
 
-    if let Some(block_reason) = response_body
-        .get("promptFeedback")
-        .and_then(|pf| pf.get("blockReason"))
-    {
-        return Err(ForwardError::RequestFailed(format!(
-            "Content blocked: {:?}",
-            block_reason
-        )));
+    if let Some(err) = gemini::blocked(&response_body) {
+        return Err(err);
     }
 
     let openai_body =
-        gemini::convert_gemini_response_to_openai(&response_body, ctx.model.upstream_model());
+        GeminiAdapter::default().response_to_openai(&response_body, ctx.model.upstream_model());
     let mut usage = extract_usage(&openai_body);
     if usage.prompt_tokens == 0 {
         usage.prompt_tokens = estimate_openai_prompt_tokens(&payload);
+        usage.prompt_estimated = true;
     }
 
     let latency_ms = start.elapsed().as_millis() as u64;
@@ -1173,16 +1210,21 @@ async fn handle_openai_to_gemini_request(
         latency_ms,
         status: status_code,
         usage,
+        audio_dropped,
+        headers: upstream_headers,
     })
 }
 
 async fn handle_openai_to_anthropic_stream(
     ctx: ForwardContext,
-    payload: Value,
+    mut payload: Value,
 ) -> ForwardResult<Response> {
     let upstream_ctx = with_provider(&ctx, Provider::Anthropic);
-    let mut body =
-        anthropic::convert_openai_to_anthropic_request(&payload, ctx.model.upstream_model());
+    images::inline_remote_images(&mut payload)
+        .await
+        .map_err(ForwardError::InvalidRequest)?;
+    let mut body = anthropic_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&body);
     if let Some(obj) = body.as_object_mut() {
         obj.insert("stream".to_string(), Value::Bool(true));
     }
@@ -1192,11 +1234,14 @@ async fn handle_openai_to_anthropic_stream(
     headers.insert("accept", HeaderValue::from_static("text/event-stream"));
     headers.insert("accept-encoding", HeaderValue::from_static("identity"));
 
-    let client = client::streaming_client()?;
+    let client = client::streaming_client_for(&ctx.upstream)?;
     let endpoint = upstream_ctx.primary_endpoint().ok_or_else(|| {
         ForwardError::UpstreamNotFound("No endpoints configured".to_string())
     })?;
-    let url = format!("{}/v1/messages", endpoint.trim_end_matches('/'));
+    let url = format!("{}{}", endpoint.trim_end_matches('/'), upstream_ctx.messages_path());
+
+    let body = forward::scrub::outbound(&body);
+    capture::record_request(&url, &headers, &body);
 
     let response = client
         .post(&url)
@@ -1206,30 +1251,33 @@ async fn handle_openai_to_anthropic_stream(
         .await
         .map_err(|e| {
             logger::error("openai", &format!("Stream request failed: {}", e));
-            ForwardError::RequestFailed(e.to_string())
+            ForwardError::request_failed(e.to_string())
         })?;
+    forward::ratelimits::observe(response.headers());
 
     if !response.status().is_success() {
         let status = response.status();
+        let response_headers = response.headers().clone();
         let text = response.text().await.unwrap_or_default();
         logger::error(
             "openai",
             &format!("Anthropic stream error: status={}, body={}", status, text),
         );
-        return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+        return Err(ForwardError::upstream(status.as_u16(), &text)
+            .with_retry_after(&response_headers)
+            .at_endpoint(endpoint));
     }
 
     let estimated_prompt_tokens = estimate_openai_prompt_tokens(&payload);
-    let state = Arc::new(Mutex::new({
-        let mut s = anthropic::AnthropicToOpenAIStreamState::new(ctx.model.upstream_model());
-        s.prompt_tokens = estimated_prompt_tokens;
-        s
-    }));
+    let state = Arc::new(Mutex::new(
+        AnthropicAdapter::default()
+            .stream_to_openai(ctx.model.upstream_model(), estimated_prompt_tokens),
+    ));
     let state_clone = Arc::clone(&state);
     let line_buffer = Arc::new(Mutex::new(Vec::new()));
     let line_buffer_clone = Arc::clone(&line_buffer);
 
-    let stream = response.bytes_stream().map(move |result| match result {
+    let stream = capture::tap(response.bytes_stream()).map(move |result| match result {
         Ok(bytes) => {
             let lines = {
                 let mut buffer = line_buffer_clone.lock().unwrap();
@@ -1245,11 +1293,7 @@ async fn handle_openai_to_anthropic_stream(
                     match serde_json::from_str::<Value>(data) {
                         Ok(json) => {
                             if let Ok(mut state) = state_clone.lock() {
-                                let chunks =
-                                    anthropic::convert_anthropic_event_to_openai_chunks(
-                                        &json, &mut state,
-                                    );
-                                for chunk in chunks {
+                                for chunk in state.map_event(&json) {
                                     output.extend_from_slice(
                                         format!("data: {}\n\n", chunk).as_bytes(),
                                     );
@@ -1279,7 +1323,7 @@ async fn handle_openai_to_anthropic_stream(
     let logged_stream = stream
         .chain(futures_util::stream::once(async move {
             if let Ok(state) = state_for_log.lock() {
-                let usage = TokenUsage::new(state.prompt_tokens, state.completion_tokens);
+                let usage = state.usage();
                 ctx_for_log.log_usage(&usage);
             }
             Ok(Bytes::from("data: [DONE]\n\n"))
@@ -1294,7 +1338,7 @@ async fn handle_openai_to_anthropic_stream(
             }
         });
 
-    Ok(Response::builder()
+    let response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", "text/event-stream")
         .header("cache-control", "no-cache")
@@ -1303,22 +1347,30 @@ async fn handle_openai_to_anthropic_stream(
         .unwrap_or_else(|e| {
             logger::error("openai", &format!("Failed to build stream response: {}", e));
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        }))
+        });
+    Ok(forward::attach_dropped_audio(response, audio_dropped))
 }
 
 async fn handle_openai_to_gemini_stream(
     ctx: ForwardContext,
-    payload: Value,
+    mut payload: Value,
 ) -> ForwardResult<Response> {
     let upstream_ctx = with_provider(&ctx, Provider::Gemini);
-    let body = gemini::convert_openai_to_gemini_request(&payload, ctx.model.upstream_model());
+    images::inline_remote_images(&mut payload)
+        .await
+        .map_err(ForwardError::InvalidRequest)?;
+    let body = gemini_body(&ctx, &payload);
+    let audio_dropped = adapters::dropped_audio(&body);
 
     let handler = gemini::GeminiHandler;
     let headers = handler.build_headers(&upstream_ctx);
-    let client = client::streaming_client()?;
+    let client = client::streaming_client_for(&ctx.upstream)?;
     let url = gemini::build_gemini_stream_url(&upstream_ctx, ctx.model.upstream_model())
         .ok_or_else(|| ForwardError::UpstreamNotFound("No endpoints configured".to_string()))?;
 
+    let body = forward::scrub::outbound(&body);
+    capture::record_request(&url, &headers, &body);
+
     let response = client
         .post(&url)
         .headers(headers)
@@ -1327,30 +1379,32 @@ async fn handle_openai_to_gemini_stream(
         .await
         .map_err(|e| {
             logger::error("openai", &format!("Gemini stream request failed: {}", e));
-            ForwardError::RequestFailed(e.to_string())
+            ForwardError::request_failed(e.to_string())
         })?;
+    forward::ratelimits::observe(response.headers());
 
     if !response.status().is_success() {
         let status = response.status();
+        let response_headers = response.headers().clone();
         let text = response.text().await.unwrap_or_default();
         logger::error(
             "openai",
             &format!("Gemini stream error: status={}, body={}", status, text),
         );
-        return Err(ForwardError::RequestFailed(format!("{}: {}", status, text)));
+        return Err(ForwardError::upstream(status.as_u16(), &text)
+            .with_retry_after(&response_headers));
     }
 
     let estimated_prompt_tokens = estimate_openai_prompt_tokens(&payload);
-    let state = Arc::new(Mutex::new({
-        let mut s = gemini::GeminiToOpenAIStreamState::new(ctx.model.upstream_model());
-        s.prompt_tokens = estimated_prompt_tokens;
-        s
-    }));
+    let state = Arc::new(Mutex::new(
+        GeminiAdapter::default()
+            .stream_to_openai(ctx.model.upstream_model(), estimated_prompt_tokens),
+    ));
     let state_clone = Arc::clone(&state);
     let line_buffer = Arc::new(Mutex::new(Vec::new()));
     let line_buffer_clone = Arc::clone(&line_buffer);
 
-    let stream = response.bytes_stream().map(move |result| match result {
+    let stream = capture::tap(response.bytes_stream()).map(move |result| match result {
         Ok(bytes) => {
             let lines = {
                 let mut buffer = line_buffer_clone.lock().unwrap();
@@ -1366,11 +1420,7 @@ async fn handle_openai_to_gemini_stream(
                     match serde_json::from_str::<Value>(data) {
                         Ok(json) => {
                             if let Ok(mut state) = state_clone.lock() {
-                                let chunks =
-                                    gemini::convert_gemini_event_to_openai_chunks(
-                                        &json, &mut state,
-                                    );
-                                for chunk in chunks {
+                                for chunk in state.map_event(&json) {
                                     output.extend_from_slice(
                                         format!("data: {}\n\n", chunk).as_bytes(),
                                     );
@@ -1400,7 +1450,7 @@ async fn handle_openai_to_gemini_stream(
     let logged_stream = stream
         .chain(futures_util::stream::once(async move {
             if let Ok(state) = state_for_log.lock() {
-                let usage = TokenUsage::new(state.prompt_tokens, state.completion_tokens);
+                let usage = state.usage();
                 ctx_for_log.log_usage(&usage);
             }
             Ok(Bytes::from("data: [DONE]\n\n"))
@@ -1415,7 +1465,7 @@ async fn handle_openai_to_gemini_stream(
             }
         });
 
-    Ok(Response::builder()
+    let response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", "text/event-stream")
         .header("cache-control", "no-cache")
@@ -1424,13 +1474,36 @@ async fn handle_openai_to_gemini_stream(
         .unwrap_or_else(|e| {
             logger::error("openai", &format!("Failed to build stream response: {}", e));
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        }))
+        });
+    Ok(forward::attach_dropped_audio(response, audio_dropped))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_responses_stream_usage_replaces_estimates() {
+        let mut usage = TokenUsage::estimated(30);
+        let created = serde_json::json!({"type": "response.created", "response": {"usage": null}});
+        apply_responses_stream_usage(&created, &mut usage);
+        assert_eq!(usage.prompt_tokens, 30, "an empty usage object keeps the estimate");
+
+        apply_responses_stream_usage(&serde_json::json!({"delta": "Hello world"}), &mut usage);
+        assert!(usage.completion_tokens > 0);
+        assert!(usage.is_estimated());
+
+        let completed = serde_json::json!({
+            "type": "response.completed",
+            "response": {"usage": {"input_tokens": 25, "output_tokens": 4}}
+        });
+        apply_responses_stream_usage(&completed, &mut usage);
+        // Late deltas are not added on top of reported numbers
+        apply_responses_stream_usage(&serde_json::json!({"delta": "trailing"}), &mut usage);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (25, 4));
+        assert!(!usage.is_estimated());
+    }
+
     #[test]
     fn test_filter_payload() {
         let payload = serde_json::json!({
@@ -1472,6 +1545,32 @@ mod tests {
         assert_eq!(usage.completion_tokens, 0);
     }
 
+    #[test]
+    fn test_extract_usage_details() {
+        let response = serde_json::json!({
+            "usage": {
+                "prompt_tokens": 200,
+                "completion_tokens": 80,
+                "prompt_tokens_details": { "cached_tokens": 150 },
+                "completion_tokens_details": { "reasoning_tokens": 64 }
+            }
+        });
+        let usage = extract_usage(&response);
+        assert_eq!(usage.cache_read_tokens, 150);
+        assert_eq!(usage.reasoning_tokens, 64);
+        assert_eq!(usage.total(), 280);
+
+        let responses_usage = serde_json::json!({
+            "input_tokens": 12,
+            "output_tokens": 9,
+            "input_tokens_details": { "cached_tokens": 4 },
+            "output_tokens_details": { "reasoning_tokens": 6 }
+        });
+        let usage = parse_openai_usage(&responses_usage);
+        assert_eq!(usage.cache_read_tokens, 4);
+        assert_eq!(usage.reasoning_tokens, 6);
+    }
+
     #[test]
     fn test_multimodal_message_format() {
         // Test that OpenAI multimodal messages (vision) are preserved correctly
@@ -0,0 +1,241 @@
+//! Remote images inlined for upstreams that only take base64.
+//!
+//! OpenAI clients may send an `image_url` with an http(s) URL, which
+//! Anthropic and Gemini requests cannot carry. With `image_fetch` enabled
+//! such URLs are downloaded before the request is converted, checked to be
+//! an image of at most `max_bytes`, and replaced by a data URL, which the
+//! adapters turn into an Anthropic `image` block or a Gemini `inline_data`
+//! part. Hosts are checked like `web_fetch` does, on every redirect.
+//! Fetched images are kept for `cache_secs` by the hash of their URL, so a
+//! conversation resending its history downloads each image once.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, ImageFetchConfig};
+use crate::forward::client;
+use crate::logger;
+use crate::tools::web_fetch;
+
+/// Media types every upstream takes
+const MEDIA_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+const MAX_REDIRECTS: usize = 5;
+/// Images cached at most; the oldest is dropped first
+const CACHE_ENTRIES: usize = 64;
+
+/// Data URLs by the SHA-256 of their URL, with when they were fetched
+type Cache = HashMap<[u8; 32], (Instant, String)>;
+
+static CACHE: Lazy<Mutex<Cache>> = Lazy::new(Default::default);
+
+/// Replace the remote `image_url`s of an OpenAI chat request by data URLs.
+/// Does nothing unless `image_fetch` is enabled; an image that cannot be
+/// fetched fails the request.
+pub async fn inline_remote_images(request: &mut Value) -> Result<(), String> {
+    let cfg = config::current().image_fetch.clone();
+    if !cfg.enabled {
+        return Ok(());
+    }
+    for url in remote_image_urls(request) {
+        let remote = url.as_str().unwrap_or_default().to_string();
+        let data_url = fetch(&cfg, &remote)
+            .await
+            .map_err(|e| format!("Cannot inline image {}: {}", remote, e))?;
+        *url = Value::String(data_url);
+    }
+    Ok(())
+}
+
+/// [`inline_remote_images`] for a request shared with the other attempts,
+/// which is only copied when it has an image to replace.
+pub async fn inline_shared_remote_images(request: &mut Arc<Value>) -> Result<(), String> {
+    if !config::current().image_fetch.enabled || !has_remote_images(request) {
+        return Ok(());
+    }
+    inline_remote_images(Arc::make_mut(request)).await
+}
+
+/// The `image_url.url`s of `request` that are http(s) URLs.
+fn remote_image_urls(request: &mut Value) -> Vec<&mut Value> {
+    request
+        .get_mut("messages")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.get_mut("content").and_then(Value::as_array_mut))
+        .flatten()
+        .filter(|part| part.get("type").and_then(Value::as_str) == Some("image_url"))
+        .filter_map(|part| part.get_mut("image_url")?.get_mut("url"))
+        .filter(|url| is_remote(url))
+        .collect()
+}
+
+fn has_remote_images(request: &Value) -> bool {
+    request
+        .get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message.get("content").and_then(Value::as_array))
+        .flatten()
+        .filter(|part| part.get("type").and_then(Value::as_str) == Some("image_url"))
+        .filter_map(|part| part.get("image_url")?.get("url"))
+        .any(is_remote)
+}
+
+fn is_remote(url: &Value) -> bool {
+    url.as_str().is_some_and(|url| {
+        let url = url.trim_start().to_ascii_lowercase();
+        url.starts_with("http://") || url.starts_with("https://")
+    })
+}
+
+/// `url` as a data URL, from the cache when fetched lately.
+async fn fetch(cfg: &ImageFetchConfig, url: &str) -> Result<String, String> {
+    let parsed = Url::parse(url.trim()).map_err(|e| format!("invalid URL: {}", e))?;
+    check(cfg, &parsed)?;
+    let key: [u8; 32] = Sha256::digest(url.as_bytes()).into();
+    let ttl = Duration::from_secs(cfg.cache_secs);
+    let cached = CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&key)
+        .filter(|(at, _)| at.elapsed() < ttl)
+        .map(|(_, data_url)| data_url.clone());
+    if let Some(data_url) = cached {
+        return Ok(data_url);
+    }
+
+    let (media_type, bytes) = download(cfg, parsed).await?;
+    logger::debug(
+        "images",
+        &format!("Inlined {} ({}, {} bytes)", url, media_type, bytes.len()),
+    );
+    let data_url = format!("data:{};base64,{}", media_type, B64.encode(&bytes));
+    if !ttl.is_zero() {
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        cache.retain(|_, (at, _)| at.elapsed() < ttl);
+        if cache.len() >= CACHE_ENTRIES {
+            let oldest = cache.iter().min_by_key(|(_, (at, _))| *at).map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(key, (Instant::now(), data_url.clone()));
+    }
+    Ok(data_url)
+}
+
+fn check(cfg: &ImageFetchConfig, url: &Url) -> Result<(), String> {
+    web_fetch::check_url(&cfg.allow_domains, &cfg.deny_domains, url)
+        .map_err(|e| format!("blocked by image_fetch: {}", e))
+}
+
+/// Media type and bytes of the image at `url`.
+async fn download(cfg: &ImageFetchConfig, mut url: Url) -> Result<(String, Vec<u8>), String> {
+    let client =
+        client::create_client_without_redirects(cfg.timeout_secs).map_err(|e| e.to_string())?;
+    for _ in 0..=MAX_REDIRECTS {
+        check(cfg, &url)?;
+        let mut response = client
+            .get(url.as_str())
+            .header("accept", MEDIA_TYPES.join(", "))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get("location")
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("HTTP {} without a location", status.as_u16()))?;
+            url = url
+                .join(location)
+                .map_err(|e| format!("invalid redirect: {}", e))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(format!("HTTP {}", status.as_u16()));
+        }
+        let media_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if !MEDIA_TYPES.contains(&media_type.as_str()) {
+            return Err(format!(
+                "content type '{}' is not one of {}",
+                media_type,
+                MEDIA_TYPES.join(", ")
+            ));
+        }
+        let too_large = || format!("larger than {} bytes", cfg.max_bytes);
+        if response
+            .content_length()
+            .is_some_and(|len| len > cfg.max_bytes as u64)
+        {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            if body.len() + chunk.len() > cfg.max_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        return Ok((media_type, body));
+    }
+    Err(format!("more than {} redirects", MAX_REDIRECTS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_remote_images_are_checked_and_cached() {
+        let mut request = json!({"messages": [
+            {"role": "system", "content": "Describe images."},
+            {"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "image_url", "image_url": {"url": "https://img.example.com/cat.png"}}
+            ]}
+        ]});
+        assert!(has_remote_images(&request));
+        assert!(!has_remote_images(&json!({"messages": [{"role": "user", "content": "Hi"}]})));
+        let urls = remote_image_urls(&mut request);
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls[0], "https://img.example.com/cat.png");
+
+        let cfg = ImageFetchConfig {
+            enabled: true,
+            deny_domains: vec!["tracker.example.com".to_string()],
+            ..ImageFetchConfig::default()
+        };
+        let blocked = fetch(&cfg, "https://tracker.example.com/pixel.gif").await;
+        assert!(blocked.unwrap_err().contains("blocked by image_fetch"));
+        assert!(fetch(&cfg, "http://127.0.0.1/cat.png").await.is_err());
+
+        let url = "https://img.example.com/cached.png";
+        let key: [u8; 32] = Sha256::digest(url.as_bytes()).into();
+        let data_url = "data:image/png;base64,iVBORw0KGgo=".to_string();
+        CACHE
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), data_url.clone()));
+        assert_eq!(fetch(&cfg, url).await, Ok(data_url));
+    }
+}
@@ -0,0 +1,93 @@
+//! Registry of streaming responses that are still being relayed.
+//!
+//! A stream is registered before its handler runs and removed when the
+//! response body is dropped, which happens both when the upstream finishes
+//! and when the client disconnects.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::response::Response;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+
+use super::context::ForwardContext;
+
+/// One stream in flight, as reported to the dashboard.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ActiveStream {
+    pub id: u64,
+    pub model: String,
+    pub upstream_id: String,
+    pub project: Option<String>,
+    /// Unix seconds when the request arrived
+    pub started_at: i64,
+    pub elapsed_ms: u64,
+}
+
+struct Entry {
+    stream: ActiveStream,
+    started: Instant,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static ACTIVE: Lazy<Mutex<HashMap<u64, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Removes its stream from the registry when dropped.
+pub struct StreamGuard(u64);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        ACTIVE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
+
+pub fn register(ctx: &ForwardContext) -> StreamGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let entry = Entry {
+        stream: ActiveStream {
+            id,
+            model: ctx.model.upstream_model().to_string(),
+            upstream_id: ctx.upstream.id.clone(),
+            project: ctx.meta.project.clone(),
+            started_at: chrono::Utc::now().timestamp(),
+            elapsed_ms: 0,
+        },
+        started: Instant::now(),
+    };
+    ACTIVE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id, entry);
+    StreamGuard(id)
+}
+
+/// Keep `guard` alive for as long as the response body is being sent.
+pub fn track(guard: StreamGuard, response: Response) -> Response {
+    let (parts, body) = response.into_parts();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+/// Streams currently in flight, oldest first.
+pub fn active_streams() -> Vec<ActiveStream> {
+    let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut streams: Vec<ActiveStream> = active
+        .values()
+        .map(|e| ActiveStream {
+            elapsed_ms: e.started.elapsed().as_millis() as u64,
+            ..e.stream.clone()
+        })
+        .collect();
+    streams.sort_by_key(|s| s.id);
+    streams
+}
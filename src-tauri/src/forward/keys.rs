@@ -0,0 +1,104 @@
+//! Upstream API key pools.
+//!
+//! An upstream may configure a single `api_key` and/or a pool of labelled
+//! `api_keys`. Each forwarding context picks one key round-robin when the plan
+//! is built and keeps it for every retry of that attempt, so the headers sent
+//! upstream and the key recorded on the usage row always agree.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+use super::middleware::token_fingerprint;
+
+/// The key chosen for one forwarding context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectedKey {
+    pub key: String,
+    /// Configured label, or a fingerprint of the key when unlabelled
+    pub id: String,
+}
+
+static CURSORS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Every usable key of an upstream, in configuration order.
+pub fn key_pool(upstream: &config::Upstream) -> Vec<SelectedKey> {
+    let single = upstream
+        .api_key
+        .iter()
+        .map(|key| config::UpstreamKey {
+            label: None,
+            key: key.clone(),
+        });
+    single
+        .chain(upstream.api_keys.iter().cloned())
+        .filter(|k| !k.key.trim().is_empty())
+        .map(|k| SelectedKey {
+            id: k
+                .label
+                .filter(|l| !l.trim().is_empty())
+                .unwrap_or_else(|| token_fingerprint(&k.key)),
+            key: k.key,
+        })
+        .collect()
+}
+
+/// Pick the next key for an upstream, rotating through its pool.
+pub fn select_key(upstream: &config::Upstream) -> Option<SelectedKey> {
+    let mut pool = key_pool(upstream);
+    match pool.len() {
+        0 => None,
+        1 => pool.pop(),
+        len => {
+            let mut cursors = CURSORS.lock().unwrap_or_else(|e| e.into_inner());
+            let cursor = cursors.entry(upstream.id.to_lowercase()).or_insert(0);
+            let idx = *cursor % len;
+            *cursor = cursor.wrapping_add(1);
+            Some(pool.swap_remove(idx))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(id: &str) -> config::Upstream {
+        config::Upstream {
+            id: id.to_string(),
+            api_key: Some("sk-primary-0000".to_string()),
+            api_keys: vec![
+                config::UpstreamKey {
+                    label: Some("team-b".to_string()),
+                    key: "sk-second-1111".to_string(),
+                },
+                config::UpstreamKey {
+                    label: None,
+                    key: " ".to_string(),
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_key_pool_labels_and_fingerprints() {
+        let pool = key_pool(&upstream("pool-labels"));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool[0].id, "...0000");
+        assert_eq!(pool[1].id, "team-b");
+    }
+
+    #[test]
+    fn test_select_key_rotates() {
+        let up = upstream("pool-rotate");
+        let first = select_key(&up).unwrap();
+        let second = select_key(&up).unwrap();
+        let third = select_key(&up).unwrap();
+        assert_ne!(first.key, second.key);
+        assert_eq!(first, third);
+    }
+}
@@ -2,21 +2,56 @@
 //!
 //! Handles request parsing, authentication, and routing to appropriate handlers.
 
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::Response;
+use once_cell::sync::Lazy;
 use rand::seq::SliceRandom;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Instant;
 
 use crate::config;
+use crate::telemetry::{Span, SpanKind};
 
 use super::context::{
-    AuthMode, ForwardContext, ForwardPlan, ModelInfo, Provider, RequestMeta, UpstreamInfo,
+    AuthMode, ForwardContext, ForwardPlan, ModelInfo, Provider, RequestMeta, RouteReason,
+    UpstreamInfo,
 };
-use super::error::{ForwardError, ForwardResult};
+use super::error::{Dialect, ForwardError, ForwardResult};
 
 /// Header name for CCR forward token
 const FORWARD_TOKEN_HEADER: &str = "x-ccr-forward-token";
 
+/// Header naming the project a request belongs to
+const PROJECT_HEADER: &str = "x-relay-project";
+
+/// Response header naming the project whose system prefix a request got,
+/// and how it was merged: `prepend; project=<name>`.
+pub const SYSTEM_PREFIX_HEADER: &str = "x-relay-system-prefix";
+
+/// Header marking a request the relay sends to itself, such as an
+/// autoconfig verification. Its value must be [`synthetic_token`].
+pub const SYNTHETIC_HEADER: &str = "x-relay-synthetic";
+
+/// Header with comma-separated tags stored on the request's usage row.
+/// OpenAI clients can also send them as `metadata.relay_tags`.
+pub const TAGS_HEADER: &str = "x-relay-tags";
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 64;
+
+/// Random per process, so clients cannot mark their own traffic as free.
+static SYNTHETIC_TOKEN: Lazy<String> = Lazy::new(|| {
+    use rand::RngCore;
+    let mut bytes = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+});
+
+/// Value of [`SYNTHETIC_HEADER`] for this process.
+pub fn synthetic_token() -> &'static str {
+    &SYNTHETIC_TOKEN
+}
+
 /// Extract authentication token from request headers
 ///
 /// Priority order:
@@ -73,12 +108,27 @@ pub fn determine_auth_mode(headers: &HeaderMap) -> ForwardResult<AuthMode> {
     let cfg = config::load();
     let request_token = extract_request_token(headers);
 
+    // A project's token stands in for the forward token
+    if request_token
+        .as_deref()
+        .is_some_and(|token| crate::projects::by_token(token).is_some())
+    {
+        return Ok(AuthMode::UseConfiguredKey);
+    }
+
     match &cfg.forward_token {
         Some(forward_token) if !forward_token.is_empty() => {
             // System has forward_token configured
+            let now = chrono::Utc::now().timestamp();
+            let version = request_token
+                .as_deref()
+                .and_then(|token| cfg.forward_token_version(token, now));
             match request_token {
-                Some(token) if token == *forward_token => {
+                Some(_) if version.is_some() => {
                     // Token matches forward_token -> use configured upstream API key
+                    if version.is_some_and(|v| v.deprecated) {
+                        log_deprecated_token(&cfg, headers);
+                    }
                     Ok(AuthMode::UseConfiguredKey)
                 }
                 Some(token) => {
@@ -106,12 +156,161 @@ pub fn determine_auth_mode(headers: &HeaderMap) -> ForwardResult<AuthMode> {
     }
 }
 
+/// Note a request made with the forward token that was rotated out.
+fn log_deprecated_token(cfg: &config::Settings, headers: &HeaderMap) {
+    let expires = cfg
+        .token_rotation
+        .previous_expires_at
+        .and_then(|at| chrono::DateTime::from_timestamp(at, 0))
+        .map(|at| at.to_rfc3339())
+        .unwrap_or_default();
+    let tool = extract_header_value(headers, "x-ccr-tool").unwrap_or_else(|| "unknown".into());
+    crate::logger::warn(
+        "auth",
+        &format!(
+            "Deprecated forward token (version {}) used by tool={}, valid until {}",
+            cfg.token_rotation.version.saturating_sub(1),
+            tool,
+            expires
+        ),
+    );
+}
+
 /// Extract request metadata from headers
+///
+/// The project is the one whose token the request carries, else the one
+/// named by `x-relay-project` (or the older `x-ccr-project`).
 pub fn extract_request_meta(headers: &HeaderMap) -> RequestMeta {
+    let token = extract_request_token(headers);
+    let project = crate::projects::attribute(
+        token.as_deref(),
+        extract_header_value(headers, PROJECT_HEADER)
+            .or_else(|| extract_header_value(headers, "x-ccr-project"))
+            .as_deref(),
+    );
     RequestMeta {
         channel: extract_header_value(headers, "x-ccr-channel")
             .unwrap_or_else(|| "web".to_string()),
         tool: extract_header_value(headers, "x-ccr-tool").unwrap_or_else(|| "unknown".to_string()),
+        client_token: token
+            .map(|token| token_fingerprint(&token))
+            .unwrap_or_else(|| "none".to_string()),
+        project_id: project.as_ref().and_then(|p| p.id),
+        project: project.map(|p| p.name),
+        session_id: extract_session_id(headers),
+        user_agent: extract_header_value(headers, "user-agent"),
+        started_at: Some(std::time::Instant::now()),
+        request_id: request_id(headers),
+        synthetic: extract_header_value(headers, SYNTHETIC_HEADER)
+            .is_some_and(|value| value == synthetic_token()),
+        mirrored: false,
+        cache: extract_header_value(headers, super::cache::HEADER)
+            .map(|value| super::cache::Directive::parse(&value))
+            .unwrap_or_default(),
+        experiment: None,
+        tags: Vec::new(),
+        replay_of: None,
+    }
+}
+
+/// Tags of `x-relay-tags` and of `metadata.relay_tags` (a comma-separated
+/// string or a list), in order and without duplicates. `relay_tags` is
+/// removed from the payload, and `metadata` with it once empty, since
+/// upstreams don't know it.
+pub fn request_tags(headers: &HeaderMap, payload: &mut Value) -> ForwardResult<Vec<String>> {
+    let mut raw: Vec<String> = extract_header_value(headers, TAGS_HEADER)
+        .map(|value| value.split(',').map(str::to_string).collect())
+        .unwrap_or_default();
+    if let Some(metadata) = payload.get_mut("metadata").and_then(Value::as_object_mut) {
+        match metadata.remove("relay_tags") {
+            Some(Value::String(value)) => raw.extend(value.split(',').map(str::to_string)),
+            Some(Value::Array(values)) => {
+                raw.extend(values.iter().filter_map(Value::as_str).map(str::to_string))
+            }
+            Some(other) => {
+                return Err(ForwardError::InvalidRequest(format!(
+                    "metadata.relay_tags must be a string or a list of strings, got {}",
+                    other
+                )))
+            }
+            None => {}
+        }
+        if metadata.is_empty() {
+            if let Some(object) = payload.as_object_mut() {
+                object.remove("metadata");
+            }
+        }
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in raw.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        if tag.len() > MAX_TAG_LEN {
+            return Err(ForwardError::InvalidRequest(format!(
+                "tag '{}...' is longer than {} characters",
+                tag.chars().take(16).collect::<String>(),
+                MAX_TAG_LEN
+            )));
+        }
+        if !tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '/'))
+        {
+            return Err(ForwardError::InvalidRequest(format!(
+                "tag '{}' may only contain letters, digits and - _ . : /",
+                tag
+            )));
+        }
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(ForwardError::InvalidRequest(format!(
+            "{} tags given, at most {} are allowed",
+            tags.len(),
+            MAX_TAGS
+        )));
+    }
+    Ok(tags)
+}
+
+/// The caller's `x-request-id` if it sent a usable one, otherwise a new ULID.
+pub fn request_id(headers: &HeaderMap) -> String {
+    extract_header_value(headers, "x-request-id")
+        .filter(|id| id.len() <= 128)
+        .unwrap_or_else(generate_request_id)
+}
+
+/// A ULID: 48-bit millisecond timestamp and 80 random bits in Crockford
+/// base32, so ids sort by arrival time.
+fn generate_request_id() -> String {
+    use rand::RngCore;
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let mut random = [0u8; 10];
+    rand::rngs::OsRng.fill_bytes(&mut random);
+    let value = random
+        .iter()
+        .fold(millis & 0xFFFF_FFFF_FFFF, |acc, &b| (acc << 8) | b as u128);
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Short, non-reversible label for a token: its prefix and last four characters.
+pub fn token_fingerprint(token: &str) -> String {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() <= 8 {
+        return "****".to_string();
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    match token.split_once('_') {
+        Some((prefix, _)) if prefix.len() <= 6 => format!("{}_...{}", prefix, tail),
+        _ => format!("...{}", tail),
     }
 }
 
@@ -127,19 +326,20 @@ pub fn extract_session_id(headers: &HeaderMap) -> Option<String> {
 
 /// Check if request is streaming
 pub fn is_streaming_request(payload: &Value) -> bool {
-    match payload.get("stream") {
-        Some(Value::Bool(stream)) => *stream,
-        Some(Value::Number(value)) => value.as_i64().map(|v| v != 0).unwrap_or(false),
-        Some(Value::String(value)) => {
-            let normalized = value.trim().to_ascii_lowercase();
-            matches!(normalized.as_str(), "true" | "1" | "yes" | "on")
-        }
-        _ => false,
-    }
+    payload
+        .get("stream")
+        .and_then(super::client::stream_flag)
+        .unwrap_or(false)
+}
+
+/// Whether the request streams, with its `stream` flag rewritten as a
+/// boolean so the payload handlers forward agrees with the plan.
+fn take_streaming_flag(payload: &mut Value) -> bool {
+    super::client::normalize_stream_flag(payload)
 }
 
-fn is_gemini_streaming_request(payload: &Value, endpoint_path: &str) -> bool {
-    if is_streaming_request(payload) {
+fn is_gemini_streaming_request(payload: &mut Value, endpoint_path: &str) -> bool {
+    if take_streaming_flag(payload) {
         return true;
     }
     let normalized = endpoint_path.to_ascii_lowercase();
@@ -192,10 +392,7 @@ pub fn find_model_config(model_id: &str) -> ForwardResult<config::ModelCfg> {
         .collect();
 
     if models.is_empty() {
-        return Err(ForwardError::ModelNotFound(format!(
-            "Model '{}' not configured",
-            model_id
-        )));
+        return unknown_model(model_id, &cfg).map(|models| models[0].clone());
     }
 
     models.sort_by(|a, b| b.priority.cmp(&a.priority));
@@ -241,7 +438,7 @@ pub fn find_models_by_priority(model_id: &str) -> ForwardResult<Vec<config::Mode
         .collect();
 
     if models.is_empty() {
-        return Err(ForwardError::ModelNotFound(format!("Model '{}' not configured", model_id)));
+        return unknown_model(model_id, &cfg);
     }
 
     // Sort by priority (highest first)
@@ -266,22 +463,110 @@ fn collect_models_for_id(
         return Ok(vec![model]);
     }
 
+    let models = matching_models(model_id, cfg);
+    if models.is_empty() {
+        return unknown_model(model_id, cfg);
+    }
+    Ok(models)
+}
+
+/// Models whose id or display name is `model_id`, highest priority first
+fn matching_models(model_id: &str, cfg: &config::Settings) -> Vec<config::ModelCfg> {
     let mut models: Vec<_> = cfg
         .models
         .iter()
         .filter(|m| m.id.eq_ignore_ascii_case(model_id) || m.display_name.eq_ignore_ascii_case(model_id))
         .cloned()
         .collect();
+    models.sort_by(|a, b| b.priority.cmp(&a.priority));
+    models
+}
 
-    if models.is_empty() {
-        return Err(ForwardError::ModelNotFound(format!(
-            "Model '{}' not configured",
-            model_id
-        )));
+/// Apply the `unknown_model` setting to an id that matched no model.
+fn unknown_model(
+    model_id: &str,
+    cfg: &config::Settings,
+) -> ForwardResult<Vec<config::ModelCfg>> {
+    let not_configured =
+        || ForwardError::ModelNotFound(format!("Model '{}' not configured", model_id));
+    match cfg.unknown_model.mode.to_lowercase().as_str() {
+        "default_to" => {
+            let target = cfg
+                .unknown_model
+                .default_to
+                .as_deref()
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .ok_or_else(not_configured)?;
+            let models = matching_models(target, cfg);
+            if models.is_empty() {
+                crate::logger::warn(
+                    "middleware",
+                    &format!(
+                        "unknown_model.default_to '{}' is not a configured model",
+                        target
+                    ),
+                );
+                return Err(not_configured());
+            }
+            crate::logger::info(
+                "middleware",
+                &format!("Unknown model '{}' remapped to '{}'", model_id, target),
+            );
+            Ok(models)
+        }
+        "suggest" => Err(ForwardError::UnknownModel {
+            model: model_id.to_string(),
+            did_you_mean: suggest_models(model_id, cfg),
+        }),
+        _ => Err(not_configured()),
     }
+}
 
-    models.sort_by(|a, b| b.priority.cmp(&a.priority));
-    Ok(models)
+/// Configured ids and display names closest to `model_id` by edit distance
+fn suggest_models(model_id: &str, cfg: &config::Settings) -> Vec<String> {
+    const MAX_SUGGESTIONS: usize = 5;
+    let wanted = model_id.to_lowercase();
+    let limit = (wanted.chars().count() / 3).max(2);
+
+    let mut scored: Vec<(usize, String)> = Vec::new();
+    for m in cfg.models.iter().filter(|m| !m.is_temporary) {
+        let best = [&m.id, &m.display_name]
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .map(|name| edit_distance(&wanted, &name.to_lowercase()))
+            .min();
+        let duplicate = scored.iter().any(|(_, id)| id.eq_ignore_ascii_case(&m.id));
+        if let Some(d) = best.filter(|d| *d <= limit && !duplicate) {
+            scored.push((d, m.id.clone()));
+        }
+    }
+    scored.sort();
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// Levenshtein distance over chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
 }
 
 fn resolve_routes_for_models(models: &[config::ModelCfg]) -> Vec<config::ModelRoute> {
@@ -367,8 +652,16 @@ fn build_plan_from_routes(
         None
     };
 
+    // The first route was drawn at random when others share its priority
+    let top_priority = ordered_routes[0].priority.unwrap_or(0);
+    let drawn = ordered_routes
+        .iter()
+        .filter(|route| route.priority.unwrap_or(0) == top_priority)
+        .count()
+        > 1;
+
     let mut contexts = Vec::new();
-    for route in ordered_routes {
+    for (index, route) in ordered_routes.into_iter().enumerate() {
         if route.upstream_id.trim().is_empty() {
             return Err(ForwardError::InvalidRequest(format!(
                 "Missing upstream_id for model '{}'",
@@ -422,19 +715,19 @@ fn build_plan_from_routes(
                 provider,
                 upstream_id: upstream_cfg.id.clone(),
                 upstream_model_id,
-                price_prompt_per_1k: model_cfg.price_prompt_per_1k,
-                price_completion_per_1k: model_cfg.price_completion_per_1k,
-            },
-            upstream: UpstreamInfo {
-                id: upstream_cfg.id,
-                endpoints: upstream_cfg.endpoints,
-                api_style: upstream_cfg.api_style,
-                api_key: upstream_cfg.api_key,
+                pricing: crate::pricing::ModelPricing::from_model(&model_cfg),
+                capabilities: model_cfg.capabilities.clone(),
             },
+            upstream: upstream_info(upstream_cfg)?,
             gemini_api_version: gemini_version,
             meta: meta.clone(),
             is_streaming,
             retry_max_attempts_override: retry_override,
+            route: match index {
+                0 if drawn => RouteReason::GroupMember,
+                0 => RouteReason::Primary,
+                n => RouteReason::Fallback(n),
+            },
         });
     }
 
@@ -451,6 +744,108 @@ fn build_plan_from_routes(
     Ok(ForwardPlan { primary, fallbacks })
 }
 
+/// Connection details of `upstream_cfg`, with a key picked from its pool.
+/// A mock upstream is reached at the relay's own mock server.
+fn upstream_info(upstream_cfg: config::Upstream) -> ForwardResult<UpstreamInfo> {
+    let selected_key = super::keys::select_key(&upstream_cfg);
+    let (endpoints, no_proxy) = if upstream_cfg.is_mock() {
+        (vec![super::mock::endpoint(&upstream_cfg.id)?], true)
+    } else {
+        (upstream_cfg.endpoints, upstream_cfg.no_proxy)
+    };
+    Ok(UpstreamInfo {
+        id: upstream_cfg.id,
+        endpoints,
+        api_style: upstream_cfg.api_style,
+        api_key: selected_key.as_ref().map(|k| k.key.clone()),
+        api_key_id: selected_key.map(|k| k.id),
+        extra_headers: upstream_cfg.extra_headers.into_iter().collect(),
+        chat_path: upstream_cfg.chat_path,
+        messages_path: upstream_cfg.messages_path,
+        generate_path: upstream_cfg.generate_path,
+        api_version: upstream_cfg.api_version,
+        proxy: upstream_cfg.proxy,
+        no_proxy,
+        ca_cert_path: upstream_cfg.ca_cert_path,
+        insecure_skip_verify: upstream_cfg.insecure_skip_verify,
+    })
+}
+
+/// Context for the copy of `primary` sent to `mirror`: its upstream, always
+/// with the configured key, and the model it names, priced like the
+/// configured model of that id if there is one. Tried once, never cached.
+pub fn build_mirror_context(
+    primary: &ForwardContext,
+    mirror: &config::MirrorConfig,
+) -> ForwardResult<ForwardContext> {
+    let upstream_cfg = find_upstream_config(&mirror.upstream_id)?;
+    let mut ctx = primary.clone();
+    if let Some(model) = mirror.model.as_deref().filter(|m| !m.trim().is_empty()) {
+        let cfg = config::load();
+        let configured = cfg.models.iter().find(|m| m.id.eq_ignore_ascii_case(model));
+        ctx.model.upstream_model_id = Some(
+            configured
+                .and_then(|m| m.upstream_model_id.clone())
+                .filter(|id| !id.trim().is_empty())
+                .unwrap_or_else(|| model.to_string()),
+        );
+        ctx.model.pricing = configured
+            .map(crate::pricing::ModelPricing::from_model)
+            .unwrap_or_default();
+        ctx.model.capabilities = configured.map(|m| m.capabilities.clone()).unwrap_or_default();
+    }
+    ctx.model.upstream_id = upstream_cfg.id.clone();
+    ctx.upstream = upstream_info(upstream_cfg)?;
+    ctx.auth_mode = AuthMode::UseConfiguredKey;
+    ctx.is_streaming = false;
+    ctx.retry_max_attempts_override = Some(1);
+    ctx.meta.mirrored = true;
+    ctx.meta.cache = Default::default();
+    Ok(ctx)
+}
+
+/// Context for replaying a captured request on `model_id`, through
+/// `upstream_id` when given, else its model's first route. The captured
+/// body is in `format`, which picks the handler converting it. Sent once
+/// with the configured key, never streamed.
+pub fn build_replay_context(
+    model_id: &str,
+    upstream_id: Option<&str>,
+    format: Provider,
+    meta: RequestMeta,
+) -> ForwardResult<ForwardContext> {
+    let cfg = config::load();
+    let models = collect_models_for_id(model_id, &cfg)?;
+    let model_cfg = models
+        .first()
+        .cloned()
+        .ok_or_else(|| ForwardError::ModelNotFound("No models configured".to_string()))?;
+    let mut routes = resolve_routes_for_models(&models);
+    if let Some(upstream_id) = upstream_id {
+        routes.retain(|route| route.upstream_id.eq_ignore_ascii_case(upstream_id));
+        if routes.is_empty() {
+            return Err(ForwardError::InvalidRequest(format!(
+                "Model '{}' has no route to upstream '{}'",
+                model_id, upstream_id
+            )));
+        }
+    }
+    let plan = build_plan_from_routes(
+        AuthMode::UseConfiguredKey,
+        meta,
+        false,
+        model_cfg,
+        routes,
+        false,
+        None,
+    )?;
+    let mut ctx = plan.primary;
+    ctx.model.provider = format;
+    ctx.retry_max_attempts_override = Some(1);
+    ctx.meta.cache = Default::default();
+    Ok(ctx)
+}
+
 /// Find upstream configuration by ID (case-insensitive)
 ///
 /// Supports:
@@ -491,11 +886,16 @@ pub fn find_upstream_config(upstream_id: &str) -> ForwardResult<config::Upstream
 /// 2. Extracts model ID from payload
 /// 3. Looks up model and upstream configurations
 /// 4. Builds the complete ForwardPlan
+/// 5. Applies spend budgets to the plan
+///
+/// The payload's `stream` flag is rewritten as a boolean on the way.
 pub fn build_forward_plan(
     headers: &HeaderMap,
-    payload: &Value,
+    payload: &mut Value,
     provider_hint: Option<Provider>,
 ) -> ForwardResult<ForwardPlan> {
+    let started = Instant::now();
+    let span = Span::start("relay.plan", SpanKind::Internal);
     let cfg = config::load();
 
     // 1. Determine auth mode
@@ -513,61 +913,160 @@ pub fn build_forward_plan(
         e
     })?;
 
-    crate::logger::debug(
-        "middleware",
-        &format!("Building context for model: {}", model_id),
-    );
+    let enable_retry_fallback = cfg.enable_retry_fallback.unwrap_or(false);
 
-    // 3. Find model configuration(s)
-    let models = collect_models_for_id(&model_id, &cfg).map_err(|e| {
-        crate::logger::error(
+    // 3. Extract metadata, attributing by the payload's user id when
+    // neither a project token nor a project header did
+    let mut meta = extract_request_meta(headers);
+    if meta.project.is_none() {
+        if let Some(project) = crate::projects::attribute_user(payload) {
+            meta.project_id = project.id;
+            meta.project = Some(project.name);
+        }
+    }
+    meta.tags = request_tags(headers, payload)?;
+    if !meta.tags.is_empty() {
+        super::activity::tagged(&meta.request_id, &meta.tags);
+    }
+    let is_streaming = take_streaming_flag(payload);
+
+    // The project's override replaces the requested model
+    let model_id = match project_model_override(&meta, &model_id) {
+        Some(model) => {
+            crate::logger::info(
+                "middleware",
+                &format!(
+                    "Project {} overrides model {} with {}",
+                    meta.project.as_deref().unwrap_or_default(),
+                    model_id,
+                    model
+                ),
+            );
+            model
+        }
+        None => model_id,
+    };
+    // A running experiment on the id picks the model of the client's variant
+    let (model_id, experiment) =
+        super::experiments::route(&cfg.experiments, model_id, payload, &meta);
+    meta.experiment = experiment;
+
+    // 4. Resolve routes and build contexts for a model id
+    let build_for = |model_id: &str| -> ForwardResult<ForwardPlan> {
+        crate::logger::debug(
             "middleware",
-            &format!("Model lookup failed: model_id='{}', error={}", model_id, e),
+            &format!("Building context for model: {}", model_id),
         );
-        e
-    })?;
 
-    let model_cfg = models
-        .first()
-        .cloned()
-        .ok_or_else(|| ForwardError::ModelNotFound("No models configured".to_string()))?;
-    let routes = resolve_routes_for_models(&models);
-    let routes = filter_routes_by_provider(routes, provider_hint)?;
+        let models = collect_models_for_id(model_id, &cfg).map_err(|e| {
+            crate::logger::error(
+                "middleware",
+                &format!("Model lookup failed: model_id='{}', error={}", model_id, e),
+            );
+            e
+        })?;
 
-    let enable_retry_fallback = cfg.enable_retry_fallback.unwrap_or(false);
+        let model_cfg = models
+            .first()
+            .cloned()
+            .ok_or_else(|| ForwardError::ModelNotFound("No models configured".to_string()))?;
+        let routes = resolve_routes_for_models(&models);
+        let routes = filter_routes_by_provider(routes, provider_hint)?;
 
-    // 4. Extract metadata
-    let meta = extract_request_meta(headers);
-    let is_streaming = is_streaming_request(payload);
+        build_plan_from_routes(
+            auth_mode.clone(),
+            meta.clone(),
+            is_streaming,
+            model_cfg,
+            routes,
+            enable_retry_fallback,
+            None,
+        )
+    };
 
-    // 5. Build contexts
-    build_plan_from_routes(
-        auth_mode,
-        meta,
-        is_streaming,
-        model_cfg,
-        routes,
-        enable_retry_fallback,
-        None,
-    )
+    // 5. Apply spend budgets (may drop routes or downgrade the model)
+    let plan =
+        build_for(&model_id).and_then(|plan| super::budget::enforce(plan, &cfg.limits, build_for));
+    trace_plan(&span, started, &model_id, &plan);
+    plan
+}
+
+fn project_model_override(meta: &RequestMeta, model_id: &str) -> Option<String> {
+    let project = crate::projects::by_id(meta.project_id?)?;
+    project.model_override(model_id).map(str::to_string)
+}
+
+/// The system prefix a request got, reported in [`SYSTEM_PREFIX_HEADER`].
+#[derive(Debug, Clone, Default)]
+pub struct PromptInjection(Option<String>);
+
+impl PromptInjection {
+    pub fn attach(self, mut response: Response) -> Response {
+        if let Some(value) = self.0.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            response.headers_mut().insert(SYSTEM_PREFIX_HEADER, value);
+        }
+        response
+    }
+}
+
+/// `payload` with the system prefix of the project `ctx` belongs to. This
+/// runs on the client's payload, before it is converted for the upstream,
+/// so the prefix survives routing to another provider.
+pub fn with_project_prompt(
+    ctx: &ForwardContext,
+    mut payload: Value,
+    dialect: Dialect,
+) -> (Value, PromptInjection) {
+    let Some(project) = ctx.meta.project_id.and_then(crate::projects::by_id) else {
+        return (payload, PromptInjection::default());
+    };
+    let Some(prefix) = project.system_prefix.as_deref() else {
+        return (payload, PromptInjection::default());
+    };
+    let mode = project.system_prefix_mode;
+    crate::projects::inject_system_prompt(&mut payload, prefix, mode, dialect);
+    let header = if project.name.is_ascii() {
+        format!("{}; project={}", mode.as_str(), project.name)
+    } else {
+        mode.as_str().to_string()
+    };
+    (payload, PromptInjection(Some(header)))
+}
+
+/// Record the outcome of plan building on its span and the request timing.
+fn trace_plan(span: &Span, started: Instant, model_id: &str, plan: &ForwardResult<ForwardPlan>) {
+    span.set("relay.requested_model", model_id);
+    match plan {
+        Ok(plan) => {
+            super::timing::record_plan(started.elapsed(), plan.primary.is_streaming);
+            span.set("relay.model", plan.primary.model.id.as_str());
+            span.set("relay.upstream", plan.primary.upstream.id.as_str());
+            span.set("relay.fallbacks", plan.fallbacks.len());
+        }
+        Err(e) => span.fail(e.to_string()),
+    }
 }
 
 /// Legacy wrapper for callers that only need a single context.
 #[allow(dead_code)]
 pub fn build_forward_context(
     headers: &HeaderMap,
-    payload: &Value,
+    payload: &mut Value,
 ) -> ForwardResult<ForwardContext> {
     build_forward_plan(headers, payload, None).map(|plan| plan.primary)
 }
 
-/// Build forward plan for Gemini (which may have model in URL path)
+/// Build forward plan for Gemini (which may have model in URL path),
+/// rewriting the payload's `stream` flag as a boolean like
+/// `build_forward_plan`
 pub fn build_gemini_plan(
     headers: &HeaderMap,
-    payload: &Value,
+    payload: &mut Value,
     endpoint_path: &str,
     api_version: &str,
 ) -> ForwardResult<ForwardPlan> {
+    let started = Instant::now();
+    let span = Span::start("relay.plan", SpanKind::Internal);
     let cfg = config::load();
 
     // Try to extract model from payload first
@@ -579,47 +1078,57 @@ pub fn build_gemini_plan(
             .unwrap_or_else(|| "gemini-pro".to_string())
     };
 
-    // Find model config or create a default one
-    let (model_cfg, routes) = match collect_models_for_id(&model_id, &cfg) {
-        Ok(models) => {
-            let model_cfg = models
-                .first()
-                .cloned()
-                .ok_or_else(|| ForwardError::ModelNotFound("No models configured".to_string()))?;
-            (model_cfg, resolve_routes_for_models(&models))
-        }
-        Err(ForwardError::ModelNotFound(_)) => {
-            let model_cfg = create_default_gemini_model(&model_id);
-            let routes = model_cfg.resolved_routes();
-            (model_cfg, routes)
-        }
-        Err(e) => return Err(e),
-    };
-
-    let routes = filter_routes_by_provider(routes, Some(Provider::Gemini))?;
-
-    // Continue with normal flow
     let auth_mode = determine_auth_mode(headers)?;
-    let meta = extract_request_meta(headers);
+    let mut meta = extract_request_meta(headers);
+    let (model_id, experiment) =
+        super::experiments::route(&cfg.experiments, model_id, payload, &meta);
+    meta.experiment = experiment;
     let is_streaming = is_gemini_streaming_request(payload, endpoint_path);
     let enable_retry_fallback = cfg.enable_retry_fallback.unwrap_or(false);
 
-    build_plan_from_routes(
-        auth_mode,
-        meta,
-        is_streaming,
-        model_cfg,
-        routes,
-        enable_retry_fallback,
-        Some(api_version),
-    )
+    let build_for = |model_id: &str| -> ForwardResult<ForwardPlan> {
+        // Find model config or create a default one
+        let (model_cfg, routes) = match collect_models_for_id(model_id, &cfg) {
+            Ok(models) => {
+                let model_cfg = models.first().cloned().ok_or_else(|| {
+                    ForwardError::ModelNotFound("No models configured".to_string())
+                })?;
+                (model_cfg, resolve_routes_for_models(&models))
+            }
+            // Unknown ids pass through to the gemini upstream unless
+            // `unknown_model` remapped them or asked for suggestions
+            Err(ForwardError::ModelNotFound(_)) => {
+                let model_cfg = create_default_gemini_model(model_id);
+                let routes = model_cfg.resolved_routes();
+                (model_cfg, routes)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let routes = filter_routes_by_provider(routes, Some(Provider::Gemini))?;
+
+        build_plan_from_routes(
+            auth_mode.clone(),
+            meta.clone(),
+            is_streaming,
+            model_cfg,
+            routes,
+            enable_retry_fallback,
+            Some(api_version),
+        )
+    };
+
+    let plan =
+        build_for(&model_id).and_then(|plan| super::budget::enforce(plan, &cfg.limits, build_for));
+    trace_plan(&span, started, &model_id, &plan);
+    plan
 }
 
 /// Legacy wrapper for callers that only need a single context.
 #[allow(dead_code)]
 pub fn build_gemini_context(
     headers: &HeaderMap,
-    payload: &Value,
+    payload: &mut Value,
     endpoint_path: &str,
     api_version: &str,
 ) -> ForwardResult<ForwardContext> {
@@ -669,8 +1178,20 @@ fn create_default_gemini_model(model_id: &str) -> config::ModelCfg {
         }],
         price_prompt_per_1k: 0.0,
         price_completion_per_1k: 0.0,
+        price_cache_write_per_1k: None,
+        price_cache_read_per_1k: None,
+        price_reasoning_per_1k: None,
+        price_tiers: Vec::new(),
+        capabilities: config::ModelCapabilities::default(),
+        price_source: None,
+        price_updated_at: None,
         priority: 50,
         is_temporary: false,
+        mirror: None,
+        max_concurrency: None,
+        max_queue_wait_ms: None,
+        context_guard: None,
+        safety_settings: Vec::new(),
     }
 }
 
@@ -678,6 +1199,76 @@ fn create_default_gemini_model(model_id: &str) -> config::ModelCfg {
 mod tests {
     use super::*;
 
+    fn settings_with_models(ids: &[&str]) -> config::Settings {
+        config::Settings {
+            models: ids
+                .iter()
+                .map(|id| config::ModelCfg {
+                    id: id.to_string(),
+                    provider: "openai".to_string(),
+                    upstream_id: "main".to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unknown_model_modes() {
+        let mut cfg = settings_with_models(&["gpt-4o", "gpt-4o-mini", "claude-sonnet-4"]);
+        assert!(matches!(
+            collect_models_for_id("gpt-3.5-turbo", &cfg),
+            Err(ForwardError::ModelNotFound(_))
+        ));
+
+        cfg.unknown_model.mode = "default_to".to_string();
+        cfg.unknown_model.default_to = Some("gpt-4o-mini".to_string());
+        let models = collect_models_for_id("gpt-3.5-turbo", &cfg).unwrap();
+        assert_eq!(models[0].id, "gpt-4o-mini");
+
+        cfg.unknown_model.mode = "suggest".to_string();
+        match collect_models_for_id("gpt4o", &cfg) {
+            Err(ForwardError::UnknownModel { did_you_mean, .. }) => {
+                assert_eq!(did_you_mean, vec!["gpt-4o".to_string()]);
+            }
+            _ => panic!("expected suggestions"),
+        }
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("gpt-4o", "gpt-4o"), 0);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let first = generate_request_id();
+        assert_eq!(first.len(), 26);
+        assert!(first
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase()));
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = generate_request_id();
+        // Later ids sort after earlier ones
+        assert!(second[..10] > first[..10]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "client-123".parse().unwrap());
+        assert_eq!(request_id(&headers), "client-123");
+        headers.insert("x-request-id", "x".repeat(200).parse().unwrap());
+        assert_eq!(request_id(&headers).len(), 26);
+    }
+
+    #[test]
+    fn test_token_fingerprint() {
+        assert_eq!(token_fingerprint("ccr_abcdefghijklmnop1234"), "ccr_...1234");
+        assert_eq!(token_fingerprint("sk-ant-api03-xyzw9876"), "...9876");
+        assert_eq!(token_fingerprint("short"), "****");
+    }
+
     #[test]
     fn test_extract_model_from_gemini_path() {
         assert_eq!(
@@ -704,18 +1295,96 @@ mod tests {
         assert!(!is_streaming_request(&serde_json::json!({"stream": "false"})));
         assert!(!is_streaming_request(&serde_json::json!({"stream": 0})));
         assert!(!is_streaming_request(&serde_json::json!({})));
+        assert!(is_streaming_request(&serde_json::json!({"stream": " Yes "})));
+        assert!(is_streaming_request(&serde_json::json!({"stream": 1.0})));
+    }
+
+    #[test]
+    fn test_plan_stream_flag_variants() {
+        use serde_json::json;
+        let variants = [
+            (json!(true), true),
+            (json!("true"), true),
+            (json!("1"), true),
+            (json!(1), true),
+            (json!(false), false),
+            (json!("false"), false),
+            (json!(0), false),
+        ];
+        for (stream, expected) in variants {
+            let mut openai = json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "hi"}],
+                "stream": stream.clone()
+            });
+            assert_eq!(take_streaming_flag(&mut openai), expected, "openai {}", stream);
+            assert_eq!(openai["stream"], json!(expected));
+
+            let mut anthropic = json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 1024,
+                "messages": [{"role": "user", "content": "hi"}],
+                "stream": stream.clone()
+            });
+            assert_eq!(take_streaming_flag(&mut anthropic), expected, "anthropic {}", stream);
+            assert_eq!(anthropic["stream"], json!(expected));
+
+            let mut gemini = json!({
+                "contents": [{"role": "user", "parts": [{"text": "hi"}]}],
+                "stream": stream.clone()
+            });
+            assert_eq!(
+                is_gemini_streaming_request(&mut gemini, "/models/gemini-pro:generateContent"),
+                expected,
+                "gemini {}",
+                stream
+            );
+            assert_eq!(gemini["stream"], json!(expected));
+        }
     }
 
     #[test]
     fn test_is_gemini_streaming_request() {
-        let payload = serde_json::json!({});
+        let mut payload = serde_json::json!({});
         assert!(is_gemini_streaming_request(
-            &payload,
+            &mut payload,
             "/models/gemini-pro:streamGenerateContent"
         ));
         assert!(!is_gemini_streaming_request(
-            &payload,
+            &mut payload,
             "/models/gemini-pro:generateContent"
         ));
+        assert!(payload.get("stream").is_none());
+    }
+
+    #[test]
+    fn test_request_tags() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TAGS_HEADER, HeaderValue::from_static(" team:search, nightly ,,"));
+        let mut payload = serde_json::json!({
+            "model": "gpt-4o",
+            "metadata": {"relay_tags": ["nightly", "eval/v2"]}
+        });
+        let tags = request_tags(&headers, &mut payload).unwrap();
+        assert_eq!(tags, vec!["team:search", "nightly", "eval/v2"]);
+        assert!(payload.get("metadata").is_none());
+
+        let mut payload = serde_json::json!({"metadata": {"relay_tags": "a", "user": "u1"}});
+        assert_eq!(request_tags(&HeaderMap::new(), &mut payload).unwrap(), vec!["a"]);
+        assert_eq!(payload["metadata"], serde_json::json!({"user": "u1"}));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TAGS_HEADER, HeaderValue::from_static("has space"));
+        assert!(matches!(
+            request_tags(&headers, &mut serde_json::json!({})),
+            Err(ForwardError::InvalidRequest(_))
+        ));
+
+        let many = (0..=MAX_TAGS).map(|i| format!("t{}", i)).collect::<Vec<_>>().join(",");
+        let mut headers = HeaderMap::new();
+        headers.insert(TAGS_HEADER, HeaderValue::from_str(&many).unwrap());
+        assert!(request_tags(&headers, &mut serde_json::json!({})).is_err());
+        let mut payload = serde_json::json!({"metadata": {"relay_tags": 7}});
+        assert!(request_tags(&HeaderMap::new(), &mut payload).is_err());
     }
 }
@@ -0,0 +1,129 @@
+//! Shadow traffic to a second upstream.
+//!
+//! A model with `mirror` configured sends a `sample_rate` share of its
+//! non-streaming requests again, to the mirror upstream, once the primary
+//! answer is in. The copy runs in the background, so the client never waits
+//! on it, and its answer is dropped. Status, latency and token counts of
+//! both sides go to the `mirror_log` table, summarized per upstream pair by
+//! `GET /api/mirror/report`.
+//!
+//! Copies are checked against budgets like any request and skipped when a
+//! budget would block or downgrade them. What they cost counts toward the
+//! budgets covering them, but they never appear in usage totals.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::Value;
+
+use super::budget::{self, BudgetDecision, SpendKey};
+use super::context::{ForwardContext, Provider, TokenUsage, UpstreamResponse};
+use super::{handlers, middleware, ForwardError};
+use crate::{config, db, logger};
+
+/// How the primary request fared.
+pub struct Primary {
+    pub status: u16,
+    pub latency_ms: i64,
+    pub usage: TokenUsage,
+}
+
+impl Primary {
+    pub fn answered(response: &UpstreamResponse) -> Self {
+        Self {
+            status: response.status,
+            latency_ms: response.latency_ms as i64,
+            usage: response.usage.clone(),
+        }
+    }
+
+    pub fn failed(ctx: &ForwardContext, err: &ForwardError) -> Self {
+        Self {
+            status: err.status_code(),
+            latency_ms: ctx
+                .meta
+                .started_at
+                .map_or(0, |t| t.elapsed().as_millis() as i64),
+            usage: TokenUsage::default(),
+        }
+    }
+}
+
+/// Mirror `payload`, answered by `primary` through the handler of
+/// `provider`, if its model asks for it and the sample picks it.
+pub fn spawn(provider: Provider, primary: &ForwardContext, payload: &Arc<Value>, outcome: Primary) {
+    if primary.meta.synthetic || primary.meta.mirrored {
+        return;
+    }
+    let cfg = config::current();
+    let Some(mirror) = cfg
+        .models
+        .iter()
+        .find(|m| m.id.eq_ignore_ascii_case(&primary.model.id))
+        .and_then(|m| m.mirror.as_ref())
+    else {
+        return;
+    };
+    if mirror.sample_rate <= 0.0 || rand::random::<f64>() >= mirror.sample_rate {
+        return;
+    }
+    let ctx = match middleware::build_mirror_context(primary, mirror) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            logger::warn(
+                "mirror",
+                &format!("Not mirroring {}: {}", primary.model.id, e),
+            );
+            return;
+        }
+    };
+    let key = SpendKey::from_context(&ctx);
+    if budget::evaluate(&key, &cfg.limits) != BudgetDecision::Allow {
+        logger::debug(
+            "mirror",
+            &format!("Not mirroring to {}: over budget", ctx.upstream.id),
+        );
+        return;
+    }
+
+    let mut record = db::MirrorRecord {
+        timestamp: chrono::Utc::now().timestamp(),
+        request_id: primary.meta.request_id.clone(),
+        model: ctx.model.upstream_model().to_string(),
+        upstream_id: ctx.upstream.id.clone(),
+        client_token: ctx.meta.client_token.clone(),
+        project: ctx.meta.project.clone(),
+        primary_upstream: primary.upstream.id.clone(),
+        primary_status: outcome.status,
+        primary_latency_ms: outcome.latency_ms,
+        primary_prompt_tokens: outcome.usage.prompt_tokens,
+        primary_completion_tokens: outcome.usage.completion_tokens,
+        ..Default::default()
+    };
+    let payload = Arc::clone(payload);
+    tokio::spawn(async move {
+        let started = Instant::now();
+        match handlers::get_handler(provider)
+            .handle_request(ctx.clone(), payload)
+            .await
+        {
+            Ok(response) => {
+                let cost = ctx.calculate_cost(&response.usage);
+                if let Some(cost) = cost {
+                    budget::record_spend(&key, cost);
+                }
+                record.status = response.status;
+                record.latency_ms = response.latency_ms as i64;
+                record.prompt_tokens = response.usage.prompt_tokens;
+                record.completion_tokens = response.usage.completion_tokens;
+                record.price_usd = cost;
+            }
+            Err(e) => {
+                record.status = e.status_code();
+                record.latency_ms = started.elapsed().as_millis() as i64;
+                record.error = Some(e.to_string());
+            }
+        }
+        db::log_mirror(record);
+    });
+}
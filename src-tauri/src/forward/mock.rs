@@ -0,0 +1,761 @@
+//! Canned upstream answers for offline development and tests.
+//!
+//! An upstream with `api_style = "mock"` is never called over the network.
+//! Its requests go to a small HTTP server the relay starts on a loopback
+//! port the first time such an upstream is routed to, so handlers,
+//! conversions, streaming and retries run exactly as they would against a
+//! real provider. The answer is in the dialect the request was sent in,
+//! told from the path the way a provider would: `:generateContent` is
+//! Gemini, `/messages` Anthropic, anything else OpenAI chat completions.
+//! Path templates of mock upstreams are best left at their defaults.
+//!
+//! `mock.response` picks the answer: `echo` repeats the last user message,
+//! `lorem` is fixed filler, `text` a template with `{prompt}` and `{model}`,
+//! and `tool_call` calls a tool, then echoes its result once the request
+//! carries one. `latency_ms` delays the start of every answer, and streams
+//! arrive in `chunk_chars` pieces `chunk_delay_ms` apart. `fail_statuses`
+//! are answered in turn before each success, for exercising retries and
+//! fallbacks.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use super::context::estimate_tokens;
+use super::error::{Dialect, ForwardError, ForwardResult};
+use super::middleware::is_streaming_request;
+use crate::config::{self, MockConfig};
+
+const LOREM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do \
+    eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, \
+    quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+
+const MESSAGE_ID: &str = "mock-message";
+const CALL_ID: &str = "call_mock";
+
+/// Address of the mock server, once started
+static SERVER: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+/// Requests answered by each mock upstream, to step through `fail_statuses`
+static ANSWERED: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// Endpoint of mock upstream `upstream_id`, starting the mock server if it
+/// isn't running yet.
+pub fn endpoint(upstream_id: &str) -> ForwardResult<String> {
+    let addr =
+        address().map_err(|e| ForwardError::Internal(format!("Mock server unavailable: {}", e)))?;
+    Ok(format!("http://{}/{}", addr, encode(upstream_id)))
+}
+
+fn address() -> Result<SocketAddr, String> {
+    let mut server = SERVER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(addr) = *server {
+        return Ok(addr);
+    }
+    let runtime = tokio::runtime::Handle::try_current().map_err(|e| e.to_string())?;
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).map_err(|e| e.to_string())?;
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    runtime.spawn(async move {
+        let result = match tokio::net::TcpListener::from_std(listener) {
+            Ok(listener) => axum::serve(listener, router()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            crate::logger::error("mock", &format!("Mock server stopped: {}", e));
+        }
+        *SERVER.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    });
+    crate::logger::info("mock", &format!("Mock upstreams served on {}", addr));
+    *server = Some(addr);
+    Ok(addr)
+}
+
+/// `id` as a single path segment.
+fn encode(id: &str) -> String {
+    id.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+fn router() -> Router {
+    Router::new().route("/:upstream/*path", post(answer))
+}
+
+async fn answer(Path((upstream_id, path)): Path<(String, String)>, body: Bytes) -> Response {
+    let Some(mock) = config::current()
+        .upstreams
+        .iter()
+        .find(|up| up.id == upstream_id && up.is_mock())
+        .map(|up| up.mock.clone())
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("No mock upstream '{}'", upstream_id) })),
+        )
+            .into_response();
+    };
+    let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let dialect = dialect_of(&path);
+
+    tokio::time::sleep(Duration::from_millis(mock.latency_ms)).await;
+    if let Some(status) = next_failure(&upstream_id, &mock.fail_statuses) {
+        return failure(dialect, status);
+    }
+
+    let reply = Reply::new(&mock, dialect, &path, &request);
+    let streaming = match dialect {
+        Dialect::Gemini => path.contains(":streamGenerateContent"),
+        _ => is_streaming_request(&request),
+    };
+    if !streaming {
+        return Json(reply.body(dialect)).into_response();
+    }
+    let events = reply.events(dialect, mock.chunk_chars.max(1));
+    let delay = Duration::from_millis(mock.chunk_delay_ms);
+    let stream = futures_util::stream::iter(events.into_iter().enumerate()).then(
+        move |(i, event)| async move {
+            if i > 0 {
+                tokio::time::sleep(delay).await;
+            }
+            Ok::<_, Infallible>(Bytes::from(event))
+        },
+    );
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(stream))
+        .unwrap_or_default()
+}
+
+fn dialect_of(path: &str) -> Dialect {
+    if path.contains(":generateContent") || path.contains(":streamGenerateContent") {
+        Dialect::Gemini
+    } else if path.trim_end_matches('/').ends_with("messages") {
+        Dialect::Anthropic
+    } else {
+        Dialect::OpenAI
+    }
+}
+
+/// The scripted error status due for `upstream_id`, if any.
+fn next_failure(upstream_id: &str, statuses: &[u16]) -> Option<u16> {
+    if statuses.is_empty() {
+        return None;
+    }
+    let mut answered = ANSWERED.lock().unwrap_or_else(|e| e.into_inner());
+    let count = answered.entry(upstream_id.to_string()).or_insert(0);
+    let step = *count % (statuses.len() + 1);
+    *count += 1;
+    statuses.get(step).copied()
+}
+
+fn failure(dialect: Dialect, status: u16) -> Response {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+    let limited = status == StatusCode::TOO_MANY_REQUESTS;
+    let message = format!("Mock upstream answered {}", status);
+    let body = match dialect {
+        Dialect::OpenAI => json!({
+            "error": {
+                "message": message,
+                "type": if limited { "rate_limit_exceeded" } else { "server_error" },
+                "code": status.as_u16(),
+            }
+        }),
+        Dialect::Anthropic => json!({
+            "type": "error",
+            "error": {
+                "type": if limited { "rate_limit_error" } else { "api_error" },
+                "message": message,
+            }
+        }),
+        Dialect::Gemini => json!({
+            "error": {
+                "code": status.as_u16(),
+                "message": message,
+                "status": if limited { "RESOURCE_EXHAUSTED" } else { "UNAVAILABLE" },
+            }
+        }),
+    };
+    (status, Json(body)).into_response()
+}
+
+/// What a mock answer says, before it is put in a dialect.
+#[derive(Debug, Clone, PartialEq)]
+enum Content {
+    Text(String),
+    ToolCall { name: String, arguments: Value },
+}
+
+#[derive(Debug, Clone)]
+struct Reply {
+    content: Content,
+    model: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+}
+
+impl Reply {
+    fn new(mock: &MockConfig, dialect: Dialect, path: &str, request: &Value) -> Self {
+        let model = match dialect {
+            Dialect::Gemini => path
+                .split("models/")
+                .nth(1)
+                .and_then(|rest| rest.split(':').next())
+                .unwrap_or("mock")
+                .to_string(),
+            _ => request["model"].as_str().unwrap_or("mock").to_string(),
+        };
+        let (prompt, tool_result) = last_turn(dialect, request);
+        let content = match mock.response.to_lowercase().as_str() {
+            "lorem" => Content::Text(LOREM.to_string()),
+            "text" => Content::Text(
+                mock.text
+                    .replace("{prompt}", &prompt)
+                    .replace("{model}", &model),
+            ),
+            "tool_call" if !tool_result => match mock
+                .tool_name
+                .clone()
+                .filter(|name| !name.trim().is_empty())
+                .or_else(|| first_tool(dialect, request))
+            {
+                Some(name) => Content::ToolCall {
+                    name,
+                    arguments: serde_json::from_str(&mock.tool_arguments)
+                        .unwrap_or_else(|_| json!({})),
+                },
+                None => Content::Text(prompt),
+            },
+            _ => Content::Text(prompt),
+        };
+        let completion = match &content {
+            Content::Text(text) => estimate_tokens(text),
+            Content::ToolCall { name, arguments } => {
+                estimate_tokens(name) + estimate_tokens(&arguments.to_string())
+            }
+        };
+        Reply {
+            content,
+            model,
+            prompt_tokens: estimate_tokens(&request.to_string()),
+            completion_tokens: completion,
+        }
+    }
+
+    /// Body of a non-streaming answer.
+    fn body(&self, dialect: Dialect) -> Value {
+        match dialect {
+            Dialect::OpenAI => {
+                let (message, finish_reason) = match &self.content {
+                    Content::Text(text) => {
+                        (json!({ "role": "assistant", "content": text }), "stop")
+                    }
+                    Content::ToolCall { name, arguments } => (
+                        json!({
+                            "role": "assistant",
+                            "content": null,
+                            "tool_calls": [{
+                                "id": CALL_ID,
+                                "type": "function",
+                                "function": { "name": name, "arguments": arguments.to_string() },
+                            }],
+                        }),
+                        "tool_calls",
+                    ),
+                };
+                json!({
+                    "id": MESSAGE_ID,
+                    "object": "chat.completion",
+                    "created": chrono::Utc::now().timestamp(),
+                    "model": self.model,
+                    "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+                    "usage": self.openai_usage(),
+                })
+            }
+            Dialect::Anthropic => {
+                let (block, stop_reason) = match &self.content {
+                    Content::Text(text) => (json!({ "type": "text", "text": text }), "end_turn"),
+                    Content::ToolCall { name, arguments } => (
+                        json!({ "type": "tool_use", "id": CALL_ID, "name": name, "input": arguments }),
+                        "tool_use",
+                    ),
+                };
+                json!({
+                    "id": MESSAGE_ID,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": self.model,
+                    "content": [block],
+                    "stop_reason": stop_reason,
+                    "stop_sequence": null,
+                    "usage": {
+                        "input_tokens": self.prompt_tokens,
+                        "output_tokens": self.completion_tokens,
+                    },
+                })
+            }
+            Dialect::Gemini => {
+                let part = match &self.content {
+                    Content::Text(text) => json!({ "text": text }),
+                    Content::ToolCall { name, arguments } => {
+                        json!({ "functionCall": { "name": name, "args": arguments } })
+                    }
+                };
+                self.gemini_chunk(part, true)
+            }
+        }
+    }
+
+    /// Server-sent events of a streamed answer, each a complete frame.
+    fn events(&self, dialect: Dialect, chunk_chars: usize) -> Vec<String> {
+        match dialect {
+            Dialect::OpenAI => self.openai_events(chunk_chars),
+            Dialect::Anthropic => self.anthropic_events(chunk_chars),
+            Dialect::Gemini => self.gemini_events(chunk_chars),
+        }
+    }
+
+    fn openai_events(&self, chunk_chars: usize) -> Vec<String> {
+        let chunk = |delta: Value, finish_reason: Value| {
+            json!({
+                "id": MESSAGE_ID,
+                "object": "chat.completion.chunk",
+                "created": chrono::Utc::now().timestamp(),
+                "model": self.model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+        };
+        let mut chunks = vec![chunk(
+            json!({ "role": "assistant", "content": "" }),
+            Value::Null,
+        )];
+        let finish_reason = match &self.content {
+            Content::Text(text) => {
+                for piece in pieces(text, chunk_chars) {
+                    chunks.push(chunk(json!({ "content": piece }), Value::Null));
+                }
+                "stop"
+            }
+            Content::ToolCall { name, arguments } => {
+                chunks.push(chunk(
+                    json!({ "tool_calls": [{
+                        "index": 0,
+                        "id": CALL_ID,
+                        "type": "function",
+                        "function": { "name": name, "arguments": "" },
+                    }] }),
+                    Value::Null,
+                ));
+                for piece in pieces(&arguments.to_string(), chunk_chars) {
+                    chunks.push(chunk(
+                        json!({ "tool_calls": [{ "index": 0, "function": { "arguments": piece } }] }),
+                        Value::Null,
+                    ));
+                }
+                "tool_calls"
+            }
+        };
+        let mut last = chunk(json!({}), finish_reason.into());
+        last["usage"] = self.openai_usage();
+        chunks.push(last);
+
+        let mut events: Vec<String> = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect();
+        events.push("data: [DONE]\n\n".to_string());
+        events
+    }
+
+    fn anthropic_events(&self, chunk_chars: usize) -> Vec<String> {
+        let event = |name: &str, data: Value| format!("event: {}\ndata: {}\n\n", name, data);
+        let mut events = vec![event(
+            "message_start",
+            json!({
+                "type": "message_start",
+                "message": {
+                    "id": MESSAGE_ID,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": self.model,
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": self.prompt_tokens, "output_tokens": 0 },
+                },
+            }),
+        )];
+        let (block, text, delta_type, delta_field, stop_reason) = match &self.content {
+            Content::Text(text) => (
+                json!({ "type": "text", "text": "" }),
+                text.clone(),
+                "text_delta",
+                "text",
+                "end_turn",
+            ),
+            Content::ToolCall { name, arguments } => (
+                json!({ "type": "tool_use", "id": CALL_ID, "name": name, "input": {} }),
+                arguments.to_string(),
+                "input_json_delta",
+                "partial_json",
+                "tool_use",
+            ),
+        };
+        events.push(event(
+            "content_block_start",
+            json!({ "type": "content_block_start", "index": 0, "content_block": block }),
+        ));
+        for piece in pieces(&text, chunk_chars) {
+            events.push(event(
+                "content_block_delta",
+                json!({
+                    "type": "content_block_delta",
+                    "index": 0,
+                    "delta": { "type": delta_type, delta_field: piece },
+                }),
+            ));
+        }
+        events.push(event(
+            "content_block_stop",
+            json!({ "type": "content_block_stop", "index": 0 }),
+        ));
+        events.push(event(
+            "message_delta",
+            json!({
+                "type": "message_delta",
+                "delta": { "stop_reason": stop_reason, "stop_sequence": null },
+                "usage": { "output_tokens": self.completion_tokens },
+            }),
+        ));
+        events.push(event("message_stop", json!({ "type": "message_stop" })));
+        events
+    }
+
+    fn gemini_events(&self, chunk_chars: usize) -> Vec<String> {
+        let chunks = match &self.content {
+            Content::Text(text) => {
+                let pieces = pieces(text, chunk_chars);
+                let last = pieces.len() - 1;
+                pieces
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, piece)| self.gemini_chunk(json!({ "text": piece }), i == last))
+                    .collect()
+            }
+            Content::ToolCall { name, arguments } => vec![self.gemini_chunk(
+                json!({ "functionCall": { "name": name, "args": arguments } }),
+                true,
+            )],
+        };
+        chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect()
+    }
+
+    /// A Gemini response carrying `part`; the final one has the finish
+    /// reason and usage.
+    fn gemini_chunk(&self, part: Value, last: bool) -> Value {
+        let mut candidate = json!({
+            "content": { "role": "model", "parts": [part] },
+            "index": 0,
+        });
+        let mut chunk = json!({ "modelVersion": self.model });
+        if last {
+            candidate["finishReason"] = "STOP".into();
+            chunk["usageMetadata"] = json!({
+                "promptTokenCount": self.prompt_tokens,
+                "candidatesTokenCount": self.completion_tokens,
+                "totalTokenCount": self.prompt_tokens + self.completion_tokens,
+            });
+        }
+        chunk["candidates"] = json!([candidate]);
+        chunk
+    }
+
+    fn openai_usage(&self) -> Value {
+        json!({
+            "prompt_tokens": self.prompt_tokens,
+            "completion_tokens": self.completion_tokens,
+            "total_tokens": self.prompt_tokens + self.completion_tokens,
+        })
+    }
+}
+
+/// `text` in pieces of `size` characters; one empty piece for no text.
+fn pieces(text: &str, size: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(size)
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+/// Text of the request's last turn, and whether that turn is a tool result.
+fn last_turn(dialect: Dialect, request: &Value) -> (String, bool) {
+    let empty = Vec::new();
+    match dialect {
+        Dialect::OpenAI => {
+            let messages = request["messages"].as_array().unwrap_or(&empty);
+            if let Some(last) = messages.last().filter(|m| m["role"] == "tool") {
+                return (text_of(&last["content"]), true);
+            }
+            let prompt = messages
+                .iter()
+                .rev()
+                .find(|m| m["role"] == "user")
+                .map(|m| text_of(&m["content"]))
+                .unwrap_or_default();
+            (prompt, false)
+        }
+        Dialect::Anthropic => {
+            let messages = request["messages"].as_array().unwrap_or(&empty);
+            let results: Vec<String> = messages
+                .last()
+                .and_then(|m| m["content"].as_array())
+                .into_iter()
+                .flatten()
+                .filter(|block| block["type"] == "tool_result")
+                .map(|block| text_of(&block["content"]))
+                .collect();
+            if !results.is_empty() {
+                return (results.join("\n"), true);
+            }
+            let prompt = messages
+                .iter()
+                .rev()
+                .find(|m| m["role"] == "user")
+                .map(|m| text_of(&m["content"]))
+                .unwrap_or_default();
+            (prompt, false)
+        }
+        Dialect::Gemini => {
+            let contents = request["contents"].as_array().unwrap_or(&empty);
+            let parts = |content: &Value| content["parts"].as_array().cloned().unwrap_or_default();
+            let results: Vec<String> = contents
+                .last()
+                .map(parts)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|part| part.get("functionResponse"))
+                .map(|response| response["response"].to_string())
+                .collect();
+            if !results.is_empty() {
+                return (results.join("\n"), true);
+            }
+            let prompt = contents
+                .iter()
+                .rev()
+                .find(|c| c["role"] == "user" || c.get("role").is_none())
+                .map(|c| {
+                    parts(c)
+                        .iter()
+                        .filter_map(|part| part["text"].as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .unwrap_or_default();
+            (prompt, false)
+        }
+    }
+}
+
+/// A message content as plain text: the string itself, or its text parts.
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn first_tool(dialect: Dialect, request: &Value) -> Option<String> {
+    let tool = &request["tools"][0];
+    let name = match dialect {
+        Dialect::OpenAI => &tool["function"]["name"],
+        Dialect::Anthropic => &tool["name"],
+        Dialect::Gemini => &tool["functionDeclarations"][0]["name"],
+    };
+    name.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(response: &str) -> MockConfig {
+        MockConfig {
+            response: response.to_string(),
+            tool_arguments: r#"{"city":"Paris"}"#.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Data of every `data:` line in `events`, parsed.
+    fn data(events: &[String]) -> Vec<Value> {
+        events
+            .iter()
+            .flat_map(|event| event.lines())
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_echo_speaks_each_dialect() {
+        let openai = json!({
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "system", "content": "be brief" },
+                { "role": "user", "content": [{ "type": "text", "text": "hello there" }] },
+            ],
+        });
+        let reply = Reply::new(&mock("echo"), Dialect::OpenAI, "chat/completions", &openai);
+        let body = reply.body(Dialect::OpenAI);
+        assert_eq!(body["choices"][0]["message"]["content"], "hello there");
+        assert_eq!(body["model"], "gpt-4o");
+        assert!(body["usage"]["prompt_tokens"].as_i64().unwrap() > 0);
+
+        let anthropic = json!({
+            "model": "claude",
+            "messages": [{ "role": "user", "content": "hi claude" }],
+        });
+        let path = "v1/messages";
+        let reply = Reply::new(&mock("echo"), dialect_of(path), path, &anthropic);
+        let body = reply.body(Dialect::Anthropic);
+        assert_eq!(body["content"][0]["text"], "hi claude");
+        assert_eq!(body["stop_reason"], "end_turn");
+
+        let gemini =
+            json!({ "contents": [{ "role": "user", "parts": [{ "text": "hi gemini" }] }] });
+        let path = "v1beta/models/gemini-2.5-flash:generateContent";
+        let reply = Reply::new(&mock("text"), dialect_of(path), path, &gemini);
+        assert_eq!(reply.content, Content::Text(String::new()));
+        let templated = MockConfig {
+            text: "{model} says {prompt}".to_string(),
+            ..mock("text")
+        };
+        let reply = Reply::new(&templated, Dialect::Gemini, path, &gemini);
+        let body = reply.body(Dialect::Gemini);
+        assert_eq!(
+            body["candidates"][0]["content"]["parts"][0]["text"],
+            "gemini-2.5-flash says hi gemini"
+        );
+        assert_eq!(body["candidates"][0]["finishReason"], "STOP");
+    }
+
+    #[test]
+    fn test_tool_call_then_its_result_echoed() {
+        let mut request = json!({
+            "model": "claude",
+            "tools": [{ "name": "weather", "input_schema": { "type": "object" } }],
+            "messages": [{ "role": "user", "content": "weather in Paris?" }],
+        });
+        let reply = Reply::new(
+            &mock("tool_call"),
+            Dialect::Anthropic,
+            "v1/messages",
+            &request,
+        );
+        assert_eq!(
+            reply.content,
+            Content::ToolCall {
+                name: "weather".to_string(),
+                arguments: json!({ "city": "Paris" }),
+            }
+        );
+        let events = data(&reply.events(Dialect::Anthropic, 4));
+        let partial: String = events
+            .iter()
+            .filter_map(|e| e["delta"]["partial_json"].as_str())
+            .collect();
+        assert_eq!(partial, r#"{"city":"Paris"}"#);
+        assert_eq!(events.last().unwrap()["type"], "message_stop");
+
+        request["messages"].as_array_mut().unwrap().push(json!({
+            "role": "user",
+            "content": [{ "type": "tool_result", "tool_use_id": CALL_ID, "content": "sunny" }],
+        }));
+        let reply = Reply::new(
+            &mock("tool_call"),
+            Dialect::Anthropic,
+            "v1/messages",
+            &request,
+        );
+        assert_eq!(reply.content, Content::Text("sunny".to_string()));
+    }
+
+    #[test]
+    fn test_streams_reassemble_to_the_answer() {
+        let request = json!({
+            "model": "gpt-4o",
+            "stream": true,
+            "messages": [{ "role": "user", "content": "stream me, please" }],
+        });
+        let reply = Reply::new(&mock("echo"), Dialect::OpenAI, "chat/completions", &request);
+        let events = reply.events(Dialect::OpenAI, 5);
+        assert_eq!(events.last().unwrap(), "data: [DONE]\n\n");
+        let chunks = data(&events);
+        let text: String = chunks
+            .iter()
+            .filter_map(|c| c["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(text, "stream me, please");
+        let last = chunks.last().unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+        assert!(last["usage"]["completion_tokens"].as_i64().unwrap() > 0);
+
+        let gemini = json!({ "contents": [{ "parts": [{ "text": "abcdefg" }] }] });
+        let path = "v1beta/models/g:streamGenerateContent";
+        let reply = Reply::new(&mock("echo"), Dialect::Gemini, path, &gemini);
+        let chunks = data(&reply.events(Dialect::Gemini, 3));
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[..2].iter().all(|c| c.get("usageMetadata").is_none()));
+        assert_eq!(
+            chunks[2]["candidates"][0]["content"]["parts"][0]["text"],
+            "g"
+        );
+        assert_eq!(chunks[2]["candidates"][0]["finishReason"], "STOP");
+    }
+
+    #[test]
+    fn test_scripted_failures_repeat_around_each_success() {
+        let statuses = [429, 429];
+        let answers: Vec<_> = (0..6)
+            .map(|_| next_failure("test-scripted", &statuses))
+            .collect();
+        assert_eq!(
+            answers,
+            vec![Some(429), Some(429), None, Some(429), Some(429), None]
+        );
+        assert_eq!(next_failure("test-none", &[]), None);
+        assert_eq!(encode("my mock/1"), "my%20mock%2F1");
+    }
+}
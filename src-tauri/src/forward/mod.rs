@@ -18,33 +18,106 @@
 //! ### Unified Endpoints (auto-route based on model)
 //! - `POST /v1/chat/completions` - OpenAI-compatible, routes to appropriate provider
 //! - `POST /v1/responses` - OpenAI Responses API, routes to OpenAI provider
+//! - `POST /v1/messages` - Anthropic Messages API, for clients that only
+//!   take a base URL
 //! - `GET /v1/models` - List available models
+//! - `POST /v1/token-count` - Local prompt token estimate in any of the three
+//!   formats, see [`tokens`]
 //!
 //! ### Provider-Specific Endpoints
 //! - `POST /openai/v1/chat/completions` - OpenAI API
 //! - `POST /openai/v1/responses` - OpenAI Responses API
 //! - `POST /anthropic/v1/messages` - Anthropic Messages API
+//! - `POST /anthropic/v1/messages/count_tokens` - Anthropic token count
 //! - `POST /gemini/v1beta/*` - Gemini API
+//! - `POST /gemini/v1beta/openai/chat/completions` - OpenAI chat completions
+//!   for Gemini's OpenAI compatibility mode, with `GET .../openai/models`
+//!
+//! Every request through these endpoints gets a request id (the caller's
+//! `x-request-id` or a new ULID, see [`correlate`]). It is returned as
+//! `x-relay-request-id` and recorded on log lines, usage rows and captures.
 //!
 //! ## Components
 //!
+//! - `activity`: Live feed of request lifecycle transitions
+//! - `budget`: Scoped spend budgets and their enforcement
+//! - `cache`: Optional cache of deterministic non-streaming answers
+//! - `capture`: Optional request/response capture for debugging
+//! - `dns`: Cached and pre-resolved upstream host lookups
+//! - `experiments`: A/B splits of a model id between two models
+//! - `fixtures`: Recorded upstream exchanges, replayed as conversion tests
+//! - `keys`: Upstream API key pools and rotation
 //! - `middleware`: Request parsing, authentication, and context building
+//! - `mirror`: Shadow traffic to a second upstream for comparison
+//! - `mock`: Canned upstream answers for offline development and tests
 //! - `handlers`: Provider-specific request/response handling
+//! - `images`: Remote images inlined for upstreams that need base64
+//! - `inflight`: Registry of streams still being relayed
+//! - `outcomes`: Recent success and failure of each upstream
+//! - `queue`: Concurrency limits of single models and upstreams
+//! - `ratelimits`: Rate limits the upstreams report, per key
+//! - `redact`: Replacements applied to model output
+//! - `replay`: Captured requests sent again, for comparing answers
+//! - `scrub`: Personal data and secrets removed from outbound prompts
+//! - `sse_body`: Event streams sent in answer to non-streaming requests
+//! - `timing`: End-to-end deadline and slow-request logging
+//! - `tokens`: Local token counts, upstreams not involved
+//! - `tool_loop`: Tools the relay runs itself (MCP servers, web fetch)
+//! - `transform`: Dry runs of request conversion
+//! - `window`: Prompts kept inside a model's context window
 //! - `client`: HTTP client utilities with retry logic
 //! - `context`: Shared data structures
 //! - `error`: Error types
 
+pub mod activity;
+pub mod budget;
+pub mod cache;
+pub mod capture;
 pub mod client;
 pub mod context;
+pub mod dns;
 pub mod error;
+pub mod experiments;
+pub mod fixtures;
 pub mod handlers;
+pub mod images;
+pub mod inflight;
+pub mod keys;
 pub mod limits;
 pub mod middleware;
+pub mod mirror;
+pub mod mock;
+pub mod outcomes;
+pub mod queue;
+pub mod ratelimits;
+pub mod redact;
+pub mod replay;
 pub mod routing;
+pub mod scrub;
+pub mod sse_body;
+pub mod timing;
+pub mod tokens;
+pub mod tool_loop;
+pub mod transform;
+pub mod window;
 
-use axum::{extract::Path, http::HeaderMap, response::{IntoResponse, Response}, Json};
+use axum::{
+    body::Body,
+    extract::{Path, Request},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
 use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
 
+use crate::access_log::Served;
+use crate::coalesce::Coalesced;
+use crate::telemetry::{self, Span, SpanKind};
 use crate::{config, routing::latency};
 
 // Re-export commonly used types (allow unused for public API)
@@ -55,6 +128,143 @@ pub use error::{ForwardError, ForwardResult};
 #[allow(unused_imports)]
 pub use handlers::{get_handler, ProviderHandler};
 
+// ============================================================================
+// Request Correlation
+// ============================================================================
+
+/// Response header carrying the request id.
+pub const REQUEST_ID_HEADER: &str = "x-relay-request-id";
+
+/// Response header counting the audio parts of a request that were
+/// replaced by a text marker because the upstream takes no such audio.
+pub const AUDIO_DROPPED_HEADER: &str = "x-relay-audio-dropped";
+
+/// `response` with [`AUDIO_DROPPED_HEADER`] when any audio was dropped.
+pub fn attach_dropped_audio(mut response: Response, dropped: usize) -> Response {
+    if dropped > 0 {
+        crate::logger::warn(
+            "forward",
+            &format!("Dropped {} audio part(s) the upstream cannot take", dropped),
+        );
+        response
+            .headers_mut()
+            .insert(AUDIO_DROPPED_HEADER, HeaderValue::from(dropped));
+    }
+    response
+}
+
+/// Headers describing the upstream's own body, which the client doesn't get
+const BODY_HEADERS: &[&str] = &[
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "transfer-encoding",
+    "connection",
+];
+
+/// Whether `server.passthrough_headers` lets `name` through.
+fn passes_through(name: &str, allowed: &[String]) -> bool {
+    !BODY_HEADERS.contains(&name)
+        && allowed.iter().any(|pattern| {
+            let pattern = pattern.trim().to_ascii_lowercase();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        })
+}
+
+/// The client's answer to a non-streaming `response`: its body with the
+/// upstream's status and the headers `server.passthrough_headers` allows.
+pub fn upstream_reply(response: UpstreamResponse) -> Response {
+    let allowed = config::current().server.passthrough_headers.clone();
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+    let mut reply = (status, Json(response.body)).into_response();
+    for (name, value) in &response.headers {
+        if passes_through(name.as_str(), &allowed) {
+            reply.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    attach_dropped_audio(reply, response.audio_dropped)
+}
+
+/// Layer for the forwarding routes: settles the request id, runs the request
+/// (and later the polling of its response body) with that id attached to
+/// every log entry, and returns it in `x-relay-request-id`. It also opens
+/// the request's trace span when telemetry is enabled.
+pub async fn correlate(mut req: Request, next: Next) -> Response {
+    let request_id = middleware::request_id(req.headers());
+    let header = HeaderValue::from_str(&request_id).ok();
+    if let Some(value) = &header {
+        // `extract_request_meta` reads it back from here
+        req.headers_mut().insert("x-request-id", value.clone());
+    }
+
+    let span = Span::request(
+        "relay.request",
+        req.headers()
+            .get("traceparent")
+            .and_then(|v| v.to_str().ok()),
+    );
+    span.set("http.request.method", req.method().as_str());
+    span.set("url.path", req.uri().path());
+    span.set("relay.request_id", request_id.as_str());
+
+    let mut tracker = activity::begin(&request_id);
+    let response = span
+        .scope(crate::logger::with_request_id(
+            request_id.clone(),
+            next.run(req),
+        ))
+        .await;
+    let status = response.status();
+    span.set("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.fail(status.to_string());
+    }
+
+    let (mut parts, body) = response.into_parts();
+    if let Some(value) = header {
+        parts.headers.insert(REQUEST_ID_HEADER, value);
+    }
+    let is_stream = parts
+        .headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    let stream_span = if is_stream {
+        span.sync_scope(|| Span::start("relay.stream", SpanKind::Internal))
+    } else {
+        Span::none()
+    };
+    if !status.is_success() {
+        tracker.finish(activity::Phase::Failed, Some(status.as_u16()));
+    } else if !is_stream {
+        tracker.finish(activity::Phase::Completed, Some(status.as_u16()));
+    }
+    let mut inner = body.into_data_stream();
+    // Both spans end when the body is dropped, i.e. after the last byte. So
+    // does the tracker, as cancelled unless the stream got to its end.
+    let stream = futures_util::stream::poll_fn(move |cx| {
+        let polled = span.sync_scope(|| {
+            stream_span
+                .sync_scope(|| crate::logger::in_request(&request_id, || inner.poll_next_unpin(cx)))
+        });
+        match &polled {
+            std::task::Poll::Ready(Some(Ok(chunk))) if !chunk.is_empty() => tracker.first_token(),
+            std::task::Poll::Ready(Some(Err(_))) => {
+                tracker.finish(activity::Phase::Failed, Some(status.as_u16()))
+            }
+            std::task::Poll::Ready(None) => {
+                tracker.finish(activity::Phase::Completed, Some(status.as_u16()))
+            }
+            _ => {}
+        }
+        polled
+    });
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
 // ============================================================================
 // Unified API Endpoints (Auto-routing based on model)
 // ============================================================================
@@ -73,33 +283,75 @@ pub use handlers::{get_handler, ProviderHandler};
 /// - All OpenAI-compatible parameters
 pub async fn unified_chat_completions(
     headers: HeaderMap,
-    Json(payload): Json<Value>,
+    Json(mut payload): Json<Value>,
 ) -> impl IntoResponse {
     // Build plan using middleware
-    let plan = match middleware::build_forward_plan(&headers, &payload, None) {
+    let plan = match middleware::build_forward_plan(&headers, &mut payload, None) {
         Ok(plan) => plan,
         Err(e) => return e.into_response(),
     };
+    let (mut payload, injection) =
+        middleware::with_project_prompt(&plan.primary, payload, error::Dialect::OpenAI);
+    let truncation = match window::fit(&plan.primary, &mut payload, error::Dialect::OpenAI) {
+        Ok(truncation) => truncation,
+        Err(e) => return e.into_response(),
+    };
+    let redactor = redact::Redactor::for_request(&plan.primary.meta);
 
     let guard = match limits::check_and_acquire(middleware::extract_session_id(&headers)).await {
         Ok(guard) => guard,
         Err(e) => return e.into_response(),
     };
+    // Only the OpenAI handler answers in the chat completions format
+    let tools = match plan.primary.model.provider {
+        Provider::OpenAI => {
+            tool_loop::ToolLoop::prepare(&mut payload, error::Dialect::OpenAI).await
+        }
+        _ => None,
+    };
 
     // Get the appropriate handler based on provider
     let handler = handlers::get_handler(plan.primary.model.provider);
 
     // Handle streaming vs non-streaming
     let response = if plan.primary.is_streaming {
-        match handler.handle_stream(plan.primary, payload).await {
-            Ok(response) => response,
-            Err(e) => e.into_response(),
-        }
+        let stream_guard = inflight::register(&plan.primary);
+        let served = Served::of(&plan.primary);
+        let primary = plan.primary.clone();
+        let rounds = tools.map(|tools| (tools, plan.primary.clone(), payload.clone()));
+        let response = match queue::run(
+            queue::Slot::of(&plan.primary),
+            run_attempt(
+                capture::Target::recorded(&plan.primary, &payload),
+                0,
+                handler.handle_stream(plan.primary, payload),
+            ),
+        )
+        .await
+        {
+            Ok(response) => {
+                let response = match rounds {
+                    Some((tools, ctx, payload)) => tools.stream(ctx, payload, response),
+                    None => response,
+                };
+                inflight::track(stream_guard, redact::stream(redactor, response))
+            }
+            Err(e) => failed(&primary, e, 1, error::Dialect::OpenAI),
+        };
+        served.attach(response)
     } else {
-        handle_request_with_fallback(handler, plan, payload).await
+        handle_request_with_fallback(
+            handler,
+            plan,
+            payload,
+            redactor,
+            tools.as_ref(),
+            error::Dialect::OpenAI,
+        )
+        .await
     };
 
-    limits::attach_guard(response, guard)
+    truncation.attach(injection.attach(limits::attach_guard(response, guard)))
 }
 
 /// Unified responses endpoint (OpenAI Responses API)
@@ -109,11 +361,14 @@ pub async fn unified_chat_completions(
 /// Supports:
 /// - Streaming (stream: true)
 /// - OpenAI Responses payload format
-pub async fn unified_responses(headers: HeaderMap, Json(payload): Json<Value>) -> impl IntoResponse {
-    let plan = match middleware::build_forward_plan(&headers, &payload, Some(Provider::OpenAI)) {
+pub async fn unified_responses(headers: HeaderMap, Json(mut payload): Json<Value>) -> impl IntoResponse {
+    let plan = match middleware::build_forward_plan(&headers, &mut payload, Some(Provider::OpenAI)) {
         Ok(plan) => plan,
         Err(e) => return e.into_response(),
     };
+    let (payload, injection) =
+        middleware::with_project_prompt(&plan.primary, payload, error::Dialect::OpenAI);
+    let redactor = redact::Redactor::for_request(&plan.primary.meta);
 
     let guard = match limits::check_and_acquire(middleware::extract_session_id(&headers)).await {
         Ok(guard) => guard,
@@ -123,17 +378,35 @@ pub async fn unified_responses(headers: HeaderMap, Json(payload): Json<Value>) -
     let handler = handlers::openai::OpenAIHandler;
 
     let response = if plan.primary.is_streaming {
-        match handler.handle_responses_stream(plan.primary, payload).await {
-            Ok(response) => response,
-            Err(e) => e.into_response(),
-        }
+        let stream_guard = inflight::register(&plan.primary);
+        let served = Served::of(&plan.primary);
+        let primary = plan.primary.clone();
+        let response = match queue::run(
+            queue::Slot::of(&plan.primary),
+            run_attempt(
+                capture::Target::of(&plan.primary),
+                0,
+                handler.handle_responses_stream(plan.primary, payload),
+            ),
+        )
+        .await
+        {
+            Ok(response) => inflight::track(stream_guard, redact::stream(redactor, response)),
+            Err(e) => failed(&primary, e, 1, error::Dialect::OpenAI),
+        };
+        served.attach(response)
     } else {
-        handle_responses_with_fallback(plan, payload).await
+        handle_responses_with_fallback(plan, payload, redactor).await
     };
 
-    limits::attach_guard(response, guard)
+    injection.attach(limits::attach_guard(response, guard))
 }
 
+/// `/v1/models` answers, shared by the calls an editor makes at once
+static MODEL_LISTS: Lazy<Coalesced<Value>> = Lazy::new(Coalesced::new);
+/// Latency measurements by upstream or list of URLs
+static LATENCIES: Lazy<Coalesced<Vec<latency::LatencyStat>>> = Lazy::new(Coalesced::new);
+
 /// List available models (OpenAI-compatible)
 ///
 /// Route: GET /v1/models
@@ -145,28 +418,30 @@ pub async fn list_models(headers: HeaderMap) -> impl IntoResponse {
         return e.into_response();
     }
 
-    let cfg = config::load();
-    let models: Vec<Value> = cfg
-        .models
-        .iter()
-        .map(|m| {
+    let list = MODEL_LISTS
+        .get("models", || async {
+            let models: Vec<Value> = config::current().models.iter().map(model_object).collect();
             serde_json::json!({
-                "id": m.id,
-                "object": "model",
-                "created": 1700000000,
-                "owned_by": m.provider,
-                "permission": [],
-                "root": m.id,
-                "parent": null
+                "object": "list",
+                "data": models
             })
         })
-        .collect();
+        .await;
+    Json(list).into_response()
+}
 
-    Json(serde_json::json!({
-        "object": "list",
-        "data": models
-    }))
-    .into_response()
+/// OpenAI-style model object, extended with the configured pricing.
+fn model_object(m: &config::ModelCfg) -> Value {
+    serde_json::json!({
+        "id": m.id,
+        "object": "model",
+        "created": 1700000000,
+        "owned_by": m.provider,
+        "permission": [],
+        "root": m.id,
+        "parent": null,
+        "pricing": crate::pricing::ModelPricing::from_model(m)
+    })
 }
 
 /// Get model details (OpenAI-compatible)
@@ -180,16 +455,7 @@ pub async fn get_model(Path(model_id): Path<String>, headers: HeaderMap) -> impl
 
     let cfg = config::load();
     if let Some(m) = cfg.models.iter().find(|m| m.id == model_id) {
-        Json(serde_json::json!({
-            "id": m.id,
-            "object": "model",
-            "created": 1700000000,
-            "owned_by": m.provider,
-            "permission": [],
-            "root": m.id,
-            "parent": null
-        }))
-        .into_response()
+        Json(model_object(m)).into_response()
     } else {
         error::ForwardError::ModelNotFound(format!("Model '{}' not found", model_id))
             .into_response()
@@ -203,32 +469,133 @@ pub async fn get_model(Path(model_id): Path<String>, headers: HeaderMap) -> impl
 /// OpenAI compatible chat completions endpoint
 ///
 /// Route: POST /openai/v1/chat/completions
-pub async fn openai_chat(headers: HeaderMap, Json(payload): Json<Value>) -> impl IntoResponse {
+pub async fn openai_chat(headers: HeaderMap, Json(mut payload): Json<Value>) -> impl IntoResponse {
     // Build plan using middleware
-    let plan = match middleware::build_forward_plan(&headers, &payload, Some(Provider::OpenAI)) {
+    let plan = match middleware::build_forward_plan(&headers, &mut payload, Some(Provider::OpenAI)) {
         Ok(plan) => plan,
         Err(e) => return e.into_response(),
     };
+    openai_chat_for(&headers, plan, payload).await
+}
 
-    let guard = match limits::check_and_acquire(middleware::extract_session_id(&headers)).await {
+/// Serve an OpenAI chat completions request whose routes are resolved.
+async fn openai_chat_for(headers: &HeaderMap, plan: ForwardPlan, payload: Value) -> Response {
+    let (mut payload, injection) =
+        middleware::with_project_prompt(&plan.primary, payload, error::Dialect::OpenAI);
+    let truncation = match window::fit(&plan.primary, &mut payload, error::Dialect::OpenAI) {
+        Ok(truncation) => truncation,
+        Err(e) => return e.into_response(),
+    };
+    let redactor = redact::Redactor::for_request(&plan.primary.meta);
+
+    let guard = match limits::check_and_acquire(middleware::extract_session_id(headers)).await {
         Ok(guard) => guard,
         Err(e) => return e.into_response(),
     };
+    // Only the OpenAI handler answers in the chat completions format
+    let tools = match plan.primary.model.provider {
+        Provider::OpenAI => {
+            tool_loop::ToolLoop::prepare(&mut payload, error::Dialect::OpenAI).await
+        }
+        _ => None,
+    };
 
     // Use the handler matching the selected provider.
     let handler = handlers::get_handler(plan.primary.model.provider);
 
     // Handle streaming vs non-streaming
     let response = if plan.primary.is_streaming {
-        match handler.handle_stream(plan.primary, payload).await {
-            Ok(response) => response,
-            Err(e) => e.into_response(),
-        }
+        let stream_guard = inflight::register(&plan.primary);
+        let served = Served::of(&plan.primary);
+        let primary = plan.primary.clone();
+        let rounds = tools.map(|tools| (tools, plan.primary.clone(), payload.clone()));
+        let response = match queue::run(
+            queue::Slot::of(&plan.primary),
+            run_attempt(
+                capture::Target::recorded(&plan.primary, &payload),
+                0,
+                handler.handle_stream(plan.primary, payload),
+            ),
+        )
+        .await
+        {
+            Ok(response) => {
+                let response = match rounds {
+                    Some((tools, ctx, payload)) => tools.stream(ctx, payload, response),
+                    None => response,
+                };
+                inflight::track(stream_guard, redact::stream(redactor, response))
+            }
+            Err(e) => failed(&primary, e, 1, error::Dialect::OpenAI),
+        };
+        served.attach(response)
     } else {
-        handle_request_with_fallback(handler, plan, payload).await
+        handle_request_with_fallback(
+            handler,
+            plan,
+            payload,
+            redactor,
+            tools.as_ref(),
+            error::Dialect::OpenAI,
+        )
+        .await
     };
 
-    limits::attach_guard(response, guard)
+    truncation.attach(injection.attach(limits::attach_guard(response, guard)))
+}
+
+/// OpenAI chat completions under the OpenAI compatibility path of Gemini
+///
+/// Route: POST /gemini/v1beta/openai/chat/completions
+///
+/// The google-genai SDKs post here in OpenAI mode. The payload is OpenAI's;
+/// the model resolves to its Gemini routes when it has any, else to any of
+/// its routes, and the answer comes back as a chat completion.
+pub async fn gemini_openai_chat(headers: HeaderMap, Json(payload): Json<Value>) -> Response {
+    let mut payload = gemini_compat_payload(payload);
+    match gemini_compat_plan(&headers, &mut payload) {
+        Ok(plan) => openai_chat_for(&headers, plan, payload).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The SDKs may name the model as Gemini does, `models/<id>`.
+fn gemini_compat_payload(mut payload: Value) -> Value {
+    let model = payload
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| model.strip_prefix("models/"))
+        .map(str::to_string);
+    if let Some(model) = model {
+        payload["model"] = Value::String(model);
+    }
+    payload
+}
+
+fn gemini_compat_plan(headers: &HeaderMap, payload: &mut Value) -> ForwardResult<ForwardPlan> {
+    let original = payload.clone();
+    let plan = match middleware::build_forward_plan(headers, payload, Some(Provider::Gemini)) {
+        Err(ForwardError::ModelNotFound(_)) => {
+            *payload = original;
+            middleware::build_forward_plan(headers, payload, None)?
+        }
+        plan => plan?,
+    };
+    Ok(ForwardPlan {
+        primary: as_openai_client(plan.primary),
+        fallbacks: plan.fallbacks.into_iter().map(as_openai_client).collect(),
+    })
+}
+
+/// `ctx` for the OpenAI handler. The upstream keeps speaking the dialect of
+/// the route unless its `api_style` says otherwise.
+fn as_openai_client(mut ctx: ForwardContext) -> ForwardContext {
+    let style = ctx.upstream.api_style.as_deref();
+    if style.filter(|style| !style.trim().is_empty()).is_none() {
+        ctx.upstream.api_style = Some(ctx.model.provider.as_str().to_string());
+    }
+    ctx.model.provider = Provider::OpenAI;
+    ctx
 }
 
 /// OpenAI Responses endpoint
@@ -240,36 +607,105 @@ pub async fn openai_responses(headers: HeaderMap, Json(payload): Json<Value>) ->
 
 /// Anthropic messages endpoint
 ///
-/// Route: POST /anthropic/v1/messages
+/// Routes: POST /anthropic/v1/messages, POST /v1/messages
+///
+/// Clients that speak Anthropic but only let the base URL be changed (Claude
+/// Code) call `/v1/messages` next to the OpenAI endpoints of the same base.
 pub async fn anthropic_messages(
     headers: HeaderMap,
-    Json(payload): Json<Value>,
+    Json(mut payload): Json<Value>,
 ) -> impl IntoResponse {
     // Build plan using middleware
-    let plan = match middleware::build_forward_plan(&headers, &payload, Some(Provider::Anthropic)) {
+    let plan = match middleware::build_forward_plan(&headers, &mut payload, Some(Provider::Anthropic)) {
         Ok(plan) => plan,
-        Err(e) => return e.into_response(),
+        Err(e) => return e.into_response_for(error::Dialect::Anthropic),
+    };
+    let (mut payload, injection) =
+        middleware::with_project_prompt(&plan.primary, payload, error::Dialect::Anthropic);
+    let truncation = match window::fit(&plan.primary, &mut payload, error::Dialect::Anthropic) {
+        Ok(truncation) => truncation,
+        Err(e) => return e.into_response_for(error::Dialect::Anthropic),
     };
+    let redactor = redact::Redactor::for_request(&plan.primary.meta);
 
     let guard = match limits::check_and_acquire(middleware::extract_session_id(&headers)).await {
         Ok(guard) => guard,
-        Err(e) => return e.into_response(),
+        Err(e) => return e.into_response_for(error::Dialect::Anthropic),
     };
+    let tools = tool_loop::ToolLoop::prepare(&mut payload, error::Dialect::Anthropic).await;
 
     // Get the appropriate handler
     let handler = handlers::get_handler(plan.primary.model.provider);
 
     // Handle streaming vs non-streaming
     let response = if plan.primary.is_streaming {
-        match handler.handle_stream(plan.primary, payload).await {
-            Ok(response) => response,
-            Err(e) => e.into_response(),
-        }
+        let stream_guard = inflight::register(&plan.primary);
+        let served = Served::of(&plan.primary);
+        let primary = plan.primary.clone();
+        let rounds = tools.map(|tools| (tools, plan.primary.clone(), payload.clone()));
+        let response = match queue::run(
+            queue::Slot::of(&plan.primary),
+            run_attempt(
+                capture::Target::recorded(&plan.primary, &payload),
+                0,
+                handler.handle_stream(plan.primary, payload),
+            ),
+        )
+        .await
+        {
+            Ok(response) => {
+                let response = match rounds {
+                    Some((tools, ctx, payload)) => tools.stream(ctx, payload, response),
+                    None => response,
+                };
+                inflight::track(stream_guard, redact::stream(redactor, response))
+            }
+            Err(e) => failed(&primary, e, 1, error::Dialect::Anthropic),
+        };
+        served.attach(response)
     } else {
-        handle_request_with_fallback(handler, plan, payload).await
+        handle_request_with_fallback(
+            handler,
+            plan,
+            payload,
+            redactor,
+            tools.as_ref(),
+            error::Dialect::Anthropic,
+        )
+        .await
     };
 
-    limits::attach_guard(response, guard)
+    truncation.attach(injection.attach(limits::attach_guard(response, guard)))
+}
+
+/// Anthropic token count endpoint, answered by the relay's local count
+/// (see [`tokens`]) without calling the upstream
+///
+/// Routes: POST /anthropic/v1/messages/count_tokens,
+/// POST /v1/messages/count_tokens
+pub async fn anthropic_count_tokens(
+    headers: HeaderMap,
+    Json(mut payload): Json<Value>,
+) -> Response {
+    // Resolving the plan checks the token and that the model exists
+    let plan = match middleware::build_forward_plan(&headers, &mut payload, Some(Provider::Anthropic)) {
+        Ok(plan) => plan,
+        Err(e) => return e.into_response_for(error::Dialect::Anthropic),
+    };
+    let input_tokens = tokens::count(
+        Provider::Anthropic,
+        &payload,
+        &crate::config::current().token_count,
+    )
+    .total_tokens;
+    crate::logger::debug(
+        "anthropic",
+        &format!(
+            "Counted {} input tokens for {}",
+            input_tokens, plan.primary.model.id
+        ),
+    );
+    Json(serde_json::json!({ "input_tokens": input_tokens })).into_response()
 }
 
 /// Gemini generate endpoint
@@ -297,18 +733,25 @@ pub async fn gemini_generate_v1(
 async fn gemini_generate_with_version(
     endpoint: String,
     headers: HeaderMap,
-    payload: Value,
+    mut payload: Value,
     api_version: &str,
 ) -> Response {
     // Build plan using Gemini-specific middleware
-    let plan = match middleware::build_gemini_plan(&headers, &payload, &endpoint, api_version) {
+    let plan = match middleware::build_gemini_plan(&headers, &mut payload, &endpoint, api_version) {
         Ok(plan) => plan,
-        Err(e) => return e.into_response(),
+        Err(e) => return e.into_response_for(error::Dialect::Gemini),
     };
+    let (mut payload, injection) =
+        middleware::with_project_prompt(&plan.primary, payload, error::Dialect::Gemini);
+    let truncation = match window::fit(&plan.primary, &mut payload, error::Dialect::Gemini) {
+        Ok(truncation) => truncation,
+        Err(e) => return e.into_response_for(error::Dialect::Gemini),
+    };
+    let redactor = redact::Redactor::for_request(&plan.primary.meta);
 
     let guard = match limits::check_and_acquire(middleware::extract_session_id(&headers)).await {
         Ok(guard) => guard,
-        Err(e) => return e.into_response(),
+        Err(e) => return e.into_response_for(error::Dialect::Gemini),
     };
 
     // Get the appropriate handler
@@ -316,15 +759,36 @@ async fn gemini_generate_with_version(
 
     // Handle streaming vs non-streaming
     let response = if plan.primary.is_streaming {
-        match handler.handle_stream(plan.primary, payload).await {
-            Ok(response) => response,
-            Err(e) => e.into_response(),
-        }
+        let stream_guard = inflight::register(&plan.primary);
+        let served = Served::of(&plan.primary);
+        let primary = plan.primary.clone();
+        let response = match queue::run(
+            queue::Slot::of(&plan.primary),
+            run_attempt(
+                capture::Target::recorded(&plan.primary, &payload),
+                0,
+                handler.handle_stream(plan.primary, payload),
+            ),
+        )
+        .await
+        {
+            Ok(response) => inflight::track(stream_guard, redact::stream(redactor, response)),
+            Err(e) => failed(&primary, e, 1, error::Dialect::Gemini),
+        };
+        served.attach(response)
     } else {
-        handle_request_with_fallback(handler, plan, payload).await
+        handle_request_with_fallback(
+            handler,
+            plan,
+            payload,
+            redactor,
+            None,
+            error::Dialect::Gemini,
+        )
+        .await
     };
 
-    limits::attach_guard(response, guard)
+    truncation.attach(injection.attach(limits::attach_guard(response, guard)))
 }
 
 // ============================================================================
@@ -333,7 +797,7 @@ async fn gemini_generate_with_version(
 
 /// List supported API styles/providers
 pub fn api_styles() -> Vec<&'static str> {
-    vec!["openai", "OpenAI-Responses", "anthropic", "gemini"]
+    vec!["openai", "OpenAI-Responses", "anthropic", "gemini", "mock"]
 }
 
 /// List API styles endpoint
@@ -350,7 +814,10 @@ pub async fn upstream_latency(Path(upstream_id): Path<String>) -> Json<Value> {
             "message": format!("Upstream '{}' not found", upstream_id)
         }));
     };
-    let stats = latency::measure_all(up.endpoints.clone()).await;
+    let key = format!("upstream:{}", upstream_id);
+    let stats = LATENCIES
+        .get(&key, || latency::measure_all(up.endpoints.clone()))
+        .await;
     Json(serde_json::json!({
         "upstream": upstream_id,
         "latency": stats
@@ -365,7 +832,8 @@ pub async fn test_latency_urls(Json(urls): Json<Vec<String>>) -> Json<Value> {
             "message": "No URLs provided"
         }));
     }
-    let stats = latency::measure_all(urls).await;
+    let key = format!("urls:{}", urls.join("\n"));
+    let stats = LATENCIES.get(&key, || latency::measure_all(urls)).await;
     Json(serde_json::json!({
         "latency": stats
     }))
@@ -374,17 +842,101 @@ pub async fn test_latency_urls(Json(urls): Json<Vec<String>>) -> Json<Value> {
 /// Get current forward token
 pub async fn get_forward_token() -> Json<Value> {
     let cfg = config::load();
+    let rotation = &cfg.token_rotation;
+    let now = chrono::Utc::now().timestamp();
     Json(serde_json::json!({
-        "token": cfg.forward_token.unwrap_or_default()
+        "token": cfg.forward_token.clone().unwrap_or_default(),
+        "version": rotation.version,
+        "scheduled_at": rotation.scheduled_at,
+        "previous_expires_at": rotation.previous_expires_at.filter(|at| *at > now)
     }))
 }
 
-/// Refresh forward token
-pub async fn refresh_forward_token() -> Json<Value> {
-    let token = config::refresh_forward_token();
-    Json(serde_json::json!({
-        "token": token
-    }))
+/// Body of `POST /api/forward/token`. With `at` the rotation is planned
+/// for that unix time instead of done now; `cancel` drops a planned one.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct RefreshTokenRequest {
+    pub at: Option<i64>,
+    pub cancel: bool,
+}
+
+/// Refresh forward token. The answer carries the new token and until when
+/// the replaced one is still accepted.
+pub async fn refresh_forward_token(request: RefreshTokenRequest) -> Response {
+    if request.cancel || request.at.is_some() {
+        let at = request.at.filter(|_| !request.cancel);
+        return match config::schedule_forward_token_rotation(at) {
+            Ok(()) => Json(serde_json::json!({ "scheduled_at": at })).into_response(),
+            Err(e) => (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": e })),
+            )
+                .into_response(),
+        };
+    }
+    Json(config::refresh_forward_token()).into_response()
+}
+
+/// Body of `POST /api/route/preview`: a request as a client would send it.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct RoutePreviewRequest {
+    pub payload: Value,
+    pub headers: std::collections::HashMap<String, String>,
+    pub provider: Option<String>,
+}
+
+/// The plan a request would get, built by `build_forward_plan` itself but
+/// not sent. Keys are shown by fingerprint only.
+pub async fn preview_route(request: RoutePreviewRequest) -> Response {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &request.headers {
+        if let (Ok(name), Ok(value)) = (
+            axum::http::HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    let provider = request.provider.as_deref().and_then(Provider::from_str);
+    let mut payload = request.payload;
+    match middleware::build_forward_plan(&headers, &mut payload, provider) {
+        Ok(plan) => Json(serde_json::json!({
+            "primary": describe_route(&plan.primary),
+            "fallbacks": plan.fallbacks.iter().map(describe_route).collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn describe_route(ctx: &ForwardContext) -> Value {
+    let auth = match &ctx.auth_mode {
+        context::AuthMode::UseRequestToken(token) => serde_json::json!({
+            "mode": "request_token",
+            "token": middleware::token_fingerprint(token),
+        }),
+        context::AuthMode::UseConfiguredKey => serde_json::json!({ "mode": "configured_key" }),
+    };
+    serde_json::json!({
+        "route_reason": ctx.route.label(),
+        "model": ctx.model.id,
+        "provider": ctx.model.provider.as_str(),
+        "upstream": ctx.upstream.id,
+        "upstream_model": ctx.model.upstream_model(),
+        "endpoints": ctx
+            .all_endpoints()
+            .iter()
+            .map(|e| crate::redact::text(e))
+            .collect::<Vec<_>>(),
+        "api_style": ctx.upstream.api_style,
+        "api_key_id": ctx.api_key_id(),
+        "api_key": ctx.get_api_key().map(|key| middleware::token_fingerprint(&key)),
+        "auth": auth,
+        "streaming": ctx.is_streaming,
+        "max_attempts": ctx.retry_max_attempts_override,
+    })
 }
 
 /// Health check for API endpoints
@@ -392,41 +944,88 @@ pub async fn api_health() -> Json<Value> {
     Json(serde_json::json!({
         "status": "ok",
         "version": env!("CARGO_PKG_VERSION"),
+        "profile": crate::profile::active(),
         "providers": api_styles()
     }))
 }
 
-fn parse_status_code(message: &str) -> Option<u16> {
-    for token in message.split(|c: char| !c.is_ascii_digit()) {
-        if token.len() == 3 {
-            if let Ok(code) = token.parse::<u16>() {
-                if (100..=599).contains(&code) {
-                    return Some(code);
-                }
-            }
-        }
-    }
-    None
+fn should_retry_error(err: &ForwardError) -> bool {
+    err.is_retryable()
 }
 
-fn should_retry_error(err: &ForwardError) -> bool {
-    match err {
-        ForwardError::Timeout(_) => true,
-        ForwardError::RequestFailed(message) => {
-            if let Some(code) = parse_status_code(message) {
-                client::should_retry(code)
-            } else {
-                true
+/// Record a request that failed for good after `attempts` attempts and
+/// answer it in `dialect`.
+fn failed(
+    ctx: &ForwardContext,
+    err: ForwardError,
+    attempts: usize,
+    dialect: error::Dialect,
+) -> Response {
+    ctx.log_failure(&err, attempts);
+    err.into_response_for(dialect)
+}
+
+/// Run one upstream attempt in its own trace span and capture scope.
+async fn run_attempt<T, F>(target: capture::Target, attempt: usize, fut: F) -> ForwardResult<T>
+where
+    T: capture::Captured,
+    F: Future<Output = ForwardResult<T>>,
+{
+    telemetry::annotate("relay.retry_count", attempt);
+    let span = Span::start("relay.upstream_attempt", SpanKind::Client);
+    span.set("relay.model", target.model.as_str());
+    span.set("relay.upstream", target.upstream_id.as_str());
+    span.set("relay.streaming", target.streaming);
+    span.set("relay.attempt", attempt);
+    let upstream_id = target.upstream_id.clone();
+    let request_id = target.request_id.clone();
+    activity::attempt(&request_id, &target.model, &upstream_id, target.streaming);
+    if crate::network::blocks(&upstream_id) {
+        // Not recorded as an upstream failure: the upstream isn't at fault
+        let err = ForwardError::Offline(format!(
+            "No network connection, upstream '{}' is unreachable",
+            upstream_id
+        ));
+        activity::attempt_failed(&request_id, &err.to_string());
+        span.fail(err.to_string());
+        return Err(err);
+    }
+    if target.streaming {
+        // Streams are sent once, without going through `client::make_request`
+        timing::record_attempt();
+    }
+    let project = target.project.clone();
+    let api_key_id = target.api_key_id.clone();
+    let result = span
+        .scope(ratelimits::scope(
+            &upstream_id,
+            api_key_id.as_deref(),
+            scrub::scope(&request_id, project.as_deref(), capture::scope(target, fut)),
+        ))
+        .await
+        .map_err(|err| err.for_upstream(&upstream_id));
+    outcomes::record(&upstream_id, result.as_ref().map(|_| ()));
+    match &result {
+        Ok(response) => span.set("http.response.status_code", response.status()),
+        Err(err) => {
+            let message = err.to_string();
+            activity::attempt_failed(&request_id, &message);
+            if let Some(status) = err.upstream_status() {
+                span.set("http.response.status_code", status);
             }
+            span.fail(message);
         }
-        _ => false,
     }
+    result
 }
 
 async fn handle_request_with_fallback(
     handler: ProviderHandler,
     plan: ForwardPlan,
     payload: Value,
+    redactor: Option<redact::Redactor>,
+    tools: Option<&tool_loop::ToolLoop>,
+    dialect: error::Dialect,
 ) -> Response {
     let retry_config = RetryConfig::from_config();
     let mut contexts = Vec::new();
@@ -434,7 +1033,8 @@ async fn handle_request_with_fallback(
     contexts.extend(plan.fallbacks);
 
     if contexts.is_empty() {
-        return ForwardError::ModelNotFound("No routes configured".to_string()).into_response();
+        return ForwardError::ModelNotFound("No routes configured".to_string())
+            .into_response_for(dialect);
     }
 
     let max_attempts = retry_config.max_attempts as usize;
@@ -443,14 +1043,64 @@ async fn handle_request_with_fallback(
     }
 
     let total_attempts = contexts.len();
+    // Answers the relay completes with its own tools are never cached
+    let cache_key = tools
+        .is_none()
+        .then(|| cache::key_for(&contexts[0], &payload))
+        .flatten();
+    if let Some(key) = &cache_key {
+        if let Some(mut body) = cache::lookup(&contexts[0], key).await {
+            let ctx = &contexts[0];
+            ctx.log_cached_usage(&handler.parse_response(&body));
+            redact::body(redactor.as_ref(), &mut body);
+            return Served::of(ctx).attach(cache::mark(Json(body).into_response(), true));
+        }
+    }
+    let payload = Arc::new(payload);
     for (attempt_idx, ctx) in contexts.into_iter().enumerate() {
-        match handler.handle_request(ctx, payload.clone()).await {
-            Ok(response) => return Json(response.body).into_response(),
+        let served = Served::of(&ctx);
+        let target = capture::Target::recorded(&ctx, &payload);
+        match queue::run(
+            queue::Slot::of(&ctx),
+            run_attempt(
+                target,
+                attempt_idx,
+                handler.handle_request(ctx.clone(), Arc::clone(&payload)),
+            ),
+        )
+        .await
+        {
+            Ok(response) => {
+                let mut response = match tools {
+                    Some(tools) => match tools.finish(&ctx, payload, response).await {
+                        Ok(response) => response,
+                        Err(err) => return served.attach(err.into_response_for(dialect)),
+                    },
+                    None => {
+                        let primary = mirror::Primary::answered(&response);
+                        mirror::spawn(handler.provider(), &ctx, &payload, primary);
+                        response
+                    }
+                };
+                if let Some(key) = &cache_key {
+                    cache::store(key, &ctx, &response.body);
+                }
+                redact::body(redactor.as_ref(), &mut response.body);
+                let mut reply = upstream_reply(response);
+                if cache_key.is_some() {
+                    reply = cache::mark(reply, false);
+                }
+                return served.attach(reply);
+            }
             Err(err) => {
                 let should_retry = should_retry_error(&err);
                 let is_last = attempt_idx + 1 >= total_attempts;
                 if !should_retry || is_last {
-                    return err.into_response();
+                    if tools.is_none() {
+                        let primary = mirror::Primary::failed(&ctx, &err);
+                        mirror::spawn(handler.provider(), &ctx, &payload, primary);
+                    }
+                    return served.attach(failed(&ctx, err, attempt_idx + 1, dialect));
                 }
                 let delay = client::calculate_retry_delay((attempt_idx + 1) as u32, &retry_config);
                 tokio::time::sleep(delay).await;
@@ -458,10 +1108,14 @@ async fn handle_request_with_fallback(
         }
     }
 
-    ForwardError::RequestFailed("No upstreams available".to_string()).into_response()
+    ForwardError::request_failed("No upstreams available").into_response_for(dialect)
 }
 
-async fn handle_responses_with_fallback(plan: ForwardPlan, payload: Value) -> Response {
+async fn handle_responses_with_fallback(
+    plan: ForwardPlan,
+    payload: Value,
+    redactor: Option<redact::Redactor>,
+) -> Response {
     let retry_config = RetryConfig::from_config();
     let mut contexts = Vec::new();
     contexts.push(plan.primary);
@@ -480,13 +1134,27 @@ async fn handle_responses_with_fallback(plan: ForwardPlan, payload: Value) -> Re
     let handler = handlers::openai::OpenAIHandler;
 
     for (attempt_idx, ctx) in contexts.into_iter().enumerate() {
-        match handler.handle_responses_request(ctx, payload.clone()).await {
-            Ok(response) => return Json(response.body).into_response(),
+        let served = Served::of(&ctx);
+        let target = capture::Target::of(&ctx);
+        match queue::run(
+            queue::Slot::of(&ctx),
+            run_attempt(
+                target,
+                attempt_idx,
+                handler.handle_responses_request(ctx.clone(), &payload),
+            ),
+        )
+        .await
+        {
+            Ok(mut response) => {
+                redact::body(redactor.as_ref(), &mut response.body);
+                return served.attach(upstream_reply(response));
+            }
             Err(err) => {
                 let should_retry = should_retry_error(&err);
                 let is_last = attempt_idx + 1 >= total_attempts;
                 if !should_retry || is_last {
-                    return err.into_response();
+                    return served.attach(failed(&ctx, err, attempt_idx + 1, error::Dialect::OpenAI));
                 }
                 let delay = client::calculate_retry_delay((attempt_idx + 1) as u32, &retry_config);
                 tokio::time::sleep(delay).await;
@@ -494,7 +1162,7 @@ async fn handle_responses_with_fallback(plan: ForwardPlan, payload: Value) -> Re
         }
     }
 
-    ForwardError::RequestFailed("No upstreams available".to_string()).into_response()
+    ForwardError::request_failed("No upstreams available").into_response()
 }
 
 // ============================================================================
@@ -512,5 +1180,228 @@ mod tests {
         assert!(styles.contains(&"OpenAI-Responses"));
         assert!(styles.contains(&"anthropic"));
         assert!(styles.contains(&"gemini"));
+        assert!(styles.contains(&"mock"));
+    }
+
+    #[test]
+    fn test_success_keeps_upstream_status_and_allowed_headers() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in [
+            ("x-request-id", "req_123"),
+            ("x-ratelimit-remaining-requests", "99"),
+            ("set-cookie", "session=1"),
+            ("content-length", "999"),
+        ] {
+            headers.insert(name, value.parse().unwrap());
+        }
+        let reply = upstream_reply(UpstreamResponse {
+            body: serde_json::json!({"id": "resp_1"}),
+            latency_ms: 12,
+            status: 201,
+            usage: TokenUsage::default(),
+            audio_dropped: 0,
+            headers,
+        });
+        assert_eq!(reply.status(), StatusCode::CREATED);
+        let headers = reply.headers();
+        assert_eq!(headers["x-request-id"], "req_123");
+        assert_eq!(headers["x-ratelimit-remaining-requests"], "99");
+        assert!(headers.get("set-cookie").is_none());
+        assert_eq!(headers["content-type"], "application/json");
+        assert_ne!(headers.get("content-length").map(|v| v.as_bytes()), Some(&b"999"[..]));
+    }
+
+    #[test]
+    fn test_gemini_compat_accepts_sdk_payloads() {
+        // As sent by the OpenAI SDK pointed at `{base}/v1beta/openai/`
+        let plain = serde_json::json!({
+            "model": "gemini-2.0-flash",
+            "messages": [
+                {"role": "system", "content": "You are a helpful assistant."},
+                {"role": "user", "content": "Explain to me how AI works"}
+            ],
+            "n": 1
+        });
+        assert_eq!(gemini_compat_payload(plain.clone()), plain);
+
+        let streamed = gemini_compat_payload(serde_json::json!({
+            "model": "models/gemini-2.0-flash",
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "stream": true,
+            "stream_options": {"include_usage": true},
+            "reasoning_effort": "low"
+        }));
+        assert_eq!(streamed["model"], "gemini-2.0-flash");
+        assert_eq!(streamed["stream"], true);
+        assert_eq!(streamed["reasoning_effort"], "low");
+    }
+
+    #[test]
+    fn test_gemini_compat_contexts_go_to_the_openai_handler() {
+        use context::{AuthMode, ModelInfo, UpstreamInfo};
+
+        let ctx = |api_style: Option<&str>| ForwardContext {
+            auth_mode: AuthMode::UseConfiguredKey,
+            model: ModelInfo {
+                id: "gemini-2.0-flash".to_string(),
+                display_name: "gemini-2.0-flash".to_string(),
+                provider: Provider::Gemini,
+                upstream_id: "google".to_string(),
+                upstream_model_id: None,
+                pricing: Default::default(),
+                capabilities: Default::default(),
+            },
+            upstream: UpstreamInfo {
+                id: "google".to_string(),
+                api_style: api_style.map(str::to_string),
+                ..Default::default()
+            },
+            gemini_api_version: None,
+            meta: Default::default(),
+            is_streaming: false,
+            retry_max_attempts_override: None,
+            route: Default::default(),
+        };
+        let native = as_openai_client(ctx(None));
+        assert_eq!(native.model.provider, Provider::OpenAI);
+        assert_eq!(native.upstream.api_style.as_deref(), Some("gemini"));
+        assert_eq!(
+            as_openai_client(ctx(Some(""))).upstream.api_style.as_deref(),
+            Some("gemini")
+        );
+        // An upstream that speaks another dialect keeps it
+        let proxied = as_openai_client(ctx(Some("openai")));
+        assert_eq!(proxied.upstream.api_style.as_deref(), Some("openai"));
+    }
+
+    fn routed_settings(priorities: [u32; 3]) -> config::Settings {
+        let upstream = |id: &str| config::Upstream {
+            id: id.to_string(),
+            endpoints: vec![format!("https://{}.example/v1", id)],
+            api_key: Some(format!("sk-{}-0123456789abcdef", id)),
+            ..Default::default()
+        };
+        let route = |id: &str, priority| config::ModelRoute {
+            provider: "openai".to_string(),
+            upstream_id: id.to_string(),
+            upstream_model_id: None,
+            priority: Some(priority),
+        };
+        config::Settings {
+            forward_token: Some("relay-plan-test-token".to_string()),
+            upstreams: vec![upstream("a"), upstream("b"), upstream("c")],
+            models: vec![config::ModelCfg {
+                id: "gpt-4o".to_string(),
+                provider: "openai".to_string(),
+                upstream_id: "a".to_string(),
+                routes: vec![
+                    route("a", priorities[0]),
+                    route("b", priorities[1]),
+                    route("c", priorities[2]),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn routed_request() -> (HeaderMap, Value) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            HeaderValue::from_static("Bearer relay-plan-test-token"),
+        );
+        let payload = serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hi"}]
+        });
+        (headers, payload)
+    }
+
+    /// (upstream, model, endpoint, route reason) each route of the plan
+    /// would report in its `x-relay-*` headers.
+    fn served_routes(plan: &ForwardPlan) -> Vec<(String, String, Option<String>, String)> {
+        std::iter::once(&plan.primary)
+            .chain(&plan.fallbacks)
+            .map(|ctx| {
+                let served = Served::of(ctx);
+                (
+                    served.upstream_id,
+                    served.model,
+                    served.endpoint,
+                    served.route_reason,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_route_headers_follow_the_plan() {
+        let settings = config::install_for_tests(routed_settings([3, 2, 1]));
+        crate::profile::use_temp_dir_for_tests("route-headers");
+        let (headers, mut payload) = routed_request();
+        let plan = middleware::build_forward_plan(&headers, &mut payload, None).unwrap();
+        let route = |upstream: &str, reason: &str| {
+            (
+                upstream.to_string(),
+                "gpt-4o".to_string(),
+                Some(format!("https://{}.example/v1", upstream)),
+                reason.to_string(),
+            )
+        };
+        assert_eq!(
+            served_routes(&plan),
+            vec![
+                route("a", "primary"),
+                route("b", "fallback-1"),
+                route("c", "fallback-2"),
+            ]
+        );
+
+        // Equal priorities: the first route was drawn from its group
+        drop(settings);
+        let _settings = config::install_for_tests(routed_settings([2, 2, 1]));
+        let (headers, mut payload) = routed_request();
+        let plan = middleware::build_forward_plan(&headers, &mut payload, None).unwrap();
+        let served = served_routes(&plan);
+        let drawn = served[0].0.clone();
+        assert!(drawn == "a" || drawn == "b", "{}", drawn);
+        assert_eq!(served[0].3, "group-member");
+        assert_eq!(served[1].0, if drawn == "a" { "b" } else { "a" });
+        assert_eq!(served[1].3, "fallback-1");
+        assert_eq!(served[2], route("c", "fallback-2"));
+    }
+
+    #[tokio::test]
+    async fn test_route_preview_matches_the_plan() {
+        let _settings = config::install_for_tests(routed_settings([3, 2, 1]));
+        crate::profile::use_temp_dir_for_tests("route-preview");
+        let (headers, mut payload) = routed_request();
+        let preview = preview_route(RoutePreviewRequest {
+            payload: payload.clone(),
+            headers: [(
+                "authorization".to_string(),
+                "Bearer relay-plan-test-token".to_string(),
+            )]
+            .into(),
+            provider: None,
+        })
+        .await;
+        assert_eq!(preview.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(preview.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let preview: Value = serde_json::from_slice(&body).unwrap();
+
+        let plan = middleware::build_forward_plan(&headers, &mut payload, None).unwrap();
+        assert_eq!(preview["primary"], describe_route(&plan.primary));
+        assert_eq!(
+            preview["fallbacks"],
+            Value::Array(plan.fallbacks.iter().map(describe_route).collect())
+        );
+        assert_eq!(preview["primary"]["upstream"], "a");
+        assert_eq!(preview["primary"]["auth"]["mode"], "configured_key");
+        // Keys are only shown by fingerprint
+        assert!(!body.windows(8).any(|w| w == b"01234567"));
     }
 }
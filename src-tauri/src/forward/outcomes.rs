@@ -0,0 +1,109 @@
+//! Recent outcome of each upstream, for the detailed health report.
+//!
+//! Every upstream attempt is recorded here. Errors caused by the request
+//! itself (a 400 for a malformed body, a 404 for an unknown model) say
+//! nothing about the upstream and are not counted; timeouts, connection
+//! errors, 5xx and auth or rate-limit rejections are.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use super::error::ForwardError;
+
+/// Consecutive failures after which an upstream is reported as failing.
+pub const FAILING_AFTER: u32 = 3;
+
+/// What is known about one upstream since the server started.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Outcome {
+    pub consecutive_failures: u32,
+    /// Unix seconds of the last successful attempt
+    pub last_success_at: Option<i64>,
+    pub last_failure_at: Option<i64>,
+    pub last_error: Option<String>,
+    /// "timeout", "auth", "rate_limited", "server_error" or "connection"
+    pub last_error_class: Option<&'static str>,
+}
+
+static OUTCOMES: Lazy<Mutex<HashMap<String, Outcome>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Record the result of one attempt against `upstream_id`.
+pub fn record(upstream_id: &str, result: Result<(), &ForwardError>) {
+    if let Err(err) = result {
+        if !counts_against_upstream(err) {
+            return;
+        }
+    }
+    let now = chrono::Utc::now().timestamp();
+    let mut outcomes = OUTCOMES.lock().unwrap_or_else(|e| e.into_inner());
+    let outcome = outcomes.entry(upstream_id.to_string()).or_default();
+    let was_failing = outcome.consecutive_failures >= FAILING_AFTER;
+    match result {
+        Ok(()) => {
+            outcome.consecutive_failures = 0;
+            outcome.last_success_at = Some(now);
+        }
+        Err(err) => {
+            outcome.consecutive_failures += 1;
+            outcome.last_failure_at = Some(now);
+            outcome.last_error = Some(crate::redact::text(&err.to_string()));
+            outcome.last_error_class = Some(err.class());
+        }
+    }
+    if was_failing != (outcome.consecutive_failures >= FAILING_AFTER) {
+        crate::relay_state::poke();
+    }
+}
+
+/// Outcome of `upstream_id`, empty if it has not been used yet.
+pub fn get(upstream_id: &str) -> Outcome {
+    OUTCOMES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(upstream_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn counts_against_upstream(err: &ForwardError) -> bool {
+    match err {
+        ForwardError::Timeout(_) => true,
+        ForwardError::RequestFailed(err) => match err.status {
+            Some(status) if (400..500).contains(&status) => {
+                matches!(status, 401 | 403 | 408 | 429)
+            }
+            _ => true,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caller_errors_are_not_counted() {
+        let id = "outcomes-test";
+        let failed = ForwardError::upstream(503, "");
+        record(id, Err(&failed));
+        record(id, Err(&failed));
+        record(id, Err(&ForwardError::upstream(400, "")));
+        let outcome = get(id);
+        assert_eq!(outcome.consecutive_failures, 2);
+        assert!(outcome.last_success_at.is_none());
+
+        record(id, Ok(()));
+        let outcome = get(id);
+        assert_eq!(outcome.consecutive_failures, 0);
+        assert!(outcome.last_success_at.is_some());
+        assert!(outcome.last_error.unwrap().contains("503"));
+        assert_eq!(outcome.last_error_class, Some("server_error"));
+
+        // Digits in a message are not a status
+        let refused = ForwardError::request_failed("Connection refused by 10.0.0.200");
+        assert_eq!(refused.class(), "connection");
+    }
+}
@@ -0,0 +1,363 @@
+//! Concurrency limits of single models and upstreams.
+//!
+//! `max_concurrency` on a model or an upstream caps how many of its
+//! requests run at once. Further requests wait in arrival order (tokio's
+//! semaphore is fair) for up to `max_queue_wait_ms`, then fail with 429. A
+//! request under both limits takes the model's turn first, then the
+//! upstream's.
+//!
+//! A turn is held by a [`Permit`]. Streams carry it in their body, so the
+//! turn lasts until the last byte is sent or the client goes away; a panic
+//! drops it like any other value. Rounds the tool loop adds to a request
+//! don't queue again: the request has already waited its turn.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::response::Response;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use super::context::{ForwardContext, UpstreamResponse};
+use super::error::{ForwardError, ForwardResult};
+use crate::{config, logger};
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+
+struct Lane {
+    limit: u32,
+    semaphore: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+/// Lanes by `model:<id>` or `upstream:<id>`, ids lowercased.
+static LANES: Lazy<Mutex<HashMap<String, Arc<Lane>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The lane of `key` with `limit` turns. A changed limit starts a new lane;
+/// requests still running in the old one finish there.
+fn lane(key: &str, limit: u32) -> Arc<Lane> {
+    let mut lanes = LANES.lock().unwrap_or_else(|e| e.into_inner());
+    match lanes.get(key) {
+        Some(lane) if lane.limit == limit => Arc::clone(lane),
+        _ => {
+            let lane = Arc::new(Lane {
+                limit,
+                semaphore: Arc::new(Semaphore::new(limit as usize)),
+                waiting: AtomicUsize::new(0),
+            });
+            lanes.insert(key.to_string(), Arc::clone(&lane));
+            lane
+        }
+    }
+}
+
+/// Counts a request as queued while it is alive, including when the
+/// request is cancelled while waiting.
+struct Waiting<'a>(&'a Lane);
+
+impl<'a> Waiting<'a> {
+    fn enter(lane: &'a Lane) -> Self {
+        lane.waiting.fetch_add(1, Ordering::Relaxed);
+        Self(lane)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The turns a request holds; dropping it lets the next request in.
+#[derive(Default)]
+pub struct Permit(Vec<OwnedSemaphorePermit>);
+
+/// What a request queues for, taken before its context moves into a handler.
+pub struct Slot {
+    model: String,
+    upstream_id: String,
+}
+
+impl Slot {
+    pub fn of(ctx: &ForwardContext) -> Self {
+        Self {
+            model: ctx.model.id.clone(),
+            upstream_id: ctx.upstream.id.clone(),
+        }
+    }
+
+    /// Limits that apply, as (lane key, kind, limit, longest wait).
+    fn limits(&self, cfg: &config::Settings) -> Vec<(String, &'static str, u32, Duration)> {
+        let wait = |ms: Option<u64>| ms.map(Duration::from_millis).unwrap_or(DEFAULT_WAIT);
+        let model = cfg
+            .models
+            .iter()
+            .find(|m| m.id.eq_ignore_ascii_case(&self.model))
+            .and_then(|m| Some((m.max_concurrency?, wait(m.max_queue_wait_ms))))
+            .map(|(limit, wait)| (self.model.to_lowercase(), "model", limit, wait));
+        let upstream = cfg
+            .upstreams
+            .iter()
+            .find(|u| u.id.eq_ignore_ascii_case(&self.upstream_id))
+            .and_then(|u| Some((u.max_concurrency?, wait(u.max_queue_wait_ms))))
+            .map(|(limit, wait)| (self.upstream_id.to_lowercase(), "upstream", limit, wait));
+        model
+            .into_iter()
+            .chain(upstream)
+            .filter(|(_, _, limit, _)| *limit > 0)
+            .map(|(id, kind, limit, wait)| (format!("{}:{}", kind, id), kind, limit, wait))
+            .collect()
+    }
+
+    /// Wait for a turn in every lane the request is limited by.
+    pub async fn acquire(&self) -> ForwardResult<Permit> {
+        self.acquire_with(&config::current()).await
+    }
+
+    async fn acquire_with(&self, cfg: &config::Settings) -> ForwardResult<Permit> {
+        let limits = self.limits(cfg);
+        let mut permit = Permit::default();
+        let started = Instant::now();
+        for (key, kind, limit, wait) in limits {
+            let lane = lane(&key, limit);
+            if let Ok(turn) = Arc::clone(&lane.semaphore).try_acquire_owned() {
+                permit.0.push(turn);
+                continue;
+            }
+            let _waiting = Waiting::enter(&lane);
+            let id = if kind == "model" {
+                &self.model
+            } else {
+                &self.upstream_id
+            };
+            logger::debug(
+                "queue",
+                &format!(
+                    "{} '{}' is at {} requests, {} waiting",
+                    kind,
+                    id,
+                    limit,
+                    lane.waiting.load(Ordering::Relaxed)
+                ),
+            );
+            let turn = tokio::time::timeout_at(
+                started + wait,
+                Arc::clone(&lane.semaphore).acquire_owned(),
+            )
+            .await;
+            match turn {
+                Ok(Ok(turn)) => permit.0.push(turn),
+                _ => {
+                    return Err(ForwardError::RateLimited(format!(
+                        "{} '{}' is busy: {} requests running, none finished within {} ms",
+                        kind,
+                        id,
+                        limit,
+                        wait.as_millis()
+                    )))
+                }
+            }
+        }
+        Ok(permit)
+    }
+}
+
+/// What a queued attempt returns, and how long it keeps its turn.
+pub trait Queued: Sized {
+    fn hold(self, permit: Permit) -> Self;
+}
+
+/// Streams keep their turn until the body is dropped.
+impl Queued for Response {
+    fn hold(self, permit: Permit) -> Self {
+        if permit.0.is_empty() {
+            return self;
+        }
+        let (parts, body) = self.into_parts();
+        let stream = body.into_data_stream().map(move |chunk| {
+            let _ = &permit;
+            chunk
+        });
+        Response::from_parts(parts, Body::from_stream(stream))
+    }
+}
+
+/// Buffered answers are complete once returned.
+impl Queued for UpstreamResponse {
+    fn hold(self, _permit: Permit) -> Self {
+        self
+    }
+}
+
+/// Run `fut` once `slot` has its turn.
+pub async fn run<T, F>(slot: Slot, fut: F) -> ForwardResult<T>
+where
+    T: Queued,
+    F: Future<Output = ForwardResult<T>>,
+{
+    let permit = slot.acquire().await?;
+    fut.await.map(|response| response.hold(permit))
+}
+
+/// Load of one limited model or upstream.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStats {
+    /// "model" or "upstream"
+    pub kind: String,
+    pub id: String,
+    pub max_concurrency: u32,
+    pub running: usize,
+    pub waiting: usize,
+}
+
+/// Every lane that has been used, by kind and id.
+pub fn stats() -> Vec<QueueStats> {
+    let lanes = LANES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stats: Vec<QueueStats> = lanes
+        .iter()
+        .map(|(key, lane)| {
+            let (kind, id) = key.split_once(':').unwrap_or(("", key));
+            QueueStats {
+                kind: kind.to_string(),
+                id: id.to_string(),
+                max_concurrency: lane.limit,
+                running: (lane.limit as usize).saturating_sub(lane.semaphore.available_permits()),
+                waiting: lane.waiting.load(Ordering::Relaxed),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| (&a.kind, &a.id).cmp(&(&b.kind, &b.id)));
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelCfg, Settings, Upstream};
+
+    fn settings(model_limit: Option<u32>, upstream_limit: Option<u32>, wait_ms: u64) -> Settings {
+        Settings {
+            upstreams: vec![Upstream {
+                id: "queue-ollama".to_string(),
+                max_concurrency: upstream_limit,
+                max_queue_wait_ms: Some(wait_ms),
+                ..Default::default()
+            }],
+            models: vec![ModelCfg {
+                id: "queue-llama".to_string(),
+                max_concurrency: model_limit,
+                max_queue_wait_ms: Some(wait_ms),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn slot() -> Slot {
+        Slot {
+            model: "Queue-Llama".to_string(),
+            upstream_id: "queue-ollama".to_string(),
+        }
+    }
+
+    fn lane_stats(kind: &str, id: &str) -> QueueStats {
+        stats()
+            .into_iter()
+            .find(|s| s.kind == kind && s.id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_limits_of_model_and_upstream() {
+        let limits = slot().limits(&settings(Some(2), Some(4), 100));
+        let keys: Vec<(&str, u32)> = limits.iter().map(|l| (l.0.as_str(), l.2)).collect();
+        assert_eq!(
+            keys,
+            vec![("model:queue-llama", 2), ("upstream:queue-ollama", 4)]
+        );
+        assert_eq!(limits[0].3, Duration::from_millis(100));
+        assert!(slot().limits(&settings(None, None, 100)).is_empty());
+        let mut unset_wait = settings(Some(1), None, 0);
+        unset_wait.models[0].max_queue_wait_ms = None;
+        assert_eq!(slot().limits(&unset_wait)[0].3, DEFAULT_WAIT);
+    }
+
+    #[tokio::test]
+    async fn test_requests_queue_in_order_and_time_out() {
+        let limit = 2;
+        let lane = lane("model:queue-fifo", limit);
+        let running: Vec<_> = (0..limit)
+            .map(|_| Arc::clone(&lane.semaphore).try_acquire_owned().unwrap())
+            .collect();
+
+        // Waiters are let in in the order they came
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for i in 0..3 {
+            let lane = Arc::clone(&lane);
+            let order = Arc::clone(&order);
+            waiters.push(tokio::spawn(async move {
+                let _waiting = Waiting::enter(&lane);
+                let turn = Arc::clone(&lane.semaphore).acquire_owned().await.unwrap();
+                order.lock().unwrap().push(i);
+                drop(turn);
+            }));
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(lane_stats("model", "queue-fifo").waiting, 3);
+        assert_eq!(lane_stats("model", "queue-fifo").running, 2);
+        drop(running);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(lane_stats("model", "queue-fifo").waiting, 0);
+        assert_eq!(lane_stats("model", "queue-fifo").running, 0);
+    }
+
+    #[tokio::test]
+    async fn test_busy_model_answers_429_and_stream_keeps_turn() {
+        let cfg = settings(Some(1), None, 50);
+
+        let permit = slot().acquire_with(&cfg).await.unwrap();
+        let stream = Response::new(Body::from_stream(futures_util::stream::iter(vec![Ok::<
+            _,
+            std::io::Error,
+        >(
+            "data: 1\n\n",
+        )])))
+        .hold(permit);
+        assert_eq!(lane_stats("model", "queue-llama").running, 1);
+
+        // The stream still holds the only turn, so the next request times out
+        let err = slot().acquire_with(&cfg).await.err().unwrap();
+        assert!(matches!(err, ForwardError::RateLimited(_)), "{}", err);
+        assert_eq!(lane_stats("model", "queue-llama").waiting, 0);
+
+        // Sending the body to the end gives the turn back
+        axum::body::to_bytes(stream.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(lane_stats("model", "queue-llama").running, 0);
+
+        // So does a request that panics while holding it
+        let held = cfg.clone();
+        let panicked = tokio::spawn(async move {
+            let _permit = slot().acquire_with(&held).await.unwrap();
+            panic!("handler failed");
+        })
+        .await;
+        assert!(panicked.is_err());
+        assert_eq!(lane_stats("model", "queue-llama").running, 0);
+        let permit = slot().acquire_with(&cfg).await.unwrap();
+        assert_eq!(lane_stats("model", "queue-llama").running, 1);
+        drop(permit);
+    }
+}
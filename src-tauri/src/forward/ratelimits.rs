@@ -0,0 +1,417 @@
+//! Rate limits the upstreams report.
+//!
+//! Every upstream response is read for the rate-limit headers of OpenAI
+//! (`x-ratelimit-{limit,remaining,reset}-{requests,tokens}`), Anthropic
+//! (`anthropic-ratelimit-{requests,tokens,input-tokens,output-tokens}-*`)
+//! and the plain `x-ratelimit-*` / `ratelimit-*` headers others send, e.g.
+//! OpenRouter. The latest values are kept per upstream and API key, served
+//! at `GET /api/upstreams/:id/ratelimits`, and passed on to the client as
+//! `x-relay-upstream-ratelimit-{remaining,limit,reset}-<window>`, the reset
+//! as an RFC 3339 time. Resets, which upstreams send as durations, dates or
+//! epoch times, are all kept as Unix milliseconds.
+//!
+//! Nothing is decided on these values yet; they are what routing may later
+//! consult.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Prefix of the headers passing the limits on to the client.
+pub const HEADER_PREFIX: &str = "x-relay-upstream-ratelimit-";
+
+/// One limited window, e.g. requests per minute.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Window {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    /// When the window resets, Unix ms
+    pub reset_at: Option<i64>,
+}
+
+/// Limits of one response; a window the upstream didn't report is `None`.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct RateLimits {
+    pub requests: Option<Window>,
+    pub tokens: Option<Window>,
+    /// Anthropic's separate limits of input and output tokens
+    pub input_tokens: Option<Window>,
+    pub output_tokens: Option<Window>,
+}
+
+/// The latest limits seen for one key of an upstream.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyRateLimits {
+    pub upstream_id: String,
+    /// Key label or fingerprint, `None` for requests sent without a key
+    pub key_id: Option<String>,
+    /// Unix ms
+    pub observed_at: i64,
+    #[serde(flatten)]
+    pub limits: RateLimits,
+}
+
+fn text<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+fn number(value: &str) -> Option<u64> {
+    value
+        .parse::<u64>()
+        .ok()
+        .or_else(|| value.parse::<f64>().ok().map(|n| n.max(0.0) as u64))
+}
+
+/// A Go-style duration such as `6m0s`, `1.5s` or `20ms`, in ms.
+fn duration_ms(value: &str) -> Option<f64> {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let (amount, tail) = rest.split_at(digits);
+        let amount: f64 = amount.parse().ok()?;
+        let letters = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(letters);
+        let unit_ms = match unit {
+            "h" => 3_600_000.0,
+            "m" => 60_000.0,
+            "s" => 1_000.0,
+            "ms" => 1.0,
+            _ => return None,
+        };
+        total += amount * unit_ms;
+        rest = tail;
+    }
+    Some(total)
+}
+
+/// When a window resets, given as seconds from now, an epoch time in
+/// seconds or ms, an RFC 3339 date or a duration.
+fn reset_at(value: &str, now_ms: i64) -> Option<i64> {
+    if let Ok(n) = value.parse::<f64>() {
+        return Some(if n >= 1e12 {
+            n as i64
+        } else if n >= 1e9 {
+            (n * 1000.0) as i64
+        } else {
+            now_ms + (n * 1000.0) as i64
+        });
+    }
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(date.timestamp_millis());
+    }
+    duration_ms(value).map(|ms| now_ms + ms as i64)
+}
+
+/// The window named by three headers, if any of them was sent.
+fn window(
+    headers: &HeaderMap,
+    [limit, remaining, reset]: [&str; 3],
+    now_ms: i64,
+) -> Option<Window> {
+    let window = Window {
+        limit: text(headers, limit).and_then(number),
+        remaining: text(headers, remaining).and_then(number),
+        reset_at: text(headers, reset).and_then(|v| reset_at(v, now_ms)),
+    };
+    (window != Window::default()).then_some(window)
+}
+
+fn openai(headers: &HeaderMap, kind: &str, now_ms: i64) -> Option<Window> {
+    let names =
+        ["limit", "remaining", "reset"].map(|part| format!("x-ratelimit-{}-{}", part, kind));
+    window(
+        headers,
+        [names[0].as_str(), names[1].as_str(), names[2].as_str()],
+        now_ms,
+    )
+}
+
+fn anthropic(headers: &HeaderMap, kind: &str, now_ms: i64) -> Option<Window> {
+    let names = ["limit", "remaining", "reset"]
+        .map(|part| format!("anthropic-ratelimit-{}-{}", kind, part));
+    window(
+        headers,
+        [names[0].as_str(), names[1].as_str(), names[2].as_str()],
+        now_ms,
+    )
+}
+
+/// Limits in the headers of an upstream response, `None` when it sent
+/// none. `now_ms` turns relative resets into times.
+pub fn parse(headers: &HeaderMap, now_ms: i64) -> Option<RateLimits> {
+    let plain = || {
+        window(
+            headers,
+            [
+                "x-ratelimit-limit",
+                "x-ratelimit-remaining",
+                "x-ratelimit-reset",
+            ],
+            now_ms,
+        )
+        .or_else(|| {
+            window(
+                headers,
+                ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset"],
+                now_ms,
+            )
+        })
+    };
+    let limits = RateLimits {
+        requests: anthropic(headers, "requests", now_ms)
+            .or_else(|| openai(headers, "requests", now_ms))
+            .or_else(plain),
+        tokens: anthropic(headers, "tokens", now_ms).or_else(|| openai(headers, "tokens", now_ms)),
+        input_tokens: anthropic(headers, "input-tokens", now_ms),
+        output_tokens: anthropic(headers, "output-tokens", now_ms),
+    };
+    (limits != RateLimits::default()).then_some(limits)
+}
+
+impl RateLimits {
+    fn windows(&self) -> [(&'static str, Option<&Window>); 4] {
+        [
+            ("requests", self.requests.as_ref()),
+            ("tokens", self.tokens.as_ref()),
+            ("input-tokens", self.input_tokens.as_ref()),
+            ("output-tokens", self.output_tokens.as_ref()),
+        ]
+    }
+
+    /// Pass the limits on in `headers` of the client's response.
+    pub fn stamp(&self, headers: &mut HeaderMap) {
+        for (kind, window) in self.windows() {
+            let Some(window) = window else {
+                continue;
+            };
+            let reset = window.reset_at.and_then(|ms| {
+                chrono::DateTime::from_timestamp_millis(ms)
+                    .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            });
+            let values = [
+                ("limit", window.limit.map(|n| n.to_string())),
+                ("remaining", window.remaining.map(|n| n.to_string())),
+                ("reset", reset),
+            ];
+            for (part, value) in values {
+                let name = format!("{}{}-{}", HEADER_PREFIX, part, kind);
+                if let (Ok(name), Some(Ok(value))) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    value.map(|v| HeaderValue::from_str(&v)),
+                ) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Latest limits by lowercased upstream id and key id ("" for none).
+static LATEST: Lazy<Mutex<HashMap<(String, String), KeyRateLimits>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Attempt {
+    upstream_id: String,
+    key_id: Option<String>,
+}
+
+tokio::task_local! {
+    static ATTEMPT: Attempt;
+}
+
+/// Run one upstream attempt, so that the limits its responses report are
+/// kept for `upstream_id` and `key_id`.
+pub async fn scope<F: Future>(upstream_id: &str, key_id: Option<&str>, fut: F) -> F::Output {
+    let attempt = Attempt {
+        upstream_id: upstream_id.to_string(),
+        key_id: key_id.map(str::to_string),
+    };
+    ATTEMPT.scope(attempt, fut).await
+}
+
+fn store(upstream_id: &str, key_id: Option<&str>, limits: RateLimits, now_ms: i64) {
+    let entry = KeyRateLimits {
+        upstream_id: upstream_id.to_string(),
+        key_id: key_id.map(str::to_string),
+        observed_at: now_ms,
+        limits,
+    };
+    LATEST.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        (upstream_id.to_lowercase(), key_id.unwrap_or("").to_string()),
+        entry,
+    );
+}
+
+/// Read the limits of an upstream response. Within a [`scope`] they are
+/// kept and passed on to the client; elsewhere they are ignored.
+pub fn observe(headers: &HeaderMap) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let Some(limits) = parse(headers, now_ms) else {
+        return;
+    };
+    let _ = ATTEMPT.try_with(|attempt| {
+        store(
+            &attempt.upstream_id,
+            attempt.key_id.as_deref(),
+            limits.clone(),
+            now_ms,
+        );
+        super::timing::record_ratelimits(limits);
+    });
+}
+
+/// The latest limits of each key of `upstream_id` that reported any.
+pub fn of_upstream(upstream_id: &str) -> Vec<KeyRateLimits> {
+    let latest = LATEST.lock().unwrap_or_else(|e| e.into_inner());
+    let id = upstream_id.to_lowercase();
+    let mut keys: Vec<KeyRateLimits> = latest
+        .iter()
+        .filter(|((upstream, _), _)| *upstream == id)
+        .map(|(_, limits)| limits.clone())
+        .collect();
+    keys.sort_by(|a, b| a.key_id.cmp(&b.key_id));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_openai_headers() {
+        let limits = parse(
+            &headers(&[
+                ("x-ratelimit-limit-requests", "500"),
+                ("x-ratelimit-remaining-requests", "499"),
+                ("x-ratelimit-reset-requests", "120ms"),
+                ("x-ratelimit-limit-tokens", "30000"),
+                ("x-ratelimit-remaining-tokens", "29000"),
+                ("x-ratelimit-reset-tokens", "6m0.5s"),
+            ]),
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(
+            limits.requests,
+            Some(Window {
+                limit: Some(500),
+                remaining: Some(499),
+                reset_at: Some(NOW + 120),
+            })
+        );
+        let tokens = limits.tokens.unwrap();
+        assert_eq!(tokens.remaining, Some(29000));
+        assert_eq!(tokens.reset_at, Some(NOW + 360_500));
+        assert!(limits.input_tokens.is_none());
+    }
+
+    #[test]
+    fn test_anthropic_headers() {
+        let limits = parse(
+            &headers(&[
+                ("anthropic-ratelimit-requests-limit", "50"),
+                ("anthropic-ratelimit-requests-remaining", "49"),
+                ("anthropic-ratelimit-requests-reset", "2023-11-14T22:13:21Z"),
+                ("anthropic-ratelimit-tokens-remaining", "80000"),
+                ("anthropic-ratelimit-input-tokens-limit", "40000"),
+                ("anthropic-ratelimit-input-tokens-remaining", "39000"),
+                ("anthropic-ratelimit-output-tokens-remaining", "7900"),
+            ]),
+            NOW,
+        )
+        .unwrap();
+        let requests = limits.requests.unwrap();
+        assert_eq!((requests.limit, requests.remaining), (Some(50), Some(49)));
+        assert_eq!(requests.reset_at, Some(1_700_000_001_000));
+        assert_eq!(limits.tokens.unwrap().remaining, Some(80000));
+        assert_eq!(limits.input_tokens.unwrap().limit, Some(40000));
+        assert_eq!(limits.output_tokens.unwrap().remaining, Some(7900));
+    }
+
+    #[test]
+    fn test_plain_headers() {
+        // OpenRouter: reset in epoch ms
+        let openrouter = parse(
+            &headers(&[
+                ("x-ratelimit-limit", "20"),
+                ("x-ratelimit-remaining", "19"),
+                ("x-ratelimit-reset", "1700000060000"),
+            ]),
+            NOW,
+        )
+        .unwrap();
+        let requests = openrouter.requests.unwrap();
+        assert_eq!(requests.remaining, Some(19));
+        assert_eq!(requests.reset_at, Some(NOW + 60_000));
+        assert!(openrouter.tokens.is_none());
+
+        // IETF draft: reset in seconds from now
+        let ietf = parse(
+            &headers(&[("ratelimit-remaining", "3"), ("ratelimit-reset", "30")]),
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(ietf.requests.unwrap().reset_at, Some(NOW + 30_000));
+
+        assert!(parse(&headers(&[("content-type", "application/json")]), NOW).is_none());
+    }
+
+    #[test]
+    fn test_stamp_and_latest_per_key() {
+        let limits = RateLimits {
+            tokens: Some(Window {
+                limit: None,
+                remaining: Some(10),
+                reset_at: Some(NOW),
+            }),
+            ..Default::default()
+        };
+        let mut response = HeaderMap::new();
+        limits.stamp(&mut response);
+        assert_eq!(
+            response["x-relay-upstream-ratelimit-remaining-tokens"],
+            "10"
+        );
+        assert_eq!(
+            response["x-relay-upstream-ratelimit-reset-tokens"],
+            "2023-11-14T22:13:20Z"
+        );
+        assert!(response
+            .get("x-relay-upstream-ratelimit-limit-tokens")
+            .is_none());
+
+        store("RL-Test", Some("team-b"), limits.clone(), NOW);
+        store("rl-test", None, RateLimits::default(), NOW);
+        store("rl-test", Some("team-b"), limits, NOW + 1);
+        let keys = of_upstream("rl-test");
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].key_id.as_deref(), Some("team-b"));
+        assert_eq!(keys[1].observed_at, NOW + 1);
+    }
+}
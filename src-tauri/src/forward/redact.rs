@@ -0,0 +1,544 @@
+//! Rewriting of model output before it reaches the client.
+//!
+//! `redaction.rules` replace literal text or regex matches in what a model
+//! returns, such as internal hostnames or credentials it echoes. A rule
+//! applies to every request or only to those of one project. Non-streaming
+//! bodies are redacted in one pass over their text fields. Streams are
+//! matched on a rolling buffer: the last `max_lookback` characters of text
+//! are held back until more arrives, so a match split across chunks is
+//! still caught. Matches are added up on the request's usage row.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::body::{Body, Bytes};
+use axum::http::header;
+use axum::response::Response;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use super::client::{drain_sse_lines, parse_sse_data};
+use super::context::RequestMeta;
+use crate::config::{self, RedactionConfig, RedactionRule};
+use crate::{db, logger};
+
+/// Fields of a response body that hold model text.
+const TEXT_FIELDS: [&str; 3] = ["text", "content", "output_text"];
+
+struct Rule {
+    regex: Regex,
+    replacement: String,
+    /// `replacement` is used as is, without `$1` expansion
+    literal: bool,
+}
+
+/// The rules of one project, joined into one regex with a group per rule.
+struct Rules {
+    any: Regex,
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    fn compile(rules: &[&RedactionRule]) -> Option<Rules> {
+        let mut compiled = Vec::new();
+        let mut alternatives = Vec::new();
+        for rule in rules {
+            let pattern = if rule.regex {
+                rule.pattern.clone()
+            } else {
+                regex::escape(&rule.pattern)
+            };
+            match Regex::new(&pattern) {
+                Ok(regex) => {
+                    alternatives.push(format!("(?P<r{}>{})", compiled.len(), pattern));
+                    compiled.push(Rule {
+                        regex,
+                        replacement: rule.replacement.clone(),
+                        literal: !rule.regex,
+                    });
+                }
+                Err(e) => logger::warn(
+                    "redact",
+                    &format!("Skipping redaction rule '{}': {}", rule.pattern, e),
+                ),
+            }
+        }
+        if compiled.is_empty() {
+            return None;
+        }
+        let any = Regex::new(&alternatives.join("|")).ok()?;
+        Some(Rules {
+            any,
+            rules: compiled,
+        })
+    }
+
+    /// What the match in `caps` is replaced with.
+    fn replacement(&self, caps: &Captures) -> String {
+        let found = self
+            .rules
+            .iter()
+            .enumerate()
+            .find_map(|(i, rule)| Some((rule, caps.name(&format!("r{}", i))?)));
+        let Some((rule, m)) = found else {
+            return String::new();
+        };
+        if rule.literal || !rule.regex.is_match(m.as_str()) {
+            return rule.replacement.clone();
+        }
+        rule.regex
+            .replace_all(m.as_str(), rule.replacement.as_str())
+            .into_owned()
+    }
+
+    /// `text` up to `end` with its matches replaced, the byte offset the
+    /// text after it resumes at, and the number of matches. A match that
+    /// runs past `end` may still grow, so it is left for the next call.
+    fn scan(&self, text: &str, end: usize) -> (String, usize, usize) {
+        let mut out = String::new();
+        let (mut pos, mut cut, mut matches) = (0, end, 0);
+        for caps in self.any.captures_iter(text) {
+            let Some(m) = caps.get(0) else {
+                continue;
+            };
+            if m.start() >= cut {
+                break;
+            }
+            if m.end() > cut {
+                cut = m.start();
+                break;
+            }
+            if m.as_str().is_empty() {
+                continue;
+            }
+            out.push_str(&text[pos..m.start()]);
+            out.push_str(&self.replacement(&caps));
+            pos = m.end();
+            matches += 1;
+        }
+        let cut = cut.max(pos);
+        out.push_str(&text[pos..cut]);
+        (out, cut, matches)
+    }
+}
+
+/// Compiled rules per project (lowercased, "" for none), along with the
+/// rules they were compiled from.
+type Compiled = (Vec<RedactionRule>, HashMap<String, Option<Arc<Rules>>>);
+
+static COMPILED: Lazy<Mutex<Compiled>> = Lazy::new(|| Mutex::new((Vec::new(), HashMap::new())));
+
+fn rules_for(cfg: &RedactionConfig, project: Option<&str>) -> Option<Arc<Rules>> {
+    let active: Vec<&RedactionRule> = cfg
+        .rules
+        .iter()
+        .filter(|r| r.enabled && !r.pattern.is_empty())
+        .filter(|r| match r.project.as_deref().map(str::trim) {
+            None | Some("") => true,
+            Some(only) => project.is_some_and(|name| only.eq_ignore_ascii_case(name)),
+        })
+        .collect();
+    if active.is_empty() {
+        return None;
+    }
+    let mut compiled = COMPILED.lock().unwrap_or_else(|e| e.into_inner());
+    if compiled.0 != cfg.rules {
+        *compiled = (cfg.rules.clone(), HashMap::new());
+    }
+    compiled
+        .1
+        .entry(project.unwrap_or("").to_lowercase())
+        .or_insert_with(|| Rules::compile(&active).map(Arc::new))
+        .clone()
+}
+
+/// The redaction rules that apply to one request.
+pub struct Redactor {
+    rules: Arc<Rules>,
+    lookback: usize,
+    request_id: String,
+}
+
+impl Redactor {
+    /// `None` when no rule applies to the request.
+    pub fn for_request(meta: &RequestMeta) -> Option<Redactor> {
+        let cfg = config::current();
+        Some(Redactor {
+            rules: rules_for(&cfg.redaction, meta.project.as_deref())?,
+            lookback: cfg.redaction.max_lookback,
+            request_id: meta.request_id.clone(),
+        })
+    }
+
+    fn record(&self, matches: usize) {
+        if matches == 0 {
+            return;
+        }
+        logger::info(
+            "redact",
+            &format!(
+                "Redacted {} match(es) in the response to {}",
+                matches, self.request_id
+            ),
+        );
+        db::add_redactions(&self.request_id, matches as i64);
+    }
+}
+
+/// Redact the model text of a non-streaming response body.
+pub fn body(redactor: Option<&Redactor>, body: &mut Value) {
+    if let Some(redactor) = redactor {
+        let matches = redact_value(&redactor.rules, body);
+        redactor.record(matches);
+    }
+}
+
+/// `response` with the text of its event stream redacted as it is sent.
+pub fn stream(redactor: Option<Redactor>, response: Response) -> Response {
+    let Some(redactor) = redactor else {
+        return response;
+    };
+    let is_sse = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if !is_sse {
+        return response;
+    }
+    let state = Arc::new(Mutex::new(StreamState {
+        rules: redactor.rules.clone(),
+        lookback: redactor.lookback,
+        held: String::new(),
+        template: None,
+        buffer: Vec::new(),
+        event: Vec::new(),
+        matches: 0,
+    }));
+    let tail = state.clone();
+
+    let (parts, body) = response.into_parts();
+    let chunks = body.into_data_stream().map(move |chunk| {
+        chunk.map(|bytes| {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            Bytes::from(state.push(&bytes))
+        })
+    });
+    let end = futures_util::stream::once(async move {
+        let mut state = tail.lock().unwrap_or_else(|e| e.into_inner());
+        let rest = state.finish();
+        redactor.record(state.matches);
+        Ok(Bytes::from(rest))
+    });
+    Response::from_parts(parts, Body::from_stream(chunks.chain(end)))
+}
+
+fn redact_value(rules: &Rules, value: &mut Value) -> usize {
+    match value {
+        Value::Array(items) => items.iter_mut().map(|v| redact_value(rules, v)).sum(),
+        Value::Object(fields) => fields
+            .iter_mut()
+            .map(|(key, v)| match v {
+                Value::String(text) if TEXT_FIELDS.contains(&key.as_str()) => {
+                    let (redacted, _, matches) = rules.scan(text, text.len());
+                    if matches > 0 {
+                        *text = redacted;
+                    }
+                    matches
+                }
+                _ => redact_value(rules, v),
+            })
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// Where the text of a streamed delta is: an Anthropic `text_delta`, a
+/// Responses API `output_text.delta`, an OpenAI chunk or a Gemini part.
+fn text_pointer(event: &Value) -> Option<String> {
+    let is_text = |pointer: &str| event.pointer(pointer).is_some_and(Value::is_string);
+    match event.get("type").and_then(Value::as_str) {
+        Some("content_block_delta") => (event.pointer("/delta/type").and_then(Value::as_str)
+            == Some("text_delta"))
+        .then(|| "/delta/text".to_string()),
+        Some("response.output_text.delta") => is_text("/delta").then(|| "/delta".to_string()),
+        _ if is_text("/choices/0/delta/content") => Some("/choices/0/delta/content".to_string()),
+        _ => {
+            let parts = event.pointer("/candidates/0/content/parts")?.as_array()?;
+            let i = parts
+                .iter()
+                .position(|p| p.get("text").is_some_and(Value::is_string))?;
+            Some(format!("/candidates/0/content/parts/{}/text", i))
+        }
+    }
+}
+
+/// Whether `event` ends the output, so nothing may be held back from it.
+fn is_final(event: &Value) -> bool {
+    event
+        .pointer("/choices/0/finish_reason")
+        .is_some_and(|v| !v.is_null())
+        || event.pointer("/candidates/0/finishReason").is_some()
+}
+
+fn write_event(out: &mut String, lines: &[String], data: &Value) {
+    for line in lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("data: ");
+    out.push_str(&data.to_string());
+    out.push_str("\n\n");
+}
+
+fn write_lines(out: &mut String, lines: &[String]) {
+    for line in lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+}
+
+struct StreamState {
+    rules: Arc<Rules>,
+    lookback: usize,
+    /// Text received but not sent yet
+    held: String,
+    /// The last text event without its text: its other lines, data and
+    /// the pointer to the text. `held` is sent in a copy of it.
+    template: Option<(Vec<String>, Value, String)>,
+    /// Bytes of an incomplete line
+    buffer: Vec<u8>,
+    /// Lines of the event being read
+    event: Vec<String>,
+    matches: usize,
+}
+
+impl StreamState {
+    /// The redacted events completed by `chunk`.
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut out = String::new();
+        for line in drain_sse_lines(&mut self.buffer, chunk) {
+            if !line.is_empty() {
+                self.event.push(line);
+                continue;
+            }
+            let event = std::mem::take(&mut self.event);
+            if !event.is_empty() {
+                self.write(event, &mut out);
+            }
+        }
+        out.into_bytes()
+    }
+
+    /// Whatever is left when the stream ends, including the held text.
+    fn finish(&mut self) -> Vec<u8> {
+        let mut out = String::new();
+        let rest = std::mem::take(&mut self.buffer);
+        let mut event = std::mem::take(&mut self.event);
+        event.extend(
+            String::from_utf8_lossy(&rest)
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(str::to_string),
+        );
+        if !event.is_empty() {
+            self.write(event, &mut out);
+        }
+        self.flush(&mut out);
+        out.into_bytes()
+    }
+
+    fn write(&mut self, lines: Vec<String>, out: &mut String) {
+        let data: Vec<&str> = lines.iter().filter_map(|l| parse_sse_data(l)).collect();
+        let parsed = (!data.is_empty())
+            .then(|| serde_json::from_str::<Value>(&data.join("\n")).ok())
+            .flatten();
+        let Some(mut json) = parsed else {
+            self.flush(out);
+            write_lines(out, &lines);
+            return;
+        };
+        let others: Vec<String> = lines
+            .iter()
+            .filter(|l| parse_sse_data(l).is_none())
+            .cloned()
+            .collect();
+        let Some(pointer) = text_pointer(&json) else {
+            self.flush(out);
+            // Full texts repeated at the end, such as `output_text.done`,
+            // are redacted too but not counted again
+            match redact_value(&self.rules, &mut json) {
+                0 => write_lines(out, &lines),
+                _ => write_event(out, &others, &json),
+            }
+            return;
+        };
+        let text = json.pointer(&pointer).and_then(Value::as_str).unwrap_or("");
+        self.held.push_str(text);
+        let last = is_final(&json);
+        let sent = self.release(last);
+        if let Some(slot) = json.pointer_mut(&pointer) {
+            *slot = sent.into();
+        }
+        write_event(out, &others, &json);
+        self.template = (!last).then_some((others, json, pointer));
+    }
+
+    /// The held text that can be sent: all of it with `all`, else all but
+    /// the last `lookback` characters.
+    fn release(&mut self, all: bool) -> String {
+        let end = match self.lookback {
+            0 => self.held.len(),
+            _ if all => self.held.len(),
+            n => self
+                .held
+                .char_indices()
+                .rev()
+                .nth(n - 1)
+                .map_or(0, |(i, _)| i),
+        };
+        let (sent, consumed, matches) = self.rules.scan(&self.held, end);
+        self.matches += matches;
+        self.held.drain(..consumed);
+        sent
+    }
+
+    /// Send the held text in a copy of the last text event.
+    fn flush(&mut self, out: &mut String) {
+        if self.held.is_empty() {
+            return;
+        }
+        let text = self.release(true);
+        if let Some((lines, mut json, pointer)) = self.template.take() {
+            if let Some(slot) = json.pointer_mut(&pointer) {
+                *slot = text.into();
+            }
+            write_event(out, &lines, &json);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(pattern: &str, regex: bool, replacement: &str) -> RedactionRule {
+        RedactionRule {
+            pattern: pattern.to_string(),
+            regex,
+            replacement: replacement.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn rules() -> Arc<Rules> {
+        let rules = [
+            rule("db01.corp.internal", false, "[host]"),
+            rule(r"sk-([a-z]{2})[a-z0-9]{6}", true, "sk-$1…"),
+        ];
+        Arc::new(Rules::compile(&rules.iter().collect::<Vec<_>>()).unwrap())
+    }
+
+    fn state(lookback: usize) -> StreamState {
+        StreamState {
+            rules: rules(),
+            lookback,
+            held: String::new(),
+            template: None,
+            buffer: Vec::new(),
+            event: Vec::new(),
+            matches: 0,
+        }
+    }
+
+    /// The text of the OpenAI chunks in `sse`.
+    fn chat_text(sse: &str) -> String {
+        sse.lines()
+            .filter_map(parse_sse_data)
+            .filter_map(|d| serde_json::from_str::<Value>(d).ok())
+            .filter_map(|v| {
+                v["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bodies_are_redacted() {
+        let rules = rules();
+        let mut chat = json!({"choices": [{"message": {"role": "assistant", "content": "Use db01.corp.internal with sk-abc12345"}}]});
+        assert_eq!(redact_value(&rules, &mut chat), 2);
+        assert_eq!(
+            chat["choices"][0]["message"]["content"],
+            "Use [host] with sk-ab…"
+        );
+        let mut messages = json!({"content": [{"type": "text", "text": "db01.corp.internal"}], "model": "db01.corp.internal"});
+        assert_eq!(redact_value(&rules, &mut messages), 1);
+        assert_eq!(messages["content"][0]["text"], "[host]");
+        assert_eq!(messages["model"], "db01.corp.internal");
+    }
+
+    #[test]
+    fn test_matches_split_across_chunks_are_caught() {
+        let chunk = |text: &str| {
+            format!(
+                "data: {}\n\n",
+                json!({"choices": [{"index": 0, "delta": {"content": text}, "finish_reason": null}]})
+            )
+        };
+        let mut stream = state(32);
+        let mut out = Vec::new();
+        for (i, piece) in [
+            "Connect to db01.co",
+            "rp.inte",
+            "rnal now, key sk-x",
+            "y123456.",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let sse = chunk(piece);
+            // Split one event mid-line too
+            let (a, b) = sse.split_at(if i == 1 { 10 } else { sse.len() });
+            out.extend(stream.push(a.as_bytes()));
+            out.extend(stream.push(b.as_bytes()));
+        }
+        out.extend(stream.push(b"data: [DONE]\n\n"));
+        out.extend(stream.finish());
+        let sse = String::from_utf8(out).unwrap();
+        assert_eq!(chat_text(&sse), "Connect to [host] now, key sk-xy….");
+        assert!(sse.ends_with("data: [DONE]\n\n"));
+        assert_eq!(stream.matches, 2);
+
+        // Anthropic deltas are flushed before the block ends
+        let mut stream = state(32);
+        let delta = |text: &str| {
+            format!(
+                "event: content_block_delta\ndata: {}\n\n",
+                json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": text}})
+            )
+        };
+        let mut out = stream.push(delta("host db01.corp.").as_bytes());
+        out.extend(stream.push(delta("internal").as_bytes()));
+        out.extend(stream.push(
+            b"event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+        ));
+        let sse = String::from_utf8(out).unwrap();
+        let texts: Vec<String> = sse
+            .lines()
+            .filter_map(parse_sse_data)
+            .filter_map(|d| serde_json::from_str::<Value>(d).ok())
+            .filter_map(|v| v["delta"]["text"].as_str().map(str::to_string))
+            .collect();
+        assert_eq!(texts.concat(), "host [host]");
+        assert!(sse
+            .trim_end()
+            .ends_with(r#"data: {"type":"content_block_stop","index":0}"#));
+        assert_eq!(stream.matches, 1);
+    }
+}
@@ -0,0 +1,336 @@
+//! Replay of captured requests.
+//!
+//! `POST /api/requests/:id/replay` sends the request body of a capture (see
+//! [`capture`](super::capture)) again, exactly as it went upstream, to the
+//! same model and upstream or to the `model` and `upstream_id` the caller
+//! picks. The body is read as a request of the API it was sent to and
+//! converted like any client request of that API when the new route speaks
+//! another. Streamed requests are replayed without streaming. The replay
+//! gets its own request id and usage row, which names the request it
+//! replays (`replay_of`), and the answer holds both responses so they can be
+//! compared side by side.
+//!
+//! Only complete captures can be replayed: a body stored in `headers` mode,
+//! truncated at `capture.max_bytes`, or redacted before it was stored is not
+//! the request that was sent.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::context::{Provider, RequestMeta};
+use super::{capture, handlers, middleware, queue, tokens};
+use crate::{config, db, logger};
+
+/// Where to replay a capture; its own model and upstream by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ReplayOptions {
+    pub model: Option<String>,
+    pub upstream_id: Option<String>,
+}
+
+/// One side of a replay.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Exchange {
+    pub request_id: Option<String>,
+    pub model: String,
+    pub upstream_id: String,
+    pub streaming: bool,
+    pub status: Option<i64>,
+    pub latency_ms: Option<i64>,
+    /// Parsed as JSON when it is; `None` for streamed originals, whose
+    /// answers aren't captured
+    pub response: Option<Value>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayResult {
+    pub capture_id: i64,
+    pub original: Exchange,
+    pub replay: Exchange,
+}
+
+/// API the captured body was sent to, by the path of its URL, else by the
+/// shape of the body.
+fn format_of(url: Option<&str>, body: &Value) -> Result<Provider, String> {
+    let path = url
+        .map(|url| url.split('?').next().unwrap_or(url))
+        .unwrap_or_default();
+    if path.ends_with("/responses") {
+        return Err("Responses API requests can't be replayed".to_string());
+    }
+    if path.contains(":generateContent") || path.contains(":streamGenerateContent") {
+        Ok(Provider::Gemini)
+    } else if path.ends_with("/messages") {
+        Ok(Provider::Anthropic)
+    } else if path.ends_with("/chat/completions") {
+        Ok(Provider::OpenAI)
+    } else {
+        tokens::detect_format(body)
+            .ok_or_else(|| "Can't tell which API the captured request was sent to".to_string())
+    }
+}
+
+/// The captured body and its API, or why it can't be sent again.
+pub fn replayable(capture: &db::CapturedRequest) -> Result<(Value, Provider), String> {
+    let Some(body) = capture.request_body.as_deref() else {
+        return Err(
+            "The capture has no request body: requests are only stored with their body in \
+             capture mode 'full'"
+                .to_string(),
+        );
+    };
+    if capture.truncated {
+        return Err(
+            "The captured request body was truncated at capture.max_bytes and can't be replayed"
+                .to_string(),
+        );
+    }
+    if capture.redacted {
+        return Err(
+            "Credentials or inline data were redacted from the captured request body, so \
+             replaying it would not send the original request"
+                .to_string(),
+        );
+    }
+    let body: Value = serde_json::from_str(body)
+        .map_err(|e| format!("The captured request body is not JSON: {}", e))?;
+    let format = format_of(capture.url.as_deref(), &body)?;
+    Ok((body, format))
+}
+
+/// The configured model whose route to the capture's upstream sends the
+/// upstream model it names.
+fn original_model(cfg: &config::Settings, capture: &db::CapturedRequest) -> Option<String> {
+    let sends = |model: &config::ModelCfg| {
+        model.resolved_routes().iter().any(|route| {
+            let upstream_model = route
+                .upstream_model_id
+                .as_deref()
+                .or(model.upstream_model_id.as_deref())
+                .filter(|id| !id.trim().is_empty())
+                .unwrap_or(&model.id);
+            route.upstream_id.eq_ignore_ascii_case(&capture.upstream_id)
+                && upstream_model == capture.model
+        })
+    };
+    cfg.models
+        .iter()
+        .find(|model| sends(model))
+        .or_else(|| cfg.models.iter().find(|model| model.id == capture.model))
+        .map(|model| model.id.clone())
+}
+
+/// `body` as a non-streaming client request for `model_id`.
+fn for_replay(body: &mut Value, format: Provider, model_id: &str) {
+    let Some(obj) = body.as_object_mut() else {
+        return;
+    };
+    obj.remove("stream");
+    obj.remove("stream_options");
+    if format != Provider::Gemini {
+        obj.insert("model".to_string(), model_id.into());
+    }
+}
+
+fn refuse(status: StatusCode, error: String) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+/// Answer of `POST /api/requests/:id/replay`.
+pub async fn replay(capture_id: i64, options: ReplayOptions) -> Response {
+    let Some(capture) = db::get_captured_request(capture_id) else {
+        return refuse(
+            StatusCode::NOT_FOUND,
+            "Request capture not found".to_string(),
+        );
+    };
+    let (mut body, format) = match replayable(&capture) {
+        Ok(replayable) => replayable,
+        Err(e) => return refuse(StatusCode::UNPROCESSABLE_ENTITY, e),
+    };
+    let model = options.model.as_deref().filter(|m| !m.trim().is_empty());
+    let upstream_id = options
+        .upstream_id
+        .as_deref()
+        .filter(|u| !u.trim().is_empty());
+    let Some(model_id) = model
+        .map(str::to_string)
+        .or_else(|| original_model(&config::current(), &capture))
+    else {
+        return refuse(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "No configured model sends '{}' to upstream '{}'; pick a model to replay on",
+                capture.model, capture.upstream_id
+            ),
+        );
+    };
+    // Unless asked otherwise, the capture's own model goes to its upstream
+    let upstream_id = upstream_id.or(model.is_none().then_some(capture.upstream_id.as_str()));
+
+    let meta = RequestMeta {
+        channel: "replay".to_string(),
+        tool: "relay".to_string(),
+        client_token: "none".to_string(),
+        started_at: Some(Instant::now()),
+        request_id: middleware::request_id(&HeaderMap::new()),
+        replay_of: capture.request_id.clone(),
+        ..Default::default()
+    };
+    let ctx = match middleware::build_replay_context(&model_id, upstream_id, format, meta) {
+        Ok(ctx) => ctx,
+        Err(e) => return refuse(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    for_replay(&mut body, format, &ctx.model.id);
+    logger::info(
+        "replay",
+        &format!(
+            "Replaying capture {} ({}) on {} via {} as {}",
+            capture_id,
+            capture.request_id.as_deref().unwrap_or("no request id"),
+            ctx.model.id,
+            ctx.upstream.id,
+            ctx.meta.request_id
+        ),
+    );
+
+    let handler = handlers::get_handler(format);
+    let started = Instant::now();
+    let result = queue::run(
+        queue::Slot::of(&ctx),
+        super::run_attempt(
+            capture::Target::of(&ctx),
+            0,
+            handler.handle_request(ctx.clone(), Arc::new(body)),
+        ),
+    )
+    .await;
+    let mut replay = Exchange {
+        request_id: Some(ctx.meta.request_id.clone()),
+        model: ctx.model.id.clone(),
+        upstream_id: ctx.upstream.id.clone(),
+        ..Default::default()
+    };
+    match result {
+        Ok(response) => {
+            replay.status = Some(i64::from(response.status));
+            replay.latency_ms = Some(response.latency_ms as i64);
+            replay.response = Some(response.body);
+        }
+        Err(err) => {
+            ctx.log_failure(&err, 1);
+            replay.status = Some(i64::from(err.status_code()));
+            replay.latency_ms = Some(started.elapsed().as_millis() as i64);
+            replay.error = Some(err.to_string());
+        }
+    }
+
+    let original = Exchange {
+        request_id: capture.request_id,
+        model: capture.model,
+        upstream_id: capture.upstream_id,
+        streaming: capture.streaming,
+        status: capture.status,
+        latency_ms: capture.latency_ms,
+        response: capture
+            .response_body
+            .map(|text| serde_json::from_str(&text).unwrap_or(Value::String(text))),
+        error: capture.error,
+    };
+    Json(ReplayResult {
+        capture_id,
+        original,
+        replay,
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(url: &str, body: &str) -> db::CapturedRequest {
+        db::CapturedRequest {
+            model: "claude-sonnet-4-20250514".to_string(),
+            upstream_id: "anthropic".to_string(),
+            url: Some(url.to_string()),
+            request_body: Some(body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_replayable_captures() {
+        let body = r#"{"model":"claude-sonnet-4-20250514","stream":true,"messages":[]}"#;
+        let capture = captured("https://api.anthropic.com/v1/messages", body);
+        let (mut payload, format) = replayable(&capture).unwrap();
+        assert_eq!(format, Provider::Anthropic);
+        for_replay(&mut payload, format, "sonnet");
+        assert_eq!(payload, json!({"model": "sonnet", "messages": []}));
+
+        let gemini = captured(
+            "https://host/v1beta/models/gemini-pro:streamGenerateContent?alt=sse&key=[REDACTED]",
+            r#"{"contents":[]}"#,
+        );
+        assert_eq!(replayable(&gemini).unwrap().1, Provider::Gemini);
+
+        let refused = [
+            db::CapturedRequest {
+                request_body: None,
+                ..capture.clone()
+            },
+            db::CapturedRequest {
+                truncated: true,
+                ..capture.clone()
+            },
+            db::CapturedRequest {
+                redacted: true,
+                ..capture.clone()
+            },
+            captured("https://api.openai.com/v1/responses", r#"{"input":"hi"}"#),
+        ];
+        for capture in refused {
+            assert!(replayable(&capture).is_err(), "{:?}", capture);
+        }
+    }
+
+    #[test]
+    fn test_original_model_by_upstream_model() {
+        let cfg = config::Settings {
+            models: vec![
+                config::ModelCfg {
+                    id: "sonnet".to_string(),
+                    provider: "anthropic".to_string(),
+                    upstream_id: "Anthropic".to_string(),
+                    upstream_model_id: Some("claude-sonnet-4-20250514".to_string()),
+                    ..Default::default()
+                },
+                config::ModelCfg {
+                    id: "claude-sonnet-4-20250514".to_string(),
+                    provider: "anthropic".to_string(),
+                    upstream_id: "other".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let capture = captured("https://api.anthropic.com/v1/messages", "{}");
+        assert_eq!(original_model(&cfg, &capture).as_deref(), Some("sonnet"));
+        let elsewhere = db::CapturedRequest {
+            upstream_id: "third".to_string(),
+            ..capture
+        };
+        assert_eq!(
+            original_model(&cfg, &elsewhere).as_deref(),
+            Some("claude-sonnet-4-20250514")
+        );
+    }
+}
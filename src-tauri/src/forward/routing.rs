@@ -8,7 +8,7 @@
 
 #![allow(dead_code)]
 
-use crate::config::{ModelCfg, Settings};
+use crate::config::{ModelCapabilities, ModelCfg, Settings};
 
 /// Configuration for retry and fallback behavior
 #[derive(Debug, Clone)]
@@ -179,8 +179,20 @@ pub fn create_claude_code_temp_models(
             routes: Vec::new(),
             price_prompt_per_1k: 0.003,
             price_completion_per_1k: 0.015,
+            price_cache_write_per_1k: None,
+            price_cache_read_per_1k: None,
+            price_reasoning_per_1k: None,
+            price_tiers: Vec::new(),
+            capabilities: ModelCapabilities::default(),
+            price_source: None,
+            price_updated_at: None,
             priority: 100,
             is_temporary: true,
+            mirror: None,
+            max_concurrency: None,
+            max_queue_wait_ms: None,
+            context_guard: None,
+            safety_settings: Vec::new(),
         },
         ModelCfg {
             id: "claude-3-5-sonnet-20240620-temp".to_string(),
@@ -191,8 +203,20 @@ pub fn create_claude_code_temp_models(
             routes: Vec::new(),
             price_prompt_per_1k: 0.003,
             price_completion_per_1k: 0.015,
+            price_cache_write_per_1k: None,
+            price_cache_read_per_1k: None,
+            price_reasoning_per_1k: None,
+            price_tiers: Vec::new(),
+            capabilities: ModelCapabilities::default(),
+            price_source: None,
+            price_updated_at: None,
             priority: 100,
             is_temporary: true,
+            mirror: None,
+            max_concurrency: None,
+            max_queue_wait_ms: None,
+            context_guard: None,
+            safety_settings: Vec::new(),
         },
         ModelCfg {
             id: "claude-3-opus-20240229-temp".to_string(),
@@ -203,8 +227,20 @@ pub fn create_claude_code_temp_models(
             routes: Vec::new(),
             price_prompt_per_1k: 0.015,
             price_completion_per_1k: 0.075,
+            price_cache_write_per_1k: None,
+            price_cache_read_per_1k: None,
+            price_reasoning_per_1k: None,
+            price_tiers: Vec::new(),
+            capabilities: ModelCapabilities::default(),
+            price_source: None,
+            price_updated_at: None,
             priority: 100,
             is_temporary: true,
+            mirror: None,
+            max_concurrency: None,
+            max_queue_wait_ms: None,
+            context_guard: None,
+            safety_settings: Vec::new(),
         },
     ];
 
@@ -0,0 +1,348 @@
+//! Redaction of prompts before they leave the machine.
+//!
+//! With `prompt_redaction.enabled`, e-mail addresses, phone numbers and
+//! API-key-shaped strings (the `builtin` patterns) plus the configured
+//! `rules` are replaced with typed placeholders such as `[EMAIL]` in the
+//! text of every request sent upstream. A rule can be limited to one
+//! project. Redaction runs on the body as converted for the upstream, right
+//! before it is sent, so it covers exactly what leaves. Only text is
+//! touched: message contents, system prompts and tool call arguments, not
+//! images, ids or parameters. The matches of a request are counted on its
+//! usage row.
+//!
+//! `POST /api/prompt-redaction/preview` shows the redacted body a sample
+//! request would be sent with, see [`preview`].
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::transform::{self, Converted, PreviewQuery};
+use crate::config::{self, PromptRedactionConfig};
+use crate::logger;
+
+/// Fields whose strings are text the client wrote or a tool returned.
+const TEXT_FIELDS: [&str; 7] = [
+    "content",
+    "text",
+    "system",
+    "instructions",
+    "input",
+    "arguments",
+    "output",
+];
+
+const EMAIL: &str = r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9.\-]+\.[A-Za-z]{2,}";
+/// International and North American numbers with separators, and mainland
+/// Chinese mobile numbers
+const PHONE: &str = r"(?:\+\d{1,3}[\s.\-]?)?(?:\(\d{2,4}\)|\b\d{2,4})[\s.\-]\d{3,4}[\s.\-]\d{3,4}\b|\b1[3-9]\d{9}\b";
+const API_KEY: &str = r"\b(?:sk-[A-Za-z0-9_\-]{16,}|AIza[0-9A-Za-z_\-]{30,}|ccr_[A-Za-z0-9]{16,}|gh[pousr]_[A-Za-z0-9]{30,}|AKIA[0-9A-Z]{16})";
+
+/// Pattern of a built-in name.
+pub fn builtin(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "email" => Some(EMAIL),
+        "phone" => Some(PHONE),
+        "api_key" => Some(API_KEY),
+        _ => None,
+    }
+}
+
+struct Pattern {
+    regex: Regex,
+    placeholder: String,
+}
+
+/// The patterns that apply to the requests of one project.
+pub struct Scrubber {
+    patterns: Vec<Pattern>,
+}
+
+/// Matches of one pattern.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Found {
+    pub placeholder: String,
+    pub count: usize,
+}
+
+impl Scrubber {
+    fn compile(cfg: &PromptRedactionConfig, project: Option<&str>) -> Option<Scrubber> {
+        let builtin = cfg
+            .builtin
+            .iter()
+            .filter_map(|name| Some((name.clone(), builtin(name)?.to_string())));
+        let rules = cfg
+            .rules
+            .iter()
+            .filter(|r| r.enabled && !r.pattern.is_empty())
+            .filter(|r| match r.project.as_deref().map(str::trim) {
+                None | Some("") => true,
+                Some(only) => project.is_some_and(|name| only.eq_ignore_ascii_case(name)),
+            })
+            .map(|r| (r.name.clone(), r.pattern.clone()));
+        let patterns: Vec<Pattern> = builtin
+            .chain(rules)
+            .filter_map(|(name, pattern)| match Regex::new(&pattern) {
+                Ok(regex) => Some(Pattern {
+                    regex,
+                    placeholder: format!("[{}]", name.trim().to_uppercase().replace(' ', "_")),
+                }),
+                Err(e) => {
+                    logger::warn(
+                        "scrub",
+                        &format!("Skipping prompt redaction rule '{}': {}", name, e),
+                    );
+                    None
+                }
+            })
+            .collect();
+        (!patterns.is_empty()).then_some(Scrubber { patterns })
+    }
+
+    fn text(&self, text: &mut String, found: &mut Vec<Found>) {
+        for pattern in &self.patterns {
+            let count = pattern.regex.find_iter(text).count();
+            if count == 0 {
+                continue;
+            }
+            let replaced = pattern
+                .regex
+                .replace_all(text, regex::NoExpand(&pattern.placeholder))
+                .into_owned();
+            *text = replaced;
+            match found
+                .iter_mut()
+                .find(|f| f.placeholder == pattern.placeholder)
+            {
+                Some(f) => f.count += count,
+                None => found.push(Found {
+                    placeholder: pattern.placeholder.clone(),
+                    count,
+                }),
+            }
+        }
+    }
+
+    /// Redact the text of `value`; `text` when its strings are text.
+    fn value(&self, value: &mut Value, text: bool, found: &mut Vec<Found>) {
+        match value {
+            Value::String(s) if text => self.text(s, found),
+            Value::Array(items) => items.iter_mut().for_each(|v| self.value(v, text, found)),
+            Value::Object(fields) => fields
+                .iter_mut()
+                .for_each(|(key, v)| self.value(v, TEXT_FIELDS.contains(&key.as_str()), found)),
+            _ => {}
+        }
+    }
+
+    /// Redact the text of a request body, returning what was found.
+    pub fn body(&self, body: &mut Value) -> Vec<Found> {
+        let mut found = Vec::new();
+        self.value(body, false, &mut found);
+        found
+    }
+}
+
+/// Compiled scrubbers per project (lowercased, "" for none), along with the
+/// settings they were compiled from.
+type Compiled = (
+    PromptRedactionConfig,
+    HashMap<String, Option<Arc<Scrubber>>>,
+);
+
+static COMPILED: Lazy<Mutex<Compiled>> =
+    Lazy::new(|| Mutex::new((PromptRedactionConfig::default(), HashMap::new())));
+
+fn scrubber_for(cfg: &PromptRedactionConfig, project: Option<&str>) -> Option<Arc<Scrubber>> {
+    if !cfg.enabled {
+        return None;
+    }
+    let mut compiled = COMPILED.lock().unwrap_or_else(|e| e.into_inner());
+    if compiled.0 != *cfg {
+        *compiled = (cfg.clone(), HashMap::new());
+    }
+    compiled
+        .1
+        .entry(project.unwrap_or("").to_lowercase())
+        .or_insert_with(|| Scrubber::compile(cfg, project).map(Arc::new))
+        .clone()
+}
+
+struct Request {
+    request_id: String,
+    project: Option<String>,
+}
+
+tokio::task_local! {
+    static CURRENT: Request;
+}
+
+/// Matches of the last attempt of each request, until its usage is logged.
+static MATCHES: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Run one upstream attempt of `request_id`, so that what it sends is
+/// redacted for `project` and counted for the request.
+pub async fn scope<F: Future>(request_id: &str, project: Option<&str>, fut: F) -> F::Output {
+    let request = Request {
+        request_id: request_id.to_string(),
+        project: project.map(str::to_string),
+    };
+    CURRENT.scope(request, fut).await
+}
+
+/// `body` as it may be sent upstream. Outside a [`scope`] only the rules
+/// for every request apply and matches aren't counted.
+pub fn outbound(body: &Value) -> Cow<'_, Value> {
+    let cfg = config::current();
+    let (request_id, project) = CURRENT
+        .try_with(|r| (Some(r.request_id.clone()), r.project.clone()))
+        .unwrap_or_default();
+    let Some(scrubber) = scrubber_for(&cfg.prompt_redaction, project.as_deref()) else {
+        return Cow::Borrowed(body);
+    };
+    let mut redacted = body.clone();
+    let found = scrubber.body(&mut redacted);
+    if found.is_empty() {
+        return Cow::Borrowed(body);
+    }
+    let total: usize = found.iter().map(|f| f.count).sum();
+    let kinds: Vec<String> = found
+        .iter()
+        .map(|f| format!("{} {}", f.count, f.placeholder))
+        .collect();
+    logger::info(
+        "scrub",
+        &format!(
+            "Redacted {} match(es) from the prompt of {}: {}",
+            total,
+            request_id.as_deref().unwrap_or("an unscoped request"),
+            kinds.join(", ")
+        ),
+    );
+    if let Some(request_id) = request_id {
+        // Fallbacks resend the request; the row counts what the last sent
+        MATCHES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(request_id, total as i64);
+    }
+    Cow::Owned(redacted)
+}
+
+/// Matches redacted from the prompt of `request_id`, for its usage row.
+pub fn take(request_id: &str) -> i64 {
+    MATCHES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(request_id)
+        .unwrap_or(0)
+}
+
+/// Answer of `POST /api/prompt-redaction/preview`: the sample planned and
+/// converted like the transform preview, with the body redacted like it
+/// would be sent. Redaction is shown even while it is disabled.
+pub fn preview(headers: &HeaderMap, query: PreviewQuery, payload: Option<Value>) -> Response {
+    let Converted { target, ctx, body } = match transform::convert(headers, query, payload) {
+        Ok(converted) => converted,
+        Err(response) => return *response,
+    };
+    let cfg = PromptRedactionConfig {
+        enabled: true,
+        ..config::current().prompt_redaction.clone()
+    };
+    let mut redacted = body.clone();
+    let found = Scrubber::compile(&cfg, ctx.meta.project.as_deref())
+        .map(|scrubber| scrubber.body(&mut redacted))
+        .unwrap_or_default();
+
+    Json(json!({
+        "target": target.as_str(),
+        "model": ctx.model.id,
+        "project": ctx.meta.project,
+        "enabled": config::current().prompt_redaction.enabled,
+        "matches": found,
+        "body": redacted,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PromptRedactionRule;
+
+    fn settings() -> PromptRedactionConfig {
+        PromptRedactionConfig {
+            enabled: true,
+            rules: vec![PromptRedactionRule {
+                name: "ticket".to_string(),
+                pattern: r"TKT-\d{6}".to_string(),
+                project: Some("Billing".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_text_fields_redacted_with_placeholders() {
+        let scrubber = Scrubber::compile(&settings(), None).unwrap();
+        let mut body = json!({
+            "system": [{"type": "text", "text": "Reply to ops@example.com"}],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Call +1 415-555-0134 or 13812345678, key sk-abcdefghijklmnop1234"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "a@b.co"}}
+                ]},
+                {"role": "assistant", "content": "TKT-123456"}
+            ],
+            "metadata": {"user_id": "someone@example.com"}
+        });
+        let found = scrubber.body(&mut body);
+        assert_eq!(body["system"][0]["text"], "Reply to [EMAIL]");
+        assert_eq!(
+            body["messages"][0]["content"][0]["text"],
+            "Call [PHONE] or [PHONE], key [API_KEY]"
+        );
+        // Not text, or a rule of another project
+        assert_eq!(
+            body["messages"][0]["content"][1]["source"]["data"],
+            "a@b.co"
+        );
+        assert_eq!(body["metadata"]["user_id"], "someone@example.com");
+        assert_eq!(body["messages"][1]["content"], "TKT-123456");
+        let total: usize = found.iter().map(|f| f.count).sum();
+        assert_eq!(total, 4);
+    }
+
+    #[test]
+    fn test_project_rules() {
+        let scrubber = Scrubber::compile(&settings(), Some("billing")).unwrap();
+        let mut body = json!({"contents": [{"parts": [{"text": "see TKT-123456"}]}]});
+        let found = scrubber.body(&mut body);
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "see [TICKET]");
+        assert_eq!(
+            found,
+            vec![Found {
+                placeholder: "[TICKET]".to_string(),
+                count: 1
+            }]
+        );
+
+        let disabled = PromptRedactionConfig {
+            builtin: Vec::new(),
+            rules: Vec::new(),
+            ..settings()
+        };
+        assert!(Scrubber::compile(&disabled, None).is_none());
+    }
+}
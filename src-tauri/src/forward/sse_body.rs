@@ -0,0 +1,258 @@
+//! Non-streaming answers that arrive as an event stream anyway.
+//!
+//! Some gateways stream whatever they are asked. [`aggregate`] folds such a
+//! body back into the single response the client expected: OpenAI chat
+//! chunks are merged into a `chat.completion`, Anthropic events into a
+//! `message`, and Responses API events yield the response of their
+//! `response.completed` event. Other streams give their last JSON event.
+
+use serde_json::{json, Map, Value};
+
+use super::client::{is_sse_done, parse_sse_data};
+
+/// Whether a body with `content_type` reads as an event stream. Comment
+/// lines some gateways send ahead of the first event are skipped.
+pub fn is_event_stream(content_type: Option<&str>, body: &str) -> bool {
+    if content_type.is_some_and(|ct| ct.trim_start().starts_with("text/event-stream")) {
+        return true;
+    }
+    body.lines()
+        .map(str::trim_start)
+        .find(|line| !line.is_empty() && !line.starts_with(':'))
+        .is_some_and(|line| line.starts_with("data:") || line.starts_with("event:"))
+}
+
+/// The response an event stream adds up to, `None` if it has no events.
+pub fn aggregate(body: &str) -> Option<Value> {
+    let events: Vec<Value> = body
+        .lines()
+        .filter_map(parse_sse_data)
+        .map(str::trim)
+        .filter(|data| !data.is_empty() && !is_sse_done(data))
+        .filter_map(|data| serde_json::from_str(data).ok())
+        .collect();
+    let first = events.first()?;
+    if first.get("choices").is_some() {
+        return Some(merge_chat_chunks(&events));
+    }
+    match first.get("type").and_then(Value::as_str) {
+        Some("message_start") => Some(merge_anthropic_events(&events)),
+        Some(kind) if kind.starts_with("response.") => events
+            .iter()
+            .rev()
+            .find_map(|event| event.get("response").cloned()),
+        _ => events.last().cloned(),
+    }
+}
+
+/// Append the string `part` to the string at `field`.
+fn append(target: &mut Map<String, Value>, field: &str, part: Option<&Value>) {
+    let Some(part) = part.and_then(Value::as_str) else {
+        return;
+    };
+    match target.get_mut(field) {
+        Some(Value::String(text)) => text.push_str(part),
+        _ => {
+            target.insert(field.to_string(), Value::String(part.to_string()));
+        }
+    }
+}
+
+/// A `chat.completion` of `chat.completion.chunk`s: deltas concatenated by
+/// choice, tool call arguments by call, the last usage reported.
+fn merge_chat_chunks(chunks: &[Value]) -> Value {
+    let mut response = Map::new();
+    let mut choices: Vec<(Map<String, Value>, Value)> = Vec::new();
+    let mut usage = Value::Null;
+    for chunk in chunks {
+        for field in ["id", "created", "model", "system_fingerprint"] {
+            if let Some(value) = chunk.get(field).filter(|v| !v.is_null()) {
+                response.insert(field.to_string(), value.clone());
+            }
+        }
+        if let Some(reported) = chunk.get("usage").filter(|u| !u.is_null()) {
+            usage = reported.clone();
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let index = choice["index"].as_u64().unwrap_or(0) as usize;
+            while choices.len() <= index {
+                choices.push((Map::new(), Value::Null));
+            }
+            let (message, finish_reason) = &mut choices[index];
+            if let Some(reason) = choice.get("finish_reason").filter(|r| !r.is_null()) {
+                *finish_reason = reason.clone();
+            }
+            let delta = &choice["delta"];
+            if let Some(role) = delta.get("role").filter(|r| !r.is_null()) {
+                message.insert("role".to_string(), role.clone());
+            }
+            append(message, "content", delta.get("content"));
+            append(message, "reasoning_content", delta.get("reasoning_content"));
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                merge_tool_call(message, call);
+            }
+        }
+    }
+    let choices: Vec<Value> = choices
+        .into_iter()
+        .enumerate()
+        .map(|(index, (mut message, finish_reason))| {
+            message
+                .entry("role")
+                .or_insert_with(|| Value::String("assistant".to_string()));
+            message.entry("content").or_insert(Value::Null);
+            json!({ "index": index, "message": message, "finish_reason": finish_reason })
+        })
+        .collect();
+    response.insert("object".to_string(), "chat.completion".into());
+    response.insert("choices".to_string(), Value::Array(choices));
+    if !usage.is_null() {
+        response.insert("usage".to_string(), usage);
+    }
+    Value::Object(response)
+}
+
+fn merge_tool_call(message: &mut Map<String, Value>, delta: &Value) {
+    let Some(calls) = message
+        .entry("tool_calls")
+        .or_insert_with(|| Value::Array(Vec::new()))
+        .as_array_mut()
+    else {
+        return;
+    };
+    let index = delta["index"].as_u64().unwrap_or(calls.len() as u64) as usize;
+    while calls.len() <= index {
+        calls.push(json!({ "type": "function", "function": { "arguments": "" } }));
+    }
+    let Some(call) = calls[index].as_object_mut() else {
+        return;
+    };
+    if let Some(id) = delta.get("id").filter(|v| !v.is_null()) {
+        call.insert("id".to_string(), id.clone());
+    }
+    if let Some(function) = call.get_mut("function").and_then(Value::as_object_mut) {
+        if let Some(name) = delta["function"].get("name").filter(|v| !v.is_null()) {
+            function.insert("name".to_string(), name.clone());
+        }
+        append(function, "arguments", delta["function"].get("arguments"));
+    }
+}
+
+/// A `message` of Anthropic stream events: blocks rebuilt from their
+/// deltas, tool input parsed once complete, usage merged.
+fn merge_anthropic_events(events: &[Value]) -> Value {
+    let mut message = Map::new();
+    let mut blocks: Vec<Value> = Vec::new();
+    let mut partial_json: Vec<String> = Vec::new();
+    for event in events {
+        match event["type"].as_str().unwrap_or_default() {
+            "message_start" => {
+                if let Some(start) = event["message"].as_object() {
+                    message = start.clone();
+                }
+            }
+            "content_block_start" => {
+                let index = event["index"].as_u64().unwrap_or(blocks.len() as u64) as usize;
+                while blocks.len() <= index {
+                    blocks.push(Value::Null);
+                    partial_json.push(String::new());
+                }
+                blocks[index] = event["content_block"].clone();
+            }
+            "content_block_delta" => {
+                let index = event["index"].as_u64().unwrap_or(0) as usize;
+                let Some(block) = blocks.get_mut(index).and_then(Value::as_object_mut) else {
+                    continue;
+                };
+                let delta = &event["delta"];
+                match delta["type"].as_str().unwrap_or_default() {
+                    "text_delta" => append(block, "text", delta.get("text")),
+                    "thinking_delta" => append(block, "thinking", delta.get("thinking")),
+                    "signature_delta" => append(block, "signature", delta.get("signature")),
+                    "input_json_delta" => {
+                        partial_json[index].push_str(delta["partial_json"].as_str().unwrap_or(""))
+                    }
+                    _ => {}
+                }
+            }
+            "message_delta" => {
+                for (key, value) in event["delta"].as_object().into_iter().flatten() {
+                    message.insert(key.clone(), value.clone());
+                }
+                if let Some(usage) = event["usage"].as_object() {
+                    let merged = message
+                        .entry("usage")
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Some(merged) = merged.as_object_mut() {
+                        for (key, value) in usage {
+                            merged.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for (block, json) in blocks.iter_mut().zip(&partial_json) {
+        if !json.is_empty() {
+            block["input"] = serde_json::from_str(json).unwrap_or_else(|_| json!({}));
+        }
+    }
+    message.insert(
+        "content".to_string(),
+        Value::Array(blocks.into_iter().filter(|b| !b.is_null()).collect()),
+    );
+    Value::Object(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_chunks_add_up_to_a_completion() {
+        let body = include_str!("../../tests/fixtures/sse/openai-chat-as-sync.txt");
+        assert!(is_event_stream(None, body));
+        let response = aggregate(body).unwrap();
+        assert_eq!(response["object"], "chat.completion");
+        assert_eq!(response["id"], "chatcmpl-BCzHqZ3yQ0bXJvJ8xg5fNn2mB1Lq7");
+        let choice = &response["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["role"], "assistant");
+        assert_eq!(
+            choice["message"]["content"],
+            "Let me check the weather in Paris."
+        );
+        let call = &choice["message"]["tool_calls"][0];
+        assert_eq!(call["id"], "call_Qm3bVgU4kYwT8rX2");
+        assert_eq!(call["function"]["name"], "get_weather");
+        assert_eq!(call["function"]["arguments"], r#"{"city":"Paris"}"#);
+        assert_eq!(response["usage"]["total_tokens"], 96);
+    }
+
+    #[test]
+    fn test_anthropic_events_add_up_to_a_message() {
+        let body = [
+            r#"event: message_start"#,
+            r#"data: {"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","model":"claude-sonnet-4","content":[],"stop_reason":null,"usage":{"input_tokens":25,"output_tokens":1}}}"#,
+            r#"data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Checking"}}"#,
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" now."}}"#,
+            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_01","name":"get_weather","input":{}}}"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\":"}}"#,
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"Paris\"}"}}"#,
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use","stop_sequence":null},"usage":{"output_tokens":31}}"#,
+            r#"data: {"type":"message_stop"}"#,
+        ]
+        .join("\n\n");
+        let message = aggregate(&body).unwrap();
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(message["usage"]["input_tokens"], 25);
+        assert_eq!(message["usage"]["output_tokens"], 31);
+        assert_eq!(message["content"][0]["text"], "Checking now.");
+        assert_eq!(message["content"][1]["input"], json!({"city": "Paris"}));
+
+        assert!(!is_event_stream(Some("application/json"), "{\"id\":1}"));
+        assert_eq!(aggregate("data: [DONE]"), None);
+    }
+}
@@ -0,0 +1,329 @@
+//! End-to-end deadline and phase timing of forwarded requests.
+//!
+//! [`layer`] gives each non-streaming request `server.request_timeout_secs`
+//! to produce its response. Past that the handler is dropped, which cancels
+//! the upstream call, and the caller gets a 504 in the format of the API it
+//! called. Streaming requests are exempted once their plan is built: their
+//! headers come back quickly and the body may legitimately run for minutes.
+//!
+//! The handlers report where the time goes: building the plan
+//! ([`record_plan`]) and waiting on the upstream ([`upstream`]). Everything
+//! else, mostly converting request and response, is counted as `convert`.
+//! Requests slower than `server.slow_request_ms` get a warn line with that
+//! breakdown.
+//!
+//! Every response, streamed or not, tells the client which model and
+//! upstream served it (`x-relay-model`, `x-relay-upstream`), at which
+//! endpoint (`x-relay-endpoint`) and why the plan chose that route
+//! (`x-relay-route-reason`: `primary`, `group-member` or `fallback-n`). It
+//! also says how long the relay took to answer (`x-relay-latency-ms`, to
+//! the first byte for streams) and how many upstream requests that took
+//! (`x-relay-attempts`), retries and fallbacks included. The rate limits
+//! the upstream reported last are passed on as well, see
+//! [`ratelimits`](super::ratelimits).
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+
+use super::error::{Dialect, ForwardError};
+use super::ratelimits::RateLimits;
+use crate::access_log::Served;
+use crate::config;
+
+pub const MODEL_HEADER: &str = "x-relay-model";
+pub const UPSTREAM_HEADER: &str = "x-relay-upstream";
+pub const ENDPOINT_HEADER: &str = "x-relay-endpoint";
+pub const ROUTE_REASON_HEADER: &str = "x-relay-route-reason";
+pub const LATENCY_HEADER: &str = "x-relay-latency-ms";
+pub const ATTEMPTS_HEADER: &str = "x-relay-attempts";
+
+tokio::task_local! {
+    static TIMING: Arc<Timing>;
+}
+
+#[derive(Default)]
+struct Timing {
+    exempt: AtomicBool,
+    plan_us: AtomicU64,
+    upstream_us: AtomicU64,
+    attempts: AtomicU32,
+    /// Endpoint of the last successful non-streaming attempt
+    endpoint: Mutex<Option<String>>,
+    /// Rate limits of the last upstream response
+    ratelimits: Mutex<Option<RateLimits>>,
+}
+
+/// Time spent building the plan. `streaming` lifts the deadline.
+pub fn record_plan(elapsed: Duration, streaming: bool) {
+    let _ = TIMING.try_with(|t| {
+        t.plan_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if streaming {
+            t.exempt.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Run `fut`, counting its time as upstream time.
+pub async fn upstream<F: Future>(fut: F) -> F::Output {
+    let started = Instant::now();
+    let output = fut.await;
+    let _ = TIMING.try_with(|t| {
+        t.upstream_us
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed)
+    });
+    output
+}
+
+/// One more request sent upstream.
+pub fn record_attempt() {
+    let _ = TIMING.try_with(|t| t.attempts.fetch_add(1, Ordering::Relaxed));
+}
+
+/// The endpoint that answered a non-streaming request.
+pub fn record_endpoint(endpoint: &str) {
+    let _ = TIMING.try_with(|t| {
+        *t.endpoint.lock().unwrap_or_else(|e| e.into_inner()) = Some(crate::redact::text(endpoint));
+    });
+}
+
+/// Rate limits reported by the latest upstream response.
+pub fn record_ratelimits(limits: RateLimits) {
+    let _ = TIMING.try_with(|t| {
+        *t.ratelimits.lock().unwrap_or_else(|e| e.into_inner()) = Some(limits);
+    });
+}
+
+/// Phase durations of a finished request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Phases {
+    plan: Duration,
+    upstream: Duration,
+    convert: Duration,
+}
+
+impl Timing {
+    fn phases(&self, total: Duration) -> Phases {
+        let plan = Duration::from_micros(self.plan_us.load(Ordering::Relaxed));
+        let upstream = Duration::from_micros(self.upstream_us.load(Ordering::Relaxed));
+        Phases {
+            plan,
+            upstream,
+            convert: total.saturating_sub(plan + upstream),
+        }
+    }
+}
+
+fn describe(what: &str, total: Duration, phases: Phases) -> String {
+    format!(
+        "{} took {}ms (plan {}ms, upstream {}ms, convert {}ms)",
+        what,
+        total.as_millis(),
+        phases.plan.as_millis(),
+        phases.upstream.as_millis(),
+        phases.convert.as_millis()
+    )
+}
+
+/// `server.request_timeout_secs` and `server.slow_request_ms`, 0 as `None`.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    timeout: Option<Duration>,
+    slow: Option<Duration>,
+}
+
+impl Limits {
+    fn from_config(server: &config::ServerConfig) -> Self {
+        Self {
+            timeout: (server.request_timeout_secs > 0)
+                .then(|| Duration::from_secs(server.request_timeout_secs)),
+            slow: (server.slow_request_ms > 0)
+                .then(|| Duration::from_millis(server.slow_request_ms)),
+        }
+    }
+}
+
+/// Route layer enforcing the deadline, logging slow requests and adding the
+/// relay's headers.
+pub async fn layer(req: Request, next: Next) -> Response {
+    let limits = Limits::from_config(&config::load().server);
+    let what = format!("{} {}", req.method(), req.uri().path());
+    let dialect = Dialect::of_path(req.uri().path());
+    guard(limits, &what, dialect, next.run(req)).await
+}
+
+async fn guard<F>(limits: Limits, what: &str, dialect: Dialect, handler: F) -> Response
+where
+    F: Future<Output = Response>,
+{
+    let timing = Arc::new(Timing::default());
+    let started = Instant::now();
+    let run = TIMING.scope(timing.clone(), handler);
+    tokio::pin!(run);
+
+    let response = match limits.timeout {
+        None => run.await,
+        Some(limit) => match tokio::time::timeout(limit, &mut run).await {
+            Ok(response) => response,
+            Err(_) if timing.exempt.load(Ordering::Relaxed) => run.await,
+            Err(_) => {
+                let total = started.elapsed();
+                crate::logger::warn(
+                    "forward",
+                    &format!("Timed out: {}", describe(what, total, timing.phases(total))),
+                );
+                return ForwardError::Timeout(format!("No response within {}s", limit.as_secs()))
+                    .into_response_for(dialect);
+            }
+        },
+    };
+
+    let total = started.elapsed();
+    let endpoint = timing
+        .endpoint
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    let ratelimits = timing
+        .ratelimits
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    let response = stamp(
+        response,
+        total,
+        timing.attempts.load(Ordering::Relaxed),
+        endpoint,
+        ratelimits,
+    );
+    if limits.slow.is_some_and(|slow| total >= slow) && !timing.exempt.load(Ordering::Relaxed) {
+        crate::logger::warn(
+            "forward",
+            &format!(
+                "Slow request: {}",
+                describe(what, total, timing.phases(total))
+            ),
+        );
+    }
+    response
+}
+
+/// Add the relay's headers to `response`. `endpoint` is the one that
+/// answered, if known, else the upstream's first.
+fn stamp(
+    mut response: Response,
+    total: Duration,
+    attempts: u32,
+    endpoint: Option<String>,
+    ratelimits: Option<RateLimits>,
+) -> Response {
+    let served = response.extensions().get::<Served>().map(|served| {
+        let endpoint = endpoint.or_else(|| served.endpoint.clone());
+        [
+            (MODEL_HEADER, Some(served.model.clone())),
+            (UPSTREAM_HEADER, Some(served.upstream_id.clone())),
+            (ENDPOINT_HEADER, endpoint),
+            (ROUTE_REASON_HEADER, Some(served.route_reason.clone())),
+        ]
+    });
+    let headers = response.headers_mut();
+    for (name, value) in served.into_iter().flatten() {
+        if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(LATENCY_HEADER, HeaderValue::from(total.as_millis() as u64));
+    if attempts > 0 {
+        headers.insert(ATTEMPTS_HEADER, HeaderValue::from(attempts));
+    }
+    if let Some(limits) = ratelimits {
+        limits.stamp(headers);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_phases_add_up() {
+        let timing = Arc::new(Timing::default());
+        TIMING
+            .scope(timing.clone(), async {
+                record_plan(Duration::from_millis(5), false);
+                upstream(tokio::time::sleep(Duration::from_millis(20))).await;
+            })
+            .await;
+        let phases = timing.phases(Duration::from_millis(100));
+        assert_eq!(phases.plan, Duration::from_millis(5));
+        assert!(phases.upstream >= Duration::from_millis(20));
+        assert_eq!(
+            phases.plan + phases.upstream + phases.convert,
+            Duration::from_millis(100)
+        );
+        assert!(!timing.exempt.load(Ordering::Relaxed));
+
+        // Outside a request the hooks do nothing
+        record_plan(Duration::from_millis(5), true);
+    }
+
+    async fn slow_handler(streaming: bool) -> Response {
+        record_plan(Duration::ZERO, streaming);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Response::new(axum::body::Body::empty())
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_relay_headers() {
+        let limits = Limits {
+            timeout: None,
+            slow: None,
+        };
+        let handler = async {
+            record_attempt();
+            record_attempt();
+            record_endpoint("https://eu.backup.example/v1");
+            Served {
+                model: "gpt-4o".to_string(),
+                upstream_id: "backup".to_string(),
+                endpoint: Some("https://backup.example/v1".to_string()),
+                route_reason: "fallback-1".to_string(),
+            }
+            .attach(Response::new(axum::body::Body::empty()))
+        };
+        let response = guard(limits, "POST /v1", Dialect::OpenAI, handler).await;
+        let headers = response.headers();
+        assert_eq!(headers[MODEL_HEADER], "gpt-4o");
+        assert_eq!(headers[UPSTREAM_HEADER], "backup");
+        assert_eq!(headers[ENDPOINT_HEADER], "https://eu.backup.example/v1");
+        assert_eq!(headers[ROUTE_REASON_HEADER], "fallback-1");
+        assert_eq!(headers[ATTEMPTS_HEADER], "2");
+        assert!(headers[LATENCY_HEADER].to_str().unwrap().parse::<u64>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deadline_spares_streams() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(50)),
+            slow: None,
+        };
+        let response = guard(limits, "POST /gemini", Dialect::Gemini, slow_handler(false)).await;
+        assert_eq!(response.status(), axum::http::StatusCode::GATEWAY_TIMEOUT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["status"], "DEADLINE_EXCEEDED");
+
+        let response = guard(limits, "POST /v1", Dialect::OpenAI, slow_handler(true)).await;
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+    }
+}
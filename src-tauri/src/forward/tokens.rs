@@ -0,0 +1,495 @@
+//! Local token counts.
+//!
+//! `POST /v1/token-count` counts the prompt of an OpenAI (`messages`),
+//! Anthropic (`messages` and `system`) or Gemini (`contents`) request
+//! without calling any upstream. The format is taken from a `format` field,
+//! else guessed from the payload's shape, else from the configured model's
+//! provider.
+//!
+//! OpenAI models are counted with their own tokenizer (`o200k_base` or
+//! `cl100k_base`, picked by model name), including the few tokens each
+//! chat message costs for its role. Other models have no tokenizer the
+//! relay can run, so their text is counted with the same estimate used when
+//! an upstream reports no usage ([`estimate_tokens`]). Only what the model
+//! reads is counted: message text, tool call arguments and results, and
+//! tool descriptions and schemas, not block types, names or ids. Images and
+//! documents count as the fixed amounts of `token_count` in the settings
+//! rather than by the length of their base64.
+//!
+//! An answer is `estimated` unless every part of it was tokenized: without
+//! a tokenizer, or with attachments or tool definitions, whose rendering
+//! the upstream doesn't document.
+
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+use super::context::{estimate_tokens, Provider};
+use super::error::{Dialect, ForwardError};
+use super::middleware;
+use crate::config::{self, TokenCountConfig};
+
+/// Tokens of one message, or of one Gemini content.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MessageTokens {
+    pub index: usize,
+    pub role: String,
+    pub tokens: i64,
+    pub images: u32,
+    pub documents: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenCount {
+    pub format: Provider,
+    pub model: Option<String>,
+    /// Encoding the text was tokenized with, `None` when it was estimated
+    pub tokenizer: Option<String>,
+    /// Whether any part of the count is an estimate rather than tokenized
+    pub estimated: bool,
+    pub total_tokens: i64,
+    /// Anthropic `system` or Gemini `systemInstruction`; OpenAI system
+    /// messages are counted with the other messages
+    pub system_tokens: i64,
+    pub tools_tokens: i64,
+    pub messages: Vec<MessageTokens>,
+}
+
+/// Fields whose strings are text the model reads, in any of the three
+/// formats; strings under other fields (`role`, `type`, `name`, ...) are not.
+const TEXT_FIELDS: [&str; 6] = [
+    "content",
+    "text",
+    "system",
+    "thinking",
+    "parts",
+    "description",
+];
+
+/// Fields holding JSON the model reads as such: tool call arguments and
+/// results, and tool schemas.
+const JSON_FIELDS: [&str; 6] = [
+    "arguments",
+    "input",
+    "args",
+    "response",
+    "parameters",
+    "input_schema",
+];
+
+enum Media {
+    Image,
+    Document,
+}
+
+/// Attachment an object of a message is, in any of the three formats.
+fn media_kind(obj: &Map<String, Value>) -> Option<Media> {
+    match obj.get("type").and_then(Value::as_str) {
+        Some("image" | "image_url" | "input_image") => return Some(Media::Image),
+        Some("document" | "file" | "input_file") => return Some(Media::Document),
+        _ => {}
+    }
+    let blob = ["inlineData", "inline_data", "fileData", "file_data"]
+        .iter()
+        .find_map(|key| obj.get(*key))?;
+    let mime = blob
+        .get("mimeType")
+        .or_else(|| blob.get("mime_type"))
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    Some(if mime.starts_with("image/") {
+        Media::Image
+    } else {
+        Media::Document
+    })
+}
+
+static O200K_BASE: Lazy<Option<CoreBPE>> = Lazy::new(|| tiktoken_rs::o200k_base().ok());
+static CL100K_BASE: Lazy<Option<CoreBPE>> = Lazy::new(|| tiktoken_rs::cl100k_base().ok());
+
+/// How text is turned into tokens: an OpenAI model's own encoding, or the
+/// estimate.
+#[derive(Clone, Copy)]
+pub struct Counter {
+    bpe: Option<(&'static str, &'static CoreBPE)>,
+}
+
+impl Counter {
+    /// The tokenizer of `model` when it is an OpenAI model the relay has the
+    /// encoding of, else the estimate.
+    pub fn for_model(model: Option<&str>) -> Self {
+        let bpe = match model.and_then(get_tokenizer) {
+            Some(Tokenizer::O200kBase) => O200K_BASE.as_ref().map(|bpe| ("o200k_base", bpe)),
+            Some(Tokenizer::Cl100kBase) => CL100K_BASE.as_ref().map(|bpe| ("cl100k_base", bpe)),
+            _ => None,
+        };
+        Self { bpe }
+    }
+
+    #[cfg(test)]
+    fn estimate() -> Self {
+        Self { bpe: None }
+    }
+
+    pub fn text(&self, text: &str) -> i64 {
+        match self.bpe {
+            Some((_, bpe)) => bpe.encode_with_special_tokens(text).len() as i64,
+            None => estimate_tokens(text),
+        }
+    }
+}
+
+// Framing of a chat conversation in OpenAI's tokenizer: every message
+// costs its role and these tokens, a name one more, and the reply is primed
+// with a few more.
+const OPENAI_TOKENS_PER_MESSAGE: i64 = 3;
+const OPENAI_TOKENS_PER_NAME: i64 = 1;
+const OPENAI_REPLY_PRIMING: i64 = 3;
+
+struct Tally<'a> {
+    counter: &'a Counter,
+    tokens: i64,
+    images: u32,
+    documents: u32,
+}
+
+impl<'a> Tally<'a> {
+    /// Tally of a message, or of tools; `text` when `value` itself is text,
+    /// like a system prompt.
+    fn of(value: &Value, text: bool, counter: &'a Counter, cfg: &TokenCountConfig) -> Self {
+        let mut tally = Self {
+            counter,
+            tokens: 0,
+            images: 0,
+            documents: 0,
+        };
+        tally.add(value, text, cfg);
+        tally
+    }
+
+    fn add(&mut self, value: &Value, text: bool, cfg: &TokenCountConfig) {
+        match value {
+            Value::String(s) if text => self.tokens += self.counter.text(s),
+            Value::Array(items) => items.iter().for_each(|item| self.add(item, text, cfg)),
+            Value::Object(obj) => match media_kind(obj) {
+                Some(Media::Image) => {
+                    self.images += 1;
+                    self.tokens += i64::from(cfg.image_tokens);
+                }
+                Some(Media::Document) => {
+                    self.documents += 1;
+                    self.tokens += i64::from(cfg.document_tokens);
+                }
+                None => obj.iter().for_each(|(key, v)| match v {
+                    _ if !JSON_FIELDS.contains(&key.as_str()) => {
+                        self.add(v, TEXT_FIELDS.contains(&key.as_str()), cfg)
+                    }
+                    Value::String(json) => self.tokens += self.counter.text(json),
+                    _ => self.tokens += self.counter.text(&v.to_string()),
+                }),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Format of `payload` judged by its shape: Gemini has `contents`, and
+/// Anthropic a top-level `system` or content blocks OpenAI doesn't use.
+pub fn detect_format(payload: &Value) -> Option<Provider> {
+    if payload.get("contents").is_some() {
+        return Some(Provider::Gemini);
+    }
+    let messages = payload.get("messages")?.as_array()?;
+    if payload.get("system").is_some() {
+        return Some(Provider::Anthropic);
+    }
+    let anthropic_block = messages
+        .iter()
+        .filter_map(|m| m.get("content").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|block| block.get("type").and_then(Value::as_str))
+        .any(|kind| {
+            matches!(
+                kind,
+                "image" | "document" | "tool_use" | "tool_result" | "thinking"
+            )
+        });
+    if anthropic_block {
+        return Some(Provider::Anthropic);
+    }
+    let openai_only = messages.iter().any(|m| {
+        m.get("tool_calls").is_some()
+            || matches!(
+                m.get("role").and_then(Value::as_str),
+                Some("system" | "developer" | "tool")
+            )
+    });
+    openai_only.then_some(Provider::OpenAI)
+}
+
+/// Count the prompt of `payload`, read as `format`, with `counter`.
+pub fn count(
+    format: Provider,
+    payload: &Value,
+    counter: &Counter,
+    cfg: &TokenCountConfig,
+) -> TokenCount {
+    // Roles and names are only part of the prompt in OpenAI's framing
+    let framed = format == Provider::OpenAI && counter.bpe.is_some();
+    let (turns, system) = match format {
+        Provider::Gemini => (
+            payload.get("contents"),
+            payload
+                .get("systemInstruction")
+                .or_else(|| payload.get("system_instruction")),
+        ),
+        Provider::Anthropic => (payload.get("messages"), payload.get("system")),
+        Provider::OpenAI => (payload.get("messages"), None),
+    };
+    let default_role = match format {
+        Provider::Gemini => "user",
+        _ => "",
+    };
+    let messages: Vec<MessageTokens> = turns
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(index, message)| {
+            let tally = Tally::of(message, false, counter, cfg);
+            let role = message
+                .get("role")
+                .and_then(Value::as_str)
+                .unwrap_or(default_role);
+            let framing = match message.get("name").and_then(Value::as_str) {
+                _ if !framed => 0,
+                Some(name) => {
+                    OPENAI_TOKENS_PER_MESSAGE
+                        + counter.text(role)
+                        + OPENAI_TOKENS_PER_NAME
+                        + counter.text(name)
+                }
+                None => OPENAI_TOKENS_PER_MESSAGE + counter.text(role),
+            };
+            MessageTokens {
+                index,
+                role: role.to_string(),
+                tokens: tally.tokens + framing,
+                images: tally.images,
+                documents: tally.documents,
+            }
+        })
+        .collect();
+    let system_tokens = system.map_or(0, |s| Tally::of(s, true, counter, cfg).tokens);
+    let tools = payload.get("tools");
+    let tools_tokens = tools.map_or(0, |t| Tally::of(t, false, counter, cfg).tokens);
+    let priming = if framed && !messages.is_empty() {
+        OPENAI_REPLY_PRIMING
+    } else {
+        0
+    };
+    let attachments = messages.iter().any(|m| m.images > 0 || m.documents > 0);
+
+    TokenCount {
+        format,
+        model: payload
+            .get("model")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        tokenizer: counter.bpe.map(|(name, _)| name.to_string()),
+        estimated: counter.bpe.is_none() || attachments || tools.is_some(),
+        total_tokens: messages.iter().map(|m| m.tokens).sum::<i64>()
+            + system_tokens
+            + tools_tokens
+            + priming,
+        system_tokens,
+        tools_tokens,
+        messages,
+    }
+}
+
+/// Answer of `POST /v1/token-count` (see the module docs). Needs the same
+/// token as the chat endpoints, but the model doesn't have to be
+/// configured; a configured one is counted as the upstream model it sends.
+pub async fn handler(headers: HeaderMap, Json(payload): Json<Value>) -> Response {
+    if let Err(e) = middleware::determine_auth_mode(&headers) {
+        return e.into_response_for(Dialect::OpenAI);
+    }
+    let format = match payload.get("format").and_then(Value::as_str) {
+        Some(name) => match Provider::from_str(name) {
+            Some(format) => Some(format),
+            None => {
+                return ForwardError::InvalidRequest(format!(
+                    "Unknown format '{}', expected openai, anthropic or gemini",
+                    name
+                ))
+                .into_response_for(Dialect::OpenAI)
+            }
+        },
+        None => detect_format(&payload),
+    };
+    if payload.get("messages").is_none() && payload.get("contents").is_none() {
+        return ForwardError::InvalidRequest("Expected 'messages' or 'contents'".to_string())
+            .into_response_for(Dialect::OpenAI);
+    }
+    let cfg = config::current();
+    let model = payload.get("model").and_then(Value::as_str);
+    let configured = model.and_then(|model| cfg.models.iter().find(|m| m.id == model));
+    let format = format
+        .or_else(|| Provider::from_str(&configured?.provider))
+        .unwrap_or(Provider::OpenAI);
+    let upstream_model = configured
+        .and_then(|m| m.upstream_model_id.as_deref())
+        .filter(|id| !id.trim().is_empty())
+        .or(model);
+    let counter = Counter::for_model(upstream_model);
+    Json(count(format, &payload, &counter, &cfg.token_count)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_detect_format() {
+        let gemini = json!({"contents": [{"parts": [{"text": "hi"}]}]});
+        assert_eq!(detect_format(&gemini), Some(Provider::Gemini));
+        let anthropic =
+            json!({"system": "be brief", "messages": [{"role": "user", "content": "hi"}]});
+        assert_eq!(detect_format(&anthropic), Some(Provider::Anthropic));
+        let openai = json!({"messages": [{"role": "system", "content": "be brief"}]});
+        assert_eq!(detect_format(&openai), Some(Provider::OpenAI));
+        let either = json!({"messages": [{"role": "user", "content": "hi"}]});
+        assert_eq!(detect_format(&either), None);
+    }
+
+    #[test]
+    fn test_attachments_count_fixed_amounts() {
+        let cfg = TokenCountConfig {
+            image_tokens: 500,
+            document_tokens: 2000,
+        };
+        let base64 = "A".repeat(100_000);
+        let anthropic = json!({
+            "system": "x".repeat(35),
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "y".repeat(70)},
+                    {"type": "image", "source": {"type": "base64", "data": base64}},
+                    {"type": "document", "source": {"type": "base64", "data": base64}}
+                ]},
+                {"role": "assistant", "content": "ok"}
+            ]
+        });
+        let counted = count(Provider::Anthropic, &anthropic, &Counter::estimate(), &cfg);
+        assert_eq!(counted.system_tokens, 10);
+        assert_eq!(counted.messages.len(), 2);
+        let first = &counted.messages[0];
+        assert_eq!((first.images, first.documents), (1, 1));
+        assert!(
+            first.tokens > 2500 && first.tokens < 2600,
+            "{}",
+            first.tokens
+        );
+        assert_eq!(
+            counted.total_tokens,
+            10 + first.tokens + counted.messages[1].tokens
+        );
+
+        let gemini = json!({
+            "systemInstruction": {"parts": [{"text": "be brief"}]},
+            "contents": [{"parts": [
+                {"text": "what is this"},
+                {"inlineData": {"mimeType": "image/png", "data": base64}}
+            ]}]
+        });
+        let counted = count(Provider::Gemini, &gemini, &Counter::estimate(), &cfg);
+        assert_eq!(counted.messages[0].role, "user");
+        assert_eq!(counted.messages[0].images, 1);
+        assert!(counted.messages[0].tokens < 600);
+        assert!(counted.system_tokens > 0);
+    }
+
+    #[test]
+    fn test_metadata_strings_not_counted() {
+        let cfg = TokenCountConfig::default();
+        let text = "What is the weather in Paris tomorrow?";
+        let plain = json!({"messages": [{"role": "user", "content": text}]});
+        let decorated = json!({
+            "model": "a-model-id-long-enough-to-change-an-estimate",
+            "messages": [
+                {"role": "user", "name": "someone-with-a-long-name", "content": [
+                    {"type": "text", "text": text, "cache_control": {"type": "ephemeral"}}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_0123456789abcdef", "name": "get_weather", "input": {}}
+                ]}
+            ]
+        });
+        let plain = count(Provider::Anthropic, &plain, &Counter::estimate(), &cfg);
+        let decorated = count(Provider::Anthropic, &decorated, &Counter::estimate(), &cfg);
+        assert!(decorated.estimated);
+        assert_eq!(plain.messages[0].tokens, estimate_tokens(text));
+        assert_eq!(decorated.messages[0].tokens, plain.messages[0].tokens);
+        // Only the `{}` input of the tool call is read by the model
+        assert_eq!(decorated.messages[1].tokens, estimate_tokens("{}"));
+
+        let gemini = json!({"contents": [{"role": "user", "parts": [
+            {"text": text},
+            {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}
+        ]}]});
+        let counted = count(Provider::Gemini, &gemini, &Counter::estimate(), &cfg);
+        assert_eq!(
+            counted.total_tokens,
+            estimate_tokens(text) + estimate_tokens(r#"{"city":"Paris"}"#)
+        );
+    }
+
+    #[test]
+    fn test_openai_models_are_tokenized() {
+        let cfg = TokenCountConfig::default();
+        let payload = json!({
+            "model": "gpt-4o",
+            "messages": [
+                {"role": "system", "content": "You are a helpful assistant."},
+                {"role": "user", "name": "ana", "content": "Hello world"}
+            ]
+        });
+        let counter = Counter::for_model(Some("gpt-4o"));
+        assert_eq!(counter.text("Hello world"), 2);
+        let counted = count(Provider::OpenAI, &payload, &counter, &cfg);
+        assert_eq!(counted.tokenizer.as_deref(), Some("o200k_base"));
+        assert!(!counted.estimated);
+        let system = counter.text("You are a helpful assistant.");
+        assert_eq!(
+            counted.messages[0].tokens,
+            3 + counter.text("system") + system
+        );
+        assert_eq!(
+            counted.messages[1].tokens,
+            3 + counter.text("user") + 1 + counter.text("ana") + 2
+        );
+        assert_eq!(
+            counted.total_tokens,
+            counted.messages[0].tokens + counted.messages[1].tokens + 3
+        );
+
+        let gpt4 = Counter::for_model(Some("gpt-4-0613"));
+        assert_eq!(gpt4.bpe.map(|(name, _)| name), Some("cl100k_base"));
+        let claude = Counter::for_model(Some("claude-sonnet-4-20250514"));
+        let counted = count(Provider::OpenAI, &payload, &claude, &cfg);
+        assert!(counted.estimated && counted.tokenizer.is_none());
+
+        let with_tools = json!({
+            "messages": payload["messages"],
+            "tools": [{"type": "function", "function": {"name": "f", "parameters": {}}}]
+        });
+        assert!(count(Provider::OpenAI, &with_tools, &counter, &cfg).estimated);
+    }
+}
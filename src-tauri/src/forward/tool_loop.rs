@@ -0,0 +1,1063 @@
+//! Relay-run tool rounds for Anthropic and OpenAI chat requests.
+//!
+//! The relay's own tools, those of the MCP servers in `mcp.servers` (see
+//! [`crate::tools::mcp`]) and the built-in [`crate::tools::web_fetch`],
+//! are added to the request's `tools`. A turn that stops to call relay
+//! tools only is not returned: the relay runs the calls, appends the
+//! assistant turn and the results to the messages and asks the upstream
+//! again. A stream carries on in the same response, without the relay's
+//! calls or the end and start of the turns in between, and with block and
+//! tool call indexes renumbered, so the client reads one message. After
+//! `mcp.max_loops` rounds the model is asked to answer without tools. A
+//! turn that also calls the client's own tools goes back to the client
+//! without the relay's calls.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::{Body, BodyDataStream, Bytes};
+use axum::http::header;
+use axum::response::Response;
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+
+use super::client::{drain_sse_lines, is_sse_done, parse_sse_data};
+use super::context::{ForwardContext, UpstreamResponse};
+use super::error::{Dialect, ForwardError, ForwardResult, UpstreamError};
+use super::{capture, client, handlers};
+use crate::tools::mcp::{self, CallResult, Toolset};
+use crate::tools::web_fetch;
+use crate::{config, logger};
+
+/// The relay tools offered to one request.
+pub struct ToolLoop {
+    dialect: Dialect,
+    mcp: Option<Arc<Toolset>>,
+    web_fetch: bool,
+    /// Tool names the client defined itself; calls to them are the client's
+    client_tools: HashSet<String>,
+    max_loops: u32,
+}
+
+impl ToolLoop {
+    /// Add the relay tools to a `payload` of `dialect`. `None` when there
+    /// are none to offer, or no tool loop for the dialect.
+    pub async fn prepare(payload: &mut Value, dialect: Dialect) -> Option<ToolLoop> {
+        if dialect == Dialect::Gemini {
+            return None;
+        }
+        let cfg = config::current();
+        let (max_loops, web_fetch) = (cfg.mcp.max_loops, cfg.web_fetch.enabled);
+        if max_loops == 0 {
+            return None;
+        }
+        let mcp = mcp::toolset().await;
+        if mcp.is_none() && !web_fetch {
+            return None;
+        }
+        let mut tools: Vec<(&str, &str, &Value)> = mcp
+            .iter()
+            .flat_map(|set| set.tools.iter())
+            .map(|t| (t.name.as_str(), t.description.as_str(), &t.input_schema))
+            .collect();
+        let fetch_schema = web_fetch::input_schema();
+        if web_fetch {
+            tools.push((web_fetch::NAME, web_fetch::DESCRIPTION, &fetch_schema));
+        }
+
+        let list = payload
+            .as_object_mut()?
+            .entry("tools")
+            .or_insert_with(|| Value::Array(Vec::new()))
+            .as_array_mut()?;
+        let client_tools: HashSet<String> = list
+            .iter()
+            .filter_map(|t| {
+                t.get("name")
+                    .or_else(|| t.pointer("/function/name"))
+                    .and_then(Value::as_str)
+            })
+            .map(str::to_string)
+            .collect();
+        for (name, description, schema) in tools {
+            if client_tools.contains(name) {
+                continue;
+            }
+            list.push(match dialect {
+                Dialect::Anthropic => json!({
+                    "name": name,
+                    "description": description,
+                    "input_schema": schema,
+                }),
+                _ => json!({
+                    "type": "function",
+                    "function": {"name": name, "description": description, "parameters": schema},
+                }),
+            });
+        }
+        Some(ToolLoop {
+            dialect,
+            mcp,
+            web_fetch,
+            client_tools,
+            max_loops,
+        })
+    }
+
+    fn manages(&self, name: &str) -> bool {
+        !self.client_tools.contains(name)
+            && ((self.web_fetch && name == web_fetch::NAME)
+                || self.mcp.as_ref().is_some_and(|set| set.get(name).is_some()))
+    }
+
+    /// `(id, name, input)` of a tool call: a `tool_use` content block, or an
+    /// entry of OpenAI `tool_calls`.
+    fn call_of(&self, item: &Value) -> Option<(Value, String, Value)> {
+        let id = item.get("id").cloned().unwrap_or(Value::Null);
+        match self.dialect {
+            Dialect::Anthropic => {
+                if item.get("type").and_then(Value::as_str) != Some("tool_use") {
+                    return None;
+                }
+                let name = item.get("name").and_then(Value::as_str)?;
+                let input = item.get("input").cloned().unwrap_or_else(|| json!({}));
+                Some((id, name.to_string(), input))
+            }
+            _ => {
+                let name = item.pointer("/function/name").and_then(Value::as_str)?;
+                let input = match item.pointer("/function/arguments") {
+                    Some(Value::String(args)) if !args.trim().is_empty() => {
+                        serde_json::from_str(args).unwrap_or_else(|_| json!({}))
+                    }
+                    Some(args @ Value::Object(_)) => args.clone(),
+                    _ => json!({}),
+                };
+                Some((id, name.to_string(), input))
+            }
+        }
+    }
+
+    /// Whether `item` calls a relay tool.
+    fn is_call(&self, item: &Value) -> bool {
+        self.call_of(item)
+            .is_some_and(|(_, name, _)| self.manages(&name))
+    }
+
+    fn is_client_call(&self, item: &Value) -> bool {
+        self.call_of(item)
+            .is_some_and(|(_, name, _)| !self.manages(&name))
+    }
+
+    /// The stop reason of a turn that ended to call tools.
+    fn tool_stop(&self) -> &'static str {
+        match self.dialect {
+            Dialect::Anthropic => "tool_use",
+            _ => "tool_calls",
+        }
+    }
+
+    /// Whether a turn of `items` that stopped for `stop_reason` is answered
+    /// by the relay, after `rounds` rounds.
+    fn runs_round(&self, stop_reason: Option<&str>, items: &[Value], rounds: u32) -> bool {
+        stop_reason == Some(self.tool_stop())
+            && rounds < self.max_loops
+            && items.iter().any(|i| self.is_call(i))
+            && !items.iter().any(|i| self.is_client_call(i))
+    }
+
+    /// The stop reason to report for a final turn of `items` once the
+    /// relay's calls are taken out.
+    fn final_stop_reason<'a>(&self, stop_reason: &'a str, items: &[Value]) -> &'a str {
+        if stop_reason == self.tool_stop()
+            && items.iter().any(|i| self.is_call(i))
+            && !items.iter().any(|i| self.is_client_call(i))
+        {
+            match self.dialect {
+                Dialect::Anthropic => "end_turn",
+                _ => "stop",
+            }
+        } else {
+            stop_reason
+        }
+    }
+
+    async fn execute(&self, name: &str, input: &Value) -> Result<CallResult, String> {
+        if self.web_fetch && name == web_fetch::NAME {
+            let (text, is_error) = match web_fetch::call(input).await {
+                Ok(text) => (text, false),
+                Err(e) => (e, true),
+            };
+            return Ok(CallResult {
+                content: vec![json!({"type": "text", "text": text})],
+                is_error,
+            });
+        }
+        match self.mcp.as_ref().and_then(|set| set.get(name)) {
+            Some(tool) => mcp::call(tool, input).await,
+            None => Err(format!("unknown tool {}", name)),
+        }
+    }
+
+    /// Run the relay calls among `items`, returning the messages that
+    /// carry their results.
+    async fn run(&self, request_id: &str, items: &[Value]) -> Vec<Value> {
+        let mut results = Vec::new();
+        for (id, name, input) in items.iter().filter_map(|i| self.call_of(i)) {
+            if !self.manages(&name) {
+                continue;
+            }
+            let started = Instant::now();
+            let outcome = self.execute(&name, &input).await;
+            let elapsed = started.elapsed().as_millis();
+            let result = match outcome {
+                Ok(result) => {
+                    logger::info(
+                        "mcp",
+                        &format!(
+                            "Ran {} for {} in {} ms{}",
+                            name,
+                            request_id,
+                            elapsed,
+                            if result.is_error {
+                                ", it reported an error"
+                            } else {
+                                ""
+                            }
+                        ),
+                    );
+                    result
+                }
+                Err(e) => {
+                    logger::warn(
+                        "mcp",
+                        &format!(
+                            "{} for {} failed after {} ms: {}",
+                            name, request_id, elapsed, e
+                        ),
+                    );
+                    CallResult {
+                        content: vec![json!({"type": "text", "text": e})],
+                        is_error: true,
+                    }
+                }
+            };
+            results.push((id, result));
+        }
+        match self.dialect {
+            Dialect::Anthropic => {
+                let blocks: Vec<Value> = results
+                    .into_iter()
+                    .map(|(id, result)| {
+                        json!({
+                            "type": "tool_result",
+                            "tool_use_id": id,
+                            "content": result.content.iter().map(content_block).collect::<Vec<_>>(),
+                            "is_error": result.is_error,
+                        })
+                    })
+                    .collect();
+                vec![json!({"role": "user", "content": blocks})]
+            }
+            _ => results
+                .into_iter()
+                .map(|(id, result)| {
+                    let text: Vec<String> = result
+                        .content
+                        .iter()
+                        .map(|item| match content_block(item).get("text") {
+                            Some(Value::String(text)) => text.clone(),
+                            _ => "[image]".to_string(),
+                        })
+                        .collect();
+                    json!({"role": "tool", "tool_call_id": id, "content": text.join("\n")})
+                })
+                .collect(),
+        }
+    }
+
+    /// The assistant message of a turn: its `text` and `items`, as the
+    /// upstream returned them.
+    fn assistant_message(&self, text: &str, items: Vec<Value>) -> Value {
+        match self.dialect {
+            Dialect::Anthropic => {
+                // Anthropic rejects empty text blocks
+                let blocks: Vec<Value> = items
+                    .into_iter()
+                    .filter(|b| {
+                        b.get("type").and_then(Value::as_str) != Some("text")
+                            || b.get("text")
+                                .and_then(Value::as_str)
+                                .is_some_and(|t| !t.is_empty())
+                    })
+                    .collect();
+                json!({"role": "assistant", "content": blocks})
+            }
+            _ => json!({
+                "role": "assistant",
+                "content": if text.is_empty() { Value::Null } else { text.into() },
+                "tool_calls": items,
+            }),
+        }
+    }
+
+    /// `payload` with the turn and the `results` of its calls appended. The
+    /// `last` round asks for an answer without tools.
+    fn continuation(
+        &self,
+        mut payload: Value,
+        assistant: Value,
+        results: Vec<Value>,
+        last: bool,
+    ) -> Value {
+        if let Some(messages) = payload.get_mut("messages").and_then(Value::as_array_mut) {
+            messages.push(assistant);
+            messages.extend(results);
+        }
+        if last {
+            let none = match self.dialect {
+                Dialect::Anthropic => json!({"type": "none"}),
+                _ => json!("none"),
+            };
+            if let Some(body) = payload.as_object_mut() {
+                body.insert("tool_choice".to_string(), none);
+            }
+        }
+        payload
+    }
+
+    /// `(text, items, stop_reason)` of a non-streaming response. Anthropic
+    /// items are all content blocks, OpenAI ones the tool calls.
+    fn turn_of(&self, body: &Value) -> (String, Vec<Value>, Option<String>) {
+        let (text, items, stop_reason) = match self.dialect {
+            Dialect::Anthropic => (None, body.get("content"), body.get("stop_reason")),
+            _ => (
+                body.pointer("/choices/0/message/content"),
+                body.pointer("/choices/0/message/tool_calls"),
+                body.pointer("/choices/0/finish_reason"),
+            ),
+        };
+        (
+            text.and_then(Value::as_str).unwrap_or_default().to_string(),
+            items.and_then(Value::as_array).cloned().unwrap_or_default(),
+            stop_reason.and_then(Value::as_str).map(str::to_string),
+        )
+    }
+
+    /// Run tool rounds after `response`, the answer to `payload`, until the
+    /// model answers without relay tools. What the turns in between said is
+    /// put in front of the last one's content.
+    pub async fn finish(
+        &self,
+        ctx: &ForwardContext,
+        mut payload: Arc<Value>,
+        mut response: UpstreamResponse,
+    ) -> ForwardResult<UpstreamResponse> {
+        let handler = handlers::get_handler(ctx.model.provider);
+        let (mut shown_text, mut shown_blocks) = (String::new(), Vec::new());
+        let mut rounds = 0;
+        loop {
+            let (text, items, stop_reason) = self.turn_of(&response.body);
+            if !self.runs_round(stop_reason.as_deref(), &items, rounds) {
+                break;
+            }
+            let results = self.run(&ctx.meta.request_id, &items).await;
+            rounds += 1;
+            shown_text.push_str(&text);
+            if self.dialect == Dialect::Anthropic {
+                shown_blocks.extend(items.iter().filter(|b| !self.is_call(b)).cloned());
+            }
+            let assistant = self.assistant_message(&text, items);
+            let last = rounds >= self.max_loops;
+            payload = Arc::new(self.continuation(
+                Arc::unwrap_or_clone(payload),
+                assistant,
+                results,
+                last,
+            ));
+            response = super::run_attempt(
+                capture::Target::of(ctx),
+                0,
+                handler.handle_request(ctx.clone(), Arc::clone(&payload)),
+            )
+            .await?;
+        }
+
+        let (text, items, stop_reason) = self.turn_of(&response.body);
+        let reported = stop_reason
+            .as_deref()
+            .map(|reason| self.final_stop_reason(reason, &items));
+        if reported.is_some() && reported != stop_reason.as_deref() {
+            logger::warn(
+                "mcp",
+                &format!(
+                    "Stopped the tool calls of {} after {} rounds",
+                    ctx.meta.request_id, rounds
+                ),
+            );
+        }
+        let reported = reported.map(str::to_string);
+        let kept: Vec<Value> = items.into_iter().filter(|i| !self.is_call(i)).collect();
+        let body = &mut response.body;
+        match self.dialect {
+            Dialect::Anthropic => {
+                shown_blocks.extend(kept);
+                body["content"] = Value::Array(shown_blocks);
+                if let Some(reason) = reported {
+                    body["stop_reason"] = reason.into();
+                }
+            }
+            _ => {
+                let Some(choice) = body.pointer_mut("/choices/0") else {
+                    return Ok(response);
+                };
+                if let Some(reason) = reported {
+                    choice["finish_reason"] = reason.into();
+                }
+                if let Some(message) = choice.get_mut("message").and_then(Value::as_object_mut) {
+                    if !shown_text.is_empty() {
+                        message.insert("content".to_string(), (shown_text + &text).into());
+                    }
+                    if kept.is_empty() {
+                        message.remove("tool_calls");
+                    } else {
+                        message.insert("tool_calls".to_string(), Value::Array(kept));
+                    }
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    /// `first`, the event stream answering `payload`, carried on through
+    /// the tool rounds as one message.
+    pub fn stream(self, ctx: ForwardContext, payload: Value, first: Response) -> Response {
+        let is_sse = first
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        if !is_sse {
+            return first;
+        }
+        let (parts, body) = first.into_parts();
+        let state = StreamLoop {
+            splice: Splice::new(self),
+            ctx,
+            payload,
+            body: Some(body.into_data_stream()),
+        };
+        let body = futures_util::stream::unfold(state, |mut state| async move {
+            let chunk = state.next().await?;
+            Some((chunk, state))
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+}
+
+/// An MCP content item as an Anthropic `tool_result` content block.
+fn content_block(item: &Value) -> Value {
+    let text = |text: &str| json!({"type": "text", "text": text});
+    match item.get("type").and_then(Value::as_str) {
+        Some("text") => text(item.get("text").and_then(Value::as_str).unwrap_or_default()),
+        Some("image") => json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": item.get("mimeType").cloned().unwrap_or_else(|| "image/png".into()),
+                "data": item.get("data").cloned().unwrap_or_default(),
+            },
+        }),
+        Some("resource") => match item.pointer("/resource/text").and_then(Value::as_str) {
+            Some(content) => text(content),
+            None => text(&format!(
+                "[resource {}]",
+                item.pointer("/resource/uri")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+            )),
+        },
+        _ => text(&item.to_string()),
+    }
+}
+
+/// An event with its data replaced by `data`, other lines kept.
+fn write_event(out: &mut String, lines: &[String], data: &Value) {
+    for line in lines.iter().filter(|l| parse_sse_data(l).is_none()) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("data: ");
+    out.push_str(&data.to_string());
+    out.push_str("\n\n");
+}
+
+fn write_lines(out: &mut String, lines: &[String]) {
+    for line in lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+}
+
+/// What has been said in the turn being streamed.
+#[derive(Default)]
+struct Turn {
+    /// OpenAI `content`
+    text: String,
+    /// Anthropic content blocks or OpenAI tool calls, by upstream index
+    items: BTreeMap<u64, Value>,
+    /// `partial_json` of Anthropic `tool_use` inputs
+    inputs: HashMap<u64, String>,
+    /// Relay calls, not sent to the client
+    hidden: HashSet<u64>,
+    /// Index each sent item has for the client
+    index: HashMap<u64, u64>,
+}
+
+impl Turn {
+    fn apply(&mut self, index: u64, delta: &Value) {
+        let Some(block) = self.items.get_mut(&index) else {
+            return;
+        };
+        match delta.get("type").and_then(Value::as_str) {
+            Some("text_delta") => append(block, "text", delta.get("text")),
+            Some("thinking_delta") => append(block, "thinking", delta.get("thinking")),
+            Some("signature_delta") => {
+                block["signature"] = delta.get("signature").cloned().unwrap_or_default();
+            }
+            Some("input_json_delta") => self.inputs.entry(index).or_default().push_str(
+                delta
+                    .get("partial_json")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default(),
+            ),
+            _ => {}
+        }
+    }
+
+    fn close(&mut self, index: u64) {
+        let Some(json) = self.inputs.remove(&index) else {
+            return;
+        };
+        if let Some(block) = self.items.get_mut(&index) {
+            block["input"] = match json.trim() {
+                "" => json!({}),
+                json => serde_json::from_str(json).unwrap_or_else(|_| json!({})),
+            };
+        }
+    }
+}
+
+/// Append the string `part` to the string `field` of `value`.
+fn append(value: &mut Value, field: &str, part: Option<&Value>) {
+    let text = value.get(field).and_then(Value::as_str).unwrap_or_default();
+    let part = part.and_then(Value::as_str).unwrap_or_default();
+    value[field] = format!("{}{}", text, part).into();
+}
+
+/// The events of successive turns rewritten into one message.
+struct Splice {
+    tools: ToolLoop,
+    turn: Turn,
+    rounds: u32,
+    /// Index of the next item sent to the client
+    next_index: u64,
+    /// Bytes of an incomplete line
+    buffer: Vec<u8>,
+    /// Lines of the event being read
+    event: Vec<String>,
+    /// The turn ended on relay calls
+    round_due: bool,
+    /// Its stream is over (`message_stop` or `[DONE]`)
+    stopped: bool,
+}
+
+impl Splice {
+    fn new(tools: ToolLoop) -> Self {
+        Self {
+            tools,
+            turn: Turn::default(),
+            rounds: 0,
+            next_index: 0,
+            buffer: Vec::new(),
+            event: Vec::new(),
+            round_due: false,
+            stopped: false,
+        }
+    }
+
+    /// The client's events completed by `chunk`.
+    fn push(&mut self, chunk: &[u8]) -> String {
+        let mut out = String::new();
+        for line in drain_sse_lines(&mut self.buffer, chunk) {
+            if self.stopped {
+                break;
+            }
+            if !line.is_empty() {
+                self.event.push(line);
+                continue;
+            }
+            let event = std::mem::take(&mut self.event);
+            if !event.is_empty() {
+                self.write(event, &mut out);
+            }
+        }
+        out
+    }
+
+    /// Whatever is left when the upstream's stream ends.
+    fn finish(&mut self) -> String {
+        let mut out = String::new();
+        let rest = std::mem::take(&mut self.buffer);
+        let mut event = std::mem::take(&mut self.event);
+        event.extend(
+            String::from_utf8_lossy(&rest)
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(str::to_string),
+        );
+        if !event.is_empty() && !self.stopped {
+            self.write(event, &mut out);
+        }
+        out
+    }
+
+    fn write(&mut self, lines: Vec<String>, out: &mut String) {
+        let data: Vec<&str> = lines.iter().filter_map(|l| parse_sse_data(l)).collect();
+        let data = data.join("\n");
+        if is_sse_done(&data) {
+            if self.round_due {
+                self.stopped = true;
+            } else {
+                write_lines(out, &lines);
+            }
+            return;
+        }
+        let Some(event) = serde_json::from_str::<Value>(&data).ok() else {
+            write_lines(out, &lines);
+            return;
+        };
+        match self.tools.dialect {
+            Dialect::Anthropic => self.write_anthropic(lines, event, out),
+            _ => self.write_openai(lines, event, out),
+        }
+    }
+
+    fn write_anthropic(&mut self, lines: Vec<String>, mut event: Value, out: &mut String) {
+        let index = event.get("index").and_then(Value::as_u64).unwrap_or(0);
+        match event
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+        {
+            "message_start" if self.rounds > 0 => {}
+            "content_block_start" => {
+                let block = event.get("content_block").cloned().unwrap_or_default();
+                if self.tools.is_call(&block) {
+                    self.turn.hidden.insert(index);
+                }
+                self.turn.items.insert(index, block);
+                self.send_block_event(index, &lines, event, out);
+            }
+            "content_block_delta" => {
+                let delta = event.get("delta").cloned().unwrap_or_default();
+                self.turn.apply(index, &delta);
+                self.send_block_event(index, &lines, event, out);
+            }
+            "content_block_stop" => {
+                self.turn.close(index);
+                self.send_block_event(index, &lines, event, out);
+            }
+            "message_delta" => {
+                let stop_reason = event
+                    .pointer("/delta/stop_reason")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                match self.end_of_turn(stop_reason.as_deref()) {
+                    None => {}
+                    Some(reason) if Some(reason.as_str()) != stop_reason.as_deref() => {
+                        event["delta"]["stop_reason"] = reason.into();
+                        write_event(out, &lines, &event);
+                    }
+                    Some(_) => write_lines(out, &lines),
+                }
+            }
+            "message_stop" if self.round_due => self.stopped = true,
+            _ => write_lines(out, &lines),
+        }
+    }
+
+    fn write_openai(&mut self, lines: Vec<String>, mut event: Value, out: &mut String) {
+        // Whatever follows the end of a turn the relay answers, such as the
+        // usage chunk, belongs to that turn
+        if self.round_due {
+            return;
+        }
+        let Some(choice) = event.pointer_mut("/choices/0") else {
+            write_lines(out, &lines);
+            return;
+        };
+        let mut changed = false;
+        if let Some(text) = choice.pointer("/delta/content").and_then(Value::as_str) {
+            self.turn.text.push_str(text);
+        }
+        if let Some(calls) = choice
+            .pointer_mut("/delta/tool_calls")
+            .and_then(Value::as_array_mut)
+        {
+            let mut sent = Vec::new();
+            for mut call in calls.drain(..) {
+                let index = call.get("index").and_then(Value::as_u64).unwrap_or(0);
+                let first = !self.turn.items.contains_key(&index);
+                let item = self.turn.items.entry(index).or_insert_with(
+                    || json!({"id": "", "type": "function", "function": {"name": "", "arguments": ""}}),
+                );
+                if let Some(id) = call.get("id").filter(|id| id.is_string()) {
+                    item["id"] = id.clone();
+                }
+                append(
+                    &mut item["function"],
+                    "name",
+                    call.pointer("/function/name"),
+                );
+                append(
+                    &mut item["function"],
+                    "arguments",
+                    call.pointer("/function/arguments"),
+                );
+                if first && self.tools.is_call(item) {
+                    self.turn.hidden.insert(index);
+                }
+                if self.turn.hidden.contains(&index) {
+                    continue;
+                }
+                call["index"] = self.client_index(index).into();
+                sent.push(call);
+            }
+            changed = true;
+            if sent.is_empty() {
+                if let Some(delta) = choice.get_mut("delta").and_then(Value::as_object_mut) {
+                    delta.remove("tool_calls");
+                }
+            } else {
+                *calls = sent;
+            }
+        }
+        let stop_reason = choice
+            .get("finish_reason")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if stop_reason.is_some() {
+            match self.end_of_turn(stop_reason.as_deref()) {
+                None => choice["finish_reason"] = Value::Null,
+                Some(reason) if Some(reason.as_str()) != stop_reason.as_deref() => {
+                    choice["finish_reason"] = reason.into();
+                }
+                Some(_) => {}
+            }
+            changed = true;
+        }
+        if !changed {
+            write_lines(out, &lines);
+            return;
+        }
+        let says_something = choice.get("finish_reason").is_some_and(|r| !r.is_null())
+            || choice
+                .get("delta")
+                .and_then(Value::as_object)
+                .is_some_and(|delta| {
+                    delta
+                        .iter()
+                        .any(|(key, value)| key != "role" && !value.is_null())
+                });
+        if says_something {
+            write_event(out, &lines, &event);
+        }
+    }
+
+    /// The stop reason to send for the turn, `None` when the relay answers
+    /// it with a round of its own.
+    fn end_of_turn(&mut self, stop_reason: Option<&str>) -> Option<String> {
+        let items: Vec<Value> = self.turn.items.values().cloned().collect();
+        if self.tools.runs_round(stop_reason, &items, self.rounds) {
+            self.round_due = true;
+            return None;
+        }
+        stop_reason.map(|reason| self.tools.final_stop_reason(reason, &items).to_string())
+    }
+
+    /// Index the client knows the upstream item `index` of this turn by.
+    fn client_index(&mut self, index: u64) -> u64 {
+        let next = &mut self.next_index;
+        *self.turn.index.entry(index).or_insert_with(|| {
+            *next += 1;
+            *next - 1
+        })
+    }
+
+    /// Send an Anthropic event of the block at upstream `index` under its
+    /// client index, unless it is a relay call.
+    fn send_block_event(
+        &mut self,
+        index: u64,
+        lines: &[String],
+        mut event: Value,
+        out: &mut String,
+    ) {
+        if self.turn.hidden.contains(&index) {
+            return;
+        }
+        event["index"] = self.client_index(index).into();
+        write_event(out, lines, &event);
+    }
+
+    /// Text and items of the finished turn, leaving an empty turn for the
+    /// next one.
+    fn end_turn(&mut self) -> (String, Vec<Value>) {
+        self.round_due = false;
+        self.stopped = false;
+        self.rounds += 1;
+        let turn = std::mem::take(&mut self.turn);
+        (turn.text, turn.items.into_values().collect())
+    }
+}
+
+struct StreamLoop {
+    splice: Splice,
+    ctx: ForwardContext,
+    payload: Value,
+    /// The upstream stream of the current turn
+    body: Option<BodyDataStream>,
+}
+
+impl StreamLoop {
+    async fn next(&mut self) -> Option<Result<Bytes, std::io::Error>> {
+        loop {
+            if self.splice.round_due && (self.splice.stopped || self.body.is_none()) {
+                self.body = None;
+                match self.next_turn().await {
+                    Ok(body) => self.body = Some(body),
+                    Err(e) => return Some(Ok(Bytes::from(self.error_event(&e)))),
+                }
+            }
+            let body = self.body.as_mut()?;
+            let out = match body.next().await {
+                Some(Ok(chunk)) => self.splice.push(&chunk),
+                Some(Err(e)) => {
+                    self.body = None;
+                    return Some(Err(std::io::Error::other(e.to_string())));
+                }
+                None => {
+                    self.body = None;
+                    self.splice.finish()
+                }
+            };
+            if !out.is_empty() {
+                return Some(Ok(Bytes::from(out)));
+            }
+        }
+    }
+
+    /// Run the relay calls of the finished turn and start the next one.
+    async fn next_turn(&mut self) -> ForwardResult<BodyDataStream> {
+        let (text, items) = self.splice.end_turn();
+        let tools = &self.splice.tools;
+        let results = tools.run(&self.ctx.meta.request_id, &items).await;
+        let last = self.splice.rounds >= tools.max_loops;
+        let assistant = tools.assistant_message(&text, items);
+        self.payload =
+            tools.continuation(std::mem::take(&mut self.payload), assistant, results, last);
+        let handler = handlers::get_handler(self.ctx.model.provider);
+        let response = super::run_attempt(
+            capture::Target::of(&self.ctx),
+            0,
+            handler.handle_stream(self.ctx.clone(), self.payload.clone()),
+        )
+        .await?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            return Err(ForwardError::RequestFailed(Box::new(UpstreamError {
+                message: format!("Upstream returned {} after a tool round", status),
+                status: Some(status),
+                retryable: client::should_retry(status),
+                ..Default::default()
+            })));
+        }
+        Ok(response.into_body().into_data_stream())
+    }
+
+    /// The event that ends the stream when a round fails.
+    fn error_event(&self, err: &ForwardError) -> String {
+        let error = json!({"type": "api_error", "message": err.to_string()});
+        let mut out = String::new();
+        match self.splice.tools.dialect {
+            Dialect::Anthropic => write_event(
+                &mut out,
+                &["event: error".to_string()],
+                &json!({"type": "error", "error": error}),
+            ),
+            _ => {
+                write_event(&mut out, &[], &json!({"error": error}));
+                out.push_str("data: [DONE]\n\n");
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool_loop(dialect: Dialect) -> ToolLoop {
+        ToolLoop {
+            dialect,
+            mcp: Some(Arc::new(Toolset {
+                tools: vec![mcp::Tool {
+                    name: "docs__search".to_string(),
+                    server: "docs".to_string(),
+                    remote_name: "search".to_string(),
+                    description: String::new(),
+                    input_schema: json!({"type": "object"}),
+                }],
+            })),
+            web_fetch: true,
+            client_tools: HashSet::from(["read_file".to_string()]),
+            max_loops: 1,
+        }
+    }
+
+    fn events(sse: &str) -> Vec<Value> {
+        sse.lines()
+            .filter_map(parse_sse_data)
+            .filter(|d| !is_sse_done(d))
+            .map(|d| serde_json::from_str(d).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_relay_calls_are_spliced_out_of_anthropic_streams() {
+        let first = concat!(
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"m1\"}}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Searching\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"t1\",\"name\":\"docs__search\",\"input\":{}}}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"q\\\": \"}}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"mcp\\\"}\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"}}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+        );
+        let mut splice = Splice::new(tool_loop(Dialect::Anthropic));
+        let (head, tail) = first.as_bytes().split_at(200);
+        let out = splice.push(head) + &splice.push(tail);
+        let sent = events(&out);
+        assert_eq!(sent.len(), 4);
+        assert!(sent.iter().all(|e| e.get("index").is_none_or(|i| i == 0)));
+        assert!(splice.round_due && splice.stopped);
+
+        let (text, items) = splice.end_turn();
+        assert_eq!(items[1]["input"], json!({"q": "mcp"}));
+        let tools = &splice.tools;
+        let payload = tools.continuation(
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+            tools.assistant_message(&text, items),
+            vec![
+                json!({"role": "user", "content": [{"type": "tool_result", "tool_use_id": "t1"}]}),
+            ],
+            splice.rounds >= tools.max_loops,
+        );
+        assert_eq!(payload["messages"][1]["content"][1]["id"], "t1");
+        assert_eq!(payload["messages"][2]["content"][0]["tool_use_id"], "t1");
+        assert_eq!(payload["tool_choice"], json!({"type": "none"}));
+
+        // The next turn joins the message: its start is dropped and its
+        // blocks follow the first turn's
+        let second = concat!(
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"m2\"}}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"t2\",\"name\":\"read_file\",\"input\":{}}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"}}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+        );
+        let sent = events(&splice.push(second.as_bytes()));
+        let kinds: Vec<&str> = sent.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "content_block_start",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        assert_eq!(sent[0]["index"], 1);
+        assert_eq!(sent[2]["index"], 2);
+        assert_eq!(sent[4]["delta"]["stop_reason"], "tool_use");
+        assert!(!splice.round_due);
+    }
+
+    #[test]
+    fn test_relay_calls_are_spliced_out_of_openai_streams() {
+        let chunk = |delta: Value, finish: Value| {
+            format!(
+                "data: {}\n\n",
+                json!({"id": "c1", "choices": [{"index": 0, "delta": delta, "finish_reason": finish}]})
+            )
+        };
+        let first = [
+            chunk(json!({"role": "assistant", "content": "Let me look"}), Value::Null),
+            chunk(
+                json!({"tool_calls": [{"index": 0, "id": "call_1", "type": "function", "function": {"name": "web_fetch", "arguments": ""}}]}),
+                Value::Null,
+            ),
+            chunk(
+                json!({"tool_calls": [{"index": 0, "function": {"arguments": "{\"url\":\"https://example.com\"}"}}]}),
+                Value::Null,
+            ),
+            chunk(json!({}), json!("tool_calls")),
+            "data: {\"id\":\"c1\",\"choices\":[],\"usage\":{\"total_tokens\":9}}\n\n".to_string(),
+            "data: [DONE]\n\n".to_string(),
+        ]
+        .concat();
+        let mut splice = Splice::new(tool_loop(Dialect::OpenAI));
+        let sent = events(&splice.push(first.as_bytes()));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["choices"][0]["delta"]["content"], "Let me look");
+        assert!(splice.round_due && splice.stopped);
+
+        let (text, items) = splice.end_turn();
+        let tools = &splice.tools;
+        assert_eq!(
+            tools.call_of(&items[0]).unwrap().2,
+            json!({"url": "https://example.com"})
+        );
+        let assistant = tools.assistant_message(&text, items);
+        assert_eq!(assistant["content"], "Let me look");
+        assert_eq!(assistant["tool_calls"][0]["id"], "call_1");
+
+        // A client tool call in the next turn keeps its place
+        let second = [
+            chunk(json!({"role": "assistant", "content": "Done."}), Value::Null),
+            chunk(
+                json!({"tool_calls": [{"index": 0, "id": "call_2", "type": "function", "function": {"name": "read_file", "arguments": "{}"}}]}),
+                Value::Null,
+            ),
+            chunk(json!({}), json!("tool_calls")),
+            "data: [DONE]\n\n".to_string(),
+        ]
+        .concat();
+        let out = splice.push(second.as_bytes());
+        let sent = events(&out);
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[1]["choices"][0]["delta"]["tool_calls"][0]["index"], 0);
+        assert_eq!(sent[2]["choices"][0]["finish_reason"], "tool_calls");
+        assert!(out.ends_with("data: [DONE]\n\n"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Dry runs of request conversion.
+//!
+//! `POST /api/transform/preview?target=openai|anthropic|gemini` plans the
+//! posted payload like the endpoint of `target` would, then returns the
+//! body its handler would send upstream for a non-streaming request, cross
+//! provider conversion included. Nothing is sent. Only the body is
+//! returned, never the headers carrying the upstream's API key.
+//!
+//! A few example payloads ship with the relay. `GET` on the same path
+//! lists them and `example=<name>` previews one, so a reported conversion
+//! bug can be checked against a known request.
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::context::{ForwardContext, Provider};
+use super::error::Dialect;
+use super::{handlers, middleware};
+
+/// A stored request to try conversions with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Example {
+    #[serde(default)]
+    pub name: String,
+    pub target: String,
+    pub description: String,
+    pub payload: Value,
+}
+
+const EXAMPLES: &[(&str, &str)] = &[
+    (
+        "openai-tool-call",
+        include_str!("../../resources/transform-examples/openai-tool-call.json"),
+    ),
+    (
+        "openai-vision",
+        include_str!("../../resources/transform-examples/openai-vision.json"),
+    ),
+    (
+        "anthropic-thinking-tools",
+        include_str!("../../resources/transform-examples/anthropic-thinking-tools.json"),
+    ),
+    (
+        "anthropic-image",
+        include_str!("../../resources/transform-examples/anthropic-image.json"),
+    ),
+    (
+        "gemini-function-call",
+        include_str!("../../resources/transform-examples/gemini-function-call.json"),
+    ),
+];
+
+/// The stored examples, in a fixed order.
+pub fn examples() -> Vec<Example> {
+    EXAMPLES
+        .iter()
+        .filter_map(|(name, raw)| {
+            let mut example: Example = serde_json::from_str(raw).ok()?;
+            example.name = name.to_string();
+            Some(example)
+        })
+        .collect()
+}
+
+/// Query of `POST /api/transform/preview`. `target` defaults to the
+/// example's; `model` replaces the payload's.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PreviewQuery {
+    pub target: Option<String>,
+    pub model: Option<String>,
+    pub example: Option<String>,
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+}
+
+/// A payload planned and converted like its endpoint would.
+pub struct Converted {
+    pub target: Provider,
+    pub ctx: ForwardContext,
+    /// Body the upstream would get
+    pub body: Value,
+}
+
+/// Plan and convert `payload` (or the example named in `query`) without
+/// sending it. `headers` authenticate the dry run like they would the real
+/// request. Failures come as the response to send.
+pub fn convert(
+    headers: &HeaderMap,
+    query: PreviewQuery,
+    payload: Option<Value>,
+) -> Result<Converted, Box<Response>> {
+    let example = match query.example.as_deref() {
+        Some(name) => match examples().into_iter().find(|e| e.name == name) {
+            Some(example) => Some(example),
+            None => {
+                return Err(Box::new(
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({ "error": format!("Unknown example '{}'", name) })),
+                    )
+                        .into_response(),
+                ))
+            }
+        },
+        None => None,
+    };
+    let target = query
+        .target
+        .as_deref()
+        .or(example.as_ref().map(|e| e.target.as_str()))
+        .unwrap_or("openai");
+    let Some(target) = Provider::from_str(target) else {
+        return Err(Box::new(bad_request(format!(
+            "Unknown target '{}', expected openai, anthropic or gemini",
+            target
+        ))));
+    };
+    let Some(mut payload) = payload.or(example.map(|e| e.payload)) else {
+        return Err(Box::new(bad_request(
+            "Post a request payload or name an example".to_string(),
+        )));
+    };
+    if let (Some(model), Some(obj)) = (&query.model, payload.as_object_mut()) {
+        obj.insert("model".to_string(), Value::String(model.clone()));
+    }
+
+    let (plan, dialect) = match target {
+        Provider::Gemini => {
+            let model = payload["model"]
+                .as_str()
+                .unwrap_or("gemini-pro")
+                .to_string();
+            let path = format!("models/{}:generateContent", model);
+            (
+                middleware::build_gemini_plan(headers, &mut payload, &path, "v1beta"),
+                Dialect::Gemini,
+            )
+        }
+        Provider::Anthropic => (
+            middleware::build_forward_plan(headers, &mut payload, Some(target)),
+            Dialect::Anthropic,
+        ),
+        Provider::OpenAI => (
+            middleware::build_forward_plan(headers, &mut payload, Some(target)),
+            Dialect::OpenAI,
+        ),
+    };
+    let ctx = plan
+        .map_err(|e| Box::new(e.into_response_for(dialect)))?
+        .primary;
+    let (payload, _) = middleware::with_project_prompt(&ctx, payload, dialect);
+    let body = handlers::get_handler(ctx.model.provider).upstream_body(&ctx, &payload);
+    Ok(Converted { target, ctx, body })
+}
+
+/// The body `payload` would be sent upstream with.
+pub fn preview(headers: &HeaderMap, query: PreviewQuery, payload: Option<Value>) -> Response {
+    let Converted { target, ctx, body } = match convert(headers, query, payload) {
+        Ok(converted) => converted,
+        Err(response) => return *response,
+    };
+
+    Json(json!({
+        "target": target.as_str(),
+        "model": ctx.model.id,
+        "upstream": ctx.upstream.id,
+        "upstream_model": ctx.model.upstream_model(),
+        "api_style": ctx.upstream.api_style.as_deref().unwrap_or(target.as_str()),
+        "body": body,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_examples_load() {
+        let examples = examples();
+        assert_eq!(examples.len(), EXAMPLES.len());
+        for example in &examples {
+            assert!(
+                Provider::from_str(&example.target).is_some(),
+                "{}",
+                example.name
+            );
+            assert!(example.payload.is_object(), "{}", example.name);
+        }
+        assert_eq!(examples[0].name, "openai-tool-call");
+    }
+}
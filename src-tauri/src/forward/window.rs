@@ -0,0 +1,411 @@
+//! Context-window guard.
+//!
+//! Models with a `context_guard` get prompts that fit their window: when
+//! the estimated prompt (see [`tokens`](super::tokens)) is larger than the
+//! context length less the room kept for the answer, the oldest messages
+//! are dropped until it fits, or the request is rejected with 400 when
+//! truncation is off. Only the client's `messages` (Gemini: `contents`) are
+//! dropped, never system prompts or the last turn, and a tool call always
+//! goes together with its results so no result is left without its call.
+//! Anthropic and Gemini conversations also keep starting with a user turn:
+//! in a tool loop, where every later turn is a call or its results, the
+//! turn that opened the conversation is kept and the calls after it go.
+//!
+//! Responses API payloads are not guarded.
+
+use axum::http::HeaderValue;
+use axum::response::Response;
+use serde_json::Value;
+
+use super::context::{ForwardContext, Provider};
+use super::error::{Dialect, ForwardError, ForwardResult};
+use super::tokens;
+use crate::config::{self, ContextGuardConfig, TokenCountConfig};
+use crate::logger;
+
+/// Response header counting the messages dropped to fit the window.
+pub const TRUNCATED_HEADER: &str = "x-relay-truncated-messages";
+
+/// Messages dropped from a request, reported in [`TRUNCATED_HEADER`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Truncation(usize);
+
+impl Truncation {
+    pub fn attach(self, mut response: Response) -> Response {
+        if self.0 > 0 {
+            response
+                .headers_mut()
+                .insert(TRUNCATED_HEADER, HeaderValue::from(self.0));
+        }
+        response
+    }
+}
+
+fn format_of(dialect: Dialect) -> Provider {
+    match dialect {
+        Dialect::OpenAI => Provider::OpenAI,
+        Dialect::Anthropic => Provider::Anthropic,
+        Dialect::Gemini => Provider::Gemini,
+    }
+}
+
+/// Answer size the request asks for, if it sets one.
+fn max_tokens(payload: &Value, format: Provider) -> Option<i64> {
+    match format {
+        Provider::OpenAI => payload
+            .get("max_completion_tokens")
+            .or_else(|| payload.get("max_tokens")),
+        Provider::Anthropic => payload.get("max_tokens"),
+        Provider::Gemini => payload
+            .get("generationConfig")
+            .and_then(|c| c.get("maxOutputTokens"))
+            .or_else(|| {
+                payload
+                    .get("generation_config")
+                    .and_then(|c| c.get("max_output_tokens"))
+            }),
+    }
+    .and_then(Value::as_i64)
+}
+
+fn is_system(message: &Value, format: Provider) -> bool {
+    format == Provider::OpenAI
+        && matches!(
+            message.get("role").and_then(Value::as_str),
+            Some("system" | "developer")
+        )
+}
+
+/// Whether `message` carries results of the tool calls before it.
+fn is_tool_result(message: &Value, format: Provider) -> bool {
+    match format {
+        Provider::OpenAI => matches!(
+            message.get("role").and_then(Value::as_str),
+            Some("tool" | "function")
+        ),
+        Provider::Anthropic => message
+            .get("content")
+            .and_then(Value::as_array)
+            .is_some_and(|blocks| {
+                blocks
+                    .iter()
+                    .any(|b| b.get("type").and_then(Value::as_str) == Some("tool_result"))
+            }),
+        Provider::Gemini => message
+            .get("parts")
+            .and_then(Value::as_array)
+            .is_some_and(|parts| {
+                parts.iter().any(|p| {
+                    p.get("functionResponse").is_some() || p.get("function_response").is_some()
+                })
+            }),
+    }
+}
+
+/// Whether a conversation may start with `message`.
+fn opens_conversation(message: &Value, format: Provider) -> bool {
+    let role = message.get("role").and_then(Value::as_str);
+    match format {
+        Provider::OpenAI => true,
+        Provider::Anthropic => role == Some("user"),
+        Provider::Gemini => role != Some("model"),
+    }
+}
+
+/// Indexes of the droppable messages, oldest first, in groups dropped
+/// together: a message with the tool results answering it.
+fn groups(messages: &[Value], format: Provider) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        if is_system(message, format) {
+            continue;
+        }
+        match groups.last_mut() {
+            Some(group) if is_tool_result(message, format) => group.push(i),
+            _ => groups.push(vec![i]),
+        }
+    }
+    groups
+}
+
+/// Fit `payload` into `guard`, returning how many messages were dropped.
+fn fit_with(
+    guard: &ContextGuardConfig,
+    counts: &TokenCountConfig,
+    model_id: &str,
+    payload: &mut Value,
+    format: Provider,
+) -> ForwardResult<usize> {
+    let reserve = max_tokens(payload, format).unwrap_or(i64::from(guard.default_max_tokens));
+    let budget = i64::from(guard.context_length) - reserve;
+    let counted = tokens::count(format, payload, counts);
+    let overflow = counted.total_tokens - budget;
+    if overflow <= 0 {
+        return Ok(0);
+    }
+    let too_large = |over: i64| {
+        ForwardError::InvalidRequest(format!(
+            "Prompt of about {} tokens is {} tokens over what model '{}' takes: {} context \
+             tokens less {} kept for the answer",
+            counted.total_tokens, over, model_id, guard.context_length, reserve
+        ))
+    };
+    if !guard.truncate {
+        return Err(too_large(overflow));
+    }
+
+    let key = match format {
+        Provider::Gemini => "contents",
+        _ => "messages",
+    };
+    let Some(messages) = payload.get_mut(key).and_then(Value::as_array_mut) else {
+        return Err(too_large(overflow));
+    };
+    let mut groups = groups(messages, format);
+    // The last turn is what the client wants answered
+    let Some(last) = groups.pop() else {
+        return Err(too_large(overflow));
+    };
+    let tokens_of = |group: &[usize]| -> i64 {
+        group
+            .iter()
+            .filter_map(|&i| counted.messages.get(i))
+            .map(|m| m.tokens)
+            .sum()
+    };
+    let opens = |group: &[usize]| opens_conversation(&messages[group[0]], format);
+    // Drop the groups from `start` on until the prompt fits, returning where
+    // the dropped ones end and the tokens freed. Without a group kept ahead
+    // of them, the first group kept must open the conversation.
+    let drop_from = |start: usize| {
+        let (mut end, mut freed) = (start, 0);
+        for group in &groups[start..] {
+            if freed >= overflow && (start > 0 || opens(group)) {
+                break;
+            }
+            freed += tokens_of(group);
+            end += 1;
+        }
+        (end, freed)
+    };
+
+    let (mut start, (mut end, mut freed)) = (0, drop_from(0));
+    if !opens(groups.get(end).unwrap_or(&last)) {
+        // Only calls and results are left after the opening turn: keep it
+        if !groups.first().is_some_and(|group| opens(group)) {
+            return Err(too_large(overflow));
+        }
+        start = 1;
+        (end, freed) = drop_from(start);
+    }
+    if freed < overflow {
+        return Err(too_large(overflow));
+    }
+
+    let removed: Vec<usize> = groups[start..end].iter().flatten().copied().collect();
+    let mut index = 0;
+    messages.retain(|_| {
+        let keep = !removed.contains(&index);
+        index += 1;
+        keep
+    });
+    Ok(removed.len())
+}
+
+/// Drop the oldest messages of `payload` until it fits the context window
+/// of `ctx`'s model, or fail when it can't or the model's guard doesn't
+/// truncate. Models without a guard are left alone.
+pub fn fit(
+    ctx: &ForwardContext,
+    payload: &mut Value,
+    dialect: Dialect,
+) -> ForwardResult<Truncation> {
+    let cfg = config::current();
+    let Some(guard) = cfg
+        .models
+        .iter()
+        .find(|m| m.id == ctx.model.id)
+        .and_then(|m| m.context_guard.as_ref())
+    else {
+        return Ok(Truncation::default());
+    };
+    match fit_with(
+        guard,
+        &cfg.token_count,
+        &ctx.model.id,
+        payload,
+        format_of(dialect),
+    ) {
+        Ok(0) => Ok(Truncation::default()),
+        Ok(dropped) => {
+            logger::log_with_fields(
+                logger::LogLevel::Warn,
+                "forward",
+                &format!(
+                    "Dropped the {} oldest message(s) to fit the context window of {}",
+                    dropped, ctx.model.id
+                ),
+                &ctx.log_fields(),
+            );
+            Ok(Truncation(dropped))
+        }
+        Err(e) => {
+            logger::log_with_fields(
+                logger::LogLevel::Warn,
+                "forward",
+                &format!("Rejected a prompt too large for {}: {}", ctx.model.id, e),
+                &ctx.log_fields(),
+            );
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn guard(truncate: bool) -> ContextGuardConfig {
+        ContextGuardConfig {
+            context_length: 1500,
+            truncate,
+            default_max_tokens: 500,
+        }
+    }
+
+    #[test]
+    fn test_tool_calls_dropped_with_results() {
+        let mut payload = json!({
+            "model": "m",
+            "messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "c1", "type": "function", "function": {"name": "f", "arguments": "{}"}}
+                ]},
+                {"role": "tool", "tool_call_id": "c1", "content": "x".repeat(3500)},
+                {"role": "user", "content": "y".repeat(1750)}
+            ]
+        });
+        let counts = TokenCountConfig::default();
+        let dropped = fit_with(&guard(true), &counts, "m", &mut payload, Provider::OpenAI);
+        assert_eq!(dropped.unwrap(), 3);
+        let roles: Vec<_> = payload["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["system", "user"]);
+    }
+
+    #[test]
+    fn test_anthropic_keeps_starting_with_user() {
+        let payload = json!({
+            "model": "m",
+            "max_tokens": 500,
+            "messages": [
+                {"role": "user", "content": "x".repeat(1750)},
+                {"role": "assistant", "content": "ok"},
+                {"role": "user", "content": "y".repeat(2800)}
+            ]
+        });
+        let counts = TokenCountConfig::default();
+        let mut truncated = payload.clone();
+        let dropped = fit_with(
+            &guard(true),
+            &counts,
+            "m",
+            &mut truncated,
+            Provider::Anthropic,
+        );
+        assert_eq!(dropped.unwrap(), 2);
+        assert_eq!(truncated["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(truncated["messages"][0]["role"], "user");
+
+        let mut rejected = payload.clone();
+        let err = fit_with(
+            &guard(false),
+            &counts,
+            "m",
+            &mut rejected,
+            Provider::Anthropic,
+        );
+        assert!(matches!(err, Err(ForwardError::InvalidRequest(m)) if m.contains("tokens over")));
+        assert_eq!(rejected, payload);
+    }
+
+    #[test]
+    fn test_last_turn_is_never_dropped() {
+        let mut payload = json!({
+            "contents": [
+                {"role": "user", "parts": [{"text": "hi"}]},
+                {"role": "model", "parts": [{"text": "hello"}]},
+                {"role": "user", "parts": [{"text": "z".repeat(7000)}]}
+            ]
+        });
+        let counts = TokenCountConfig::default();
+        let result = fit_with(&guard(true), &counts, "m", &mut payload, Provider::Gemini);
+        assert!(matches!(result, Err(ForwardError::InvalidRequest(_))));
+        assert_eq!(payload["contents"].as_array().unwrap().len(), 3);
+    }
+
+    /// A task, three long tool round trips and a short last one.
+    fn tool_loop(format: Provider) -> Value {
+        let long = "r".repeat(1750);
+        let mut turns = Vec::new();
+        for (i, result) in [long.as_str(), &long, &long, "sunny"]
+            .into_iter()
+            .enumerate()
+        {
+            let id = format!("call_{}", i);
+            turns.extend(match format {
+                Provider::Gemini => [
+                    json!({"role": "model", "parts": [
+                        {"functionCall": {"name": "weather", "args": {}}}
+                    ]}),
+                    json!({"role": "user", "parts": [
+                        {"functionResponse": {"name": "weather", "response": {"text": result}}}
+                    ]}),
+                ],
+                _ => [
+                    json!({"role": "assistant", "content": [
+                        {"type": "tool_use", "id": id, "name": "weather", "input": {}}
+                    ]}),
+                    json!({"role": "user", "content": [
+                        {"type": "tool_result", "tool_use_id": id, "content": result}
+                    ]}),
+                ],
+            });
+        }
+        let task = match format {
+            Provider::Gemini => json!({"role": "user", "parts": [{"text": "plan the trip"}]}),
+            _ => json!({"role": "user", "content": "plan the trip"}),
+        };
+        turns.insert(0, task);
+        match format {
+            Provider::Gemini => json!({"contents": turns}),
+            _ => json!({"model": "m", "max_tokens": 500, "messages": turns}),
+        }
+    }
+
+    #[test]
+    fn test_tool_loops_keep_their_opening_turn() {
+        let counts = TokenCountConfig::default();
+        for (format, key) in [
+            (Provider::Anthropic, "messages"),
+            (Provider::Gemini, "contents"),
+        ] {
+            let mut payload = tool_loop(format);
+            let dropped = fit_with(&guard(true), &counts, "m", &mut payload, format);
+            // The first two round trips; the third fits with the last one
+            assert_eq!(dropped.unwrap(), 4, "{:?}", format);
+            let kept = payload[key].as_array().unwrap();
+            assert_eq!(kept.len(), 5, "{:?}", format);
+            assert_eq!(kept[0]["role"], "user");
+            assert_eq!(kept[0], tool_loop(format)[key][0]);
+            assert_ne!(kept[1]["role"], "user");
+        }
+    }
+}
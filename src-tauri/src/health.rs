@@ -0,0 +1,325 @@
+//! Detailed health report for monitoring (`GET /api/health/detail`).
+//!
+//! Each check carries its own status so an alert can name what is wrong:
+//! the database, the configuration, an upstream, the stream count, the
+//! number of requests in flight, the queues of limited models and
+//! upstreams, the network or the disk. The report's status is the worst of
+//! them. `/health` stays the cheap check for load balancers.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config;
+use crate::forward::{client, inflight, outcomes, queue};
+
+/// A write lock that takes longer than this means the database is contended.
+const SLOW_DB: Duration = Duration::from_secs(1);
+const LOW_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+const CRITICAL_DISK_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Ordered so the worst status compares greatest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checked_at: i64,
+    pub db: DbCheck,
+    pub config: ConfigCheck,
+    pub upstreams: Vec<UpstreamCheck>,
+    pub streams: StreamsCheck,
+    pub connections: ConnectionsCheck,
+    pub queues: QueuesCheck,
+    pub network: NetworkCheck,
+    pub disk: Vec<DiskCheck>,
+    /// Connection reuse towards upstreams; informational, no status
+    pub upstream_pools: client::PoolStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DbCheck {
+    pub status: Status,
+    /// Time to open the database, query it and take the write lock
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigCheck {
+    pub status: Status,
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<config::ValidationIssue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamCheck {
+    pub id: String,
+    pub status: Status,
+    #[serde(flatten)]
+    pub outcome: outcomes::Outcome,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamsCheck {
+    pub status: Status,
+    pub active: usize,
+}
+
+/// Warns while `server.max_connections` is reached.
+#[derive(Debug, Serialize)]
+pub struct ConnectionsCheck {
+    pub status: Status,
+    pub max: usize,
+    pub max_per_ip: usize,
+    #[serde(flatten)]
+    pub counts: crate::connections::Snapshot,
+}
+
+/// Warns while requests wait for a model or upstream at its
+/// `max_concurrency`.
+#[derive(Debug, Serialize)]
+pub struct QueuesCheck {
+    pub status: Status,
+    pub waiting: usize,
+    pub lanes: Vec<queue::QueueStats>,
+}
+
+/// Warns while offline; only upstreams on the local network can be reached.
+#[derive(Debug, Serialize)]
+pub struct NetworkCheck {
+    pub status: Status,
+    #[serde(flatten)]
+    pub state: crate::network::NetworkState,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskCheck {
+    pub path: String,
+    pub status: Status,
+    pub free_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+/// Run every check. Blocking: it touches the database and the file system.
+pub fn report() -> Report {
+    let cfg = config::load();
+    let db = db_check();
+    let config = config_check(&cfg);
+    let upstreams: Vec<UpstreamCheck> = cfg
+        .upstreams
+        .iter()
+        .map(|up| upstream_check(&up.id, outcomes::get(&up.id)))
+        .collect();
+    let streams = StreamsCheck {
+        status: Status::Ok,
+        active: inflight::active_streams().len(),
+    };
+    let counts = crate::connections::snapshot();
+    let connections = ConnectionsCheck {
+        status: if cfg.server.max_connections > 0 && counts.active >= cfg.server.max_connections {
+            Status::Warn
+        } else {
+            Status::Ok
+        },
+        max: cfg.server.max_connections,
+        max_per_ip: cfg.server.max_connections_per_ip,
+        counts,
+    };
+    let lanes = queue::stats();
+    let waiting = lanes.iter().map(|l| l.waiting).sum();
+    let queues = QueuesCheck {
+        status: if waiting > 0 {
+            Status::Warn
+        } else {
+            Status::Ok
+        },
+        waiting,
+        lanes,
+    };
+    let state = crate::network::state();
+    let network = NetworkCheck {
+        status: if state.online {
+            Status::Ok
+        } else {
+            Status::Warn
+        },
+        state,
+    };
+    let mut dirs: Vec<PathBuf> = vec![crate::profile::dir(), crate::logger::log_dir()];
+    dirs.dedup();
+    let disk: Vec<DiskCheck> = dirs.iter().map(|d| disk_check(d)).collect();
+
+    let status = [
+        db.status,
+        config.status,
+        streams.status,
+        connections.status,
+        queues.status,
+        network.status,
+    ]
+    .into_iter()
+    .chain(upstreams.iter().map(|u| u.status))
+    .chain(disk.iter().map(|d| d.status))
+    .max()
+    .unwrap_or(Status::Ok);
+    Report {
+        status,
+        checked_at: chrono::Utc::now().timestamp(),
+        db,
+        config,
+        upstreams,
+        streams,
+        connections,
+        queues,
+        network,
+        disk,
+        upstream_pools: client::pool_stats(),
+    }
+}
+
+fn db_check() -> DbCheck {
+    match crate::db::probe() {
+        Ok(latency) => DbCheck {
+            status: if latency > SLOW_DB {
+                Status::Warn
+            } else {
+                Status::Ok
+            },
+            latency_ms: Some(latency.as_millis() as u64),
+            error: None,
+        },
+        Err(e) => DbCheck {
+            status: Status::Error,
+            latency_ms: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn config_check(cfg: &config::Settings) -> ConfigCheck {
+    let issues = config::validate(cfg);
+    let errors = issues
+        .iter()
+        .filter(|i| matches!(i.severity, config::Severity::Error))
+        .count();
+    let warnings = issues.len() - errors;
+    ConfigCheck {
+        status: if errors > 0 {
+            Status::Error
+        } else if warnings > 0 {
+            Status::Warn
+        } else {
+            Status::Ok
+        },
+        errors,
+        warnings,
+        issues,
+    }
+}
+
+fn upstream_check(id: &str, outcome: outcomes::Outcome) -> UpstreamCheck {
+    let status = match outcome.consecutive_failures {
+        0 => Status::Ok,
+        n if n >= outcomes::FAILING_AFTER => Status::Error,
+        _ => Status::Warn,
+    };
+    UpstreamCheck {
+        id: id.to_string(),
+        status,
+        outcome,
+    }
+}
+
+fn disk_check(dir: &Path) -> DiskCheck {
+    let space = disk_space(dir);
+    let status = match space {
+        Some((free, _)) if free < CRITICAL_DISK_BYTES => Status::Error,
+        Some((free, _)) if free < LOW_DISK_BYTES => Status::Warn,
+        Some(_) => Status::Ok,
+        // Not knowing is not worth an alert
+        None => Status::Ok,
+    };
+    DiskCheck {
+        path: dir.display().to_string(),
+        status,
+        free_bytes: space.map(|(free, _)| free),
+        total_bytes: space.map(|(_, total)| total),
+    }
+}
+
+/// Bytes available to this process and total bytes on the volume of `dir`.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn disk_space(dir: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let block = stat.f_frsize as u64;
+    Some((stat.f_bavail as u64 * block, stat.f_blocks as u64 * block))
+}
+
+#[cfg(target_os = "windows")]
+fn disk_space(dir: &Path) -> Option<(u64, u64)> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows::{core::PCWSTR, Win32::Storage::FileSystem::GetDiskFreeSpaceExW};
+
+    let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+    let (mut free, mut total) = (0u64, 0u64);
+    unsafe {
+        GetDiskFreeSpaceExW(
+            PCWSTR(wide.as_ptr()),
+            Some(&mut free as *mut u64),
+            Some(&mut total as *mut u64),
+            None,
+        )
+    }
+    .ok()?;
+    Some((free, total))
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+fn disk_space(_dir: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_status_follows_failures() {
+        let outcome = |n| outcomes::Outcome {
+            consecutive_failures: n,
+            ..Default::default()
+        };
+        assert_eq!(upstream_check("a", outcome(0)).status, Status::Ok);
+        assert_eq!(upstream_check("a", outcome(1)).status, Status::Warn);
+        assert_eq!(
+            upstream_check("a", outcome(outcomes::FAILING_AFTER)).status,
+            Status::Error
+        );
+        assert!(Status::Error > Status::Warn && Status::Warn > Status::Ok);
+    }
+
+    #[test]
+    fn test_disk_space_of_temp_dir() {
+        let check = disk_check(&std::env::temp_dir());
+        if cfg!(unix) {
+            let (free, total) = (check.free_bytes.unwrap(), check.total_bytes.unwrap());
+            assert!(total > 0 && free <= total);
+        }
+    }
+}
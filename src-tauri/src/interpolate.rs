@@ -0,0 +1,192 @@
+//! `${VAR}` expansion for configuration values.
+//!
+//! String values in the settings file may reference environment variables as
+//! `${VAR}` or `${VAR:-default}` (the default applies when the variable is
+//! unset or empty). `$${...}` is an escape that yields a literal `${...}`.
+//!
+//! The file always keeps the templates: `restore` maps expanded values back
+//! onto the templates they came from before anything is written, so secrets
+//! read from the environment never end up on disk.
+
+use serde_json::Value;
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expand one string. Returns the expanded text and the names of required
+/// variables that were not set.
+pub fn expand_str(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> (String, Vec<String>) {
+    let mut out = String::with_capacity(s.len());
+    let mut missing = Vec::new();
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(body) = tail.strip_prefix("${") else {
+            out.push('$');
+            rest = &tail[1..];
+            continue;
+        };
+        let Some(end) = body.find('}') else {
+            out.push_str(tail);
+            rest = "";
+            break;
+        };
+        let inner = &body[..end];
+        let (name, default) = match inner.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (inner, None),
+        };
+        if !is_var_name(name) {
+            out.push_str(&tail[..end + 3]);
+        } else {
+            match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => missing.push(name.to_string()),
+            }
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    (out, missing)
+}
+
+/// Whether `s` contains anything `expand_str` would change.
+fn is_template(s: &str) -> bool {
+    s.contains("${")
+}
+
+fn env_lookup(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Expand every string in `value` in place, collecting the JSON pointer and
+/// names of each unresolved reference.
+pub fn expand_value(value: &mut Value, missing: &mut Vec<(String, Vec<String>)>) {
+    expand_at(value, String::new(), &env_lookup, missing);
+}
+
+fn expand_at(
+    value: &mut Value,
+    path: String,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    missing: &mut Vec<(String, Vec<String>)>,
+) {
+    match value {
+        Value::String(s) if is_template(s) => {
+            let (expanded, names) = expand_str(s, lookup);
+            if !names.is_empty() {
+                missing.push((path, names));
+            }
+            *s = expanded;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                expand_at(item, format!("{}/{}", path, i), lookup, missing);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                expand_at(item, format!("{}/{}", path, key), lookup, missing);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Put templates from `raw` back into `value` wherever `value` still holds
+/// exactly what the template expands to.
+pub fn restore(value: &mut Value, raw: &Value) {
+    restore_with(value, raw, &env_lookup);
+}
+
+fn restore_with(value: &mut Value, raw: &Value, lookup: &dyn Fn(&str) -> Option<String>) {
+    match (value, raw) {
+        (Value::String(s), Value::String(template))
+            if is_template(template) && expand_str(template, lookup).0 == *s =>
+        {
+            *s = template.clone();
+        }
+        (Value::Array(items), Value::Array(raw_items)) => {
+            for (item, raw_item) in items.iter_mut().zip(raw_items) {
+                restore_with(item, raw_item, lookup);
+            }
+        }
+        (Value::Object(map), Value::Object(raw_map)) => {
+            for (key, item) in map.iter_mut() {
+                if let Some(raw_item) = raw_map.get(key) {
+                    restore_with(item, raw_item, lookup);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "KEY" => Some("sk-123".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_str() {
+        assert_eq!(expand_str("${KEY}", &lookup), ("sk-123".into(), vec![]));
+        assert_eq!(
+            expand_str("http://${HOST:-localhost}:8080/${EMPTY:-v1}", &lookup),
+            ("http://localhost:8080/v1".into(), vec![])
+        );
+        assert_eq!(expand_str("$${KEY} $5 ${", &lookup).0, "${KEY} $5 ${");
+        assert_eq!(expand_str("${not-a-var}", &lookup).0, "${not-a-var}");
+        assert_eq!(
+            expand_str("${MISSING}/${KEY}", &lookup),
+            ("/sk-123".into(), vec!["MISSING".to_string()])
+        );
+        // Set but empty counts as provided
+        assert_eq!(expand_str("${EMPTY}", &lookup), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn test_expand_and_restore_value() {
+        let raw = json!({
+            "upstreams": [{"api_key": "${KEY}", "endpoints": ["${BASE}"], "id": "$${id}"}]
+        });
+        let mut value = raw.clone();
+        let mut missing = Vec::new();
+        expand_at(&mut value, String::new(), &lookup, &mut missing);
+        assert_eq!(value["upstreams"][0]["api_key"], "sk-123");
+        assert_eq!(value["upstreams"][0]["id"], "${id}");
+        assert_eq!(
+            missing,
+            vec![(
+                "/upstreams/0/endpoints/0".to_string(),
+                vec!["BASE".to_string()]
+            )]
+        );
+
+        // An unchanged value goes back to its template; an edited one is kept
+        value["upstreams"][0]["endpoints"][0] = json!("https://edited");
+        restore_with(&mut value, &raw, &lookup);
+        assert_eq!(value["upstreams"][0]["api_key"], "${KEY}");
+        assert_eq!(value["upstreams"][0]["id"], "$${id}");
+        assert_eq!(value["upstreams"][0]["endpoints"][0], "https://edited");
+    }
+}
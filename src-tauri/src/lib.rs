@@ -1,9 +1,190 @@
+use tauri::image::Image;
+use tauri::menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem};
+use tauri::tray::{TrayIcon, TrayIconBuilder};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+
+use crate::relay_state::{Level, RelayState};
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+/// Passed by the login item: start in the tray even without
+/// `desktop.background`.
+const BACKGROUND_ARG: &str = "--background";
+
+fn background_mode() -> bool {
+    starts_in_background(std::env::args(), &crate::config::load().desktop)
+}
+
+/// Whether the app starts in the tray: launched at login with
+/// [`BACKGROUND_ARG`], or always with `desktop.background`.
+fn starts_in_background(
+    mut args: impl Iterator<Item = String>,
+    desktop: &crate::config::DesktopConfig,
+) -> bool {
+    args.any(|arg| arg == BACKGROUND_ARG) || desktop.background
+}
+
+/// Show and focus the main window, creating it first if the app started in
+/// the tray. The window is not created from the config at startup
+/// (`create: false`) so that background mode never opens one.
+fn show_main_window(app: &AppHandle) {
+    let window = match app.get_webview_window("main") {
+        Some(window) => window,
+        None => {
+            let Some(window_config) = app.config().app.windows.first() else {
+                return;
+            };
+            match tauri::WebviewWindowBuilder::from_config(app, window_config)
+                .and_then(|builder| builder.build())
+            {
+                Ok(window) => window,
+                Err(e) => {
+                    crate::logger::error("app", &format!("Failed to open the window: {}", e));
+                    return;
+                }
+            }
+        }
+    };
+    let _ = window.unminimize();
+    let _ = window.show();
+    let _ = window.set_focus();
+}
+
+/// Tray icon showing the relay state, with quick actions. The icon gets a
+/// coloured dot: green running, orange when an upstream keeps failing,
+/// yellow near a budget limit, red on error and grey while stopped.
+fn setup_tray(app: &tauri::App) -> tauri::Result<()> {
+    let status = MenuItem::with_id(app, "status", "Starting", false, None::<&str>)?;
+    let lan = CheckMenuItem::with_id(app, "lan", "Allow LAN access", true, false, None::<&str>)?;
+    let menu = Menu::with_items(
+        app,
+        &[
+            &status,
+            &PredefinedMenuItem::separator(app)?,
+            &MenuItem::with_id(app, "copy_url", "Copy endpoint URL", true, None::<&str>)?,
+            &MenuItem::with_id(app, "copy_token", "Copy forward token", true, None::<&str>)?,
+            &lan,
+            &PredefinedMenuItem::separator(app)?,
+            &MenuItem::with_id(app, "open", "Open dashboard", true, None::<&str>)?,
+            &MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?,
+        ],
+    )?;
+    let base = app
+        .default_window_icon()
+        .map(|icon| icon.clone().to_owned());
+    let lan_item = lan.clone();
+    let mut builder = TrayIconBuilder::with_id("relay")
+        .tooltip("AI relay")
+        .menu(&menu)
+        .on_menu_event(move |app, event| on_tray_menu(app, event.id().as_ref(), &lan_item));
+    if let Some(base) = base.as_ref() {
+        builder = builder.icon(badged(base, Level::Starting));
+    }
+    let tray = builder.build(app)?;
+
+    tauri::async_runtime::spawn(relay_state::run());
+    let mut states = relay_state::subscribe();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let state = states.borrow_and_update().clone();
+            update_tray(&tray, base.as_ref(), &status, &lan, &state);
+            if states.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn update_tray(
+    tray: &TrayIcon,
+    base: Option<&Image<'static>>,
+    status: &MenuItem<tauri::Wry>,
+    lan: &CheckMenuItem<tauri::Wry>,
+    state: &RelayState,
+) {
+    let summary = state.summary();
+    let first_line = summary.lines().next().unwrap_or_default();
+    let result = tray
+        .set_tooltip(Some(format!("AI relay\n{}", summary)))
+        .and_then(|_| status.set_text(first_line))
+        .and_then(|_| lan.set_checked(state.lan))
+        .and_then(|_| match base {
+            Some(base) => tray.set_icon(Some(badged(base, state.level))),
+            None => Ok(()),
+        });
+    if let Err(e) = result {
+        crate::logger::debug("tray", &format!("Failed to update tray: {}", e));
+    }
+}
+
+fn on_tray_menu(app: &AppHandle, id: &str, lan: &CheckMenuItem<tauri::Wry>) {
+    match id {
+        "copy_url" => match crate::server::info().url {
+            Some(url) => copy(app, url),
+            None => crate::logger::info("tray", "The server is not running"),
+        },
+        "copy_token" => match crate::config::load().forward_token {
+            Some(token) if !token.is_empty() => copy(app, token),
+            _ => crate::logger::info("tray", "No forward token is set"),
+        },
+        "lan" => {
+            let lan = lan.clone();
+            tauri::async_runtime::spawn(async move {
+                let was_lan = crate::server::lan_reachable(&crate::config::load().server);
+                let host = if was_lan { "127.0.0.1" } else { "0.0.0.0" };
+                if let Err(e) = commands::restart_server(Some(host.to_string()), None).await {
+                    crate::logger::error("tray", &format!("Failed to switch LAN access: {}", e));
+                }
+                let lan_now = crate::server::lan_reachable(&crate::config::load().server);
+                let _ = lan.set_checked(lan_now);
+            });
+        }
+        "open" => show_main_window(app),
+        // Exiting runs the server shutdown below, which lets in-flight
+        // requests finish
+        "quit" => app.exit(0),
+        _ => {}
+    }
+}
+
+fn copy(app: &AppHandle, text: String) {
+    if let Err(e) = app.clipboard().write_text(text) {
+        crate::logger::warn("tray", &format!("Failed to copy to the clipboard: {}", e));
+    }
+}
+
+/// `base` with a dot in the bottom right corner in the colour of `level`.
+fn badged(base: &Image<'_>, level: Level) -> Image<'static> {
+    let color: [u8; 4] = match level {
+        Level::Running => [46, 160, 67, 255],
+        Level::Offline => [96, 125, 139, 255],
+        Level::Degraded => [230, 126, 34, 255],
+        Level::BudgetWarning => [219, 171, 9, 255],
+        Level::Error => [218, 54, 51, 255],
+        Level::Starting | Level::Stopped => [128, 128, 128, 255],
+    };
+    let (width, height) = (base.width(), base.height());
+    let mut rgba = base.rgba().to_vec();
+    let radius = width.min(height) as f32 / 4.0;
+    let (cx, cy) = (width as f32 - radius, height as f32 - radius);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            if dx * dx + dy * dy <= radius * radius {
+                let i = ((y * width + x) * 4) as usize;
+                rgba[i..i + 4].copy_from_slice(&color);
+            }
+        }
+    }
+    Image::new_owned(rgba, width, height)
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     // Set up panic hook to log panics before they crash the app
@@ -39,23 +220,163 @@ pub fn run() {
     crate::db::init();
     crate::logger::init();
     crate::logger::info("app", "Application started");
-    crate::server::spawn();
+    let mut server = Some(crate::server::spawn());
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
-        .invoke_handler(tauri::generate_handler![greet])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .plugin(tauri_plugin_clipboard_manager::init())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_autostart::init(
+            tauri_plugin_autostart::MacosLauncher::LaunchAgent,
+            Some(vec![BACKGROUND_ARG]),
+        ))
+        .setup(|app| {
+            commands::set_app_handle(app.handle().clone());
+            commands::sync_autostart();
+            commands::spawn_log_events();
+            commands::spawn_server_events();
+            commands::spawn_activity_events();
+            commands::spawn_notifications();
+            setup_tray(app)?;
+            if !background_mode() {
+                show_main_window(app.handle());
+            }
+            Ok(())
+        })
+        .on_window_event(|window, event| match event {
+            tauri::WindowEvent::CloseRequested { api, .. }
+                if crate::config::load().desktop.background =>
+            {
+                api.prevent_close();
+                let _ = window.hide();
+            }
+            tauri::WindowEvent::DragDrop(tauri::DragDropEvent::Drop { paths, .. }) => {
+                commands::on_file_drop(paths);
+            }
+            _ => {}
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            commands::get_usage_summary,
+            commands::get_top_models,
+            commands::get_recent_errors,
+            commands::get_active_streams,
+            commands::get_queue_stats,
+            commands::get_upstream_ratelimits,
+            commands::get_activity,
+            commands::get_key_usage,
+            commands::export_usage_file,
+            commands::get_budget_status,
+            commands::get_experiment_report,
+            commands::end_experiment,
+            commands::run_maintenance,
+            commands::preview_model_prices,
+            commands::refresh_model_prices,
+            commands::test_webhook,
+            commands::reload_config,
+            commands::export_config_file,
+            commands::import_config_file,
+            commands::preview_import_file,
+            commands::apply_import_file,
+            commands::export_diagnostics,
+            commands::list_profiles,
+            commands::create_profile,
+            commands::switch_profile,
+            commands::set_log_levels,
+            commands::rotate_logs_now,
+            commands::open_log_folder,
+            commands::get_live_logs,
+            commands::restart_server,
+            commands::get_server_info,
+            commands::server_status,
+            commands::start_server,
+            commands::stop_server,
+            commands::discover_relays,
+            commands::adopt_relay,
+            commands::list_upstreams,
+            commands::save_upstream,
+            commands::delete_upstream,
+            commands::list_models,
+            commands::save_model,
+            commands::delete_model,
+            commands::list_projects,
+            commands::get_project,
+            commands::create_project,
+            commands::update_project,
+            commands::delete_project,
+            commands::get_settings,
+            commands::save_settings,
+            commands::get_editor_snippet,
+            commands::copy_editor_snippet,
+            commands::preview_autoconfig,
+            commands::apply_autoconfig,
+            commands::revert_autoconfig,
+            commands::verify_autoconfig,
+            commands::set_autostart
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(move |_app, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Some(handle) = server.take() {
+                    tauri::async_runtime::block_on(handle.shutdown());
+                }
+                crate::server::shutdown();
+            }
+        });
 }
+mod access_log;
 mod adapters;
 mod autoconfig;
+mod bundle;
+mod coalesce;
+mod commands;
 mod config;
+mod connections;
 mod db;
+mod diagnostics;
+mod discovery;
 mod error;
 mod forward;
+mod health;
+mod interpolate;
 pub mod logger;
+mod maintenance;
+mod network;
+mod notifications;
+mod price_sync;
 mod pricing;
+mod profile;
 mod projects;
+mod redact;
+mod relay_state;
 mod routing;
 pub mod server;
+mod status_page;
+mod telemetry;
+mod tls;
 mod tools;
+mod webhooks;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DesktopConfig;
+
+    fn starts(args: &[&str], desktop: &DesktopConfig) -> bool {
+        starts_in_background(args.iter().map(|arg| arg.to_string()), desktop)
+    }
+
+    #[test]
+    fn test_starts_in_background() {
+        let desktop = DesktopConfig::default();
+        assert!(!starts(&["ccr"], &desktop));
+        assert!(starts(&["ccr", BACKGROUND_ARG], &desktop));
+        assert!(!starts(&["ccr", "--backgrounds"], &desktop));
+        let background = DesktopConfig {
+            background: true,
+            ..Default::default()
+        };
+        assert!(starts(&["ccr"], &background));
+    }
+}
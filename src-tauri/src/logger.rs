@@ -2,24 +2,74 @@
 //!
 //! 提供统一的日志记录接口，支持日志持久化到 SQLite 数据库。
 //! 使用异步批量写入优化性能。
+//!
+//! Entries can also be appended to `logs/ccr.log` in the data directory, as
+//! plain text or as one JSON object per line (`logging.format`). Which
+//! entries are kept at all is decided by `logging.levels`, a default level
+//! plus per-module overrides such as `info, anthropic=debug`.
+//!
+//! The log file is rolled over once it passes `logging.max_file_mb` or
+//! `logging.max_age_hours`; rolled files are gzipped to
+//! `ccr-<timestamp>.log.gz` and only the newest `logging.keep_archives` are
+//! kept. All file access happens under one lock, so rotation never races a
+//! writer.
+//!
+//! Messages, metadata and string fields are masked with
+//! [`crate::redact::text`] before they reach any sink.
+//!
+//! Entries logged while a forwarded request is handled (see
+//! [`with_request_id`]) carry its request id, so every line of one request
+//! can be pulled with [`logs_for_request`].
+//!
+//! Every entry is also published to live subscribers ([`subscribe`]) and
+//! kept in a small ring buffer so a new subscriber starts with the most
+//! recent lines. Publishing never waits: a subscriber that falls more than
+//! `LIVE_CHANNEL_CAPACITY` entries behind is cut off.
+//!
+//! Levels listed in `logging.dedup_levels` are deduplicated: once an entry
+//! is written, further entries with the same level, module and message
+//! template (digits ignored) are dropped for `logging.dedup_window_secs`.
+//! When the window closes a single `(repeated N times)` entry reports how
+//! many were dropped. Debug entries are never deduplicated.
 
 use dirs::data_dir;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::Write;
 use std::path::PathBuf;
-use std::sync::{mpsc, Once, RwLock};
-use std::time::{Duration, Instant};
+use std::sync::{mpsc, Mutex, Once, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::redact;
 
 static INIT: Once = Once::new();
 
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
 // Log message for batching
 #[derive(Debug, Clone)]
 struct LogMessage {
-    timestamp: i64,
-    level: String,
+    at: chrono::DateTime<chrono::Utc>,
+    level: LogLevel,
     source: String,
     message: String,
     metadata: Option<String>,
+    /// Structured fields from `log_with_fields`; also stored as `metadata`
+    fields: Option<serde_json::Map<String, Value>>,
+}
+
+impl LogMessage {
+    fn request_id(&self) -> Option<&str> {
+        self.fields.as_ref()?.get("request_id")?.as_str()
+    }
 }
 
 // Async log channel sender
@@ -29,7 +79,7 @@ static LOG_SENDER: RwLock<Option<mpsc::Sender<LogMessage>>> = RwLock::new(None);
 // Log Level & Entry Types
 // ============================================
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Debug,
@@ -59,6 +109,345 @@ impl LogLevel {
     }
 }
 
+/// Minimum level per module, parsed from a spec like `info, anthropic=debug`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelFilter {
+    default: LogLevel,
+    modules: Vec<(String, LogLevel)>,
+}
+
+impl Default for LevelFilter {
+    fn default() -> Self {
+        Self {
+            default: LogLevel::Debug,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LevelFilter {
+    /// Parse comma-separated `level` and `module=level` items. A bare level
+    /// sets the default for modules without an override.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = LevelFilter::default();
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (module, level) = match item.split_once('=') {
+                Some((module, level)) => (Some(module.trim()), level.trim()),
+                None => (None, item),
+            };
+            let level = LogLevel::from_str(level).ok_or_else(|| {
+                format!(
+                    "unknown log level '{}', expected debug, info, warn or error",
+                    level
+                )
+            })?;
+            match module {
+                Some("") => return Err(format!("missing module name in '{}'", item)),
+                Some(module) => filter.modules.push((module.to_lowercase(), level)),
+                None => filter.default = level,
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn allows(&self, module: &str, level: LogLevel) -> bool {
+        let min = self
+            .modules
+            .iter()
+            .rev()
+            .find(|(m, _)| m.eq_ignore_ascii_case(module))
+            .map(|(_, l)| *l)
+            .unwrap_or(self.default);
+        level >= min
+    }
+}
+
+/// Where and how entries are written, from `config::LoggingConfig`.
+struct Output {
+    filter: LevelFilter,
+    json: bool,
+    file: bool,
+    rotation: Rotation,
+}
+
+/// When the log file is rolled over and how many archives survive.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rotation {
+    max_bytes: u64,
+    /// Zero disables age-based rotation
+    max_age: Duration,
+    keep_archives: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        let cfg = crate::config::LoggingConfig::default();
+        Rotation::from_config(&cfg)
+    }
+}
+
+impl Rotation {
+    pub(crate) fn from_config(cfg: &crate::config::LoggingConfig) -> Self {
+        Self {
+            max_bytes: cfg.max_file_mb.max(1) * 1024 * 1024,
+            max_age: Duration::from_secs(cfg.max_age_hours * 3600),
+            keep_archives: cfg.keep_archives,
+        }
+    }
+
+    fn due(&self, size: u64, opened: SystemTime) -> bool {
+        let too_old = !self.max_age.is_zero()
+            && opened
+                .elapsed()
+                .map(|age| age >= self.max_age)
+                .unwrap_or(false);
+        size >= self.max_bytes || too_old
+    }
+}
+
+/// The open log file, with what rotation needs to know about it.
+struct ActiveFile {
+    file: std::fs::File,
+    size: u64,
+    opened: SystemTime,
+}
+
+/// `<stem>.log` in the log directory, rolled over into gzipped
+/// `<stem>-<timestamp>.log.gz` archives.
+pub(crate) struct RotatingFile {
+    stem: &'static str,
+    active: Mutex<Option<ActiveFile>>,
+}
+
+static OUTPUT: RwLock<Option<Output>> = RwLock::new(None);
+static LOG_FILE: RotatingFile = RotatingFile::new("ccr");
+
+/// Apply logging settings. Called whenever a configuration is installed, so
+/// level changes take effect without a restart.
+pub fn configure(cfg: &crate::config::LoggingConfig) {
+    let filter = LevelFilter::parse(&cfg.levels).unwrap_or_else(|e| {
+        eprintln!("Invalid logging.levels ({}), logging everything", e);
+        LevelFilter::default()
+    });
+    *OUTPUT.write().unwrap_or_else(|e| e.into_inner()) = Some(Output {
+        filter,
+        json: cfg.format.eq_ignore_ascii_case("json"),
+        file: cfg.file,
+        rotation: Rotation::from_config(cfg),
+    });
+    if !cfg.file {
+        LOG_FILE.close();
+    }
+    let levels = cfg
+        .dedup_levels
+        .iter()
+        .filter_map(|l| LogLevel::from_str(l))
+        .filter(|l| *l != LogLevel::Debug)
+        .collect();
+    let mut dedup = DEDUP.lock().unwrap_or_else(|e| e.into_inner());
+    dedup.window = Duration::from_secs(cfg.dedup_window_secs);
+    dedup.levels = levels;
+}
+
+fn enabled(source: &str, level: LogLevel) -> bool {
+    OUTPUT
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|o| o.filter.allows(source, level))
+        .unwrap_or(true)
+}
+
+/// Directory holding `ccr.log` and the access log.
+pub fn log_dir() -> PathBuf {
+    let p = crate::profile::base_dir().join("logs");
+    std::fs::create_dir_all(&p).ok();
+    p
+}
+
+fn format_line(msg: &LogMessage, json: bool) -> String {
+    let time = msg.at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    if json {
+        let mut line = serde_json::json!({
+            "timestamp": time,
+            "level": msg.level.as_str(),
+            "module": msg.source,
+            "message": msg.message,
+        });
+        if let Some(fields) = &msg.fields {
+            line["fields"] = Value::Object(fields.clone());
+        } else if let Some(metadata) = &msg.metadata {
+            line["metadata"] = Value::String(metadata.clone());
+        }
+        return line.to_string();
+    }
+    let mut line = format!(
+        "{} {:<5} [{}] {}",
+        time,
+        msg.level.as_str().to_uppercase(),
+        msg.source,
+        msg.message
+    );
+    for (key, value) in msg.fields.iter().flatten() {
+        match value {
+            Value::String(s) => line.push_str(&format!(" {}={}", key, s)),
+            other => line.push_str(&format!(" {}={}", key, other)),
+        }
+    }
+    line
+}
+
+/// Append `messages` to the log file when file output is on, rotating first
+/// if the file is due.
+fn write_to_file(messages: &[LogMessage]) {
+    let (json, rotation) = {
+        let output = OUTPUT.read().unwrap_or_else(|e| e.into_inner());
+        match output.as_ref() {
+            Some(o) if o.file => (o.json, o.rotation),
+            _ => return,
+        }
+    };
+    let mut out = String::new();
+    for msg in messages {
+        out.push_str(&format_line(msg, json));
+        out.push('\n');
+    }
+    LOG_FILE.append(&out, rotation);
+}
+
+impl RotatingFile {
+    pub(crate) const fn new(stem: &'static str) -> Self {
+        Self {
+            stem,
+            active: Mutex::new(None),
+        }
+    }
+
+    fn open(&self, dir: &std::path::Path) -> Option<ActiveFile> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(format!("{}.log", self.stem)))
+            .ok()?;
+        let meta = file.metadata().ok();
+        Some(ActiveFile {
+            size: meta.as_ref().map(|m| m.len()).unwrap_or(0),
+            // Creation time isn't available everywhere; fall back to now
+            opened: meta
+                .and_then(|m| m.created().ok())
+                .unwrap_or_else(SystemTime::now),
+            file,
+        })
+    }
+
+    /// Append `out`, rotating first if the file is due.
+    pub(crate) fn append(&self, out: &str, rotation: Rotation) {
+        let dir = log_dir();
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if active.is_none() {
+            *active = self.open(&dir);
+        }
+        if active
+            .as_ref()
+            .is_some_and(|f| f.size > 0 && rotation.due(f.size + out.len() as u64, f.opened))
+        {
+            *active = None;
+            if let Err(e) = rotate_file(&dir, self.stem, rotation.keep_archives) {
+                eprintln!("Log rotation failed: {}", e);
+            }
+            *active = self.open(&dir);
+        }
+        let Some(f) = active.as_mut() else {
+            return;
+        };
+        if f.file.write_all(out.as_bytes()).is_ok() {
+            f.size += out.len() as u64;
+        }
+    }
+
+    /// Rotate right away, whatever the size.
+    pub(crate) fn rotate(&self, keep_archives: usize) -> Result<Option<PathBuf>, String> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        *active = None;
+        rotate_file(&log_dir(), self.stem, keep_archives)
+    }
+
+    pub(crate) fn close(&self) {
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Move `<stem>.log` to a gzipped archive and prune old archives. The caller
+/// must hold the file's lock with the file closed. Returns the archive path,
+/// or `None` when there was nothing to rotate.
+fn rotate_file(
+    dir: &std::path::Path,
+    stem: &str,
+    keep_archives: usize,
+) -> Result<Option<PathBuf>, String> {
+    let current = dir.join(format!("{}.log", stem));
+    let empty = std::fs::metadata(&current)
+        .map(|m| m.len() == 0)
+        .unwrap_or(true);
+    let archive = if empty {
+        None
+    } else {
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f");
+        let mut archive = dir.join(format!("{}-{}.log.gz", stem, stamp));
+        let mut n = 1;
+        while archive.exists() {
+            archive = dir.join(format!("{}-{}-{}.log.gz", stem, stamp, n));
+            n += 1;
+        }
+        let mut input = std::fs::File::open(&current).map_err(|e| e.to_string())?;
+        let output = std::fs::File::create(&archive).map_err(|e| e.to_string())?;
+        let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+        std::io::copy(&mut input, &mut encoder).map_err(|e| e.to_string())?;
+        encoder.finish().map_err(|e| e.to_string())?;
+        std::fs::remove_file(&current).map_err(|e| e.to_string())?;
+        Some(archive)
+    };
+
+    // Archive names sort by time, so everything after the newest N goes
+    let prefix = format!("{}-", stem);
+    let mut archives: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(&prefix) && n.ends_with(".log.gz"))
+        })
+        .collect();
+    archives.sort();
+    archives.reverse();
+    for old in archives.iter().skip(keep_archives) {
+        let _ = std::fs::remove_file(old);
+    }
+    Ok(archive)
+}
+
+/// Rotate the log file right away, whatever its size. Returns the archive
+/// that was written, if the file had any content.
+pub fn rotate_now() -> Result<Option<PathBuf>, String> {
+    let keep = OUTPUT
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|o| o.rotation)
+        .unwrap_or_default()
+        .keep_archives;
+    // Logged below, so the file lock must be released first
+    let archive = LOG_FILE.rotate(keep)?;
+    if let Some(archive) = &archive {
+        info(
+            "logger",
+            &format!("Rotated log file to {}", archive.display()),
+        );
+    }
+    Ok(archive)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
     pub id: i64,
@@ -67,6 +456,7 @@ pub struct LogEntry {
     pub source: String,
     pub message: String,
     pub metadata: Option<String>,
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -185,6 +575,18 @@ pub fn init() {
             "CREATE INDEX IF NOT EXISTS idx_global_logs_source ON global_logs(source)",
             [],
         ).ok();
+        // Added after the original schema
+        let has_request_id = conn
+            .prepare("SELECT request_id FROM global_logs LIMIT 0")
+            .is_ok();
+        if !has_request_id {
+            conn.execute("ALTER TABLE global_logs ADD COLUMN request_id TEXT", [])
+                .ok();
+        }
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_global_logs_request_id ON global_logs(request_id)",
+            [],
+        ).ok();
 
         // 安装日志表
         conn.execute(
@@ -240,11 +642,13 @@ fn spawn_batch_writer() {
                 Ok(msg) => {
                     buffer.push(msg);
                     if buffer.len() >= 100 || last_flush.elapsed() >= flush_interval {
+                        add_closed_windows(&mut buffer);
                         flush_logs(&mut buffer);
                         last_flush = Instant::now();
                     }
                 }
                 Err(mpsc::RecvTimeoutError::Timeout) => {
+                    add_closed_windows(&mut buffer);
                     if !buffer.is_empty() {
                         flush_logs(&mut buffer);
                     }
@@ -268,77 +672,412 @@ fn flush_logs(buffer: &mut Vec<LogMessage>) {
         return;
     }
 
+    write_to_file(buffer);
+
     let mut conn = open_conn();
     let tx = conn.transaction().unwrap();
 
     for msg in buffer.drain(..) {
         let _ = tx.execute(
-            "INSERT INTO global_logs (timestamp, level, source, message, metadata) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![msg.timestamp, msg.level, msg.source, msg.message, msg.metadata],
+            "INSERT INTO global_logs (timestamp, level, source, message, metadata, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![msg.at.timestamp(), msg.level.as_str(), msg.source, msg.message, msg.metadata, msg.request_id()],
         );
     }
 
     let _ = tx.commit();
 }
 
+// ============================================
+// Live Tail
+// ============================================
+
+/// Entries kept for subscribers that connect later.
+const LIVE_BACKLOG: usize = 500;
+/// How far a subscriber may fall behind before it is dropped.
+pub const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+/// An entry as sent to live subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveEntry {
+    /// Unix milliseconds
+    pub timestamp: i64,
+    pub level: LogLevel,
+    pub module: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<serde_json::Map<String, Value>>,
+}
+
+/// Which entries a subscriber wants: at least `level`, from `module` only.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LiveFilter {
+    pub level: Option<LogLevel>,
+    pub module: Option<String>,
+}
+
+impl LiveFilter {
+    pub fn matches(&self, entry: &LiveEntry) -> bool {
+        self.level.is_none_or(|level| entry.level >= level)
+            && self
+                .module
+                .as_deref()
+                .filter(|m| !m.is_empty())
+                .is_none_or(|m| entry.module.eq_ignore_ascii_case(m))
+    }
+}
+
+struct Live {
+    sender: broadcast::Sender<LiveEntry>,
+    /// Guards `recent` and orders it with the channel, so a subscriber gets
+    /// every entry exactly once across backlog and channel.
+    recent: Mutex<VecDeque<LiveEntry>>,
+}
+
+static LIVE: Lazy<Live> = Lazy::new(|| Live {
+    sender: broadcast::channel(LIVE_CHANNEL_CAPACITY).0,
+    recent: Mutex::new(VecDeque::with_capacity(LIVE_BACKLOG)),
+});
+
+fn publish(msg: &LogMessage) {
+    let entry = LiveEntry {
+        timestamp: msg.at.timestamp_millis(),
+        level: msg.level,
+        module: msg.source.clone(),
+        message: msg.message.clone(),
+        fields: msg.fields.clone(),
+    };
+    let mut recent = LIVE.recent.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == LIVE_BACKLOG {
+        recent.pop_front();
+    }
+    recent.push_back(entry.clone());
+    // Fails only when nobody is subscribed
+    let _ = LIVE.sender.send(entry);
+}
+
+/// The buffered entries matching `filter` (oldest first, at most `backlog`)
+/// and a receiver for everything logged after them.
+pub fn subscribe(
+    filter: &LiveFilter,
+    backlog: usize,
+) -> (Vec<LiveEntry>, broadcast::Receiver<LiveEntry>) {
+    let recent = LIVE.recent.lock().unwrap_or_else(|e| e.into_inner());
+    let receiver = LIVE.sender.subscribe();
+    let mut entries: Vec<LiveEntry> = recent
+        .iter()
+        .rev()
+        .filter(|e| filter.matches(e))
+        .take(backlog)
+        .cloned()
+        .collect();
+    entries.reverse();
+    (entries, receiver)
+}
+
+// ============================================
+// Repeat Suppression
+// ============================================
+
+/// Repeats of one entry seen since its window opened.
+struct Repeats {
+    opened: Instant,
+    suppressed: u64,
+    /// Latest suppressed message, reported when the window closes
+    last: String,
+}
+
+/// A window that closed with entries dropped.
+#[derive(Debug, PartialEq)]
+struct Closed {
+    level: LogLevel,
+    source: String,
+    message: String,
+    repeats: u64,
+    window: Duration,
+}
+
+/// Open suppression windows, keyed by level, module and message template.
+struct Dedup {
+    window: Duration,
+    levels: Vec<LogLevel>,
+    open: HashMap<(LogLevel, String, String), Repeats>,
+}
+
+static DEDUP: Lazy<Mutex<Dedup>> = Lazy::new(|| {
+    Mutex::new(Dedup {
+        window: Duration::ZERO,
+        levels: Vec::new(),
+        open: HashMap::new(),
+    })
+});
+
+/// `message` with every run of digits replaced by `#`, so entries that only
+/// differ in counts, ports or ids count as the same.
+fn template(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut in_digits = false;
+    for c in message.chars() {
+        if c.is_ascii_digit() {
+            if !in_digits {
+                out.push('#');
+            }
+            in_digits = true;
+        } else {
+            out.push(c);
+            in_digits = false;
+        }
+    }
+    out
+}
+
+impl Dedup {
+    /// Whether an entry should be written, plus the window it closed, if any.
+    fn admit(
+        &mut self,
+        level: LogLevel,
+        source: &str,
+        message: &str,
+        now: Instant,
+    ) -> (bool, Option<Closed>) {
+        if self.window.is_zero() || !self.levels.contains(&level) {
+            return (true, None);
+        }
+        let key = (level, source.to_string(), template(message));
+        let fresh = Repeats {
+            opened: now,
+            suppressed: 0,
+            last: String::new(),
+        };
+        match self.open.get_mut(&key) {
+            Some(repeats) if now.duration_since(repeats.opened) < self.window => {
+                repeats.suppressed += 1;
+                repeats.last = message.to_string();
+                (false, None)
+            }
+            Some(repeats) => {
+                let old = std::mem::replace(repeats, fresh);
+                (true, closed(key, old, self.window))
+            }
+            None => {
+                self.open.insert(key, fresh);
+                (true, None)
+            }
+        }
+    }
+
+    /// Close every window that has run its course.
+    fn expire(&mut self, now: Instant) -> Vec<Closed> {
+        let window = self.window;
+        let due: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(_, r)| now.duration_since(r.opened) >= window)
+            .map(|(k, _)| k.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|key| {
+                let repeats = self.open.remove(&key)?;
+                closed(key, repeats, window)
+            })
+            .collect()
+    }
+}
+
+fn closed(
+    (level, source, _): (LogLevel, String, String),
+    repeats: Repeats,
+    window: Duration,
+) -> Option<Closed> {
+    (repeats.suppressed > 0).then_some(Closed {
+        level,
+        source,
+        message: repeats.last,
+        repeats: repeats.suppressed,
+        window,
+    })
+}
+
+impl Closed {
+    fn into_message(self) -> LogMessage {
+        let mut fields = serde_json::Map::new();
+        fields.insert("repeats".to_string(), Value::from(self.repeats));
+        let mut msg = new_message(
+            self.level,
+            &self.source,
+            &format!(
+                "{} (repeated {} times in {}s)",
+                self.message,
+                self.repeats,
+                self.window.as_secs()
+            ),
+            None,
+            Some(fields),
+        );
+        // The summary spans many requests, so it belongs to none of them
+        if let Some(fields) = msg.fields.as_mut() {
+            fields.remove("request_id");
+            fields.remove("trace_id");
+        }
+        msg.metadata = msg
+            .fields
+            .as_ref()
+            .map(|f| Value::Object(f.clone()).to_string());
+        msg
+    }
+}
+
+/// Queue summaries of the windows that closed without a new repeat, so
+/// counts are reported even once the repeats stop.
+fn add_closed_windows(buffer: &mut Vec<LogMessage>) {
+    let closed = DEDUP
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .expire(Instant::now());
+    for msg in closed.into_iter().map(Closed::into_message) {
+        publish(&msg);
+        buffer.push(msg);
+    }
+}
+
 // ============================================
 // Global Log Functions
 // ============================================
 
 /// 记录日志（内部函数）
-fn log_internal(level: LogLevel, source: &str, message: &str, metadata: Option<&str>) {
-    let timestamp = chrono::Utc::now().timestamp();
-    let msg = LogMessage {
-        timestamp,
-        level: level.as_str().to_string(),
-        source: source.to_string(),
-        message: message.to_string(),
-        metadata: metadata.map(|s| s.to_string()),
-    };
+fn log_internal(
+    level: LogLevel,
+    source: &str,
+    message: &str,
+    metadata: Option<&str>,
+    fields: Option<serde_json::Map<String, Value>>,
+) {
+    if !enabled(source, level) {
+        return;
+    }
+    let (admit, closed) = DEDUP.lock().unwrap_or_else(|e| e.into_inner()).admit(
+        level,
+        source,
+        message,
+        Instant::now(),
+    );
+    if let Some(closed) = closed {
+        send(closed.into_message());
+    }
+    if admit {
+        send(new_message(level, source, message, metadata, fields));
+    }
+}
+
+/// Hand an entry to live subscribers and the batch writer.
+fn send(msg: LogMessage) {
+    publish(&msg);
 
     // Try to send to async channel
     if let Some(sender) = LOG_SENDER.read().unwrap().as_ref() {
         let _ = sender.send(msg);
     } else {
         // Fallback to direct write if channel not initialized
+        write_to_file(std::slice::from_ref(&msg));
         let conn = open_conn();
         let _ = conn.execute(
-            "INSERT INTO global_logs (timestamp, level, source, message, metadata) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![timestamp, level.as_str(), source, message, metadata],
+            "INSERT INTO global_logs (timestamp, level, source, message, metadata, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![msg.at.timestamp(), msg.level.as_str(), msg.source, msg.message, msg.metadata, msg.request_id()],
         );
     }
 }
 
+/// Build an entry with credentials masked and the current request and trace
+/// ids, if any, added to its fields.
+fn new_message(
+    level: LogLevel,
+    source: &str,
+    message: &str,
+    metadata: Option<&str>,
+    mut fields: Option<serde_json::Map<String, Value>>,
+) -> LogMessage {
+    let _ = REQUEST_ID.try_with(|id| {
+        fields
+            .get_or_insert_with(serde_json::Map::new)
+            .entry("request_id")
+            .or_insert_with(|| Value::String(id.clone()));
+    });
+    if let Some(trace_id) = crate::telemetry::current_trace_id() {
+        fields
+            .get_or_insert_with(serde_json::Map::new)
+            .entry("trace_id")
+            .or_insert(Value::String(trace_id));
+    }
+    LogMessage {
+        at: chrono::Utc::now(),
+        level,
+        source: source.to_string(),
+        message: redact::text(message),
+        metadata: metadata.map(redact::text),
+        fields: fields.map(|fields| {
+            fields
+                .into_iter()
+                .map(|(k, v)| match v {
+                    Value::String(s) => (k, Value::String(redact::text(&s))),
+                    v => (k, v),
+                })
+                .collect()
+        }),
+    }
+}
+
 /// 记录 DEBUG 级别日志
 pub fn debug(source: &str, message: &str) {
-    log_internal(LogLevel::Debug, source, message, None);
+    log_internal(LogLevel::Debug, source, message, None, None);
 }
 
 /// 记录 INFO 级别日志
 pub fn info(source: &str, message: &str) {
-    log_internal(LogLevel::Info, source, message, None);
+    log_internal(LogLevel::Info, source, message, None, None);
 }
 
 /// 记录 WARN 级别日志
 pub fn warn(source: &str, message: &str) {
-    log_internal(LogLevel::Warn, source, message, None);
+    log_internal(LogLevel::Warn, source, message, None, None);
 }
 
 /// 记录 ERROR 级别日志
 pub fn error(source: &str, message: &str) {
-    log_internal(LogLevel::Error, source, message, None);
+    log_internal(LogLevel::Error, source, message, None, None);
 }
 
 /// 记录带元数据的日志
 pub fn log_with_metadata(level: LogLevel, source: &str, message: &str, metadata: Option<&str>) {
-    log_internal(level, source, message, metadata);
+    log_internal(level, source, message, metadata, None);
+}
+
+/// Log with key-value fields (request_id, model, upstream, latency_ms, ...).
+/// The fields are stored as the entry's metadata and written as separate
+/// keys in the log file.
+pub fn log_with_fields(level: LogLevel, source: &str, message: &str, fields: &[(&str, Value)]) {
+    let map: serde_json::Map<String, Value> = fields
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+    let metadata = Value::Object(map.clone()).to_string();
+    log_internal(level, source, message, Some(&metadata), Some(map));
+}
+
+/// Run `fut` with `request_id` attached to every entry it logs.
+pub async fn with_request_id<F: Future>(request_id: String, fut: F) -> F::Output {
+    REQUEST_ID.scope(request_id, fut).await
+}
+
+/// Synchronous counterpart of [`with_request_id`], for work done while a
+/// response body is polled.
+pub fn in_request<R>(request_id: &str, f: impl FnOnce() -> R) -> R {
+    REQUEST_ID.sync_scope(request_id.to_string(), f)
 }
 
 /// 查询日志
 pub fn query_logs(query: &LogQuery) -> Vec<LogEntry> {
     let conn = open_conn();
     let mut sql = String::from(
-        "SELECT id, timestamp, level, source, message, metadata FROM global_logs WHERE 1=1",
+        "SELECT id, timestamp, level, source, message, metadata, request_id FROM global_logs WHERE 1=1",
     );
     let mut params_vec: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
 
@@ -369,22 +1108,38 @@ pub fn query_logs(query: &LogQuery) -> Vec<LogEntry> {
     let params_refs: Vec<&dyn rusqlite::ToSql> = params_vec.iter().map(|p| p.as_ref()).collect();
 
     let rows = stmt
-        .query_map(params_refs.as_slice(), |row| {
-            let level_str: String = row.get(2)?;
-            Ok(LogEntry {
-                id: row.get(0)?,
-                timestamp: row.get(1)?,
-                level: LogLevel::from_str(&level_str).unwrap_or(LogLevel::Info),
-                source: row.get(3)?,
-                message: row.get(4)?,
-                metadata: row.get(5)?,
-            })
-        })
+        .query_map(params_refs.as_slice(), log_entry_from_row)
         .unwrap();
 
     rows.filter_map(|r| r.ok()).collect()
 }
 
+fn log_entry_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<LogEntry> {
+    let level_str: String = row.get(2)?;
+    Ok(LogEntry {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        level: LogLevel::from_str(&level_str).unwrap_or(LogLevel::Info),
+        source: row.get(3)?,
+        message: row.get(4)?,
+        metadata: row.get(5)?,
+        request_id: row.get(6)?,
+    })
+}
+
+/// Every entry logged for one request, oldest first.
+pub fn logs_for_request(request_id: &str) -> Vec<LogEntry> {
+    let conn = open_conn();
+    let Ok(mut stmt) = conn.prepare(
+        "SELECT id, timestamp, level, source, message, metadata, request_id FROM global_logs WHERE request_id = ?1 ORDER BY id",
+    ) else {
+        return Vec::new();
+    };
+    stmt.query_map(params![request_id], log_entry_from_row)
+        .map(|rows| rows.filter_map(|r| r.ok()).collect())
+        .unwrap_or_default()
+}
+
 /// 获取日志总数
 pub fn logs_count(query: &LogQuery) -> i64 {
     let conn = open_conn();
@@ -583,3 +1338,234 @@ pub fn install_logs_count() -> i64 {
     let mut stmt = conn.prepare("SELECT COUNT(*) FROM install_logs").unwrap();
     stmt.query_row([], |row| row.get(0)).unwrap_or(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        let filter = LevelFilter::parse("warn, anthropic=debug, Server=info").unwrap();
+        assert!(filter.allows("anthropic", LogLevel::Debug));
+        assert!(filter.allows("server", LogLevel::Info));
+        assert!(!filter.allows("server", LogLevel::Debug));
+        assert!(!filter.allows("openai", LogLevel::Info));
+        assert!(filter.allows("openai", LogLevel::Error));
+
+        assert_eq!(LevelFilter::parse("").unwrap(), LevelFilter::default());
+        assert!(LevelFilter::parse("anthropic=loud").is_err());
+        assert!(LevelFilter::parse("=info").is_err());
+    }
+
+    #[test]
+    fn test_rotate_file_keeps_newest_archives() {
+        let dir = std::env::temp_dir().join(format!("ccr-log-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for stamp in [
+            "20240101-000000-000",
+            "20240102-000000-000",
+            "20240103-000000-000",
+        ] {
+            std::fs::write(dir.join(format!("ccr-{}.log.gz", stamp)), b"old").unwrap();
+        }
+        std::fs::write(dir.join("ccr.log"), "line one\nline two\n").unwrap();
+        // Another file's archives are left alone
+        std::fs::write(dir.join("access-20240101-000000-000.log.gz"), b"old").unwrap();
+
+        let archive = rotate_file(&dir, "ccr", 2).unwrap().unwrap();
+        assert!(!dir.join("ccr.log").exists());
+        let mut text = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(std::fs::File::open(&archive).unwrap()),
+            &mut text,
+        )
+        .unwrap();
+        assert_eq!(text, "line one\nline two\n");
+
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left.len(), 3);
+        assert_eq!(left[0], "access-20240101-000000-000.log.gz");
+        assert_eq!(left[1], "ccr-20240103-000000-000.log.gz");
+        // Nothing to rotate once the file is gone
+        assert!(rotate_file(&dir, "ccr", 2).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dedup_counts_repeats() {
+        let mut dedup = Dedup {
+            window: Duration::from_secs(60),
+            levels: vec![LogLevel::Warn, LogLevel::Error],
+            open: HashMap::new(),
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let failed =
+            |port: u16| format!("Stream request failed: connection refused (port {})", port);
+
+        assert_eq!(
+            dedup.admit(LogLevel::Error, "openai", &failed(8080), at(0)),
+            (true, None)
+        );
+        for i in 0..250 {
+            let (admit, closed) = dedup.admit(LogLevel::Error, "openai", &failed(8081), at(i % 59));
+            assert!(!admit && closed.is_none());
+        }
+        // Other modules, messages and levels are counted separately
+        assert!(
+            dedup
+                .admit(LogLevel::Error, "anthropic", &failed(1), at(1))
+                .0
+        );
+        assert!(
+            dedup
+                .admit(LogLevel::Error, "openai", "Upstream timed out", at(1))
+                .0
+        );
+        assert!(dedup.admit(LogLevel::Info, "openai", &failed(1), at(1)).0);
+        assert!(dedup.admit(LogLevel::Info, "openai", &failed(1), at(2)).0);
+
+        // The next repeat after the window reports the count and is written
+        let (admit, closed) = dedup.admit(LogLevel::Error, "openai", &failed(8082), at(60));
+        assert!(admit);
+        let closed = closed.unwrap();
+        assert_eq!(closed.repeats, 250);
+        assert_eq!(closed.message, failed(8081));
+
+        // Windows closing without a new repeat are reported by expire
+        assert!(!dedup.admit(LogLevel::Error, "openai", &failed(1), at(61)).0);
+        assert!(!dedup.admit(LogLevel::Error, "openai", &failed(2), at(62)).0);
+        let closed = dedup.expire(at(200));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].repeats, 2);
+        assert_eq!(closed[0].source, "openai");
+        assert!(dedup.open.is_empty());
+        assert!(dedup.expire(at(400)).is_empty());
+    }
+
+    #[test]
+    fn test_dedup_never_suppresses_unlisted_levels() {
+        let mut dedup = Dedup {
+            window: Duration::from_secs(60),
+            levels: vec![LogLevel::Error],
+            open: HashMap::new(),
+        };
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(
+                dedup.admit(LogLevel::Debug, "openai", "Chunk 1", now),
+                (true, None)
+            );
+        }
+        assert!(dedup.open.is_empty());
+        assert_eq!(template("port 8080, try 3/10"), "port #, try #/#");
+    }
+
+    #[test]
+    fn test_rotation_due() {
+        let rotation = Rotation {
+            max_bytes: 100,
+            max_age: Duration::from_secs(3600),
+            keep_archives: 1,
+        };
+        assert!(!rotation.due(50, SystemTime::now()));
+        assert!(rotation.due(100, SystemTime::now()));
+        assert!(rotation.due(1, SystemTime::now() - Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let msg = LogMessage {
+            at: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            level: LogLevel::Info,
+            source: "openai".to_string(),
+            message: "Request completed".to_string(),
+            metadata: None,
+            fields: serde_json::json!({"model": "gpt-4o", "latency_ms": 42})
+                .as_object()
+                .cloned(),
+        };
+        let line: Value = serde_json::from_str(&format_line(&msg, true)).unwrap();
+        assert_eq!(line["timestamp"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(line["module"], "openai");
+        assert_eq!(line["fields"]["latency_ms"], 42);
+        assert_eq!(
+            format_line(&msg, false),
+            "2023-11-14T22:13:20.000Z INFO  [openai] Request completed latency_ms=42 model=gpt-4o"
+        );
+    }
+
+    #[test]
+    fn test_request_id_attached_in_scope() {
+        let msg = in_request("01HZX3Q7R4", || {
+            new_message(LogLevel::Info, "openai", "Request started", None, None)
+        });
+        assert_eq!(msg.request_id(), Some("01HZX3Q7R4"));
+        assert!(format_line(&msg, false).ends_with("Request started request_id=01HZX3Q7R4"));
+
+        // An explicit field wins over the scope
+        let fields = serde_json::json!({"request_id": "explicit"})
+            .as_object()
+            .cloned();
+        let msg = in_request("scoped", || {
+            new_message(LogLevel::Info, "openai", "Request completed", None, fields)
+        });
+        assert_eq!(msg.request_id(), Some("explicit"));
+
+        let msg = new_message(LogLevel::Info, "server", "Started", None, None);
+        assert!(msg.request_id().is_none() && msg.fields.is_none());
+    }
+
+    #[test]
+    fn test_live_backlog_and_filter() {
+        let log = |level, message: &str| {
+            publish(&new_message(level, "live-test", message, None, None));
+        };
+        log(LogLevel::Debug, "one");
+        log(LogLevel::Warn, "two");
+        log(LogLevel::Error, "three");
+
+        let filter = LiveFilter {
+            level: Some(LogLevel::Warn),
+            module: Some("LIVE-TEST".to_string()),
+        };
+        let (backlog, mut receiver) = subscribe(&filter, 10);
+        let messages: Vec<&str> = backlog.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["two", "three"]);
+        assert_eq!(subscribe(&filter, 1).0[0].message, "three");
+
+        log(LogLevel::Info, "four");
+        log(LogLevel::Error, "five");
+        let mut live = Vec::new();
+        while let Ok(entry) = receiver.try_recv() {
+            if filter.matches(&entry) {
+                live.push(entry.message);
+            }
+        }
+        assert_eq!(live, ["five"]);
+    }
+
+    #[test]
+    fn test_seeded_key_never_written() {
+        let key = "sk-seededkey0123456789abcdef";
+        let msg = new_message(
+            LogLevel::Error,
+            "gemini",
+            &format!("Request failed: https://host/v1/models/g?key={} 401", key),
+            Some(&format!("{{\"authorization\":\"Bearer {}\"}}", key)),
+            serde_json::json!({"upstream_error": format!("invalid key {}", key)})
+                .as_object()
+                .cloned(),
+        );
+        for json in [false, true] {
+            let line = format_line(&msg, json);
+            assert!(!line.contains(key), "{}", line);
+            assert!(line.contains("[REDACTED]"));
+        }
+        assert!(!msg.metadata.unwrap().contains(key));
+    }
+}
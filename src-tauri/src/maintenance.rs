@@ -0,0 +1,110 @@
+//! Scheduled database maintenance.
+//!
+//! Once a day (at `maintenance.hour`, local time) old usage rows are rolled up
+//! into `usage_rollup_daily` and deleted, expired request captures are pruned
+//! and free pages are reclaimed. The same job can be triggered on demand.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{Local, TimeZone};
+
+use crate::{config, db, logger};
+
+/// Usage rows younger than this are never pruned: monthly stats and budget
+/// periods read them directly.
+const MIN_USAGE_RETENTION_DAYS: u32 = 35;
+
+static RUNNING: Mutex<()> = Mutex::new(());
+
+fn policy_from_config(cfg: &config::Settings, now: i64) -> db::RetentionPolicy {
+    let days_ago = |days: u32| now - days as i64 * 86_400;
+    db::RetentionPolicy {
+        usage_before: cfg
+            .maintenance
+            .usage_retention_days
+            .map(|d| days_ago(d.max(MIN_USAGE_RETENTION_DAYS))),
+        captures_before: (cfg.capture.retention_days > 0)
+            .then(|| days_ago(cfg.capture.retention_days)),
+        rollup: cfg.maintenance.rollup,
+        vacuum: cfg.maintenance.vacuum,
+    }
+}
+
+/// Run maintenance now with the current configuration. Blocking.
+pub fn run_now() -> Result<db::MaintenanceReport, String> {
+    let _guard = RUNNING
+        .try_lock()
+        .map_err(|_| "Maintenance is already running".to_string())?;
+    let cfg = config::load();
+    let policy = policy_from_config(&cfg, chrono::Utc::now().timestamp());
+    let report = db::run_maintenance(&policy)?;
+    logger::info(
+        "maintenance",
+        &format!(
+            "Maintenance finished: usage_deleted={}, rolled_up={}, captures_deleted={}, reclaimed={} bytes",
+            report.usage_rows_deleted,
+            report.usage_rows_rolled_up,
+            report.captures_deleted,
+            report.reclaimed_bytes
+        ),
+    );
+    Ok(report)
+}
+
+/// Time until the next run at `hour:00` local time.
+fn delay_until_hour(now: chrono::DateTime<Local>, hour: u32) -> Duration {
+    let hour = hour.min(23);
+    let today = now
+        .date_naive()
+        .and_hms_opt(hour, 0, 0)
+        .and_then(|t| Local.from_local_datetime(&t).earliest());
+    let next = match today {
+        Some(t) if t > now => t,
+        Some(t) => t + chrono::Duration::days(1),
+        None => now + chrono::Duration::hours(1),
+    };
+    (next - now).to_std().unwrap_or(Duration::from_secs(3600))
+}
+
+/// Spawn the daily maintenance loop on the current tokio runtime.
+pub fn spawn() {
+    tokio::spawn(async {
+        loop {
+            let hour = config::load().maintenance.hour;
+            tokio::time::sleep(delay_until_hour(Local::now(), hour)).await;
+            if !config::load().maintenance.enabled {
+                continue;
+            }
+            match tokio::task::spawn_blocking(run_now).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => logger::warn("maintenance", &format!("Maintenance failed: {}", e)),
+                Err(e) => logger::error("maintenance", &format!("Maintenance task panicked: {}", e)),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_until_hour() {
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 1, 30, 0).unwrap();
+        assert_eq!(delay_until_hour(now, 3), Duration::from_secs(90 * 60));
+        let later = Local.with_ymd_and_hms(2024, 5, 1, 4, 0, 0).unwrap();
+        assert_eq!(delay_until_hour(later, 3), Duration::from_secs(23 * 3600));
+        assert!(delay_until_hour(now, 99).as_secs() <= 24 * 3600);
+    }
+
+    #[test]
+    fn test_policy_clamps_usage_retention() {
+        let mut cfg = config::Settings::default();
+        cfg.maintenance.usage_retention_days = Some(7);
+        cfg.capture.retention_days = 0;
+        let policy = policy_from_config(&cfg, 100 * 86_400);
+        assert_eq!(policy.usage_before, Some(65 * 86_400));
+        assert_eq!(policy.captures_before, None);
+    }
+}
@@ -0,0 +1,213 @@
+//! Offline detection.
+//!
+//! [`spawn`] starts a loop that opens a TCP connection to one of
+//! `network.check_targets` every `network.check_interval_secs`, or to the
+//! global proxy when a custom one is set, since that is the only way out
+//! then. No answer from any of them means the machine is offline. While it is, the background jobs that call
+//! out wait in [`wait_online`], and requests to upstreams that aren't on
+//! this machine or the local network fail at once (see [`blocks`]) instead
+//! of running through their retries.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{config, logger};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Recheck interval while offline, to notice the way back quickly.
+const OFFLINE_INTERVAL: Duration = Duration::from_secs(5);
+
+static ONLINE: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(true).0);
+/// Unix seconds of the last change, 0 before the first
+static SINCE: AtomicI64 = AtomicI64::new(0);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkState {
+    pub online: bool,
+    pub since: Option<i64>,
+}
+
+pub fn state() -> NetworkState {
+    let since = SINCE.load(Ordering::Relaxed);
+    NetworkState {
+        online: is_online(),
+        since: (since > 0).then_some(since),
+    }
+}
+
+pub fn is_online() -> bool {
+    *ONLINE.borrow()
+}
+
+/// Return once the machine is online, right away if it is.
+pub async fn wait_online() {
+    let mut online = ONLINE.subscribe();
+    // The sender lives in a static, so this only ends by going online
+    let _ = online.wait_for(|online| *online).await;
+}
+
+fn set_online(online: bool, why: &str) {
+    let changed = ONLINE.send_if_modified(|current| std::mem::replace(current, online) != online);
+    if !changed {
+        return;
+    }
+    SINCE.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    if online {
+        logger::info("network", "Back online, resuming background jobs");
+    } else {
+        logger::warn(
+            "network",
+            &format!("Offline ({}), pausing background jobs", why),
+        );
+    }
+    crate::relay_state::poke();
+}
+
+/// Where the check connects: the custom global proxy, else the targets.
+fn targets(cfg: &config::Settings) -> Vec<String> {
+    let proxy = cfg
+        .proxy
+        .as_ref()
+        .filter(|p| p.enabled && p.proxy_type == "custom")
+        .and_then(|p| p.url.as_deref())
+        .and_then(|url| reqwest::Url::parse(url.trim()).ok())
+        .and_then(|url| {
+            Some(format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        });
+    match proxy {
+        Some(proxy) => vec![proxy],
+        None => cfg.network.check_targets.clone(),
+    }
+}
+
+/// `Ok` as soon as one target accepts a connection.
+async fn check(targets: &[String]) -> Result<(), String> {
+    let mut last_error = "no check targets".to_string();
+    for target in targets {
+        let connect = tokio::net::TcpStream::connect(target.trim());
+        match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => last_error = format!("{}: {}", target, e),
+            Err(_) => last_error = format!("{}: no answer", target),
+        }
+    }
+    Err(last_error)
+}
+
+/// Spawn the connectivity check loop on the current tokio runtime.
+pub fn spawn() {
+    tokio::spawn(run());
+}
+
+async fn run() {
+    loop {
+        let cfg = config::current();
+        if !cfg.network.detect_offline {
+            set_online(true, "");
+        } else {
+            match check(&targets(&cfg)).await {
+                Ok(()) => set_online(true, ""),
+                Err(e) => set_online(false, &e),
+            }
+        }
+        let interval = if is_online() {
+            Duration::from_secs(cfg.network.check_interval_secs.max(5))
+        } else {
+            OFFLINE_INTERVAL
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// [`wait_online`] unless `url` is on this machine or the local network.
+pub async fn wait_online_for(url: &str) {
+    if !is_local(url) {
+        wait_online().await;
+    }
+}
+
+/// Whether `endpoint` is on this machine or the local network, and so
+/// reachable without internet.
+fn is_local(endpoint: &str) -> bool {
+    let Some(host) = reqwest::Url::parse(endpoint.trim())
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+    else {
+        return false;
+    };
+    match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // fc00::/7 is unique local, fe80::/10 link local
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+        Err(_) => host.eq_ignore_ascii_case("localhost") || host.ends_with(".local"),
+    }
+}
+
+/// Whether requests to `upstream_id` should fail fast: offline, and it has
+/// an endpoint beyond the local network. Mock upstreams never leave the
+/// machine.
+pub fn blocks(upstream_id: &str) -> bool {
+    if is_online() {
+        return false;
+    }
+    let cfg = config::current();
+    cfg.upstreams
+        .iter()
+        .find(|up| up.id == upstream_id)
+        .is_some_and(|up| !up.is_mock() && !up.endpoints.iter().all(|e| is_local(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_endpoints_and_targets() {
+        assert!(is_local("http://localhost:11434/v1"));
+        assert!(is_local("http://192.168.1.20:8000"));
+        assert!(is_local("http://[::1]:8080"));
+        assert!(is_local("http://[fd00::5]:8080"));
+        assert!(is_local("http://gpu-box.local:8000"));
+        assert!(!is_local("https://api.openai.com/v1"));
+        assert!(!is_local("https://8.8.8.8"));
+        assert!(!is_local("not a url"));
+
+        let mut cfg = config::Settings::default();
+        assert_eq!(targets(&cfg), cfg.network.check_targets);
+        cfg.proxy = Some(config::ProxyConfig {
+            enabled: true,
+            proxy_type: "custom".to_string(),
+            url: Some("http://proxy.corp:3128".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(targets(&cfg), vec!["proxy.corp:3128"]);
+    }
+
+    #[tokio::test]
+    async fn test_check_reaches_a_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap().to_string();
+
+        assert!(check(std::slice::from_ref(&closed)).await.is_err());
+        assert!(check(&[closed, listening]).await.is_ok());
+        assert!(check(&[]).await.is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Desktop notifications for the events on [`relay_state::events`].
+//!
+//! Each kind can be turned off under `notifications`. One subject (an
+//! upstream, a budget threshold, an address) notifies at most once per
+//! `notifications.min_interval_secs`, so a flapping upstream shows up once
+//! instead of every time it trips. Showing them is up to the caller of
+//! [`run`]: the desktop app hands them to the system and to the UI.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::forward::outcomes;
+use crate::relay_state::{self, RelayEvent};
+use crate::{config, logger};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+    /// UI page with the details, e.g. "/models"
+    pub page: &'static str,
+}
+
+/// Call `show` for every event that is enabled and not rate limited, until
+/// the process exits.
+pub async fn run(mut show: impl FnMut(Notification)) {
+    let mut events = relay_state::events();
+    let mut limiter = Limiter::default();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                logger::debug("notifications", &format!("Missed {} events", missed));
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let cfg = config::current();
+        if !enabled(&cfg.notifications, &event) {
+            continue;
+        }
+        let interval = Duration::from_secs(cfg.notifications.min_interval_secs);
+        if !limiter.allow(subject(&event), Instant::now(), interval) {
+            logger::debug(
+                "notifications",
+                &format!("Not repeating notification for {}", subject(&event)),
+            );
+            continue;
+        }
+        show(notification(&event));
+    }
+}
+
+fn enabled(cfg: &config::NotificationsConfig, event: &RelayEvent) -> bool {
+    cfg.enabled
+        && match event {
+            RelayEvent::UpstreamFailing { .. } => cfg.upstream_failing,
+            RelayEvent::BudgetThreshold { .. } => cfg.budget,
+            RelayEvent::NewClientIp { .. } => cfg.new_client_ip,
+        }
+}
+
+/// What the rate limit is kept per.
+fn subject(event: &RelayEvent) -> String {
+    match event {
+        RelayEvent::UpstreamFailing { upstream, .. } => format!("upstream {}", upstream),
+        RelayEvent::BudgetThreshold {
+            budget, percent, ..
+        } => format!("budget {} {}%", budget, percent),
+        RelayEvent::NewClientIp { ip } => format!("client {}", ip),
+    }
+}
+
+fn notification(event: &RelayEvent) -> Notification {
+    match event {
+        RelayEvent::UpstreamFailing {
+            upstream,
+            error_class,
+            error,
+        } => Notification {
+            title: format!("Upstream '{}' is failing", upstream),
+            body: format!(
+                "{} failures in a row ({}): {}",
+                outcomes::FAILING_AFTER,
+                error_class.as_deref().unwrap_or("error"),
+                error.as_deref().unwrap_or("no details")
+            ),
+            page: "/models",
+        },
+        RelayEvent::BudgetThreshold {
+            budget,
+            percent,
+            spent_usd,
+            limit_usd,
+        } => Notification {
+            title: if *percent >= 100 {
+                format!("Budget {} exceeded", budget)
+            } else {
+                format!("Budget {} at {}%", budget, percent)
+            },
+            body: format!("${:.2} of ${:.2} spent", spent_usd, limit_usd),
+            page: "/settings",
+        },
+        RelayEvent::NewClientIp { ip } => Notification {
+            title: format!("Forward token used from {}", ip),
+            body: "First request from this address since the relay started. \
+                   If you don't recognise it, rotate the forward token."
+                .to_string(),
+            page: "/settings",
+        },
+    }
+}
+
+#[derive(Default)]
+struct Limiter {
+    sent: HashMap<String, Instant>,
+}
+
+impl Limiter {
+    fn allow(&mut self, subject: String, now: Instant, interval: Duration) -> bool {
+        if let Some(last) = self.sent.get(&subject) {
+            if now.duration_since(*last) < interval {
+                return false;
+            }
+        }
+        self.sent.insert(subject, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggles_and_rate_limit() {
+        let failing = RelayEvent::UpstreamFailing {
+            upstream: "openai".to_string(),
+            error_class: Some("timeout".to_string()),
+            error: Some("No response within 30s".to_string()),
+        };
+        let mut cfg = config::NotificationsConfig::default();
+        assert!(enabled(&cfg, &failing));
+        cfg.upstream_failing = false;
+        assert!(!enabled(&cfg, &failing));
+
+        let shown = notification(&failing);
+        assert_eq!(shown.title, "Upstream 'openai' is failing");
+        assert!(shown.body.contains("(timeout): No response within 30s"));
+        assert_eq!(shown.page, "/models");
+
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+        let interval = Duration::from_secs(300);
+        assert!(limiter.allow(subject(&failing), start, interval));
+        assert!(!limiter.allow(subject(&failing), start + Duration::from_secs(10), interval));
+        assert!(limiter.allow("upstream other".to_string(), start, interval));
+        assert!(limiter.allow(subject(&failing), start + interval, interval));
+    }
+}
@@ -0,0 +1,338 @@
+//! Model price sync from the OpenRouter catalog.
+//!
+//! The catalog lists per-token prices for most public models. Configured models
+//! are matched against it by `upstream_model_id` and `id` (exact first, then
+//! with provider prefixes, date stamps and `-latest` suffixes removed). Models
+//! whose prices were entered by hand are never overwritten; every synced model
+//! records its source and the time of the update.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::{config, forward, logger};
+
+/// Prices for one model, per 1k tokens.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PriceFields {
+    pub prompt: f64,
+    pub completion: f64,
+    pub cache_write: Option<f64>,
+    pub cache_read: Option<f64>,
+    pub reasoning: Option<f64>,
+}
+
+impl PriceFields {
+    fn of(model: &config::ModelCfg) -> Self {
+        Self {
+            prompt: model.price_prompt_per_1k,
+            completion: model.price_completion_per_1k,
+            cache_write: model.price_cache_write_per_1k,
+            cache_read: model.price_cache_read_per_1k,
+            reasoning: model.price_reasoning_per_1k,
+        }
+    }
+}
+
+/// A price update proposed (or applied) for one configured model.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct PriceChange {
+    pub model_id: String,
+    pub catalog_id: String,
+    pub current: PriceFields,
+    pub proposed: PriceFields,
+    /// Manually priced models are reported but left untouched
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone)]
+struct CatalogEntry {
+    id: String,
+    prices: PriceFields,
+}
+
+/// Parse a catalog per-token price string into a per-1k rate. Negative
+/// values mark variable pricing (e.g. routers) and are treated as unknown.
+fn per_1k(pricing: &Value, key: &str) -> Option<f64> {
+    let value = pricing.get(key)?;
+    let per_token = match value {
+        Value::String(s) => s.trim().parse::<f64>().ok()?,
+        Value::Number(n) => n.as_f64()?,
+        _ => return None,
+    };
+    (per_token >= 0.0).then_some(per_token * 1000.0)
+}
+
+fn parse_catalog(body: &Value) -> Vec<CatalogEntry> {
+    let Some(data) = body.get("data").and_then(|d| d.as_array()) else {
+        return Vec::new();
+    };
+    data.iter()
+        .filter_map(|item| {
+            let id = item.get("id")?.as_str()?.to_string();
+            let pricing = item.get("pricing")?;
+            let prices = PriceFields {
+                prompt: per_1k(pricing, "prompt")?,
+                completion: per_1k(pricing, "completion")?,
+                // A zero rate means "billed like prompt/completion" here
+                cache_write: per_1k(pricing, "input_cache_write").filter(|p| *p > 0.0),
+                cache_read: per_1k(pricing, "input_cache_read").filter(|p| *p > 0.0),
+                reasoning: per_1k(pricing, "internal_reasoning").filter(|p| *p > 0.0),
+            };
+            Some(CatalogEntry { id, prices })
+        })
+        .collect()
+}
+
+/// Loose form of a model id used for fuzzy matching:
+/// `anthropic/claude-3.5-sonnet-20241022` -> `claude-3-5-sonnet`.
+fn normalize_id(id: &str) -> String {
+    let id = id.trim().to_lowercase();
+    let id = id.rsplit('/').next().unwrap_or(&id);
+    let id = id.split(':').next().unwrap_or(id);
+    let mut id = id.replace(['.', '_'], "-");
+    if let Some(stripped) = id.strip_suffix("-latest") {
+        id = stripped.to_string();
+    }
+    // Trailing date stamps: -20241022 or -2024-10-22
+    let parts: Vec<&str> = id.split('-').collect();
+    let is_digits = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_digit());
+    let keep = match parts.as_slice() {
+        [.., y, m, d] if is_digits(y, 4) && is_digits(m, 2) && is_digits(d, 2) => parts.len() - 3,
+        [.., date] if is_digits(date, 8) => parts.len() - 1,
+        _ => parts.len(),
+    };
+    parts[..keep.max(1)].join("-")
+}
+
+struct Catalog {
+    exact: HashMap<String, usize>,
+    loose: HashMap<String, usize>,
+    entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    fn new(entries: Vec<CatalogEntry>) -> Self {
+        let mut exact = HashMap::new();
+        let mut loose = HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            let lower = entry.id.to_lowercase();
+            exact.entry(lower.clone()).or_insert(idx);
+            if let Some((_, bare)) = lower.split_once('/') {
+                exact.entry(bare.to_string()).or_insert(idx);
+            }
+            // Free variants (":free") must not shadow the paid listing
+            if !lower.contains(':') {
+                loose.entry(normalize_id(&lower)).or_insert(idx);
+            }
+        }
+        Self {
+            exact,
+            loose,
+            entries,
+        }
+    }
+
+    fn find(&self, model: &config::ModelCfg) -> Option<&CatalogEntry> {
+        let candidates: Vec<String> = model
+            .upstream_model_id
+            .iter()
+            .filter(|m| !m.trim().is_empty())
+            .chain(std::iter::once(&model.id))
+            .map(|s| s.trim().to_lowercase())
+            .collect();
+        candidates
+            .iter()
+            .find_map(|c| self.exact.get(c))
+            .or_else(|| candidates.iter().find_map(|c| self.loose.get(&normalize_id(c))))
+            .map(|idx| &self.entries[*idx])
+    }
+}
+
+/// Whether the model's prices were set by hand. Models without a recorded
+/// source count as manual once any base price is non-zero.
+fn is_manual(model: &config::ModelCfg) -> bool {
+    match model.price_source.as_deref() {
+        Some("manual") => true,
+        Some(_) => false,
+        None => model.price_prompt_per_1k > 0.0 || model.price_completion_per_1k > 0.0,
+    }
+}
+
+fn diff(models: &[config::ModelCfg], catalog: &Catalog) -> Vec<PriceChange> {
+    models
+        .iter()
+        .filter(|m| !m.is_temporary)
+        .filter_map(|m| {
+            let entry = catalog.find(m)?;
+            let current = PriceFields::of(m);
+            (current != entry.prices).then(|| PriceChange {
+                model_id: m.id.clone(),
+                catalog_id: entry.id.clone(),
+                current,
+                proposed: entry.prices.clone(),
+                protected: is_manual(m),
+            })
+        })
+        .collect()
+}
+
+async fn fetch_catalog(url: &str) -> Result<Catalog, String> {
+    let client = forward::client::default_client().map_err(|e| e.to_string())?;
+    let resp = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch price catalog: {}", e))?;
+    if !resp.status().is_success() {
+        return Err(format!("Price catalog returned HTTP {}", resp.status().as_u16()));
+    }
+    let body: Value = resp
+        .json()
+        .await
+        .map_err(|e| format!("Invalid price catalog: {}", e))?;
+    let entries = parse_catalog(&body);
+    if entries.is_empty() {
+        return Err("Price catalog contained no priced models".to_string());
+    }
+    Ok(Catalog::new(entries))
+}
+
+/// Fetch the catalog and list the price changes a sync would make.
+pub async fn preview() -> Result<Vec<PriceChange>, String> {
+    let url = config::load().price_sync.catalog_url;
+    let catalog = fetch_catalog(&url).await?;
+    Ok(diff(&config::load().models, &catalog))
+}
+
+/// Fetch the catalog and apply the changes for `model_ids` (all unprotected
+/// models when `None`). Returns the changes that were written.
+pub async fn apply(model_ids: Option<Vec<String>>) -> Result<Vec<PriceChange>, String> {
+    let url = config::load().price_sync.catalog_url;
+    let catalog = fetch_catalog(&url).await?;
+
+    // Reload after the fetch so edits made meanwhile are not lost
+    let mut cfg = config::load();
+    let selected = |id: &str| {
+        model_ids
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|m| m.eq_ignore_ascii_case(id)))
+    };
+    let changes: Vec<PriceChange> = diff(&cfg.models, &catalog)
+        .into_iter()
+        .filter(|c| !c.protected && selected(&c.model_id))
+        .collect();
+    if changes.is_empty() {
+        return Ok(changes);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    for change in &changes {
+        if let Some(model) = cfg.models.iter_mut().find(|m| m.id == change.model_id) {
+            let p = &change.proposed;
+            model.price_prompt_per_1k = p.prompt;
+            model.price_completion_per_1k = p.completion;
+            model.price_cache_write_per_1k = p.cache_write;
+            model.price_cache_read_per_1k = p.cache_read;
+            model.price_reasoning_per_1k = p.reasoning;
+            model.price_source = Some("openrouter".to_string());
+            model.price_updated_at = Some(now);
+        }
+    }
+    config::save(&cfg)?;
+    logger::info(
+        "price_sync",
+        &format!("Updated prices for {} model(s) from catalog", changes.len()),
+    );
+    Ok(changes)
+}
+
+/// Spawn the periodic sync loop. It only fetches while `price_sync.enabled`.
+pub fn spawn() {
+    tokio::spawn(async {
+        // Let startup settle before the first fetch
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        loop {
+            let sync = config::load().price_sync;
+            if sync.enabled {
+                crate::network::wait_online().await;
+                if let Err(e) = apply(None).await {
+                    logger::warn("price_sync", &format!("Price sync failed: {}", e));
+                }
+            }
+            let hours = sync.interval_hours.max(1) as u64;
+            tokio::time::sleep(Duration::from_secs(hours * 3600)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn model(id: &str, upstream_model_id: Option<&str>) -> config::ModelCfg {
+        config::ModelCfg {
+            id: id.to_string(),
+            upstream_model_id: upstream_model_id.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn catalog() -> Catalog {
+        Catalog::new(parse_catalog(&json!({
+            "data": [
+                {"id": "openai/gpt-4o", "pricing": {"prompt": "0.0000025", "completion": "0.00001", "input_cache_read": "0.00000125"}},
+                {"id": "anthropic/claude-3.5-sonnet:free", "pricing": {"prompt": "0", "completion": "0"}},
+                {"id": "anthropic/claude-3.5-sonnet", "pricing": {"prompt": "0.000003", "completion": "0.000015", "internal_reasoning": "0"}},
+                {"id": "openrouter/auto", "pricing": {"prompt": "-1", "completion": "-1"}}
+            ]
+        })))
+    }
+
+    #[test]
+    fn test_normalize_id() {
+        assert_eq!(normalize_id("anthropic/claude-3.5-sonnet-20241022"), "claude-3-5-sonnet");
+        assert_eq!(normalize_id("claude-3-5-sonnet-latest"), "claude-3-5-sonnet");
+        assert_eq!(normalize_id("gpt-4o-2024-08-06"), "gpt-4o");
+        assert_eq!(normalize_id("GPT-4o"), "gpt-4o");
+    }
+
+    #[test]
+    fn test_parse_and_match() {
+        let catalog = catalog();
+        assert_eq!(catalog.entries.len(), 3, "variable pricing is skipped");
+
+        let gpt = catalog.find(&model("my-gpt", Some("gpt-4o"))).unwrap();
+        assert_eq!(gpt.id, "openai/gpt-4o");
+        assert!((gpt.prices.prompt - 0.0025).abs() < 1e-12);
+        assert!((gpt.prices.cache_read.unwrap() - 0.00125).abs() < 1e-12);
+
+        let claude = catalog
+            .find(&model("claude-3-5-sonnet-20241022", None))
+            .unwrap();
+        assert_eq!(claude.id, "anthropic/claude-3.5-sonnet");
+        assert_eq!(claude.prices.reasoning, None);
+
+        assert!(catalog.find(&model("unknown-model", None)).is_none());
+    }
+
+    #[test]
+    fn test_manual_prices_are_protected() {
+        let catalog = catalog();
+        let mut manual = model("gpt-4o", None);
+        manual.price_prompt_per_1k = 0.001;
+        let mut synced = model("claude-3-5-sonnet", None);
+        synced.price_prompt_per_1k = 0.001;
+        synced.price_source = Some("openrouter".to_string());
+        let unpriced = model("openai/gpt-4o", None);
+
+        let changes = diff(&[manual, synced, unpriced], &catalog);
+        let protected: Vec<_> = changes.iter().map(|c| (c.model_id.as_str(), c.protected)).collect();
+        assert_eq!(
+            protected,
+            vec![("gpt-4o", true), ("claude-3-5-sonnet", false), ("openai/gpt-4o", false)]
+        );
+    }
+}
@@ -1,18 +1,207 @@
-pub fn cost_usd(
-    prompt_tokens: i64,
-    completion_tokens: i64,
-    prompt_price_per_1k: f64,
-    completion_price_per_1k: f64,
-) -> f64 {
-    (prompt_tokens as f64 / 1000.0) * prompt_price_per_1k
-        + (completion_tokens as f64 / 1000.0) * completion_price_per_1k
+use crate::config::{ModelCfg, PriceTier};
+
+/// Per-1k prices in effect when a request was logged.
+///
+/// Cache and reasoning rates are already resolved: when a model does not set
+/// them they equal the prompt (cache) or completion (reasoning) rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct PriceSnapshot {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+    pub cache_write_per_1k: f64,
+    pub cache_read_per_1k: f64,
+    pub reasoning_per_1k: f64,
+}
+
+impl PriceSnapshot {
+    /// A model with no positive price is treated as unpriced rather than free.
+    pub fn is_priced(&self) -> bool {
+        self.prompt_per_1k > 0.0
+            || self.completion_per_1k > 0.0
+            || self.cache_write_per_1k > 0.0
+            || self.cache_read_per_1k > 0.0
+            || self.reasoning_per_1k > 0.0
+    }
+}
+
+/// Token counts that are billed separately.
+///
+/// `cache_creation`/`cache_read` are part of `prompt` and `reasoning` is part
+/// of `completion`, matching `TokenUsage`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BilledTokens {
+    pub prompt: i64,
+    pub completion: i64,
+    pub cache_creation: i64,
+    pub cache_read: i64,
+    pub reasoning: i64,
+}
+
+/// Everything needed to price a request for one model.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct ModelPricing {
+    pub price_prompt_per_1k: f64,
+    pub price_completion_per_1k: f64,
+    pub price_cache_write_per_1k: Option<f64>,
+    pub price_cache_read_per_1k: Option<f64>,
+    pub price_reasoning_per_1k: Option<f64>,
+    pub price_tiers: Vec<PriceTier>,
+}
+
+impl ModelPricing {
+    pub fn from_model(model: &ModelCfg) -> Self {
+        Self {
+            price_prompt_per_1k: model.price_prompt_per_1k,
+            price_completion_per_1k: model.price_completion_per_1k,
+            price_cache_write_per_1k: model.price_cache_write_per_1k,
+            price_cache_read_per_1k: model.price_cache_read_per_1k,
+            price_reasoning_per_1k: model.price_reasoning_per_1k,
+            price_tiers: model.price_tiers.clone(),
+        }
+    }
+
+    /// Rates for a request with `prompt_tokens` of context: the tier with the
+    /// highest `above_prompt_tokens` strictly below the prompt size applies,
+    /// with unset tier fields falling back to the base rates.
+    pub fn snapshot_for(&self, prompt_tokens: i64) -> PriceSnapshot {
+        let tier = self
+            .price_tiers
+            .iter()
+            .filter(|t| prompt_tokens > t.above_prompt_tokens)
+            .max_by_key(|t| t.above_prompt_tokens);
+
+        let prompt = tier
+            .and_then(|t| t.price_prompt_per_1k)
+            .unwrap_or(self.price_prompt_per_1k);
+        let completion = tier
+            .and_then(|t| t.price_completion_per_1k)
+            .unwrap_or(self.price_completion_per_1k);
+        let pick = |tier_rate: Option<f64>, base_rate: Option<f64>, default: f64| {
+            tier_rate.or(base_rate).unwrap_or(default)
+        };
+        PriceSnapshot {
+            prompt_per_1k: prompt,
+            completion_per_1k: completion,
+            cache_write_per_1k: pick(
+                tier.and_then(|t| t.price_cache_write_per_1k),
+                self.price_cache_write_per_1k,
+                prompt,
+            ),
+            cache_read_per_1k: pick(
+                tier.and_then(|t| t.price_cache_read_per_1k),
+                self.price_cache_read_per_1k,
+                prompt,
+            ),
+            reasoning_per_1k: pick(
+                tier.and_then(|t| t.price_reasoning_per_1k),
+                self.price_reasoning_per_1k,
+                completion,
+            ),
+        }
+    }
+}
+
+/// Cost of a request, or `None` when the model has no prices configured.
+pub fn request_cost(tokens: &BilledTokens, price: &PriceSnapshot) -> Option<f64> {
+    if !price.is_priced() {
+        return None;
+    }
+    let cache_creation = tokens.cache_creation.max(0);
+    let cache_read = tokens.cache_read.max(0);
+    let reasoning = tokens.reasoning.clamp(0, tokens.completion.max(0));
+    let uncached_prompt = (tokens.prompt - cache_creation - cache_read).max(0);
+    let per_1k = |count: i64, rate: f64| count as f64 / 1000.0 * rate;
+    Some(
+        per_1k(uncached_prompt, price.prompt_per_1k)
+            + per_1k(cache_creation, price.cache_write_per_1k)
+            + per_1k(cache_read, price.cache_read_per_1k)
+            + per_1k(tokens.completion - reasoning, price.completion_per_1k)
+            + per_1k(reasoning, price.reasoning_per_1k),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    fn tokens(prompt: i64, completion: i64) -> BilledTokens {
+        BilledTokens {
+            prompt,
+            completion,
+            ..Default::default()
+        }
+    }
+
+    fn flat(prompt_per_1k: f64, completion_per_1k: f64) -> PriceSnapshot {
+        ModelPricing {
+            price_prompt_per_1k: prompt_per_1k,
+            price_completion_per_1k: completion_per_1k,
+            ..Default::default()
+        }
+        .snapshot_for(0)
+    }
+
     #[test]
     fn calc_cost() {
-        assert!((cost_usd(1000, 2000, 1.0, 2.0) - 5.0).abs() < 1e-6)
+        let cost = request_cost(&tokens(1000, 2000), &flat(1.0, 2.0)).unwrap();
+        assert!((cost - 5.0).abs() < 1e-6)
+    }
+
+    #[test]
+    fn unpriced_model_has_no_cost() {
+        assert_eq!(request_cost(&tokens(1000, 1000), &PriceSnapshot::default()), None);
+        let cost = request_cost(&tokens(1000, 0), &flat(0.5, 0.0)).unwrap();
+        assert!((cost - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cache_and_reasoning_tokens_use_their_own_rates() {
+        let pricing = ModelPricing {
+            price_prompt_per_1k: 3.0,
+            price_completion_per_1k: 15.0,
+            price_cache_write_per_1k: Some(3.75),
+            price_cache_read_per_1k: Some(0.3),
+            price_reasoning_per_1k: Some(20.0),
+            ..Default::default()
+        };
+        let billed = BilledTokens {
+            prompt: 4000,
+            completion: 2000,
+            cache_creation: 1000,
+            cache_read: 2000,
+            reasoning: 1000,
+        };
+        let cost = request_cost(&billed, &pricing.snapshot_for(billed.prompt)).unwrap();
+        // 1k uncached * 3 + 1k write * 3.75 + 2k read * 0.3 + 1k out * 15 + 1k reasoning * 20
+        assert!((cost - (3.0 + 3.75 + 0.6 + 15.0 + 20.0)).abs() < 1e-9);
+
+        // without explicit rates cache/reasoning tokens cost the same as before
+        let cost = request_cost(&billed, &flat(3.0, 15.0)).unwrap();
+        assert!((cost - (4.0 * 3.0 + 2.0 * 15.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn context_tiers_apply_above_their_boundary() {
+        let pricing = ModelPricing {
+            price_prompt_per_1k: 1.25,
+            price_completion_per_1k: 10.0,
+            price_cache_read_per_1k: Some(0.31),
+            price_tiers: vec![PriceTier {
+                above_prompt_tokens: 200_000,
+                price_prompt_per_1k: Some(2.5),
+                price_completion_per_1k: Some(15.0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let at_boundary = pricing.snapshot_for(200_000);
+        assert_eq!(at_boundary.prompt_per_1k, 1.25);
+        assert_eq!(at_boundary.completion_per_1k, 10.0);
+
+        let above = pricing.snapshot_for(200_001);
+        assert_eq!(above.prompt_per_1k, 2.5);
+        assert_eq!(above.completion_per_1k, 15.0);
+        // unset tier fields inherit the base rate, not the tier prompt rate
+        assert_eq!(above.cache_read_per_1k, 0.31);
+        assert_eq!(above.cache_write_per_1k, 2.5);
     }
 }
@@ -0,0 +1,218 @@
+//! Profiles: independent sets of configuration and usage data.
+//!
+//! The `default` profile lives directly in the CCR data directory, where
+//! installs without profiles keep their files. Every other profile gets its
+//! own `profiles/<name>/` directory holding `settings.toml` and `ccr.db`, so
+//! upstreams, forward tokens and cost data never mix. Logs and projects stay
+//! shared between profiles.
+//!
+//! The active profile is remembered in `active_profile` in the data
+//! directory; `CCR_PROFILE` overrides it for one process.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use dirs::data_dir;
+
+use crate::{config, db};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+static ACTIVE: RwLock<Option<String>> = RwLock::new(None);
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub active: bool,
+    pub path: String,
+}
+
+/// Data directory tests use instead of the user's, see
+/// [`use_temp_dir_for_tests`].
+#[cfg(test)]
+static TEST_BASE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// The CCR data directory shared by all profiles.
+pub fn base_dir() -> PathBuf {
+    #[cfg(test)]
+    if let Some(dir) = TEST_BASE_DIR.read().unwrap_or_else(|e| e.into_inner()).clone() {
+        return dir;
+    }
+    let mut p = data_dir().unwrap_or_else(|| PathBuf::from("."));
+    p.push("CCR");
+    fs::create_dir_all(&p).ok();
+    p
+}
+
+fn marker_path() -> PathBuf {
+    base_dir().join("active_profile")
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid profile name '{}': use 1-32 lowercase letters, digits, '-' or '_'",
+            name
+        ))
+    }
+}
+
+fn path_of(name: &str) -> PathBuf {
+    if name == DEFAULT_PROFILE {
+        base_dir()
+    } else {
+        base_dir().join("profiles").join(name)
+    }
+}
+
+fn exists(name: &str) -> bool {
+    name == DEFAULT_PROFILE || path_of(name).is_dir()
+}
+
+/// Name of the profile in use.
+pub fn active() -> String {
+    if let Some(name) = ACTIVE.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return name.clone();
+    }
+    let name = std::env::var("CCR_PROFILE")
+        .ok()
+        .or_else(|| fs::read_to_string(marker_path()).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| validate_name(s).is_ok() && exists(s))
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    *ACTIVE.write().unwrap_or_else(|e| e.into_inner()) = Some(name.clone());
+    name
+}
+
+/// Use a fresh data directory `name` under the temp dir, in the default
+/// profile, for the rest of the test process, with its database
+/// initialized, so a test never touches the user's settings or usage.
+#[cfg(test)]
+pub(crate) fn use_temp_dir_for_tests(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ccr-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).ok();
+    *TEST_BASE_DIR.write().unwrap_or_else(|e| e.into_inner()) = Some(dir.clone());
+    *ACTIVE.write().unwrap_or_else(|e| e.into_inner()) = Some(DEFAULT_PROFILE.to_string());
+    db::init();
+    db::reopen_writer();
+    dir
+}
+
+/// Directory holding the active profile's settings and database.
+pub fn dir() -> PathBuf {
+    let p = path_of(&active());
+    fs::create_dir_all(&p).ok();
+    p
+}
+
+/// All profiles, `default` first.
+pub fn list() -> Vec<ProfileInfo> {
+    let active = active();
+    let mut names = vec![DEFAULT_PROFILE.to_string()];
+    if let Ok(entries) = fs::read_dir(base_dir().join("profiles")) {
+        let mut others: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|n| n != DEFAULT_PROFILE && validate_name(n).is_ok())
+            .collect();
+        others.sort();
+        names.extend(others);
+    }
+    names
+        .into_iter()
+        .map(|name| ProfileInfo {
+            active: name == active,
+            path: path_of(&name).display().to_string(),
+            name,
+        })
+        .collect()
+}
+
+/// Create an empty profile, or one starting from a copy of `copy_from`'s
+/// settings. Usage data is never copied.
+pub fn create(name: &str, copy_from: Option<&str>) -> Result<ProfileInfo, String> {
+    validate_name(name)?;
+    if exists(name) {
+        return Err(format!("Profile '{}' already exists", name));
+    }
+    if let Some(source) = copy_from {
+        if !exists(source) {
+            return Err(format!("Profile '{}' not found", source));
+        }
+    }
+    let dir = path_of(name);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create profile directory: {}", e))?;
+    if let Some(source) = copy_from {
+        let settings = path_of(source).join(config::SETTINGS_FILE);
+        if settings.exists() {
+            fs::copy(&settings, dir.join(config::SETTINGS_FILE))
+                .map_err(|e| format!("Failed to copy settings from '{}': {}", source, e))?;
+        }
+    }
+    crate::logger::info("profile", &format!("Created profile '{}'", name));
+    Ok(ProfileInfo {
+        name: name.to_string(),
+        active: false,
+        path: dir.display().to_string(),
+    })
+}
+
+/// Make `name` the active profile: pending usage rows are committed to the
+/// old database, then config and database are reopened from the new one and
+/// the HTTP server restarts on top of them.
+pub fn switch(name: &str) -> Result<ProfileInfo, String> {
+    validate_name(name)?;
+    if !exists(name) {
+        return Err(format!("Profile '{}' not found", name));
+    }
+    let previous = active();
+    if previous == name {
+        return list()
+            .into_iter()
+            .find(|p| p.active)
+            .ok_or_else(|| format!("Profile '{}' not found", name));
+    }
+
+    db::flush_writes();
+    fs::write(marker_path(), name).map_err(|e| format!("Failed to save active profile: {}", e))?;
+    *ACTIVE.write().unwrap_or_else(|e| e.into_inner()) = Some(name.to_string());
+    config::unload();
+    db::init();
+    db::reopen_writer();
+    crate::server::restart();
+
+    crate::logger::info(
+        "profile",
+        &format!("Switched profile from '{}' to '{}'", previous, name),
+    );
+    Ok(ProfileInfo {
+        name: name.to_string(),
+        active: true,
+        path: path_of(name).display().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("work").is_ok());
+        assert!(validate_name("client_a-2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("Work").is_err());
+        assert!(validate_name("../escape").is_err());
+        assert!(validate_name(&"x".repeat(33)).is_err());
+    }
+}
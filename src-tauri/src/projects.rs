@@ -1,21 +1,18 @@
-use dirs::data_dir;
+use once_cell::sync::Lazy;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, process::Command};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::process::Command;
 
-use crate::tools;
-
-fn db_path() -> PathBuf {
-    let mut p = data_dir().unwrap_or_else(|| PathBuf::from("."));
-    std::fs::create_dir_all(&p).ok();
-    p.push("CCR");
-    std::fs::create_dir_all(&p).ok();
-    p.push("ccr.db");
-    p
-}
+use crate::config::{self, SaveError, Severity};
+use crate::forward::error::Dialect;
+use crate::{logger, tools};
 
+/// Projects are shared by all profiles (see [`crate::db::open_projects_conn`]).
 fn open_conn() -> Connection {
-    Connection::open(db_path()).unwrap()
+    crate::db::open_projects_conn()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -26,6 +23,26 @@ pub struct Project {
     pub description: String,
     pub tags: Vec<String>,
     pub created_at: i64,
+    /// Clients sending this token are attributed to the project; it
+    /// authenticates like the forward token
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Requested model id, or "*" for any, to the model used instead
+    #[serde(default)]
+    pub model_overrides: BTreeMap<String, String>,
+    /// Added to the system prompt of every request, as `system_prefix_mode`
+    /// says
+    #[serde(default)]
+    pub system_prefix: Option<String>,
+    #[serde(default)]
+    pub system_prefix_mode: PrefixMode,
+    /// Patterns (`*` wildcards) for the OpenAI `user` or Anthropic
+    /// `metadata.user_id` of requests belonging to the project
+    #[serde(default)]
+    pub user_patterns: Vec<String>,
+    /// Monthly spend after which the project's requests are blocked
+    #[serde(default)]
+    pub budget_usd: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,6 +51,63 @@ pub struct ProjectInput {
     pub path: String,
     pub description: Option<String>,
     pub tags: Option<Vec<String>>,
+    pub token: Option<String>,
+    pub model_overrides: Option<BTreeMap<String, String>>,
+    pub system_prefix: Option<String>,
+    pub system_prefix_mode: Option<PrefixMode>,
+    pub user_patterns: Option<Vec<String>>,
+    pub budget_usd: Option<f64>,
+}
+
+/// How a project's system prefix combines with the system prompt a request
+/// already has.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefixMode {
+    /// Before the existing system prompt
+    #[default]
+    Prepend,
+    /// After the existing system prompt
+    Append,
+    /// Instead of the existing system prompt
+    Replace,
+}
+
+impl PrefixMode {
+    pub fn from_str(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "append" => PrefixMode::Append,
+            "replace" => PrefixMode::Replace,
+            _ => PrefixMode::Prepend,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PrefixMode::Prepend => "prepend",
+            PrefixMode::Append => "append",
+            PrefixMode::Replace => "replace",
+        }
+    }
+
+    /// `text` combined with the system prompt `existing`.
+    fn join(self, text: &str, existing: &str) -> String {
+        match self {
+            _ if existing.trim().is_empty() => text.to_string(),
+            PrefixMode::Prepend => format!("{}\n\n{}", text, existing),
+            PrefixMode::Append => format!("{}\n\n{}", existing, text),
+            PrefixMode::Replace => text.to_string(),
+        }
+    }
+
+    /// `part` combined with the system prompt parts `parts`.
+    fn merge(self, parts: &mut Vec<Value>, part: Value) {
+        match self {
+            PrefixMode::Prepend => parts.insert(0, part),
+            PrefixMode::Append => parts.push(part),
+            PrefixMode::Replace => *parts = vec![part],
+        }
+    }
 }
 
 fn serialize_tags(tags: &[String]) -> String {
@@ -51,85 +125,202 @@ fn parse_tags(raw: String) -> Vec<String> {
         .collect()
 }
 
+/// Routing fields as stored: blanks as NULL, the map and list as JSON.
+struct Routing {
+    token: Option<String>,
+    model_overrides: String,
+    system_prefix: Option<String>,
+    user_patterns: String,
+}
+
+impl Routing {
+    fn of(
+        token: Option<String>,
+        model_overrides: &BTreeMap<String, String>,
+        system_prefix: Option<String>,
+        user_patterns: &[String],
+    ) -> Self {
+        let not_blank = |s: String| {
+            let s = s.trim().to_string();
+            (!s.is_empty()).then_some(s)
+        };
+        Routing {
+            token: token.and_then(not_blank),
+            model_overrides: serde_json::to_string(model_overrides).unwrap_or_default(),
+            system_prefix: system_prefix.filter(|p| !p.trim().is_empty()),
+            user_patterns: serde_json::to_string(user_patterns).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&Project> for Routing {
+    fn from(project: &Project) -> Self {
+        Routing::of(
+            project.token.clone(),
+            &project.model_overrides,
+            project.system_prefix.clone(),
+            &project.user_patterns,
+        )
+    }
+}
+
+const PROJECT_COLUMNS: &str = "id,name,path,description,ifnull(tags,''),ifnull(created_at,0),token,model_overrides,system_prefix,user_patterns,budget_usd,system_prefix_mode";
+
+fn parse_json<T: serde::de::DeserializeOwned + Default>(raw: Option<String>) -> T {
+    raw.and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_default()
+}
+
+fn project_from_row(r: &rusqlite::Row) -> rusqlite::Result<Project> {
+    Ok(Project {
+        id: r.get(0)?,
+        name: r.get(1)?,
+        path: r.get(2)?,
+        description: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        tags: parse_tags(r.get(4)?),
+        created_at: r.get(5)?,
+        token: r.get(6)?,
+        model_overrides: parse_json(r.get(7)?),
+        system_prefix: r.get(8)?,
+        user_patterns: parse_json(r.get(9)?),
+        budget_usd: r.get(10)?,
+        system_prefix_mode: PrefixMode::from_str(
+            &r.get::<_, Option<String>>(11)?.unwrap_or_default(),
+        ),
+    })
+}
+
+/// Projects that were not deleted; deleted ones stay behind as tombstones
+/// so their usage rows keep pointing at a row.
 pub fn list() -> Vec<Project> {
     let conn = open_conn();
-    let mut stmt = conn.prepare("select id,name,path,description,ifnull(tags,''),ifnull(created_at,0) from projects order by id desc").unwrap();
-    let rows = stmt
-        .query_map([], |r| {
-            Ok(Project {
-                id: r.get(0)?,
-                name: r.get(1)?,
-                path: r.get(2)?,
-                description: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
-                tags: parse_tags(r.get(4)?),
-                created_at: r.get(5)?,
-            })
-        })
-        .unwrap();
-    rows.filter_map(|x| x.ok()).collect()
+    let sql =
+        format!("select {PROJECT_COLUMNS} from projects where deleted_at is null order by id desc");
+    let rows = conn.prepare(&sql).and_then(|mut stmt| {
+        stmt.query_map([], project_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+    });
+    rows.unwrap_or_else(|e| {
+        logger::warn("projects", &format!("Failed to list projects: {}", e));
+        Vec::new()
+    })
 }
 
 pub fn get(id: i64) -> Option<Project> {
     let conn = open_conn();
-    let mut stmt = conn.prepare("select id,name,path,description,ifnull(tags,''),ifnull(created_at,0) from projects where id=?").ok()?;
-    stmt.query_row(params![id], |r| {
-        Ok(Project {
-            id: r.get(0)?,
-            name: r.get(1)?,
-            path: r.get(2)?,
-            description: r.get::<_, Option<String>>(3)?.unwrap_or_default(),
-            tags: parse_tags(r.get(4)?),
-            created_at: r.get(5)?,
-        })
-    })
-    .ok()
+    let sql = format!("select {PROJECT_COLUMNS} from projects where id=? and deleted_at is null");
+    let mut stmt = conn.prepare(&sql).ok()?;
+    stmt.query_row(params![id], project_from_row).ok()
 }
 
-pub fn create(input: ProjectInput) -> Option<Project> {
-    let conn = open_conn();
+/// `input` as the project `id`, checked against the other projects.
+fn validated(id: i64, created_at: i64, input: ProjectInput) -> Result<Project, SaveError> {
+    let model_overrides = input.model_overrides.unwrap_or_default();
+    let user_patterns = input.user_patterns.unwrap_or_default();
+    let routing = Routing::of(
+        input.token,
+        &model_overrides,
+        input.system_prefix,
+        &user_patterns,
+    );
+    let project = Project {
+        id,
+        name: input.name.trim().to_string(),
+        path: input.path,
+        description: input.description.unwrap_or_default(),
+        tags: parse_tags(serialize_tags(&input.tags.unwrap_or_default())),
+        created_at,
+        token: routing.token,
+        model_overrides,
+        system_prefix: routing.system_prefix,
+        system_prefix_mode: input.system_prefix_mode.unwrap_or_default(),
+        user_patterns,
+        budget_usd: input.budget_usd.filter(|b| *b != 0.0),
+    };
+    let issues = config::validate_project(&config::load(), &project, &list());
+    if issues.iter().any(|i| i.severity == Severity::Error) {
+        return Err(SaveError {
+            message: "Invalid project".to_string(),
+            issues,
+        });
+    }
+    Ok(project)
+}
+
+pub fn create(input: ProjectInput) -> Result<Project, SaveError> {
     let ts = chrono::Utc::now().timestamp();
-    let ProjectInput {
-        name,
-        path,
-        description,
-        tags,
-    } = input;
-    let tags_vec = tags.unwrap_or_default();
-    let tags = serialize_tags(&tags_vec);
-    let desc = description.unwrap_or_default();
+    let project = validated(0, ts, input)?;
+    let routing = Routing::from(&project);
+    let conn = open_conn();
     conn.execute(
-        "insert into projects(name,path,description,tags,created_at) values(?,?,?,?,?)",
-        params![name, path, desc, tags, ts],
+        "insert into projects(name,path,description,tags,created_at,token,model_overrides,system_prefix,user_patterns,budget_usd,system_prefix_mode) values(?,?,?,?,?,?,?,?,?,?,?)",
+        params![
+            project.name,
+            project.path,
+            project.description,
+            serialize_tags(&project.tags),
+            ts,
+            routing.token,
+            routing.model_overrides,
+            routing.system_prefix,
+            routing.user_patterns,
+            project.budget_usd,
+            project.system_prefix_mode.as_str()
+        ],
     )
-    .ok()?;
+    .map_err(|e| e.to_string())?;
     let id = conn.last_insert_rowid();
-    get(id)
+    invalidate();
+    logger::info("projects", &format!("Created project '{}'", project.name));
+    get(id).ok_or_else(|| "Project was not saved".to_string().into())
 }
 
-pub fn update(id: i64, input: ProjectInput) -> Option<Project> {
+/// Replace the project `id`; `None` if there is no such project.
+pub fn update(id: i64, input: ProjectInput) -> Result<Option<Project>, SaveError> {
+    let Some(existing) = get(id) else {
+        return Ok(None);
+    };
+    let project = validated(id, existing.created_at, input)?;
+    let routing = Routing::from(&project);
     let conn = open_conn();
-    let ProjectInput {
-        name,
-        path,
-        description,
-        tags,
-    } = input;
-    let tags_vec = tags.unwrap_or_default();
-    let tags = serialize_tags(&tags_vec);
-    let desc = description.unwrap_or_default();
     conn.execute(
-        "update projects set name=?, path=?, description=?, tags=? where id=?",
-        params![name, path, desc, tags, id],
+        "update projects set name=?, path=?, description=?, tags=?, token=?, model_overrides=?, system_prefix=?, user_patterns=?, budget_usd=?, system_prefix_mode=? where id=?",
+        params![
+            project.name,
+            project.path,
+            project.description,
+            serialize_tags(&project.tags),
+            routing.token,
+            routing.model_overrides,
+            routing.system_prefix,
+            routing.user_patterns,
+            project.budget_usd,
+            project.system_prefix_mode.as_str(),
+            id
+        ],
     )
-    .ok()?;
-    get(id)
+    .map_err(|e| e.to_string())?;
+    invalidate();
+    Ok(get(id))
 }
 
+/// Delete the project `id`, revoking its token. The row is kept as a
+/// tombstone: usage logged for the project keeps its id and name, and the
+/// name can be used again.
 pub fn remove(id: i64) -> bool {
     let conn = open_conn();
-    conn.execute("delete from projects where id=?", params![id])
+    let removed = conn
+        .execute(
+            "update projects set deleted_at=?, token=null where id=? and deleted_at is null",
+            params![chrono::Utc::now().timestamp(), id],
+        )
         .map(|n| n > 0)
-        .unwrap_or(false)
+        .unwrap_or(false);
+    invalidate();
+    if removed {
+        logger::info("projects", &format!("Deleted project {}", id));
+    }
+    removed
 }
 
 pub fn replace_all(projects: &[Project]) -> Result<usize, String> {
@@ -139,23 +330,249 @@ pub fn replace_all(projects: &[Project]) -> Result<usize, String> {
         .map_err(|e| e.to_string())?;
     for project in projects {
         let tags = serialize_tags(&project.tags);
+        let routing = Routing::from(project);
         tx.execute(
-            "insert into projects(id,name,path,description,tags,created_at) values(?,?,?,?,?,?)",
+            "insert into projects(id,name,path,description,tags,created_at,token,model_overrides,system_prefix,user_patterns,budget_usd,system_prefix_mode) values(?,?,?,?,?,?,?,?,?,?,?,?)",
             params![
                 project.id,
                 project.name,
                 project.path,
                 project.description,
                 tags,
-                project.created_at
+                project.created_at,
+                routing.token,
+                routing.model_overrides,
+                routing.system_prefix,
+                routing.user_patterns,
+                project.budget_usd,
+                project.system_prefix_mode.as_str()
             ],
         )
         .map_err(|e| e.to_string())?;
     }
     tx.commit().map_err(|e| e.to_string())?;
+    invalidate();
     Ok(projects.len())
 }
 
+// ============================================
+// Request attribution
+// ============================================
+
+/// Projects as last read, for lookups on every request.
+static CACHE: Lazy<RwLock<Option<Arc<Vec<Project>>>>> = Lazy::new(|| RwLock::new(None));
+
+fn cached() -> Arc<Vec<Project>> {
+    if let Some(projects) = CACHE.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return projects.clone();
+    }
+    let projects = Arc::new(list());
+    *CACHE.write().unwrap_or_else(|e| e.into_inner()) = Some(projects.clone());
+    projects
+}
+
+/// Read the projects again on the next lookup, and recount their budgets.
+pub fn invalidate() {
+    *CACHE.write().unwrap_or_else(|e| e.into_inner()) = None;
+    crate::forward::budget::reset_cache();
+}
+
+/// The project a request was attributed to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribution {
+    /// Project name, or the name the client sent for an unknown project
+    pub name: String,
+    /// Id of a configured project
+    pub id: Option<i64>,
+}
+
+impl From<&Project> for Attribution {
+    fn from(project: &Project) -> Self {
+        Attribution {
+            name: project.name.clone(),
+            id: Some(project.id),
+        }
+    }
+}
+
+/// The project whose token is `token`.
+pub fn by_token(token: &str) -> Option<Project> {
+    find_by_token(&cached(), token).cloned()
+}
+
+fn find_by_token<'a>(projects: &'a [Project], token: &str) -> Option<&'a Project> {
+    projects.iter().find(|p| {
+        p.token
+            .as_deref()
+            .is_some_and(|t| !t.is_empty() && t == token)
+    })
+}
+
+/// Name and monthly budget of every project that has one.
+pub fn budgets() -> Vec<(String, f64)> {
+    cached()
+        .iter()
+        .filter_map(|p| Some((p.name.clone(), p.budget_usd.filter(|b| *b > 0.0)?)))
+        .collect()
+}
+
+/// The configured project `id`.
+pub fn by_id(id: i64) -> Option<Project> {
+    cached().iter().find(|p| p.id == id).cloned()
+}
+
+/// Attribute a request by its token, then by the project name it sent.
+pub fn attribute(token: Option<&str>, header: Option<&str>) -> Option<Attribution> {
+    attribute_in(&cached(), token, header)
+}
+
+fn attribute_in(
+    projects: &[Project],
+    token: Option<&str>,
+    header: Option<&str>,
+) -> Option<Attribution> {
+    if let Some(project) = token.and_then(|t| find_by_token(projects, t)) {
+        return Some(project.into());
+    }
+    let name = header?.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(
+        projects
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+            .map(Attribution::from)
+            .unwrap_or_else(|| Attribution {
+                name: name.to_string(),
+                id: None,
+            }),
+    )
+}
+
+/// Attribute a request by its OpenAI `user` or Anthropic `metadata.user_id`.
+pub fn attribute_user(payload: &Value) -> Option<Attribution> {
+    attribute_user_in(&cached(), payload)
+}
+
+fn attribute_user_in(projects: &[Project], payload: &Value) -> Option<Attribution> {
+    let user = payload
+        .get("user")
+        .or_else(|| payload.pointer("/metadata/user_id"))
+        .and_then(Value::as_str)?;
+    projects
+        .iter()
+        .find(|p| {
+            p.user_patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, user))
+        })
+        .map(Attribution::from)
+}
+
+/// Case-insensitive match of `text` against `pattern`, where `*` matches
+/// any run of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let text = text.to_lowercase();
+    if pattern.is_empty() {
+        return false;
+    }
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return text == first;
+    }
+    if !text.starts_with(first) || text.len() < first.len() + last.len() || !text.ends_with(last) {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+impl Project {
+    /// The model to use instead of `requested`, if the project overrides it.
+    pub fn model_override(&self, requested: &str) -> Option<&str> {
+        self.model_overrides
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(requested))
+            .or_else(|| self.model_overrides.get_key_value("*"))
+            .map(|(_, to)| to.as_str())
+            .filter(|to| !to.trim().is_empty())
+    }
+}
+
+/// Add `text` to the system prompt of a request in `dialect`'s format as
+/// `mode` says, adding a system prompt if it has none: Anthropic `system`,
+/// Gemini `systemInstruction`, or a leading OpenAI system message.
+/// Responses API requests (no `messages`) get it in `instructions`.
+pub fn inject_system_prompt(payload: &mut Value, text: &str, mode: PrefixMode, dialect: Dialect) {
+    let Some(body) = payload.as_object_mut() else {
+        return;
+    };
+    let block = || serde_json::json!({"type": "text", "text": text});
+    match dialect {
+        Dialect::Anthropic => match body.get_mut("system") {
+            Some(Value::String(system)) => *system = mode.join(text, system),
+            Some(Value::Array(blocks)) => mode.merge(blocks, block()),
+            _ => {
+                body.insert("system".to_string(), text.into());
+            }
+        },
+        Dialect::Gemini => {
+            let part = serde_json::json!({"text": text});
+            match body
+                .get_mut("systemInstruction")
+                .and_then(|s| s.get_mut("parts"))
+                .and_then(Value::as_array_mut)
+            {
+                Some(parts) => mode.merge(parts, part),
+                None => {
+                    body.insert(
+                        "systemInstruction".to_string(),
+                        serde_json::json!({"parts": [part]}),
+                    );
+                }
+            }
+        }
+        Dialect::OpenAI => {
+            let Some(Value::Array(messages)) = body.get_mut("messages") else {
+                let existing = body
+                    .get("instructions")
+                    .and_then(Value::as_str)
+                    .unwrap_or("");
+                let instructions = mode.join(text, existing);
+                body.insert("instructions".to_string(), instructions.into());
+                return;
+            };
+            let is_system = |m: &Value| {
+                matches!(
+                    m.get("role").and_then(Value::as_str),
+                    Some("system" | "developer")
+                )
+            };
+            if mode == PrefixMode::Replace {
+                messages.retain(|m| !is_system(m));
+            }
+            match messages
+                .first_mut()
+                .filter(|m| is_system(m))
+                .and_then(|m| m.get_mut("content"))
+            {
+                Some(Value::String(content)) => *content = mode.join(text, content),
+                Some(Value::Array(parts)) => mode.merge(parts, block()),
+                _ => messages.insert(0, serde_json::json!({"role": "system", "content": text})),
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ProjectOpenTarget {
     Folder,
@@ -318,3 +735,184 @@ pub fn detect_available_editors() -> Vec<String> {
 
     available
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn project(id: i64, name: &str) -> Project {
+        Project {
+            id,
+            name: name.to_string(),
+            path: String::new(),
+            description: String::new(),
+            tags: Vec::new(),
+            created_at: 0,
+            token: None,
+            model_overrides: BTreeMap::new(),
+            system_prefix: None,
+            system_prefix_mode: PrefixMode::Prepend,
+            user_patterns: Vec::new(),
+            budget_usd: None,
+        }
+    }
+
+    #[test]
+    fn test_projects_are_validated_against_each_other() {
+        let mut cfg = config::Settings {
+            forward_token: Some("forward-token-0123456789".to_string()),
+            ..Default::default()
+        };
+        cfg.models.push(config::ModelCfg {
+            id: "gpt-4o".to_string(),
+            ..Default::default()
+        });
+        let mut existing = project(1, "Relay");
+        existing.token = Some("relay-token-0123456789".to_string());
+
+        let mut candidate = project(2, " relay ");
+        candidate.token = Some("forward-token-0123456789".to_string());
+        candidate
+            .model_overrides
+            .insert("*".to_string(), "GPT-4o".to_string());
+        candidate
+            .model_overrides
+            .insert("a/b".to_string(), "missing".to_string());
+        candidate.budget_usd = Some(-1.0);
+        let issues = config::validate_project(&cfg, &candidate, std::slice::from_ref(&existing));
+        let paths: Vec<&str> = issues.iter().map(|i| i.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["/name", "/token", "/model_overrides/a~1b", "/budget_usd"]
+        );
+
+        candidate.token = existing.token.clone();
+        let issues = config::validate_project(&cfg, &candidate, std::slice::from_ref(&existing));
+        assert!(issues[1].message.contains("another project"));
+
+        // A project may keep its own name and token
+        assert!(
+            config::validate_project(&cfg, &existing, std::slice::from_ref(&existing)).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_requests_are_attributed_and_rerouted() {
+        let mut docs = project(1, "docs-bot");
+        docs.token = Some("proj_docs_secret".to_string());
+        docs.model_overrides
+            .insert("*".to_string(), "gpt-4o-mini".to_string());
+        docs.model_overrides
+            .insert("Claude-Opus".to_string(), "claude-haiku".to_string());
+        let mut ci = project(2, "ci");
+        ci.user_patterns = vec!["ci-*-runner".to_string()];
+        let projects = vec![docs, ci];
+
+        let by_token = attribute_in(&projects, Some("proj_docs_secret"), Some("ci"));
+        assert_eq!(by_token.unwrap().id, Some(1));
+        assert_eq!(
+            attribute_in(&projects, Some("other"), Some("CI"))
+                .unwrap()
+                .id,
+            Some(2)
+        );
+        assert_eq!(
+            attribute_in(&projects, None, Some("adhoc")),
+            Some(Attribution {
+                name: "adhoc".to_string(),
+                id: None
+            })
+        );
+        assert_eq!(attribute_in(&projects, None, None), None);
+
+        let openai = json!({"model": "gpt-4o", "user": "ci-linux-runner"});
+        assert_eq!(attribute_user_in(&projects, &openai).unwrap().name, "ci");
+        let anthropic = json!({"metadata": {"user_id": "ci-mac-runner"}});
+        assert_eq!(
+            attribute_user_in(&projects, &anthropic).unwrap().id,
+            Some(2)
+        );
+        assert!(attribute_user_in(&projects, &json!({"user": "ci-runner-2"})).is_none());
+
+        assert_eq!(
+            projects[0].model_override("claude-opus"),
+            Some("claude-haiku")
+        );
+        assert_eq!(projects[0].model_override("gpt-4o"), Some("gpt-4o-mini"));
+        assert_eq!(projects[1].model_override("gpt-4o"), None);
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("a*b*c", "aXXbYc"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn test_system_prefix_is_merged_with_the_system_prompt() {
+        let prefix = "Answer in English.";
+        let inject = |mut payload: Value, mode, dialect| {
+            inject_system_prompt(&mut payload, prefix, mode, dialect);
+            payload
+        };
+        use PrefixMode::{Append, Prepend, Replace};
+
+        let chat = json!({"messages": [{"role": "system", "content": "Be brief."}]});
+        let prepended = inject(chat.clone(), Prepend, Dialect::OpenAI);
+        assert_eq!(
+            prepended["messages"][0]["content"],
+            "Answer in English.\n\nBe brief."
+        );
+        let appended = inject(chat.clone(), Append, Dialect::OpenAI);
+        assert_eq!(
+            appended["messages"][0]["content"],
+            "Be brief.\n\nAnswer in English."
+        );
+        let replaced = inject(chat, Replace, Dialect::OpenAI);
+        assert_eq!(replaced["messages"][0]["content"], prefix);
+        let instructed = json!({"messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "developer", "content": [{"type": "text", "text": "Use tools."}]},
+            {"role": "user", "content": "hi"},
+        ]});
+        let replaced = inject(instructed, Replace, Dialect::OpenAI);
+        assert_eq!(
+            replaced["messages"],
+            json!([
+                {"role": "system", "content": prefix},
+                {"role": "user", "content": "hi"},
+            ])
+        );
+
+        let chat = inject(
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+            Append,
+            Dialect::OpenAI,
+        );
+        assert_eq!(
+            chat["messages"][0],
+            json!({"role": "system", "content": prefix})
+        );
+        assert_eq!(chat["messages"].as_array().unwrap().len(), 2);
+
+        let responses = inject(json!({"input": "hi"}), Prepend, Dialect::OpenAI);
+        assert_eq!(responses["instructions"], prefix);
+
+        let blocks = json!({"system": [{"type": "text", "text": "Be brief."}]});
+        let prepended = inject(blocks.clone(), Prepend, Dialect::Anthropic);
+        assert_eq!(prepended["system"][0]["text"], prefix);
+        let appended = inject(blocks.clone(), Append, Dialect::Anthropic);
+        assert_eq!(appended["system"][1]["text"], prefix);
+        let replaced = inject(blocks, Replace, Dialect::Anthropic);
+        assert_eq!(
+            replaced["system"],
+            json!([{"type": "text", "text": prefix}])
+        );
+        let messages = inject(json!({"messages": []}), Prepend, Dialect::Anthropic);
+        assert_eq!(messages["system"], prefix);
+
+        let gemini = json!({"systemInstruction": {"parts": [{"text": "Be brief."}]}});
+        let appended = inject(gemini, Append, Dialect::Gemini);
+        assert_eq!(appended["systemInstruction"]["parts"][1]["text"], prefix);
+        let gemini = inject(json!({"contents": []}), Replace, Dialect::Gemini);
+        assert_eq!(gemini["systemInstruction"]["parts"][0]["text"], prefix);
+    }
+}
@@ -0,0 +1,439 @@
+//! Overall state of the relay in one value, for the tray icon.
+//!
+//! [`run`] recomputes it from the server state, the upstream outcomes and
+//! the budgets and the network, every couple of seconds and right away when something
+//! [`poke`]s it: an upstream starting or stopping to fail, a budget being
+//! crossed, the server changing state. Subscribers only wake up when the
+//! result actually differs.
+//!
+//! What changed for the worse is also published as a [`RelayEvent`] on
+//! [`events`]: an upstream that started failing, a budget past 80% or 100%
+//! of its limit, the forward token used from an address not seen before.
+//! The first state after launch is taken as it is, without events.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch, Notify};
+
+use crate::forward::{budget, middleware, outcomes};
+use crate::{config, server};
+
+/// How often the state is recomputed without being poked.
+const INTERVAL: Duration = Duration::from_secs(2);
+/// Share of a budget spent from which it is reported.
+const BUDGET_WARN_RATIO: f64 = 0.8;
+
+/// Worst thing going on, in the order the tray checks them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Starting,
+    Running,
+    /// No network connection, see [`crate::network`]
+    Offline,
+    /// At least one upstream keeps failing
+    Degraded,
+    /// A budget is nearly used up or exceeded
+    BudgetWarning,
+    Stopped,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RelayState {
+    pub level: Level,
+    /// Base URL while running
+    pub url: Option<String>,
+    /// Whether other machines can connect
+    pub lan: bool,
+    pub offline: bool,
+    /// Upstreams with [`outcomes::FAILING_AFTER`] or more failures in a row
+    pub failing_upstreams: Vec<String>,
+    /// Budgets past the warning ratio
+    pub budget_warnings: Vec<BudgetWarning>,
+    /// Why the server is not running
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetWarning {
+    /// "daily global" or "monthly model gpt-4o"
+    pub budget: String,
+    pub spent_usd: f64,
+    pub limit_usd: f64,
+}
+
+impl BudgetWarning {
+    /// 100 once the limit is reached, 80 before.
+    pub fn threshold(&self) -> u32 {
+        if self.spent_usd >= self.limit_usd {
+            100
+        } else {
+            80
+        }
+    }
+}
+
+impl std::fmt::Display for BudgetWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: ${:.2} of ${:.2}",
+            self.budget, self.spent_usd, self.limit_usd
+        )
+    }
+}
+
+/// A change for the worse, for notifications.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelayEvent {
+    UpstreamFailing {
+        upstream: String,
+        /// See [`outcomes::Outcome::last_error_class`]
+        error_class: Option<String>,
+        error: Option<String>,
+    },
+    BudgetThreshold {
+        budget: String,
+        /// 80 or 100
+        percent: u32,
+        spent_usd: f64,
+        limit_usd: f64,
+    },
+    NewClientIp {
+        ip: String,
+    },
+}
+
+impl Default for RelayState {
+    fn default() -> Self {
+        Self {
+            level: Level::Starting,
+            url: None,
+            lan: false,
+            offline: false,
+            failing_upstreams: Vec::new(),
+            budget_warnings: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+impl RelayState {
+    /// One line per problem, for a tooltip.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![match self.level {
+            Level::Starting => "Starting".to_string(),
+            Level::Stopped => "Stopped".to_string(),
+            Level::Error => format!("Error: {}", self.error.as_deref().unwrap_or("unknown")),
+            _ => format!("Running on {}", self.url.as_deref().unwrap_or("?")),
+        }];
+        if self.offline {
+            lines.push("Offline, background jobs paused".to_string());
+        }
+        for id in &self.failing_upstreams {
+            lines.push(format!("Upstream '{}' is failing", id));
+        }
+        for budget in &self.budget_warnings {
+            lines.push(format!("Budget {}", budget));
+        }
+        lines.join("\n")
+    }
+}
+
+static STATE: Lazy<watch::Sender<RelayState>> =
+    Lazy::new(|| watch::channel(RelayState::default()).0);
+static POKED: Notify = Notify::const_new();
+static EVENTS: Lazy<broadcast::Sender<RelayEvent>> = Lazy::new(|| broadcast::channel(64).0);
+/// Addresses the forward token has been used from since the start.
+static CLIENTS: Lazy<Mutex<HashSet<IpAddr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Recompute the state now instead of at the next tick.
+pub fn poke() {
+    POKED.notify_one();
+}
+
+pub fn subscribe() -> watch::Receiver<RelayState> {
+    STATE.subscribe()
+}
+
+pub fn events() -> broadcast::Receiver<RelayEvent> {
+    EVENTS.subscribe()
+}
+
+fn publish(event: RelayEvent) {
+    // No receivers is fine: nobody is listening for notifications
+    let _ = EVENTS.send(event);
+}
+
+/// Note a request presenting the forward token from `ip`. Loopback
+/// addresses are this machine and never reported.
+pub fn token_used_from(ip: IpAddr) {
+    if ip.is_loopback() {
+        return;
+    }
+    let new = CLIENTS.lock().unwrap_or_else(|e| e.into_inner()).insert(ip);
+    if new {
+        publish(RelayEvent::NewClientIp { ip: ip.to_string() });
+    }
+}
+
+/// Route layer calling [`token_used_from`] for the forward routes.
+pub async fn client_layer(req: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let cfg = config::current();
+        let now = chrono::Utc::now().timestamp();
+        if middleware::extract_request_token(req.headers())
+            .is_some_and(|token| cfg.forward_token_version(&token, now).is_some())
+        {
+            token_used_from(addr.ip());
+        }
+    }
+    next.run(req).await
+}
+
+/// Keep the state current, until the process exits.
+pub async fn run() {
+    tokio::spawn(server::watch_state(|_| poke()));
+    let mut previous: Option<RelayState> = None;
+    loop {
+        let cfg = config::current();
+        let state = compute(
+            &server::state(),
+            &cfg,
+            crate::network::is_online(),
+            |id| outcomes::get(id).consecutive_failures,
+            &budget::status(),
+        );
+        if let Some(previous) = previous.as_ref() {
+            for event in changes(previous, &state, outcomes::get) {
+                publish(event);
+            }
+        }
+        previous = Some(state.clone());
+        STATE.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            *current = state;
+            true
+        });
+        tokio::select! {
+            _ = tokio::time::sleep(INTERVAL) => {}
+            _ = POKED.notified() => {}
+        }
+    }
+}
+
+fn compute(
+    server: &server::ServerState,
+    cfg: &config::Settings,
+    online: bool,
+    failures: impl Fn(&str) -> u32,
+    budgets: &[budget::BudgetStatus],
+) -> RelayState {
+    let failing_upstreams: Vec<String> = cfg
+        .upstreams
+        .iter()
+        .filter(|up| failures(&up.id) >= outcomes::FAILING_AFTER)
+        .map(|up| up.id.clone())
+        .collect();
+    let budget_warnings: Vec<BudgetWarning> = budgets
+        .iter()
+        .filter(|b| b.valid && b.limit_usd > 0.0)
+        .filter(|b| b.exceeded || b.spent_usd >= b.limit_usd * BUDGET_WARN_RATIO)
+        .map(|b| BudgetWarning {
+            budget: match b.target.as_deref() {
+                Some(target) => format!("{} {} {}", b.period, b.scope, target),
+                None => format!("{} {}", b.period, b.scope),
+            },
+            spent_usd: b.spent_usd,
+            limit_usd: b.limit_usd,
+        })
+        .collect();
+
+    let (level, url, error) = match server {
+        server::ServerState::Starting => (Level::Starting, None, None),
+        server::ServerState::Stopped => (Level::Stopped, None, None),
+        server::ServerState::Error { message, .. } => (Level::Error, None, Some(message.clone())),
+        server::ServerState::Running { url, .. } => {
+            // Offline explains failing upstreams, so it goes first
+            let level = if !online {
+                Level::Offline
+            } else if !failing_upstreams.is_empty() {
+                Level::Degraded
+            } else if !budget_warnings.is_empty() {
+                Level::BudgetWarning
+            } else {
+                Level::Running
+            };
+            (level, url.clone(), None)
+        }
+    };
+    RelayState {
+        level,
+        url,
+        lan: server::lan_reachable(&cfg.server),
+        offline: !online,
+        failing_upstreams,
+        budget_warnings,
+        error,
+    }
+}
+
+/// Events for what got worse from `before` to `after`.
+fn changes(
+    before: &RelayState,
+    after: &RelayState,
+    outcome: impl Fn(&str) -> outcomes::Outcome,
+) -> Vec<RelayEvent> {
+    let mut events = Vec::new();
+    for id in &after.failing_upstreams {
+        if !before.failing_upstreams.contains(id) {
+            let outcome = outcome(id);
+            events.push(RelayEvent::UpstreamFailing {
+                upstream: id.clone(),
+                error_class: outcome.last_error_class.map(str::to_string),
+                error: outcome.last_error,
+            });
+        }
+    }
+    for warning in &after.budget_warnings {
+        let was = before
+            .budget_warnings
+            .iter()
+            .find(|b| b.budget == warning.budget)
+            .map_or(0, BudgetWarning::threshold);
+        if warning.threshold() > was {
+            events.push(RelayEvent::BudgetThreshold {
+                budget: warning.budget.clone(),
+                percent: warning.threshold(),
+                spent_usd: warning.spent_usd,
+                limit_usd: warning.limit_usd,
+            });
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(spent_usd: f64, exceeded: bool) -> budget::BudgetStatus {
+        budget::BudgetStatus {
+            scope: "global".to_string(),
+            target: None,
+            period: "daily".to_string(),
+            action: "block".to_string(),
+            limit_usd: 10.0,
+            spent_usd,
+            remaining_usd: (10.0 - spent_usd).max(0.0),
+            period_start: 0,
+            exceeded,
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn test_worst_problem_wins() {
+        let mut cfg = config::Settings::default();
+        for id in ["good", "bad"] {
+            cfg.upstreams.push(config::Upstream {
+                id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        let running = server::ServerState::Running {
+            address: Some("127.0.0.1:8787".to_string()),
+            url: Some("http://127.0.0.1:8787".to_string()),
+        };
+        let failures = |id: &str| match id {
+            "bad" => outcomes::FAILING_AFTER,
+            _ => 1,
+        };
+
+        let state = compute(&running, &cfg, true, |_| 0, &[budget(5.0, false)]);
+        assert_eq!(state.level, Level::Running);
+        assert!(state.budget_warnings.is_empty());
+
+        let state = compute(&running, &cfg, true, |_| 0, &[budget(8.5, false)]);
+        assert_eq!(state.level, Level::BudgetWarning);
+        assert_eq!(
+            state.budget_warnings[0].to_string(),
+            "daily global: $8.50 of $10.00"
+        );
+
+        let state = compute(&running, &cfg, true, failures, &[budget(8.5, false)]);
+        assert_eq!(state.level, Level::Degraded);
+        assert_eq!(state.failing_upstreams, vec!["bad"]);
+        assert!(state.summary().contains("Upstream 'bad' is failing"));
+
+        let state = compute(&running, &cfg, false, failures, &[]);
+        assert_eq!(state.level, Level::Offline);
+        assert!(state.summary().contains("Offline"));
+
+        let state = compute(&server::ServerState::Stopped, &cfg, true, failures, &[]);
+        assert_eq!(state.level, Level::Stopped);
+        assert_eq!(state.url, None);
+    }
+
+    #[test]
+    fn test_changes_for_the_worse() {
+        let warning = |spent_usd| BudgetWarning {
+            budget: "daily global".to_string(),
+            spent_usd,
+            limit_usd: 10.0,
+        };
+        let before = RelayState {
+            failing_upstreams: vec!["a".to_string()],
+            budget_warnings: vec![warning(8.0)],
+            ..Default::default()
+        };
+        let after = RelayState {
+            failing_upstreams: vec!["a".to_string(), "b".to_string()],
+            budget_warnings: vec![warning(10.5)],
+            ..Default::default()
+        };
+        let outcome = |_: &str| outcomes::Outcome {
+            last_error_class: Some("timeout"),
+            ..Default::default()
+        };
+        let events = changes(&before, &after, outcome);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[0],
+            RelayEvent::UpstreamFailing { upstream, error_class: Some(class), .. }
+                if upstream == "b" && class == "timeout"
+        ));
+        assert!(matches!(
+            events[1],
+            RelayEvent::BudgetThreshold { percent: 100, .. }
+        ));
+
+        // Recovering, or staying where it was, is not news
+        assert!(changes(&after, &before, outcome).is_empty());
+        assert!(changes(&after, &after, outcome).is_empty());
+
+        token_used_from("127.0.0.1".parse().unwrap());
+        let mut receiver = super::events();
+        token_used_from("198.51.100.4".parse().unwrap());
+        token_used_from("198.51.100.4".parse().unwrap());
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            RelayEvent::NewClientIp {
+                ip: "198.51.100.4".to_string()
+            }
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
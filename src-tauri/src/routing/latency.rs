@@ -1,117 +1,265 @@
-use reqwest::Client;
-use serde::Serialize;
-use std::process::Command;
+//! Endpoint latency probes for the latency tester and upstream checks.
+//!
+//! Endpoints are probed [`CONCURRENCY`] at a time, each with [`SAMPLES`]
+//! `HEAD` requests on fresh connections so every sample includes connect and
+//! TLS setup. A probe never takes longer than [`PROBE_TIMEOUT`], so a dead host
+//! only holds up its own row. Failures are classified by the step that
+//! failed: name resolution, connecting, the TLS handshake or the HTTP status.
+
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
-#[cfg(target_os = "windows")]
-use std::os::windows::process::CommandExt;
+use futures_util::StreamExt;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+/// Endpoints probed at once
+const CONCURRENCY: usize = 8;
+/// Requests timed per endpoint
+const SAMPLES: usize = 3;
+/// Budget of one endpoint, DNS and all samples included
+const PROBE_TIMEOUT: Duration = Duration::from_secs(8);
+/// Budget of one sample
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The step a probe failed at.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeErrorKind {
+    InvalidUrl,
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    /// An answer, but not a successful one
+    HttpStatus,
+    Other,
+}
 
-#[cfg(target_os = "windows")]
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+#[derive(Debug, Serialize, Clone)]
+pub struct ProbeError {
+    pub kind: ProbeErrorKind,
+    pub message: String,
+}
 
 #[derive(Debug, Serialize, Clone)]
 pub struct LatencyStat {
     pub endpoint: String,
+    /// Every sample got a successful answer
     pub ok: bool,
-    pub ms: Option<u128>,
+    /// Samples that got an answer, whatever its status
+    pub samples: usize,
+    pub min_ms: Option<u64>,
+    pub avg_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    /// Status of the last answer
+    pub status: Option<u16>,
+    /// Why the last failed sample failed
+    pub error: Option<ProbeError>,
 }
 
-fn curl_sink() -> &'static str {
-    if cfg!(target_os = "windows") {
-        "NUL"
-    } else {
-        "/dev/null"
+impl LatencyStat {
+    fn failed(endpoint: &str, kind: ProbeErrorKind, message: impl Into<String>) -> Self {
+        LatencyStat {
+            endpoint: endpoint.to_string(),
+            ok: false,
+            samples: 0,
+            min_ms: None,
+            avg_ms: None,
+            p95_ms: None,
+            status: None,
+            error: Some(ProbeError {
+                kind,
+                message: message.into(),
+            }),
+        }
     }
 }
 
-fn measure_with_curl(url: &str) -> Option<LatencyStat> {
-    // Use curl to measure total time; fallback handled by caller.
-    let sink = curl_sink();
-    let mut cmd = Command::new("curl");
-    #[cfg(target_os = "windows")]
-    {
-        // Avoid popping console windows on Windows GUI apps.
-        cmd.creation_flags(CREATE_NO_WINDOW);
+/// Measure latency for a list of endpoints, in the order given.
+pub async fn measure_all(urls: Vec<String>) -> Vec<LatencyStat> {
+    futures_util::stream::iter(urls)
+        .map(|url| async move {
+            tokio::time::timeout(PROBE_TIMEOUT, measure(&url))
+                .await
+                .unwrap_or_else(|_| {
+                    LatencyStat::failed(&url, ProbeErrorKind::Timeout, "probe timed out")
+                })
+        })
+        .buffered(CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// Probe one endpoint: resolve it once, then time each sample.
+async fn measure(endpoint: &str) -> LatencyStat {
+    let url = match Url::parse(endpoint.trim()) {
+        Ok(url) if url.host_str().is_some() => url,
+        _ => return LatencyStat::failed(endpoint, ProbeErrorKind::InvalidUrl, "not a URL"),
+    };
+    let addr = match resolve(&url).await {
+        Ok(addr) => addr,
+        Err(e) => return LatencyStat::failed(endpoint, ProbeErrorKind::Dns, e),
+    };
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(0)
+        .connect_timeout(SAMPLE_TIMEOUT)
+        .timeout(SAMPLE_TIMEOUT);
+    if let Some(domain) = url.domain() {
+        // Samples time the connection, not the resolver
+        builder = builder.resolve(domain, addr);
     }
-    let output = cmd
-        .args([
-            "-o",
-            sink,
-            "-s",
-            "-w",
-            "%{time_total}",
-            "-m",
-            "5",
-            "-I",
-            url,
-        ])
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        return Some(LatencyStat {
-            endpoint: url.to_string(),
-            ok: false,
-            ms: None,
-        });
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => return LatencyStat::failed(endpoint, ProbeErrorKind::Other, e.to_string()),
+    };
+
+    let mut times = Vec::with_capacity(SAMPLES);
+    let (mut status, mut error) = (None, None);
+    for _ in 0..SAMPLES {
+        let start = Instant::now();
+        match client.head(url.clone()).send().await {
+            Ok(resp) => {
+                times.push(start.elapsed().as_millis() as u64);
+                status = Some(resp.status().as_u16());
+                if !resp.status().is_success() {
+                    error = Some(ProbeError {
+                        kind: ProbeErrorKind::HttpStatus,
+                        message: resp.status().to_string(),
+                    });
+                }
+            }
+            Err(e) => {
+                error = Some(classify(&e));
+                // Neither will the next sample connect
+                if !e.is_timeout() {
+                    break;
+                }
+            }
+        }
     }
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    if let Ok(seconds) = stdout.trim().parse::<f64>() {
-        let ms = (seconds * 1000.0) as u128;
-        return Some(LatencyStat {
-            endpoint: url.to_string(),
-            ok: true,
-            ms: Some(ms),
-        });
+    let samples = times.len();
+    let (min_ms, avg_ms, p95_ms) = summarize(&mut times);
+    LatencyStat {
+        endpoint: endpoint.to_string(),
+        ok: error.is_none(),
+        samples,
+        min_ms,
+        avg_ms,
+        p95_ms,
+        status,
+        error,
     }
-    None
 }
 
-/// Measure latency for a list of endpoints, returning the elapsed ms (if any) for each.
-pub async fn measure_all(urls: Vec<String>) -> Vec<LatencyStat> {
-    let client = Client::new();
-    let mut stats = Vec::new();
-    for u in urls {
-        if let Some(curl_result) = measure_with_curl(&u) {
-            stats.push(curl_result);
-            continue;
+/// The first address `url`'s host resolves to.
+async fn resolve(url: &Url) -> Result<SocketAddr, String> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(443);
+    // IPv6 literals come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", host))
+}
+
+/// Which step `err` failed at.
+fn classify(err: &reqwest::Error) -> ProbeError {
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(err);
+    while let Some(inner) = source {
+        message = format!("{}: {}", message, inner);
+        source = inner.source();
+    }
+    let kind = if err.is_timeout() {
+        ProbeErrorKind::Timeout
+    } else if err.is_connect() {
+        let lower = message.to_ascii_lowercase();
+        if ["tls", "certificate", "handshake"]
+            .iter()
+            .any(|word| lower.contains(word))
+        {
+            ProbeErrorKind::Tls
+        } else {
+            ProbeErrorKind::Connect
         }
-        let start = Instant::now();
-        match client.head(&u).timeout(Duration::from_secs(5)).send().await {
-            Ok(resp) => {
-                let elapsed = start.elapsed().as_millis();
-                stats.push(LatencyStat {
-                    endpoint: u,
-                    ok: resp.status().is_success(),
-                    ms: Some(elapsed),
-                });
-            }
-            Err(_) => stats.push(LatencyStat {
-                endpoint: u,
-                ok: false,
-                ms: None,
-            }),
-        };
+    } else {
+        ProbeErrorKind::Other
+    };
+    ProbeError { kind, message }
+}
+
+/// Min, mean and nearest-rank 95th percentile of `times`.
+fn summarize(times: &mut [u64]) -> (Option<u64>, Option<u64>, Option<u64>) {
+    if times.is_empty() {
+        return (None, None, None);
     }
-    stats
+    times.sort_unstable();
+    let avg = times.iter().sum::<u64>() / times.len() as u64;
+    let rank = (times.len() * 95).div_ceil(100);
+    (Some(times[0]), Some(avg), Some(times[rank - 1]))
 }
 
 /// Return the fastest available endpoint (best-effort).
 #[allow(dead_code)]
 pub async fn probe(urls: Vec<String>) -> Option<String> {
-    let mut best: Option<(String, Duration)> = None;
-    for stat in measure_all(urls).await {
-        if stat.ok {
-            if let Some(ms) = stat.ms {
-                let dur = Duration::from_millis(ms as u64);
-                if let Some((_, best_dur)) = &best {
-                    if dur < *best_dur {
-                        best = Some((stat.endpoint, dur));
-                    }
-                } else {
-                    best = Some((stat.endpoint, dur));
-                }
+    measure_all(urls)
+        .await
+        .into_iter()
+        .filter(|stat| stat.ok)
+        .filter_map(|stat| Some((stat.avg_ms?, stat.endpoint)))
+        .min()
+        .map(|(_, endpoint)| endpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_summarize_samples() {
+        assert_eq!(summarize(&mut []), (None, None, None));
+        assert_eq!(summarize(&mut [30, 10, 20]), (Some(10), Some(20), Some(30)));
+        let mut times: Vec<u64> = (1..=100).collect();
+        assert_eq!(summarize(&mut times), (Some(1), Some(50), Some(95)));
+    }
+
+    #[tokio::test]
+    async fn test_probes_keep_order_and_classify_failures() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await;
             }
-        }
+        });
+        // Bound and dropped, so nothing listens there
+        let dead = {
+            let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", socket.local_addr().unwrap())
+        };
+
+        let started = Instant::now();
+        let stats = measure_all(vec![dead.clone(), live.clone(), "not a url".to_string()]).await;
+        assert!(started.elapsed() < PROBE_TIMEOUT);
+        assert_eq!(stats[0].endpoint, dead);
+        assert_eq!(
+            stats[0].error.as_ref().unwrap().kind,
+            ProbeErrorKind::Connect
+        );
+        assert!(stats[1].ok, "{:?}", stats[1]);
+        assert_eq!((stats[1].samples, stats[1].status), (SAMPLES, Some(200)));
+        assert!(stats[1].min_ms <= stats[1].p95_ms);
+        assert_eq!(
+            stats[2].error.as_ref().unwrap().kind,
+            ProbeErrorKind::InvalidUrl
+        );
     }
-    best.map(|b| b.0)
 }
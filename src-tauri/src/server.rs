@@ -1,19 +1,80 @@
 use axum::{
+    body::{Body, Bytes},
     extract::{Path, Query},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post, put},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
     Json, Router,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use futures_util::StreamExt;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use hyper_util::service::TowerToHyperService;
+use once_cell::sync::Lazy;
 use serde_json::{json, Value};
-use std::net::SocketAddr;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
 
-use crate::{autoconfig, config, db, forward, logger, projects, tools};
+use crate::coalesce::Coalesced;
+use crate::{
+    access_log, autoconfig, bundle, config, connections, db, discovery, forward, health, logger,
+    maintenance, network, price_sync, profile, projects, relay_state, status_page, telemetry,
+    tools, webhooks,
+};
 
 async fn health() -> Json<Value> {
-    Json(json!({"status": "ok"}))
+    Json(json!({"status": "ok", "profile": profile::active()}))
+}
+
+/// Detailed health reports, shared by monitors asking at once
+static HEALTH_REPORTS: Lazy<Coalesced<Result<Arc<health::Report>, String>>> =
+    Lazy::new(Coalesced::new);
+
+/// Every check with its own status; 503 when any of them is an error.
+async fn health_detail(headers: HeaderMap) -> Response {
+    if !admin_authorized(&headers, None) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    let report = HEALTH_REPORTS
+        .get("health", || async {
+            tokio::task::spawn_blocking(health::report)
+                .await
+                .map(Arc::new)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    match report {
+        Ok(report) => {
+            let status = if report.status == health::Status::Error {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            (status, Json(&*report)).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e})),
+        )
+            .into_response(),
+    }
 }
 
 #[derive(Deserialize)]
@@ -24,11 +85,23 @@ struct SummaryQ {
 async fn stats_summary(Query(q): Query<SummaryQ>) -> Json<Value> {
     let range = q.range.unwrap_or_else(|| "daily".to_string());
     let (reqs, tokens, price) = db::summary_for_range(&range);
+    let details = db::token_details_for_range(&range);
     Json(json!({
         "range": range,
         "requests": reqs,
         "tokens": tokens,
-        "price_usd": price
+        "price_usd": price,
+        "cache_creation_tokens": details.cache_creation_tokens,
+        "cache_read_tokens": details.cache_read_tokens,
+        "reasoning_tokens": details.reasoning_tokens,
+        "unpriced_requests": details.unpriced_requests,
+        "estimated_requests": details.estimated_requests,
+        "estimated_tokens": details.estimated_tokens,
+        "estimated_token_ratio": if details.total_tokens > 0 {
+            details.estimated_tokens as f64 / details.total_tokens as f64
+        } else {
+            0.0
+        }
     }))
 }
 
@@ -91,15 +164,240 @@ async fn stats_logs(Query(q): Query<LogsQ>) -> Json<Value> {
     }))
 }
 
+async fn usage_summary(Query(q): Query<db::UsageSummaryQuery>) -> impl IntoResponse {
+    match db::usage_summary(&q) {
+        Ok(summary) => Json(summary).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({"error": err}))).into_response(),
+    }
+}
+
+async fn list_captured_requests(
+    Query(q): Query<db::CapturedRequestQuery>,
+) -> Json<Vec<db::CapturedRequest>> {
+    Json(db::list_captured_requests(&q))
+}
+
+async fn get_captured_request(Path(id): Path<i64>) -> impl IntoResponse {
+    match db::get_captured_request(id) {
+        Some(entry) => Json(entry).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Request capture not found"})),
+        )
+            .into_response(),
+    }
+}
+
+/// Send a captured request again and answer with both responses.
+async fn replay_captured_request(
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    options: Option<Json<forward::replay::ReplayOptions>>,
+) -> Response {
+    if !admin_authorized(&headers, None) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    forward::replay::replay(id, options.map(|Json(o)| o).unwrap_or_default()).await
+}
+
+/// Every log line recorded for one request id (`x-relay-request-id`).
+async fn request_logs(Path(request_id): Path<String>) -> Json<Value> {
+    let logs = tokio::task::spawn_blocking({
+        let request_id = request_id.clone();
+        move || logger::logs_for_request(&request_id)
+    })
+    .await
+    .unwrap_or_default();
+    Json(json!({"request_id": request_id, "logs": logs}))
+}
+
+async fn run_maintenance() -> impl IntoResponse {
+    match tokio::task::spawn_blocking(maintenance::run_now).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(err)) => (StatusCode::CONFLICT, Json(json!({"error": err}))).into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": err.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn preview_prices() -> impl IntoResponse {
+    match price_sync::preview().await {
+        Ok(changes) => Json(changes).into_response(),
+        Err(err) => (StatusCode::BAD_GATEWAY, Json(json!({"error": err}))).into_response(),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct RefreshPricesBody {
+    model_ids: Option<Vec<String>>,
+}
+
+async fn refresh_prices(body: Option<Json<RefreshPricesBody>>) -> impl IntoResponse {
+    let body = body.map(|Json(b)| b).unwrap_or_default();
+    match price_sync::apply(body.model_ids).await {
+        Ok(changes) => Json(changes).into_response(),
+        Err(err) => (StatusCode::BAD_GATEWAY, Json(json!({"error": err}))).into_response(),
+    }
+}
+
+async fn budget_status() -> Json<Vec<forward::budget::BudgetStatus>> {
+    Json(forward::budget::status())
+}
+
+async fn response_cache_stats() -> Json<forward::cache::CacheStats> {
+    Json(forward::cache::stats())
+}
+
+async fn queue_stats() -> Json<Vec<forward::queue::QueueStats>> {
+    Json(forward::queue::stats())
+}
+
+#[derive(Deserialize)]
+struct MirrorReportQ {
+    days: Option<i64>,
+}
+
+/// Mirrored requests against their primaries, per upstream pair.
+async fn mirror_report(Query(q): Query<MirrorReportQ>) -> impl IntoResponse {
+    let since = chrono::Utc::now().timestamp() - q.days.unwrap_or(7).max(0) * 86_400;
+    match tokio::task::spawn_blocking(move || db::mirror_report(since)).await {
+        Ok(Ok(rows)) => Json(json!({ "rows": rows })).into_response(),
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": err})),
+        )
+            .into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": err.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn experiment_report(Path(id): Path<String>) -> impl IntoResponse {
+    match tokio::task::spawn_blocking(move || forward::experiments::report(&id)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(err)) => (StatusCode::NOT_FOUND, Json(json!({"error": err}))).into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": err.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct EndExperimentBody {
+    winner: String,
+}
+
+/// End an experiment: its winning variant serves the model from now on.
+async fn end_experiment(
+    Path(id): Path<String>,
+    Json(body): Json<EndExperimentBody>,
+) -> impl IntoResponse {
+    match config::end_experiment(&id, &body.winner) {
+        Ok(warnings) => Json(json!({"warnings": warnings})).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(err)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageExportQ {
+    format: Option<String>,
+    from: Option<i64>,
+    to: Option<i64>,
+    /// Only requests carrying this tag
+    tag: Option<String>,
+}
+
+/// `io::Write` adapter that forwards buffered chunks to a response body stream.
+struct ChannelWriter {
+    tx: tokio::sync::mpsc::Sender<Bytes>,
+}
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tx
+            .blocking_send(Bytes::copy_from_slice(buf))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn usage_export(Query(q): Query<UsageExportQ>) -> impl IntoResponse {
+    let format_name = q.format.as_deref().unwrap_or("csv");
+    let Some(format) = db::ExportFormat::from_str(format_name) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Unsupported format: {}", format_name)})),
+        )
+            .into_response();
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(16);
+    let (from, to, tag) = (q.from, q.to, q.tag);
+    tokio::task::spawn_blocking(move || {
+        let mut out = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter { tx });
+        if let Err(err) = db::export_usage(from, to, tag.as_deref(), format, &mut out) {
+            logger::warn("server", &format!("Usage export aborted: {}", err));
+        }
+    });
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (Ok::<_, std::io::Error>(chunk), rx))
+    });
+
+    let filename = format!(
+        "ccr-usage-{}.{}",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+        format.extension()
+    );
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(Body::from_stream(body))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
 async fn list_projects() -> Json<Vec<projects::Project>> {
     Json(projects::list())
 }
 
+async fn get_project(Path(id): Path<i64>) -> impl IntoResponse {
+    match projects::get(id) {
+        Some(project) => Json(project).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn project_error(err: config::SaveError) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": err.message, "issues": err.issues})),
+    )
+        .into_response()
+}
+
 async fn create_project(Json(input): Json<projects::ProjectInput>) -> impl IntoResponse {
-    if let Some(project) = projects::create(input) {
-        (StatusCode::CREATED, Json(project)).into_response()
-    } else {
-        StatusCode::BAD_REQUEST.into_response()
+    match projects::create(input) {
+        Ok(project) => (StatusCode::CREATED, Json(project)).into_response(),
+        Err(err) => project_error(err),
     }
 }
 
@@ -107,10 +405,10 @@ async fn update_project(
     Path(id): Path<i64>,
     Json(input): Json<projects::ProjectInput>,
 ) -> impl IntoResponse {
-    if let Some(project) = projects::update(id, input) {
-        Json(project).into_response()
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+    match projects::update(id, input) {
+        Ok(Some(project)) => Json(project).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => project_error(err),
     }
 }
 
@@ -210,7 +508,7 @@ async fn environment_report() -> Json<tools::EnvironmentReport> {
 }
 
 async fn get_config() -> Json<config::Settings> {
-    Json(config::load())
+    Json(config::load_raw())
 }
 
 async fn put_config(Json(body): Json<config::Settings>) -> impl IntoResponse {
@@ -219,6 +517,14 @@ async fn put_config(Json(body): Json<config::Settings>) -> impl IntoResponse {
         body.models.len(),
         body.upstreams.len()
     );
+    let issues = config::validate(&body);
+    if issues.iter().any(|i| i.severity == config::Severity::Error) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Invalid configuration", "issues": issues})),
+        )
+            .into_response();
+    }
     match config::save(&body) {
         Ok(_) => {
             eprintln!("Config saved successfully via API");
@@ -231,6 +537,77 @@ async fn put_config(Json(body): Json<config::Settings>) -> impl IntoResponse {
     }
 }
 
+async fn validate_config(Json(body): Json<config::Settings>) -> impl IntoResponse {
+    let issues = config::validate(&body);
+    let valid = !issues.iter().any(|i| i.severity == config::Severity::Error);
+    Json(json!({"valid": valid, "issues": issues}))
+}
+
+#[derive(Deserialize)]
+struct ExportConfigQ {
+    passphrase: Option<String>,
+}
+
+async fn export_config(Query(q): Query<ExportConfigQ>) -> impl IntoResponse {
+    match bundle::export(&config::load_raw(), q.passphrase.as_deref()) {
+        Ok(bundle) => Json(bundle).into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": err})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ImportConfigQ {
+    mode: Option<String>,
+    prefer: Option<String>,
+    passphrase: Option<String>,
+}
+
+fn import_options(q: &ImportConfigQ) -> Result<(bundle::ImportMode, bundle::Prefer), String> {
+    let mode = q.mode.as_deref().unwrap_or_default();
+    let mode = bundle::ImportMode::from_str(mode)
+        .ok_or_else(|| format!("Unknown import mode: {}", mode))?;
+    let prefer = q.prefer.as_deref().unwrap_or_default();
+    let prefer = bundle::Prefer::from_str(prefer)
+        .ok_or_else(|| format!("Unknown prefer value: {}", prefer))?;
+    Ok((mode, prefer))
+}
+
+/// Import a bundle, backup, settings file or keys file given as the body.
+async fn import_config(Query(q): Query<ImportConfigQ>, body: String) -> impl IntoResponse {
+    let result = import_options(&q).and_then(|(mode, prefer)| {
+        let source = bundle::parse(&body)?;
+        bundle::import(source, mode, prefer, q.passphrase.as_deref())
+    });
+    match result {
+        Ok(report) => Json(report).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({"error": err}))).into_response(),
+    }
+}
+
+/// What `POST /api/config/import` would change, without saving.
+async fn preview_import(Query(q): Query<ImportConfigQ>, body: String) -> impl IntoResponse {
+    let result = import_options(&q).and_then(|(mode, prefer)| {
+        let source = bundle::parse(&body)?;
+        let cfg = config::load_raw();
+        bundle::preview(&cfg, source, mode, prefer, q.passphrase.as_deref())
+    });
+    match result {
+        Ok(preview) => Json(preview).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({"error": err}))).into_response(),
+    }
+}
+
+async fn reload_config() -> impl IntoResponse {
+    match config::reload() {
+        Ok(report) => Json(report).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({"error": err}))).into_response(),
+    }
+}
+
 async fn list_providers() -> Json<Value> {
     forward::list_api_styles().await
 }
@@ -239,6 +616,18 @@ async fn upstream_latency(Path(id): Path<String>) -> Json<Value> {
     forward::upstream_latency(Path(id)).await
 }
 
+/// Latest rate limits each key of an upstream reported.
+async fn upstream_ratelimits(Path(id): Path<String>) -> Response {
+    if !config::current().upstreams.iter().any(|u| u.id == id) {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("Upstream '{}' not found", id)})),
+        )
+            .into_response();
+    }
+    Json(forward::ratelimits::of_upstream(&id)).into_response()
+}
+
 async fn test_latency_urls(Json(urls): Json<Vec<String>>) -> Json<Value> {
     forward::test_latency_urls(Json(urls)).await
 }
@@ -247,12 +636,53 @@ async fn get_forward_token() -> Json<Value> {
     forward::get_forward_token().await
 }
 
-async fn refresh_forward_token() -> Json<Value> {
-    forward::refresh_forward_token().await
+async fn refresh_forward_token(
+    request: Option<Json<forward::RefreshTokenRequest>>,
+) -> Response {
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+    forward::refresh_forward_token(request).await
+}
+
+async fn preview_route(Json(request): Json<forward::RoutePreviewRequest>) -> Response {
+    forward::preview_route(request).await
+}
+
+async fn transform_examples() -> Json<Vec<forward::transform::Example>> {
+    Json(forward::transform::examples())
+}
+
+async fn preview_transform(
+    headers: HeaderMap,
+    Query(query): Query<forward::transform::PreviewQuery>,
+    payload: Option<Json<Value>>,
+) -> Response {
+    if !admin_authorized(&headers, None) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    forward::transform::preview(&headers, query, payload.map(|Json(p)| p))
+}
+
+async fn preview_prompt_redaction(
+    headers: HeaderMap,
+    Query(query): Query<forward::transform::PreviewQuery>,
+    payload: Option<Json<Value>>,
+) -> Response {
+    if !admin_authorized(&headers, None) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    forward::scrub::preview(&headers, query, payload.map(|Json(p)| p))
 }
 
 async fn export_backup() -> Json<Value> {
-    let cfg = config::load();
+    let cfg = config::load_raw();
     let projects = projects::list();
     let tools = tools::list();
     let daily = db::summary_for_range("daily");
@@ -322,6 +752,7 @@ async fn clear_all_data() -> impl IntoResponse {
 
 fn clear_all_data_inner() -> Result<Value, String> {
     db::clear_all_data()?;
+    forward::budget::reset_cache();
     let logs_deleted = logger::clear_all_logs()?;
     let install_logs_deleted = logger::clear_install_logs()?;
     autoconfig::clear_all_backups()?;
@@ -347,6 +778,19 @@ async fn configure_auto_config(
     }
 }
 
+#[derive(Deserialize)]
+struct SnippetQ {
+    editor: String,
+    model: String,
+}
+
+async fn get_editor_snippet(Query(q): Query<SnippetQ>) -> impl IntoResponse {
+    match autoconfig::editor_snippet(&q.editor, &q.model) {
+        Ok(snippet) => Json(snippet).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({"error": err}))).into_response(),
+    }
+}
+
 // Backup handlers
 async fn list_tool_backups(Path(tool): Path<String>) -> Json<autoconfig::ToolConfigBackupList> {
     Json(autoconfig::list_backups(&tool))
@@ -406,6 +850,123 @@ async fn get_global_logs(Query(q): Query<GlobalLogsQuery>) -> Json<Value> {
     }))
 }
 
+#[derive(Deserialize)]
+struct LogStreamQ {
+    level: Option<String>,
+    module: Option<String>,
+    /// Buffered entries sent first (default 100)
+    backlog: Option<usize>,
+    /// Forward token, for clients like `EventSource` that can't set headers
+    token: Option<String>,
+}
+
+/// With a forward token configured, only callers presenting it may read
+/// the live log, the activity feed or the detailed health report.
+fn admin_authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let cfg = config::current();
+    if cfg.forward_token.as_deref().is_none_or(str::is_empty) {
+        return true;
+    }
+    let now = chrono::Utc::now().timestamp();
+    token
+        .map(str::to_string)
+        .or_else(|| forward::middleware::extract_request_token(headers))
+        .is_some_and(|t| cfg.forward_token_version(&t, now).is_some())
+}
+
+/// Live tail of the log as server-sent events, starting with the most
+/// recent buffered entries. A client too slow to keep up is disconnected.
+async fn stream_global_logs(headers: HeaderMap, Query(q): Query<LogStreamQ>) -> Response {
+    if !admin_authorized(&headers, q.token.as_deref()) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    let level = match q.level.as_deref().filter(|l| !l.is_empty()) {
+        Some(l) => match logger::LogLevel::from_str(l) {
+            Some(level) => Some(level),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": format!("Unknown log level '{}'", l)})),
+                )
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+    let filter = logger::LiveFilter {
+        level,
+        module: q.module,
+    };
+    let (backlog, receiver) = logger::subscribe(&filter, q.backlog.unwrap_or(100));
+
+    let event = |entry: &logger::LiveEntry| -> Result<Event, Infallible> {
+        Ok(Event::default()
+            .event("log")
+            .data(serde_json::to_string(entry).unwrap_or_default()))
+    };
+    let live = futures_util::stream::unfold(
+        (receiver, filter),
+        move |(mut receiver, filter)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(entry) if filter.matches(&entry) => {
+                        return Some((event(&entry), (receiver, filter)))
+                    }
+                    Ok(_) => continue,
+                    // Lagged or closed: drop the subscriber rather than
+                    // holding entries back for it
+                    Err(_) => return None,
+                }
+            }
+        },
+    );
+    let stream =
+        futures_util::stream::iter(backlog.iter().map(event).collect::<Vec<_>>()).chain(live);
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct ActivityStreamQ {
+    /// Forward token, for clients like `EventSource` that can't set headers
+    token: Option<String>,
+}
+
+/// Request lifecycle transitions as server-sent `activity` events, starting
+/// with the requests already in flight. Bursts are coalesced the same way as
+/// for the desktop UI.
+async fn stream_activity(headers: HeaderMap, Query(q): Query<ActivityStreamQ>) -> Response {
+    if !admin_authorized(&headers, q.token.as_deref()) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Missing or invalid forward token"})),
+        )
+            .into_response();
+    }
+    let event = |activity: forward::activity::Activity| -> Result<Event, Infallible> {
+        Ok(Event::default()
+            .event("activity")
+            .data(serde_json::to_string(&activity).unwrap_or_default()))
+    };
+    let receiver = forward::activity::subscribe();
+    let backlog = forward::activity::in_flight();
+    let live = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        forward::activity::next_batch(&mut receiver)
+            .await
+            .map(|batch| (futures_util::stream::iter(batch), receiver))
+    })
+    .flatten();
+    let stream = futures_util::stream::iter(backlog).chain(live).map(event);
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
 async fn get_global_logs_count(Query(q): Query<GlobalLogsQuery>) -> Json<Value> {
     let query = logger::LogQuery {
         limit: None,
@@ -480,7 +1041,9 @@ pub fn app() -> Router {
     let cors = CorsLayer::permissive();
     Router::new()
         // Health check
+        .route("/", get(status_page::handler))
         .route("/health", get(health))
+        .route("/api/health/detail", get(health_detail))
         // ============================================
         // Unified API Endpoints (main entry points for editors)
         // ============================================
@@ -491,6 +1054,14 @@ pub fn app() -> Router {
         )
         // OpenAI Responses endpoint (auto-routes to OpenAI provider)
         .route("/v1/responses", post(forward::unified_responses))
+        // Anthropic Messages endpoint, for clients that only take a base URL
+        .route("/v1/messages", post(forward::anthropic_messages))
+        .route(
+            "/v1/messages/count_tokens",
+            post(forward::anthropic_count_tokens),
+        )
+        // Prompt token count, computed locally
+        .route("/v1/token-count", post(forward::tokens::handler))
         // Model listing (OpenAI-compatible)
         .route("/v1/models", get(forward::list_models))
         .route("/v1/models/:model_id", get(forward::get_model))
@@ -505,9 +1076,26 @@ pub fn app() -> Router {
         .route("/openai/v1/models", get(forward::list_models))
         // Anthropic-style
         .route("/anthropic/v1/messages", post(forward::anthropic_messages))
+        .route(
+            "/anthropic/v1/messages/count_tokens",
+            post(forward::anthropic_count_tokens),
+        )
+        // Gemini's OpenAI compatibility mode; static paths win over the wildcard
+        .route(
+            "/gemini/v1beta/openai/chat/completions",
+            post(forward::gemini_openai_chat),
+        )
+        .route("/gemini/v1beta/openai/models", get(forward::list_models))
+        .route(
+            "/gemini/v1beta/openai/models/:model_id",
+            get(forward::get_model),
+        )
         // Gemini-style (wildcard for all endpoints)
         .route("/gemini/v1beta/*endpoint", post(forward::gemini_generate))
         .route("/gemini/v1/*endpoint", post(forward::gemini_generate_v1))
+        .route_layer(axum::middleware::from_fn(forward::timing::layer))
+        .route_layer(axum::middleware::from_fn(forward::correlate))
+        .route_layer(axum::middleware::from_fn(relay_state::client_layer))
         // ============================================
         // Stats & Analytics API
         // ============================================
@@ -516,13 +1104,25 @@ pub fn app() -> Router {
         .route("/api/stats/channels", get(stats_channels))
         .route("/api/stats/models", get(stats_models))
         .route("/api/stats/logs", get(stats_logs))
+        .route("/api/usage/summary", get(usage_summary))
+        .route("/api/usage/export", get(usage_export))
+        .route("/api/budgets", get(budget_status))
+        .route("/api/response-cache", get(response_cache_stats))
+        .route("/api/queues", get(queue_stats))
+        .route("/api/mirror/report", get(mirror_report))
+        .route("/api/experiments/:id/report", get(experiment_report))
+        .route("/api/experiments/:id/end", post(end_experiment))
+        .route("/api/requests", get(list_captured_requests))
+        .route("/api/requests/:id", get(get_captured_request))
+        .route("/api/requests/:id/replay", post(replay_captured_request))
+        .route("/api/requests/:id/logs", get(request_logs))
         // ============================================
         // Projects API
         // ============================================
         .route("/api/projects", get(list_projects).post(create_project))
         .route(
             "/api/projects/:id",
-            put(update_project).delete(delete_project),
+            get(get_project).put(update_project).delete(delete_project),
         )
         .route("/api/projects/:id/open", post(open_project))
         .route("/api/projects/:id/detect-type", get(detect_project_type))
@@ -545,21 +1145,40 @@ pub fn app() -> Router {
         // Config API
         // ============================================
         .route("/api/config", get(get_config).put(put_config))
+        .route("/api/config/reload", post(reload_config))
+        .route("/api/config/validate", post(validate_config))
+        .route("/api/config/export", get(export_config))
+        .route("/api/config/import", post(import_config))
+        .route("/api/config/import/preview", post(preview_import))
         .route("/api/providers", get(list_providers))
         .route("/api/upstreams/:id/latency", get(upstream_latency))
+        .route("/api/upstreams/:id/ratelimits", get(upstream_ratelimits))
         .route("/api/latency/test", post(test_latency_urls))
         .route(
             "/api/forward/token",
             get(get_forward_token).post(refresh_forward_token),
         )
+        .route("/api/route/preview", post(preview_route))
+        .route(
+            "/api/transform/preview",
+            get(transform_examples).post(preview_transform),
+        )
+        .route(
+            "/api/prompt-redaction/preview",
+            post(preview_prompt_redaction),
+        )
         .route("/api/export/backup", get(export_backup))
         .route("/api/export/restore", post(restore_backup))
         .route("/api/data/clear", post(clear_all_data))
+        .route("/api/maintenance/run", post(run_maintenance))
+        .route("/api/prices/preview", get(preview_prices))
+        .route("/api/prices/refresh", post(refresh_prices))
         // ============================================
         // Auto Config & Backup API
         // ============================================
         .route("/api/auto-config/status", get(get_auto_config_status))
         .route("/api/auto-config/configure", post(configure_auto_config))
+        .route("/api/auto-config/snippet", get(get_editor_snippet))
         .route("/api/auto-config/backups/:tool", get(list_tool_backups))
         .route("/api/auto-config/backup", post(create_tool_backup))
         .route(
@@ -575,6 +1194,8 @@ pub fn app() -> Router {
         // ============================================
         .route("/api/logs", get(get_global_logs).delete(clear_global_logs))
         .route("/api/logs/count", get(get_global_logs_count))
+        .route("/api/logs/stream", get(stream_global_logs))
+        .route("/api/activity/stream", get(stream_activity))
         .route("/api/logs/:id", axum::routing::delete(delete_global_log))
         .route("/api/logs/delete", post(delete_global_logs_batch))
         // ============================================
@@ -582,19 +1203,775 @@ pub fn app() -> Router {
         // ============================================
         .route("/api/install-logs", get(get_install_logs))
         .route("/api/install-logs/:id", get(get_install_log))
+        // ============================================
+        // Server API
+        // ============================================
+        .route("/api/server/info", get(server_info))
+        .layer(axum::middleware::from_fn(connections::layer))
+        .layer(compression())
         .layer(cors)
+        .layer(axum::middleware::from_fn(access_log::layer))
 }
 
-pub async fn serve() {
-    db::init();
+/// Compresses JSON bodies for clients that accept gzip. Event streams are
+/// left alone: a buffering encoder holds events back, and some proxies stop
+/// delivering them incrementally.
+fn compression() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(
+        SizeAbove::new(1024)
+            .and(NotForContentType::const_new("text/event-stream"))
+            .and(JsonOnly),
+    )
+}
+
+#[derive(Clone, Copy)]
+struct JsonOnly;
+
+impl Predicate for JsonOnly {
+    fn should_compress<B>(&self, response: &axum::http::Response<B>) -> bool
+    where
+        B: axum::body::HttpBody,
+    {
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .is_some_and(|v| {
+                let v = v.trim();
+                v.eq_ignore_ascii_case("application/json") || v.ends_with("+json")
+            })
+    }
+}
+
+/// Wakes `serve` to rebuild the server, e.g. after a profile switch.
+static RESTART: Notify = Notify::const_new();
+/// Set by `ServerHandle::shutdown` so `serve` stops instead of rebuilding.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// Set by `stop`: `serve` keeps running but listens on nothing until
+/// `restart`.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// How long in-flight requests (and open streams) may run on after a
+/// restart or shutdown before their connections are dropped.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How long `restart_and_wait` waits for the server to come back.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+enum Status {
+    Starting,
+    Running(Bound),
+    Failed(BindError),
+    Stopped,
+}
+
+/// Where the running server accepts connections.
+#[derive(Debug, Clone)]
+struct Bound {
+    tcp: Option<SocketAddr>,
+    tls: bool,
+    unix: Option<PathBuf>,
+}
+
+impl Bound {
+    /// URL for clients on this machine, TCP preferred.
+    fn url(&self) -> Option<String> {
+        match (&self.tcp, &self.unix) {
+            (Some(addr), _) => Some(local_url(*addr, self.tls)),
+            (None, Some(path)) => Some(unix_url(path)),
+            (None, None) => None,
+        }
+    }
+}
+
+static STATUS: Lazy<watch::Sender<Status>> = Lazy::new(|| watch::channel(Status::Starting).0);
+
+/// Why the server could not listen on its configured address.
+#[derive(Debug, Clone, Serialize)]
+pub struct BindError {
+    /// The address that was tried, e.g. "127.0.0.1:8787" or a socket path
+    pub address: String,
+    /// "invalid_address", "address_in_use", "permission_denied",
+    /// "address_not_available", "tls" (certificate unusable), "timeout" or
+    /// "io"
+    pub kind: String,
+    /// TCP port that was tried, so the UI can offer another; none for a
+    /// unix socket
+    pub port: Option<u16>,
+    pub message: String,
+}
+
+impl std::fmt::Display for BindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cannot listen on {}: {}", self.address, self.message)
+    }
+}
+
+/// Server state as reported to the UI (`server-state` events).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum ServerState {
+    Starting,
+    Running {
+        /// TCP address actually bound
+        address: Option<String>,
+        url: Option<String>,
+    },
+    Stopped,
+    Error {
+        message: String,
+        error: BindError,
+    },
+}
+
+impl ServerState {
+    fn of(status: &Status) -> Self {
+        match status {
+            Status::Starting => ServerState::Starting,
+            Status::Running(bound) => ServerState::Running {
+                address: bound.tcp.map(|a| a.to_string()),
+                url: bound.url(),
+            },
+            Status::Stopped => ServerState::Stopped,
+            Status::Failed(e) => ServerState::Error {
+                message: e.to_string(),
+                error: e.clone(),
+            },
+        }
+    }
+}
+
+pub fn state() -> ServerState {
+    ServerState::of(&STATUS.borrow())
+}
+
+/// Call `f` with the current state and again after every change, until
+/// the process exits.
+pub async fn watch_state(mut f: impl FnMut(ServerState)) {
+    let mut status = STATUS.subscribe();
+    loop {
+        let state = ServerState::of(&status.borrow_and_update());
+        f(state);
+        if status.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Configured and actual listen address (`GET /api/server/info`).
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    pub host: String,
+    pub port: u16,
+    /// "starting", "running", "failed" or "stopped"
+    pub status: &'static str,
+    /// TCP address actually bound, which differs from `port` when it is 0
+    pub address: Option<String>,
+    /// Unix socket being served on
+    pub unix_socket: Option<String>,
+    /// Base URL for clients on this machine
+    pub url: Option<String>,
+    /// Whether other machines can connect
+    pub lan: bool,
+    /// Serving HTTPS
+    pub tls: bool,
+    /// SHA-256 fingerprint of the served certificate, for pinning
+    pub cert_fingerprint: Option<String>,
+    pub error: Option<BindError>,
+}
+
+/// Whether `server` listens on an address other machines can reach.
+pub fn lan_reachable(server: &config::ServerConfig) -> bool {
+    server.tcp
+        && server
+            .host
+            .trim()
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| !ip.is_loopback())
+}
+
+/// Current state of the HTTP server.
+pub fn info() -> ServerInfo {
+    let cfg = config::load().server;
+    let status = STATUS.borrow().clone();
+    let bound = match &status {
+        Status::Running(bound) => Some(bound.clone()),
+        _ => None,
+    };
+    let fingerprint = crate::tls::fingerprint().filter(|_| bound.as_ref().is_some_and(|b| b.tls));
+    ServerInfo {
+        lan: lan_reachable(&cfg),
+        status: match status {
+            Status::Starting => "starting",
+            Status::Running(_) => "running",
+            Status::Failed(_) => "failed",
+            Status::Stopped => "stopped",
+        },
+        address: bound.as_ref().and_then(|b| b.tcp).map(|a| a.to_string()),
+        unix_socket: bound
+            .as_ref()
+            .and_then(|b| b.unix.as_ref())
+            .map(|p| p.display().to_string()),
+        url: bound.as_ref().and_then(Bound::url),
+        tls: fingerprint.is_some(),
+        cert_fingerprint: fingerprint,
+        error: match status {
+            Status::Failed(e) => Some(e),
+            _ => None,
+        },
+        host: cfg.host,
+        port: cfg.port,
+    }
+}
+
+/// URL a client on this machine reaches `addr` with.
+fn local_url(addr: SocketAddr, tls: bool) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let scheme = if tls { "https" } else { "http" };
+    format!("{}://{}", scheme, SocketAddr::new(ip, addr.port()))
+}
+
+/// `http+unix://` URL of a socket, the path percent-encoded as the host.
+fn unix_url(path: &std::path::Path) -> String {
+    let encoded: String = path
+        .to_string_lossy()
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("http+unix://{}", encoded)
+}
+
+/// Base URL written into tool configurations: the bound address when the
+/// server is up, otherwise the configured one.
+pub fn base_url() -> String {
+    let cfg = config::load().server;
+    if let Some(url) = match &*STATUS.borrow() {
+        Status::Running(bound) => bound.url(),
+        _ => None,
+    } {
+        return url;
+    }
+    if let (false, Some(path)) = (cfg.tcp, unix_socket_path(&cfg)) {
+        return unix_url(&path);
+    }
+    let ip = cfg
+        .host
+        .trim()
+        .parse()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    local_url(SocketAddr::new(ip, cfg.port), cfg.tls.enabled)
+}
+
+/// The configured unix socket, relative paths taken from the data directory.
+fn unix_socket_path(cfg: &config::ServerConfig) -> Option<PathBuf> {
+    let path = cfg.unix_socket.trim();
+    if path.is_empty() {
+        return None;
+    }
+    Some(profile::base_dir().join(path))
+}
+
+/// The certificate to serve with, when TLS is enabled.
+fn tls_for(cfg: &config::ServerConfig) -> Result<Option<crate::tls::Tls>, BindError> {
+    if !cfg.tls.enabled {
+        crate::tls::clear();
+        return Ok(None);
+    }
+    crate::tls::Tls::from_config(&cfg.tls, &cfg.host)
+        .map(Some)
+        .map_err(|message| BindError {
+            address: format!("{}:{}", cfg.host.trim(), cfg.port),
+            kind: "tls".to_string(),
+            port: Some(cfg.port),
+            message,
+        })
+}
+
+async fn server_info() -> Json<ServerInfo> {
+    Json(info())
+}
+
+fn bind_error_kind(e: &std::io::Error) -> String {
+    match e.kind() {
+        std::io::ErrorKind::AddrInUse => "address_in_use",
+        std::io::ErrorKind::PermissionDenied => "permission_denied",
+        std::io::ErrorKind::AddrNotAvailable => "address_not_available",
+        _ => "io",
+    }
+    .to_string()
+}
+
+async fn bind(cfg: &config::ServerConfig) -> Result<tokio::net::TcpListener, BindError> {
+    let address = format!("{}:{}", cfg.host.trim(), cfg.port);
+    let ip: IpAddr = cfg.host.trim().parse().map_err(|_| BindError {
+        address: address.clone(),
+        kind: "invalid_address".to_string(),
+        port: Some(cfg.port),
+        message: format!("'{}' is not an IP address", cfg.host),
+    })?;
+    tokio::net::TcpListener::bind(SocketAddr::new(ip, cfg.port))
+        .await
+        .map_err(|e| BindError {
+            address,
+            kind: bind_error_kind(&e),
+            port: Some(cfg.port),
+            message: e.to_string(),
+        })
+}
+
+/// Listen on `path`, readable and writable by the current user only. A
+/// socket left behind by an unclean exit is replaced; one that still
+/// answers belongs to another instance and is an error.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener, BindError> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let error = |kind: &str, message: String| BindError {
+        address: path.display().to_string(),
+        kind: kind.to_string(),
+        port: None,
+        message,
+    };
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(error(
+                    "address_in_use",
+                    "another server is listening on this socket".to_string(),
+                ));
+            }
+            std::fs::remove_file(path).map_err(|e| error(&bind_error_kind(&e), e.to_string()))?;
+        }
+        Ok(_) => {
+            return Err(error(
+                "address_in_use",
+                "the path exists and is not a socket".to_string(),
+            ))
+        }
+        Err(_) => {}
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).ok();
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| error(&bind_error_kind(&e), e.to_string()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| error("permission_denied", e.to_string()))?;
+    Ok(listener)
+}
+
+/// What `serve` listens on in one round.
+struct Listeners {
+    tcp: Option<(tokio::net::TcpListener, Option<crate::tls::Tls>)>,
+    #[cfg(unix)]
+    unix: Option<(tokio::net::UnixListener, PathBuf)>,
+}
+
+impl Listeners {
+    async fn open(cfg: &config::ServerConfig) -> Result<Self, BindError> {
+        let tcp = if cfg.tcp {
+            let listener = bind(cfg).await?;
+            Some((listener, tls_for(cfg)?))
+        } else {
+            crate::tls::clear();
+            None
+        };
+        let unix_path = unix_socket_path(cfg);
+        if tcp.is_none() && unix_path.is_none() {
+            return Err(BindError {
+                address: String::new(),
+                kind: "invalid_address".to_string(),
+                port: None,
+                message: "TCP is disabled and no unix socket is configured".to_string(),
+            });
+        }
+        #[cfg(unix)]
+        let unix = match unix_path {
+            Some(path) => Some((bind_unix(&path)?, path)),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if let Some(path) = unix_path {
+            return Err(BindError {
+                address: path.display().to_string(),
+                kind: "invalid_address".to_string(),
+                port: None,
+                message: "unix sockets are not supported on this platform".to_string(),
+            });
+        }
+        Ok(Self {
+            tcp,
+            #[cfg(unix)]
+            unix,
+        })
+    }
+
+    fn bound(&self) -> Bound {
+        Bound {
+            tcp: self.tcp.as_ref().map(|(listener, _)| {
+                listener
+                    .local_addr()
+                    .unwrap_or_else(|_| SocketAddr::from(([127, 0, 0, 1], 0)))
+            }),
+            tls: self.tcp.as_ref().is_some_and(|(_, tls)| tls.is_some()),
+            #[cfg(unix)]
+            unix: self.unix.as_ref().map(|(_, path)| path.clone()),
+            #[cfg(not(unix))]
+            unix: None,
+        }
+    }
+
+    /// Serve on every listener until a restart or shutdown is requested.
+    async fn run(self) {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let tcp = async {
+            if let Some((listener, tls)) = self.tcp {
+                run(listener, tls, stop_rx.clone()).await;
+            }
+        };
+        #[cfg(unix)]
+        let unix = async {
+            if let Some((listener, path)) = self.unix {
+                run_unix(listener, stop_rx.clone()).await;
+                std::fs::remove_file(path).ok();
+            }
+        };
+        #[cfg(not(unix))]
+        let unix = async {};
+        let both = async { tokio::join!(tcp, unix) };
+        tokio::pin!(both);
+        tokio::select! {
+            _ = &mut both => {}
+            _ = RESTART.notified() => {
+                let _ = stop_tx.send(true);
+                both.await;
+            }
+        }
+    }
+}
+
+/// Completes once `stop` is set (or its sender is gone).
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+/// Serve on `listener` until `stop` is set, then give open connections
+/// `SHUTDOWN_GRACE` to finish.
+async fn run(
+    listener: tokio::net::TcpListener,
+    tls: Option<crate::tls::Tls>,
+    stop: watch::Receiver<bool>,
+) {
+    if let Some(tls) = tls {
+        return run_tls(listener, tls, stop).await;
+    }
+    let (signalled_tx, signalled_rx) = tokio::sync::oneshot::channel::<()>();
+    let app = app().into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        stopped(stop).await;
+        let _ = signalled_tx.send(());
+    });
+    let deadline = async move {
+        if signalled_rx.await.is_ok() {
+            tokio::time::sleep(SHUTDOWN_GRACE).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                logger::error("server", &format!("Server error: {}", e));
+            }
+        }
+        _ = deadline => warn_still_open(),
+    }
+}
+
+fn warn_still_open() {
+    logger::warn(
+        "server",
+        &format!(
+            "Connections still open after {}s, closing them",
+            SHUTDOWN_GRACE.as_secs()
+        ),
+    );
+}
+
+/// Serve one accepted connection with hyper, tracked for graceful shutdown.
+/// `peer` is made available to handlers as `ConnectInfo`, as `axum::serve`
+/// does for plain HTTP.
+async fn serve_connection<I>(
+    io: I,
+    peer: Option<SocketAddr>,
+    app: Router,
+    builder: Builder<TokioExecutor>,
+    watcher: Watcher,
+) where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let app = match peer {
+        Some(peer) => app.layer(axum::Extension(axum::extract::ConnectInfo(peer))),
+        None => app,
+    };
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(app))
+        .into_owned();
+    let _ = watcher.watch(conn).await;
+}
+
+/// Wait up to `SHUTDOWN_GRACE` for the connections of `graceful` to finish.
+async fn drain(graceful: GracefulShutdown) {
+    if tokio::time::timeout(SHUTDOWN_GRACE, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn_still_open();
+    }
+}
+
+/// `run` for HTTPS: accepts connections itself and hands each, after the
+/// handshake, to hyper.
+async fn run_tls(
+    listener: tokio::net::TcpListener,
+    tls: crate::tls::Tls,
+    stop: watch::Receiver<bool>,
+) {
+    let app = app();
+    let builder = Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let signal = stopped(stop);
+    tokio::pin!(signal);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    logger::warn("server", &format!("Accept failed: {}", e));
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+        let acceptor = tls.acceptor();
+        let connection = (app.clone(), builder.clone(), graceful.watcher());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    logger::debug(
+                        "server",
+                        &format!("TLS handshake with {} failed: {}", peer, e),
+                    );
+                    return;
+                }
+            };
+            let (app, builder, watcher) = connection;
+            serve_connection(stream, Some(peer), app, builder, watcher).await;
+        });
+    }
+    drop(listener);
+    drain(graceful).await;
+}
+
+/// `run` for the unix socket.
+#[cfg(unix)]
+async fn run_unix(listener: tokio::net::UnixListener, stop: watch::Receiver<bool>) {
     let app = app();
-    let addr: SocketAddr = "127.0.0.1:8787".parse().unwrap();
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let builder = Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let signal = stopped(stop);
+    tokio::pin!(signal);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    logger::warn("server", &format!("Accept failed: {}", e));
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+        tokio::spawn(serve_connection(
+            stream,
+            None,
+            app.clone(),
+            builder.clone(),
+            graceful.watcher(),
+        ));
+    }
+    drop(listener);
+    drain(graceful).await;
+}
+
+pub async fn serve() {
+    status_page::mark_started();
+    config::watch();
+    maintenance::spawn();
+    price_sync::spawn();
+    network::spawn();
+    forward::dns::spawn();
+    webhooks::spawn();
+    telemetry::spawn();
+    serve_rounds().await;
+}
+
+/// Listen and serve, listening again after every restart and staying down
+/// while stopped, until shut down.
+async fn serve_rounds() {
+    loop {
+        if PAUSED.load(Ordering::SeqCst) {
+            STATUS.send_replace(Status::Stopped);
+            RESTART.notified().await;
+            if STOPPING.load(Ordering::SeqCst) {
+                break;
+            }
+            continue;
+        }
+        db::init();
+        STATUS.send_replace(Status::Starting);
+        let cfg = config::load().server;
+        match Listeners::open(&cfg).await {
+            Ok(listeners) => {
+                let bound = listeners.bound();
+                let addresses: Vec<String> = bound
+                    .tcp
+                    .map(|addr| local_url(addr, bound.tls))
+                    .into_iter()
+                    .chain(bound.unix.as_deref().map(unix_url))
+                    .collect();
+                logger::info(
+                    "server",
+                    &format!(
+                        "Serving profile '{}' on {}",
+                        profile::active(),
+                        addresses.join(" and ")
+                    ),
+                );
+                let _advertisement = bound
+                    .tcp
+                    .and_then(|addr| discovery::advertise(addr, bound.tls));
+                STATUS.send_replace(Status::Running(bound));
+                config::set_listening(Some(&cfg));
+                listeners.run().await;
+                config::set_listening(None);
+            }
+            Err(e) => {
+                logger::error("server", &e.to_string());
+                STATUS.send_replace(Status::Failed(e));
+                // Stay down until the address is changed and a restart asked for
+                RESTART.notified().await;
+            }
+        }
+        if STOPPING.load(Ordering::SeqCst) {
+            STATUS.send_replace(Status::Stopped);
+            break;
+        }
+    }
+}
+
+/// Stop accepting connections, let in-flight requests finish, then serve
+/// again with freshly loaded state. Also starts a stopped server.
+pub fn restart() {
+    PAUSED.store(false, Ordering::SeqCst);
+    RESTART.notify_one();
+}
+
+/// Stop listening, giving in-flight requests `SHUTDOWN_GRACE`, until the
+/// next `restart`. Resolves once the listeners are closed.
+pub async fn stop_and_wait() -> Result<(), String> {
+    let mut status = STATUS.subscribe();
+    if PAUSED.load(Ordering::SeqCst) && matches!(*status.borrow_and_update(), Status::Stopped) {
+        return Ok(());
+    }
+    PAUSED.store(true, Ordering::SeqCst);
+    RESTART.notify_one();
+    let stopped = async {
+        status
+            .wait_for(|s| matches!(s, Status::Stopped))
+            .await
+            .map(|_| ())
+    };
+    match tokio::time::timeout(RESTART_TIMEOUT + SHUTDOWN_GRACE, stopped).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => Err("the server task has exited".to_string()),
+        Err(_) => Err(format!(
+            "server did not stop within {}s",
+            (RESTART_TIMEOUT + SHUTDOWN_GRACE).as_secs()
+        )),
+    }
+}
+
+/// Restart and wait until the server listens again (or fails to).
+pub async fn restart_and_wait() -> Result<ServerInfo, BindError> {
+    let mut status = STATUS.subscribe();
+    status.borrow_and_update();
+    restart();
+    let outcome = tokio::time::timeout(RESTART_TIMEOUT, async {
+        loop {
+            if status.changed().await.is_err() {
+                return None;
+            }
+            match &*status.borrow_and_update() {
+                Status::Running(_) => return Some(Ok(())),
+                Status::Failed(e) => return Some(Err(e.clone())),
+                Status::Starting | Status::Stopped => {}
+            }
+        }
+    })
+    .await;
+    match outcome {
+        Ok(Some(Ok(()))) => Ok(info()),
+        Ok(Some(Err(e))) => Err(e),
+        Ok(None) | Err(_) => {
+            let cfg = config::load().server;
+            Err(BindError {
+                address: format!("{}:{}", cfg.host, cfg.port),
+                kind: "timeout".to_string(),
+                port: Some(cfg.port),
+                message: format!(
+                    "server did not come back within {}s",
+                    RESTART_TIMEOUT.as_secs()
+                ),
+            })
+        }
+    }
 }
 
-pub fn spawn() {
-    tauri::async_runtime::spawn(async move { serve().await });
+/// The running server task.
+pub struct ServerHandle {
+    task: tauri::async_runtime::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Stop serving: no new connections are accepted and in-flight requests
+    /// get `SHUTDOWN_GRACE` to finish.
+    pub async fn shutdown(self) {
+        STOPPING.store(true, Ordering::SeqCst);
+        RESTART.notify_one();
+        let _ = self.task.await;
+    }
+}
+
+pub fn spawn() -> ServerHandle {
+    ServerHandle {
+        task: tauri::async_runtime::spawn(async move { serve().await }),
+    }
+}
+
+/// Flush queued database writes before the process exits.
+pub fn shutdown() {
+    db::flush_writes();
 }
 
 #[cfg(test)]
@@ -610,6 +1987,271 @@ mod tests {
         let r = reqwest::get(format!("{}/health", url)).await.unwrap();
         let s = r.json::<serde_json::Value>().await.unwrap();
         assert_eq!(s["status"], "ok");
+        assert!(s["profile"].is_string());
+
+        let r = reqwest::Client::new()
+            .get(format!("{}/health", url))
+            .header("x-request-id", "client-req-1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(r.headers()[forward::REQUEST_ID_HEADER], "client-req-1");
+
+        let r = reqwest::get(format!("{}/api/server/info", url))
+            .await
+            .unwrap();
+        let s = r.json::<serde_json::Value>().await.unwrap();
+        assert!(s["host"].is_string());
+
+        let mut detail = reqwest::Client::new().get(format!("{}/api/health/detail", url));
+        if let Some(token) = config::load().forward_token.filter(|t| !t.is_empty()) {
+            detail = detail.bearer_auth(token);
+        }
+        let s = detail.send().await.unwrap().json::<Value>().await.unwrap();
+        assert!(s["db"]["status"].is_string());
+        assert!(s["streams"]["active"].is_number());
+
+        // Gemini's OpenAI compatibility mode lists models the OpenAI way
+        let mut models = reqwest::Client::new().get(format!("{}/gemini/v1beta/openai/models", url));
+        if let Some(token) = config::load().forward_token.filter(|t| !t.is_empty()) {
+            models = models.bearer_auth(token);
+        }
+        let s = models.send().await.unwrap().json::<Value>().await.unwrap();
+        assert_eq!(s["object"], "list");
         drop(h);
     }
+
+    #[tokio::test]
+    async fn test_bind_errors_are_structured() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let err = bind(&config::ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind, "address_in_use");
+        assert_eq!(err.address, format!("127.0.0.1:{}", port));
+        assert_eq!(err.port, Some(port));
+
+        // What a `server-state` event carries for it
+        let state = serde_json::to_value(ServerState::of(&Status::Failed(err))).unwrap();
+        assert_eq!(state["state"], "error");
+        assert_eq!(state["error"]["port"], port);
+
+        let err = bind(&config::ServerConfig {
+            host: "localhost:80".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind, "invalid_address");
+
+        // Port 0 binds to whatever is free
+        let listener = bind(&config::ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn test_local_url() {
+        assert_eq!(
+            local_url("0.0.0.0:8787".parse().unwrap(), false),
+            "http://127.0.0.1:8787"
+        );
+        assert_eq!(
+            local_url("192.168.1.5:9000".parse().unwrap(), true),
+            "https://192.168.1.5:9000"
+        );
+        assert_eq!(
+            local_url("[::]:8787".parse().unwrap(), false),
+            "http://[::1]:8787"
+        );
+        assert_eq!(
+            unix_url(std::path::Path::new("/run/ccr/ccr.sock")),
+            "http+unix://%2Frun%2Fccr%2Fccr.sock"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = std::env::temp_dir().join(format!("ccr-uds-{}", std::process::id()));
+        let path = dir.join("ccr.sock");
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let server = tokio::spawn(run_unix(listener, stop_rx));
+        // A live socket is not taken over
+        assert_eq!(bind_unix(&path).unwrap_err().kind, "address_in_use");
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        stop_tx.send(true).unwrap();
+        server.await.unwrap();
+        // A stale socket from an unclean exit is replaced
+        drop(bind_unix(&path).unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_unified_routes_reach_their_handlers() {
+        profile::use_temp_dir_for_tests("routes");
+        let _settings = config::install_for_tests(config::Settings {
+            forward_token: Some("relay-routes-test-token".to_string()),
+            ..Default::default()
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let h = tokio::spawn(async move { axum::serve(listener, app()).await.unwrap() });
+        let client = reqwest::Client::new();
+        let send = |request: reqwest::RequestBuilder| {
+            let request = request.bearer_auth("relay-routes-test-token");
+            async move {
+                let r = request.send().await.unwrap();
+                (r.status(), r.json::<Value>().await.unwrap())
+            }
+        };
+        let unknown = json!({
+            "model": "relay-route-test-no-such-model",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "hi"}]
+        });
+
+        // Answered by the Anthropic messages handler, in its error format
+        let (status, body) = send(client.post(format!("{}/v1/messages", url)).json(&unknown)).await;
+        assert!(status.is_client_error(), "{}", status);
+        assert_eq!(body["type"], "error", "{}", body);
+        assert!(body["error"]["type"].is_string(), "{}", body);
+
+        // The OpenAI chat handler, in OpenAI's
+        let (status, body) = send(
+            client
+                .post(format!("{}/v1/chat/completions", url))
+                .json(&unknown),
+        )
+        .await;
+        assert!(status.is_client_error(), "{}", status);
+        assert!(body.get("type").is_none(), "{}", body);
+        assert!(body["error"]["message"].is_string(), "{}", body);
+
+        // The model list
+        let (status, body) = send(client.get(format!("{}/v1/models", url))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["object"], "list", "{}", body);
+        drop(h);
+    }
+
+    #[tokio::test]
+    async fn test_event_streams_are_not_compressed() {
+        let big = "x".repeat(4096);
+        let json_body = big.clone();
+        let a = Router::new()
+            .route(
+                "/json",
+                get(move || async move { Json(json!({ "text": json_body })) }),
+            )
+            .route(
+                "/sse",
+                get(move || async move {
+                    let events = futures_util::stream::iter(vec![Ok::<_, Infallible>(
+                        Event::default().data(big),
+                    )]);
+                    Sse::new(events)
+                }),
+            )
+            .layer(compression());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let h = tokio::spawn(async move { axum::serve(listener, a).await.unwrap() });
+        let client = reqwest::Client::new();
+        let get = |path: &str| {
+            client
+                .get(format!("{}{}", url, path))
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .send()
+        };
+
+        let r = get("/json").await.unwrap();
+        assert_eq!(r.headers()[header::CONTENT_ENCODING], "gzip");
+        let r = get("/sse").await.unwrap();
+        assert!(r.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(r.text().await.unwrap().contains("xxxx"));
+        drop(h);
+    }
+
+    async fn answers_health(address: &str) -> bool {
+        reqwest::get(format!("http://{}/health", address))
+            .await
+            .is_ok_and(|r| r.status().is_success())
+    }
+
+    fn on_port(port: u16) -> config::Settings {
+        config::Settings {
+            server: config::ServerConfig {
+                port,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_releases_the_port_and_restart_binds_it_again() {
+        let settings = config::install_for_tests(on_port(0));
+        profile::use_temp_dir_for_tests("lifecycle");
+        let mut status = STATUS.subscribe();
+        let task = tokio::spawn(serve_rounds());
+        let running = status.wait_for(|s| matches!(s, Status::Running(_)));
+        tokio::time::timeout(RESTART_TIMEOUT, running)
+            .await
+            .unwrap()
+            .unwrap();
+        let address = info().address.unwrap();
+        assert!(answers_health(&address).await);
+
+        stop_and_wait().await.unwrap();
+        assert_eq!(info().status, "stopped");
+        assert!(!answers_health(&address).await);
+        // Nothing holds the port any more
+        drop(tokio::net::TcpListener::bind(&address).await.unwrap());
+
+        // Listen on that very port again
+        let port = address.rsplit(':').next().unwrap().parse().unwrap();
+        drop(settings);
+        let _settings = config::install_for_tests(on_port(port));
+        let restarted = restart_and_wait().await.unwrap();
+        assert_eq!(restarted.status, "running");
+        assert_eq!(restarted.address.as_deref(), Some(address.as_str()));
+        assert!(answers_health(&address).await);
+
+        STOPPING.store(true, Ordering::SeqCst);
+        RESTART.notify_one();
+        tokio::time::timeout(RESTART_TIMEOUT + SHUTDOWN_GRACE, task)
+            .await
+            .unwrap()
+            .unwrap();
+        STOPPING.store(false, Ordering::SeqCst);
+        assert!(!answers_health(&address).await);
+    }
 }
@@ -0,0 +1,163 @@
+//! Status page served at `/`, for people checking in a browser that the
+//! relay is up. It shows nothing secret: version, uptime and the API styles
+//! of the configured upstreams. `accept: application/json` gets the same as
+//! JSON.
+
+use std::time::{Duration, Instant};
+
+use axum::http::{header, HeaderMap};
+use axum::response::{Html, IntoResponse, Json, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config;
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Endpoints linked from the page.
+const LINKS: [(&str, &str); 4] = [
+    ("/health", "Health"),
+    ("/api/health/detail", "Detailed health"),
+    ("/v1/models", "Models"),
+    ("/api/providers", "Supported API styles"),
+];
+
+/// Start the uptime clock.
+pub fn mark_started() {
+    Lazy::force(&STARTED);
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    name: &'static str,
+    version: &'static str,
+    uptime_secs: u64,
+    provider_styles: Vec<String>,
+    links: Vec<&'static str>,
+}
+
+fn status(cfg: &config::Settings) -> Status {
+    let mut styles: Vec<String> = cfg
+        .upstreams
+        .iter()
+        .filter_map(|up| up.api_style.as_deref())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    styles.sort();
+    styles.dedup();
+    Status {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: STARTED.elapsed().as_secs(),
+        provider_styles: styles,
+        links: LINKS.iter().map(|(path, _)| *path).collect(),
+    }
+}
+
+pub async fn handler(headers: HeaderMap) -> Response {
+    let status = status(&config::current());
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        Json(status).into_response()
+    } else {
+        Html(render(&status)).into_response()
+    }
+}
+
+fn render(status: &Status) -> String {
+    let styles = if status.provider_styles.is_empty() {
+        "none configured".to_string()
+    } else {
+        escape(&status.provider_styles.join(", "))
+    };
+    let links: String = LINKS
+        .iter()
+        .map(|(path, label)| {
+            format!(
+                "<li><a href=\"{0}\">{1}</a> <code>{0}</code></li>",
+                path, label
+            )
+        })
+        .collect();
+    format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>AI relay</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 3rem auto; max-width: 36rem; color: #222; }}
+h1 {{ font-size: 1.4rem; }}
+dt {{ font-weight: 600; }}
+dd {{ margin: 0 0 .75rem; }}
+</style>
+</head>
+<body>
+<h1>AI relay is running</h1>
+<dl>
+<dt>Version</dt><dd>{version}</dd>
+<dt>Uptime</dt><dd>{uptime}</dd>
+<dt>Provider styles</dt><dd>{styles}</dd>
+</dl>
+<ul>{links}</ul>
+</body>
+</html>
+"#,
+        version = escape(status.version),
+        uptime = uptime(Duration::from_secs(status.uptime_secs)),
+        styles = styles,
+        links = links,
+    )
+}
+
+/// "3d 4h 5m", "4h 5m" or "5m 6s".
+fn uptime(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    let (days, hours, minutes) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, secs % 60)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_page() {
+        let mut cfg = config::Settings::default();
+        for style in ["openai", "<b>", "openai"] {
+            cfg.upstreams.push(config::Upstream {
+                api_style: Some(style.to_string()),
+                api_key: Some("sk-secret-key".to_string()),
+                ..Default::default()
+            });
+        }
+        let status = status(&cfg);
+        assert_eq!(status.provider_styles, vec!["<b>", "openai"]);
+
+        let html = render(&status);
+        assert!(html.contains("&lt;b&gt;, openai"));
+        assert!(html.contains("href=\"/health\""));
+        assert!(!html.contains("sk-secret-key"));
+
+        assert_eq!(uptime(Duration::from_secs(65)), "1m 5s");
+        assert_eq!(uptime(Duration::from_secs(90_061)), "1d 1h 1m");
+    }
+}
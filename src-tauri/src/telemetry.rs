@@ -0,0 +1,485 @@
+//! Optional OpenTelemetry trace export.
+//!
+//! With `telemetry.enabled`, every forwarded request produces a trace: a
+//! server span for the request that ends with the last byte of the response,
+//! and children for plan building, each upstream attempt and the lifetime of
+//! a streamed body. Finished spans go through a bounded queue to a background
+//! task that posts them in batches to `telemetry.endpoint` as OTLP/HTTP JSON,
+//! so the request path never waits on the collector. An incoming W3C
+//! `traceparent` header makes the request span part of the caller's trace.
+//!
+//! When export is disabled, [`Span::start`] costs one atomic load and every
+//! call on the returned span is a no-op.
+//!
+//! The span being recorded is tracked in a task-local (see [`Span::scope`]);
+//! log entries written inside it carry its `trace_id`.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{config, forward, logger};
+
+const QUEUE_CAPACITY: usize = 4096;
+/// Spans are posted once this many are queued...
+const BATCH_MAX: usize = 512;
+/// ...or this long after the first one arrived.
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+const SCOPE_NAME: &str = "ccr-relay";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: OnceLock<mpsc::Sender<FinishedSpan>> = OnceLock::new();
+
+tokio::task_local! {
+    static CURRENT: Arc<SpanData>;
+}
+
+/// OTLP span kinds used by the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<&str> for AttrValue {
+    fn from(v: &str) -> Self {
+        AttrValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(v: String) -> Self {
+        AttrValue::Str(v)
+    }
+}
+
+impl From<i64> for AttrValue {
+    fn from(v: i64) -> Self {
+        AttrValue::Int(v)
+    }
+}
+
+impl From<usize> for AttrValue {
+    fn from(v: usize) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+impl From<u16> for AttrValue {
+    fn from(v: u16) -> Self {
+        AttrValue::Int(v as i64)
+    }
+}
+
+impl From<f64> for AttrValue {
+    fn from(v: f64) -> Self {
+        AttrValue::Float(v)
+    }
+}
+
+impl From<bool> for AttrValue {
+    fn from(v: bool) -> Self {
+        AttrValue::Bool(v)
+    }
+}
+
+#[derive(Default)]
+struct SpanState {
+    attributes: Vec<(&'static str, AttrValue)>,
+    error: Option<String>,
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start_ns: u64,
+    state: Mutex<SpanState>,
+}
+
+impl SpanData {
+    fn set(&self, key: &'static str, value: AttrValue) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.attributes.iter_mut().find(|(k, _)| *k == key) {
+            Some(slot) => slot.1 = value,
+            None => state.attributes.push((key, value)),
+        }
+    }
+}
+
+struct FinishedSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start_ns: u64,
+    end_ns: u64,
+    attributes: Vec<(&'static str, AttrValue)>,
+    error: Option<String>,
+}
+
+/// A span being recorded. It ends, and is queued for export, when dropped.
+pub struct Span(Option<Arc<SpanData>>);
+
+impl Span {
+    /// A span that records nothing.
+    pub fn none() -> Self {
+        Span(None)
+    }
+
+    /// Start a span under the current one, or a new trace outside any span.
+    pub fn start(name: &'static str, kind: SpanKind) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Span(None);
+        }
+        let parent = CURRENT.try_with(|p| (p.trace_id, p.span_id)).ok();
+        new_span(name, kind, parent)
+    }
+
+    /// Start the server span of an incoming request, continuing the
+    /// caller's trace when it sent a valid `traceparent`.
+    pub fn request(name: &'static str, traceparent: Option<&str>) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Span(None);
+        }
+        new_span(
+            name,
+            SpanKind::Server,
+            traceparent.and_then(parse_traceparent),
+        )
+    }
+
+    pub fn set(&self, key: &'static str, value: impl Into<AttrValue>) {
+        if let Some(data) = &self.0 {
+            data.set(key, value.into());
+        }
+    }
+
+    /// Mark the span as failed.
+    pub fn fail(&self, message: impl Into<String>) {
+        if let Some(data) = &self.0 {
+            data.state.lock().unwrap_or_else(|e| e.into_inner()).error = Some(message.into());
+        }
+    }
+
+    /// Run `fut` with this span as the current one.
+    pub async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        match &self.0 {
+            Some(data) => CURRENT.scope(Arc::clone(data), fut).await,
+            None => fut.await,
+        }
+    }
+
+    /// Synchronous counterpart of [`Span::scope`], for work done while a
+    /// response body is polled.
+    pub fn sync_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.0 {
+            Some(data) => CURRENT.sync_scope(Arc::clone(data), f),
+            None => f(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(data) = self.0.take() else {
+            return;
+        };
+        let Some(queue) = QUEUE.get() else {
+            return;
+        };
+        let state = std::mem::take(&mut *data.state.lock().unwrap_or_else(|e| e.into_inner()));
+        // A full queue means the collector is not keeping up; drop the span
+        let _ = queue.try_send(FinishedSpan {
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_id: data.parent_id,
+            name: data.name,
+            kind: data.kind,
+            start_ns: data.start_ns,
+            end_ns: now_ns(),
+            attributes: state.attributes,
+            error: state.error,
+        });
+    }
+}
+
+fn new_span(name: &'static str, kind: SpanKind, parent: Option<([u8; 16], [u8; 8])>) -> Span {
+    let mut rng = rand::rngs::OsRng;
+    let trace_id = parent.map(|(t, _)| t).unwrap_or_else(|| {
+        let mut id = [0u8; 16];
+        rng.fill_bytes(&mut id);
+        id
+    });
+    let mut span_id = [0u8; 8];
+    rng.fill_bytes(&mut span_id);
+    Span(Some(Arc::new(SpanData {
+        trace_id,
+        span_id,
+        parent_id: parent.map(|(_, s)| s),
+        name,
+        kind,
+        start_ns: now_ns(),
+        state: Mutex::new(SpanState::default()),
+    })))
+}
+
+/// Set an attribute on the current span, if any.
+pub fn annotate(key: &'static str, value: impl Into<AttrValue>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let value = value.into();
+    let _ = CURRENT.try_with(|data| data.set(key, value));
+}
+
+/// Trace id of the current span, for log entries.
+pub fn current_trace_id() -> Option<String> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    CURRENT.try_with(|data| hex(&data.trace_id)).ok()
+}
+
+/// Apply the `telemetry` settings.
+pub fn configure(cfg: &config::TelemetryConfig) {
+    ENABLED.store(
+        cfg.enabled && !cfg.endpoint.trim().is_empty(),
+        Ordering::Relaxed,
+    );
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Trace and parent span id from `00-<trace-id>-<span-id>-<flags>`.
+fn parse_traceparent(header: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = header.trim().split('-');
+    let (version, trace, span) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next()?;
+    if version == "ff" {
+        return None;
+    }
+    let trace_id = unhex::<16>(trace).filter(|t| t.iter().any(|b| *b != 0))?;
+    let span_id = unhex::<8>(span).filter(|s| s.iter().any(|b| *b != 0))?;
+    Some((trace_id, span_id))
+}
+
+fn encode_value(value: &AttrValue) -> Value {
+    match value {
+        AttrValue::Str(s) => json!({ "stringValue": s }),
+        // OTLP/JSON carries 64-bit integers as strings
+        AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttrValue::Float(f) => json!({ "doubleValue": f }),
+        AttrValue::Bool(b) => json!({ "boolValue": b }),
+    }
+}
+
+fn encode_span(span: &FinishedSpan) -> Value {
+    let mut out = json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "name": span.name,
+        "kind": span.kind as i32,
+        "startTimeUnixNano": span.start_ns.to_string(),
+        "endTimeUnixNano": span.end_ns.to_string(),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(k, v)| json!({ "key": k, "value": encode_value(v) }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(parent) = &span.parent_id {
+        out["parentSpanId"] = json!(hex(parent));
+    }
+    if let Some(error) = &span.error {
+        out["status"] = json!({ "code": 2, "message": error });
+    }
+    out
+}
+
+/// An OTLP `ExportTraceServiceRequest` body.
+fn encode(spans: &[FinishedSpan], service_name: &str) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": SCOPE_NAME },
+                "spans": spans.iter().map(encode_span).collect::<Vec<_>>(),
+            }]
+        }]
+    })
+}
+
+async fn export(
+    client: &forward::client::HttpClient,
+    spans: &[FinishedSpan],
+) -> Result<(), String> {
+    let cfg = config::load().telemetry;
+    let mut req = client
+        .post(cfg.endpoint.trim())
+        .json(&encode(spans, &cfg.service_name));
+    for (name, value) in &cfg.headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    let resp = req.send().await.map_err(|e| e.to_string())?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("HTTP {}", resp.status().as_u16()))
+    }
+}
+
+/// Start the export task on the current tokio runtime.
+pub fn spawn() {
+    let (tx, mut rx) = mpsc::channel::<FinishedSpan>(QUEUE_CAPACITY);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        let client = match forward::client::create_client(10) {
+            Ok(client) => client,
+            Err(e) => {
+                logger::error("telemetry", &format!("Cannot create HTTP client: {}", e));
+                return;
+            }
+        };
+        let mut batch = Vec::new();
+        while let Some(span) = rx.recv().await {
+            batch.push(span);
+            let deadline = tokio::time::sleep(BATCH_INTERVAL);
+            tokio::pin!(deadline);
+            while batch.len() < BATCH_MAX {
+                tokio::select! {
+                    span = rx.recv() => match span {
+                        Some(span) => batch.push(span),
+                        None => break,
+                    },
+                    _ = &mut deadline => break,
+                }
+            }
+            // New spans are dropped while this waits and the queue is full
+            crate::network::wait_online_for(config::load().telemetry.endpoint.trim()).await;
+            if let Err(e) = export(&client, &batch).await {
+                logger::warn(
+                    "telemetry",
+                    &format!("Dropping {} spans, export failed: {}", batch.len(), e),
+                );
+            }
+            batch.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let (trace, span) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(hex(&trace), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&span), "00f067aa0ba902b7");
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(parse_traceparent("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("garbage").is_none());
+    }
+
+    #[test]
+    fn test_child_spans_and_encoding() {
+        let parent = new_span("relay.request", SpanKind::Server, None);
+        let parent_data = parent.0.clone().unwrap();
+        let child = parent.sync_scope(|| {
+            let parent = CURRENT.try_with(|p| (p.trace_id, p.span_id)).ok();
+            new_span("relay.upstream_attempt", SpanKind::Client, parent)
+        });
+        child.set("relay.upstream", "openai");
+        child.set("relay.attempt", 0i64);
+        child.set("relay.attempt", 1i64);
+        child.set("relay.streaming", false);
+        child.fail("502 Bad Gateway");
+
+        let data = child.0.clone().unwrap();
+        assert_eq!(data.trace_id, parent_data.trace_id);
+        assert_eq!(data.parent_id, Some(parent_data.span_id));
+
+        let state = std::mem::take(&mut *data.state.lock().unwrap());
+        let span = FinishedSpan {
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_id: data.parent_id,
+            name: data.name,
+            kind: data.kind,
+            start_ns: 1,
+            end_ns: 2,
+            attributes: state.attributes,
+            error: state.error,
+        };
+        let body = encode(std::slice::from_ref(&span), "ccr-test");
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "ccr-test"
+        );
+        let out = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(out["kind"], 3);
+        assert_eq!(out["parentSpanId"], hex(&parent_data.span_id));
+        assert_eq!(out["status"]["code"], 2);
+        assert_eq!(out["endTimeUnixNano"], "2");
+        let attrs = out["attributes"].as_array().unwrap();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs[1]["value"]["intValue"], "1");
+    }
+
+    #[test]
+    fn test_disabled_spans_are_inert() {
+        let span = Span::none();
+        span.set("relay.model", "gpt-4o");
+        assert!(span.0.is_none());
+        assert_eq!(span.sync_scope(current_trace_id), None);
+    }
+}
@@ -0,0 +1,410 @@
+//! HTTPS for the relay server.
+//!
+//! With `server.tls.enabled` the server terminates TLS itself (rustls with
+//! the ring provider). The certificate comes from `cert_path` / `key_path`
+//! PEM files, or, with `self_signed`, from a certificate generated once and
+//! kept under `tls/` in the data directory so its fingerprint stays stable
+//! for clients that pin it. It is only generated again when the configured
+//! host is no longer among its names. Its key is readable by the owner only.
+//!
+//! The files are checked on every new connection: when either changes the
+//! pair is loaded again, so a renewed certificate applies without a
+//! restart. A pair that fails to load is logged and the previous one stays
+//! in use.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+const SELF_SIGNED_CERT: &str = "selfsigned-cert.pem";
+const SELF_SIGNED_KEY: &str = "selfsigned-key.pem";
+const SELF_SIGNED_YEARS: i64 = 10;
+
+/// SHA-256 fingerprint of the certificate being served, if TLS is on.
+static FINGERPRINT: RwLock<Option<String>> = RwLock::new(None);
+
+/// Fingerprint of the served certificate, `AB:CD:...` (SHA-256).
+pub fn fingerprint() -> Option<String> {
+    FINGERPRINT
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Forget the fingerprint once the server runs without TLS.
+pub fn clear() {
+    *FINGERPRINT.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+struct Loaded {
+    acceptor: TlsAcceptor,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+/// The certificate pair in use, reloaded when its files change.
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl Tls {
+    /// Load the configured pair, generating the self-signed one if needed.
+    /// `host` is added to a generated certificate's names.
+    pub fn from_config(cfg: &TlsConfig, host: &str) -> Result<Self, String> {
+        let (cert, key) = if cfg.cert_path.trim().is_empty() && cfg.self_signed {
+            let dir = crate::profile::base_dir().join("tls");
+            let (cert, key) = (dir.join(SELF_SIGNED_CERT), dir.join(SELF_SIGNED_KEY));
+            let stale = !key.exists() || !names_host(&cert, host);
+            if stale {
+                std::fs::create_dir_all(&dir)
+                    .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+                let (cert_pem, key_pem) = self_signed(host)?;
+                write_private(&key, &key_pem)
+                    .map_err(|e| format!("Cannot write {}: {}", key.display(), e))?;
+                std::fs::write(&cert, cert_pem)
+                    .map_err(|e| format!("Cannot write {}: {}", cert.display(), e))?;
+                crate::logger::info(
+                    "tls",
+                    &format!("Generated self-signed certificate {}", cert.display()),
+                );
+            }
+            (cert, key)
+        } else {
+            (
+                PathBuf::from(cfg.cert_path.trim()),
+                PathBuf::from(cfg.key_path.trim()),
+            )
+        };
+        let loaded = load(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Acceptor for a new connection, picking up changed files first.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        if modified(&self.cert, &self.key) != loaded.modified {
+            match load(&self.cert, &self.key) {
+                Ok(fresh) => {
+                    crate::logger::info(
+                        "tls",
+                        &format!("Reloaded certificate {}", self.cert.display()),
+                    );
+                    *loaded = fresh;
+                }
+                Err(e) => {
+                    crate::logger::error("tls", &format!("{}; keeping the previous one", e));
+                    // Don't retry until the files change again
+                    loaded.modified = modified(&self.cert, &self.key);
+                }
+            }
+        }
+        loaded.acceptor.clone()
+    }
+}
+
+/// Write a private key readable by the owner only.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // `mode` only applies to new files; tighten a key written before
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Whether the generated certificate at `cert` exists and carries the
+/// names [`self_signed`] would give it for `host`, so a changed host gets
+/// a new certificate.
+fn names_host(cert: &Path, host: &str) -> bool {
+    let Ok(Some(Ok(cert))) = CertificateDer::pem_file_iter(cert).map(|mut certs| certs.next())
+    else {
+        return false;
+    };
+    let extension = der(0x04, &alt_names(host));
+    cert.as_ref()
+        .windows(extension.len())
+        .any(|window| window == extension.as_slice())
+}
+
+fn modified(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let at = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    (at(cert), at(key))
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<Loaded, String> {
+    let modified = modified(cert_path, key_path);
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read TLS certificate {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!(
+            "Cannot read TLS certificate {}: no certificate found",
+            cert_path.display()
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Cannot read TLS key {}: {}", key_path.display(), e))?;
+    let fingerprint = fingerprint_of(&certs[0]);
+    let config = server_config(certs, key)?;
+    *FINGERPRINT.write().unwrap_or_else(|e| e.into_inner()) = Some(fingerprint);
+    Ok(Loaded {
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+        modified,
+    })
+}
+
+fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig, String> {
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn fingerprint_of(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// ============================================
+// Self-Signed Certificates
+// ============================================
+
+/// A DER TLV.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn seq(parts: &[&[u8]]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+// Object identifiers, value bytes only
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// A self-signed P-256 certificate for `localhost`, the loopback addresses
+/// and `host`, as (certificate PEM, PKCS#8 key PEM).
+fn self_signed(host: &str) -> Result<(String, String), String> {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| "Failed to generate a TLS key".to_string())?;
+    let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|_| "Failed to generate a TLS key".to_string())?;
+
+    let algorithm = seq(&[&der(0x06, OID_ECDSA_SHA256)]);
+    let name = seq(&[&der(
+        0x31,
+        &seq(&[&der(0x06, OID_COMMON_NAME), &der(0x0c, b"CCR Relay")]),
+    )]);
+    let now = chrono::Utc::now();
+    let time = |t: chrono::DateTime<chrono::Utc>| {
+        der(0x17, t.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    };
+    let validity = seq(&[
+        &time(now - chrono::Duration::days(1)),
+        &time(now + chrono::Duration::days(365 * SELF_SIGNED_YEARS)),
+    ]);
+    let public_key = seq(&[
+        &seq(&[&der(0x06, OID_EC_PUBLIC_KEY), &der(0x06, OID_P256)]),
+        &der(0x03, &[&[0u8][..], pair.public_key().as_ref()].concat()),
+    ]);
+
+    let extensions = der(
+        0xa3,
+        &seq(&[&seq(&[
+            &der(0x06, OID_SUBJECT_ALT_NAME),
+            &der(0x04, &alt_names(host)),
+        ])]),
+    );
+
+    let mut serial: [u8; 16] = rand::random();
+    serial[0] &= 0x7f;
+    let tbs = seq(&[
+        &der(0xa0, &der(0x02, &[2])),
+        &der(0x02, &serial),
+        &algorithm,
+        &name,
+        &validity,
+        &name,
+        &public_key,
+        &extensions,
+    ]);
+    let signature = pair
+        .sign(&rng, &tbs)
+        .map_err(|_| "Failed to sign the TLS certificate".to_string())?;
+    let cert = seq(&[
+        &tbs,
+        &algorithm,
+        &der(0x03, &[&[0u8][..], signature.as_ref()].concat()),
+    ]);
+    Ok((
+        pem("CERTIFICATE", &cert),
+        pem("PRIVATE KEY", pkcs8.as_ref()),
+    ))
+}
+
+/// The subjectAltName of a generated certificate: `localhost`, the
+/// loopback addresses and `host` when it is a specific IP address.
+fn alt_names(host: &str) -> Vec<u8> {
+    let mut names = vec![der(0x82, b"localhost"), der(0x87, &[127, 0, 0, 1])];
+    let mut loopback_v6 = [0u8; 16];
+    loopback_v6[15] = 1;
+    names.push(der(0x87, &loopback_v6));
+    match host.trim().parse::<std::net::IpAddr>() {
+        Ok(ip) if ip.is_unspecified() || ip.is_loopback() => {}
+        Ok(std::net::IpAddr::V4(ip)) => names.push(der(0x87, &ip.octets())),
+        Ok(std::net::IpAddr::V6(ip)) => names.push(der(0x87, &ip.octets())),
+        Err(_) => {}
+    }
+    seq(&names.iter().map(Vec::as_slice).collect::<Vec<_>>())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    use base64::Engine;
+    let body = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in body.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ccr-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_der_lengths() {
+        assert_eq!(der(0x04, &[1, 2]), vec![0x04, 2, 1, 2]);
+        let long = der(0x04, &[0; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[test]
+    fn test_unreadable_files_are_reported() {
+        let dir = temp_dir("missing");
+        let err = load(&dir.join("nope.pem"), &dir.join("nope-key.pem"))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("Cannot read TLS certificate"), "{}", err);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_generated_certificate_follows_host() {
+        let dir = temp_dir("host");
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        assert!(!names_host(&cert, "192.168.1.20"));
+        let (cert_pem, key_pem) = self_signed("192.168.1.20").unwrap();
+        std::fs::write(&cert, cert_pem).unwrap();
+        assert!(names_host(&cert, "192.168.1.20"));
+        assert!(!names_host(&cert, "10.0.0.5"));
+        assert!(!names_host(&cert, "127.0.0.1"));
+
+        write_private(&key, &key_pem).unwrap();
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), key_pem);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_handshake() {
+        let dir = temp_dir("handshake");
+        let (cert_pem, key_pem) = self_signed("192.168.1.20").unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, &cert_pem).unwrap();
+        std::fs::write(&key, &key_pem).unwrap();
+        let acceptor = load(&cert, &key).unwrap().acceptor;
+        assert_eq!(fingerprint().unwrap().len(), 32 * 3 - 1);
+
+        // A client trusting exactly this certificate can connect
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap())
+            .unwrap();
+        let client = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.is_ok()
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        assert!(connector.connect(name, stream).await.is_ok());
+        assert!(server.await.unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
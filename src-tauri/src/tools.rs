@@ -10,6 +10,8 @@ use std::{
 
 use crate::logger;
 
+pub mod mcp;
+pub mod web_fetch;
 mod user_config;
 
 // 环境检测缓存
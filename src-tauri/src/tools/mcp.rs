@@ -0,0 +1,395 @@
+//! Relay-managed tools from shared MCP servers.
+//!
+//! Each server in `mcp.servers` is reached over MCP's streamable HTTP
+//! transport: JSON-RPC messages POSTed to its URL, answered with JSON or a
+//! short event stream. A session is opened with `initialize` on first use,
+//! and again when the server no longer knows it. The tools of all enabled
+//! servers are offered as `<server>__<tool>` and their list is cached for a
+//! few minutes. `forward::tool_loop` adds them to requests and runs the
+//! calls the model makes.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::config::{self, McpServer};
+use crate::forward::client::{self, parse_sse_data};
+use crate::logger;
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const SESSION_HEADER: &str = "mcp-session-id";
+/// How long the tool lists of the servers are reused.
+const TOOLS_TTL: Duration = Duration::from_secs(300);
+/// Longest tool name Anthropic and OpenAI accept.
+const MAX_NAME_LEN: usize = 64;
+
+/// A tool of an MCP server, under the name the model sees.
+#[derive(Debug, Clone)]
+pub struct Tool {
+    /// `<server>__<tool>`
+    pub name: String,
+    pub server: String,
+    /// Name of the tool on its server
+    pub remote_name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+/// The tools of every enabled server.
+#[derive(Debug, Default)]
+pub struct Toolset {
+    pub tools: Vec<Tool>,
+}
+
+impl Toolset {
+    pub fn get(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|t| t.name == name)
+    }
+}
+
+/// Outcome of a `tools/call`.
+#[derive(Debug, Clone)]
+pub struct CallResult {
+    /// MCP content items (`text`, `image`, `resource`, ...)
+    pub content: Vec<Value>,
+    pub is_error: bool,
+}
+
+struct Listing {
+    servers: Vec<McpServer>,
+    at: Instant,
+    toolset: Arc<Toolset>,
+}
+
+static LISTING: Lazy<Mutex<Option<Listing>>> = Lazy::new(|| Mutex::new(None));
+/// Session id of each server; empty for servers without sessions
+static SESSIONS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+enum Failure {
+    /// The server no longer knows the session
+    Expired,
+    Other(String),
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Other(message)
+    }
+}
+
+/// The tools currently offered, `None` when no server has any. A server
+/// that cannot be listed is skipped until the cache expires.
+pub async fn toolset() -> Option<Arc<Toolset>> {
+    let servers: Vec<McpServer> = config::current()
+        .mcp
+        .servers
+        .iter()
+        .filter(|s| s.enabled)
+        .cloned()
+        .collect();
+    if servers.is_empty() {
+        return None;
+    }
+    let cached = LISTING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .filter(|l| l.servers == servers && l.at.elapsed() < TOOLS_TTL)
+        .map(|l| l.toolset.clone());
+    let toolset = match cached {
+        Some(toolset) => toolset,
+        None => {
+            let mut tools = Vec::new();
+            for server in &servers {
+                match list_tools(server).await {
+                    Ok(listed) => tools.extend(listed),
+                    Err(e) => logger::warn(
+                        "mcp",
+                        &format!(
+                            "Could not list the tools of MCP server '{}': {}",
+                            server.id, e
+                        ),
+                    ),
+                }
+            }
+            let toolset = Arc::new(Toolset { tools });
+            *LISTING.lock().unwrap_or_else(|e| e.into_inner()) = Some(Listing {
+                servers,
+                at: Instant::now(),
+                toolset: toolset.clone(),
+            });
+            toolset
+        }
+    };
+    (!toolset.tools.is_empty()).then_some(toolset)
+}
+
+/// Run `tool` with `arguments`. Errors the tool reports itself come back
+/// as a result with `is_error`, failures to reach it as `Err`.
+pub async fn call(tool: &Tool, arguments: &Value) -> Result<CallResult, String> {
+    let server = server(&tool.server)?;
+    let result = rpc(
+        &server,
+        "tools/call",
+        json!({"name": tool.remote_name, "arguments": arguments}),
+    )
+    .await?;
+    Ok(CallResult {
+        content: result
+            .get("content")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default(),
+        is_error: result
+            .get("isError")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+fn server(id: &str) -> Result<McpServer, String> {
+    config::current()
+        .mcp
+        .servers
+        .iter()
+        .find(|s| s.id == id && s.enabled)
+        .cloned()
+        .ok_or_else(|| format!("MCP server '{}' is not configured", id))
+}
+
+async fn list_tools(server: &McpServer) -> Result<Vec<Tool>, String> {
+    let mut tools = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let params = match &cursor {
+            Some(cursor) => json!({"cursor": cursor}),
+            None => json!({}),
+        };
+        let result = rpc(server, "tools/list", params).await?;
+        for tool in result
+            .get("tools")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let Some(remote_name) = tool.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            let name = exposed_name(&server.id, remote_name);
+            if name.len() > MAX_NAME_LEN {
+                logger::warn(
+                    "mcp",
+                    &format!("Skipped MCP tool {}: its name is too long", name),
+                );
+                continue;
+            }
+            tools.push(Tool {
+                name,
+                server: server.id.clone(),
+                remote_name: remote_name.to_string(),
+                description: tool
+                    .get("description")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                input_schema: tool
+                    .get("inputSchema")
+                    .cloned()
+                    .unwrap_or_else(|| json!({"type": "object"})),
+            });
+        }
+        cursor = result
+            .get("nextCursor")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if cursor.is_none() {
+            return Ok(tools);
+        }
+    }
+}
+
+/// `<server>__<tool>`, with characters rejected in tool names
+/// replaced by `_`.
+fn exposed_name(server: &str, tool: &str) -> String {
+    let tool: String = tool
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}__{}", server, tool)
+}
+
+/// Send a request in the server's session, opening a new one if needed.
+async fn rpc(server: &McpServer, method: &str, params: Value) -> Result<Value, String> {
+    let known = SESSIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&server.id)
+        .cloned();
+    let session = match known {
+        Some(session) => session,
+        None => initialize(server).await?,
+    };
+    match send(server, &session, method, Some(params.clone())).await {
+        Err(Failure::Expired) => {
+            let session = initialize(server).await?;
+            send(server, &session, method, Some(params))
+                .await
+                .map(|(_, result)| result)
+                .map_err(|e| match e {
+                    Failure::Expired => "the server rejected its new session".to_string(),
+                    Failure::Other(message) => message,
+                })
+        }
+        Err(Failure::Other(message)) => Err(message),
+        Ok((_, result)) => Ok(result),
+    }
+}
+
+/// Open a session and remember its id.
+async fn initialize(server: &McpServer) -> Result<String, String> {
+    let params = json!({
+        "protocolVersion": PROTOCOL_VERSION,
+        "capabilities": {},
+        "clientInfo": {"name": "ai-relay", "version": env!("CARGO_PKG_VERSION")},
+    });
+    let (session, _) = send(server, "", "initialize", Some(params))
+        .await
+        .map_err(|e| match e {
+            Failure::Expired => "initialize was rejected".to_string(),
+            Failure::Other(message) => message,
+        })?;
+    let session = session.unwrap_or_default();
+    // A notification: the server answers 202 without a body
+    let _ = send(server, &session, "notifications/initialized", None).await;
+    SESSIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(server.id.clone(), session.clone());
+    Ok(session)
+}
+
+/// POST one message: a request when `params` is set, else a notification.
+/// Returns the session id the server assigned, if any, and the result.
+async fn send(
+    server: &McpServer,
+    session: &str,
+    method: &str,
+    params: Option<Value>,
+) -> Result<(Option<String>, Value), Failure> {
+    let id = params
+        .is_some()
+        .then(|| NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let message = match (&params, id) {
+        (Some(params), Some(id)) => {
+            json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+        }
+        _ => json!({"jsonrpc": "2.0", "method": method}),
+    };
+    let client = client::create_client(server.timeout_secs).map_err(|e| e.to_string())?;
+    let mut request = client
+        .post(&server.url)
+        .header("accept", "application/json, text/event-stream")
+        .json(&message);
+    for (name, value) in &server.headers {
+        request = request.header(name, value);
+    }
+    if !session.is_empty() {
+        request = request.header(SESSION_HEADER, session);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status.as_u16() == 404 && !session.is_empty() {
+        return Err(Failure::Expired);
+    }
+    let assigned = response
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let is_sse = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        let body: String = body.chars().take(300).collect();
+        return Err(Failure::Other(format!(
+            "HTTP {}: {}",
+            status.as_u16(),
+            body
+        )));
+    }
+    let Some(id) = id else {
+        return Ok((assigned, Value::Null));
+    };
+    let reply = read_reply(&body, is_sse, id)
+        .ok_or_else(|| format!("no reply to {} in the response", method))?;
+    if let Some(error) = reply.get("error") {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error");
+        return Err(Failure::Other(format!("{} failed: {}", method, message)));
+    }
+    Ok((
+        assigned,
+        reply.get("result").cloned().unwrap_or(Value::Null),
+    ))
+}
+
+/// The JSON-RPC reply with `id` in a JSON or event stream body.
+fn read_reply(body: &str, is_sse: bool, id: u64) -> Option<Value> {
+    if !is_sse {
+        return serde_json::from_str(body).ok();
+    }
+    let mut data = Vec::new();
+    for line in body.lines().chain([""]) {
+        if let Some(part) = parse_sse_data(line) {
+            data.push(part);
+            continue;
+        }
+        if !line.trim().is_empty() || data.is_empty() {
+            continue;
+        }
+        let message: Option<Value> = serde_json::from_str(&data.join("\n")).ok();
+        data.clear();
+        if let Some(message) = message.filter(|m| m.get("id").and_then(Value::as_u64) == Some(id)) {
+            return Some(message);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replies_are_read_from_event_streams() {
+        let body = "event: message\ndata: {\"jsonrpc\":\"2.0\",\"method\":\"notifications/progress\"}\n\nevent: message\ndata: {\"jsonrpc\":\"2.0\",\"id\":7,\"result\":{\"tools\":[]}}";
+        assert_eq!(
+            read_reply(body, true, 7).unwrap()["result"],
+            json!({"tools": []})
+        );
+        assert!(read_reply(body, true, 8).is_none());
+        assert_eq!(
+            read_reply(r#"{"jsonrpc":"2.0","id":3,"result":{}}"#, false, 3).unwrap()["id"],
+            3
+        );
+
+        assert_eq!(exposed_name("docs", "search.pages"), "docs__search_pages");
+        assert_eq!(exposed_name("gh", "create-issue"), "gh__create-issue");
+    }
+}
@@ -0,0 +1,298 @@
+//! The relay's built-in `web_fetch` tool.
+//!
+//! Lets models on upstreams without browsing read a page: a GET of the URL
+//! they pass, cut off after `web_fetch.timeout_secs` or `max_bytes`, with
+//! HTML reduced to its text. Only http(s) URLs whose host passes
+//! `deny_domains` and `allow_domains` are fetched, and loopback, private
+//! or link-local addresses written in the URL only when allowed by name.
+//! Redirects are followed here rather than by the client, so every hop is
+//! checked the same way.
+
+use std::net::IpAddr;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use crate::config::{self, WebFetchConfig};
+use crate::forward::client;
+
+pub const NAME: &str = "web_fetch";
+pub const DESCRIPTION: &str = "Fetch a web page over HTTP(S) and return its text. Use it to read documentation, articles or API responses at a known URL.";
+const MAX_REDIRECTS: usize = 5;
+
+static COMMENTS: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static TITLE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title\s*>").unwrap());
+/// Elements whose content is not page text; the regex crate has no
+/// backreferences, hence one alternative per element
+static HIDDEN: Lazy<Regex> = Lazy::new(|| {
+    let alternatives: Vec<String> = ["head", "script", "style", "noscript", "template", "svg"]
+        .iter()
+        .map(|tag| format!(r"<{0}\b.*?</{0}\s*>", tag))
+        .collect();
+    Regex::new(&format!("(?is){}", alternatives.join("|"))).unwrap()
+});
+static BLOCKS: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)</?(p|div|br|li|ul|ol|tr|table|h[1-6]|section|article|header|footer|nav|main|blockquote|pre|hr|dt|dd)\b[^>]*>").unwrap()
+});
+static TAGS: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+static ENTITIES: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|[a-zA-Z]+);").unwrap());
+
+/// JSON schema of the tool's input.
+pub fn input_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "url": {"type": "string", "description": "Absolute http or https URL"}
+        },
+        "required": ["url"],
+    })
+}
+
+/// Fetch the `url` of a call's `input`. Refusals and failures are errors
+/// meant for the model.
+pub async fn call(input: &Value) -> Result<String, String> {
+    let cfg = config::current().web_fetch.clone();
+    let url = input
+        .get("url")
+        .and_then(Value::as_str)
+        .ok_or("web_fetch needs a url")?;
+    let mut url = Url::parse(url.trim()).map_err(|e| format!("invalid URL: {}", e))?;
+    let client =
+        client::create_client_without_redirects(cfg.timeout_secs).map_err(|e| e.to_string())?;
+    for _ in 0..=MAX_REDIRECTS {
+        check(&cfg, &url)?;
+        let mut response = client
+            .get(url.as_str())
+            .header("accept", "text/html, text/plain;q=0.9, */*;q=0.5")
+            .send()
+            .await
+            .map_err(|e| format!("fetching {} failed: {}", url, e))?;
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get("location")
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("HTTP {} without a location", status.as_u16()))?;
+            url = url
+                .join(location)
+                .map_err(|e| format!("invalid redirect: {}", e))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(format!("{} returned HTTP {}", url, status.as_u16()));
+        }
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let mut body = Vec::new();
+        let mut cut = false;
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            let room = cfg.max_bytes.saturating_sub(body.len());
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if chunk.len() > room {
+                cut = true;
+                break;
+            }
+        }
+        let raw = String::from_utf8_lossy(&body);
+        let text = if content_type.contains("html") {
+            html_to_text(&raw)
+        } else if content_type.is_empty()
+            || content_type.starts_with("text/")
+            || content_type.contains("json")
+            || content_type.contains("xml")
+        {
+            raw.into_owned()
+        } else {
+            return Err(format!("{} is {}, not text", url, content_type));
+        };
+        return Ok(limit(&text, cfg.max_chars, cut));
+    }
+    Err(format!("more than {} redirects", MAX_REDIRECTS))
+}
+
+/// Whether `cfg` lets `url` be fetched.
+fn check(cfg: &WebFetchConfig, url: &Url) -> Result<(), String> {
+    check_url(&cfg.allow_domains, &cfg.deny_domains, url)
+}
+
+/// Whether `url` is http(s) and its host passes the domain lists; local
+/// addresses only when allowed by name.
+pub(crate) fn check_url(
+    allow_domains: &[String],
+    deny_domains: &[String],
+    url: &Url,
+) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "only http and https URLs can be fetched, not {}",
+            url
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("{} has no host", url))?
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if deny_domains.iter().any(|d| matches_domain(&host, d)) {
+        return Err(format!("{} is blocked", host));
+    }
+    let allowed = allow_domains.iter().any(|d| matches_domain(&host, d));
+    if !allow_domains.is_empty() && !allowed {
+        return Err(format!("{} is not in the allowed domains", host));
+    }
+    if !allowed && is_local(&host) {
+        return Err(format!("{} is a local address", host));
+    }
+    Ok(())
+}
+
+/// `host` is `domain` or one of its subdomains.
+fn matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain
+        .trim()
+        .trim_start_matches("*.")
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    !domain.is_empty()
+        && (host == domain
+            || host
+                .strip_suffix(&domain)
+                .is_some_and(|rest| rest.ends_with('.')))
+}
+
+fn is_local(host: &str) -> bool {
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|v4| v4.is_loopback() || v4.is_private() || v4.is_link_local())
+        }
+        Err(_) => false,
+    }
+}
+
+/// The text of an HTML page, its title first.
+fn html_to_text(html: &str) -> String {
+    let title = TITLE
+        .captures(html)
+        .map(|c| decode_entities(TAGS.replace_all(&c[1], "").trim()));
+    let text = COMMENTS.replace_all(html, "");
+    let text = HIDDEN.replace_all(&text, "");
+    let text = BLOCKS.replace_all(&text, "\n");
+    let text = decode_entities(&TAGS.replace_all(&text, ""));
+
+    let mut lines: Vec<String> = Vec::new();
+    if let Some(title) = title.filter(|t| !t.is_empty()) {
+        lines.push(title);
+        lines.push(String::new());
+    }
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() || lines.last().is_some_and(|l| !l.is_empty()) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    ENTITIES
+        .replace_all(text, |caps: &Captures| {
+            let entity = &caps[1];
+            let code = match entity.strip_prefix('#') {
+                Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok(),
+                Some(dec) => dec.parse().ok(),
+                None => None,
+            };
+            if let Some(c) = code.and_then(char::from_u32) {
+                return c.to_string();
+            }
+            match entity {
+                "amp" => "&",
+                "lt" => "<",
+                "gt" => ">",
+                "quot" => "\"",
+                "apos" => "'",
+                "nbsp" => " ",
+                "mdash" => "\u{2014}",
+                "ndash" => "\u{2013}",
+                "hellip" => "\u{2026}",
+                "copy" => "\u{a9}",
+                _ => return caps[0].to_string(),
+            }
+            .to_string()
+        })
+        .into_owned()
+}
+
+/// `text` cut to `max_chars`, saying so when anything was left out.
+fn limit(text: &str, max_chars: usize, cut: bool) -> String {
+    let mut limited: String = text.chars().take(max_chars).collect();
+    if cut || limited.len() < text.len() {
+        limited.push_str("\n\n[truncated]");
+    }
+    limited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_are_reduced_to_text_and_hosts_checked() {
+        let html = "<html><head><title>Docs &amp; more</title><style>p{}</style></head>\
+            <body><!-- nav --><script>var x = '<p>';</script><h1>Intro</h1>\
+            <p>Hello,&nbsp;<b>world</b> &#8212; &#x41;&lt;&gt;</p>\n\n\n<ul><li>one</li><li>two</li></ul></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Docs & more\n\nIntro\n\nHello, world \u{2014} A<>\n\none\n\ntwo"
+        );
+        assert_eq!(limit("abcdef", 3, false), "abc\n\n[truncated]");
+        assert_eq!(limit("abc", 3, false), "abc");
+
+        let url = |u: &str| Url::parse(u).unwrap();
+        let mut cfg = WebFetchConfig {
+            deny_domains: vec!["ads.example.com".to_string()],
+            ..WebFetchConfig::default()
+        };
+        assert!(check(&cfg, &url("https://docs.example.com/a")).is_ok());
+        assert!(check(&cfg, &url("https://x.ads.example.com/")).is_err());
+        assert!(check(&cfg, &url("ftp://example.com/")).is_err());
+        assert!(check(&cfg, &url("http://127.0.0.1:8080/")).is_err());
+        assert!(check(&cfg, &url("http://[::1]/")).is_err());
+        assert!(check(&cfg, &url("http://192.168.1.4/")).is_err());
+        assert!(check(&cfg, &url("http://localhost/")).is_err());
+
+        cfg.allow_domains = vec!["*.example.com".to_string(), "localhost".to_string()];
+        assert!(check(&cfg, &url("https://example.com/")).is_ok());
+        assert!(check(&cfg, &url("https://notexample.com/")).is_err());
+        assert!(check(&cfg, &url("http://localhost:3000/")).is_ok());
+    }
+}
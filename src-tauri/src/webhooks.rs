@@ -0,0 +1,282 @@
+//! Usage webhooks.
+//!
+//! Completed requests and exhausted budgets are posted as JSON events to the
+//! URLs under `webhooks`. Events go through a bounded queue drained by a
+//! background task, so the request path never waits on delivery; failed
+//! deliveries are retried with exponential backoff and then logged.
+//!
+//! When a webhook has a secret, the body is signed with HMAC-SHA256 and sent
+//! as `X-CCR-Signature: sha256=<hex>` so receivers can verify it.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::{config, forward, logger};
+
+const QUEUE_CAPACITY: usize = 1024;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 60_000;
+const SIGNATURE_HEADER: &str = "X-CCR-Signature";
+const EVENT_HEADER: &str = "X-CCR-Event";
+
+/// A completed request.
+#[derive(serde::Serialize, Clone, Debug, Default)]
+pub struct RequestEvent {
+    pub model: String,
+    pub upstream_id: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub cost_usd: Option<f64>,
+    pub latency_ms: Option<i64>,
+    pub status: i64,
+    pub project: Option<String>,
+}
+
+/// A budget whose limit was crossed by the last request.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct BudgetEvent {
+    pub rule: String,
+    pub spent_usd: f64,
+    pub limit_usd: f64,
+    pub action: String,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum WebhookEvent {
+    #[serde(rename = "request.completed")]
+    RequestCompleted(RequestEvent),
+    #[serde(rename = "budget.exceeded")]
+    BudgetExceeded(BudgetEvent),
+    #[serde(rename = "test")]
+    Test(RequestEvent),
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::RequestCompleted(_) => "request.completed",
+            WebhookEvent::BudgetExceeded(_) => "budget.exceeded",
+            WebhookEvent::Test(_) => "test",
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+    timestamp: i64,
+}
+
+fn encode(event: &WebhookEvent) -> String {
+    serde_json::to_string(&Envelope {
+        event,
+        timestamp: chrono::Utc::now().timestamp(),
+    })
+    .unwrap_or_default()
+}
+
+/// HMAC-SHA256 (RFC 2104).
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Value of the signature header for `body`.
+pub fn signature(secret: &str, body: &str) -> String {
+    let mac = hmac_sha256(secret.as_bytes(), body.as_bytes());
+    let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+fn subscribed(hook: &config::WebhookConfig, event: &WebhookEvent) -> bool {
+    hook.enabled
+        && !hook.url.trim().is_empty()
+        && (hook.events.is_empty()
+            || hook
+                .events
+                .iter()
+                .any(|e| e == "*" || e.eq_ignore_ascii_case(event.name())))
+}
+
+async fn send_once(
+    client: &forward::client::HttpClient,
+    url: &str,
+    secret: Option<&str>,
+    event_name: &str,
+    body: &str,
+) -> Result<u16, String> {
+    let mut req = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, event_name)
+        .body(body.to_string());
+    if let Some(secret) = secret.filter(|s| !s.is_empty()) {
+        req = req.header(SIGNATURE_HEADER, signature(secret, body));
+    }
+    let resp = req.send().await.map_err(|e| e.to_string())?;
+    let status = resp.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err(format!("HTTP {}", status.as_u16()))
+    }
+}
+
+async fn deliver(hook: config::WebhookConfig, event_name: &'static str, body: String) {
+    let client = match forward::client::create_client(10) {
+        Ok(client) => client,
+        Err(e) => {
+            logger::error("webhook", &format!("Cannot create HTTP client: {}", e));
+            return;
+        }
+    };
+    let mut backoff = INITIAL_BACKOFF_MS;
+    for attempt in 1..=MAX_ATTEMPTS {
+        // Attempts are not used up while offline
+        crate::network::wait_online_for(&hook.url).await;
+        match send_once(&client, &hook.url, hook.secret.as_deref(), event_name, &body).await {
+            Ok(_) => return,
+            Err(e) if attempt == MAX_ATTEMPTS => {
+                logger::error(
+                    "webhook",
+                    &format!(
+                        "Giving up on {} event to {} after {} attempts: {}",
+                        event_name, hook.url, attempt, e
+                    ),
+                );
+            }
+            Err(e) => {
+                logger::warn(
+                    "webhook",
+                    &format!(
+                        "Delivery of {} event to {} failed (attempt {}/{}): {}",
+                        event_name, hook.url, attempt, MAX_ATTEMPTS, e
+                    ),
+                );
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF_MS);
+            }
+        }
+    }
+}
+
+static QUEUE: OnceLock<mpsc::Sender<WebhookEvent>> = OnceLock::new();
+
+/// Queue an event for every subscribed webhook. Never blocks; events are
+/// dropped (and logged) when the queue is full or delivery is not running.
+pub fn emit(event: WebhookEvent) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if let Err(mpsc::error::TrySendError::Full(event)) = queue.try_send(event) {
+        logger::warn(
+            "webhook",
+            &format!("Webhook queue full, dropping {} event", event.name()),
+        );
+    }
+}
+
+/// Start the delivery task on the current tokio runtime.
+pub fn spawn() {
+    let (tx, mut rx) = mpsc::channel::<WebhookEvent>(QUEUE_CAPACITY);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            let hooks: Vec<config::WebhookConfig> = config::load()
+                .webhooks
+                .into_iter()
+                .filter(|h| subscribed(h, &event))
+                .collect();
+            if hooks.is_empty() {
+                continue;
+            }
+            let body = encode(&event);
+            for hook in hooks {
+                tokio::spawn(deliver(hook, event.name(), body.clone()));
+            }
+        }
+    });
+}
+
+/// Send a sample event to `url` right away and report the outcome.
+pub async fn send_test(url: &str, secret: Option<&str>) -> Result<u16, String> {
+    let event = WebhookEvent::Test(RequestEvent {
+        model: "test-model".to_string(),
+        upstream_id: "test-upstream".to_string(),
+        prompt_tokens: 12,
+        completion_tokens: 34,
+        total_tokens: 46,
+        cost_usd: Some(0.0001),
+        latency_ms: Some(250),
+        status: 200,
+        project: None,
+    });
+    let client = forward::client::create_client(10).map_err(|e| e.to_string())?;
+    send_once(&client, url, secret, event.name(), &encode(&event)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        assert_eq!(
+            signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Keys longer than the block size are hashed first (RFC 4231 case 6)
+        let key = [0xaau8; 131];
+        let mac = hmac_sha256(&key, b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(mac[..4], [0x60, 0xe4, 0x31, 0x59]);
+    }
+
+    #[test]
+    fn test_event_shape_and_subscription() {
+        let event = WebhookEvent::BudgetExceeded(BudgetEvent {
+            rule: "global:*:monthly".to_string(),
+            spent_usd: 10.5,
+            limit_usd: 10.0,
+            action: "block".to_string(),
+        });
+        let body: serde_json::Value = serde_json::from_str(&encode(&event)).unwrap();
+        assert_eq!(body["type"], "budget.exceeded");
+        assert_eq!(body["data"]["limit_usd"], 10.0);
+        assert!(body["timestamp"].as_i64().unwrap() > 0);
+
+        let mut hook = config::WebhookConfig {
+            url: "http://localhost/hook".to_string(),
+            ..Default::default()
+        };
+        assert!(subscribed(&hook, &event));
+        hook.events = vec!["request.completed".to_string()];
+        assert!(!subscribed(&hook, &event));
+        hook.enabled = false;
+        hook.events.clear();
+        assert!(!subscribed(&hook, &event));
+    }
+}
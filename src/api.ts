@@ -9,11 +9,20 @@ import type {
   ToolInstallPlan,
   InstallResult,
   Settings,
+  TokenRotation,
+  RoutePreview,
+  TransformExample,
+  TransformPreview,
+  MirrorReportRow,
+  ExperimentReport,
+  ValidationIssue,
   EnvironmentReport,
   UpstreamLatency,
   LogsResponse,
   AutoConfigStatus,
   AutoConfigRequest,
+  EditorSnippet,
+  SnippetEditor,
   ToolConfigBackupList,
   ToolConfigBackup,
   GlobalLogsResponse,
@@ -87,6 +96,7 @@ export const api = {
   },
   projects: {
     list: () => request<Project[]>("/api/projects"),
+    get: (id: number) => request<Project>(`/api/projects/${id}`),
     create: (payload: ProjectInput) =>
       request<Project>("/api/projects", { method: "POST", body: payload }),
     update: (id: number, payload: ProjectInput) =>
@@ -124,8 +134,43 @@ export const api = {
   },
   providers: () => request<{ providers: string[] }>("/api/providers"),
   forward: {
-    token: () => request<{ token: string }>("/api/forward/token"),
-    refreshToken: () => request<{ token: string }>("/api/forward/token", { method: "POST" }),
+    token: () =>
+      request<{ token: string; version: number; scheduled_at?: number | null; previous_expires_at?: number | null }>(
+        "/api/forward/token",
+      ),
+    refreshToken: () => request<TokenRotation>("/api/forward/token", { method: "POST" }),
+    scheduleRotation: (at: number | null) =>
+      request<{ scheduled_at: number | null }>("/api/forward/token", {
+        method: "POST",
+        body: at === null ? { cancel: true } : { at },
+      }),
+  },
+  route: {
+    preview: (payload: unknown, headers: Record<string, string> = {}, provider?: string) =>
+      request<RoutePreview>("/api/route/preview", { method: "POST", body: { payload, headers, provider } }),
+  },
+  transform: {
+    examples: () => request<TransformExample[]>("/api/transform/preview"),
+    preview: (target: string, payload: unknown, model?: string) =>
+      request<TransformPreview>(
+        `/api/transform/preview?target=${target}${model ? `&model=${encodeURIComponent(model)}` : ""}`,
+        { method: "POST", body: payload },
+      ),
+    previewExample: (example: string) =>
+      request<TransformPreview>(`/api/transform/preview?example=${encodeURIComponent(example)}`, {
+        method: "POST",
+      }),
+  },
+  mirror: {
+    report: (days = 7) => request<{ rows: MirrorReportRow[] }>(`/api/mirror/report?days=${days}`),
+  },
+  experiments: {
+    report: (id: string) => request<ExperimentReport>(`/api/experiments/${encodeURIComponent(id)}/report`),
+    end: (id: string, winner: "a" | "b") =>
+      request<{ warnings: ValidationIssue[] }>(`/api/experiments/${encodeURIComponent(id)}/end`, {
+        method: "POST",
+        body: { winner },
+      }),
   },
   export: {
     backup: () => request<any>("/api/export/backup"),
@@ -138,6 +183,10 @@ export const api = {
     status: () => request<AutoConfigStatus>("/api/auto-config/status"),
     configure: (payload: AutoConfigRequest) =>
       request<void>("/api/auto-config/configure", { method: "POST", body: payload }),
+    snippet: (editor: SnippetEditor, model: string) =>
+      request<EditorSnippet>(
+        `/api/auto-config/snippet?editor=${editor}&model=${encodeURIComponent(model)}`,
+      ),
     listBackups: (tool: "claude" | "codex" | "gemini") =>
       request<ToolConfigBackupList>(`/api/auto-config/backups/${tool}`),
     createBackup: (tool: "claude" | "codex" | "gemini", description?: string) =>
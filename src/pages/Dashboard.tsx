@@ -328,6 +328,7 @@ export default function Dashboard() {
           const spend = summary[range]?.price_usd ?? 0;
           const requests = summary[range]?.requests ?? 0;
           const tokens = summary[range]?.tokens ?? 0;
+          const estimatedRatio = summary[range]?.estimated_token_ratio ?? 0;
           return (
             <div key={range} className="card stat-card">
               <div className="stat-header">
@@ -348,6 +349,17 @@ export default function Dashboard() {
                   <span className="stat-label">费用</span>
                   <span className="stat-value">${spend.toFixed(2)}</span>
                 </div>
+                {estimatedRatio > 0 && (
+                  <div className="stat-item">
+                    <span className="stat-label">估算占比</span>
+                    <span
+                      className="stat-value"
+                      title={`${(summary[range]?.estimated_tokens ?? 0).toLocaleString()} tokens 为本地估算（上游未返回用量）`}
+                    >
+                      {(estimatedRatio * 100).toFixed(1)}%
+                    </span>
+                  </div>
+                )}
               </div>
             </div>
           );
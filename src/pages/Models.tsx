@@ -1,7 +1,7 @@
 import { useEffect, useState, useMemo, useCallback, useRef } from "react";
 import { api } from "../api";
 import { useToast, Modal, useModal } from "../components";
-import type { ModelConfig, ModelRoute, Settings, ToolInfo, ToolConfigBackup } from "../types";
+import type { ModelConfig, ModelRoute, PriceTier, Settings, ToolInfo, ToolConfigBackup } from "../types";
 import {
   Bot,
   Zap,
@@ -30,6 +30,9 @@ const emptyRoute = (): ModelRoute => ({
   priority: undefined,
 });
 
+const parseOptionalPrice = (value: string): number | null =>
+  value.trim() === "" ? null : Number(value);
+
 const emptyModel = (): ModelConfig => ({
   id: "",
   display_name: "",
@@ -134,6 +137,24 @@ export default function Models() {
   const [saving, setSaving] = useState(false);
   const [editingIndex, setEditingIndex] = useState<number | null>(null);
   const [editingModel, setEditingModel] = useState<ModelConfig | null>(null);
+
+  const updateTier = (idx: number, patch: Partial<PriceTier>) => {
+    if (!editingModel) return;
+    const tiers = (editingModel.price_tiers ?? []).map((tier, i) => (i === idx ? { ...tier, ...patch } : tier));
+    setEditingModel({ ...editingModel, price_source: "manual", price_tiers: tiers });
+  };
+
+  const addTier = () => {
+    if (!editingModel) return;
+    const tiers = [...(editingModel.price_tiers ?? []), { above_prompt_tokens: 200000 }];
+    setEditingModel({ ...editingModel, price_source: "manual", price_tiers: tiers });
+  };
+
+  const removeTier = (idx: number) => {
+    if (!editingModel) return;
+    const tiers = (editingModel.price_tiers ?? []).filter((_, i) => i !== idx);
+    setEditingModel({ ...editingModel, price_source: "manual", price_tiers: tiers });
+  };
   const [tools, setTools] = useState<ToolInfo[]>([]);
   const [autoConfigStatus, setAutoConfigStatus] = useState<AutoConfigStatus>({
     claude: { configured: false },
@@ -907,7 +928,7 @@ export default function Models() {
                   step="0.0001"
                   value={editingModel.price_prompt_per_1k}
                   onChange={(e) =>
-                    setEditingModel({ ...editingModel, price_prompt_per_1k: Number(e.target.value) })
+                    setEditingModel({ ...editingModel, price_source: "manual", price_prompt_per_1k: Number(e.target.value) })
                   }
                 />
               </label>
@@ -919,10 +940,82 @@ export default function Models() {
                   step="0.0001"
                   value={editingModel.price_completion_per_1k}
                   onChange={(e) =>
-                    setEditingModel({ ...editingModel, price_completion_per_1k: Number(e.target.value) })
+                    setEditingModel({ ...editingModel, price_source: "manual", price_completion_per_1k: Number(e.target.value) })
+                  }
+                />
+              </label>
+              <label>
+                Cache 写入价格 ($/1k, 留空同 Prompt)
+                <input
+                  type="number"
+                  min="0"
+                  step="0.0001"
+                  value={editingModel.price_cache_write_per_1k ?? ""}
+                  onChange={(e) =>
+                    setEditingModel({ ...editingModel, price_source: "manual", price_cache_write_per_1k: parseOptionalPrice(e.target.value) })
+                  }
+                />
+              </label>
+              <label>
+                Cache 读取价格 ($/1k, 留空同 Prompt)
+                <input
+                  type="number"
+                  min="0"
+                  step="0.0001"
+                  value={editingModel.price_cache_read_per_1k ?? ""}
+                  onChange={(e) =>
+                    setEditingModel({ ...editingModel, price_source: "manual", price_cache_read_per_1k: parseOptionalPrice(e.target.value) })
                   }
                 />
               </label>
+              <label>
+                推理价格 ($/1k, 留空同 Completion)
+                <input
+                  type="number"
+                  min="0"
+                  step="0.0001"
+                  value={editingModel.price_reasoning_per_1k ?? ""}
+                  onChange={(e) =>
+                    setEditingModel({ ...editingModel, price_source: "manual", price_reasoning_per_1k: parseOptionalPrice(e.target.value) })
+                  }
+                />
+              </label>
+              <div className="price-tiers">
+                <span>上下文分级价格 (Prompt 超过指定 tokens 时生效)</span>
+                {(editingModel.price_tiers ?? []).map((tier, idx) => (
+                  <div className="price-tier-row" key={idx}>
+                    <input
+                      type="number"
+                      min="0"
+                      placeholder="超过 tokens"
+                      value={tier.above_prompt_tokens}
+                      onChange={(e) => updateTier(idx, { above_prompt_tokens: Number(e.target.value) })}
+                    />
+                    <input
+                      type="number"
+                      min="0"
+                      step="0.0001"
+                      placeholder="Prompt $/1k"
+                      value={tier.price_prompt_per_1k ?? ""}
+                      onChange={(e) => updateTier(idx, { price_prompt_per_1k: parseOptionalPrice(e.target.value) })}
+                    />
+                    <input
+                      type="number"
+                      min="0"
+                      step="0.0001"
+                      placeholder="Completion $/1k"
+                      value={tier.price_completion_per_1k ?? ""}
+                      onChange={(e) => updateTier(idx, { price_completion_per_1k: parseOptionalPrice(e.target.value) })}
+                    />
+                    <button type="button" onClick={() => removeTier(idx)}>
+                      删除
+                    </button>
+                  </div>
+                ))}
+                <button type="button" onClick={addTier}>
+                  添加分级
+                </button>
+              </div>
               <label>
                 优先级 (0-99, 100为系统保留)
                 <input
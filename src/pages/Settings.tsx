@@ -27,6 +27,26 @@ const simplifyEndpoint = (url: string): string => {
   }
 };
 
+// 延迟测试失败原因
+const probeErrorLabel = (stat: LatencyStat): string => {
+  switch (stat.error?.kind) {
+    case "dns":
+      return "DNS 失败";
+    case "connect":
+      return "连接失败";
+    case "tls":
+      return "TLS 失败";
+    case "http_status":
+      return `HTTP ${stat.status ?? ""}`.trim();
+    case "invalid_url":
+      return "地址无效";
+    case "timeout":
+      return "超时";
+    default:
+      return "失败";
+  }
+};
+
 const maskApiKey = (apiKey: string | undefined | null): string => {
   if (!apiKey || apiKey.length <= 8) {
     return apiKey || "";
@@ -377,7 +397,10 @@ export default function SettingsPage() {
       } catch {
         // ignore
       }
-      toast.success("转发 Token 已刷新");
+      const graceUntil = res.previous_expires_at
+        ? new Date(res.previous_expires_at * 1000).toLocaleString()
+        : null;
+      toast.success(graceUntil ? `转发 Token 已刷新，旧 Token 在 ${graceUntil} 前仍可用` : "转发 Token 已刷新");
     } catch (err: any) {
       toast.error(err.message || "刷新失败");
     } finally {
@@ -1236,11 +1259,17 @@ export default function SettingsPage() {
                           </td>
                           <td className={`latency-ms ${l.ok ? "ok" : "fail"}`}>
                             {l.ok ? <CheckCircle size={14} style={{verticalAlign: 'middle', marginRight: 4}} /> : <AlertCircle size={14} style={{verticalAlign: 'middle', marginRight: 4}} />}
-                            {l.ms ? `${l.ms}ms` : "-"}
+                            {l.avg_ms != null ? `${l.avg_ms}ms` : "-"}
+                            {l.p95_ms != null && l.samples > 1 && (
+                              <span className="muted"> ({l.min_ms}–{l.p95_ms}ms)</span>
+                            )}
                           </td>
                           <td>
-                            <span className={`latency-status ${l.ok ? "ok" : "fail"}`}>
-                              {l.ok ? "正常" : "超时"}
+                            <span
+                              className={`latency-status ${l.ok ? "ok" : "fail"}`}
+                              title={l.error?.message}
+                            >
+                              {l.ok ? "正常" : probeErrorLabel(l)}
                             </span>
                           </td>
                         </tr>
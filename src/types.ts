@@ -3,6 +3,9 @@ export interface StatsSummary {
   requests: number;
   tokens: number;
   price_usd: number;
+  estimated_requests?: number;
+  estimated_tokens?: number;
+  estimated_token_ratio?: number; // fraction of tokens that are local estimates
 }
 
 export interface StatsSeries {
@@ -22,8 +25,80 @@ export interface ModelStats {
   requests: number;
   tokens: number;
   price_usd: number;
+  cache_creation_tokens?: number;
+  cache_read_tokens?: number;
+  reasoning_tokens?: number;
 }
 
+// A streaming response still being relayed (get_active_streams)
+export interface ActiveStream {
+  id: number;
+  model: string;
+  upstream_id: string;
+  project?: string | null;
+  started_at: number;
+  elapsed_ms: number;
+}
+
+// A model or upstream with max_concurrency (GET /api/queues, get_queue_stats)
+export interface QueueStats {
+  kind: 'model' | 'upstream';
+  id: string; // lowercased
+  max_concurrency: number;
+  running: number;
+  waiting: number;
+}
+
+// One limited window an upstream reported; reset_at in Unix ms
+export interface RateLimitWindow {
+  limit: number | null;
+  remaining: number | null;
+  reset_at: number | null;
+}
+
+// Latest limits of one key (GET /api/upstreams/:id/ratelimits, get_upstream_ratelimits)
+export interface KeyRateLimits {
+  upstream_id: string;
+  key_id: string | null; // label or fingerprint
+  observed_at: number;
+  requests: RateLimitWindow | null;
+  tokens: RateLimitWindow | null;
+  input_tokens: RateLimitWindow | null; // Anthropic only
+  output_tokens: RateLimitWindow | null;
+}
+
+export type ActivityPhase = 'started' | 'first_token' | 'completed' | 'failed' | 'cancelled';
+
+/** `activity` event of GET /api/activity/stream; the `activity` Tauri event carries an array */
+export interface Activity {
+  request_id: string;
+  phase: ActivityPhase;
+  model: string;
+  upstream_id: string;
+  streaming: boolean;
+  prompt_tokens: number; // so far; streams usually report at the end
+  completion_tokens: number;
+  elapsed_ms: number;
+  status?: number | null;
+  error?: string | null;
+  tags: string[];
+}
+
+// Payload of the `usage-updated` event emitted after each recorded request
+export interface UsageUpdatedEvent {
+  model: string;
+  upstream_id: string;
+  prompt_tokens: number;
+  completion_tokens: number;
+  total_tokens: number;
+  cost_usd: number | null;
+  latency_ms: number | null;
+  status: number;
+  project?: string | null;
+}
+
+export type PrefixMode = "prepend" | "append" | "replace";
+
 export interface Project {
   id: number;
   name: string;
@@ -31,6 +106,12 @@ export interface Project {
   description: string;
   tags: string[];
   created_at: number;
+  token?: string | null; // attributes requests and authenticates like the forward token
+  model_overrides?: Record<string, string>; // requested model (or "*") -> model used
+  system_prefix?: string | null; // added to every request's system prompt
+  system_prefix_mode?: PrefixMode;
+  user_patterns?: string[]; // "*" wildcards for OpenAI `user` / Anthropic `metadata.user_id`
+  budget_usd?: number | null; // monthly; requests are blocked once it is spent
 }
 
 export interface ProjectInput {
@@ -38,6 +119,12 @@ export interface ProjectInput {
   path: string;
   description?: string;
   tags?: string[];
+  token?: string | null;
+  model_overrides?: Record<string, string>;
+  system_prefix?: string | null;
+  system_prefix_mode?: PrefixMode;
+  user_patterns?: string[];
+  budget_usd?: number | null;
 }
 
 export interface ToolInfo {
@@ -87,6 +174,31 @@ export interface RateLimitConfig {
   budget_daily_usd?: number;
   budget_weekly_usd?: number;
   budget_monthly_usd?: number;
+  budgets?: BudgetRule[];
+  budget_reset_day?: number; // 1-28
+  budget_utc_offset_minutes?: number;
+}
+
+export interface BudgetRule {
+  scope: 'global' | 'model' | 'upstream' | 'token' | 'project';
+  target?: string | null;
+  period: 'daily' | 'weekly' | 'monthly';
+  limit_usd: number;
+  action: 'block' | 'downgrade' | 'warn';
+  fallback_model?: string | null;
+}
+
+export interface BudgetStatus {
+  scope: string;
+  target: string | null;
+  period: string;
+  action: string;
+  limit_usd: number;
+  spent_usd: number;
+  remaining_usd: number;
+  period_start: number;
+  exceeded: boolean;
+  valid: boolean;
 }
 
 // AI 工具配置文件路径
@@ -111,6 +223,7 @@ export interface Settings {
   retry_initial_ms?: number;
   retry_max_ms?: number;
   forward_token?: string;
+  token_rotation?: TokenRotationConfig;
   preferred_api_style?: string;
   accent_color?: string; // 保留用于向后兼容
   proxy?: ProxyConfig;
@@ -119,6 +232,503 @@ export interface Settings {
   limits?: RateLimitConfig;
   theme?: ThemeConfig;
   backup?: BackupConfig;
+  capture?: CaptureConfig;
+  response_cache?: ResponseCacheConfig;
+  maintenance?: MaintenanceConfig;
+  price_sync?: PriceSyncConfig;
+  webhooks?: WebhookConfig[];
+  unknown_model?: UnknownModelConfig;
+  logging?: LoggingConfig;
+  telemetry?: TelemetryConfig;
+  server?: ServerConfig;
+  discovery?: DiscoveryConfig;
+  http_client?: HttpClientConfig;
+  notifications?: NotificationsConfig;
+  desktop?: DesktopConfig;
+  network?: NetworkConfig;
+  redaction?: RedactionConfig;
+  prompt_redaction?: PromptRedactionConfig;
+  mcp?: McpConfig;
+  web_fetch?: WebFetchConfig;
+  image_fetch?: ImageFetchConfig;
+  reasoning_budgets?: ReasoningBudgets;
+  keep_thinking_history?: boolean; // earlier Anthropic thinking sent to other providers as text; off = dropped
+  experiments?: ExperimentConfig[];
+  token_count?: TokenCountConfig;
+}
+
+// Fixed estimates for attachments in POST /v1/token-count
+export interface TokenCountConfig {
+  image_tokens?: number; // 1000 by default
+  document_tokens?: number; // 3000 by default
+}
+
+// Answer of POST /v1/token-count
+export interface TokenCount {
+  format: "openai" | "anthropic" | "gemini";
+  model?: string | null;
+  tokenizer?: string | null; // encoding of OpenAI models, e.g. "o200k_base"; null when estimated
+  estimated: boolean; // some part was estimated rather than tokenized
+  total_tokens: number;
+  system_tokens: number;
+  tools_tokens: number;
+  messages: MessageTokens[];
+}
+
+export interface MessageTokens {
+  index: number;
+  role: string;
+  tokens: number;
+  images: number;
+  documents: number;
+}
+
+// Requests for `model` split between two configured models, sticky per client
+export interface ExperimentConfig {
+  id: string;
+  model: string;
+  variant_a: string;
+  variant_b: string;
+  split_percent: number; // share of clients on variant A
+  winner?: "a" | "b" | null; // set once ended; serves every request
+}
+
+export interface ExperimentVariantRow {
+  variant: "a" | "b";
+  models: string[];
+  requests: number;
+  prompt_tokens: number;
+  completion_tokens: number;
+  price_usd: number;
+  avg_latency_ms: number | null;
+  error_rate: number;
+}
+
+export interface ExperimentReport extends ExperimentConfig {
+  variants: ExperimentVariantRow[];
+}
+
+// Remote image_url images inlined as base64 for Anthropic and Gemini upstreams
+export interface ImageFetchConfig {
+  enabled: boolean;
+  allow_domains?: string[]; // subdomains included; empty allows any
+  deny_domains?: string[];
+  timeout_secs?: number;
+  max_bytes?: number; // 5 MiB by default
+  cache_secs?: number; // how long a fetched image is reused
+}
+
+// Thinking budget in tokens for each reasoning_effort level
+// Forward token rotation; the replaced token works for grace_secs more
+export interface TokenRotationConfig {
+  grace_secs?: number;
+  scheduled_at?: number | null; // unix seconds of a planned rotation
+  version?: number;
+  previous_token?: string | null;
+  previous_expires_at?: number | null;
+}
+
+export interface TokenRotation {
+  token: string;
+  version: number;
+  previous_expires_at?: number | null;
+}
+
+export interface PreviewedRoute {
+  route_reason: string; // primary, group-member or fallback-n
+  model: string;
+  provider: string;
+  upstream: string;
+  upstream_model: string;
+  endpoints: string[];
+  api_style?: string | null;
+  api_key_id?: string | null;
+  api_key?: string | null; // fingerprint only
+  auth: { mode: "request_token" | "configured_key"; token?: string };
+  streaming: boolean;
+  max_attempts?: number | null;
+}
+
+export interface RoutePreview {
+  primary: PreviewedRoute;
+  fallbacks: PreviewedRoute[];
+}
+
+export interface TransformExample {
+  name: string;
+  target: "openai" | "anthropic" | "gemini";
+  description: string;
+  payload: unknown;
+}
+
+export interface TransformPreview {
+  target: string;
+  model: string;
+  upstream: string;
+  upstream_model: string;
+  api_style: string;
+  body: unknown; // what would be sent upstream, headers and keys left out
+}
+
+export interface ReasoningBudgets {
+  minimal?: number;
+  low?: number;
+  medium?: number;
+  high?: number;
+}
+
+// Replacements applied to model output before it reaches the client
+export interface RedactionRule {
+  pattern: string;
+  regex?: boolean;
+  replacement?: string; // "[REDACTED]" by default; regex rules can use $1
+  project?: string | null; // limits the rule to one project
+  enabled?: boolean;
+}
+
+export interface RedactionConfig {
+  rules: RedactionRule[];
+  max_lookback?: number; // characters of streamed text held back, 256 by default
+}
+
+// Text of outbound prompts with matches replaced by `[NAME]` placeholders
+export interface PromptRedactionConfig {
+  enabled: boolean;
+  builtin?: ("email" | "phone" | "api_key")[]; // all three by default
+  rules?: PromptRedactionRule[];
+}
+
+export interface PromptRedactionRule {
+  name: string; // placeholder is the name uppercased, e.g. [TICKET]
+  pattern: string; // regular expression
+  project?: string | null; // limits the rule to one project
+  enabled?: boolean;
+}
+
+// Answer of POST /api/prompt-redaction/preview
+export interface PromptRedactionPreview {
+  target: "openai" | "anthropic" | "gemini";
+  model: string;
+  project?: string | null;
+  enabled: boolean; // false: shown as it would be, but not applied yet
+  matches: { placeholder: string; count: number }[];
+  body: unknown;
+}
+
+// MCP servers whose tools the relay runs for Anthropic and OpenAI chat clients
+export interface McpServer {
+  id: string; // tools are offered as `<id>__<tool>`
+  url: string; // streamable HTTP endpoint
+  headers?: Record<string, string>;
+  timeout_secs?: number;
+  enabled?: boolean;
+}
+
+export interface McpConfig {
+  servers: McpServer[];
+  max_loops?: number; // tool rounds per request, built-in tools included, 8 by default
+}
+
+// The relay's built-in web_fetch(url) tool
+export interface WebFetchConfig {
+  enabled: boolean;
+  allow_domains?: string[]; // subdomains included; empty allows any
+  deny_domains?: string[];
+  timeout_secs?: number;
+  max_bytes?: number;
+  max_chars?: number; // characters of extracted text returned to the model
+}
+
+// Offline detection; while offline price sync, webhooks and telemetry wait and
+// requests to non-local upstreams fail at once with a 503 "offline" error
+export interface NetworkConfig {
+  detect_offline: boolean;
+  check_targets: string[]; // host:port, any reachable = online; a custom global proxy is checked instead
+  check_interval_secs: number;
+}
+
+// Listen address; applied by the restart_server command
+export interface ServerConfig {
+  host: string; // "127.0.0.1" = this machine only, "0.0.0.0" = LAN
+  port: number; // 0 = pick a free port
+  tcp?: boolean; // false = serve only on unix_socket
+  tls?: TlsConfig;
+  unix_socket?: string; // e.g. "ccr.sock" (in the data directory); not on Windows
+  request_timeout_secs?: number; // non-streaming requests only, 504 when exceeded; 0 = no limit
+  slow_request_ms?: number; // log slower requests with a plan/upstream/convert breakdown; 0 = off
+  max_connections?: number; // requests in flight at once, streams included; 503 beyond; 0 = no limit
+  max_connections_per_ip?: number; // the same per client address; 0 = no limit
+  probe_cache_secs?: number; // model lists, latency and health reused until a config change; 0 = in-flight only
+  passthrough_headers?: string[]; // upstream headers kept on non-streaming answers; trailing * = prefix
+}
+
+// Connection pool towards upstreams; 0 turns a setting off
+export interface HttpClientConfig {
+  pool_idle_timeout_secs: number;
+  pool_max_idle_per_host: number;
+  http2_keep_alive_secs: number; // ping interval, idle connections included
+  http2_keep_alive_timeout_secs: number;
+  tcp_keepalive_secs: number;
+  connect_timeout_secs: number; // DNS, TCP and TLS; then the next endpoint
+  read_timeout_secs: number; // silence from the upstream; 0 = off
+  dns_cache_secs: number; // 0 = no caching
+}
+
+// Desktop app only
+export interface DesktopConfig {
+  autostart: boolean; // start at login, in the tray
+  background: boolean; // start without a window; closing the window hides it, Quit in the tray exits
+}
+
+// Desktop notifications; each event and subject notifies at most once per min_interval_secs
+export interface NotificationsConfig {
+  enabled: boolean;
+  upstream_failing: boolean;
+  budget: boolean; // at 80% and 100% of a limit
+  new_client_ip: boolean; // forward token used from an address not seen since start
+  min_interval_secs: number;
+}
+
+// mDNS (_ai-relay._tcp) advertisement and the relay tools are configured against
+export interface DiscoveryConfig {
+  advertise: boolean; // never while the server listens on 127.0.0.1 only; applied on restart_server
+  name: string; // shown to other devices; "" = host name
+  remote_url: string; // adopted relay, e.g. "http://192.168.1.20:8787"; "" = this relay
+  remote_token: string;
+}
+
+// A relay found by the discover_relays command
+export interface RemoteRelay {
+  name: string;
+  host: string;
+  addresses: string[];
+  port: number;
+  url: string; // pass to adopt_relay
+  version?: string | null;
+  tls: boolean;
+  auth: boolean; // needs the relay's forward token
+  apis: string[];
+}
+
+// HTTPS for the relay server
+export interface TlsConfig {
+  enabled: boolean;
+  cert_path: string; // PEM; reloaded when the file changes
+  key_path: string;
+  self_signed: boolean; // generate a certificate when cert_path is empty
+}
+
+// restart_server error
+export interface BindError {
+  address: string;
+  kind: 'invalid_address' | 'address_in_use' | 'permission_denied' | 'address_not_available' | 'timeout' | 'tls' | 'io';
+  port?: number | null; // the TCP port that was tried; null for a unix socket
+  message: string;
+}
+
+// Payload of `server-state` events and result of the server_status command
+export type ServerState =
+  | { state: 'starting' }
+  | { state: 'running'; address?: string | null; url?: string | null }
+  | { state: 'stopped' }
+  | { state: 'error'; message: string; error: BindError };
+
+// GET /api/server/info, get_server_info, restart_server
+export interface ServerInfo {
+  host: string;
+  port: number;
+  status: 'starting' | 'running' | 'failed' | 'stopped';
+  address?: string | null; // actually bound, e.g. the port chosen for port 0
+  unix_socket?: string | null;
+  url?: string | null; // base URL for clients on this machine
+  lan: boolean;
+  tls: boolean;
+  cert_fingerprint?: string | null; // SHA-256, AB:CD:...
+  error?: BindError | null;
+}
+
+// GET /api/health/detail; each check has its own status, the report the worst
+export type HealthStatus = 'ok' | 'warn' | 'error';
+
+export interface HealthReport {
+  status: HealthStatus; // 'error' is also returned as HTTP 503
+  checked_at: number;
+  db: { status: HealthStatus; latency_ms?: number | null; error?: string | null };
+  config: { status: HealthStatus; errors: number; warnings: number; issues: ValidationIssue[] };
+  upstreams: UpstreamHealth[];
+  streams: { status: HealthStatus; active: number };
+  connections: {
+    status: HealthStatus; // 'warn' while max_connections is reached
+    max: number;
+    max_per_ip: number;
+    active: number;
+    by_ip: { ip: string; active: number }[]; // busiest first
+    rejected: number; // since the server started
+  };
+  queues: { status: HealthStatus; waiting: number; lanes: QueueStats[] }; // 'warn' while requests wait
+  network: { status: HealthStatus; online: boolean; since?: number | null }; // 'warn' while offline
+  disk: { path: string; status: HealthStatus; free_bytes?: number | null; total_bytes?: number | null }[];
+  upstream_pools: { pools: number; requests: number; connections: number }; // connections well below requests = reuse works
+}
+
+export interface UpstreamHealth {
+  id: string;
+  status: HealthStatus; // 'error' after 3 failures in a row
+  consecutive_failures: number;
+  last_success_at?: number | null;
+  last_failure_at?: number | null;
+  last_error?: string | null;
+  last_error_class?: 'timeout' | 'auth' | 'rate_limited' | 'server_error' | 'connection' | null;
+}
+
+export interface LoggingConfig {
+  levels: string; // default level plus overrides, e.g. "info, anthropic=debug"
+  format: 'text' | 'json'; // logs/ccr.log line format
+  file: boolean;
+  max_file_mb: number; // roll over at this size
+  max_age_hours: number; // roll over at this age, 0 = never
+  keep_archives: number; // gzipped ccr-<timestamp>.log.gz files kept
+  access_log: boolean; // one line per request in logs/access.log
+  access_log_db: boolean; // also store access lines in the access_log table
+  dedup_levels: string[]; // repeated entries at these levels are collapsed (never debug)
+  dedup_window_secs: number; // collapse window, 0 = off
+}
+
+// OpenTelemetry trace export (OTLP/HTTP JSON)
+export interface TelemetryConfig {
+  enabled: boolean;
+  endpoint: string; // collector traces URL, e.g. http://localhost:4318/v1/traces
+  headers: Record<string, string>; // sent with every export, e.g. auth
+  service_name: string;
+}
+
+// Requests for model ids that aren't configured
+export interface UnknownModelConfig {
+  mode: 'reject' | 'default_to' | 'suggest'; // suggest: 404 with error.did_you_mean
+  default_to?: string | null; // model id served in default_to mode
+}
+
+// A set of settings and usage data (list_profiles, create_profile, switch_profile)
+export interface ProfileInfo {
+  name: string; // "default" lives in the data directory itself
+  active: boolean;
+  path: string;
+}
+
+// Result of POST /api/config/reload
+export interface ReloadReport {
+  version: number;
+  upstreams: number;
+  models: number;
+  warnings: ValidationIssue[];
+  restart_required: string[]; // listener settings applied on restart_server, e.g. "server.port"
+}
+
+// Result of POST /api/config/import and import_config_file
+export interface ImportReport {
+  upstreams_added: number;
+  upstreams_replaced: number;
+  upstreams_skipped: number;
+  models_added: number;
+  models_replaced: number;
+  models_skipped: number;
+  secrets_imported: boolean;
+  keys_added: number;
+  unknown_upstreams: string[];
+  warnings: ValidationIssue[];
+}
+
+// One entry of POST /api/config/import/preview; field names only, no values
+export interface ImportChange {
+  section: 'upstream' | 'model' | 'routing';
+  id: string;
+  kind: 'added' | 'removed' | 'changed';
+  fields: string[];
+}
+
+export interface ImportPreview {
+  report: ImportReport;
+  changes: ImportChange[];
+}
+
+// Payload of the "import-preview" event sent when a file is dropped on the window
+export interface ImportPreviewEvent {
+  path: string;
+  preview?: ImportPreview;
+  error?: string;
+}
+
+export type ImportMode = 'merge' | 'replace';
+export type ImportPrefer = 'existing' | 'imported'; // which side wins on id collisions
+
+// Returned by POST /api/config/validate and by rejected saves
+export interface ValidationIssue {
+  path: string; // JSON pointer, e.g. /models/2/upstream_id
+  message: string;
+  severity: 'error' | 'warning';
+}
+
+// Rejection from save_upstream, delete_upstream, save_model, delete_model and save_settings
+export interface SaveError {
+  message: string;
+  issues: ValidationIssue[]; // empty when the failure wasn't validation (e.g. unknown id, disk error)
+}
+
+// Payload of the `config-changed` event, emitted after every successful save
+export interface ConfigChangedEvent {
+  section: 'upstreams' | 'models' | 'settings';
+}
+
+// Payload of the `notification` event, sent along with each desktop notification
+export interface NotificationEvent {
+  title: string;
+  body: string;
+  page: string; // route with the details, e.g. "/models"
+}
+
+export interface WebhookConfig {
+  url: string;
+  secret?: string | null; // signs bodies as X-CCR-Signature: sha256=<hmac>
+  events: string[]; // 'request.completed' | 'budget.exceeded'; empty = all
+  enabled: boolean;
+}
+
+export interface PriceSyncConfig {
+  enabled: boolean;
+  interval_hours: number;
+  catalog_url: string;
+}
+
+export interface MaintenanceConfig {
+  enabled: boolean;
+  hour: number; // local hour, 0-23
+  usage_retention_days?: number | null; // null keeps usage forever
+  rollup: boolean;
+  vacuum: boolean;
+}
+
+export interface MaintenanceReport {
+  usage_rows_deleted: number;
+  usage_rows_rolled_up: number;
+  captures_deleted: number;
+  vacuumed: boolean;
+  size_before_bytes: number;
+  size_after_bytes: number;
+  reclaimed_bytes: number;
+}
+
+export interface CaptureConfig {
+  mode: 'off' | 'headers' | 'full';
+  max_bytes: number;
+  retention_days: number;
+  fixtures?: boolean; // also write answered exchanges as replay fixtures
+}
+
+// Answers to temperature-0 or seeded requests; `x-relay-cache: use/bypass/refresh` per request
+export interface ResponseCacheConfig {
+  enabled: boolean;
+  ttl_secs: number;
+  max_mb: number; // least recently used answers go first
 }
 
 export interface ProxyConfig {
@@ -135,12 +745,61 @@ export interface Upstream {
   endpoints: string[];
   api_style?: string;
   api_key?: string;
+  api_keys?: UpstreamKey[]; // rotated round-robin together with api_key
+  extra_headers?: Record<string, string>;
+  // Path overrides; {model} and {api_version} are substituted per request
+  chat_path?: string | null; // default /chat/completions
+  messages_path?: string | null; // default /v1/messages
+  generate_path?: string | null; // default /{api_version}/models/{model}:generateContent
+  api_version?: string | null;
+  proxy?: string | null; // http://, https:// or socks5://, overrides the global proxy
+  no_proxy?: boolean; // connect directly even with a global proxy
+  ca_cert_path?: string | null; // extra PEM roots for private CAs
+  insecure_skip_verify?: boolean; // lab use only: accepts any certificate
+  mock?: MockConfig; // answers of an api_style "mock" upstream
+  max_concurrency?: number | null; // requests at once over all models; more queue
+  max_queue_wait_ms?: number | null; // 429 after waiting this long, default 30000
+  safety_settings?: SafetySetting[]; // Gemini defaults, after the model's
+}
+
+// Sent to Gemini for the harm categories a request doesn't set itself
+export interface SafetySetting {
+  category: string; // e.g. HARM_CATEGORY_HARASSMENT
+  threshold: string; // e.g. BLOCK_ONLY_HIGH
+}
+
+export interface MockConfig {
+  response?: "echo" | "lorem" | "text" | "tool_call";
+  text?: string; // {prompt} and {model} are filled in
+  tool_name?: string | null; // defaults to the request's first tool
+  tool_arguments?: string; // JSON object
+  latency_ms?: number;
+  chunk_chars?: number;
+  chunk_delay_ms?: number;
+  fail_statuses?: number[]; // answered in turn before each success
+}
+
+export interface UpstreamKey {
+  label?: string | null;
+  key: string;
+}
+
+export type ProbeErrorKind = "invalid_url" | "dns" | "connect" | "tls" | "timeout" | "http_status" | "other";
+
+export interface ProbeError {
+  kind: ProbeErrorKind;
+  message: string;
 }
 
 export interface LatencyStat {
   endpoint: string;
-  ok: boolean;
-  ms?: number;
+  ok: boolean; // every sample got a 2xx answer
+  samples: number; // samples answered, whatever the status
+  min_ms: number | null;
+  avg_ms: number | null;
+  p95_ms: number | null;
+  status: number | null;
+  error: ProbeError | null;
 }
 
 export interface UpstreamLatency {
@@ -157,8 +816,89 @@ export interface ModelConfig {
   routes?: ModelRoute[];
   price_prompt_per_1k: number;
   price_completion_per_1k: number;
+  price_cache_write_per_1k?: number | null; // defaults to prompt price
+  price_cache_read_per_1k?: number | null; // defaults to prompt price
+  price_reasoning_per_1k?: number | null; // defaults to completion price
+  price_tiers?: PriceTier[];
+  capabilities?: ModelCapabilities;
+  price_source?: 'manual' | 'openrouter' | null;
+  price_updated_at?: number | null; // unix seconds of the last catalog sync
   priority: number;
   is_temporary?: boolean;
+  mirror?: MirrorConfig | null;
+  max_concurrency?: number | null; // requests at once; more queue
+  max_queue_wait_ms?: number | null; // 429 after waiting this long, default 30000
+  context_guard?: ContextGuardConfig | null;
+  safety_settings?: SafetySetting[]; // Gemini defaults, before the upstream's
+}
+
+// Prompts over context_length less the answer's room lose their oldest
+// messages (x-relay-truncated-messages), or get a 400 when truncate is off
+export interface ContextGuardConfig {
+  context_length: number;
+  truncate?: boolean; // true by default
+  default_max_tokens?: number; // room for the answer when the request sets none, default 4096
+}
+
+// Copies of a sample of requests sent to a second upstream, answers dropped
+export interface MirrorConfig {
+  upstream_id: string;
+  model?: string | null; // configured model id whose upstream model and prices the copy uses
+  sample_rate: number; // 0..1
+}
+
+export interface MirrorReportRow {
+  primary_upstream: string;
+  upstream_id: string;
+  model: string;
+  requests: number;
+  primary_avg_latency_ms: number | null;
+  avg_latency_ms: number | null;
+  latency_delta_ms: number | null; // mirror minus primary, over requests both answered
+  primary_error_rate: number;
+  error_rate: number;
+  divergent: number; // requests where exactly one side failed
+  primary_completion_tokens: number;
+  completion_tokens: number;
+  price_usd: number;
+}
+
+// Context-size price tier; unset prices inherit the model's base prices
+export interface PriceTier {
+  above_prompt_tokens: number;
+  price_prompt_per_1k?: number | null;
+  price_completion_per_1k?: number | null;
+  price_cache_write_per_1k?: number | null;
+  price_cache_read_per_1k?: number | null;
+  price_reasoning_per_1k?: number | null;
+}
+
+// Parameters the model takes; unset ones are guessed from the model name
+export interface ModelCapabilities {
+  temperature?: boolean | null;
+  top_p?: boolean | null;
+  top_k?: boolean | null;
+  penalties?: boolean | null; // presence and frequency penalties
+  reasoning?: boolean | null; // reasoning_effort becomes a thinking budget
+}
+
+// Prices for one model as a sync would write them (per 1k tokens)
+export interface PriceFields {
+  prompt: number;
+  completion: number;
+  cache_write?: number | null;
+  cache_read?: number | null;
+  reasoning?: number | null;
+}
+
+// One pending or applied price update from the OpenRouter catalog
+export interface PriceChange {
+  model_id: string;
+  catalog_id: string;
+  current: PriceFields;
+  proposed: PriceFields;
+  // Manually priced models are listed but never overwritten
+  protected: boolean;
 }
 
 export interface ModelRoute {
@@ -184,6 +924,46 @@ export interface EnvironmentReport {
   ai_tools: ToolInfo[];
 }
 
+export interface CapturedRequest {
+  id: number;
+  timestamp: number;
+  model: string;
+  upstream_id: string;
+  url: string | null;
+  streaming: boolean;
+  status: number | null;
+  latency_ms: number | null;
+  request_headers: string | null; // JSON object
+  request_body: string | null;
+  response_body: string | null;
+  error: string | null;
+  truncated: boolean;
+  request_id: string | null; // x-relay-request-id of the forwarded request
+  redacted: boolean; // request body redacted before it was stored, can't be replayed
+}
+
+export interface ReplayOptions {
+  model?: string | null; // defaults to the captured request's model and upstream
+  upstream_id?: string | null;
+}
+
+export interface ReplayExchange {
+  request_id: string | null;
+  model: string;
+  upstream_id: string;
+  streaming: boolean;
+  status: number | null;
+  latency_ms: number | null;
+  response: unknown | null; // null for streamed originals
+  error: string | null;
+}
+
+export interface ReplayResult {
+  capture_id: number;
+  original: ReplayExchange;
+  replay: ReplayExchange;
+}
+
 export interface RequestLog {
   id: number;
   timestamp: number;
@@ -193,8 +973,22 @@ export interface RequestLog {
   prompt_tokens: number;
   completion_tokens: number;
   total_tokens: number;
-  price_usd: number;
+  price_usd: number | null;
   upstream_id: string;
+  cache_creation_tokens: number;
+  cache_read_tokens: number;
+  reasoning_tokens: number;
+  price_prompt_per_1k: number | null;
+  price_completion_per_1k: number | null;
+  estimated?: boolean; // token counts partly estimated locally
+  synthetic?: boolean; // sent by the relay itself (autoconfig verification), not billed
+  redactions?: number; // matches redacted from the response
+  prompt_redactions?: number; // matches redacted from the prompt before sending
+  success?: boolean; // false when the request failed for good (zero cost)
+  endpoint?: string | null;
+  error_class?: string | null; // timeout, auth, rate_limited, server_error, ...
+  attempts?: number | null;
+  replay_of?: string | null; // request id of the captured request this replayed
 }
 
 export interface LogsResponse {
@@ -214,6 +1008,26 @@ export interface GlobalLogEntry {
   source: string;
   message: string;
   metadata?: string;
+  request_id?: string; // set for lines logged while forwarding a request
+}
+
+/** `log` event of GET /api/logs/stream, and payload of the `log-entry` Tauri event */
+export interface LiveLogEntry {
+  timestamp: number; // unix ms
+  level: LogLevel;
+  module: string;
+  message: string;
+  fields?: Record<string, unknown>;
+}
+
+export interface LiveLogFilter {
+  level?: LogLevel; // minimum level
+  module?: string;
+}
+
+export interface RequestLogsResponse {
+  request_id: string;
+  logs: GlobalLogEntry[];
 }
 
 export interface GlobalLogsResponse {
@@ -281,6 +1095,60 @@ export interface AutoConfigRequest {
   global: boolean;
 }
 
+export type SnippetEditor = "claude-code" | "cursor" | "continue" | "openai-sdk";
+
+// Ready-to-paste editor configuration; refetch after config-changed or server-state
+export interface EditorSnippet {
+  editor: SnippetEditor;
+  label: string;
+  language: "shell" | "text" | "yaml" | "python";
+  target: string; // where to paste it
+  content: string;
+}
+
+// Autoconfig generators (preview_autoconfig / apply_autoconfig / revert_autoconfig)
+export type AutoconfigTarget = "claude_code" | "cursor" | "vscode" | "continue" | "zed";
+
+export interface AutoconfigKeyChange {
+  key: string; // dotted, e.g. "env.ANTHROPIC_BASE_URL" or "models[gpt-4o].apiBase"
+  before: unknown | null; // secrets show only their last characters
+  after: unknown | null;
+}
+
+export interface AutoconfigFileChange {
+  path: string;
+  action: "created" | "updated" | "unchanged" | "restored" | "removed";
+  changes: AutoconfigKeyChange[];
+  backup: string | null; // set once written
+}
+
+export interface AppliedChanges {
+  target: AutoconfigTarget;
+  applied: boolean; // false for a preview
+  files: AutoconfigFileChange[];
+  warnings: string[];
+}
+
+export type AutoconfigProblem =
+  | "relay_down"
+  | "auth"
+  | "model_not_found"
+  | "upstream_down"
+  | "other";
+
+export interface AutoconfigVerifyResult {
+  target: AutoconfigTarget;
+  ok: boolean;
+  model: string;
+  url: string;
+  upstream_id?: string | null; // served the request, or the last one tried
+  latency_ms: number;
+  status?: number | null; // null when the relay could not be reached
+  problem?: AutoconfigProblem | null;
+  message?: string | null;
+  fix?: string | null; // what to do about the problem
+}
+
 // Tool config backup types
 export interface ToolConfigBackup {
   id: string;